@@ -3,7 +3,7 @@ use std::io::Cursor;
 use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
 use futures::StreamExt;
 
-use attic::chunking::chunk_stream;
+use attic::chunking::{chunk_stream, ChunkingAlgorithm};
 use attic::testing::{get_fake_data, get_runtime};
 
 struct Parameters {
@@ -52,6 +52,7 @@ pub fn bench_chunking(c: &mut Criterion) {
                 let cursor = Cursor::new(&data);
                 let mut chunks = chunk_stream(
                     cursor,
+                    ChunkingAlgorithm::Ronomon,
                     params.min_size as usize,
                     params.avg_size as usize,
                     params.max_size as usize,
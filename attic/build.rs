@@ -9,11 +9,14 @@ fn main() {
 
 #[cfg(feature = "nix_store")]
 mod nix_store {
+    use std::path::PathBuf;
+
     use cc::Build;
     use version_compare::Version;
 
     struct NixDependency {
         version: String,
+        include_paths: Vec<PathBuf>,
     }
 
     impl NixDependency {
@@ -24,8 +27,32 @@ mod nix_store {
                 .probe("nix-main")
                 .expect("Failed to find nix-main >=2.4 through pkg-config");
 
+            // `NIX_INCLUDE_PATH` is set by our Nix expressions. Otherwise,
+            // use what pkg-config tells us (usually `<prefix>/include/nix`)
+            // along with its parent so `<nix/...>` includes resolve.
+            println!("cargo:rerun-if-env-changed=NIX_INCLUDE_PATH");
+            let include_paths = match std::env::var_os("NIX_INCLUDE_PATH") {
+                Some(path) => vec![PathBuf::from(path)],
+                None => library
+                    .include_paths
+                    .iter()
+                    .map(|path| match path.file_name() {
+                        Some(name) if name == "nix" => path.parent().unwrap().to_owned(),
+                        _ => path.to_owned(),
+                    })
+                    .collect(),
+            };
+
             Self {
                 version: library.version,
+                include_paths,
+            }
+        }
+
+        fn apply_include_flags(&self, build: &mut Build) {
+            for path in &self.include_paths {
+                // In Nix 2.19+, nix/args/root.hh depends on being able to #include "args.hh" (which is in its parent directory), for some reason
+                build.include(path).include(path.join("nix"));
             }
         }
 
@@ -69,11 +96,9 @@ mod nix_store {
             .flag("-include")
             .flag("nix/config.h")
             .flag("-idirafter")
-            .flag(hacky_include.path().to_str().unwrap())
-            // In Nix 2.19+, nix/args/root.hh depends on being able to #include "args.hh" (which is in its parent directory), for some reason
-            .flag("-I")
-            .flag(concat!(env!("NIX_INCLUDE_PATH"), "/nix"));
+            .flag(hacky_include.path().to_str().unwrap());
 
+        nix_dep.apply_include_flags(&mut build);
         nix_dep.apply_version_flags(&mut build);
 
         build.compile("nixbinding");
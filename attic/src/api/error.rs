@@ -0,0 +1,242 @@
+//! API error codes.
+//!
+//! Errors returned by the server have a JSON body like:
+//!
+//! ```json
+//! {"code": 404, "error": "NoSuchCache", "error_code": 100, "message": "The requested cache does not exist."}
+//! ```
+//!
+//! `code` is the HTTP status code and `message` is meant for humans
+//! and may change at any time. `error` and `error_code` identify the
+//! kind of the error and are stable: Existing names and numbers are
+//! never changed or reused. Clients should match on them instead of
+//! the message.
+
+/// The kind of an API error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    // Generic responses
+    /// The URL was not found.
+    NotFound,
+
+    /// The client is not authorized.
+    ///
+    /// Also returned in place of more specific errors if the client
+    /// isn't allowed to know whether the cache exists.
+    Unauthorized,
+
+    /// The server encountered an internal error.
+    InternalServerError,
+
+    // Specialized responses
+    /// The cache does not exist.
+    NoSuchCache,
+
+    /// The cache already exists.
+    CacheAlreadyExists,
+
+    /// The object does not exist.
+    NoSuchObject,
+
+    /// The compression type is invalid.
+    InvalidCompressionType,
+
+    /// The NAR has missing chunks.
+    IncompleteNar,
+
+    /// The content encoding of the request is not supported.
+    UnsupportedContentEncoding,
+
+    /// The client lacks a permission on the cache.
+    PermissionDenied,
+
+    /// The client lacks permissions to configure some fields.
+    FieldPermissionDenied,
+
+    /// The client made too many requests.
+    RateLimited,
+
+    /// The payload is too large.
+    PayloadTooLarge,
+
+    /// The request is invalid.
+    RequestError,
+
+    // Server-side errors, only reported as `InternalServerError`
+    /// A database error.
+    DatabaseError,
+
+    /// A storage error.
+    StorageError,
+
+    /// Failed to serialize a manifest.
+    ManifestSerializationError,
+
+    // Errors from the common components
+    /// The store path is invalid.
+    InvalidStorePath,
+
+    /// The store path name is invalid.
+    InvalidStorePathName,
+
+    /// The store path hash is invalid.
+    InvalidStorePathHash,
+
+    /// The cache name is invalid.
+    InvalidCacheName,
+
+    /// A signing error.
+    SigningError,
+
+    /// A hashing error.
+    HashError,
+
+    /// An I/O error.
+    IoError,
+
+    /// An error from the Nix store.
+    CxxError,
+
+    /// The content address is invalid.
+    InvalidContentAddress,
+}
+
+impl ErrorCode {
+    /// All error codes.
+    pub const ALL: &'static [Self] = &[
+        Self::NotFound,
+        Self::Unauthorized,
+        Self::InternalServerError,
+        Self::NoSuchCache,
+        Self::CacheAlreadyExists,
+        Self::NoSuchObject,
+        Self::InvalidCompressionType,
+        Self::IncompleteNar,
+        Self::UnsupportedContentEncoding,
+        Self::PermissionDenied,
+        Self::FieldPermissionDenied,
+        Self::RateLimited,
+        Self::PayloadTooLarge,
+        Self::RequestError,
+        Self::DatabaseError,
+        Self::StorageError,
+        Self::ManifestSerializationError,
+        Self::InvalidStorePath,
+        Self::InvalidStorePathName,
+        Self::InvalidStorePathHash,
+        Self::InvalidCacheName,
+        Self::SigningError,
+        Self::HashError,
+        Self::IoError,
+        Self::CxxError,
+        Self::InvalidContentAddress,
+    ];
+
+    /// Returns the numeric code.
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::NotFound => 1,
+            Self::Unauthorized => 2,
+            Self::InternalServerError => 3,
+
+            Self::NoSuchCache => 100,
+            Self::CacheAlreadyExists => 101,
+            Self::NoSuchObject => 102,
+            Self::InvalidCompressionType => 103,
+            Self::IncompleteNar => 104,
+            Self::UnsupportedContentEncoding => 105,
+
+            Self::PermissionDenied => 200,
+            Self::FieldPermissionDenied => 201,
+            Self::RateLimited => 202,
+            Self::PayloadTooLarge => 203,
+            Self::RequestError => 204,
+
+            Self::DatabaseError => 300,
+            Self::StorageError => 301,
+            Self::ManifestSerializationError => 302,
+
+            Self::InvalidStorePath => 400,
+            Self::InvalidStorePathName => 401,
+            Self::InvalidStorePathHash => 402,
+            Self::InvalidCacheName => 403,
+            Self::SigningError => 404,
+            Self::HashError => 405,
+            Self::IoError => 406,
+            Self::CxxError => 407,
+            Self::InvalidContentAddress => 408,
+        }
+    }
+
+    /// Returns the name reported in the `error` field.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::NotFound => "NotFound",
+            Self::Unauthorized => "Unauthorized",
+            Self::InternalServerError => "InternalServerError",
+
+            Self::NoSuchCache => "NoSuchCache",
+            Self::CacheAlreadyExists => "CacheAlreadyExists",
+            Self::NoSuchObject => "NoSuchObject",
+            Self::InvalidCompressionType => "InvalidCompressionType",
+            Self::IncompleteNar => "IncompleteNar",
+            Self::UnsupportedContentEncoding => "UnsupportedContentEncoding",
+
+            // Named after the original server-side error
+            Self::PermissionDenied => "AccessError",
+            Self::FieldPermissionDenied => "FieldPermissionDenied",
+            Self::RateLimited => "RateLimited",
+            Self::PayloadTooLarge => "PayloadTooLarge",
+            Self::RequestError => "RequestError",
+
+            Self::DatabaseError => "DatabaseError",
+            Self::StorageError => "StorageError",
+            Self::ManifestSerializationError => "ManifestSerializationError",
+
+            Self::InvalidStorePath => "InvalidStorePath",
+            Self::InvalidStorePathName => "InvalidStorePathName",
+            Self::InvalidStorePathHash => "InvalidStorePathHash",
+            Self::InvalidCacheName => "InvalidCacheName",
+            Self::SigningError => "SigningError",
+            Self::HashError => "HashError",
+            Self::IoError => "IoError",
+            Self::CxxError => "CxxError",
+            Self::InvalidContentAddress => "InvalidContentAddress",
+        }
+    }
+
+    /// Returns the error code with a numeric code.
+    pub fn from_code(code: u16) -> Option<Self> {
+        Self::ALL.iter().copied().find(|c| c.code() == code)
+    }
+
+    /// Returns the error code with a name.
+    ///
+    /// This is useful with older servers that don't report numeric codes.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|c| c.name() == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_error_code_roundtrip() {
+        let codes: HashSet<u16> = ErrorCode::ALL.iter().map(|c| c.code()).collect();
+        let names: HashSet<&str> = ErrorCode::ALL.iter().map(|c| c.name()).collect();
+        assert_eq!(ErrorCode::ALL.len(), codes.len());
+        assert_eq!(ErrorCode::ALL.len(), names.len());
+
+        for &code in ErrorCode::ALL {
+            assert_eq!(Some(code), ErrorCode::from_code(code.code()));
+            assert_eq!(Some(code), ErrorCode::from_name(code.name()));
+        }
+
+        assert_eq!(None, ErrorCode::from_code(0));
+        assert_eq!(None, ErrorCode::from_name("Meow"));
+    }
+}
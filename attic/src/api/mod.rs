@@ -1,2 +1,3 @@
 pub mod binary_cache;
+pub mod error;
 pub mod v1;
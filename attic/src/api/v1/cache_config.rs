@@ -4,6 +4,12 @@ use serde::{Deserialize, Serialize};
 
 use crate::signing::NixKeypair;
 
+/// The smallest chunk size a cache can be configured with.
+///
+/// FastCDC itself accepts much smaller sizes, but those result in
+/// huge numbers of chunks.
+pub const MIN_CHUNK_SIZE: usize = 4096;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateCacheRequest {
     /// The keypair of the cache.
@@ -65,6 +71,21 @@ pub struct CacheConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub public_key: Option<String>,
 
+    /// Public keys of previous keypairs that narinfos are still signed with.
+    ///
+    /// This is read-only and may not be available.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_public_keys: Option<Vec<String>>,
+
+    /// Content encodings accepted in upload bodies.
+    ///
+    /// Clients may compress upload bodies with one of these and set
+    /// `Content-Encoding` accordingly.
+    ///
+    /// This is read-only and may not be available.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload_encodings: Option<Vec<String>>,
+
     /// Whether the cache is public or not.
     ///
     /// Anonymous clients are implicitly granted the "pull"
@@ -95,6 +116,38 @@ pub struct CacheConfig {
     /// The retention period of the cache.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub retention_period: Option<RetentionPeriodConfig>,
+
+    /// How NAR URLs are emitted in narinfos.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nar_url_base: Option<NarUrlBaseConfig>,
+
+    /// Whether signatures by upstream caches are served in narinfos.
+    ///
+    /// If disabled, signatures by keys in `upstream_cache_key_names`
+    /// are stripped. Other signatures supplied by uploaders are always
+    /// served.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preserve_upstream_signatures: Option<bool>,
+
+    /// Chunking parameters of the cache.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chunking: Option<ChunkingConfig>,
+}
+
+/// Information needed to use a cache as a substituter.
+///
+/// Unlike the full configuration, this only requires permission to
+/// discover the cache.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachePublicKey {
+    /// The public key of the cache, in the canonical format used by Nix.
+    pub public_key: String,
+
+    /// The Nix binary cache endpoint of the cache.
+    pub substituter_endpoint: String,
+
+    /// The priority of the binary cache.
+    pub priority: i32,
 }
 
 /// Configuaration of a keypair.
@@ -108,7 +161,7 @@ pub enum KeypairConfig {
 }
 
 /// Configuration of retention period.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum RetentionPeriodConfig {
     /// Use the global default.
     Global,
@@ -119,6 +172,114 @@ pub enum RetentionPeriodConfig {
     Period(u32),
 }
 
+/// Configuration of NAR URLs in narinfos.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NarUrlBaseConfig {
+    /// Emit URLs relative to the binary cache endpoint.
+    Relative,
+
+    /// Emit absolute URLs under a base URL.
+    ///
+    /// For example, with `https://cdn.example.com/demo/` the NAR
+    /// URLs will look like `https://cdn.example.com/demo/nar/{storePathHash}.nar`.
+    Base(String),
+}
+
+/// Configuration of chunking parameters.
+///
+/// Changing these makes newly-uploaded NARs be cut at different
+/// places, so they will share few chunks with existing data.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChunkingConfig {
+    /// Use the server-wide parameters.
+    Global,
+
+    /// Override some of the server-wide parameters.
+    ///
+    /// When configuring a cache, unset values are left unchanged.
+    Override(ChunkingOverrides),
+}
+
+/// Per-cache overrides of chunking parameters.
+///
+/// Unset values fall back to the server configuration.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkingOverrides {
+    /// The minimum NAR size to trigger chunking.
+    ///
+    /// If 0, chunking is disabled for the cache.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nar_size_threshold: Option<usize>,
+
+    /// The preferred minimum size of a chunk, in bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_size: Option<usize>,
+
+    /// The preferred average size of a chunk, in bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avg_size: Option<usize>,
+
+    /// The preferred maximum size of a chunk, in bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<usize>,
+}
+
+impl ChunkingOverrides {
+    /// Returns whether no values are overridden.
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Checks that the values set are usable.
+    ///
+    /// Values that are unset can only be checked against the
+    /// server configuration by the server.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        let sizes = [self.min_size, self.avg_size, self.max_size];
+
+        if sizes.iter().flatten().any(|size| *size < MIN_CHUNK_SIZE) {
+            return Err("Chunk sizes must be at least 4096 bytes");
+        }
+
+        let set: Vec<usize> = sizes.into_iter().flatten().collect();
+        if set.windows(2).any(|w| w[0] > w[1]) {
+            return Err("Chunk sizes must satisfy min-size <= avg-size <= max-size");
+        }
+
+        Ok(())
+    }
+}
+
+impl NarUrlBaseConfig {
+    /// Checks that the base URL is usable.
+    ///
+    /// The base URL must have an HTTP(S) scheme and a trailing slash.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        let Self::Base(base) = self else {
+            return Ok(());
+        };
+
+        let rest = base
+            .strip_prefix("https://")
+            .or_else(|| base.strip_prefix("http://"))
+            .ok_or("The NAR URL base must start with http:// or https://")?;
+
+        if rest.is_empty() || rest.starts_with('/') {
+            return Err("The NAR URL base must have a host");
+        }
+
+        if rest.contains(['?', '#']) {
+            return Err("The NAR URL base cannot have a query or fragment");
+        }
+
+        if !rest.ends_with('/') {
+            return Err("The NAR URL base must end with a slash");
+        }
+
+        Ok(())
+    }
+}
+
 impl CacheConfig {
     pub fn blank() -> Self {
         Self {
@@ -126,11 +287,63 @@ impl CacheConfig {
             substituter_endpoint: None,
             api_endpoint: None,
             public_key: None,
+            previous_public_keys: None,
+            upload_encodings: None,
             is_public: None,
             store_dir: None,
             priority: None,
             upstream_cache_key_names: None,
             retention_period: None,
+            nar_url_base: None,
+            preserve_upstream_signatures: None,
+            chunking: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nar_url_base_validation() {
+        let valid = |base: &str| NarUrlBaseConfig::Base(base.to_string()).validate();
+
+        assert!(NarUrlBaseConfig::Relative.validate().is_ok());
+        assert!(valid("https://cdn.example.com/").is_ok());
+        assert!(valid("http://cdn.example.com:8080/demo/").is_ok());
+
+        assert!(valid("https://cdn.example.com").is_err());
+        assert!(valid("https://cdn.example.com/demo").is_err());
+        assert!(valid("ftp://cdn.example.com/").is_err());
+        assert!(valid("cdn.example.com/").is_err());
+        assert!(valid("https:///").is_err());
+        assert!(valid("https://").is_err());
+        assert!(valid("https://cdn.example.com/?a=/").is_err());
+    }
+
+    #[test]
+    fn test_chunking_overrides_validation() {
+        let overrides = |min, avg, max| ChunkingOverrides {
+            nar_size_threshold: None,
+            min_size: min,
+            avg_size: avg,
+            max_size: max,
+        };
+
+        assert!(ChunkingOverrides::default().validate().is_ok());
+        assert!(overrides(Some(16384), Some(65536), Some(262144))
+            .validate()
+            .is_ok());
+        assert!(overrides(Some(65536), None, Some(65536)).validate().is_ok());
+        assert!(overrides(None, Some(1048576), None).validate().is_ok());
+
+        assert!(overrides(Some(1024), None, None).validate().is_err());
+        assert!(overrides(Some(65536), Some(16384), None)
+            .validate()
+            .is_err());
+        assert!(overrides(Some(65536), None, Some(16384))
+            .validate()
+            .is_err());
+    }
+}
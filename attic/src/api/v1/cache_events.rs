@@ -0,0 +1,66 @@
+//! Cache event stream endpoint.
+
+use serde::{Deserialize, Serialize};
+
+/// Query parameters for fetching cache events.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CacheEventsQuery {
+    /// Only return events with sequence numbers greater than this.
+    ///
+    /// Pass the `last_seq` of the previous response to resume.
+    pub since: Option<i64>,
+
+    /// How long to wait for new events, in seconds.
+    ///
+    /// If there are no events after `since`, the server holds the
+    /// request until an event arrives or the timeout elapses. The
+    /// server may cap the timeout.
+    pub timeout: Option<u64>,
+}
+
+/// A batch of cache events.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheEvents {
+    /// The events in ascending order of sequence numbers.
+    ///
+    /// This may be a subset of the events available, in which
+    /// case the next request returns immediately.
+    pub events: Vec<CacheEvent>,
+
+    /// The sequence number to resume from.
+    pub last_seq: i64,
+}
+
+/// A change to a cache.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheEvent {
+    /// The sequence number of the event.
+    ///
+    /// Sequence numbers increase monotonically within a cache
+    /// but may have gaps.
+    pub seq: i64,
+
+    /// What happened.
+    pub kind: CacheEventKind,
+
+    /// The hash of the store path affected.
+    pub store_path_hash: String,
+
+    /// Unix timestamp when the event happened.
+    pub timestamp: i64,
+}
+
+/// The kind of a cache event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum CacheEventKind {
+    /// A path was uploaded to the cache.
+    ///
+    /// This is also sent when an existing path is uploaded again.
+    #[serde(rename = "upload")]
+    Upload,
+
+    /// A path was deleted from the cache.
+    #[serde(rename = "delete")]
+    Delete,
+}
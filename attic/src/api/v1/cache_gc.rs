@@ -0,0 +1,50 @@
+//! Cache garbage collection endpoint.
+
+use serde::{Deserialize, Serialize};
+
+/// A garbage collection job on a cache.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheGcJob {
+    /// The ID of the job.
+    pub id: String,
+
+    /// The status of the job.
+    #[serde(flatten)]
+    pub status: CacheGcStatus,
+}
+
+/// Status of a garbage collection job.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum CacheGcStatus {
+    /// The job is still running.
+    #[serde(rename = "running")]
+    Running,
+
+    /// The job has completed.
+    #[serde(rename = "completed")]
+    Completed {
+        /// Number of objects deleted from the cache.
+        objects_deleted: u64,
+
+        /// Total size of NARs no longer referenced by any cache, in bytes.
+        ///
+        /// The storage is reclaimed the next time the server
+        /// cleans up orphaned NARs.
+        bytes_freed: u64,
+    },
+
+    /// The job has failed.
+    #[serde(rename = "failed")]
+    Failed {
+        /// The error message.
+        error: String,
+    },
+}
+
+impl CacheGcStatus {
+    /// Returns whether the job has finished.
+    pub fn is_finished(&self) -> bool {
+        !matches!(self, Self::Running)
+    }
+}
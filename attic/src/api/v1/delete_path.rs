@@ -0,0 +1,53 @@
+//! Path deletion endpoint.
+
+use serde::{Deserialize, Serialize};
+
+/// Query parameters for deleting a path.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeletePathQuery {
+    /// Whether to also delete paths referencing the path.
+    ///
+    /// This applies recursively, so everything in the cache that
+    /// depends on the path is deleted.
+    #[serde(default)]
+    pub with_referrers: bool,
+}
+
+/// The result of deleting a path.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeletePathResult {
+    /// The full store paths deleted.
+    pub deleted_paths: Vec<String>,
+}
+
+/// A request to delete all objects matching a filter.
+///
+/// At least one filter must be set. Filters are combined with AND.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeleteObjectsRequest {
+    /// Only delete paths whose name matches this glob.
+    ///
+    /// The name is the part of the store path after the hash, like
+    /// `hello-2.10`. `*` matches any sequence of characters and `?`
+    /// matches a single character.
+    pub name_glob: Option<String>,
+
+    /// Only delete objects created before this Unix timestamp.
+    pub created_before: Option<i64>,
+
+    /// Only delete objects uploaded by this user.
+    pub created_by: Option<String>,
+
+    /// Only count the objects that would be deleted.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// The result of deleting objects matching a filter.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeleteObjectsResult {
+    /// The number of objects deleted.
+    ///
+    /// For dry runs, this is the number that would have been deleted.
+    pub num_deleted: u64,
+}
@@ -0,0 +1,98 @@
+//! Object listing endpoint.
+
+use serde::{Deserialize, Serialize};
+
+/// Query parameters for listing objects in a cache.
+///
+/// All filters are optional and combined with AND.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ListObjectsQuery {
+    /// Only return objects whose store path contains this string.
+    pub name: Option<String>,
+
+    /// Only return objects with NARs at least this large, in bytes.
+    pub min_size: Option<u64>,
+
+    /// Only return objects with NARs at most this large, in bytes.
+    pub max_size: Option<u64>,
+
+    /// Only return objects uploaded by this user.
+    pub created_by: Option<String>,
+
+    /// Only return objects created after this Unix timestamp.
+    pub created_after: Option<i64>,
+
+    /// Where to resume listing.
+    ///
+    /// Pass the `next_cursor` of the previous response.
+    pub cursor: Option<i64>,
+
+    /// The maximum number of objects to return.
+    ///
+    /// The server may cap the limit.
+    pub limit: Option<u64>,
+}
+
+/// A page of objects in a cache.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectList {
+    /// The objects in the order they were first uploaded.
+    pub objects: Vec<ObjectInfo>,
+
+    /// The cursor to fetch the next page with.
+    ///
+    /// This is absent on the last page.
+    pub next_cursor: Option<i64>,
+}
+
+/// Metadata of an object in a cache.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectInfo {
+    /// The full store path.
+    pub store_path: String,
+
+    /// The size of the NAR.
+    pub nar_size: u64,
+
+    /// The number of chunks the NAR is stored in.
+    ///
+    /// This is None if the server doesn't report it.
+    #[serde(default)]
+    pub num_chunks: Option<u64>,
+
+    /// Unix timestamp when the object was created.
+    pub created_at: i64,
+
+    /// The user who uploaded the object, if known.
+    pub created_by: Option<String>,
+
+    /// Unix timestamp when the object was last accessed, if ever.
+    pub last_accessed_at: Option<i64>,
+}
+
+/// Query parameters for paging through all objects in a cache.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CacheObjectsQuery {
+    /// The maximum number of objects to return.
+    ///
+    /// The server may cap the limit.
+    pub limit: Option<u64>,
+
+    /// Where to resume listing.
+    ///
+    /// Pass the `next_cursor` of the previous response. The cursor
+    /// is opaque to clients.
+    pub cursor: Option<String>,
+}
+
+/// A page of objects in a cache, ordered by creation time.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheObjectsPage {
+    /// The objects in the order they were last uploaded.
+    pub objects: Vec<ObjectInfo>,
+
+    /// The cursor to fetch the next page with.
+    ///
+    /// This is absent on the last page.
+    pub next_cursor: Option<String>,
+}
@@ -1,3 +1,7 @@
 pub mod cache_config;
+pub mod cache_events;
+pub mod cache_gc;
+pub mod delete_path;
 pub mod get_missing_paths;
+pub mod list_objects;
 pub mod upload_path;
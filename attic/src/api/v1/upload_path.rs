@@ -1,9 +1,12 @@
+use std::path::Path;
+
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DefaultOnError};
 
 use crate::cache::CacheName;
 use crate::hash::Hash;
 use crate::nix_store::StorePathHash;
+use crate::signing;
 
 /// Header containing the upload info.
 pub const ATTIC_NAR_INFO: &str = "X-Attic-Nar-Info";
@@ -25,7 +28,7 @@ pub const ATTIC_NAR_INFO_PREAMBLE_SIZE: &str = "X-Attic-Nar-Info-Preamble-Size";
 /// Regardless of client compression, the server will always decompress
 /// the NAR to validate the NAR hash before applying the server-configured
 /// compression again.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UploadPathNarInfo {
     /// The name of the binary cache to upload to.
     pub cache: CacheName,
@@ -64,6 +67,21 @@ pub struct UploadPathNarInfo {
     pub nar_size: usize,
 }
 
+impl UploadPathNarInfo {
+    /// Returns the fingerprint of the object.
+    ///
+    /// The fingerprint is identical to the one the server computes
+    /// from the resulting narinfo.
+    pub fn fingerprint(&self) -> Vec<u8> {
+        signing::fingerprint(
+            Path::new(&self.store_path),
+            &self.nar_hash,
+            self.nar_size,
+            &self.references,
+        )
+    }
+}
+
 #[serde_as]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UploadPathResult {
@@ -99,3 +117,48 @@ impl Default for UploadPathResultKind {
         Self::Uploaded
     }
 }
+
+/// Request to check whether a NAR needs to be uploaded.
+///
+/// This is an optional extension. Servers without support respond
+/// with 404, in which case the client should upload the NAR as usual.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadPathPreflightRequest {
+    /// The name of the binary cache to upload to.
+    pub cache: CacheName,
+
+    /// The hash of the NAR.
+    pub nar_hash: Hash,
+
+    /// The size of the NAR.
+    pub nar_size: usize,
+}
+
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadPathPreflightResult {
+    #[serde_as(deserialize_as = "DefaultOnError")]
+    pub kind: UploadPathPreflightResultKind,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum UploadPathPreflightResultKind {
+    /// The NAR must be uploaded in full.
+    #[default]
+    Required,
+
+    /// The NAR can be omitted from the upload.
+    ///
+    /// The server already has the NAR and doesn't require proof
+    /// of possession, so the upload body can be empty. If the NAR
+    /// goes away before the upload, the upload fails and should be
+    /// retried with the NAR.
+    Deduplicated,
+
+    /// The NAR must be uploaded to prove possession.
+    ///
+    /// The server already has the NAR and will only hash the upload
+    /// without storing it.
+    ProofOfPossession,
+}
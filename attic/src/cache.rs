@@ -9,6 +9,7 @@
 //!
 /// The plus sign is intended to be used as the delimiter between a
 /// namespace and a user-given name (e.g., `zhaofengli+shared`).
+use std::cmp::Reverse;
 use std::hash::{Hash, Hasher};
 use std::str::FromStr;
 
@@ -132,6 +133,26 @@ impl CacheNamePattern {
             None => self.pattern == name.as_str(),
         }
     }
+
+    /// Returns the length of the literal prefix before the first wildcard.
+    pub fn literal_prefix_len(&self) -> usize {
+        self.pattern.find('*').unwrap_or(self.pattern.len())
+    }
+
+    /// Returns the number of wildcards in the pattern.
+    pub fn wildcard_count(&self) -> usize {
+        self.pattern.matches('*').count()
+    }
+
+    /// Returns whether the pattern is more specific than another.
+    ///
+    /// A pattern with a longer literal prefix is more specific. If the
+    /// prefixes are equally long, the one with fewer wildcards is more
+    /// specific.
+    pub fn is_more_specific_than(&self, other: &Self) -> bool {
+        let key = |p: &Self| (p.literal_prefix_len(), Reverse(p.wildcard_count()));
+        key(self) > key(other)
+    }
 }
 
 impl FromStr for CacheNamePattern {
@@ -263,4 +284,21 @@ pub mod tests {
         assert_eq!(pattern1, pattern2);
         assert_ne!(pattern, pattern1);
     }
+
+    #[test]
+    fn test_cache_name_pattern_specificity() {
+        let pattern = |s: &str| CacheNamePattern::new(s.to_string()).unwrap();
+
+        assert_eq!(5, pattern("team-*").literal_prefix_len());
+        assert_eq!(0, pattern("*").literal_prefix_len());
+        assert_eq!(11, pattern("no-wildcard").literal_prefix_len());
+        assert_eq!(2, pattern("team-*-*").wildcard_count());
+
+        assert!(pattern("team-ci-*").is_more_specific_than(&pattern("team-*")));
+        assert!(pattern("team-*").is_more_specific_than(&pattern("*")));
+        assert!(pattern("team-*").is_more_specific_than(&pattern("team-*-*")));
+        assert!(pattern("team-*-ci").is_more_specific_than(&pattern("team-*-*")));
+        assert!(!pattern("team-*").is_more_specific_than(&pattern("team-*")));
+        assert!(!pattern("team-*").is_more_specific_than(&pattern("team-ci-*")));
+    }
 }
@@ -3,20 +3,42 @@
 //! We perform chunking on uncompressed NARs using the FastCDC
 //! algorithm.
 
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+
 use async_stream::try_stream;
 use bytes::{BufMut, Bytes, BytesMut};
-use fastcdc::ronomon::FastCDC;
+use fastcdc::{ronomon, v2020};
 use futures::stream::Stream;
 use tokio::io::AsyncRead;
 
 use crate::stream::read_chunk_async;
 
+/// A variant of the FastCDC algorithm.
+///
+/// Different variants produce different cutpoints for the same
+/// data, so switching between them hurts deduplication.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChunkingAlgorithm {
+    /// The ronomon variant.
+    #[default]
+    Ronomon,
+
+    /// The 2020 variant with normalized chunking.
+    V2020 {
+        /// The normalization level, from 0 to 3.
+        ///
+        /// Higher levels make the chunk sizes closer to the average.
+        normalization_level: u32,
+    },
+}
+
 /// Splits a streams into content-defined chunks.
 ///
 /// This is a wrapper over fastcdc-rs that takes an `AsyncRead` and
 /// returns a `Stream` of chunks as `Bytes`s.
 pub fn chunk_stream<R>(
     mut stream: R,
+    algorithm: ChunkingAlgorithm,
     min_size: usize,
     avg_size: usize,
     max_size: usize,
@@ -25,6 +47,8 @@ where
     R: AsyncRead + Unpin + Send,
 {
     let s = try_stream! {
+        algorithm.validate(min_size, avg_size, max_size)?;
+
         let mut buf = BytesMut::with_capacity(max_size);
 
         loop {
@@ -39,13 +63,13 @@ where
                 eof = true;
             }
 
-            let chunks = FastCDC::with_eof(&read, min_size, avg_size, max_size, eof);
+            let chunks = algorithm.find_chunks(&read, min_size, avg_size, max_size, eof);
             let mut consumed = 0;
 
-            for chunk in chunks {
-                consumed += chunk.length;
+            for (offset, length) in chunks {
+                consumed += length;
 
-                let slice = read.slice(chunk.offset..chunk.offset + chunk.length);
+                let slice = read.slice(offset..offset + length);
                 yield slice;
             }
 
@@ -65,6 +89,104 @@ where
     Box::pin(s)
 }
 
+impl ChunkingAlgorithm {
+    /// Checks that the chunk sizes are supported by the algorithm.
+    pub fn validate(
+        &self,
+        min_size: usize,
+        avg_size: usize,
+        max_size: usize,
+    ) -> std::io::Result<()> {
+        let valid = match self {
+            Self::Ronomon => {
+                (ronomon::MINIMUM_MIN..=ronomon::MINIMUM_MAX).contains(&min_size)
+                    && (ronomon::AVERAGE_MIN..=ronomon::AVERAGE_MAX).contains(&avg_size)
+                    && (ronomon::MAXIMUM_MIN..=ronomon::MAXIMUM_MAX).contains(&max_size)
+            }
+            Self::V2020 {
+                normalization_level,
+            } => {
+                if v2020_normalization(*normalization_level).is_none() {
+                    return Err(IoError::new(
+                        IoErrorKind::InvalidInput,
+                        format!("Invalid normalization level {}", normalization_level),
+                    ));
+                }
+
+                let in_range =
+                    |size: usize, min: u32, max: u32| (min as usize..=max as usize).contains(&size);
+
+                in_range(min_size, v2020::MINIMUM_MIN, v2020::MINIMUM_MAX)
+                    && in_range(avg_size, v2020::AVERAGE_MIN, v2020::AVERAGE_MAX)
+                    && in_range(max_size, v2020::MAXIMUM_MIN, v2020::MAXIMUM_MAX)
+            }
+        };
+
+        if valid {
+            Ok(())
+        } else {
+            Err(IoError::new(
+                IoErrorKind::InvalidInput,
+                format!(
+                    "Unsupported chunk sizes {}/{}/{} for {:?}",
+                    min_size, avg_size, max_size, self
+                ),
+            ))
+        }
+    }
+
+    /// Finds chunks in a buffer, returning their offsets and lengths.
+    ///
+    /// If `eof` is false, the trailing bytes that may be part of a
+    /// longer chunk are not returned.
+    fn find_chunks(
+        &self,
+        data: &[u8],
+        min_size: usize,
+        avg_size: usize,
+        max_size: usize,
+        eof: bool,
+    ) -> Vec<(usize, usize)> {
+        match self {
+            Self::Ronomon => ronomon::FastCDC::with_eof(data, min_size, avg_size, max_size, eof)
+                .map(|chunk| (chunk.offset, chunk.length))
+                .collect(),
+            Self::V2020 {
+                normalization_level,
+            } => {
+                let mut chunks: Vec<_> = v2020::FastCDC::with_level(
+                    data,
+                    min_size as u32,
+                    avg_size as u32,
+                    max_size as u32,
+                    v2020_normalization(*normalization_level).unwrap(),
+                )
+                .map(|chunk| (chunk.offset, chunk.length))
+                .collect();
+
+                // The v2020 chunker always cuts at the end of the data.
+                // Unless the last chunk hit the maximum size, its real
+                // cutpoint may be in the next read.
+                if !eof && chunks.last().is_some_and(|(_, length)| *length < max_size) {
+                    chunks.pop();
+                }
+
+                chunks
+            }
+        }
+    }
+}
+
+fn v2020_normalization(level: u32) -> Option<v2020::Normalization> {
+    match level {
+        0 => Some(v2020::Normalization::Level0),
+        1 => Some(v2020::Normalization::Level1),
+        2 => Some(v2020::Normalization::Level2),
+        3 => Some(v2020::Normalization::Level3),
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -75,15 +197,19 @@ mod tests {
 
     use crate::testing::get_fake_data;
 
+    const V2020: ChunkingAlgorithm = ChunkingAlgorithm::V2020 {
+        normalization_level: 2,
+    };
+
     /// Chunks and reconstructs a file.
     #[tokio::test]
     async fn test_chunking_basic() {
-        async fn case(size: usize) {
+        async fn case(algorithm: ChunkingAlgorithm, size: usize) {
             let test_file = get_fake_data(size); // 32 MiB
             let mut reconstructed_file = Vec::new();
 
             let cursor = Cursor::new(&test_file);
-            let mut chunks = chunk_stream(cursor, 8 * 1024, 16 * 1024, 32 * 1024);
+            let mut chunks = chunk_stream(cursor, algorithm, 8 * 1024, 16 * 1024, 32 * 1024);
 
             while let Some(chunk) = chunks.next().await {
                 let chunk = chunk.unwrap();
@@ -94,8 +220,46 @@ mod tests {
             assert_eq!(reconstructed_file, test_file);
         }
 
-        case(32 * 1024 * 1024 - 1).await;
-        case(32 * 1024 * 1024).await;
-        case(32 * 1024 * 1024 + 1).await;
+        for algorithm in [ChunkingAlgorithm::Ronomon, V2020] {
+            case(algorithm, 32 * 1024 * 1024 - 1).await;
+            case(algorithm, 32 * 1024 * 1024).await;
+            case(algorithm, 32 * 1024 * 1024 + 1).await;
+        }
+    }
+
+    /// Streaming v2020 chunking finds the same cutpoints as chunking in one go.
+    #[tokio::test]
+    async fn test_chunking_v2020_cutpoints() {
+        let test_file = get_fake_data(4 * 1024 * 1024 + 123);
+
+        let expected: Vec<usize> = v2020::FastCDC::with_level(
+            &test_file,
+            8 * 1024,
+            16 * 1024,
+            32 * 1024,
+            v2020::Normalization::Level2,
+        )
+        .map(|chunk| chunk.length)
+        .collect();
+
+        let cursor = Cursor::new(&test_file);
+        let actual: Vec<usize> = chunk_stream(cursor, V2020, 8 * 1024, 16 * 1024, 32 * 1024)
+            .map(|chunk| chunk.unwrap().len())
+            .collect()
+            .await;
+
+        assert_eq!(expected, actual);
+    }
+
+    #[tokio::test]
+    async fn test_chunking_invalid_parameters() {
+        let algorithm = ChunkingAlgorithm::V2020 {
+            normalization_level: 4,
+        };
+        let mut chunks = chunk_stream(Cursor::new(vec![0; 100]), algorithm, 64, 256, 1024);
+        assert!(chunks.next().await.unwrap().is_err());
+
+        let mut chunks = chunk_stream(Cursor::new(vec![0; 100]), V2020, 64, 256, 32 * 1024 * 1024);
+        assert!(chunks.next().await.unwrap().is_err());
     }
 }
@@ -10,10 +10,20 @@ use sha2::{Digest, Sha256};
 use crate::error::AtticResult;
 
 /// A hash.
+///
+/// Attic itself only computes SHA-256 hashes. SHA-1 and SHA-512 hashes
+/// can be parsed and round-tripped, since they may appear in narinfos
+/// and content addresses produced by Nix.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Hash {
+    /// An SHA-1 hash.
+    Sha1([u8; 20]),
+
     /// An SHA-256 hash.
     Sha256([u8; 32]),
+
+    /// An SHA-512 hash.
+    Sha512([u8; 64]),
 }
 
 /// A hashing error.
@@ -56,10 +66,18 @@ impl Hash {
         let hash = &rest[1..];
 
         match typ {
+            "sha1" => {
+                let v = decode_hash(hash, "SHA-1", 20)?;
+                Ok(Self::Sha1(v.try_into().unwrap()))
+            }
             "sha256" => {
                 let v = decode_hash(hash, "SHA-256", 32)?;
                 Ok(Self::Sha256(v.try_into().unwrap()))
             }
+            "sha512" => {
+                let v = decode_hash(hash, "SHA-512", 64)?;
+                Ok(Self::Sha512(v.try_into().unwrap()))
+            }
             _ => Err(Error::UnsupportedHashAlgorithm(typ.to_owned()).into()),
         }
     }
@@ -76,15 +94,21 @@ impl Hash {
         format!("{}:{}", self.hash_type(), hex::encode(self.data()))
     }
 
-    fn data(&self) -> &[u8] {
+    /// Returns the raw digest.
+    pub fn data(&self) -> &[u8] {
         match self {
+            Self::Sha1(d) => d,
             Self::Sha256(d) => d,
+            Self::Sha512(d) => d,
         }
     }
 
-    fn hash_type(&self) -> &'static str {
+    /// Returns the name of the hash algorithm as used by Nix.
+    pub fn hash_type(&self) -> &'static str {
         match self {
+            Self::Sha1(_) => "sha1",
             Self::Sha256(_) => "sha256",
+            Self::Sha512(_) => "sha512",
         }
     }
 
@@ -16,6 +16,33 @@ fn test_basic() {
     assert_eq!(expected_base32, hash.to_typed_base32());
 }
 
+#[test]
+fn test_other_algorithms() {
+    let cases = [
+        (
+            "sha1:029e15ad0c67cd6857b8b67f7e4ac1baf01aaf8a",
+            "sha1:iapimw5sq557wzxnp1bnikb71jnib7h2",
+        ),
+        (
+            "sha512:34f93f97310bad61aed74fef10498a941c31d97bf8a6b31dd143cc66cad91949993201b0b8585bdd0f340460ebf18e9bb29355bac9a6829a7b120239918c77ef",
+            "sha512:3ppg34i74114ywshakckfjmjfr9p3pixdh08d0gvmdmif5h04r9jj8rv756dk23s4fv79pqggck274li94i1vsgsyp63b8b66bkzy9l",
+        ),
+    ];
+
+    for (base16, base32) in cases {
+        let hash = Hash::from_typed(base16).unwrap();
+        assert_eq!(hash, Hash::from_typed(base32).unwrap());
+        assert_eq!(base16, hash.to_typed_base16());
+        assert_eq!(base32, hash.to_typed_base32());
+    }
+
+    // The length must match the algorithm
+    assert!(matches!(
+        Hash::from_typed("sha1:df3404eaf1481506db9ca155e0a871d5b4d22e62a96961e8bf4ad1a8ca525330"),
+        Err(AtticError::HashError(Error::InvalidHashStringLength { .. }))
+    ));
+}
+
 #[test]
 fn test_nar_hash() {
     let nar = test_nar::NO_DEPS;
@@ -10,6 +10,8 @@ use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 use crate::{AtticError, AtticResult};
 
+pub use ffi::CPathInfo;
+
 // The C++ implementation takes care of concurrency
 #[repr(transparent)]
 pub struct FfiNixStore(UnsafeCell<cxx::UniquePtr<ffi::CNixStore>>);
@@ -42,7 +44,8 @@ pub unsafe fn open_nix_store() -> AtticResult<FfiNixStore> {
 mod mpsc {
     // Tokio
     pub use tokio::sync::mpsc::{
-        error::SendError, unbounded_channel, UnboundedReceiver, UnboundedSender,
+        channel, error::SendError, unbounded_channel, Receiver, Sender, UnboundedReceiver,
+        UnboundedSender,
     };
 }
 
@@ -153,6 +156,81 @@ impl Stream for AsyncWriteAdapter {
     }
 }
 
+/// Number of chunks buffered between `AsyncReadAdapter` and the C++ side.
+const READ_BUFFER: usize = 8;
+
+/// Async read request.
+#[derive(Debug)]
+enum AsyncReadMessage {
+    Data(Vec<u8>),
+    Error(String),
+}
+
+/// Async read request receiver.
+///
+/// The C++ side reads from this in a blocking thread. The end of
+/// the data is signaled by dropping the `AsyncReadAdapter`.
+pub struct AsyncReadReceiver {
+    receiver: mpsc::Receiver<AsyncReadMessage>,
+    buffer: Vec<u8>,
+    pos: usize,
+}
+
+impl AsyncReadReceiver {
+    /// Reads some data, returning 0 at the end.
+    fn recv(&mut self, data: &mut [u8]) -> Result<usize, String> {
+        while self.pos == self.buffer.len() {
+            match self.receiver.blocking_recv() {
+                Some(AsyncReadMessage::Data(v)) => {
+                    self.buffer = v;
+                    self.pos = 0;
+                }
+                Some(AsyncReadMessage::Error(e)) => return Err(e),
+                None => return Ok(0),
+            }
+        }
+
+        let len = data.len().min(self.buffer.len() - self.pos);
+        data[..len].copy_from_slice(&self.buffer[self.pos..self.pos + len]);
+        self.pos += len;
+
+        Ok(len)
+    }
+}
+
+/// A wrapper of the `AsyncRead` side for the synchronous Nix C++ land.
+pub struct AsyncReadAdapter {
+    sender: mpsc::Sender<AsyncReadMessage>,
+}
+
+impl AsyncReadAdapter {
+    pub fn new() -> (Self, Box<AsyncReadReceiver>) {
+        let (sender, receiver) = mpsc::channel(READ_BUFFER);
+
+        let r = Self { sender };
+        let receiver = Box::new(AsyncReadReceiver {
+            receiver,
+            buffer: Vec::new(),
+            pos: 0,
+        });
+
+        (r, receiver)
+    }
+
+    /// Sends data to the C++ side.
+    ///
+    /// Returns false if the C++ side has stopped reading.
+    pub async fn send(&self, data: Vec<u8>) -> bool {
+        self.sender.send(AsyncReadMessage::Data(data)).await.is_ok()
+    }
+
+    /// Makes the next read on the C++ side throw an exception.
+    pub async fn error(&self, error: impl std::error::Error) {
+        let message = AsyncReadMessage::Error(error.to_string());
+        let _ = self.sender.send(message).await;
+    }
+}
+
 #[cxx::bridge]
 /// Generated by `cxx.rs`.
 ///
@@ -162,6 +240,9 @@ mod ffi {
         type AsyncWriteSender;
         fn send(self: &mut AsyncWriteSender, data: &[u8]) -> Result<()>;
         fn eof(self: &mut AsyncWriteSender) -> Result<()>;
+
+        type AsyncReadReceiver;
+        fn recv(self: &mut AsyncReadReceiver, data: &mut [u8]) -> Result<usize>;
     }
 
     unsafe extern "C++" {
@@ -190,6 +271,16 @@ mod ffi {
             store_path: &[u8],
         ) -> Result<UniquePtr<CPathInfo>>;
 
+        /// Queries information about a list of valid paths.
+        ///
+        /// This is the multi-path variant of `query_path_info`, which
+        /// crosses the FFI boundary only once. The results are in the
+        /// same order as `base_names`.
+        fn query_path_infos(
+            self: Pin<&mut CNixStore>,
+            base_names: &[&[u8]],
+        ) -> Result<UniquePtr<CxxVector<CPathInfo>>>;
+
         /// Computes the closure of a valid path.
         ///
         /// If `flip_directions` is true, the set of paths that can reach `store_path` is
@@ -219,6 +310,17 @@ mod ffi {
             include_derivers: bool,
         ) -> Result<UniquePtr<CxxVector<CxxString>>>;
 
+        /// Adds signatures to a valid path.
+        ///
+        /// This requires the user to be trusted by the Nix daemon.
+        fn add_signatures(self: Pin<&mut CNixStore>, base_name: &[u8], sigs: &[&str])
+            -> Result<()>;
+
+        /// Returns the system a derivation is built for.
+        ///
+        /// The derivation must be present in the store.
+        fn derivation_system(self: Pin<&mut CNixStore>, base_name: &[u8]) -> Result<String>;
+
         /// Creates a NAR dump from a path.
         fn nar_from_path(
             self: Pin<&mut CNixStore>,
@@ -226,6 +328,25 @@ mod ffi {
             sender: Box<AsyncWriteSender>,
         ) -> Result<()>;
 
+        /// Imports a NAR into the store.
+        ///
+        /// The NAR is read from `receiver`, and the rest of the
+        /// arguments make up the path info. `deriver` and `ca` are
+        /// empty if unknown.
+        #[allow(clippy::too_many_arguments)]
+        fn import_nar(
+            self: Pin<&mut CNixStore>,
+            base_name: &[u8],
+            nar_sha256_hash: &[u8],
+            nar_size: u64,
+            references: &[&[u8]],
+            deriver: &[u8],
+            sigs: &[&str],
+            ca: &str,
+            check_sigs: bool,
+            receiver: Box<AsyncReadReceiver>,
+        ) -> Result<()>;
+
         /// Obtains a handle to the Nix store.
         fn open_nix_store() -> Result<UniquePtr<CNixStore>>;
 
@@ -250,5 +371,10 @@ mod ffi {
 
         /// Returns the CA field of the store path.
         fn ca(self: Pin<&mut CPathInfo>) -> String;
+
+        /// Returns the base name of the deriver of the store path.
+        ///
+        /// Returns an empty string if the deriver is unknown.
+        fn deriver(self: Pin<&mut CPathInfo>) -> String;
     }
 }
@@ -26,6 +26,21 @@ static bool hash_is_sha256(const nix::Hash &hash) {
 #endif
 }
 
+static nix::Hash sha256_hash_from_rust(RHashSlice hash_slice) {
+#ifdef ATTIC_NIX_2_20
+	nix::Hash hash(nix::HashAlgorithm::SHA256);
+#else
+	nix::Hash hash(nix::htSHA256);
+#endif
+
+	if (hash_slice.size() != hash.hashSize) {
+		throw nix::Error("Invalid SHA-256 hash length");
+	}
+
+	std::memcpy(hash.hash, hash_slice.data(), hash.hashSize);
+	return hash;
+}
+
 // ========
 // RustSink
 // ========
@@ -43,6 +58,24 @@ void RustSink::eof() {
 }
 
 
+// ==========
+// RustSource
+// ==========
+
+RustSource::RustSource(RBox<AsyncReadReceiver> receiver) : receiver(std::move(receiver)) {}
+
+size_t RustSource::read(char *data, size_t len) {
+	RSlice<unsigned char> s((unsigned char *)data, len);
+
+	// errors sent by Rust are thrown here
+	auto n = this->receiver->recv(s);
+	if (n == 0) {
+		throw nix::EndOfFile("Unexpected end of NAR");
+	}
+
+	return n;
+}
+
 // =========
 // CPathInfo
 // =========
@@ -87,6 +120,14 @@ RString CPathInfo::ca() {
 	}
 }
 
+RString CPathInfo::deriver() {
+	if (this->pi->deriver) {
+		return RString(std::string(this->pi->deriver->to_string()));
+	} else {
+		return RString("");
+	}
+}
+
 // =========
 // CNixStore
 // =========
@@ -114,6 +155,18 @@ std::unique_ptr<CPathInfo> CNixStore::query_path_info(RBasePathSlice base_name)
 	return std::make_unique<CPathInfo>(r);
 }
 
+std::unique_ptr<std::vector<CPathInfo>> CNixStore::query_path_infos(RSlice<const RBasePathSlice> base_names) {
+	auto result = std::make_unique<std::vector<CPathInfo>>();
+	result->reserve(base_names.size());
+
+	for (auto&& base_name : base_names) {
+		auto r = this->store->queryPathInfo(store_path_from_rust(base_name));
+		result->push_back(CPathInfo(r));
+	}
+
+	return result;
+}
+
 std::unique_ptr<std::vector<std::string>> CNixStore::compute_fs_closure(RBasePathSlice base_name, bool flip_direction, bool include_outputs, bool include_derivers) {
 	std::set<nix::StorePath> out;
 
@@ -141,6 +194,20 @@ std::unique_ptr<std::vector<std::string>> CNixStore::compute_fs_closure_multi(RS
 	return std::make_unique<std::vector<std::string>>(result);
 }
 
+void CNixStore::add_signatures(RBasePathSlice base_name, RSlice<const RStr> sigs) {
+	nix::StringSet sig_set;
+	for (auto&& sig : sigs) {
+		sig_set.insert(std::string(sig));
+	}
+
+	this->store->addSignatures(store_path_from_rust(base_name), sig_set);
+}
+
+RString CNixStore::derivation_system(RBasePathSlice base_name) {
+	auto drv = this->store->readDerivation(store_path_from_rust(base_name));
+	return RString(drv.platform);
+}
+
 void CNixStore::nar_from_path(RVec<unsigned char> base_name, RBox<AsyncWriteSender> sender) {
 	RustSink sink(std::move(sender));
 
@@ -152,6 +219,42 @@ void CNixStore::nar_from_path(RVec<unsigned char> base_name, RBox<AsyncWriteSend
 	sink.eof();
 }
 
+void CNixStore::import_nar(
+	RBasePathSlice base_name,
+	RHashSlice nar_sha256_hash,
+	uint64_t nar_size,
+	RSlice<const RBasePathSlice> references,
+	RBasePathSlice deriver,
+	RSlice<const RStr> sigs,
+	RStr ca,
+	bool check_sigs,
+	RBox<AsyncReadReceiver> receiver)
+{
+	RustSource source(std::move(receiver));
+
+	nix::ValidPathInfo info(store_path_from_rust(base_name), sha256_hash_from_rust(nar_sha256_hash));
+	info.narSize = nar_size;
+
+	for (auto&& reference : references) {
+		info.references.insert(store_path_from_rust(reference));
+	}
+
+	if (deriver.size() != 0) {
+		info.deriver = store_path_from_rust(deriver);
+	}
+
+	for (auto&& sig : sigs) {
+		info.sigs.insert(std::string(sig));
+	}
+
+	if (ca.size() != 0) {
+		info.ca = nix::ContentAddress::parseOpt(std::string(ca));
+	}
+
+	// exceptions will be thrown into Rust
+	this->store->addToStore(info, source, nix::NoRepair, check_sigs ? nix::CheckSigs : nix::NoCheckSigs);
+}
+
 std::unique_ptr<CNixStore> open_nix_store() {
 	return std::make_unique<CNixStore>();
 }
@@ -9,11 +9,13 @@
 // satisfying to use from the Rust side via cxx.rs.
 
 #pragma once
+#include <cstring>
 #include <iostream>
 #include <memory>
 #include <mutex>
 #include <set>
 #include <nix/store-api.hh>
+#include <nix/derivations.hh>
 #include <nix/local-store.hh>
 #include <nix/remote-store.hh>
 #include <nix/uds-remote-store.hh>
@@ -42,6 +44,16 @@ public:
 	void eof();
 };
 
+struct AsyncReadReceiver;
+
+struct RustSource : nix::Source
+{
+	RBox<AsyncReadReceiver> receiver;
+public:
+	RustSource(RBox<AsyncReadReceiver> receiver);
+	size_t read(char *data, size_t len) override;
+};
+
 // Opaque wrapper for nix::ValidPathInfo
 class CPathInfo {
 	nix::ref<const nix::ValidPathInfo> pi;
@@ -52,6 +64,7 @@ public:
 	std::unique_ptr<std::vector<std::string>> sigs();
 	std::unique_ptr<std::vector<std::string>> references();
 	RString ca();
+	RString deriver();
 };
 
 class CNixStore {
@@ -61,6 +74,7 @@ public:
 
 	RString store_dir();
 	std::unique_ptr<CPathInfo> query_path_info(RBasePathSlice base_name);
+	std::unique_ptr<std::vector<CPathInfo>> query_path_infos(RSlice<const RBasePathSlice> base_names);
 	std::unique_ptr<std::vector<std::string>> compute_fs_closure(
 		RBasePathSlice base_name,
 		bool flip_direction,
@@ -71,7 +85,19 @@ public:
 		bool flip_direction,
 		bool include_outputs,
 		bool include_derivers);
+	void add_signatures(RBasePathSlice base_name, RSlice<const RStr> sigs);
+	RString derivation_system(RBasePathSlice base_name);
 	void nar_from_path(RVec<unsigned char> base_name, RBox<AsyncWriteSender> sender);
+	void import_nar(
+		RBasePathSlice base_name,
+		RHashSlice nar_sha256_hash,
+		uint64_t nar_size,
+		RSlice<const RBasePathSlice> references,
+		RBasePathSlice deriver,
+		RSlice<const RStr> sigs,
+		RStr ca,
+		bool check_sigs,
+		RBox<AsyncReadReceiver> receiver);
 };
 
 std::unique_ptr<CNixStore> open_nix_store();
@@ -0,0 +1,165 @@
+//! Content addresses.
+//!
+//! Content-addressed store paths carry a `CA` field describing how the
+//! path was hashed:
+//!
+//! - `text:sha256:<hash>`: A text file, like a derivation. Only SHA-256 is allowed.
+//! - `fixed:<algo>:<hash>`: A fixed-output path hashed as a flat file.
+//! - `fixed:r:<algo>:<hash>`: A fixed-output path hashed as a NAR.
+//!
+//! Consult `src/libstore/content-address.cc` for the Nix implementation.
+
+use std::fmt;
+
+use crate::error::{AtticError, AtticResult};
+use crate::hash::Hash;
+
+/// A content address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContentAddress {
+    /// Text hashing, used for derivations and `builtins.toFile`.
+    Text(Hash),
+
+    /// Fixed-output hashing.
+    Fixed {
+        /// How the path was serialized before hashing.
+        method: FileIngestionMethod,
+
+        /// The hash.
+        hash: Hash,
+    },
+}
+
+/// How a fixed-output path was serialized before hashing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileIngestionMethod {
+    /// The contents of a single file.
+    Flat,
+
+    /// The NAR serialization of the path.
+    Recursive,
+}
+
+impl ContentAddress {
+    /// Parses a content address.
+    pub fn parse(ca: &str) -> AtticResult<Self> {
+        let invalid = |reason| AtticError::InvalidContentAddress {
+            ca: ca.to_owned(),
+            reason,
+        };
+
+        let parse_hash = |hash| Hash::from_typed(hash).map_err(|_| invalid("Invalid hash"));
+
+        let (prefix, rest) = ca
+            .split_once(':')
+            .ok_or_else(|| invalid("No method prefix"))?;
+
+        match prefix {
+            "text" => {
+                let hash = parse_hash(rest)?;
+                if !matches!(hash, Hash::Sha256(_)) {
+                    return Err(invalid("Text hashing must use SHA-256"));
+                }
+
+                Ok(Self::Text(hash))
+            }
+            "fixed" => {
+                let (method, hash) = match rest.strip_prefix("r:") {
+                    Some(hash) => (FileIngestionMethod::Recursive, hash),
+                    None => (FileIngestionMethod::Flat, rest),
+                };
+
+                Ok(Self::Fixed {
+                    method,
+                    hash: parse_hash(hash)?,
+                })
+            }
+            _ => Err(invalid("Unknown method")),
+        }
+    }
+
+    /// Returns the hash.
+    pub fn hash(&self) -> &Hash {
+        match self {
+            Self::Text(hash) => hash,
+            Self::Fixed { hash, .. } => hash,
+        }
+    }
+}
+
+impl fmt::Display for ContentAddress {
+    /// Renders the content address like Nix, with a Base32 hash.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Text(hash) => write!(f, "text:{}", hash.to_typed_base32()),
+            Self::Fixed {
+                method: FileIngestionMethod::Flat,
+                hash,
+            } => write!(f, "fixed:{}", hash.to_typed_base32()),
+            Self::Fixed {
+                method: FileIngestionMethod::Recursive,
+                hash,
+            } => write!(f, "fixed:r:{}", hash.to_typed_base32()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256: &str = "sha256:094qif9n4cq4fdg459qzbhg1c6wywawwaaivx0k0x8xhbyx4vwic";
+    const SHA1: &str = "sha1:9m1skbnr5i43n3yypvda5s65vhfwdx5a";
+
+    #[test]
+    fn test_content_address_roundtrip() {
+        for ca in [
+            format!("text:{}", SHA256),
+            format!("fixed:{}", SHA256),
+            format!("fixed:r:{}", SHA256),
+            format!("fixed:r:{}", SHA1),
+        ] {
+            assert_eq!(ca, ContentAddress::parse(&ca).unwrap().to_string());
+        }
+
+        let ca = ContentAddress::parse(&format!("fixed:r:{}", SHA256)).unwrap();
+        assert_eq!(
+            ContentAddress::Fixed {
+                method: FileIngestionMethod::Recursive,
+                hash: Hash::from_typed(SHA256).unwrap(),
+            },
+            ca
+        );
+
+        // Base16 hashes are accepted but rendered in Base32
+        let ca = ContentAddress::parse(
+            "text:sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+        )
+        .unwrap();
+        assert_eq!(format!("text:{}", SHA256), ca.to_string());
+    }
+
+    #[test]
+    fn test_content_address_invalid() {
+        for ca in [
+            "",
+            "sha256",
+            format!("nar:{}", SHA256).as_str(),
+            format!("text:{}", SHA1).as_str(),
+            "text:sha256:",
+            "fixed:sha256",
+            "fixed:r:sha256",
+            "fixed:md5:00000000000000000000000000000000",
+            "fixed:r:r:sha256:094qif9n4cq4fdg459qzbhg1c6wywawwaaivx0k0x8xhbyx4vwic",
+        ] {
+            assert!(
+                matches!(
+                    ContentAddress::parse(ca),
+                    Err(AtticError::InvalidContentAddress { .. })
+                ),
+                "{}",
+                ca
+            );
+        }
+    }
+}
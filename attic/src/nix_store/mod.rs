@@ -16,7 +16,14 @@
 //!
 //! The C++-side code is responsible for translating the calls
 //! into actual `libnixstore` invocations which are version-specific.
-//! (we target Nix 2.4 and 2.5).
+//! We support Nix 2.4 and later, and CI builds against 2.18, 2.20
+//! and 2.24. The build script detects the version through pkg-config
+//! and works around the releases that broke us:
+//!
+//! - 2.19: Headers include siblings without the `nix/` prefix,
+//!   so `include/nix` is also on the include path
+//! - 2.20: `HashType` was renamed to `HashAlgorithm`, gated behind
+//!   the `ATTIC_NIX_2_20` define
 //!
 //! We have the following goals:
 //! - Retrieval of store path information
@@ -43,12 +50,15 @@
 #[allow(unsafe_code)]
 mod bindings;
 
+mod content_address;
+
+#[cfg(all(feature = "nar", target_family = "unix"))]
+pub mod nar;
+
 #[cfg(feature = "nix_store")]
 mod nix_store;
 
 use std::ffi::OsStr;
-#[cfg(target_family = "unix")]
-use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 
 use lazy_static::lazy_static;
@@ -58,6 +68,7 @@ use serde::{de, Deserialize, Serialize};
 use crate::error::{AtticError, AtticResult};
 use crate::hash::Hash;
 
+pub use content_address::{ContentAddress, FileIngestionMethod};
 #[cfg(feature = "nix_store")]
 pub use nix_store::NixStore;
 
@@ -148,12 +159,15 @@ pub struct ValidPathInfo {
 
     /// Content Address.
     pub ca: Option<String>,
+
+    /// The derivation that produced the store path, if known.
+    pub deriver: Option<StorePath>,
 }
 
 #[cfg_attr(not(feature = "nix_store"), allow(dead_code))]
 impl StorePath {
     /// Creates a StorePath with a base name.
-    fn from_base_name(base_name: PathBuf) -> AtticResult<Self> {
+    pub fn from_base_name(base_name: PathBuf) -> AtticResult<Self> {
         let s = base_name
             .as_os_str()
             .to_str()
@@ -172,6 +186,24 @@ impl StorePath {
         Ok(Self { base_name })
     }
 
+    /// Creates a StorePath from a full path directly in a store directory.
+    ///
+    /// Unlike `NixStore::parse_store_path`, this rejects paths inside
+    /// a store path as well as non-canonical spellings like trailing
+    /// slashes, so the result can be compared with the original string.
+    pub fn from_full_path(store_dir: &Path, path: &Path) -> AtticResult<Self> {
+        let base_name = to_base_name(store_dir, path)?;
+
+        if store_dir.join(&base_name).as_os_str() != path.as_os_str() {
+            return Err(AtticError::InvalidStorePath {
+                path: path.to_owned(),
+                reason: "Path is not directly in store directory",
+            });
+        }
+
+        Self::from_base_name(base_name)
+    }
+
     /// Creates a StorePath with a known valid base name.
     ///
     /// # Safety
@@ -185,36 +217,49 @@ impl StorePath {
     }
 
     /// Gets the hash portion of the store path.
-    #[cfg(target_family = "unix")]
     pub fn to_hash(&self) -> StorePathHash {
+        let hash = self.as_base_name_str()[..STORE_PATH_HASH_LEN].to_string();
+
         // Safety: We have already validated the format of the base name,
-        // including the hash part. The name is guaranteed valid UTF-8.
+        // including the hash part.
         #[allow(unsafe_code)]
         unsafe {
-            let s = std::str::from_utf8_unchecked(self.base_name.as_os_str().as_bytes());
-            let hash = s[..STORE_PATH_HASH_LEN].to_string();
             StorePathHash::new_unchecked(hash)
         }
     }
 
     /// Returns the human-readable name.
-    #[cfg(target_family = "unix")]
     pub fn name(&self) -> String {
-        // Safety: Already checked
+        self.as_base_name_str()[STORE_PATH_HASH_LEN + 1..].to_string()
+    }
+
+    pub fn as_os_str(&self) -> &OsStr {
+        self.base_name.as_os_str()
+    }
+
+    /// Returns the base name as a string.
+    #[cfg(target_family = "unix")]
+    fn as_base_name_str(&self) -> &str {
+        // Safety: The name is guaranteed valid UTF-8 since it has
+        // been validated against `STORE_BASE_NAME_REGEX`.
         #[allow(unsafe_code)]
         unsafe {
-            let s = std::str::from_utf8_unchecked(self.base_name.as_os_str().as_bytes());
-            s[STORE_PATH_HASH_LEN + 1..].to_string()
+            std::str::from_utf8_unchecked(self.as_base_name_bytes())
         }
     }
 
-    pub fn as_os_str(&self) -> &OsStr {
-        self.base_name.as_os_str()
+    /// Returns the base name as a string.
+    #[cfg(not(target_family = "unix"))]
+    fn as_base_name_str(&self) -> &str {
+        self.base_name
+            .to_str()
+            .expect("Store path base name is not valid UTF-8")
     }
 
-    #[cfg_attr(not(feature = "nix_store"), allow(dead_code))]
     #[cfg(target_family = "unix")]
     fn as_base_name_bytes(&self) -> &[u8] {
+        use std::os::unix::ffi::OsStrExt;
+
         self.base_name.as_os_str().as_bytes()
     }
 }
@@ -0,0 +1,281 @@
+//! Pure-Rust NAR serialization.
+//!
+//! This dumps a path on the local filesystem into the Nix Archive
+//! format without going through `libnixstore`. The output is identical
+//! to `nix-store --dump`, so NAR hashes computed from it can be used
+//! for signing.
+//!
+//! The format is a sequence of length-prefixed strings, each padded
+//! to a multiple of 8 bytes:
+//!
+//! ```text
+//! nar       = "nix-archive-1" node
+//! node      = "(" "type" type ")"
+//! type      = "regular" ["executable" ""] "contents" contents
+//!           | "symlink" "target" target
+//!           | "directory" { "entry" "(" "name" name "node" node ")" }
+//! ```
+//!
+//! Directory entries are sorted by name. Only Unix is supported since
+//! we need the executable bit and raw file names.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use futures::stream::{self, Stream};
+use tokio::sync::mpsc;
+use tokio::task::spawn_blocking;
+
+use crate::error::AtticResult;
+
+/// The magic string at the start of every NAR.
+const NAR_VERSION_MAGIC: &[u8] = b"nix-archive-1";
+
+/// Size of the chunks emitted by `dump_path_stream`.
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Number of chunks buffered by `dump_path_stream`.
+const STREAM_BUFFER: usize = 8;
+
+/// Dumps a path as a NAR.
+///
+/// Symlinks are not followed, so if `path` is a symlink, the NAR
+/// contains the symlink itself.
+pub fn dump_path<W: Write>(path: &Path, writer: W) -> AtticResult<()> {
+    let mut writer = BufWriter::new(writer);
+
+    write_str(&mut writer, NAR_VERSION_MAGIC)?;
+    dump_node(&mut writer, path)?;
+
+    writer.flush()?;
+    Ok(())
+}
+
+/// Dumps a path as a stream of NAR chunks.
+///
+/// The dump is done on a blocking thread. Like `NixStore::nar_from_path`,
+/// the stream yields `Vec<u8>` chunks.
+pub fn dump_path_stream(path: PathBuf) -> impl Stream<Item = AtticResult<Vec<u8>>> {
+    let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+
+    spawn_blocking(move || {
+        let writer = ChannelWriter {
+            sender: sender.clone(),
+        };
+
+        // The receiver may have been dropped, in which case there
+        // is nobody to report the error to
+        if let Err(e) = dump_path(&path, BufWriter::with_capacity(STREAM_CHUNK_SIZE, writer)) {
+            let _ = sender.blocking_send(Err(e));
+        }
+    });
+
+    stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|item| (item, receiver))
+    })
+}
+
+fn dump_node<W: Write>(writer: &mut W, path: &Path) -> AtticResult<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    let file_type = metadata.file_type();
+
+    write_str(writer, b"(")?;
+
+    if file_type.is_file() {
+        write_str(writer, b"type")?;
+        write_str(writer, b"regular")?;
+
+        if metadata.permissions().mode() & 0o100 != 0 {
+            write_str(writer, b"executable")?;
+            write_str(writer, b"")?;
+        }
+
+        write_str(writer, b"contents")?;
+        dump_contents(writer, path, metadata.len())?;
+    } else if file_type.is_symlink() {
+        let target = fs::read_link(path)?;
+
+        write_str(writer, b"type")?;
+        write_str(writer, b"symlink")?;
+        write_str(writer, b"target")?;
+        write_str(writer, target.as_os_str().as_bytes())?;
+    } else if file_type.is_dir() {
+        write_str(writer, b"type")?;
+        write_str(writer, b"directory")?;
+
+        let mut names = fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<io::Result<Vec<_>>>()?;
+        names.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+
+        for name in names {
+            write_str(writer, b"entry")?;
+            write_str(writer, b"(")?;
+            write_str(writer, b"name")?;
+            write_str(writer, name.as_bytes())?;
+            write_str(writer, b"node")?;
+            dump_node(writer, &path.join(&name))?;
+            write_str(writer, b")")?;
+        }
+    } else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} cannot be archived", path.display()),
+        )
+        .into());
+    }
+
+    write_str(writer, b")")?;
+
+    Ok(())
+}
+
+/// Writes the contents of a regular file.
+///
+/// The file must not change size while it's being dumped, or the
+/// NAR would be corrupt.
+fn dump_contents<W: Write>(writer: &mut W, path: &Path, size: u64) -> AtticResult<()> {
+    writer.write_all(&size.to_le_bytes())?;
+
+    let copied = io::copy(&mut File::open(path)?.take(size), writer)?;
+    if copied != size {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("{} changed size while being archived", path.display()),
+        )
+        .into());
+    }
+
+    write_padding(writer, size)?;
+
+    Ok(())
+}
+
+fn write_str<W: Write>(writer: &mut W, s: &[u8]) -> io::Result<()> {
+    writer.write_all(&(s.len() as u64).to_le_bytes())?;
+    writer.write_all(s)?;
+    write_padding(writer, s.len() as u64)
+}
+
+fn write_padding<W: Write>(writer: &mut W, len: u64) -> io::Result<()> {
+    let padding = (8 - len % 8) % 8;
+    writer.write_all(&[0; 8][..padding as usize])
+}
+
+/// A writer that sends everything through a channel.
+struct ChannelWriter {
+    sender: mpsc::Sender<AtticResult<Vec<u8>>>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sender
+            .blocking_send(Ok(buf.to_vec()))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::symlink;
+
+    use futures::stream::StreamExt;
+
+    const NARS: &[&str] = &[
+        "nm1w9sdm6j6icmhd2q3260hl1w9zj6li-attic-test-no-deps",
+        "n7q4i7rlmbk4xz8qdsxpm6jbhrnxraq2-attic-test-with-deps-a",
+        "544qcchwgcgpz3xi1bbml28f8jj6009p-attic-test-with-deps-b",
+        "3k1wymic8p7h5pfcqfhh0jan8ny2a712-attic-test-with-deps-c-final",
+    ];
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/nix_store/tests/nar")
+            .join(name)
+    }
+
+    fn dump(path: &Path) -> Vec<u8> {
+        let mut nar = Vec::new();
+        dump_path(path, &mut nar).unwrap();
+        nar
+    }
+
+    /// Builds a NAR by hand.
+    fn nar(tokens: &[&[u8]]) -> Vec<u8> {
+        let mut nar = Vec::new();
+        write_str(&mut nar, NAR_VERSION_MAGIC).unwrap();
+        for token in tokens {
+            write_str(&mut nar, token).unwrap();
+        }
+        nar
+    }
+
+    #[test]
+    fn test_dump_fixtures() {
+        for name in NARS {
+            let expected = fs::read(fixture(&format!("{}.nar", name))).unwrap();
+            assert!(expected == dump(&fixture(name)), "{} differs", name);
+        }
+    }
+
+    #[test]
+    fn test_dump_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+
+        fs::create_dir_all(root.join("bin")).unwrap();
+        fs::write(root.join("bin/hello"), b"#!/bin/sh\n").unwrap();
+        fs::set_permissions(root.join("bin/hello"), fs::Permissions::from_mode(0o555)).unwrap();
+        fs::write(root.join("a"), b"").unwrap();
+        symlink("bin/hello", root.join("B")).unwrap();
+
+        #[rustfmt::skip]
+        let expected = nar(&[
+            b"(", b"type", b"directory",
+                b"entry", b"(", b"name", b"B", b"node",
+                    b"(", b"type", b"symlink", b"target", b"bin/hello", b")",
+                b")",
+                b"entry", b"(", b"name", b"a", b"node",
+                    b"(", b"type", b"regular", b"contents", b"", b")",
+                b")",
+                b"entry", b"(", b"name", b"bin", b"node",
+                    b"(", b"type", b"directory",
+                        b"entry", b"(", b"name", b"hello", b"node",
+                            b"(", b"type", b"regular", b"executable", b"", b"contents", b"#!/bin/sh\n", b")",
+                        b")",
+                    b")",
+                b")",
+            b")",
+        ]);
+
+        assert_eq!(expected, dump(&root));
+    }
+
+    #[tokio::test]
+    async fn test_dump_path_stream() {
+        let name = NARS[0];
+        let expected = fs::read(fixture(&format!("{}.nar", name))).unwrap();
+
+        let mut nar = Vec::new();
+        let mut stream = Box::pin(dump_path_stream(fixture(name)));
+        while let Some(chunk) = stream.next().await {
+            nar.extend(chunk.unwrap());
+        }
+        assert_eq!(expected, nar);
+
+        // Errors end the stream
+        let results: Vec<_> = dump_path_stream(fixture("does-not-exist")).collect().await;
+        assert!(results.last().unwrap().is_err());
+    }
+}
@@ -1,17 +1,27 @@
 //! High-level Nix Store interface.
 
+use std::collections::HashMap;
 use std::ffi::OsStr;
+use std::io;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::sync::Arc;
 
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt};
 use tokio::task::spawn_blocking;
 
-use super::bindings::{open_nix_store, AsyncWriteAdapter, FfiNixStore};
-use super::{to_base_name, StorePath, ValidPathInfo};
-use crate::error::AtticResult;
+use super::bindings::{
+    open_nix_store, AsyncReadAdapter, AsyncWriteAdapter, CPathInfo, FfiNixStore,
+};
+use super::{to_base_name, StorePath, StorePathHash, ValidPathInfo};
+use crate::error::{AtticError, AtticResult};
 use crate::hash::Hash;
 
+/// Size of the chunks `import_nar` sends to Nix.
+const IMPORT_CHUNK_SIZE: usize = 64 * 1024;
+
 /// High-level wrapper for the Unix Domain Socket Nix Store.
 pub struct NixStore {
     /// The Nix store FFI.
@@ -103,6 +113,74 @@ impl NixStore {
         adapter
     }
 
+    /// Imports a NAR into the store.
+    ///
+    /// This is the counterpart of `nar_from_path`. The NAR is checked
+    /// against the hash and size in `path_info` before Nix is allowed
+    /// to finish the import, and the references of the path must
+    /// already be valid.
+    ///
+    /// If `check_sigs` is true, the path must be signed by a key
+    /// trusted by the store. The Nix daemon always checks signatures
+    /// for untrusted users.
+    pub async fn import_nar<R: AsyncRead + Unpin>(
+        &self,
+        path_info: ValidPathInfo,
+        mut reader: R,
+        check_sigs: bool,
+    ) -> AtticResult<()> {
+        let Hash::Sha256(nar_sha256_hash) = path_info.nar_hash else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Only SHA-256 NAR hashes are supported",
+            )
+            .into());
+        };
+
+        let inner = self.inner.clone();
+        let nar_size = path_info.nar_size;
+        let (adapter, receiver) = AsyncReadAdapter::new();
+
+        let import = spawn_blocking(move || {
+            let references: Vec<&[u8]> = path_info
+                .references
+                .iter()
+                .map(|r| r.as_os_str().as_bytes())
+                .collect();
+            let deriver = path_info
+                .deriver
+                .as_ref()
+                .map(|d| d.as_base_name_bytes())
+                .unwrap_or_default();
+            let sigs: Vec<&str> = path_info.sigs.iter().map(String::as_str).collect();
+
+            inner.store().import_nar(
+                path_info.path.as_base_name_bytes(),
+                &nar_sha256_hash,
+                nar_size,
+                &references,
+                deriver,
+                &sigs,
+                path_info.ca.as_deref().unwrap_or_default(),
+                check_sigs,
+                receiver,
+            )?;
+
+            Ok(())
+        });
+
+        let fed = feed_nar(&adapter, &mut reader, &nar_sha256_hash, nar_size).await;
+        if let Err(e) = &fed {
+            // Abort the import
+            adapter.error(e).await;
+        }
+        drop(adapter);
+
+        let imported = import.await.unwrap();
+        fed?;
+        imported
+    }
+
     /// Returns the closure of a valid path.
     ///
     /// If `flip_directions` is true, the set of paths that can reach `store_path` is
@@ -191,6 +269,47 @@ impl NixStore {
         .unwrap()
     }
 
+    /// Adds signatures to a valid path.
+    ///
+    /// This requires the user to be trusted by the Nix daemon.
+    pub async fn add_signatures(
+        &self,
+        store_path: StorePath,
+        sigs: Vec<String>,
+    ) -> AtticResult<()> {
+        let inner = self.inner.clone();
+
+        spawn_blocking(move || {
+            let sigs: Vec<&str> = sigs.iter().map(String::as_str).collect();
+            inner
+                .store()
+                .add_signatures(store_path.as_base_name_bytes(), &sigs)?;
+
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Returns the system a derivation is built for.
+    ///
+    /// This fails if the derivation is not present in the store,
+    /// which is common since derivations are often garbage-collected
+    /// or never copied along with their outputs.
+    pub async fn query_derivation_system(&self, drv_path: StorePath) -> AtticResult<String> {
+        let inner = self.inner.clone();
+
+        spawn_blocking(move || {
+            let system = inner
+                .store()
+                .derivation_system(drv_path.as_base_name_bytes())?;
+
+            Ok(system)
+        })
+        .await
+        .unwrap()
+    }
+
     /// Returns detailed information on a path.
     pub async fn query_path_info(&self, store_path: StorePath) -> AtticResult<ValidPathInfo> {
         let inner = self.inner.clone();
@@ -199,40 +318,153 @@ impl NixStore {
             let base_name = store_path.as_base_name_bytes();
             let mut c_path_info = inner.store().query_path_info(base_name)?;
 
-            // FIXME: Make this more ergonomic and efficient
-            let nar_size = c_path_info.pin_mut().nar_size();
-            let nar_sha256_hash: [u8; 32] =
-                c_path_info.pin_mut().nar_sha256_hash().try_into().unwrap();
-            let references = c_path_info
-                .pin_mut()
-                .references()
+            convert_path_info(store_path, c_path_info.pin_mut())
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Returns detailed information on a list of paths.
+    ///
+    /// Unlike calling `query_path_info` for each path, this queries
+    /// all paths in a single blocking task and FFI call.
+    pub async fn query_path_infos(
+        &self,
+        store_paths: Vec<StorePath>,
+    ) -> AtticResult<HashMap<StorePathHash, ValidPathInfo>> {
+        let inner = self.inner.clone();
+
+        spawn_blocking(move || {
+            let plain_base_names: Vec<&[u8]> = store_paths
                 .iter()
-                .map(|s| {
-                    let osstr = OsStr::from_bytes(s.as_bytes());
-                    PathBuf::from(osstr)
-                })
+                .map(|sp| sp.as_base_name_bytes())
                 .collect();
-            let sigs = c_path_info
-                .pin_mut()
-                .sigs()
+
+            let mut cxx_vector = inner.store().query_path_infos(&plain_base_names)?;
+
+            store_paths
                 .iter()
-                .map(|s| {
-                    let osstr = OsStr::from_bytes(s.as_bytes());
-                    osstr.to_str().unwrap().to_string()
+                .zip(cxx_vector.pin_mut().iter_mut())
+                .map(|(store_path, c_path_info)| {
+                    convert_path_info(store_path.clone(), c_path_info)
+                        .map(|path_info| (store_path.to_hash(), path_info))
                 })
-                .collect();
-            let ca = c_path_info.pin_mut().ca();
-
-            Ok(ValidPathInfo {
-                path: store_path,
-                nar_size,
-                nar_hash: Hash::Sha256(nar_sha256_hash),
-                references,
-                sigs,
-                ca: if ca.is_empty() { None } else { Some(ca) },
-            })
+                .collect()
         })
         .await
         .unwrap()
     }
 }
+
+/// Sends a NAR to an import, verifying its hash and size.
+///
+/// The last chunk is held back until the whole NAR is verified, so
+/// the import can't complete with a bad NAR. If the import stops
+/// reading early, this returns successfully and the import reports
+/// the error.
+async fn feed_nar<R: AsyncRead + Unpin>(
+    adapter: &AsyncReadAdapter,
+    reader: &mut R,
+    nar_sha256_hash: &[u8; 32],
+    nar_size: u64,
+) -> AtticResult<()> {
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    let mut pending = None;
+
+    loop {
+        let mut buf = vec![0; IMPORT_CHUNK_SIZE];
+        let read = reader.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+
+        buf.truncate(read);
+        hasher.update(&buf);
+        size += read as u64;
+
+        if size > nar_size {
+            return Err(nar_mismatch(format!(
+                "NAR is larger than the expected {} bytes",
+                nar_size
+            )));
+        }
+
+        if let Some(chunk) = pending.replace(buf) {
+            if !adapter.send(chunk).await {
+                return Ok(());
+            }
+        }
+    }
+
+    if size != nar_size {
+        return Err(nar_mismatch(format!(
+            "NAR has {} bytes, expected {}",
+            size, nar_size
+        )));
+    }
+
+    let actual = Hash::Sha256(hasher.finalize().into());
+    if actual != Hash::Sha256(*nar_sha256_hash) {
+        return Err(nar_mismatch(format!(
+            "NAR has hash {}, expected {}",
+            actual.to_typed_base32(),
+            Hash::Sha256(*nar_sha256_hash).to_typed_base32()
+        )));
+    }
+
+    if let Some(chunk) = pending {
+        adapter.send(chunk).await;
+    }
+
+    Ok(())
+}
+
+fn nar_mismatch(reason: String) -> AtticError {
+    io::Error::new(io::ErrorKind::InvalidData, reason).into()
+}
+
+/// Converts path information returned by the FFI.
+fn convert_path_info(
+    store_path: StorePath,
+    mut c_path_info: Pin<&mut CPathInfo>,
+) -> AtticResult<ValidPathInfo> {
+    // FIXME: Make this more ergonomic and efficient
+    let nar_size = c_path_info.as_mut().nar_size();
+    let nar_sha256_hash: [u8; 32] = c_path_info.as_mut().nar_sha256_hash().try_into().unwrap();
+    let references = c_path_info
+        .as_mut()
+        .references()
+        .iter()
+        .map(|s| {
+            let osstr = OsStr::from_bytes(s.as_bytes());
+            PathBuf::from(osstr)
+        })
+        .collect();
+    let sigs = c_path_info
+        .as_mut()
+        .sigs()
+        .iter()
+        .map(|s| {
+            let osstr = OsStr::from_bytes(s.as_bytes());
+            osstr.to_str().unwrap().to_string()
+        })
+        .collect();
+    let ca = c_path_info.as_mut().ca();
+    let deriver = c_path_info.as_mut().deriver();
+    let deriver = if deriver.is_empty() {
+        None
+    } else {
+        Some(StorePath::from_base_name(PathBuf::from(deriver))?)
+    };
+
+    Ok(ValidPathInfo {
+        path: store_path,
+        nar_size,
+        nar_hash: Hash::Sha256(nar_sha256_hash),
+        references,
+        sigs,
+        ca: if ca.is_empty() { None } else { Some(ca) },
+        deriver,
+    })
+}
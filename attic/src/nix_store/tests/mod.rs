@@ -243,4 +243,36 @@ async fn test_query_path_info() {
         ),],
         path_info.references
     );
+
+    // Paths imported from NARs don't have derivers
+    assert!(path_info.deriver.is_none());
+}
+
+#[tokio::test]
+async fn test_query_path_infos() {
+    use test_nar::{WITH_DEPS_B, WITH_DEPS_C};
+
+    let store = NixStore::connect().expect("Failed to connect to the Nix store");
+
+    let mut paths = Vec::new();
+    for nar in [WITH_DEPS_C, WITH_DEPS_B] {
+        nar.import().await.expect("Could not import test NAR");
+        paths.push(store.parse_store_path(nar.path()).unwrap());
+    }
+
+    let path_infos = store
+        .query_path_infos(paths.clone())
+        .await
+        .expect("Could not query path infos");
+
+    assert_eq!(2, path_infos.len());
+    for path in paths {
+        let expected = store.query_path_info(path.clone()).await.unwrap();
+        let path_info = &path_infos[&path.to_hash()];
+
+        assert_eq!(expected.path, path_info.path);
+        assert_eq!(expected.nar_hash, path_info.nar_hash);
+        assert_eq!(expected.nar_size, path_info.nar_size);
+        assert_eq!(expected.references, path_info.references);
+    }
 }
@@ -21,7 +21,9 @@
 //! `Serialize` and `Deserialize` are implemented to convert the structs
 //! from and to the canonical format.
 
+use std::borrow::Cow;
 use std::convert::TryInto;
+use std::path::Path;
 
 use serde::{de, ser, Deserialize, Serialize};
 
@@ -30,6 +32,7 @@ use displaydoc::Display;
 use ed25519_compact::{Error as SignatureError, KeyPair, PublicKey, Signature};
 
 use crate::error::AtticResult;
+use crate::hash::Hash;
 
 #[cfg(test)]
 mod tests;
@@ -165,6 +168,71 @@ impl NixKeypair {
     }
 }
 
+/// Returns the fingerprint of a store path.
+///
+/// This is the message that gets signed by Nix signing keys:
+///
+/// ```text
+/// 1;{storePath};{narHash};{narSize};{commaDelimitedReferences}
+/// ```
+///
+/// `references` are base names, which are resolved against the
+/// store directory of `store_path`.
+pub fn fingerprint(
+    store_path: &Path,
+    nar_hash: &Hash,
+    nar_size: usize,
+    references: &[String],
+) -> Vec<u8> {
+    let store_dir = store_path.parent().unwrap_or_else(|| Path::new(""));
+    let mut fingerprint = b"1;".to_vec();
+
+    // storePath
+    fingerprint.extend(path_bytes(store_path).iter());
+    fingerprint.extend(b";");
+
+    // narHash
+    fingerprint.extend(nar_hash.to_typed_base32().as_bytes());
+    fingerprint.extend(b";");
+
+    // narSize
+    fingerprint.extend(nar_size.to_string().as_bytes());
+    fingerprint.extend(b";");
+
+    // commaDelimitedReferences
+    let mut iter = references.iter().peekable();
+    while let Some(reference) = iter.next() {
+        fingerprint.extend(path_bytes(store_dir).iter());
+        fingerprint.extend(b"/");
+        fingerprint.extend(reference.as_bytes());
+
+        if iter.peek().is_some() {
+            fingerprint.extend(b",");
+        }
+    }
+
+    fingerprint
+}
+
+/// Returns the raw bytes of a path.
+#[cfg(target_family = "unix")]
+fn path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+
+    Cow::Borrowed(path.as_os_str().as_bytes())
+}
+
+/// Returns the raw bytes of a path.
+///
+/// Store paths are ASCII, so nothing is lost in practice.
+#[cfg(not(target_family = "unix"))]
+fn path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    match path.to_string_lossy() {
+        Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
+        Cow::Owned(s) => Cow::Owned(s.into_bytes()),
+    }
+}
+
 impl<'de> Deserialize<'de> for NixKeypair {
     /// Deserializes a potentially-invalid Nix keypair from its canonical representation.
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
@@ -221,6 +289,28 @@ impl NixPublicKey {
     }
 }
 
+impl<'de> Deserialize<'de> for NixPublicKey {
+    /// Deserializes a potentially-invalid Nix public key from its canonical representation.
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        use de::Error;
+        String::deserialize(deserializer)
+            .and_then(|s| Self::from_str(&s).map_err(|e| Error::custom(e.to_string())))
+    }
+}
+
+impl Serialize for NixPublicKey {
+    /// Serializes a Nix public key to its canonical representation.
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        serializer.serialize_str(&self.export())
+    }
+}
+
 /// Validates the name/label of a signing key.
 ///
 /// A valid name cannot be empty and must not contain colons (:).
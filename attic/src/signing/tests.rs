@@ -41,6 +41,15 @@ fn test_serde() {
     eprintln!("Public Key: {}", keypair.export_public_key());
 
     assert_eq!(json, &export);
+
+    let json = "\"cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=\"";
+
+    let public: NixPublicKey =
+        serde_json::from_str(json).expect("Could not deserialize public key");
+
+    let export = serde_json::to_string(&public).expect("Could not serialize public key");
+
+    assert_eq!(json, &export);
 }
 
 #[test]
@@ -25,6 +25,16 @@ pub struct StreamHasher<R: AsyncRead + Unpin, D: Digest + Unpin> {
     finalized: Arc<OnceCell<(DigestOutput<D>, usize)>>,
 }
 
+/// Stream filter that fails once too many bytes have been read.
+///
+/// Reading stops as soon as the limit is exceeded, so a client
+/// cannot keep us busy with an endless stream.
+pub struct StreamLimiter<R: AsyncRead + Unpin> {
+    inner: R,
+    limit: u64,
+    bytes_read: u64,
+}
+
 /// Merge chunks lazily into a continuous stream.
 ///
 /// For each chunk, a function is called to transform it into a
@@ -48,7 +58,8 @@ pub struct StreamHasher<R: AsyncRead + Unpin, D: Digest + Unpin> {
 ///
 /// ```
 ///
-/// TODO: Support range requests so we can have seekable NARs.
+/// To serve a byte range, pass only the chunks covering the range
+/// and have the streamer start the first chunk at the right offset.
 pub fn merge_chunks<C, F, S, Fut, E>(
     mut chunks: VecDeque<C>,
     streamer: F,
@@ -152,6 +163,50 @@ impl<R: AsyncRead + Unpin, D: Digest + Unpin> AsyncRead for StreamHasher<R, D> {
     }
 }
 
+impl<R: AsyncRead + Unpin> StreamLimiter<R> {
+    pub fn new(inner: R, limit: u64) -> Self {
+        Self {
+            inner,
+            limit,
+            bytes_read: 0,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for StreamLimiter<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<tokio::io::Result<()>> {
+        if self.bytes_read > self.limit {
+            return Poll::Ready(Err(limit_exceeded(self.limit)));
+        }
+
+        let old_filled = buf.filled().len();
+        let r = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read_len = buf.filled().len() - old_filled;
+
+        if let Poll::Ready(Ok(())) = r {
+            self.bytes_read += read_len as u64;
+
+            if self.bytes_read > self.limit {
+                buf.set_filled(old_filled);
+                return Poll::Ready(Err(limit_exceeded(self.limit)));
+            }
+        }
+
+        r
+    }
+}
+
+fn limit_exceeded(limit: u64) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Stream is longer than {} bytes", limit),
+    )
+}
+
 /// Greedily reads from a stream to fill a buffer.
 pub async fn read_chunk_async<S: AsyncRead + Unpin + Send>(
     stream: &mut S,
@@ -217,6 +272,27 @@ mod tests {
         eprintln!("finalized = {:x?}", finalized);
     }
 
+    #[tokio::test]
+    async fn test_stream_limiter() {
+        let mut buf = Vec::new();
+        let mut read = StreamLimiter::new(b"hello world".as_slice(), 11);
+        read.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(b"hello world", buf.as_slice());
+
+        let mut buf = Vec::new();
+        let mut read = StreamLimiter::new(b"hello world".as_slice(), 10);
+        let e = read.read_to_end(&mut buf).await.unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidData, e.kind());
+        assert!(buf.len() <= 10);
+
+        // endless streams are cut off
+        let mut read = StreamLimiter::new(tokio::io::repeat(0), 1024);
+        let e = tokio::io::copy(&mut read, &mut tokio::io::sink())
+            .await
+            .unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidData, e.kind());
+    }
+
     #[tokio::test]
     async fn test_merge_chunks() {
         let chunk_a: BoxStream<Result<Bytes, ()>> = {
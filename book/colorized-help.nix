@@ -9,6 +9,7 @@ let
       "login"
       "use"
       "push"
+      "pull"
       "watch-store"
       "cache"
       "cache create"
@@ -22,6 +23,8 @@ let
     atticadm = [
       null
       "make-token"
+      "test-chunking"
+      "audit tail"
     ];
   };
   renderMarkdown = name: subcommands: ''
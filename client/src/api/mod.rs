@@ -1,37 +1,75 @@
 use std::error::Error as StdError;
 use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use async_compression::tokio::bufread::{
+    BrotliDecoder, Lz4Decoder, XzDecoder, ZstdDecoder, ZstdEncoder,
+};
 use bytes::Bytes;
-use const_format::concatcp;
+use const_format::formatcp;
 use displaydoc::Display;
 use futures::{
     future,
-    stream::{self, StreamExt, TryStream, TryStreamExt},
+    stream::{self, Stream, StreamExt, TryStream, TryStreamExt},
 };
 use reqwest::{
-    header::{HeaderMap, HeaderValue, AUTHORIZATION, USER_AGENT},
-    Body, Client as HttpClient, Response, StatusCode, Url,
+    header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_ENCODING},
+    Body, Client as HttpClient, ClientBuilder, Response, StatusCode, Url,
 };
 use serde::Deserialize;
+use tokio::io::AsyncRead;
+use tokio_util::io::{ReaderStream, StreamReader};
 
 use crate::config::ServerConfig;
+use crate::narinfo::NarInfo;
 use crate::version::ATTIC_DISTRIBUTOR;
-use attic::api::v1::cache_config::{CacheConfig, CreateCacheRequest};
+use attic::api::error::ErrorCode;
+use attic::api::v1::cache_config::{CacheConfig, CachePublicKey, CreateCacheRequest};
+use attic::api::v1::cache_events::{CacheEvents, CacheEventsQuery};
+use attic::api::v1::cache_gc::CacheGcJob;
+use attic::api::v1::delete_path::{
+    DeleteObjectsRequest, DeleteObjectsResult, DeletePathQuery, DeletePathResult,
+};
 use attic::api::v1::get_missing_paths::{GetMissingPathsRequest, GetMissingPathsResponse};
+use attic::api::v1::list_objects::{
+    CacheObjectsPage, CacheObjectsQuery, ListObjectsQuery, ObjectList,
+};
 use attic::api::v1::upload_path::{
-    UploadPathNarInfo, UploadPathResult, ATTIC_NAR_INFO, ATTIC_NAR_INFO_PREAMBLE_SIZE,
+    UploadPathNarInfo, UploadPathPreflightRequest, UploadPathPreflightResult, UploadPathResult,
+    ATTIC_NAR_INFO, ATTIC_NAR_INFO_PREAMBLE_SIZE,
 };
 use attic::cache::CacheName;
 use attic::nix_store::StorePathHash;
 
 /// The User-Agent string of Attic.
-const ATTIC_USER_AGENT: &str =
-    concatcp!("Attic/{} ({})", env!("CARGO_PKG_NAME"), ATTIC_DISTRIBUTOR);
+///
+/// This is in the form of `Attic/<version> (<distributor>)`, for
+/// example `Attic/0.1.0 (nixpkgs)`.
+const ATTIC_USER_AGENT: &str = formatcp!(
+    "Attic/{} ({})",
+    env!("CARGO_PKG_VERSION"),
+    ATTIC_DISTRIBUTOR
+);
 
 /// The size threshold to send the upload info as part of the PUT body.
 const NAR_INFO_PREAMBLE_THRESHOLD: usize = 4 * 1024; // 4 KiB
 
+/// How often to ping the server on idle HTTP/2 connections.
+///
+/// This keeps pooled connections from being dropped by proxies and
+/// load balancers between bursts of uploads.
+const HTTP2_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The encoding of compressed uploads.
+const UPLOAD_ENCODING: &str = "zstd";
+
+/// A stream making up the body of an upload.
+type UploadStream =
+    Pin<Box<dyn Stream<Item = Result<Bytes, Box<dyn StdError + Send + Sync>>> + Send + Sync>>;
+
 /// The Attic API client.
 #[derive(Debug, Clone)]
 pub struct ApiClient {
@@ -54,15 +92,21 @@ pub enum ApiError {
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct StructuredApiError {
-    #[allow(dead_code)]
-    code: u16,
-    error: String,
-    message: String,
+    pub(crate) code: u16,
+    pub(crate) error: String,
+
+    /// The stable code of the error.
+    ///
+    /// Older servers don't report this.
+    #[serde(default)]
+    pub(crate) error_code: Option<u16>,
+
+    pub(crate) message: String,
 }
 
 impl ApiClient {
     pub fn from_server_config(config: ServerConfig) -> Result<Self> {
-        let client = build_http_client(config.token()?.as_deref());
+        let client = build_http_client(&config)?;
 
         Ok(Self {
             endpoint: Url::parse(&config.endpoint)?,
@@ -70,6 +114,11 @@ impl ApiClient {
         })
     }
 
+    /// Returns the API endpoint of this client.
+    pub fn endpoint(&self) -> &Url {
+        &self.endpoint
+    }
+
     /// Sets the API endpoint of this client.
     pub fn set_endpoint(&mut self, endpoint: &str) -> Result<()> {
         self.endpoint = Url::parse(endpoint)?;
@@ -94,6 +143,26 @@ impl ApiClient {
         }
     }
 
+    /// Returns the public key and substituter endpoint of a cache.
+    ///
+    /// Older servers don't support this and return `NotFound`.
+    pub async fn get_cache_public_key(&self, cache: &CacheName) -> Result<CachePublicKey> {
+        let endpoint = self
+            .endpoint
+            .join("_api/v1/cache-config/")?
+            .join(&format!("{}/public-key", cache.as_str()))?;
+
+        let res = self.client.get(endpoint).send().await?;
+
+        if res.status().is_success() {
+            let public_key = res.json().await?;
+            Ok(public_key)
+        } else {
+            let api_error = ApiError::try_from_response(res).await?;
+            Err(api_error.into())
+        }
+    }
+
     /// Creates a cache.
     pub async fn create_cache(&self, cache: &CacheName, request: CreateCacheRequest) -> Result<()> {
         let endpoint = self
@@ -145,6 +214,167 @@ impl ApiClient {
         }
     }
 
+    /// Runs garbage collection on a cache.
+    pub async fn run_cache_gc(&self, cache: &CacheName) -> Result<CacheGcJob> {
+        let endpoint = self
+            .endpoint
+            .join("_api/v1/cache/")?
+            .join(&format!("{}/gc", cache.as_str()))?;
+
+        let res = self.client.post(endpoint).send().await?;
+
+        if res.status().is_success() {
+            let job = res.json().await?;
+            Ok(job)
+        } else {
+            let api_error = ApiError::try_from_response(res).await?;
+            Err(api_error.into())
+        }
+    }
+
+    /// Returns events in a cache after a sequence number.
+    ///
+    /// If `timeout` is non-zero, the server waits up to that long
+    /// for new events.
+    pub async fn get_cache_events(
+        &self,
+        cache: &CacheName,
+        since: i64,
+        timeout: Duration,
+    ) -> Result<CacheEvents> {
+        let endpoint = self
+            .endpoint
+            .join("_api/v1/cache/")?
+            .join(&format!("{}/events", cache.as_str()))?;
+        let query = CacheEventsQuery {
+            since: Some(since),
+            timeout: Some(timeout.as_secs()),
+        };
+
+        let res = self.client.get(endpoint).query(&query).send().await?;
+
+        if res.status().is_success() {
+            let events = res.json().await?;
+            Ok(events)
+        } else {
+            let api_error = ApiError::try_from_response(res).await?;
+            Err(api_error.into())
+        }
+    }
+
+    /// Lists objects in a cache.
+    pub async fn list_objects(
+        &self,
+        cache: &CacheName,
+        query: &ListObjectsQuery,
+    ) -> Result<ObjectList> {
+        let endpoint = self
+            .endpoint
+            .join("_api/v1/cache/")?
+            .join(&format!("{}/objects", cache.as_str()))?;
+
+        let res = self.client.get(endpoint).query(query).send().await?;
+
+        if res.status().is_success() {
+            let list = res.json().await?;
+            Ok(list)
+        } else {
+            let api_error = ApiError::try_from_response(res).await?;
+            Err(api_error.into())
+        }
+    }
+
+    /// Lists all objects in a cache by creation time.
+    pub async fn list_cache_objects(
+        &self,
+        cache: &CacheName,
+        query: &CacheObjectsQuery,
+    ) -> Result<CacheObjectsPage> {
+        let endpoint = self
+            .endpoint
+            .join("_api/v1/cache-objects/")?
+            .join(cache.as_str())?;
+
+        let res = self.client.get(endpoint).query(query).send().await?;
+
+        if res.status().is_success() {
+            let page = res.json().await?;
+            Ok(page)
+        } else {
+            let api_error = ApiError::try_from_response(res).await?;
+            Err(api_error.into())
+        }
+    }
+
+    /// Returns the status of a garbage collection job.
+    pub async fn get_cache_gc_job(&self, cache: &CacheName, job: &str) -> Result<CacheGcJob> {
+        let endpoint = self.endpoint.join("_api/v1/cache/")?.join(&format!(
+            "{}/gc/{}",
+            cache.as_str(),
+            job
+        ))?;
+
+        let res = self.client.get(endpoint).send().await?;
+
+        if res.status().is_success() {
+            let job = res.json().await?;
+            Ok(job)
+        } else {
+            let api_error = ApiError::try_from_response(res).await?;
+            Err(api_error.into())
+        }
+    }
+
+    /// Deletes a path from a cache.
+    ///
+    /// With `with_referrers`, paths depending on the path are
+    /// deleted as well.
+    pub async fn delete_path(
+        &self,
+        cache: &CacheName,
+        store_path_hash: &StorePathHash,
+        with_referrers: bool,
+    ) -> Result<DeletePathResult> {
+        let endpoint = self.endpoint.join("_api/v1/cache/")?.join(&format!(
+            "{}/path/{}",
+            cache.as_str(),
+            store_path_hash.as_str()
+        ))?;
+        let query = DeletePathQuery { with_referrers };
+
+        let res = self.client.delete(endpoint).query(&query).send().await?;
+
+        if res.status().is_success() {
+            let result = res.json().await?;
+            Ok(result)
+        } else {
+            let api_error = ApiError::try_from_response(res).await?;
+            Err(api_error.into())
+        }
+    }
+
+    /// Deletes all objects matching a filter from a cache.
+    pub async fn delete_objects(
+        &self,
+        cache: &CacheName,
+        request: &DeleteObjectsRequest,
+    ) -> Result<DeleteObjectsResult> {
+        let endpoint = self
+            .endpoint
+            .join("_api/v1/cache/")?
+            .join(&format!("{}/delete-objects", cache.as_str()))?;
+
+        let res = self.client.post(endpoint).json(request).send().await?;
+
+        if res.status().is_success() {
+            let result = res.json().await?;
+            Ok(result)
+        } else {
+            let api_error = ApiError::try_from_response(res).await?;
+            Err(api_error.into())
+        }
+    }
+
     /// Returns paths missing from a cache.
     pub async fn get_missing_paths(
         &self,
@@ -168,12 +398,122 @@ impl ApiClient {
         }
     }
 
+    /// Returns the narinfo of a path in a cache.
+    ///
+    /// Returns `None` if the path isn't in the cache.
+    pub async fn get_narinfo(
+        &self,
+        cache: &CacheName,
+        store_path_hash: &StorePathHash,
+    ) -> Result<Option<NarInfo>> {
+        let endpoint = self.endpoint.join(&format!(
+            "{}/{}.narinfo",
+            cache.as_str(),
+            store_path_hash.as_str()
+        ))?;
+
+        let res = self.client.get(endpoint).send().await?;
+
+        if res.status().is_success() {
+            let narinfo = res.text().await?;
+            Ok(Some(NarInfo::from_str(&narinfo)?))
+        } else if res.status() == StatusCode::NOT_FOUND {
+            Ok(None)
+        } else {
+            let api_error = ApiError::try_from_response(res).await?;
+            Err(api_error.into())
+        }
+    }
+
+    /// Downloads the NAR of a path in a cache.
+    ///
+    /// The NAR is returned as served, with the compression in the
+    /// narinfo.
+    pub async fn get_nar(
+        &self,
+        cache: &CacheName,
+        narinfo: &NarInfo,
+    ) -> Result<impl Stream<Item = Result<Bytes, reqwest::Error>>> {
+        let base = self.endpoint.join(&format!("{}/", cache.as_str()))?;
+        let endpoint = base.join(&narinfo.url)?;
+
+        // Don't send our token to other hosts (e.g., a CDN)
+        let req = if endpoint.origin() == self.endpoint.origin() {
+            self.client.get(endpoint)
+        } else {
+            HttpClient::new().get(endpoint)
+        };
+
+        let res = req.send().await?;
+
+        if res.status().is_success() {
+            Ok(res.bytes_stream())
+        } else {
+            let api_error = ApiError::try_from_response(res).await?;
+            Err(api_error.into())
+        }
+    }
+
+    /// Downloads and decompresses the NAR of a path in a cache.
+    pub async fn get_decompressed_nar(
+        &self,
+        cache: &CacheName,
+        narinfo: &NarInfo,
+    ) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        let stream = self.get_nar(cache, narinfo).await?;
+        let reader = StreamReader::new(stream.map_err(io::Error::other));
+
+        let reader: Box<dyn AsyncRead + Unpin + Send> = match narinfo.compression.as_str() {
+            "none" => Box::new(reader),
+            "zstd" => Box::new(ZstdDecoder::new(reader)),
+            "xz" => Box::new(XzDecoder::new(reader)),
+            "br" => Box::new(BrotliDecoder::new(reader)),
+            "lz4" => Box::new(Lz4Decoder::new(reader)),
+            compression => {
+                return Err(anyhow!("Unsupported NAR compression {}", compression));
+            }
+        };
+
+        Ok(reader)
+    }
+
+    /// Checks whether the NAR of a path needs to be uploaded.
+    ///
+    /// Returns `None` if the server doesn't support upload preflight.
+    pub async fn upload_path_preflight(
+        &self,
+        nar_info: &UploadPathNarInfo,
+    ) -> Result<Option<UploadPathPreflightResult>> {
+        let endpoint = self.endpoint.join("_api/v1/upload-path/preflight")?;
+        let payload = UploadPathPreflightRequest {
+            cache: nar_info.cache.to_owned(),
+            nar_hash: nar_info.nar_hash.to_owned(),
+            nar_size: nar_info.nar_size,
+        };
+
+        let res = self.client.post(endpoint).json(&payload).send().await?;
+
+        if res.status().is_success() {
+            Ok(Some(res.json().await?))
+        } else if res.status() == StatusCode::NOT_FOUND {
+            Ok(None)
+        } else {
+            let api_error = ApiError::try_from_response(res).await?;
+            Err(api_error.into())
+        }
+    }
+
     /// Uploads a path.
+    ///
+    /// If `compress` is set, the body (including any preamble) is
+    /// compressed with zstd. Check that the server supports it with
+    /// [`supports_compressed_upload`] first.
     pub async fn upload_path<S>(
         &self,
         nar_info: UploadPathNarInfo,
         stream: S,
         force_preamble: bool,
+        compress: bool,
     ) -> Result<Option<UploadPathResult>>
     where
         S: TryStream<Ok = Bytes> + Send + Sync + 'static,
@@ -182,24 +522,31 @@ impl ApiClient {
         let endpoint = self.endpoint.join("_api/v1/upload-path")?;
         let upload_info_json = serde_json::to_string(&nar_info)?;
 
-        let mut req = self
-            .client
-            .put(endpoint)
-            .header(USER_AGENT, HeaderValue::from_str(ATTIC_USER_AGENT)?);
+        let mut req = self.client.put(endpoint);
+        let stream = stream.into_stream().map_err(Into::into);
+
+        let body: UploadStream =
+            if force_preamble || upload_info_json.len() >= NAR_INFO_PREAMBLE_THRESHOLD {
+                let preamble = Bytes::from(upload_info_json);
+                let preamble_len = preamble.len();
+                let preamble_stream = stream::once(future::ok(preamble));
 
-        if force_preamble || upload_info_json.len() >= NAR_INFO_PREAMBLE_THRESHOLD {
-            let preamble = Bytes::from(upload_info_json);
-            let preamble_len = preamble.len();
-            let preamble_stream = stream::once(future::ok(preamble));
+                req = req.header(ATTIC_NAR_INFO_PREAMBLE_SIZE, preamble_len);
+                Box::pin(preamble_stream.chain(stream))
+            } else {
+                req = req.header(ATTIC_NAR_INFO, HeaderValue::from_str(&upload_info_json)?);
+                Box::pin(stream)
+            };
+
+        if compress {
+            let reader = StreamReader::new(body.map_err(io::Error::other));
+            let compressed = ReaderStream::new(ZstdEncoder::new(reader));
 
-            let chained = preamble_stream.chain(stream.into_stream());
             req = req
-                .header(ATTIC_NAR_INFO_PREAMBLE_SIZE, preamble_len)
-                .body(Body::wrap_stream(chained));
+                .header(CONTENT_ENCODING, UPLOAD_ENCODING)
+                .body(Body::wrap_stream(compressed));
         } else {
-            req = req
-                .header(ATTIC_NAR_INFO, HeaderValue::from_str(&upload_info_json)?)
-                .body(Body::wrap_stream(stream));
+            req = req.body(Body::wrap_stream(body));
         }
 
         let res = req.send().await?;
@@ -222,11 +569,46 @@ impl ApiError {
     async fn try_from_response(response: Response) -> Result<Self> {
         let status = response.status();
         let text = response.text().await?;
+        Ok(Self::from_response_text(status, text))
+    }
+
+    fn from_response_text(status: StatusCode, text: String) -> Self {
         match serde_json::from_str(&text) {
-            Ok(s) => Ok(Self::Structured(s)),
-            Err(_) => Ok(Self::Unstructured(status, text)),
+            Ok(s) => Self::Structured(s),
+            Err(_) => Self::Unstructured(status, text),
+        }
+    }
+
+    /// Returns the kind of the error reported by the server, if any.
+    ///
+    /// With older servers that don't report numeric codes, the kind is
+    /// derived from the name of the error.
+    pub fn kind(&self) -> Option<ErrorCode> {
+        match self {
+            Self::Structured(e) => e
+                .error_code
+                .and_then(ErrorCode::from_code)
+                .or_else(|| ErrorCode::from_name(&e.error)),
+            Self::Unstructured(_, _) => None,
+        }
+    }
+
+    /// Returns the HTTP status code of the error.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Structured(e) => {
+                StatusCode::from_u16(e.code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+            Self::Unstructured(status, _) => *status,
         }
     }
+
+    /// Returns whether the error is of a specific kind.
+    ///
+    /// This works on errors returned by `ApiClient` methods.
+    pub fn is(error: &anyhow::Error, kind: ErrorCode) -> bool {
+        error.downcast_ref::<Self>().and_then(|e| e.kind()) == Some(kind)
+    }
 }
 
 impl fmt::Display for StructuredApiError {
@@ -235,16 +617,142 @@ impl fmt::Display for StructuredApiError {
     }
 }
 
-fn build_http_client(token: Option<&str>) -> HttpClient {
+/// Returns whether the server of a cache accepts compressed uploads.
+///
+/// Servers that do advertise it in the cache configuration.
+pub fn supports_compressed_upload(cache_config: &CacheConfig) -> bool {
+    cache_config
+        .upload_encodings
+        .as_ref()
+        .is_some_and(|encodings| encodings.iter().any(|e| e == UPLOAD_ENCODING))
+}
+
+fn build_http_client(config: &ServerConfig) -> Result<HttpClient> {
     let mut headers = HeaderMap::new();
 
-    if let Some(token) = token {
-        let auth_header = HeaderValue::from_str(&format!("bearer {}", token)).unwrap();
+    if let Some(token) = config.token()? {
+        let mut auth_header = HeaderValue::from_str(&format!("bearer {}", token))
+            .map_err(|_| anyhow!("The token contains characters not allowed in HTTP headers"))?;
+        auth_header.set_sensitive(true);
         headers.insert(AUTHORIZATION, auth_header);
     }
 
-    reqwest::Client::builder()
+    Ok(http_client_builder(config)
         .default_headers(headers)
-        .build()
-        .unwrap()
+        .build()?)
+}
+
+/// Returns an HTTP client builder with the connection settings of a server.
+///
+/// The resulting client doesn't send the token of the server.
+pub(crate) fn http_client_builder(config: &ServerConfig) -> ClientBuilder {
+    let mut builder = reqwest::Client::builder()
+        .user_agent(ATTIC_USER_AGENT)
+        .http2_keep_alive_interval(HTTP2_KEEP_ALIVE_INTERVAL)
+        .http2_keep_alive_while_idle(true);
+
+    if let Some(max) = config.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max);
+    }
+
+    if let Some(timeout) = config.request_timeout() {
+        builder = builder.timeout(timeout);
+    }
+
+    if let Some(timeout) = config.connect_timeout() {
+        builder = builder.connect_timeout(timeout);
+    }
+
+    builder
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use regex::Regex;
+
+    #[test]
+    fn test_user_agent() {
+        let re = Regex::new(r"^Attic/\d+\.\d+\.\d+\S* \([^()]+\)$").unwrap();
+        assert!(re.is_match(ATTIC_USER_AGENT), "{}", ATTIC_USER_AGENT);
+        assert!(ATTIC_USER_AGENT.starts_with(concat!("Attic/", env!("CARGO_PKG_VERSION"), " (")));
+    }
+
+    #[test]
+    fn test_build_http_client() {
+        let config = |token: &str| -> ServerConfig {
+            toml::from_str(&format!(
+                r#"
+endpoint = "https://attic.example.com"
+token = "{token}"
+request-timeout = 600
+connect-timeout = 10
+pool-max-idle-per-host = 8
+"#
+            ))
+            .unwrap()
+        };
+
+        let good = config("eyJhbGciOiJIUzI1NiJ9.e30.c2lnbmF0dXJl");
+        assert_eq!(Some(Duration::from_secs(600)), good.request_timeout());
+        assert_eq!(Some(Duration::from_secs(10)), good.connect_timeout());
+        assert_eq!(Some(8), good.pool_max_idle_per_host);
+        assert!(build_http_client(&good).is_ok());
+
+        // A stray newline in the token is an error, not a panic
+        assert!(build_http_client(&config("abc\\ndef")).is_err());
+    }
+
+    #[test]
+    fn test_supports_compressed_upload() {
+        let mut config = CacheConfig::blank();
+        assert!(!supports_compressed_upload(&config));
+
+        config.upload_encodings = Some(vec!["gzip".to_string()]);
+        assert!(!supports_compressed_upload(&config));
+
+        config.upload_encodings = Some(vec!["gzip".to_string(), "zstd".to_string()]);
+        assert!(supports_compressed_upload(&config));
+    }
+
+    #[test]
+    fn test_api_error_from_response() {
+        let structured = ApiError::from_response_text(
+            StatusCode::CONFLICT,
+            r#"{"code":409,"error":"CacheAlreadyExists","error_code":101,"message":"The cache already exists."}"#
+                .to_string(),
+        );
+        assert_eq!(Some(ErrorCode::CacheAlreadyExists), structured.kind());
+
+        // The numeric code takes precedence over the name
+        let renamed = ApiError::from_response_text(
+            StatusCode::FORBIDDEN,
+            r#"{"code":403,"error":"SomeNewName","error_code":200,"message":"Permission denied."}"#
+                .to_string(),
+        );
+        assert_eq!(Some(ErrorCode::PermissionDenied), renamed.kind());
+
+        // Older servers only report the name
+        let old = ApiError::from_response_text(
+            StatusCode::NOT_FOUND,
+            r#"{"code":404,"error":"NoSuchCache","message":"The requested cache does not exist."}"#
+                .to_string(),
+        );
+        assert_eq!(Some(ErrorCode::NoSuchCache), old.kind());
+
+        let unstructured = ApiError::from_response_text(
+            StatusCode::BAD_GATEWAY,
+            "<html>Bad Gateway</html>".to_string(),
+        );
+        assert_eq!(None, unstructured.kind());
+
+        let error: anyhow::Error = structured.into();
+        assert!(ApiError::is(&error, ErrorCode::CacheAlreadyExists));
+        assert!(!ApiError::is(&error, ErrorCode::NoSuchCache));
+        assert!(!ApiError::is(
+            &anyhow::anyhow!("Other"),
+            ErrorCode::CacheAlreadyExists
+        ));
+    }
 }
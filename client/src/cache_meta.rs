@@ -0,0 +1,357 @@
+//! Cached cache configurations.
+//!
+//! Every push needs the configuration of the cache to know the
+//! store directory and upstream cache keys. To avoid a round-trip
+//! on every invocation, responses are cached on disk under
+//! `$XDG_CACHE_HOME/attic/cache-config.json` (`%LOCALAPPDATA%\attic`
+//! on Windows) for a short while.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use reqwest::{StatusCode, Url};
+use serde::{Deserialize, Serialize};
+
+use crate::api::{ApiClient, ApiError};
+use crate::cache::CacheName;
+use crate::dirs;
+use attic::api::error::ErrorCode;
+use attic::api::v1::cache_config::CacheConfig;
+
+/// The default time-to-live of cached entries.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(300);
+
+/// On-disk cache of cache configurations.
+#[derive(Debug)]
+pub struct CacheMeta {
+    /// Path to the cache file.
+    ///
+    /// If `None`, nothing is cached.
+    path: Option<PathBuf>,
+
+    /// How long entries stay valid.
+    ttl: Duration,
+}
+
+/// A cached cache configuration.
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheMetaEntry {
+    /// Unix timestamp when the configuration was fetched.
+    fetched_at: u64,
+
+    /// The configuration returned by the server.
+    config: CacheConfig,
+}
+
+type CacheMetaData = HashMap<String, CacheMetaEntry>;
+
+impl CacheMeta {
+    /// Opens the cache in the standard location.
+    pub fn load(ttl: Duration) -> Self {
+        let path = get_cache_meta_path()
+            .map_err(|e| {
+                tracing::warn!("Could not get cache path: {}", e);
+                e
+            })
+            .ok();
+
+        Self { path, ttl }
+    }
+
+    /// Opens the cache at a specific path.
+    #[cfg(test)]
+    fn at_path(path: PathBuf, ttl: Duration) -> Self {
+        Self {
+            path: Some(path),
+            ttl,
+        }
+    }
+
+    /// Returns the configuration of a cache, fetching it if necessary.
+    ///
+    /// If `refresh` is true, the cached copy is ignored.
+    pub async fn get_cache_config(
+        &self,
+        api: &ApiClient,
+        cache: &CacheName,
+        refresh: bool,
+    ) -> Result<CacheConfig> {
+        let endpoint = api.endpoint().as_str();
+
+        if !refresh {
+            if let Some(config) = self.get(endpoint, cache) {
+                tracing::debug!("Using cached configuration of {}", cache.as_str());
+                return Ok(config);
+            }
+        }
+
+        let config = api.get_cache_config(cache).await?;
+
+        if let Err(e) = self.put(endpoint, cache, &config) {
+            tracing::warn!("Could not cache the cache configuration: {}", e);
+        }
+
+        Ok(config)
+    }
+
+    /// Invalidates the cached configuration if an error indicates it's stale.
+    ///
+    /// This is the case when the cache no longer exists or we are no
+    /// longer authorized to access it.
+    ///
+    /// `endpoint` must be the endpoint of the `ApiClient` used to
+    /// fetch the configuration.
+    pub fn invalidate_on_error(&self, endpoint: &Url, cache: &CacheName, error: &anyhow::Error) {
+        if !is_stale_config_error(error) {
+            return;
+        }
+
+        if let Err(e) = self.invalidate(endpoint.as_str(), cache) {
+            tracing::warn!("Could not invalidate the cache configuration: {}", e);
+        }
+    }
+
+    fn get(&self, endpoint: &str, cache: &CacheName) -> Option<CacheConfig> {
+        self.get_at(endpoint, cache, now())
+    }
+
+    fn get_at(&self, endpoint: &str, cache: &CacheName, now: u64) -> Option<CacheConfig> {
+        let path = self.path.as_ref()?;
+        let mut data = read_data(path);
+        let entry = data.remove(&make_key(endpoint, cache))?;
+
+        if now.saturating_sub(entry.fetched_at) < self.ttl.as_secs() {
+            Some(entry.config)
+        } else {
+            None
+        }
+    }
+
+    fn put(&self, endpoint: &str, cache: &CacheName, config: &CacheConfig) -> Result<()> {
+        self.put_at(endpoint, cache, config, now())
+    }
+
+    fn put_at(
+        &self,
+        endpoint: &str,
+        cache: &CacheName,
+        config: &CacheConfig,
+        now: u64,
+    ) -> Result<()> {
+        if self.ttl.is_zero() {
+            return Ok(());
+        }
+
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let mut data = read_data(path);
+
+        // Drop expired entries so the file doesn't grow forever
+        data.retain(|_, entry| now.saturating_sub(entry.fetched_at) < self.ttl.as_secs());
+
+        // Round-trip through JSON since CacheConfig isn't Clone
+        let config = serde_json::from_value(serde_json::to_value(config)?)?;
+        data.insert(
+            make_key(endpoint, cache),
+            CacheMetaEntry {
+                fetched_at: now,
+                config,
+            },
+        );
+
+        write_data(path, &data)
+    }
+
+    fn invalidate(&self, endpoint: &str, cache: &CacheName) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let mut data = read_data(path);
+        if data.remove(&make_key(endpoint, cache)).is_some() {
+            tracing::debug!("Invalidated cached configuration of {}", cache.as_str());
+            write_data(path, &data)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns whether an error indicates that the cached configuration is stale.
+///
+/// Errors without a known kind fall back to the HTTP status code.
+fn is_stale_config_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        let Some(api_error) = cause.downcast_ref::<ApiError>() else {
+            return false;
+        };
+
+        match api_error.kind() {
+            Some(kind) => matches!(kind, ErrorCode::NoSuchCache | ErrorCode::Unauthorized),
+            None => matches!(
+                api_error.status(),
+                StatusCode::UNAUTHORIZED | StatusCode::NOT_FOUND
+            ),
+        }
+    })
+}
+
+fn make_key(endpoint: &str, cache: &CacheName) -> String {
+    format!("{} {}", endpoint, cache.as_str())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Reads the cache file, treating any error as an empty cache.
+fn read_data(path: &Path) -> CacheMetaData {
+    fs::read(path)
+        .ok()
+        .and_then(|contents| serde_json::from_slice(&contents).ok())
+        .unwrap_or_default()
+}
+
+/// Writes the cache file atomically.
+fn write_data(path: &Path, data: &CacheMetaData) -> Result<()> {
+    let temp_path = path.with_extension(format!("json.{}", std::process::id()));
+    fs::write(&temp_path, serde_json::to_vec(data)?)?;
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+fn get_cache_meta_path() -> Result<PathBuf> {
+    dirs::place_cache_file("cache-config.json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::api::StructuredApiError;
+
+    const ENDPOINT: &str = "https://attic.example.com/";
+
+    fn make_config(priority: i32) -> CacheConfig {
+        let mut config = CacheConfig::blank();
+        config.priority = Some(priority);
+        config.store_dir = Some("/nix/store".to_string());
+        config
+    }
+
+    #[test]
+    fn test_cache_meta_expiry() {
+        let dir = tempfile::tempdir().unwrap();
+        let meta = CacheMeta::at_path(dir.path().join("cache-config.json"), DEFAULT_TTL);
+        let cache: CacheName = "test".parse().unwrap();
+        let other: CacheName = "other".parse().unwrap();
+
+        assert!(meta.get_at(ENDPOINT, &cache, 1000).is_none());
+
+        meta.put_at(ENDPOINT, &cache, &make_config(41), 1000)
+            .unwrap();
+
+        let config = meta.get_at(ENDPOINT, &cache, 1000 + 299).unwrap();
+        assert_eq!(Some(41), config.priority);
+        assert_eq!(Some("/nix/store".to_string()), config.store_dir);
+
+        // Entries are keyed by server and cache
+        assert!(meta.get_at(ENDPOINT, &other, 1000).is_none());
+        assert!(meta
+            .get_at("https://other.example.com/", &cache, 1000)
+            .is_none());
+
+        // Expired
+        assert!(meta.get_at(ENDPOINT, &cache, 1000 + 300).is_none());
+
+        // Refreshed
+        meta.put_at(ENDPOINT, &cache, &make_config(42), 1300)
+            .unwrap();
+        let config = meta.get_at(ENDPOINT, &cache, 1400).unwrap();
+        assert_eq!(Some(42), config.priority);
+    }
+
+    #[test]
+    fn test_cache_meta_disabled() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache-config.json");
+        let meta = CacheMeta::at_path(path.clone(), Duration::ZERO);
+        let cache: CacheName = "test".parse().unwrap();
+
+        meta.put_at(ENDPOINT, &cache, &make_config(41), 1000)
+            .unwrap();
+        assert!(meta.get_at(ENDPOINT, &cache, 1000).is_none());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_cache_meta_invalidation() {
+        let dir = tempfile::tempdir().unwrap();
+        let meta = CacheMeta::at_path(dir.path().join("cache-config.json"), DEFAULT_TTL);
+        let cache: CacheName = "test".parse().unwrap();
+        let now = now();
+
+        meta.put_at(ENDPOINT, &cache, &make_config(41), now)
+            .unwrap();
+        assert!(meta.get_at(ENDPOINT, &cache, now).is_some());
+
+        meta.invalidate(ENDPOINT, &cache).unwrap();
+        assert!(meta.get_at(ENDPOINT, &cache, now).is_none());
+    }
+
+    #[test]
+    fn test_is_stale_config_error() {
+        fn structured(code: u16, kind: Option<ErrorCode>) -> anyhow::Error {
+            ApiError::Structured(StructuredApiError {
+                code,
+                error: kind.map_or("Error", |k| k.name()).to_string(),
+                error_code: kind.map(|k| k.code()),
+                message: "Some message".to_string(),
+            })
+            .into()
+        }
+
+        assert!(is_stale_config_error(&structured(
+            401,
+            Some(ErrorCode::Unauthorized)
+        )));
+        assert!(is_stale_config_error(&structured(
+            404,
+            Some(ErrorCode::NoSuchCache)
+        )));
+        assert!(!is_stale_config_error(&structured(
+            403,
+            Some(ErrorCode::PermissionDenied)
+        )));
+        assert!(!is_stale_config_error(&structured(
+            404,
+            Some(ErrorCode::NotFound)
+        )));
+        assert!(!is_stale_config_error(&structured(
+            500,
+            Some(ErrorCode::InternalServerError)
+        )));
+
+        // Unknown kinds
+        assert!(is_stale_config_error(&structured(401, None)));
+        assert!(is_stale_config_error(&structured(404, None)));
+        assert!(!is_stale_config_error(&structured(403, None)));
+
+        let unstructured: anyhow::Error =
+            ApiError::Unstructured(StatusCode::NOT_FOUND, "Not Found".to_string()).into();
+        assert!(is_stale_config_error(&unstructured));
+        assert!(is_stale_config_error(
+            &unstructured.context("Failed to push")
+        ));
+
+        assert!(!is_stale_config_error(&anyhow::anyhow!("Other")));
+    }
+}
@@ -8,11 +8,27 @@ use clap_complete::Shell;
 use enum_as_inner::EnumAsInner;
 
 use crate::command::cache::{self, Cache};
-use crate::command::get_closure::{self, GetClosure};
 use crate::command::login::{self, Login};
-use crate::command::push::{self, Push};
-use crate::command::r#use::{self, Use};
-use crate::command::watch_store::{self, WatchStore};
+#[cfg(feature = "nix_store")]
+use crate::command::{
+    get_closure::{self, GetClosure},
+    pull::{self, Pull},
+    push::{self, Push},
+    r#use::{self, Use},
+    verify::{self, Verify},
+    watch_store::{self, WatchStore},
+};
+
+/// Subcommands that need the `nix_store` feature.
+#[cfg(not(feature = "nix_store"))]
+const NIX_STORE_COMMANDS: &[&str] = &[
+    "use",
+    "push",
+    "pull",
+    "verify",
+    "watch-store",
+    "get-closure",
+];
 
 /// Attic binary cache client.
 #[derive(Debug, Parser)]
@@ -26,13 +42,26 @@ pub struct Opts {
 #[derive(Debug, Subcommand, EnumAsInner)]
 pub enum Command {
     Login(Login),
+    #[cfg(feature = "nix_store")]
     Use(Use),
+    #[cfg(feature = "nix_store")]
     Push(Push),
+    #[cfg(feature = "nix_store")]
+    Pull(Pull),
+    #[cfg(feature = "nix_store")]
+    Verify(Verify),
     Cache(Cache),
+    #[cfg(feature = "nix_store")]
     WatchStore(WatchStore),
 
+    #[cfg(feature = "nix_store")]
     #[clap(hide = true)]
     GetClosure(GetClosure),
+
+    /// Catches the subcommands that aren't available in this build.
+    #[cfg(not(feature = "nix_store"))]
+    #[clap(external_subcommand)]
+    Unsupported(Vec<String>),
 }
 
 /// Generate shell autocompletion files.
@@ -52,11 +81,33 @@ pub async fn run() -> Result<()> {
 
     match opts.command {
         Command::Login(_) => login::run(opts).await,
+        #[cfg(feature = "nix_store")]
         Command::Use(_) => r#use::run(opts).await,
+        #[cfg(feature = "nix_store")]
         Command::Push(_) => push::run(opts).await,
+        #[cfg(feature = "nix_store")]
+        Command::Pull(_) => pull::run(opts).await,
+        #[cfg(feature = "nix_store")]
+        Command::Verify(_) => verify::run(opts).await,
         Command::Cache(_) => cache::run(opts).await,
+        #[cfg(feature = "nix_store")]
         Command::WatchStore(_) => watch_store::run(opts).await,
+        #[cfg(feature = "nix_store")]
         Command::GetClosure(_) => get_closure::run(opts).await,
+        #[cfg(not(feature = "nix_store"))]
+        Command::Unsupported(args) => unsupported(&args[0]),
+    }
+}
+
+#[cfg(not(feature = "nix_store"))]
+fn unsupported(command: &str) -> Result<()> {
+    if NIX_STORE_COMMANDS.contains(&command) {
+        Err(anyhow!(
+            "`attic {}` is not supported in this build, which lacks Nix Store support (the `nix_store` feature)",
+            command
+        ))
+    } else {
+        Err(anyhow!("Unrecognized subcommand '{}'", command))
     }
 }
 
@@ -1,15 +1,42 @@
-use anyhow::{anyhow, Result};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration as StdDuration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context, Result};
 use clap::{Parser, Subcommand};
 use dialoguer::Input;
 use humantime::Duration;
+use indicatif::HumanBytes;
+use serde::{de, Deserialize};
 
-use crate::api::ApiClient;
+use crate::api::{ApiClient, ApiError};
 use crate::cache::CacheRef;
 use crate::cli::Opts;
+use crate::command::r#use::{print_settings, resolve_settings};
 use crate::config::Config;
+use crate::nix_netrc::SYSTEM_NETRC;
+use attic::api::error::ErrorCode;
 use attic::api::v1::cache_config::{
-    CacheConfig, CreateCacheRequest, KeypairConfig, RetentionPeriodConfig,
+    CacheConfig, ChunkingConfig, ChunkingOverrides, CreateCacheRequest, KeypairConfig,
+    NarUrlBaseConfig, RetentionPeriodConfig,
 };
+use attic::api::v1::cache_events::CacheEventKind;
+use attic::api::v1::cache_gc::CacheGcStatus;
+use attic::api::v1::delete_path::DeleteObjectsRequest;
+use attic::api::v1::list_objects::{CacheObjectsQuery, ListObjectsQuery};
+#[cfg(feature = "nix_store")]
+use attic::nix_store::NixStore;
+use attic::nix_store::StorePath;
+use attic::signing::NixKeypair;
+
+/// How often to poll background garbage collection jobs.
+const GC_POLL_INTERVAL: StdDuration = StdDuration::from_secs(5);
+
+/// How long to wait for new events in each request when following.
+const EVENTS_POLL_TIMEOUT: StdDuration = StdDuration::from_secs(60);
 
 /// Manage caches on an Attic server.
 #[derive(Debug, Parser)]
@@ -24,6 +51,15 @@ enum Command {
     Configure(Configure),
     Destroy(Destroy),
     Info(Info),
+    PublicKey(PublicKey),
+    NixConf(NixConf),
+    Apply(Apply),
+    Gc(Gc),
+    Events(Events),
+    Ls(Ls),
+    ListPaths(ListPaths),
+    Delete(Delete),
+    DeletePath(DeletePath),
 }
 
 /// Create a cache.
@@ -51,33 +87,77 @@ struct Create {
     ///
     /// You probably don't want to change this. Changing
     /// this can make your cache unusable.
-    #[clap(long, hide = true, default_value = "/nix/store")]
-    store_dir: String,
+    ///
+    /// Defaults to `/nix/store`.
+    #[clap(long, hide = true)]
+    store_dir: Option<String>,
 
     /// The priority of the binary cache.
     ///
     /// A lower number denotes a higher priority.
     /// <https://cache.nixos.org> has a priority of 40.
-    #[clap(long, default_value = "41")]
-    priority: i32,
+    ///
+    /// Defaults to 41.
+    #[clap(long)]
+    priority: Option<i32>,
 
     /// The signing key name of an upstream cache.
     ///
     /// When pushing to the cache, paths signed with this key
     /// will be skipped by default. Specify this flag multiple
     /// times to add multiple key names.
-    #[clap(
-        name = "NAME",
-        long = "upstream-cache-key-name",
-        default_value = "cache.nixos.org-1"
-    )]
+    ///
+    /// Defaults to `cache.nixos.org-1`.
+    #[clap(name = "NAME", long = "upstream-cache-key-name")]
     upstream_cache_key_names: Vec<String>,
+
+    /// Succeed if the cache already exists.
+    ///
+    /// Other errors, like missing permissions, are still reported.
+    #[clap(long)]
+    if_not_exists: bool,
+
+    /// Apply the settings to the cache if it already exists.
+    ///
+    /// Only the settings specified on the command line are
+    /// changed. This implies `--if-not-exists` and additionally
+    /// requires the `configure_cache` permission on the cache.
+    #[clap(long)]
+    update: bool,
+
+    /// Read the signing keypair from standard input.
+    ///
+    /// The keypair should be in the `name:base64` format. By default,
+    /// the server generates a keypair. The keypair of an existing
+    /// cache is never changed.
+    #[clap(long, conflicts_with = "keypair_env")]
+    keypair_stdin: bool,
+
+    /// Read the signing keypair from an environment variable.
+    ///
+    /// The keypair should be in the `name:base64` format.
+    #[clap(long, value_name = "VAR")]
+    keypair_env: Option<String>,
+}
+
+/// Outcome of a cache creation request.
+#[derive(Debug, PartialEq, Eq)]
+enum CreateOutcome {
+    /// The cache was created.
+    Created,
+
+    /// The cache already exists and was left untouched.
+    Exists,
+
+    /// The cache already exists and should be updated.
+    NeedsUpdate,
 }
 
 /// Configure a cache.
 ///
 /// You need the `configure_cache` permission on the cache that
-/// you are configuring.
+/// you are configuring. Changing the retention period requires
+/// the `configure_cache_retention` permission instead.
 #[derive(Debug, Clone, Parser)]
 struct Configure {
     /// Name of the cache to configure.
@@ -135,6 +215,149 @@ struct Configure {
     /// Reset the retention period of the cache to global default.
     #[clap(long)]
     reset_retention_period: bool,
+
+    /// Emit absolute NAR URLs under this base URL in narinfos.
+    ///
+    /// The URL must start with http:// or https:// and end
+    /// with a slash, like "https://cdn.example.com/demo/".
+    #[clap(long, value_name = "URL")]
+    nar_url_base: Option<String>,
+
+    /// Emit NAR URLs relative to the binary cache endpoint.
+    #[clap(long)]
+    reset_nar_url_base: bool,
+
+    /// Serve signatures by upstream caches alongside the cache's own.
+    ///
+    /// This keeps signatures from upstream caches like
+    /// cache.nixos.org in narinfos. Other signatures of uploaded
+    /// paths, like those made with `attic push --sign-key`, are
+    /// always served. Use `--no-preserve-upstream-signatures` to
+    /// disable.
+    #[clap(long)]
+    preserve_upstream_signatures: bool,
+
+    /// Strip signatures by upstream caches from narinfos.
+    ///
+    /// Use `--preserve-upstream-signatures` to enable.
+    #[clap(long)]
+    no_preserve_upstream_signatures: bool,
+
+    /// Set the minimum NAR size to trigger chunking, in bytes.
+    ///
+    /// If 0, chunking is disabled for the cache. This and the
+    /// other chunking parameters only affect new uploads, and
+    /// changing them makes new uploads share few chunks with
+    /// existing data.
+    #[clap(long, value_name = "BYTES")]
+    chunk_nar_size_threshold: Option<usize>,
+
+    /// Set the preferred minimum size of a chunk, in bytes.
+    #[clap(long, value_name = "BYTES")]
+    chunk_min_size: Option<usize>,
+
+    /// Set the preferred average size of a chunk, in bytes.
+    #[clap(long, value_name = "BYTES")]
+    chunk_avg_size: Option<usize>,
+
+    /// Set the preferred maximum size of a chunk, in bytes.
+    #[clap(long, value_name = "BYTES")]
+    chunk_max_size: Option<usize>,
+
+    /// Reset the chunking parameters of the cache to global default.
+    #[clap(long)]
+    reset_chunking: bool,
+}
+
+impl Configure {
+    /// Returns the patch to send to the server.
+    ///
+    /// Only fields corresponding to flags that were passed are set,
+    /// so retention-only changes can be made with just the
+    /// `configure_cache_retention` permission.
+    fn to_patch(&self) -> Result<CacheConfig> {
+        let mut patch = CacheConfig::blank();
+
+        if self.public && self.private {
+            return Err(anyhow!(
+                "`--public` and `--private` cannot be set at the same time."
+            ));
+        }
+
+        if self.retention_period.is_some() && self.reset_retention_period {
+            return Err(anyhow!(
+                "`--retention-period` and `--reset-retention-period` cannot be set at the same time."
+            ));
+        }
+
+        if self.nar_url_base.is_some() && self.reset_nar_url_base {
+            return Err(anyhow!(
+                "`--nar-url-base` and `--reset-nar-url-base` cannot be set at the same time."
+            ));
+        }
+
+        if self.preserve_upstream_signatures && self.no_preserve_upstream_signatures {
+            return Err(anyhow!(
+                "`--preserve-upstream-signatures` and `--no-preserve-upstream-signatures` cannot be set at the same time."
+            ));
+        }
+
+        let chunking = ChunkingOverrides {
+            nar_size_threshold: self.chunk_nar_size_threshold,
+            min_size: self.chunk_min_size,
+            avg_size: self.chunk_avg_size,
+            max_size: self.chunk_max_size,
+        };
+
+        if !chunking.is_empty() && self.reset_chunking {
+            return Err(anyhow!(
+                "`--chunk-*` and `--reset-chunking` cannot be set at the same time."
+            ));
+        }
+
+        if self.public {
+            patch.is_public = Some(true);
+        } else if self.private {
+            patch.is_public = Some(false);
+        }
+
+        if let Some(period) = self.retention_period {
+            patch.retention_period = Some(RetentionPeriodConfig::Period(period.as_secs() as u32));
+        } else if self.reset_retention_period {
+            patch.retention_period = Some(RetentionPeriodConfig::Global);
+        }
+
+        if let Some(base) = &self.nar_url_base {
+            let config = NarUrlBaseConfig::Base(base.clone());
+            config.validate().map_err(|e| anyhow!(e))?;
+            patch.nar_url_base = Some(config);
+        } else if self.reset_nar_url_base {
+            patch.nar_url_base = Some(NarUrlBaseConfig::Relative);
+        }
+
+        if self.preserve_upstream_signatures {
+            patch.preserve_upstream_signatures = Some(true);
+        } else if self.no_preserve_upstream_signatures {
+            patch.preserve_upstream_signatures = Some(false);
+        }
+
+        if !chunking.is_empty() {
+            chunking.validate().map_err(|e| anyhow!(e))?;
+            patch.chunking = Some(ChunkingConfig::Override(chunking));
+        } else if self.reset_chunking {
+            patch.chunking = Some(ChunkingConfig::Global);
+        }
+
+        if self.regenerate_keypair {
+            patch.keypair = Some(KeypairConfig::Generate);
+        }
+
+        patch.store_dir = self.store_dir.clone();
+        patch.priority = self.priority;
+        patch.upstream_cache_key_names = self.upstream_cache_key_names.clone();
+
+        Ok(patch)
+    }
 }
 
 /// Destroy a cache.
@@ -156,6 +379,194 @@ struct Destroy {
     no_confirm: bool,
 }
 
+/// Create or update a cache from a declarative definition.
+///
+/// The definition is a TOML file with the name of the cache in
+/// `cache` and any of `public`, `priority`, `retention-period`
+/// ("global" or a duration like "30d") and `upstream-cache-key-names`.
+///
+/// Only the settings present in the file are managed. If the cache
+/// doesn't exist, it's created with the default values for the
+/// missing settings.
+#[derive(Debug, Clone, Parser)]
+struct Apply {
+    /// Path to the cache definition.
+    file: PathBuf,
+}
+
+/// A declarative cache definition.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CacheDefinition {
+    /// Name of the cache.
+    ///
+    /// This can be either `servername:cachename` or `cachename`
+    /// when using the default server.
+    cache: String,
+
+    /// Whether the cache is public.
+    public: Option<bool>,
+
+    /// The priority of the binary cache.
+    priority: Option<i32>,
+
+    /// The retention period of the cache.
+    ///
+    /// This is either "global" or a duration like "30d".
+    #[serde(rename = "retention-period")]
+    #[serde(default, deserialize_with = "deserialize_retention_period")]
+    retention_period: Option<RetentionPeriodConfig>,
+
+    /// The signing key names of upstream caches.
+    #[serde(rename = "upstream-cache-key-names")]
+    upstream_cache_key_names: Option<Vec<String>>,
+}
+
+/// A change made by `attic cache apply`.
+#[derive(Debug, PartialEq, Eq)]
+struct Change {
+    field: &'static str,
+    old: Option<String>,
+    new: String,
+}
+
+/// Run garbage collection on a cache.
+///
+/// This deletes objects older than the retention period of the
+/// cache. You need the `configure_cache_retention` permission on
+/// the cache.
+///
+/// Large caches are collected in the background on the server.
+#[derive(Debug, Clone, Parser)]
+struct Gc {
+    /// Name of the cache to collect.
+    cache: CacheRef,
+
+    /// Wait for background garbage collection to finish.
+    #[clap(long)]
+    wait: bool,
+}
+
+/// Show changes to a cache.
+///
+/// Each line contains the sequence number, time, kind of change,
+/// and the store path hash affected. You need the `pull` permission
+/// on the cache.
+#[derive(Debug, Clone, Parser)]
+struct Events {
+    /// Name of the cache to show changes of.
+    cache: CacheRef,
+
+    /// Only show events after this sequence number.
+    #[clap(long, default_value = "0")]
+    since: i64,
+
+    /// Keep waiting for new events.
+    #[clap(short = 'f', long)]
+    follow: bool,
+}
+
+/// List objects in a cache.
+///
+/// Each line contains the store path, NAR size, upload time and
+/// uploader. You need the `pull` permission on the cache.
+#[derive(Debug, Clone, Parser)]
+struct Ls {
+    /// Name of the cache to list.
+    cache: CacheRef,
+
+    /// Only list store paths containing this string.
+    #[clap(long)]
+    name: Option<String>,
+
+    /// Only list objects with NARs at least this many bytes large.
+    #[clap(long)]
+    min_size: Option<u64>,
+
+    /// Only list objects with NARs at most this many bytes large.
+    #[clap(long)]
+    max_size: Option<u64>,
+
+    /// Only list objects uploaded by this user.
+    #[clap(long)]
+    created_by: Option<String>,
+
+    /// Only list objects uploaded after this time.
+    ///
+    /// This is an RFC 3339 timestamp like `2023-01-01T00:00:00Z`.
+    #[clap(long, value_parser = parse_timestamp)]
+    created_after: Option<i64>,
+
+    /// Output one JSON object per line.
+    #[clap(long)]
+    json: bool,
+}
+
+/// List all store paths in a cache.
+///
+/// Paths are printed in the order they were uploaded. You need
+/// the `pull` permission on the cache.
+#[derive(Debug, Clone, Parser)]
+struct ListPaths {
+    /// Name of the cache to list.
+    cache: CacheRef,
+}
+
+/// Delete all paths matching a filter from a cache.
+///
+/// At least one filter must be given. Unless `--yes` is passed, the
+/// number of matching paths is shown and you are asked to confirm.
+/// You need the `delete` permission on the cache.
+#[derive(Debug, Clone, Parser)]
+struct Delete {
+    /// Name of the cache to delete paths from.
+    cache: CacheRef,
+
+    /// Only delete paths uploaded longer ago than this.
+    ///
+    /// This is a duration like `90d`.
+    #[clap(long)]
+    older_than: Option<Duration>,
+
+    /// Only delete paths whose name matches this glob.
+    ///
+    /// The name is the part of the store path after the hash,
+    /// like `hello-2.10`.
+    #[clap(long)]
+    name_glob: Option<String>,
+
+    /// Only delete paths uploaded by this user.
+    #[clap(long)]
+    created_by: Option<String>,
+
+    /// Only show how many paths would be deleted.
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Don't ask for interactive confirmation.
+    #[clap(long)]
+    yes: bool,
+}
+
+/// Delete a store path from a cache.
+///
+/// You need the `delete` permission on the cache.
+#[derive(Debug, Clone, Parser)]
+struct DeletePath {
+    /// Name of the cache to delete the path from.
+    cache: CacheRef,
+
+    /// The store path to delete.
+    path: PathBuf,
+
+    /// Also delete paths in the cache that depend on the path.
+    ///
+    /// This applies recursively. Either all paths are deleted
+    /// or none.
+    #[clap(long)]
+    with_referrers: bool,
+}
+
 /// Show the current configuration of a cache.
 #[derive(Debug, Clone, Parser)]
 struct Info {
@@ -163,6 +574,33 @@ struct Info {
     cache: CacheRef,
 }
 
+/// Print the nix.conf settings to use a cache.
+///
+/// This prints the `substituters`, `trusted-public-keys`, and
+/// `netrc-file` lines to stdout without changing anything. If the
+/// cache requires a token, the netrc entry is printed to stderr.
+///
+/// Use `attic use` to edit your configuration automatically instead.
+#[derive(Debug, Clone, Parser)]
+struct NixConf {
+    /// Name of the cache to configure.
+    cache: CacheRef,
+
+    /// The netrc file that will hold the token.
+    #[clap(long, default_value = SYSTEM_NETRC)]
+    netrc_file: String,
+}
+
+/// Print the public key of a cache.
+///
+/// Only the key is printed to stdout, so it can be added to
+/// `trusted-public-keys` directly.
+#[derive(Debug, Clone, Parser)]
+struct PublicKey {
+    /// Name of the cache to query.
+    cache: CacheRef,
+}
+
 pub async fn run(opts: Opts) -> Result<()> {
     let sub = opts.command.as_cache().unwrap();
     match &sub.command {
@@ -170,6 +608,15 @@ pub async fn run(opts: Opts) -> Result<()> {
         Command::Configure(sub) => configure_cache(sub.to_owned()).await,
         Command::Destroy(sub) => destroy_cache(sub.to_owned()).await,
         Command::Info(sub) => show_cache_config(sub.to_owned()).await,
+        Command::PublicKey(sub) => show_public_key(sub.to_owned()).await,
+        Command::NixConf(sub) => show_nix_conf(sub.to_owned()).await,
+        Command::Apply(sub) => apply_cache(sub.to_owned()).await,
+        Command::Gc(sub) => collect_cache(sub.to_owned()).await,
+        Command::Events(sub) => show_cache_events(sub.to_owned()).await,
+        Command::Ls(sub) => list_objects(sub.to_owned()).await,
+        Command::ListPaths(sub) => list_paths(sub.to_owned()).await,
+        Command::Delete(sub) => delete_objects(sub.to_owned()).await,
+        Command::DeletePath(sub) => delete_path(sub.to_owned()).await,
     }
 }
 
@@ -179,62 +626,129 @@ async fn create_cache(sub: Create) -> Result<()> {
     let (server_name, server, cache) = config.resolve_cache(&sub.cache)?;
     let api = ApiClient::from_server_config(server.clone())?;
 
-    let request = CreateCacheRequest {
-        // TODO: Make this configurable?
-        keypair: KeypairConfig::Generate,
-        is_public: sub.public,
-        priority: sub.priority,
-        store_dir: sub.store_dir,
-        upstream_cache_key_names: sub.upstream_cache_key_names,
-    };
+    let request = sub.to_request()?;
 
-    api.create_cache(cache, request).await?;
-    eprintln!(
-        "✨ Created cache \"{}\" on \"{}\"",
-        cache.as_str(),
-        server_name.as_str()
-    );
+    let result = api.create_cache(cache, request).await;
+    match create_outcome(result, sub.if_not_exists, sub.update)? {
+        CreateOutcome::Created => {
+            eprintln!(
+                "✨ Created cache \"{}\" on \"{}\"",
+                cache.as_str(),
+                server_name.as_str()
+            );
+        }
+        CreateOutcome::Exists => {
+            eprintln!(
+                "✅ Cache \"{}\" already exists on \"{}\"",
+                cache.as_str(),
+                server_name.as_str()
+            );
+        }
+        CreateOutcome::NeedsUpdate => {
+            let patch = sub.to_patch();
+            api.configure_cache(cache, &patch).await.map_err(|e| {
+                if ApiError::is(&e, ErrorCode::PermissionDenied)
+                    || ApiError::is(&e, ErrorCode::Unauthorized)
+                {
+                    e.context("The cache already exists, but updating it requires the `configure_cache` permission")
+                } else {
+                    e
+                }
+            })?;
+
+            eprintln!(
+                "✅ Updated existing cache \"{}\" on \"{}\"",
+                cache.as_str(),
+                server_name.as_str()
+            );
+        }
+    }
 
     Ok(())
 }
 
-async fn configure_cache(sub: Configure) -> Result<()> {
-    let config = Config::load()?;
+/// Decides what to do with the result of a cache creation request.
+fn create_outcome(result: Result<()>, if_not_exists: bool, update: bool) -> Result<CreateOutcome> {
+    match result {
+        Ok(()) => Ok(CreateOutcome::Created),
+        Err(e) if ApiError::is(&e, ErrorCode::CacheAlreadyExists) => {
+            if update {
+                Ok(CreateOutcome::NeedsUpdate)
+            } else if if_not_exists {
+                Ok(CreateOutcome::Exists)
+            } else {
+                Err(e)
+            }
+        }
+        Err(e) => Err(e),
+    }
+}
 
-    let (server_name, server, cache) = config.resolve_cache(&sub.cache)?;
-    let mut patch = CacheConfig::blank();
+impl Create {
+    /// Returns the keypair to create the cache with.
+    fn keypair(&self) -> Result<KeypairConfig> {
+        let keypair = if self.keypair_stdin {
+            let mut keypair = String::new();
+            io::stdin()
+                .read_to_string(&mut keypair)
+                .context("Failed to read keypair from standard input")?;
+            keypair
+        } else if let Some(var) = &self.keypair_env {
+            env::var(var).with_context(|| format!("Failed to read keypair from ${}", var))?
+        } else {
+            return Ok(KeypairConfig::Generate);
+        };
 
-    if sub.public && sub.private {
-        return Err(anyhow!(
-            "`--public` and `--private` cannot be set at the same time."
-        ));
+        let keypair = NixKeypair::from_str(keypair.trim())?;
+        Ok(KeypairConfig::Keypair(keypair))
     }
 
-    if sub.retention_period.is_some() && sub.reset_retention_period {
-        return Err(anyhow!(
-            "`--retention-period` and `--reset-retention-period` cannot be set at the same time."
-        ));
-    }
+    /// Returns the request to create the cache, filling in defaults.
+    fn to_request(&self) -> Result<CreateCacheRequest> {
+        let upstream_cache_key_names = if self.upstream_cache_key_names.is_empty() {
+            vec!["cache.nixos.org-1".to_string()]
+        } else {
+            self.upstream_cache_key_names.clone()
+        };
 
-    if sub.public {
-        patch.is_public = Some(true);
-    } else if sub.private {
-        patch.is_public = Some(false);
+        Ok(CreateCacheRequest {
+            keypair: self.keypair()?,
+            is_public: self.public,
+            priority: self.priority.unwrap_or(41),
+            store_dir: self
+                .store_dir
+                .clone()
+                .unwrap_or_else(|| "/nix/store".to_string()),
+            upstream_cache_key_names,
+        })
     }
 
-    if let Some(period) = sub.retention_period {
-        patch.retention_period = Some(RetentionPeriodConfig::Period(period.as_secs() as u32));
-    } else {
-        patch.retention_period = Some(RetentionPeriodConfig::Global);
-    }
+    /// Returns a patch applying the settings to an existing cache.
+    ///
+    /// Settings not specified on the command line are left unchanged.
+    fn to_patch(&self) -> CacheConfig {
+        let mut patch = CacheConfig::blank();
+
+        if self.public {
+            patch.is_public = Some(true);
+        }
 
-    if sub.regenerate_keypair {
-        patch.keypair = Some(KeypairConfig::Generate);
+        patch.store_dir = self.store_dir.clone();
+        patch.priority = self.priority;
+
+        if !self.upstream_cache_key_names.is_empty() {
+            patch.upstream_cache_key_names = Some(self.upstream_cache_key_names.clone());
+        }
+
+        patch
     }
+}
 
-    patch.store_dir = sub.store_dir;
-    patch.priority = sub.priority;
-    patch.upstream_cache_key_names = sub.upstream_cache_key_names;
+async fn configure_cache(sub: Configure) -> Result<()> {
+    let config = Config::load()?;
+
+    let (server_name, server, cache) = config.resolve_cache(&sub.cache)?;
+    let patch = sub.to_patch()?;
 
     let api = ApiClient::from_server_config(server.clone())?;
     api.configure_cache(cache, &patch).await?;
@@ -276,13 +790,499 @@ async fn destroy_cache(sub: Destroy) -> Result<()> {
     }
 
     let api = ApiClient::from_server_config(server.clone())?;
-    api.destroy_cache(cache).await?;
+    api.destroy_cache(cache).await.map_err(|e| {
+        if ApiError::is(&e, ErrorCode::NoSuchCache) {
+            e.context(format!("The cache \"{}\" does not exist", cache.as_str()))
+        } else if ApiError::is(&e, ErrorCode::PermissionDenied) {
+            e.context("Destroying the cache requires the `destroy_cache` permission")
+        } else {
+            e
+        }
+    })?;
 
     eprintln!("🗑️ The cache was destroyed.");
 
     Ok(())
 }
 
+async fn apply_cache(sub: Apply) -> Result<()> {
+    let config = Config::load()?;
+
+    let definition =
+        fs::read_to_string(&sub.file).with_context(|| format!("Failed to read {:?}", sub.file))?;
+    let definition: CacheDefinition =
+        toml::from_str(&definition).with_context(|| format!("Failed to parse {:?}", sub.file))?;
+    let cache_ref: CacheRef = definition.cache.parse()?;
+
+    let (server_name, server, cache) = config.resolve_cache(&cache_ref)?;
+    let api = ApiClient::from_server_config(server.clone())?;
+
+    let current = match api.get_cache_config(cache).await {
+        Ok(current) => Some(current),
+        Err(e) if ApiError::is(&e, ErrorCode::NoSuchCache) => None,
+        Err(e) => return Err(e),
+    };
+
+    let (changes, created) = if let Some(current) = current {
+        let (patch, changes) = definition.diff(&current);
+        if !changes.is_empty() {
+            api.configure_cache(cache, &patch).await?;
+        }
+
+        (changes, false)
+    } else {
+        let (request, patch) = definition.to_create_request();
+        api.create_cache(cache, request).await?;
+
+        if let Some(patch) = patch {
+            api.configure_cache(cache, &patch).await?;
+        }
+
+        (definition.diff(&CacheConfig::blank()).1, true)
+    };
+
+    for change in &changes {
+        eprintln!("{}", change);
+    }
+
+    if created {
+        eprintln!(
+            "✨ Created cache \"{}\" on \"{}\"",
+            cache.as_str(),
+            server_name.as_str()
+        );
+    } else if changes.is_empty() {
+        eprintln!(
+            "✅ Cache \"{}\" on \"{}\" is up to date",
+            cache.as_str(),
+            server_name.as_str()
+        );
+    } else {
+        eprintln!(
+            "✅ Updated cache \"{}\" on \"{}\"",
+            cache.as_str(),
+            server_name.as_str()
+        );
+    }
+
+    Ok(())
+}
+
+async fn collect_cache(sub: Gc) -> Result<()> {
+    let config = Config::load()?;
+
+    let (_, server, cache) = config.resolve_cache(&sub.cache)?;
+    let api = ApiClient::from_server_config(server.clone())?;
+
+    let mut job = api.run_cache_gc(cache).await?;
+
+    if !job.status.is_finished() {
+        if !sub.wait {
+            eprintln!(
+                "⏳ Garbage collection is running in the background (job {})",
+                job.id
+            );
+            return Ok(());
+        }
+
+        eprintln!("⏳ Waiting for garbage collection to finish...");
+        while !job.status.is_finished() {
+            tokio::time::sleep(GC_POLL_INTERVAL).await;
+            job = api.get_cache_gc_job(cache, &job.id).await?;
+        }
+    }
+
+    match job.status {
+        CacheGcStatus::Completed {
+            objects_deleted,
+            bytes_freed,
+        } => {
+            eprintln!(
+                "🗑️ Deleted {} objects from \"{}\" ({} of NARs freed)",
+                objects_deleted,
+                cache.as_str(),
+                HumanBytes(bytes_freed)
+            );
+            Ok(())
+        }
+        CacheGcStatus::Failed { error } => Err(anyhow!("Garbage collection failed: {}", error)),
+        CacheGcStatus::Running => unreachable!(),
+    }
+}
+
+async fn delete_path(sub: DeletePath) -> Result<()> {
+    let config = Config::load()?;
+
+    let store_path = resolve_store_path(&sub.path)?;
+
+    let (_, server, cache) = config.resolve_cache(&sub.cache)?;
+    let api = ApiClient::from_server_config(server.clone())?;
+
+    let result = api
+        .delete_path(cache, &store_path.to_hash(), sub.with_referrers)
+        .await?;
+
+    for path in &result.deleted_paths {
+        println!("{}", path);
+    }
+
+    eprintln!(
+        "🗑️ Deleted {} path{} from \"{}\"",
+        result.deleted_paths.len(),
+        if result.deleted_paths.len() == 1 {
+            ""
+        } else {
+            "s"
+        },
+        cache.as_str()
+    );
+
+    Ok(())
+}
+
+/// Resolves the store path to delete.
+#[cfg(feature = "nix_store")]
+fn resolve_store_path(path: &Path) -> Result<StorePath> {
+    let store = NixStore::connect()?;
+    Ok(store.follow_store_path(path)?)
+}
+
+/// Resolves the store path to delete.
+///
+/// Without access to the Nix Store, symlinks can't be followed, so
+/// the path must be a store path itself.
+#[cfg(not(feature = "nix_store"))]
+fn resolve_store_path(path: &Path) -> Result<StorePath> {
+    let base_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} is not a store path", path.display()))?;
+
+    Ok(StorePath::from_base_name(base_name.into())?)
+}
+
+async fn delete_objects(sub: Delete) -> Result<()> {
+    let config = Config::load()?;
+
+    let (server_name, server, cache) = config.resolve_cache(&sub.cache)?;
+    let api = ApiClient::from_server_config(server.clone())?;
+
+    let created_before = sub
+        .older_than
+        .map(|older_than| -> Result<i64> {
+            let cutoff = SystemTime::now()
+                .checked_sub(*older_than)
+                .ok_or_else(|| anyhow!("--older-than is too large"))?;
+            let secs = cutoff
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            Ok(secs as i64)
+        })
+        .transpose()?;
+
+    let mut request = DeleteObjectsRequest {
+        name_glob: sub.name_glob,
+        created_before,
+        created_by: sub.created_by,
+        dry_run: true,
+    };
+
+    if sub.dry_run || !sub.yes {
+        let result = api.delete_objects(cache, &request).await?;
+
+        if sub.dry_run {
+            eprintln!(
+                "Would delete {} path{} from \"{}\"",
+                result.num_deleted,
+                if result.num_deleted == 1 { "" } else { "s" },
+                cache.as_str()
+            );
+            return Ok(());
+        }
+
+        if result.num_deleted == 0 {
+            eprintln!("No paths match the filter.");
+            return Ok(());
+        }
+
+        let answer: String = Input::new()
+            .with_prompt(format!(
+                "⚠️ Type the cache name to confirm deleting {} path{} from \"{}\" on \"{}\"",
+                result.num_deleted,
+                if result.num_deleted == 1 { "" } else { "s" },
+                cache.as_str(),
+                server_name.as_str()
+            ))
+            .allow_empty(true)
+            .interact()?;
+
+        if answer != cache.as_str() {
+            return Err(anyhow!("Incorrect answer. Aborting..."));
+        }
+    }
+
+    request.dry_run = false;
+    let result = api.delete_objects(cache, &request).await?;
+
+    eprintln!(
+        "🗑️ Deleted {} path{} from \"{}\"",
+        result.num_deleted,
+        if result.num_deleted == 1 { "" } else { "s" },
+        cache.as_str()
+    );
+
+    Ok(())
+}
+
+async fn show_cache_events(sub: Events) -> Result<()> {
+    let config = Config::load()?;
+
+    let (_, server, cache) = config.resolve_cache(&sub.cache)?;
+    let api = ApiClient::from_server_config(server.clone())?;
+
+    let mut since = sub.since;
+    loop {
+        let timeout = if sub.follow {
+            EVENTS_POLL_TIMEOUT
+        } else {
+            StdDuration::ZERO
+        };
+
+        let batch = api.get_cache_events(cache, since, timeout).await?;
+
+        for event in &batch.events {
+            let time = UNIX_EPOCH + StdDuration::from_secs(event.timestamp.max(0) as u64);
+            let time = humantime::format_rfc3339_seconds(time);
+            let kind = match event.kind {
+                CacheEventKind::Upload => "upload",
+                CacheEventKind::Delete => "delete",
+                _ => "unknown",
+            };
+
+            println!("{} {} {} {}", event.seq, time, kind, event.store_path_hash);
+        }
+
+        if batch.events.is_empty() && !sub.follow {
+            return Ok(());
+        }
+
+        since = batch.last_seq;
+    }
+}
+
+async fn list_objects(sub: Ls) -> Result<()> {
+    let config = Config::load()?;
+
+    let (_, server, cache) = config.resolve_cache(&sub.cache)?;
+    let api = ApiClient::from_server_config(server.clone())?;
+
+    let mut query = ListObjectsQuery {
+        name: sub.name,
+        min_size: sub.min_size,
+        max_size: sub.max_size,
+        created_by: sub.created_by,
+        created_after: sub.created_after,
+        ..Default::default()
+    };
+
+    loop {
+        let page = api.list_objects(cache, &query).await?;
+
+        for object in &page.objects {
+            if sub.json {
+                println!("{}", serde_json::to_string(object)?);
+            } else {
+                let time = UNIX_EPOCH + StdDuration::from_secs(object.created_at.max(0) as u64);
+                let time = humantime::format_rfc3339_seconds(time);
+
+                println!(
+                    "{} {} {} {}",
+                    object.store_path,
+                    HumanBytes(object.nar_size),
+                    time,
+                    object.created_by.as_deref().unwrap_or("-"),
+                );
+            }
+        }
+
+        match page.next_cursor {
+            Some(cursor) => query.cursor = Some(cursor),
+            None => return Ok(()),
+        }
+    }
+}
+
+async fn list_paths(sub: ListPaths) -> Result<()> {
+    let config = Config::load()?;
+
+    let (_, server, cache) = config.resolve_cache(&sub.cache)?;
+    let api = ApiClient::from_server_config(server.clone())?;
+
+    let mut query = CacheObjectsQuery::default();
+    loop {
+        let page = api.list_cache_objects(cache, &query).await?;
+
+        for object in &page.objects {
+            println!("{}", object.store_path);
+        }
+
+        match page.next_cursor {
+            Some(cursor) => query.cursor = Some(cursor),
+            None => return Ok(()),
+        }
+    }
+}
+
+fn parse_timestamp(s: &str) -> Result<i64> {
+    let time = humantime::parse_rfc3339_weak(s)?;
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| anyhow!("Timestamp must be after the Unix epoch"))?
+        .as_secs();
+
+    Ok(secs as i64)
+}
+
+impl CacheDefinition {
+    /// Returns the patch and changes needed to bring a cache in line with the definition.
+    fn diff(&self, current: &CacheConfig) -> (CacheConfig, Vec<Change>) {
+        let mut patch = CacheConfig::blank();
+        let mut changes = Vec::new();
+
+        fn compare<T: PartialEq + fmt::Debug>(
+            changes: &mut Vec<Change>,
+            field: &'static str,
+            current: &Option<T>,
+            desired: &Option<T>,
+            format: impl Fn(&T) -> String,
+        ) -> bool {
+            let Some(desired) = desired else {
+                return false;
+            };
+
+            if current.as_ref() == Some(desired) {
+                return false;
+            }
+
+            changes.push(Change {
+                field,
+                old: current.as_ref().map(&format),
+                new: format(desired),
+            });
+
+            true
+        }
+
+        if compare(
+            &mut changes,
+            "public",
+            &current.is_public,
+            &self.public,
+            ToString::to_string,
+        ) {
+            patch.is_public = self.public;
+        }
+
+        if compare(
+            &mut changes,
+            "priority",
+            &current.priority,
+            &self.priority,
+            ToString::to_string,
+        ) {
+            patch.priority = self.priority;
+        }
+
+        if compare(
+            &mut changes,
+            "retention-period",
+            &current.retention_period,
+            &self.retention_period,
+            format_retention_period,
+        ) {
+            patch.retention_period = self.retention_period.clone();
+        }
+
+        if compare(
+            &mut changes,
+            "upstream-cache-key-names",
+            &current.upstream_cache_key_names,
+            &self.upstream_cache_key_names,
+            |names| format!("{:?}", names),
+        ) {
+            patch.upstream_cache_key_names = self.upstream_cache_key_names.clone();
+        }
+
+        (patch, changes)
+    }
+
+    /// Returns the request to create the cache.
+    ///
+    /// The retention period cannot be set at creation time, so a
+    /// patch to apply afterwards is returned if it's specified.
+    fn to_create_request(&self) -> (CreateCacheRequest, Option<CacheConfig>) {
+        let request = CreateCacheRequest {
+            keypair: KeypairConfig::Generate,
+            is_public: self.public.unwrap_or(false),
+            store_dir: "/nix/store".to_string(),
+            priority: self.priority.unwrap_or(41),
+            upstream_cache_key_names: self
+                .upstream_cache_key_names
+                .clone()
+                .unwrap_or_else(|| vec!["cache.nixos.org-1".to_string()]),
+        };
+
+        let patch = self.retention_period.clone().map(|period| {
+            let mut patch = CacheConfig::blank();
+            patch.retention_period = Some(period);
+            patch
+        });
+
+        (request, patch)
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(old) = &self.old {
+            write!(f, "~ {}: {} -> {}", self.field, old, self.new)
+        } else {
+            write!(f, "+ {}: {}", self.field, self.new)
+        }
+    }
+}
+
+fn format_retention_period(period: &RetentionPeriodConfig) -> String {
+    match period {
+        RetentionPeriodConfig::Global => "global".to_string(),
+        RetentionPeriodConfig::Period(secs) => {
+            humantime::format_duration(StdDuration::from_secs(*secs as u64)).to_string()
+        }
+    }
+}
+
+fn deserialize_retention_period<'de, D>(
+    deserializer: D,
+) -> Result<Option<RetentionPeriodConfig>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    use de::Error;
+
+    let s = String::deserialize(deserializer)?;
+    if s == "global" {
+        return Ok(Some(RetentionPeriodConfig::Global));
+    }
+
+    let period = humantime::parse_duration(&s).map_err(Error::custom)?;
+    let secs = period
+        .as_secs()
+        .try_into()
+        .map_err(|_| Error::custom("Retention period is too long"))?;
+
+    Ok(Some(RetentionPeriodConfig::Period(secs)))
+}
+
 async fn show_cache_config(sub: Info) -> Result<()> {
     let config = Config::load()?;
 
@@ -298,6 +1298,12 @@ async fn show_cache_config(sub: Info) -> Result<()> {
         eprintln!("           Public Key: {}", public_key);
     }
 
+    if let Some(previous_public_keys) = cache_config.previous_public_keys {
+        for public_key in previous_public_keys {
+            eprintln!("  Previous Public Key: {}", public_key);
+        }
+    }
+
     if let Some(substituter_endpoint) = cache_config.substituter_endpoint {
         eprintln!("Binary Cache Endpoint: {}", substituter_endpoint);
     }
@@ -329,5 +1335,437 @@ async fn show_cache_config(sub: Info) -> Result<()> {
         }
     }
 
+    if let Some(nar_url_base) = cache_config.nar_url_base {
+        match nar_url_base {
+            NarUrlBaseConfig::Base(base) => {
+                eprintln!("         NAR URL Base: {}", base);
+            }
+            NarUrlBaseConfig::Relative => {
+                eprintln!("         NAR URL Base: Relative");
+            }
+        }
+    }
+
+    if let Some(preserve) = cache_config.preserve_upstream_signatures {
+        eprintln!(
+            "  Upstream Signatures: {}",
+            if preserve { "Preserved" } else { "Stripped" }
+        );
+    }
+
+    if let Some(chunking) = cache_config.chunking {
+        match chunking {
+            ChunkingConfig::Override(overrides) => {
+                let format = |value: Option<usize>| {
+                    value.map_or_else(|| "global".to_string(), |v| v.to_string())
+                };
+
+                eprintln!(
+                    "             Chunking: threshold {}, min {}, avg {}, max {}",
+                    format(overrides.nar_size_threshold),
+                    format(overrides.min_size),
+                    format(overrides.avg_size),
+                    format(overrides.max_size)
+                );
+            }
+            ChunkingConfig::Global => {
+                eprintln!("             Chunking: Global Default");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn show_nix_conf(sub: NixConf) -> Result<()> {
+    let config = Config::load()?;
+    let settings = resolve_settings(&config, &sub.cache).await?;
+    print_settings(&settings, &sub.netrc_file);
+
     Ok(())
 }
+
+async fn show_public_key(sub: PublicKey) -> Result<()> {
+    let config = Config::load()?;
+
+    let (_, server, cache) = config.resolve_cache(&sub.cache)?;
+    let api = ApiClient::from_server_config(server.clone())?;
+    let cache_config = api.get_cache_config(cache).await?;
+
+    let public_key = cache_config.public_key.ok_or_else(|| {
+        anyhow!(
+            "The server did not return the public key of \"{}\"",
+            cache.as_str()
+        )
+    })?;
+
+    println!("{}", public_key);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::api::StructuredApiError;
+
+    fn api_error(kind: ErrorCode) -> anyhow::Error {
+        ApiError::Structured(StructuredApiError {
+            code: 409,
+            error: kind.name().to_string(),
+            error_code: Some(kind.code()),
+            message: "Some message".to_string(),
+        })
+        .into()
+    }
+
+    #[test]
+    fn test_create_outcome() {
+        assert_eq!(
+            CreateOutcome::Created,
+            create_outcome(Ok(()), false, false).unwrap()
+        );
+        assert_eq!(
+            CreateOutcome::Created,
+            create_outcome(Ok(()), true, true).unwrap()
+        );
+
+        assert!(
+            create_outcome(Err(api_error(ErrorCode::CacheAlreadyExists)), false, false).is_err()
+        );
+        assert_eq!(
+            CreateOutcome::Exists,
+            create_outcome(Err(api_error(ErrorCode::CacheAlreadyExists)), true, false).unwrap()
+        );
+        assert_eq!(
+            CreateOutcome::NeedsUpdate,
+            create_outcome(Err(api_error(ErrorCode::CacheAlreadyExists)), false, true).unwrap()
+        );
+
+        // Other errors are never masked
+        assert!(create_outcome(Err(api_error(ErrorCode::PermissionDenied)), true, false).is_err());
+        assert!(create_outcome(Err(api_error(ErrorCode::PermissionDenied)), true, true).is_err());
+    }
+
+    #[test]
+    fn test_create_to_patch() {
+        let create = Create::parse_from(["create", "test", "--public", "--priority", "30"]);
+        let patch = create.to_patch();
+
+        assert_eq!(Some(true), patch.is_public);
+        assert_eq!(Some(30), patch.priority);
+        assert!(patch.store_dir.is_none());
+        assert!(patch.upstream_cache_key_names.is_none());
+        assert!(patch.keypair.is_none());
+        assert!(patch.retention_period.is_none());
+
+        // Omitted flags are left unchanged
+        let create = Create::parse_from(["create", "test", "--update", "--priority", "30"]);
+        let patch = create.to_patch();
+
+        assert!(patch.is_public.is_none());
+        assert_eq!(Some(30), patch.priority);
+        assert!(patch.store_dir.is_none());
+        assert!(patch.upstream_cache_key_names.is_none());
+
+        let create = Create::parse_from([
+            "create",
+            "test",
+            "--update",
+            "--upstream-cache-key-name",
+            "example-1",
+        ]);
+        let patch = create.to_patch();
+
+        assert!(patch.priority.is_none());
+        assert_eq!(
+            Some(vec!["example-1".to_string()]),
+            patch.upstream_cache_key_names
+        );
+    }
+
+    #[test]
+    fn test_create_to_request() {
+        let request = Create::parse_from(["create", "test"]).to_request().unwrap();
+
+        assert!(!request.is_public);
+        assert_eq!(41, request.priority);
+        assert_eq!("/nix/store", request.store_dir);
+        assert_eq!(
+            vec!["cache.nixos.org-1".to_string()],
+            request.upstream_cache_key_names
+        );
+    }
+
+    #[test]
+    fn test_create_keypair_env() {
+        const VAR: &str = "ATTIC_TEST_CREATE_KEYPAIR";
+
+        let create = Create::parse_from(["create", "test"]);
+        assert!(matches!(create.keypair().unwrap(), KeypairConfig::Generate));
+
+        let expected = NixKeypair::generate("test-1").unwrap();
+        env::set_var(VAR, format!("{}\n", expected.export_keypair()));
+
+        let create = Create::parse_from(["create", "test", "--keypair-env", VAR]);
+        match create.keypair().unwrap() {
+            KeypairConfig::Keypair(keypair) => {
+                assert_eq!(expected.export_keypair(), keypair.export_keypair());
+            }
+            KeypairConfig::Generate => panic!("Keypair was not read"),
+        }
+
+        env::set_var(VAR, "not-a-keypair");
+        assert!(create.keypair().is_err());
+
+        env::remove_var(VAR);
+        assert!(create.keypair().is_err());
+
+        assert!(Create::try_parse_from([
+            "create",
+            "test",
+            "--keypair-stdin",
+            "--keypair-env",
+            VAR
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_configure_to_patch() {
+        // Retention-only changes don't touch other fields
+        let configure = Configure::parse_from(["configure", "test", "--retention-period", "1d"]);
+        let patch = configure.to_patch().unwrap();
+        assert!(matches!(
+            patch.retention_period,
+            Some(RetentionPeriodConfig::Period(86400))
+        ));
+        assert!(patch.is_public.is_none());
+        assert!(patch.priority.is_none());
+        assert!(patch.keypair.is_none());
+
+        let configure = Configure::parse_from(["configure", "test", "--reset-retention-period"]);
+        let patch = configure.to_patch().unwrap();
+        assert!(matches!(
+            patch.retention_period,
+            Some(RetentionPeriodConfig::Global)
+        ));
+
+        // Other changes don't reset the retention period
+        let configure =
+            Configure::parse_from(["configure", "test", "--private", "--priority", "30"]);
+        let patch = configure.to_patch().unwrap();
+        assert_eq!(Some(false), patch.is_public);
+        assert_eq!(Some(30), patch.priority);
+        assert!(patch.retention_period.is_none());
+
+        // Conflicting flags
+        let configure = Configure::parse_from(["configure", "test", "--public", "--private"]);
+        assert!(configure.to_patch().is_err());
+
+        let configure = Configure::parse_from([
+            "configure",
+            "test",
+            "--retention-period",
+            "1d",
+            "--reset-retention-period",
+        ]);
+        assert!(configure.to_patch().is_err());
+
+        // NAR URL base
+        let configure = Configure::parse_from([
+            "configure",
+            "test",
+            "--nar-url-base",
+            "https://cdn.example.com/test/",
+        ]);
+        assert_eq!(
+            Some(NarUrlBaseConfig::Base(
+                "https://cdn.example.com/test/".to_string()
+            )),
+            configure.to_patch().unwrap().nar_url_base
+        );
+
+        let configure = Configure::parse_from(["configure", "test", "--reset-nar-url-base"]);
+        assert_eq!(
+            Some(NarUrlBaseConfig::Relative),
+            configure.to_patch().unwrap().nar_url_base
+        );
+
+        let configure = Configure::parse_from([
+            "configure",
+            "test",
+            "--nar-url-base",
+            "https://cdn.example.com/test",
+        ]);
+        assert!(configure.to_patch().is_err());
+
+        // Upstream signatures
+        let configure =
+            Configure::parse_from(["configure", "test", "--preserve-upstream-signatures"]);
+        assert_eq!(
+            Some(true),
+            configure.to_patch().unwrap().preserve_upstream_signatures
+        );
+
+        let configure =
+            Configure::parse_from(["configure", "test", "--no-preserve-upstream-signatures"]);
+        assert_eq!(
+            Some(false),
+            configure.to_patch().unwrap().preserve_upstream_signatures
+        );
+
+        let configure = Configure::parse_from(["configure", "test", "--priority", "42"]);
+        assert!(configure
+            .to_patch()
+            .unwrap()
+            .preserve_upstream_signatures
+            .is_none());
+
+        let configure = Configure::parse_from([
+            "configure",
+            "test",
+            "--preserve-upstream-signatures",
+            "--no-preserve-upstream-signatures",
+        ]);
+        assert!(configure.to_patch().is_err());
+    }
+
+    #[test]
+    fn test_configure_chunking() {
+        let configure = Configure::parse_from(["configure", "test", "--chunk-min-size", "65536"]);
+        assert_eq!(
+            Some(ChunkingConfig::Override(ChunkingOverrides {
+                min_size: Some(65536),
+                ..Default::default()
+            })),
+            configure.to_patch().unwrap().chunking
+        );
+
+        let configure = Configure::parse_from(["configure", "test", "--reset-chunking"]);
+        assert_eq!(
+            Some(ChunkingConfig::Global),
+            configure.to_patch().unwrap().chunking
+        );
+
+        let configure = Configure::parse_from(["configure", "test", "--priority", "42"]);
+        assert!(configure.to_patch().unwrap().chunking.is_none());
+
+        let configure = Configure::parse_from([
+            "configure",
+            "test",
+            "--chunk-min-size",
+            "65536",
+            "--reset-chunking",
+        ]);
+        assert!(configure.to_patch().is_err());
+
+        let configure = Configure::parse_from([
+            "configure",
+            "test",
+            "--chunk-min-size",
+            "65536",
+            "--chunk-max-size",
+            "16384",
+        ]);
+        assert!(configure.to_patch().is_err());
+    }
+
+    fn definition(s: &str) -> CacheDefinition {
+        toml::from_str(s).unwrap()
+    }
+
+    fn current() -> CacheConfig {
+        let mut config = CacheConfig::blank();
+        config.is_public = Some(false);
+        config.priority = Some(41);
+        config.retention_period = Some(RetentionPeriodConfig::Global);
+        config.upstream_cache_key_names = Some(vec!["cache.nixos.org-1".to_string()]);
+        config
+    }
+
+    #[test]
+    fn test_cache_definition_diff() {
+        // Up to date
+        let (patch, changes) = definition(
+            r#"
+            cache = "demo"
+            public = false
+            retention-period = "global"
+            "#,
+        )
+        .diff(&current());
+        assert!(changes.is_empty());
+        assert!(patch.is_public.is_none());
+        assert!(patch.retention_period.is_none());
+
+        // Only changed fields are patched
+        let (patch, changes) = definition(
+            r#"
+            cache = "demo"
+            public = false
+            priority = 30
+            retention-period = "1d"
+            "#,
+        )
+        .diff(&current());
+        assert_eq!(
+            vec![
+                Change {
+                    field: "priority",
+                    old: Some("41".to_string()),
+                    new: "30".to_string(),
+                },
+                Change {
+                    field: "retention-period",
+                    old: Some("global".to_string()),
+                    new: "1day".to_string(),
+                },
+            ],
+            changes
+        );
+        assert!(patch.is_public.is_none());
+        assert_eq!(Some(30), patch.priority);
+        assert_eq!(
+            Some(RetentionPeriodConfig::Period(86400)),
+            patch.retention_period
+        );
+        assert!(patch.upstream_cache_key_names.is_none());
+
+        assert_eq!("~ priority: 41 -> 30", changes[0].to_string());
+    }
+
+    #[test]
+    fn test_cache_definition_create() {
+        let definition = definition(
+            r#"
+            cache = "server:demo"
+            public = true
+            retention-period = "30d"
+            "#,
+        );
+
+        let (request, patch) = definition.to_create_request();
+        assert!(request.is_public);
+        assert_eq!(41, request.priority);
+        assert_eq!(
+            Some(RetentionPeriodConfig::Period(30 * 86400)),
+            patch.unwrap().retention_period
+        );
+
+        let (_, changes) = definition.diff(&CacheConfig::blank());
+        assert_eq!("+ public: true", changes[0].to_string());
+        assert_eq!(2, changes.len());
+    }
+
+    #[test]
+    fn test_cache_definition_invalid() {
+        assert!(toml::from_str::<CacheDefinition>("cache = \"demo\"\nquota = 1").is_err());
+        assert!(
+            toml::from_str::<CacheDefinition>("cache = \"demo\"\nretention-period = \"soon\"")
+                .is_err()
+        );
+        assert!(toml::from_str::<CacheDefinition>("public = true").is_err());
+    }
+}
@@ -4,6 +4,7 @@ use clap::Parser;
 use crate::cache::ServerName;
 use crate::cli::Opts;
 use crate::config::{Config, ServerConfig, ServerTokenConfig};
+use crate::oauth::{self, LoginOptions};
 
 /// Log into an Attic server.
 #[derive(Debug, Parser)]
@@ -15,43 +16,82 @@ pub struct Login {
     endpoint: String,
 
     /// Access token.
+    #[clap(conflicts_with = "oauth")]
     token: Option<String>,
 
     /// Set the server as the default.
     #[clap(long)]
     set_default: bool,
+
+    /// Log in through an OAuth identity provider with this issuer URL.
+    ///
+    /// This uses the device authorization grant, and the resulting
+    /// access token is exchanged for an Attic token at the
+    /// exchange URL. The token is renewed automatically before pushes.
+    #[clap(long, value_name = "ISSUER_URL", requires = "exchange_url")]
+    oauth: Option<String>,
+
+    /// The endpoint exchanging access tokens for Attic tokens.
+    #[clap(long, value_name = "URL", requires = "oauth")]
+    exchange_url: Option<String>,
+
+    /// The OAuth client ID.
+    #[clap(long, default_value = "attic", requires = "oauth")]
+    client_id: String,
+
+    /// The OAuth scopes to request.
+    #[clap(long, default_value = "openid offline_access", requires = "oauth")]
+    scope: String,
 }
 
 pub async fn run(opts: Opts) -> Result<()> {
     let sub = opts.command.as_login().unwrap();
     let mut config = Config::load()?;
-    let mut config_m = config.as_mut();
 
-    if let Some(server) = config_m.servers.get_mut(&sub.name) {
-        eprintln!("✍️ Overwriting server \"{}\"", sub.name.as_str());
+    let mut server = config
+        .servers
+        .get(&sub.name)
+        .cloned()
+        .unwrap_or_else(|| ServerConfig {
+            endpoint: sub.endpoint.to_owned(),
+            token: None,
+            request_timeout: None,
+            connect_timeout: None,
+            pool_max_idle_per_host: None,
+            oauth: None,
+        });
+    server.endpoint = sub.endpoint.to_owned();
 
-        server.endpoint = sub.endpoint.to_owned();
+    if let (Some(issuer), Some(exchange_url)) = (&sub.oauth, &sub.exchange_url) {
+        let options = LoginOptions {
+            issuer,
+            client_id: &sub.client_id,
+            scope: &sub.scope,
+            exchange_url,
+        };
+        let result = oauth::login(&server, &options).await?;
 
-        if let Some(token) = &sub.token {
-            server.token = Some(ServerTokenConfig::Raw {
-                token: token.clone(),
-            });
-        }
+        server.token = Some(ServerTokenConfig::Raw {
+            token: result.token,
+        });
+        server.oauth = Some(result.oauth);
+    } else if let Some(token) = &sub.token {
+        server.token = Some(ServerTokenConfig::Raw {
+            token: token.clone(),
+        });
+        server.oauth = None;
+    }
+
+    let mut config_m = config.as_mut();
+
+    if config_m.servers.contains_key(&sub.name) {
+        eprintln!("✍️ Overwriting server \"{}\"", sub.name.as_str());
     } else {
         eprintln!("✍️ Configuring server \"{}\"", sub.name.as_str());
-
-        config_m.servers.insert(
-            sub.name.to_owned(),
-            ServerConfig {
-                endpoint: sub.endpoint.to_owned(),
-                token: sub
-                    .token
-                    .to_owned()
-                    .map(|token| ServerTokenConfig::Raw { token }),
-            },
-        );
     }
 
+    config_m.servers.insert(sub.name.to_owned(), server);
+
     if sub.set_default || config_m.servers.len() == 1 {
         config_m.default_server = Some(sub.name.to_owned());
     }
@@ -0,0 +1,211 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use indicatif::HumanBytes;
+
+use crate::api::ApiClient;
+use crate::cache::{CacheName, CacheRef};
+use crate::cli::Opts;
+use crate::config::Config;
+use crate::narinfo::NarInfo;
+use attic::nix_store::{NixStore, StorePath, ValidPathInfo};
+
+/// Pull closures from a binary cache into the local store.
+///
+/// The paths are pulled along with their references, skipping
+/// those already in the local store. Each NAR is checked against
+/// the hash and size in its narinfo before it's imported.
+#[derive(Debug, Parser)]
+pub struct Pull {
+    /// The cache to pull from.
+    ///
+    /// This can be either `servername:cachename` or `cachename`
+    /// when using the default server.
+    cache: CacheRef,
+
+    /// The store paths to pull.
+    paths: Vec<PathBuf>,
+
+    /// Don't require the paths to be signed by a key trusted by the local store.
+    ///
+    /// Like `nix copy --no-check-sigs`, this requires the user to be
+    /// trusted by the Nix daemon.
+    #[clap(long)]
+    no_check_sigs: bool,
+
+    /// Print the paths that would be pulled without pulling them.
+    #[clap(long)]
+    dry_run: bool,
+}
+
+/// A path missing from the local store.
+struct MissingPath {
+    path: StorePath,
+    narinfo: NarInfo,
+
+    /// References other than the path itself.
+    references: Vec<StorePath>,
+}
+
+pub async fn run(opts: Opts) -> Result<()> {
+    let sub = opts.command.as_pull().unwrap();
+    let config = Config::load()?;
+
+    let store = NixStore::connect()?;
+
+    let (_, server, cache_name) = config.resolve_cache(&sub.cache)?;
+    let api = ApiClient::from_server_config(server.clone())?;
+
+    // The paths don't exist locally, so there are no symlinks to follow
+    let roots = sub
+        .paths
+        .iter()
+        .map(|p| store.parse_store_path(p))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+
+    if roots.is_empty() {
+        eprintln!("🤷 Nothing specified.");
+        return Ok(());
+    }
+
+    let missing = find_missing_paths(&store, &api, cache_name, roots).await?;
+    if missing.is_empty() {
+        eprintln!("✅ All done! (everything is already in the local store)");
+        return Ok(());
+    }
+
+    let missing = sort_by_references(missing);
+
+    if sub.dry_run {
+        for missing in &missing {
+            println!("{}", store.get_full_path(&missing.path).display());
+        }
+        return Ok(());
+    }
+
+    let num_paths = missing.len();
+    for MissingPath { path, narinfo, .. } in missing {
+        let full_path = store.get_full_path(&path);
+        eprintln!(
+            "⬇️ Pulling {} ({})",
+            full_path.display(),
+            HumanBytes(narinfo.nar_size as u64)
+        );
+
+        let path_info = to_path_info(path, &narinfo)?;
+        let reader = api.get_decompressed_nar(cache_name, &narinfo).await?;
+
+        store
+            .import_nar(path_info, reader, !sub.no_check_sigs)
+            .await
+            .with_context(|| format!("Failed to import {}", full_path.display()))?;
+    }
+
+    eprintln!(
+        "✅ Pulled {} path{} from \"{}\"",
+        num_paths,
+        if num_paths == 1 { "" } else { "s" },
+        cache_name.as_str()
+    );
+
+    Ok(())
+}
+
+/// Returns the paths in the closures of `roots` that are missing locally.
+async fn find_missing_paths(
+    store: &NixStore,
+    api: &ApiClient,
+    cache_name: &CacheName,
+    roots: Vec<StorePath>,
+) -> Result<Vec<MissingPath>> {
+    let mut queue = roots;
+    let mut seen = HashSet::new();
+    let mut missing = Vec::new();
+
+    while let Some(path) = queue.pop() {
+        if !seen.insert(path.clone()) {
+            continue;
+        }
+
+        if store.query_path_info(path.clone()).await.is_ok() {
+            continue;
+        }
+
+        let narinfo = api
+            .get_narinfo(cache_name, &path.to_hash())
+            .await?
+            .ok_or_else(|| {
+                anyhow!(
+                    "{} is not in the cache",
+                    store.get_full_path(&path).display()
+                )
+            })?;
+
+        let references = narinfo
+            .references
+            .iter()
+            .map(|r| StorePath::from_base_name(PathBuf::from(r)))
+            .filter(|r| r.as_ref().map_or(true, |r| *r != path))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        queue.extend(references.iter().cloned());
+        missing.push(MissingPath {
+            path,
+            narinfo,
+            references,
+        });
+    }
+
+    Ok(missing)
+}
+
+/// Sorts missing paths so that each comes after its references.
+///
+/// Nix refuses to import a path whose references aren't valid.
+fn sort_by_references(mut remaining: Vec<MissingPath>) -> Vec<MissingPath> {
+    let mut sorted = Vec::with_capacity(remaining.len());
+    let mut pending: HashSet<StorePath> = remaining.iter().map(|m| m.path.clone()).collect();
+
+    while !remaining.is_empty() {
+        let (ready, rest): (Vec<_>, Vec<_>) = remaining
+            .into_iter()
+            .partition(|m| m.references.iter().all(|r| !pending.contains(r)));
+
+        // Store paths can't have reference cycles other than
+        // self-references, but don't loop forever on bad narinfos
+        if ready.is_empty() {
+            sorted.extend(rest);
+            break;
+        }
+
+        for m in &ready {
+            pending.remove(&m.path);
+        }
+
+        sorted.extend(ready);
+        remaining = rest;
+    }
+
+    sorted
+}
+
+/// Converts a narinfo to path info to import with.
+fn to_path_info(path: StorePath, narinfo: &NarInfo) -> Result<ValidPathInfo> {
+    let deriver = narinfo
+        .deriver
+        .as_ref()
+        .map(|d| StorePath::from_base_name(PathBuf::from(d)))
+        .transpose()?;
+
+    Ok(ValidPathInfo {
+        path,
+        nar_hash: narinfo.nar_hash.clone(),
+        nar_size: narinfo.nar_size as u64,
+        references: narinfo.references.iter().map(PathBuf::from).collect(),
+        sigs: narinfo.signatures.clone(),
+        ca: narinfo.ca.clone(),
+        deriver,
+    })
+}
@@ -1,18 +1,34 @@
 use std::io::IsTerminal;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use clap::Parser;
-use indicatif::MultiProgress;
+use futures::future::{join_all, BoxFuture};
+use futures::StreamExt;
+use indicatif::{HumanBytes, MultiProgress, ProgressDrawTarget};
+use reqwest::Url;
+use tokio::fs;
 use tokio::io::{self, AsyncBufReadExt, BufReader};
+use tokio::join;
+use tokio::task::spawn;
 
-use crate::api::ApiClient;
+use crate::api::{self, ApiClient};
 use crate::cache::{CacheName, CacheRef, ServerName};
+use crate::cache_meta::CacheMeta;
 use crate::cli::Opts;
 use crate::config::Config;
-use crate::push::{PushConfig, PushSessionConfig, Pusher};
+use crate::oauth;
+use crate::push::{
+    report_failures, report_json, MultiPushPlan, MultiPusher, PushConfig, PushPlan, PushResults,
+    PushSessionConfig, PushTarget, Pusher,
+};
 use attic::nix_store::NixStore;
+use attic::signing::NixKeypair;
+
+/// The number of store paths read from the standard input to queue at once.
+const STDIN_BATCH_SIZE: usize = 1000;
 
 /// Push closures to a binary cache.
 #[derive(Debug, Parser)]
@@ -23,10 +39,22 @@ pub struct Push {
     /// when using the default server.
     cache: CacheRef,
 
+    /// Also push to another cache.
+    ///
+    /// This can be specified multiple times. The closure is only
+    /// computed once, and each NAR is read from the store once and
+    /// uploaded to all caches missing the path concurrently.
+    #[clap(long, value_name = "CACHE", conflicts_with = "stdin")]
+    also_to: Vec<CacheRef>,
+
     /// The store paths to push.
     paths: Vec<PathBuf>,
 
     /// Read paths from the standard input.
+    ///
+    /// Each line should contain a full store path, like the output
+    /// of `nix-store -qR`. This avoids the limit on the length of
+    /// command lines when pushing many paths.
     #[clap(long)]
     stdin: bool,
 
@@ -42,9 +70,58 @@ pub struct Push {
     #[clap(short = 'j', long, default_value = "5")]
     jobs: usize,
 
+    /// Sign the paths with a local secret key before pushing.
+    ///
+    /// The file should contain a Nix signing key (`name:base64`).
+    /// The signature is stored by the server verbatim, so the key
+    /// never needs to leave this machine.
+    #[clap(long, value_name = "PATH")]
+    sign_key: Option<PathBuf>,
+
+    /// Also add the signatures made with `--sign-key` to the local store.
+    ///
+    /// This requires the user to be trusted by the Nix daemon.
+    #[clap(long, requires = "sign_key")]
+    sign_local_store: bool,
+
+    /// Fetch the cache configuration from the server even if a
+    /// cached copy is available.
+    #[clap(long)]
+    refresh_cache_config: bool,
+
+    /// Compress NARs with zstd before uploading them.
+    ///
+    /// This saves bandwidth on slow links at the cost of some CPU
+    /// time. Paths are uploaded uncompressed if the server doesn't
+    /// support it.
+    #[clap(long)]
+    compress_upload: bool,
+
     /// Always send the upload info as part of the payload.
     #[clap(long, hide = true)]
     force_preamble: bool,
+
+    /// Print a JSON object for each pushed path instead of progress bars.
+    ///
+    /// Each line on the standard output is an object with `path`,
+    /// `result` ("uploaded", "deduplicated", or "failed"), `file_size`,
+    /// `frac_deduplicated`, and `error`.
+    #[clap(long)]
+    json: bool,
+
+    /// Print the paths that would be pushed without pushing them.
+    #[clap(long, conflicts_with_all = ["stdin", "json"])]
+    dry_run: bool,
+}
+
+/// A cache resolved from the command line.
+struct ResolvedCache {
+    server_name: ServerName,
+
+    /// The endpoint the cache configuration is stored under.
+    endpoint: Url,
+
+    target: PushTarget,
 }
 
 struct PushContext {
@@ -52,19 +129,31 @@ struct PushContext {
     cache_name: CacheName,
     server_name: ServerName,
     pusher: Pusher,
+    mp: MultiProgress,
+    no_closure: bool,
+    ignore_upstream_cache_filter: bool,
+    json: bool,
+    dry_run: bool,
+}
+
+struct MultiPushContext {
+    store: Arc<NixStore>,
+
+    /// The server and cache names of each target.
+    names: Vec<(ServerName, CacheName)>,
+
+    pusher: MultiPusher,
+    mp: MultiProgress,
     no_closure: bool,
     ignore_upstream_cache_filter: bool,
+    json: bool,
+    dry_run: bool,
 }
 
 impl PushContext {
     async fn push_static(self, paths: Vec<PathBuf>) -> Result<()> {
         if paths.is_empty() {
-            eprintln!("🤷 Nothing specified.");
-            if !std::io::stdin().is_terminal() {
-                eprintln!(
-                    "Hint: Pass --stdin to read the list of store paths from standard input."
-                );
-            }
+            print_nothing_specified();
             return Ok(());
         }
 
@@ -78,6 +167,11 @@ impl PushContext {
             .plan(roots, self.no_closure, self.ignore_upstream_cache_filter)
             .await?;
 
+        if self.dry_run {
+            self.print_plan(&plan);
+            return Ok(());
+        }
+
         if plan.store_path_map.is_empty() {
             if plan.num_all_paths == 0 {
                 eprintln!("🤷 Nothing selected.");
@@ -100,98 +194,470 @@ impl PushContext {
             );
         }
 
+        let mut pusher = self.pusher;
+        let reporter = report(pusher.results(), self.json, &self.store, &self.mp, None);
+
         for (_, path_info) in plan.store_path_map {
-            self.pusher.queue(path_info).await?;
+            pusher.queue(path_info).await?;
         }
 
-        let results = self.pusher.wait().await;
-        results.into_values().collect::<Result<Vec<()>>>()?;
+        let (_, result) = join!(pusher.wait(), reporter);
+        result
+    }
 
-        Ok(())
+    /// Prints the paths in a push plan.
+    fn print_plan(&self, plan: &PushPlan) {
+        let mut paths: Vec<_> = plan.store_path_map.values().collect();
+        paths.sort_by_key(|p| p.path.name());
+
+        for path_info in &paths {
+            println!(
+                "{} ({})",
+                self.store.get_full_path(&path_info.path).display(),
+                HumanBytes(path_info.nar_size),
+            );
+        }
+
+        let nar_size: u64 = paths.iter().map(|p| p.nar_size).sum();
+        eprintln!(
+            "📋 Would push {num_missing_paths} paths ({nar_size}) to \"{cache}\" on \"{server}\" ({num_already_cached} already cached, {num_upstream} in upstream, {num_all_paths} in total)",
+            num_missing_paths = paths.len(),
+            nar_size = HumanBytes(nar_size),
+            cache = self.cache_name.as_str(),
+            server = self.server_name.as_str(),
+            num_already_cached = plan.num_already_cached,
+            num_upstream = plan.num_upstream,
+            num_all_paths = plan.num_all_paths,
+        );
     }
 
     async fn push_stdin(self) -> Result<()> {
-        let session = self.pusher.into_push_session(PushSessionConfig {
+        let mut session = self.pusher.into_push_session(PushSessionConfig {
             no_closure: self.no_closure,
             ignore_upstream_cache_filter: self.ignore_upstream_cache_filter,
         });
 
+        let num_pushed = Arc::new(AtomicUsize::new(0));
+        let results = session.results().inspect({
+            let num_pushed = num_pushed.clone();
+            move |(_, result)| {
+                if result.is_ok() {
+                    num_pushed.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        });
+        let reporter = spawn(report(
+            Box::pin(results),
+            self.json,
+            &self.store,
+            &self.mp,
+            None,
+        ));
+
         let stdin = BufReader::new(io::stdin());
         let mut lines = stdin.lines();
+        let mut line_number = 0;
+        let mut num_invalid = 0;
+        let mut batch = Vec::new();
+
         while let Some(line) = lines.next_line().await? {
+            line_number += 1;
+
+            let line = line.trim();
             if line.is_empty() {
                 continue;
             }
 
-            let path = self.store.follow_store_path(line)?;
-            session.queue_many(vec![path])?;
+            match self.store.parse_store_path(line) {
+                Ok(path) => batch.push(path),
+                Err(e) => {
+                    self.mp.suspend(|| {
+                        eprintln!("❌ Line {}: {}: {}", line_number, line, e);
+                    });
+                    num_invalid += 1;
+                }
+            }
+
+            if batch.len() >= STDIN_BATCH_SIZE {
+                session.queue_many(std::mem::take(&mut batch))?;
+            }
+        }
+
+        if !batch.is_empty() {
+            session.queue_many(batch)?;
         }
 
-        let results = session.wait().await?;
-        results.into_values().collect::<Result<Vec<()>>>()?;
+        session.wait().await?;
+        let result = reporter.await?;
+
+        let num_pushed = num_pushed.load(Ordering::Relaxed);
+        if num_pushed != 0 {
+            eprintln!(
+                "✅ Pushed {num_pushed} paths to \"{cache}\" on \"{server}\"",
+                cache = self.cache_name.as_str(),
+                server = self.server_name.as_str(),
+            );
+        }
+
+        result?;
+
+        if num_invalid != 0 {
+            return Err(anyhow!(
+                "{} line{} did not contain a valid store path",
+                num_invalid,
+                if num_invalid == 1 { "" } else { "s" }
+            ));
+        }
 
         Ok(())
     }
 }
 
-pub async fn run(opts: Opts) -> Result<()> {
-    let sub = opts.command.as_push().unwrap();
-    if sub.jobs == 0 {
-        return Err(anyhow!("The number of jobs cannot be 0"));
+impl MultiPushContext {
+    async fn push_static(self, paths: Vec<PathBuf>) -> Result<()> {
+        if paths.is_empty() {
+            print_nothing_specified();
+            return Ok(());
+        }
+
+        let roots = paths
+            .into_iter()
+            .map(|p| self.store.follow_store_path(p))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        let mut plan = self
+            .pusher
+            .plan(roots, self.no_closure, self.ignore_upstream_cache_filter)
+            .await?;
+
+        if self.dry_run {
+            self.print_plan(&plan);
+            return Ok(());
+        }
+
+        if plan.num_all_paths == 0 {
+            eprintln!("🤷 Nothing selected.");
+            return Ok(());
+        }
+
+        for ((server_name, cache_name), target) in self.names.iter().zip(&plan.targets) {
+            if target.missing_paths.is_empty() {
+                eprintln!("✅ \"{cache}\" on \"{server}\" is up to date ({num_already_cached} already cached, {num_upstream} in upstream)",
+                    cache = cache_name.as_str(),
+                    server = server_name.as_str(),
+                    num_already_cached = target.num_already_cached,
+                    num_upstream = target.num_upstream,
+                );
+            } else {
+                eprintln!("⚙️ Pushing {num_missing_paths} paths to \"{cache}\" on \"{server}\" ({num_already_cached} already cached, {num_upstream} in upstream)...",
+                    cache = cache_name.as_str(),
+                    server = server_name.as_str(),
+                    num_missing_paths = target.missing_paths.len(),
+                    num_already_cached = target.num_already_cached,
+                    num_upstream = target.num_upstream,
+                );
+            }
+        }
+
+        if plan.store_path_map.is_empty() {
+            return Ok(());
+        }
+
+        let mut pusher = self.pusher;
+        let num_pushed: Vec<Arc<AtomicUsize>> = self
+            .names
+            .iter()
+            .map(|_| Arc::new(AtomicUsize::new(0)))
+            .collect();
+
+        let reporters = pusher
+            .results()
+            .into_iter()
+            .zip(&self.names)
+            .zip(&num_pushed)
+            .map(|((results, (_, cache_name)), num_pushed)| {
+                let results = results.inspect({
+                    let num_pushed = num_pushed.clone();
+                    move |(_, result)| {
+                        if result.is_ok() {
+                            num_pushed.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                });
+
+                report(
+                    Box::pin(results),
+                    self.json,
+                    &self.store,
+                    &self.mp,
+                    Some(cache_name.clone()),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        for (store_path_hash, path_info) in std::mem::take(&mut plan.store_path_map) {
+            pusher
+                .queue(path_info, plan.targets_for(&store_path_hash))
+                .await?;
+        }
+
+        let (_, results) = join!(pusher.wait(), join_all(reporters));
+
+        // Results are summarized for each cache separately
+        for (((server_name, cache_name), target), num_pushed) in
+            self.names.iter().zip(&plan.targets).zip(&num_pushed)
+        {
+            let num_missing_paths = target.missing_paths.len();
+            if num_missing_paths == 0 {
+                continue;
+            }
+
+            let num_pushed = num_pushed.load(Ordering::Relaxed);
+            if num_pushed == num_missing_paths {
+                eprintln!(
+                    "✅ Pushed {num_pushed} paths to \"{cache}\" on \"{server}\"",
+                    cache = cache_name.as_str(),
+                    server = server_name.as_str(),
+                );
+            } else {
+                eprintln!(
+                    "❌ Pushed {num_pushed} of {num_missing_paths} paths to \"{cache}\" on \"{server}\"",
+                    cache = cache_name.as_str(),
+                    server = server_name.as_str(),
+                );
+            }
+        }
+
+        results.into_iter().collect()
     }
 
-    let config = Config::load()?;
+    /// Prints the paths in a push plan.
+    fn print_plan(&self, plan: &MultiPushPlan) {
+        let mut paths: Vec<_> = plan.store_path_map.values().collect();
+        paths.sort_by_key(|p| p.path.name());
 
-    let store = Arc::new(NixStore::connect()?);
+        for path_info in &paths {
+            println!(
+                "{} ({})",
+                self.store.get_full_path(&path_info.path).display(),
+                HumanBytes(path_info.nar_size),
+            );
+        }
+
+        for ((server_name, cache_name), target) in self.names.iter().zip(&plan.targets) {
+            let nar_size: u64 = paths
+                .iter()
+                .filter(|p| target.missing_paths.contains(&p.path.to_hash()))
+                .map(|p| p.nar_size)
+                .sum();
+
+            eprintln!(
+                "📋 Would push {num_missing_paths} paths ({nar_size}) to \"{cache}\" on \"{server}\" ({num_already_cached} already cached, {num_upstream} in upstream, {num_all_paths} in total)",
+                num_missing_paths = target.missing_paths.len(),
+                nar_size = HumanBytes(nar_size),
+                cache = cache_name.as_str(),
+                server = server_name.as_str(),
+                num_already_cached = target.num_already_cached,
+                num_upstream = target.num_upstream,
+                num_all_paths = plan.num_all_paths,
+            );
+        }
+    }
+}
+
+fn print_nothing_specified() {
+    eprintln!("🤷 Nothing specified.");
+    if !std::io::stdin().is_terminal() {
+        eprintln!("Hint: Pass --stdin to read the list of store paths from standard input.");
+    }
+}
 
-    let (server_name, server, cache_name) = config.resolve_cache(&sub.cache)?;
+/// Reports results as paths finish pushing.
+fn report(
+    results: PushResults,
+    json: bool,
+    store: &Arc<NixStore>,
+    mp: &MultiProgress,
+    cache: Option<CacheName>,
+) -> BoxFuture<'static, Result<()>> {
+    if json {
+        Box::pin(report_json(results, store.clone(), cache))
+    } else {
+        Box::pin(report_failures(results, mp.clone(), cache))
+    }
+}
+
+/// Resolves a cache and fetches its configuration.
+async fn resolve_cache(
+    config: &Config,
+    cache_meta: &CacheMeta,
+    cache_ref: &CacheRef,
+    sub: &Push,
+) -> Result<ResolvedCache> {
+    let (server_name, server, cache_name) = config.resolve_cache(cache_ref)?;
 
     let mut api = ApiClient::from_server_config(server.clone())?;
+    let endpoint = api.endpoint().clone();
 
     // Confirm remote cache validity, query cache config
-    let cache_config = api.get_cache_config(cache_name).await?;
+    let cache_config = cache_meta
+        .get_cache_config(&api, cache_name, sub.refresh_cache_config)
+        .await?;
 
     if let Some(api_endpoint) = &cache_config.api_endpoint {
         // Use delegated API endpoint
         api.set_endpoint(api_endpoint)?;
     }
 
+    let supports_compressed_upload =
+        sub.compress_upload && api::supports_compressed_upload(&cache_config);
+
+    Ok(ResolvedCache {
+        server_name: server_name.clone(),
+        endpoint,
+        target: PushTarget {
+            api,
+            cache: cache_name.clone(),
+            cache_config,
+            supports_compressed_upload,
+        },
+    })
+}
+
+pub async fn run(opts: Opts) -> Result<()> {
+    let sub = opts.command.as_push().unwrap();
+    if sub.jobs == 0 {
+        return Err(anyhow!("The number of jobs cannot be 0"));
+    }
+
+    let mut config = Config::load()?;
+    oauth::refresh_tokens(&mut config, std::iter::once(&sub.cache).chain(&sub.also_to)).await?;
+
+    let store = Arc::new(NixStore::connect()?);
+
+    let cache_meta = CacheMeta::load(config.cache_config_ttl());
+    let mut caches = Vec::new();
+    for cache_ref in std::iter::once(&sub.cache).chain(&sub.also_to) {
+        let resolved = resolve_cache(&config, &cache_meta, cache_ref, sub).await?;
+
+        if caches.iter().any(|c: &ResolvedCache| {
+            c.endpoint == resolved.endpoint && c.target.cache == resolved.target.cache
+        }) {
+            return Err(anyhow!(
+                "Cache \"{}\" on \"{}\" is specified more than once",
+                resolved.target.cache.as_str(),
+                resolved.server_name.as_str(),
+            ));
+        }
+
+        if sub.compress_upload && !resolved.target.supports_compressed_upload {
+            eprintln!(
+                "⚠️ The server of \"{}\" does not accept compressed uploads. Uploading uncompressed.",
+                resolved.target.cache.as_str(),
+            );
+        }
+
+        caches.push(resolved);
+    }
+
+    let signing_keypair = if let Some(path) = &sub.sign_key {
+        let key = fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read signing key {}", path.display()))?;
+        Some(Arc::new(NixKeypair::from_str(key.trim())?))
+    } else {
+        None
+    };
+
     let push_config = PushConfig {
         num_workers: sub.jobs,
         force_preamble: sub.force_preamble,
+        signing_keypair,
+        sign_local_store: sub.sign_local_store,
+        quiet: sub.json,
+        compress_upload: sub.compress_upload,
     };
 
-    let mp = MultiProgress::new();
+    let mp = if sub.json {
+        MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+    } else {
+        MultiProgress::new()
+    };
 
-    let pusher = Pusher::new(
-        store.clone(),
-        api,
-        cache_name.to_owned(),
-        cache_config,
-        mp,
-        push_config,
-    );
+    let endpoints: Vec<(Url, CacheName)> = caches
+        .iter()
+        .map(|c| (c.endpoint.clone(), c.target.cache.clone()))
+        .collect();
+
+    let result = if caches.len() == 1 {
+        let ResolvedCache {
+            server_name,
+            target,
+            ..
+        } = caches.pop().unwrap();
+
+        let pusher = Pusher::new(
+            store.clone(),
+            target.api,
+            target.cache.clone(),
+            target.cache_config,
+            mp.clone(),
+            PushConfig {
+                compress_upload: target.supports_compressed_upload,
+                ..push_config
+            },
+        );
+
+        let push_ctx = PushContext {
+            store,
+            cache_name: target.cache,
+            server_name,
+            pusher,
+            mp,
+            no_closure: sub.no_closure,
+            ignore_upstream_cache_filter: sub.ignore_upstream_cache_filter,
+            json: sub.json,
+            dry_run: sub.dry_run,
+        };
+
+        if sub.stdin {
+            if !sub.paths.is_empty() {
+                return Err(anyhow!(
+                    "No paths can be specified on the command line with --stdin"
+                ));
+            }
 
-    let push_ctx = PushContext {
-        store,
-        cache_name: cache_name.clone(),
-        server_name: server_name.clone(),
-        pusher,
-        no_closure: sub.no_closure,
-        ignore_upstream_cache_filter: sub.ignore_upstream_cache_filter,
+            push_ctx.push_stdin().await
+        } else {
+            push_ctx.push_static(sub.paths.clone()).await
+        }
+    } else {
+        let names = caches
+            .iter()
+            .map(|c| (c.server_name.clone(), c.target.cache.clone()))
+            .collect();
+        let targets = caches.into_iter().map(|c| c.target).collect();
+
+        let push_ctx = MultiPushContext {
+            store: store.clone(),
+            names,
+            pusher: MultiPusher::new(store, targets, mp.clone(), push_config),
+            mp,
+            no_closure: sub.no_closure,
+            ignore_upstream_cache_filter: sub.ignore_upstream_cache_filter,
+            json: sub.json,
+            dry_run: sub.dry_run,
+        };
+
+        push_ctx.push_static(sub.paths.clone()).await
     };
 
-    if sub.stdin {
-        if !sub.paths.is_empty() {
-            return Err(anyhow!(
-                "No paths can be specified on the command line with --stdin"
-            ));
+    if let Err(e) = &result {
+        // The caches may have been deleted or our access revoked
+        for (endpoint, cache_name) in &endpoints {
+            cache_meta.invalidate_on_error(endpoint, cache_name, e);
         }
-
-        push_ctx.push_stdin().await?;
-    } else {
-        push_ctx.push_static(sub.paths.clone()).await?;
     }
 
-    Ok(())
+    result
 }
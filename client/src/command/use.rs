@@ -1,15 +1,28 @@
+#[cfg(feature = "nix_store")]
+use std::io;
+
 use anyhow::{anyhow, Result};
-use clap::Parser;
-use reqwest::Url;
+#[cfg(feature = "nix_store")]
+use clap::{Parser, ValueEnum};
+use reqwest::{StatusCode, Url};
+use serde::Serialize;
+#[cfg(feature = "nix_store")]
+use tokio::fs;
 
-use crate::api::ApiClient;
+use crate::api::{ApiClient, ApiError};
 use crate::cache::CacheRef;
-use crate::cli::Opts;
 use crate::config::Config;
 use crate::nix_config::NixConfig;
-use crate::nix_netrc::NixNetrc;
+#[cfg(feature = "nix_store")]
+use crate::{
+    cli::Opts,
+    nix_config::SYSTEM_NIX_CONF,
+    nix_netrc::{NixNetrc, SYSTEM_NETRC},
+};
+use attic::api::error::ErrorCode;
 
 /// Configure Nix to use a binary cache.
+#[cfg(feature = "nix_store")]
 #[derive(Debug, Parser)]
 pub struct Use {
     /// The cache to configure.
@@ -17,55 +30,303 @@ pub struct Use {
     /// This can be either `servername:cachename` or `cachename`
     /// when using the default server.
     cache: CacheRef,
+
+    /// Where to put the configuration.
+    ///
+    /// "user" edits the user's nix.conf and netrc, and "system" edits
+    /// /etc/nix/nix.conf and /etc/nix/netrc as root. "stdout" prints
+    /// the nix.conf settings for a system configuration, and "json"
+    /// prints them in a machine-readable format.
+    #[clap(long, value_enum, default_value = "user")]
+    output: UseOutput,
+
+    /// Print the changes to the netrc without saving anything.
+    #[clap(long)]
+    print_netrc_diff: bool,
 }
 
+/// Where to put the configuration.
+#[cfg(feature = "nix_store")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum UseOutput {
+    User,
+    System,
+    Stdout,
+    Json,
+}
+
+/// Settings for Nix to use a cache.
+#[derive(Debug, Serialize)]
+pub(crate) struct UseSettings {
+    /// The name of the cache.
+    cache: String,
+
+    /// The name of the server.
+    server: String,
+
+    /// The substituter endpoint.
+    substituter: String,
+
+    /// The public key of the cache.
+    trusted_public_key: String,
+
+    /// The netrc entry holding the token, if any.
+    netrc: Option<NetrcEntry>,
+}
+
+#[derive(Debug, Serialize)]
+struct NetrcEntry {
+    machine: String,
+    password: String,
+}
+
+#[cfg(feature = "nix_store")]
 pub async fn run(opts: Opts) -> Result<()> {
     let sub = opts.command.as_use().unwrap();
     let config = Config::load()?;
+    let settings = resolve_settings(&config, &sub.cache).await?;
+
+    match sub.output {
+        UseOutput::User => configure(sub, &settings, false).await,
+        UseOutput::System => configure(sub, &settings, true).await,
+        UseOutput::Stdout => {
+            print_settings(&settings, SYSTEM_NETRC);
+            Ok(())
+        }
+        UseOutput::Json => {
+            println!("{}", serde_json::to_string_pretty(&settings)?);
+            Ok(())
+        }
+    }
+}
 
-    let (server_name, server, cache) = config.resolve_cache(&sub.cache)?;
+/// Fetches the settings for Nix to use a cache.
+pub(crate) async fn resolve_settings(config: &Config, cache_ref: &CacheRef) -> Result<UseSettings> {
+    let (server_name, server, cache) = config.resolve_cache(cache_ref)?;
 
     let api = ApiClient::from_server_config(server.clone())?;
-    let cache_config = api.get_cache_config(cache).await?;
+    let (substituter, public_key) = match api.get_cache_public_key(cache).await {
+        Ok(info) => (info.substituter_endpoint, info.public_key),
+        Err(e) if is_unsupported_endpoint(&e) => {
+            // Older servers only return the public key as part of the full configuration
+            let cache_config = api.get_cache_config(cache).await?;
+
+            let substituter = cache_config.substituter_endpoint.ok_or_else(|| {
+                anyhow!("The server did not tell us where the binary cache endpoint is.")
+            })?;
+            let public_key = cache_config.public_key
+                .ok_or_else(|| anyhow!("The server did not tell us which public key it uses. Is signing managed by the client?"))?;
+
+            (substituter, public_key)
+        }
+        Err(e) => return Err(e),
+    };
+
+    let netrc = if let Some(token) = server.token()? {
+        let machine = Url::parse(&substituter)?
+            .host()
+            .map(|h| h.to_string())
+            .unwrap();
 
-    let substituter = cache_config
-        .substituter_endpoint
-        .ok_or_else(|| anyhow!("The server did not tell us where the binary cache endpoint is."))?;
-    let public_key = cache_config.public_key
-        .ok_or_else(|| anyhow!("The server did not tell us which public key it uses. Is signing managed by the client?"))?;
+        Some(NetrcEntry {
+            machine,
+            password: token,
+        })
+    } else {
+        None
+    };
 
+    Ok(UseSettings {
+        cache: cache.as_str().to_string(),
+        server: server_name.as_str().to_string(),
+        substituter,
+        trusted_public_key: public_key,
+        netrc,
+    })
+}
+
+/// Edits the user's or system's Nix configuration.
+#[cfg(feature = "nix_store")]
+async fn configure(sub: &Use, settings: &UseSettings, system: bool) -> Result<()> {
     eprintln!(
         "Configuring Nix to use \"{cache}\" on \"{server_name}\":",
-        cache = cache.as_str(),
-        server_name = server_name.as_str(),
+        cache = settings.cache,
+        server_name = settings.server,
     );
 
     // Modify nix.conf
-    eprintln!("+ Substituter: {}", substituter);
-    eprintln!("+ Trusted Public Key: {}", public_key);
+    eprintln!("+ Substituter: {}", settings.substituter);
+    eprintln!("+ Trusted Public Key: {}", settings.trusted_public_key);
 
-    let mut nix_config = NixConfig::load().await?;
-    nix_config.add_substituter(&substituter);
-    nix_config.add_trusted_public_key(&public_key);
+    let mut nix_config = if system {
+        check_system_file(SYSTEM_NIX_CONF).await?;
+        NixConfig::load_from(SYSTEM_NIX_CONF.into())
+            .await
+            .map_err(|e| explain_system_error(e, SYSTEM_NIX_CONF))?
+    } else {
+        NixConfig::load().await?
+    };
+    nix_config.add_substituter(&settings.substituter);
+    nix_config.add_trusted_public_key(&settings.trusted_public_key);
 
     // Modify netrc
-    if let Some(token) = server.token()? {
+    if let Some(netrc) = &settings.netrc {
         eprintln!("+ Access Token");
 
-        let mut nix_netrc = NixNetrc::load().await?;
-        let host = Url::parse(&substituter)?
-            .host()
-            .map(|h| h.to_string())
-            .unwrap();
-        nix_netrc.add_token(host, token.to_string());
-        nix_netrc.save().await?;
+        let mut nix_netrc = if system {
+            NixNetrc::load_from(SYSTEM_NETRC.into())
+                .await
+                .map_err(|e| explain_system_error(e, SYSTEM_NETRC))?
+        } else {
+            NixNetrc::load().await?
+        };
+        nix_netrc.add_token(netrc.machine.clone(), netrc.password.clone())?;
+
+        let netrc_path = nix_netrc.path().unwrap().to_str().unwrap().to_string();
+
+        if sub.print_netrc_diff {
+            match nix_netrc.diff() {
+                Some(diff) => {
+                    eprintln!("Changes to {}:", netrc_path);
+                    print!("{}", diff);
+                }
+                None => eprintln!("No changes to {}", netrc_path),
+            }
 
-        let netrc_path = nix_netrc.path().unwrap().to_str().unwrap();
+            eprintln!("Nothing was saved. Run again without --print-netrc-diff to apply.");
+            return Ok(());
+        }
 
-        nix_config.set_netrc_file(netrc_path);
+        if system {
+            nix_netrc
+                .save()
+                .await
+                .map_err(|e| explain_system_error(e, SYSTEM_NETRC))?;
+        } else {
+            nix_netrc.save().await?;
+        }
+
+        nix_config.set_netrc_file(&netrc_path);
     }
 
-    nix_config.save().await?;
+    if system {
+        nix_config
+            .save()
+            .await
+            .map_err(|e| explain_system_error(e, SYSTEM_NIX_CONF))?;
+    } else {
+        nix_config.save().await?;
+    }
 
     Ok(())
 }
+
+/// Prints the nix.conf settings.
+///
+/// The token, if any, is expected to be in the netrc at `netrc_file`.
+pub(crate) fn print_settings(settings: &UseSettings, netrc_file: &str) {
+    let mut nix_config = NixConfig::empty();
+    nix_config.add_substituter(&settings.substituter);
+    nix_config.add_trusted_public_key(&settings.trusted_public_key);
+
+    if settings.netrc.is_some() {
+        nix_config.set_netrc_file(netrc_file);
+    }
+
+    println!("{}", nix_config.to_string());
+
+    if let Some(netrc) = &settings.netrc {
+        eprintln!(
+            "The cache requires a token. Add the following to {}:",
+            netrc_file
+        );
+        eprintln!("machine {} password {}", netrc.machine, netrc.password);
+    }
+}
+
+/// Checks that a system configuration file can be edited in place.
+///
+/// On NixOS, the files in `/etc/nix` are links into the Nix store
+/// and must be changed through the system configuration instead.
+#[cfg(feature = "nix_store")]
+async fn check_system_file(path: &str) -> Result<()> {
+    if let Ok(target) = fs::canonicalize(path).await {
+        if target.starts_with("/nix/store") {
+            return Err(anyhow!(
+                "{} is managed by NixOS. Use `--output stdout` and add the settings to your system configuration instead.",
+                path
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Adds a hint to permission errors on system configuration files.
+#[cfg(feature = "nix_store")]
+fn explain_system_error(error: anyhow::Error, path: &str) -> anyhow::Error {
+    let denied = error
+        .chain()
+        .filter_map(|e| e.downcast_ref::<io::Error>())
+        .any(|e| e.kind() == io::ErrorKind::PermissionDenied);
+
+    if denied {
+        anyhow!(
+            "Cannot access {}: Permission denied. Editing the system configuration requires root.",
+            path
+        )
+    } else {
+        error
+    }
+}
+
+/// Returns whether an error indicates that the server doesn't have an endpoint.
+///
+/// Servers report missing caches as `NoSuchCache`, so a plain `NotFound`
+/// comes from the router.
+fn is_unsupported_endpoint(error: &anyhow::Error) -> bool {
+    match error.downcast_ref::<ApiError>() {
+        Some(ApiError::Structured(_)) => ApiError::is(error, ErrorCode::NotFound),
+        Some(ApiError::Unstructured(status, _)) => *status == StatusCode::NOT_FOUND,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::api::StructuredApiError;
+
+    #[test]
+    fn test_is_unsupported_endpoint() {
+        fn structured(code: u16, kind: ErrorCode) -> anyhow::Error {
+            ApiError::Structured(StructuredApiError {
+                code,
+                error: kind.name().to_string(),
+                error_code: Some(kind.code()),
+                message: "Some message".to_string(),
+            })
+            .into()
+        }
+
+        assert!(is_unsupported_endpoint(&structured(
+            404,
+            ErrorCode::NotFound
+        )));
+        assert!(!is_unsupported_endpoint(&structured(
+            404,
+            ErrorCode::NoSuchCache
+        )));
+        assert!(!is_unsupported_endpoint(&structured(
+            401,
+            ErrorCode::Unauthorized
+        )));
+
+        let unstructured: anyhow::Error =
+            ApiError::Unstructured(StatusCode::NOT_FOUND, "Not Found".to_string()).into();
+        assert!(is_unsupported_endpoint(&unstructured));
+
+        assert!(!is_unsupported_endpoint(&anyhow!("Other")));
+    }
+}
@@ -0,0 +1,244 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use futures::stream::{self, StreamExt};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+
+use crate::api::ApiClient;
+use crate::cache::{CacheName, CacheRef};
+use crate::cli::Opts;
+use crate::config::Config;
+use crate::narinfo::NarInfo;
+use attic::hash::Hash;
+use attic::nix_store::{NixStore, StorePath, ValidPathInfo};
+use attic::signing::NixPublicKey;
+
+/// Verify local store paths against a binary cache.
+///
+/// For each path, the narinfo served by the cache is compared with
+/// the local store, and its signature is checked against the public
+/// key of the cache.
+#[derive(Debug, Parser)]
+pub struct Verify {
+    /// The cache to verify against.
+    ///
+    /// This can be either `servername:cachename` or `cachename`
+    /// when using the default server.
+    cache: CacheRef,
+
+    /// The store paths to verify.
+    paths: Vec<PathBuf>,
+
+    /// Verify the closures of the specified paths.
+    #[clap(long)]
+    closure: bool,
+
+    /// Also download the NARs and check their hashes.
+    #[clap(long)]
+    download: bool,
+
+    /// The maximum number of paths to verify in parallel.
+    #[clap(short = 'j', long, default_value = "5")]
+    jobs: usize,
+}
+
+/// The result of verifying a path.
+#[derive(Debug)]
+enum Verification {
+    /// The cache matches the local store.
+    Match,
+
+    /// The cache differs from the local store.
+    Mismatch(Vec<String>),
+
+    /// The path isn't in the cache.
+    Missing,
+}
+
+struct VerifyContext {
+    api: ApiClient,
+    cache_name: CacheName,
+    public_keys: Vec<NixPublicKey>,
+    download: bool,
+}
+
+impl VerifyContext {
+    async fn verify_path(&self, path_info: &ValidPathInfo) -> Result<Verification> {
+        let Some(narinfo) = self
+            .api
+            .get_narinfo(&self.cache_name, &path_info.path.to_hash())
+            .await?
+        else {
+            return Ok(Verification::Missing);
+        };
+
+        let mut problems = Vec::new();
+
+        if narinfo.nar_hash != path_info.nar_hash {
+            problems.push(format!(
+                "NarHash is {} in the cache but {} locally",
+                narinfo.nar_hash.to_typed_base32(),
+                path_info.nar_hash.to_typed_base32()
+            ));
+        }
+
+        if narinfo.nar_size as u64 != path_info.nar_size {
+            problems.push(format!(
+                "NarSize is {} in the cache but {} locally",
+                narinfo.nar_size, path_info.nar_size
+            ));
+        }
+
+        if !self.public_keys.iter().any(|k| narinfo.is_signed_by(k)) {
+            problems.push("No valid signature from the cache".to_string());
+        }
+
+        if self.download {
+            let (nar_hash, nar_size) = self.download_nar(&narinfo).await?;
+
+            if nar_hash != path_info.nar_hash || nar_size as u64 != path_info.nar_size {
+                problems.push(format!(
+                    "Downloaded NAR has hash {} and size {}",
+                    nar_hash.to_typed_base32(),
+                    nar_size
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(Verification::Match)
+        } else {
+            Ok(Verification::Mismatch(problems))
+        }
+    }
+
+    /// Downloads a NAR, returning the hash and size of the uncompressed NAR.
+    async fn download_nar(&self, narinfo: &NarInfo) -> Result<(Hash, usize)> {
+        let mut reader = self
+            .api
+            .get_decompressed_nar(&self.cache_name, narinfo)
+            .await?;
+
+        let mut hasher = Sha256::new();
+        let mut nar_size = 0;
+        let mut buf = vec![0; 64 * 1024];
+
+        loop {
+            let read = reader.read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+
+            hasher.update(&buf[..read]);
+            nar_size += read;
+        }
+
+        Ok((Hash::Sha256(hasher.finalize().into()), nar_size))
+    }
+}
+
+pub async fn run(opts: Opts) -> Result<()> {
+    let sub = opts.command.as_verify().unwrap();
+    if sub.jobs == 0 {
+        return Err(anyhow!("The number of jobs cannot be 0"));
+    }
+
+    let config = Config::load()?;
+
+    let store = Arc::new(NixStore::connect()?);
+
+    let (_, server, cache_name) = config.resolve_cache(&sub.cache)?;
+    let api = ApiClient::from_server_config(server.clone())?;
+
+    let cache_config = api.get_cache_config(cache_name).await?;
+    let public_key = cache_config
+        .public_key
+        .ok_or_else(|| anyhow!("The server did not return the public key of the cache"))?;
+
+    // Narinfos may still be signed only by a previous key
+    let public_keys = std::iter::once(public_key)
+        .chain(cache_config.previous_public_keys.unwrap_or_default())
+        .map(|k| NixPublicKey::from_str(&k))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+
+    let roots = sub
+        .paths
+        .iter()
+        .map(|p| store.follow_store_path(p))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+
+    let paths: Vec<StorePath> = if sub.closure {
+        store
+            .compute_fs_closure_multi(roots, false, false, false)
+            .await?
+    } else {
+        roots
+    };
+
+    if paths.is_empty() {
+        eprintln!("🤷 Nothing specified.");
+        return Ok(());
+    }
+
+    let ctx = VerifyContext {
+        api,
+        cache_name: cache_name.to_owned(),
+        public_keys,
+        download: sub.download,
+    };
+
+    let mut results = stream::iter(paths)
+        .map(|path| {
+            let store = store.clone();
+            let ctx = &ctx;
+            async move {
+                let full_path = store.get_full_path(&path);
+                let path_info = store.query_path_info(path).await?;
+                let verification = ctx.verify_path(&path_info).await?;
+                Ok::<_, anyhow::Error>((full_path, verification))
+            }
+        })
+        .buffered(sub.jobs);
+
+    let mut num_match = 0;
+    let mut num_mismatch = 0;
+    let mut num_missing = 0;
+
+    while let Some(result) = results.next().await {
+        let (path, verification) = result?;
+
+        match verification {
+            Verification::Match => {
+                println!("✅ {}", path.display());
+                num_match += 1;
+            }
+            Verification::Mismatch(problems) => {
+                println!("❌ {}: {}", path.display(), problems.join("; "));
+                num_mismatch += 1;
+            }
+            Verification::Missing => {
+                println!("❓ {}: Not in the cache", path.display());
+                num_missing += 1;
+            }
+        }
+    }
+
+    eprintln!(
+        "🔍 Verified {num_paths} paths against \"{cache}\": {num_match} match, {num_mismatch} mismatch, {num_missing} missing",
+        num_paths = num_match + num_mismatch + num_missing,
+        cache = cache_name.as_str(),
+    );
+
+    if num_mismatch != 0 {
+        return Err(anyhow!(
+            "{} path{} did not match the cache",
+            num_mismatch,
+            if num_mismatch == 1 { "" } else { "s" }
+        ));
+    }
+
+    Ok(())
+}
@@ -3,15 +3,20 @@ use std::sync::Arc;
 
 use anyhow::{anyhow, Result};
 use clap::Parser;
+use humantime::Duration;
 use indicatif::MultiProgress;
 use notify::{EventKind, RecursiveMode, Watcher};
 use tokio::sync::mpsc;
+use tokio::task::spawn;
 
 use crate::api::ApiClient;
 use crate::cache::CacheRef;
+use crate::cache_meta::CacheMeta;
 use crate::cli::Opts;
 use crate::config::Config;
-use crate::push::{PushConfig, PushSessionConfig, Pusher};
+use crate::oauth;
+use crate::push::{report_failures, PushConfig, PushSession, PushSessionConfig, Pusher};
+use crate::push_state::PushState;
 use attic::nix_store::{NixStore, StorePath};
 
 /// Watch the Nix Store for new paths and upload them to a binary cache.
@@ -35,9 +40,27 @@ pub struct WatchStore {
     #[clap(short = 'j', long, default_value = "5")]
     jobs: usize,
 
+    /// Fetch the cache configuration from the server even if a
+    /// cached copy is available.
+    #[clap(long)]
+    refresh_cache_config: bool,
+
     /// Always send the upload info as part of the payload.
     #[clap(long, hide = true)]
     force_preamble: bool,
+
+    /// A file to remember pushed paths in across restarts.
+    ///
+    /// Paths recorded here are not checked against the server again.
+    #[clap(long)]
+    state_file: Option<PathBuf>,
+
+    /// How long paths are remembered in the state file.
+    ///
+    /// Older paths are checked against the server again, in case they
+    /// have been garbage-collected.
+    #[clap(long, default_value = "7d", requires = "state_file")]
+    state_max_age: Duration,
 }
 
 pub async fn run(opts: Opts) -> Result<()> {
@@ -46,7 +69,8 @@ pub async fn run(opts: Opts) -> Result<()> {
         return Err(anyhow!("The number of jobs cannot be 0"));
     }
 
-    let config = Config::load()?;
+    let mut config = Config::load()?;
+    oauth::refresh_tokens(&mut config, [&sub.cache]).await?;
 
     let store = Arc::new(NixStore::connect()?);
     let store_dir = store.store_dir().to_owned();
@@ -55,7 +79,9 @@ pub async fn run(opts: Opts) -> Result<()> {
     let mut api = ApiClient::from_server_config(server.clone())?;
 
     // Confirm remote cache validity, query cache config
-    let cache_config = api.get_cache_config(cache).await?;
+    let cache_config = CacheMeta::load(config.cache_config_ttl())
+        .get_cache_config(&api, cache, sub.refresh_cache_config)
+        .await?;
 
     if let Some(api_endpoint) = &cache_config.api_endpoint {
         // Use delegated API endpoint
@@ -65,6 +91,10 @@ pub async fn run(opts: Opts) -> Result<()> {
     let push_config = PushConfig {
         num_workers: sub.jobs,
         force_preamble: sub.force_preamble,
+        signing_keypair: None,
+        sign_local_store: false,
+        quiet: false,
+        compress_upload: false,
     };
 
     let push_session_config = PushSessionConfig {
@@ -72,16 +102,28 @@ pub async fn run(opts: Opts) -> Result<()> {
         ignore_upstream_cache_filter: sub.ignore_upstream_cache_filter,
     };
 
+    let state = sub.state_file.as_ref().map(|path| {
+        PushState::load(
+            path.to_owned(),
+            api.endpoint().as_str(),
+            cache,
+            sub.state_max_age.into(),
+        )
+    });
+
     let mp = MultiProgress::new();
-    let session = Pusher::new(
+    let pusher = Pusher::new(
         store.clone(),
         api,
         cache.to_owned(),
         cache_config,
-        mp,
+        mp.clone(),
         push_config,
-    )
-    .into_push_session(push_session_config);
+    );
+    let mut session = PushSession::with_state(pusher, push_session_config, state);
+
+    // Failures are printed as they happen, and the session never ends
+    spawn(report_failures(session.results(), mp, None));
 
     let (tx, mut rx) = mpsc::unbounded_channel();
 
@@ -1,6 +1,7 @@
 //! Client configurations.
 //!
-//! Configuration files are stored under `$XDG_CONFIG_HOME/attic/config.toml`.
+//! Configuration files are stored under `$XDG_CONFIG_HOME/attic/config.toml`
+//! (`%APPDATA%\attic\config.toml` on Windows).
 //! We automatically write modified configurations back for a good end-user
 //! experience (e.g., `attic login`).
 
@@ -8,21 +9,20 @@ use std::collections::HashMap;
 use std::fs::{self, read_to_string, OpenOptions, Permissions};
 use std::io::Write;
 use std::ops::{Deref, DerefMut};
+#[cfg(target_family = "unix")]
 use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 use std::path::PathBuf;
+use std::time::Duration;
 
 use anyhow::{anyhow, Context, Result};
 use serde::{Deserialize, Serialize};
-use xdg::BaseDirectories;
 
 use crate::cache::{CacheName, CacheRef, ServerName};
-
-/// Application prefix in XDG base directories.
-///
-/// This will be concatenated into `$XDG_CONFIG_HOME/attic`.
-const XDG_PREFIX: &str = "attic";
+use crate::cache_meta;
+use crate::dirs;
 
 /// The permission the configuration file should have.
+#[cfg(target_family = "unix")]
 const FILE_MODE: u32 = 0o600;
 
 /// Configuration loader.
@@ -46,6 +46,13 @@ pub struct ConfigData {
     #[serde(default = "HashMap::new")]
     #[serde(skip_serializing_if = "HashMap::is_empty")]
     pub servers: HashMap<ServerName, ServerConfig>,
+
+    /// How long to cache cache configurations for, in seconds.
+    ///
+    /// Zero disables caching. Defaults to 5 minutes.
+    #[serde(rename = "cache-config-ttl")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_config_ttl: Option<u64>,
 }
 
 /// Configuration of a server.
@@ -54,12 +61,81 @@ pub struct ServerConfig {
     pub endpoint: String,
     #[serde(flatten)]
     pub token: Option<ServerTokenConfig>,
+
+    /// How long a request to the server may take, in seconds.
+    ///
+    /// This covers the whole request including the upload of the NAR,
+    /// so it should leave room for the largest paths you push.
+    /// Unlimited by default.
+    #[serde(rename = "request-timeout")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_timeout: Option<u64>,
+
+    /// How long connecting to the server may take, in seconds.
+    ///
+    /// Unlimited by default.
+    #[serde(rename = "connect-timeout")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_timeout: Option<u64>,
+
+    /// The maximum number of idle connections to keep open to the server.
+    ///
+    /// Unlimited by default. Connections are reused across requests,
+    /// so this rarely needs to be lower than the number of push jobs.
+    #[serde(rename = "pool-max-idle-per-host")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pool_max_idle_per_host: Option<usize>,
+
+    /// How to renew the token with an OAuth identity provider.
+    ///
+    /// This is set by `attic login --oauth`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub oauth: Option<OAuthConfig>,
+}
+
+/// OAuth login state of a server.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OAuthConfig {
+    /// The issuer URL of the identity provider.
+    pub issuer: String,
+
+    /// The client ID registered with the identity provider.
+    #[serde(rename = "client-id")]
+    pub client_id: String,
+
+    /// The token endpoint of the identity provider.
+    #[serde(rename = "token-endpoint")]
+    pub token_endpoint: String,
+
+    /// The endpoint exchanging access tokens for Attic tokens.
+    #[serde(rename = "exchange-url")]
+    pub exchange_url: String,
+
+    /// The refresh token issued by the identity provider.
+    #[serde(rename = "refresh-token")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+
+    /// When the Attic token expires, in seconds since the Unix epoch.
+    #[serde(rename = "expires-at")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
 }
 
 impl ServerConfig {
     pub fn token(&self) -> Result<Option<String>> {
         self.token.as_ref().map(|token| token.get()).transpose()
     }
+
+    /// Returns the request timeout.
+    pub fn request_timeout(&self) -> Option<Duration> {
+        self.request_timeout.map(Duration::from_secs)
+    }
+
+    /// Returns the connect timeout.
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        self.connect_timeout.map(Duration::from_secs)
+    }
 }
 
 /// Configured server token
@@ -117,17 +193,19 @@ impl Config {
 
             // This isn't atomic, so some other process might chmod it
             // to something else before we write. We don't handle this case.
+            #[cfg(target_family = "unix")]
             if path.exists() {
                 let permissions = Permissions::from_mode(FILE_MODE);
                 fs::set_permissions(path, permissions)?;
             }
 
-            let mut file = OpenOptions::new()
-                .create(true)
-                .write(true)
-                .truncate(true)
-                .mode(FILE_MODE)
-                .open(path)?;
+            let mut options = OpenOptions::new();
+            options.create(true).write(true).truncate(true);
+
+            #[cfg(target_family = "unix")]
+            options.mode(FILE_MODE);
+
+            let mut file = options.open(path)?;
 
             file.write_all(serialized.as_bytes())?;
 
@@ -160,6 +238,13 @@ impl ConfigData {
         Ok(ConfigData::default())
     }
 
+    /// Returns the time-to-live of cached cache configurations.
+    pub fn cache_config_ttl(&self) -> Duration {
+        self.cache_config_ttl
+            .map(Duration::from_secs)
+            .unwrap_or(cache_meta::DEFAULT_TTL)
+    }
+
     pub fn default_server(&self) -> Result<(&ServerName, &ServerConfig)> {
         if let Some(name) = &self.default_server {
             let config = self.servers.get(name).ok_or_else(|| {
@@ -219,8 +304,5 @@ impl<'a> Drop for ConfigWriteGuard<'a> {
 }
 
 fn get_config_path() -> Result<PathBuf> {
-    let xdg_dirs = BaseDirectories::with_prefix(XDG_PREFIX)?;
-    let config_path = xdg_dirs.place_config_file("config.toml")?;
-
-    Ok(config_path)
+    dirs::place_config_file("config.toml")
 }
@@ -0,0 +1,49 @@
+//! Per-user directories.
+//!
+//! On Unix, we follow the XDG Base Directory Specification and place
+//! files under `$XDG_CONFIG_HOME/attic` and `$XDG_CACHE_HOME/attic`.
+//! Elsewhere, files go under `%APPDATA%\attic` and `%LOCALAPPDATA%\attic`.
+
+use std::path::PathBuf;
+
+use anyhow::Result;
+
+/// Application prefix in the base directories.
+const PREFIX: &str = "attic";
+
+/// Returns the path to a configuration file, creating its parent directories.
+#[cfg(target_family = "unix")]
+pub fn place_config_file(name: &str) -> Result<PathBuf> {
+    let xdg_dirs = xdg::BaseDirectories::with_prefix(PREFIX)?;
+    Ok(xdg_dirs.place_config_file(name)?)
+}
+
+/// Returns the path to a cache file, creating its parent directories.
+#[cfg(target_family = "unix")]
+pub fn place_cache_file(name: &str) -> Result<PathBuf> {
+    let xdg_dirs = xdg::BaseDirectories::with_prefix(PREFIX)?;
+    Ok(xdg_dirs.place_cache_file(name)?)
+}
+
+/// Returns the path to a configuration file, creating its parent directories.
+#[cfg(not(target_family = "unix"))]
+pub fn place_config_file(name: &str) -> Result<PathBuf> {
+    place_file("APPDATA", name)
+}
+
+/// Returns the path to a cache file, creating its parent directories.
+#[cfg(not(target_family = "unix"))]
+pub fn place_cache_file(name: &str) -> Result<PathBuf> {
+    place_file("LOCALAPPDATA", name)
+}
+
+#[cfg(not(target_family = "unix"))]
+fn place_file(base_var: &str, name: &str) -> Result<PathBuf> {
+    let base =
+        std::env::var_os(base_var).ok_or_else(|| anyhow::anyhow!("{} is not set", base_var))?;
+
+    let dir = PathBuf::from(base).join(PREFIX);
+    std::fs::create_dir_all(&dir)?;
+
+    Ok(dir.join(name))
+}
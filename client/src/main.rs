@@ -12,17 +12,29 @@
     not(debug_assertions),
     deny(unused_imports, unused_mut, unused_variables,)
 )]
+// Much of the API client is only used by the Nix Store commands
+#![cfg_attr(not(feature = "nix_store"), allow(dead_code))]
 
 mod api;
 mod cache;
+mod cache_meta;
 mod cli;
 mod command;
 mod config;
+mod dirs;
+mod narinfo;
 mod nix_config;
 mod nix_netrc;
+mod oauth;
+#[cfg(feature = "nix_store")]
 mod push;
+#[cfg(feature = "nix_store")]
+mod push_state;
 mod version;
 
+#[cfg(test)]
+mod tests;
+
 use anyhow::Result;
 
 #[tokio::main]
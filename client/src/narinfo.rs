@@ -0,0 +1,204 @@
+//! Nix narinfos.
+//!
+//! We only need to read the narinfos served by binary caches, so
+//! this is a minimal parser that keeps the fields we care about.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+
+use attic::hash::Hash;
+use attic::signing::{self, NixPublicKey};
+
+/// A narinfo served by a binary cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NarInfo {
+    /// The full store path.
+    pub store_path: PathBuf,
+
+    /// The URL to download the NAR from.
+    ///
+    /// This can be relative to the binary cache.
+    pub url: String,
+
+    /// The compression of the NAR.
+    pub compression: String,
+
+    /// The hash of the uncompressed NAR.
+    pub nar_hash: Hash,
+
+    /// The size of the uncompressed NAR.
+    pub nar_size: usize,
+
+    /// Other store paths this object references, as base names.
+    pub references: Vec<String>,
+
+    /// Signatures of the object.
+    pub signatures: Vec<String>,
+
+    /// The derivation that produced the object, as a base name.
+    pub deriver: Option<String>,
+
+    /// The content address of the object.
+    pub ca: Option<String>,
+}
+
+impl NarInfo {
+    /// Parses a narinfo from a string.
+    pub fn from_str(narinfo: &str) -> Result<Self> {
+        let mut store_path = None;
+        let mut url = None;
+        let mut compression = None;
+        let mut nar_hash = None;
+        let mut nar_size = None;
+        let mut references = Vec::new();
+        let mut signatures = Vec::new();
+        let mut deriver = None;
+        let mut ca = None;
+
+        for line in narinfo.lines() {
+            if line.is_empty() {
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once(": ")
+                .ok_or_else(|| anyhow!("Invalid narinfo line: {}", line))?;
+
+            match key {
+                "StorePath" => store_path = Some(PathBuf::from(value)),
+                "URL" => url = Some(value.to_string()),
+                "Compression" => compression = Some(value.to_string()),
+                "NarHash" => nar_hash = Some(Hash::from_typed(value)?),
+                "NarSize" => nar_size = Some(value.parse()?),
+                "References" => {
+                    references = value.split_whitespace().map(str::to_string).collect();
+                }
+                "Sig" => signatures.push(value.to_string()),
+                "Deriver" if value != "unknown-deriver" => deriver = Some(value.to_string()),
+                "CA" => ca = Some(value.to_string()),
+                _ => {}
+            }
+        }
+
+        let missing = |field| anyhow!("The narinfo has no {}", field);
+
+        Ok(Self {
+            store_path: store_path.ok_or_else(|| missing("StorePath"))?,
+            url: url.ok_or_else(|| missing("URL"))?,
+            // Nix assumes bzip2 when unspecified
+            compression: compression.unwrap_or_else(|| "bzip2".to_string()),
+            nar_hash: nar_hash.ok_or_else(|| missing("NarHash"))?,
+            nar_size: nar_size.ok_or_else(|| missing("NarSize"))?,
+            references,
+            signatures,
+            deriver,
+            ca,
+        })
+    }
+
+    /// Returns the store directory of this object.
+    pub fn store_dir(&self) -> &Path {
+        self.store_path.parent().unwrap_or_else(|| Path::new(""))
+    }
+
+    /// Returns whether the object has a valid signature from a public key.
+    pub fn is_signed_by(&self, public_key: &NixPublicKey) -> bool {
+        let fingerprint = signing::fingerprint(
+            &self.store_path,
+            &self.nar_hash,
+            self.nar_size,
+            &self.references,
+        );
+
+        self.signatures
+            .iter()
+            .any(|signature| public_key.verify(&fingerprint, signature).is_ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use attic::signing::NixKeypair;
+
+    const NARINFO: &str = "StorePath: /nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10
+URL: nar/xcp9cav49dmsjbwdjlmkjxj10gkpx553.nar
+Compression: zstd
+NarHash: sha256:0aak5bl4qq1ixh0fqn2k8gc2xzdf5w3qmy2s7r26b1q4rgnxm7d5
+NarSize: 226560
+References: 563528481rvhc5kxwipjmg6rqrl95mdx-glibc-2.33-56 xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10
+Deriver: vvb4wxmnjixmrkhmj2xb75z62hrr41i7-hello-2.10.drv
+";
+
+    #[test]
+    fn test_narinfo_parse() {
+        let narinfo = NarInfo::from_str(NARINFO).unwrap();
+
+        assert_eq!(
+            Path::new("/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10"),
+            narinfo.store_path
+        );
+        assert_eq!(Path::new("/nix/store"), narinfo.store_dir());
+        assert_eq!("nar/xcp9cav49dmsjbwdjlmkjxj10gkpx553.nar", narinfo.url);
+        assert_eq!("zstd", narinfo.compression);
+        assert_eq!(
+            "sha256:0aak5bl4qq1ixh0fqn2k8gc2xzdf5w3qmy2s7r26b1q4rgnxm7d5",
+            narinfo.nar_hash.to_typed_base32()
+        );
+        assert_eq!(226560, narinfo.nar_size);
+        assert_eq!(2, narinfo.references.len());
+        assert!(narinfo.signatures.is_empty());
+        assert_eq!(
+            Some("vvb4wxmnjixmrkhmj2xb75z62hrr41i7-hello-2.10.drv"),
+            narinfo.deriver.as_deref()
+        );
+        assert!(narinfo.ca.is_none());
+
+        let narinfo = NarInfo::from_str(&format!(
+            "{}CA: fixed:r:sha256:1b8m03r63zqhnjf7l5wnldhh7c134ap5vpj0850ymkq1iyzicy5s\n",
+            NARINFO.replace(
+                "Deriver: vvb4wxmnjixmrkhmj2xb75z62hrr41i7-hello-2.10.drv",
+                "Deriver: unknown-deriver"
+            )
+        ))
+        .unwrap();
+        assert!(narinfo.deriver.is_none());
+        assert_eq!(
+            Some("fixed:r:sha256:1b8m03r63zqhnjf7l5wnldhh7c134ap5vpj0850ymkq1iyzicy5s"),
+            narinfo.ca.as_deref()
+        );
+
+        assert!(NarInfo::from_str("StorePath: /nix/store/a").is_err());
+        assert!(NarInfo::from_str("garbage").is_err());
+    }
+
+    #[test]
+    fn test_narinfo_signature() {
+        let keypair = NixKeypair::generate("cache-1").unwrap();
+        let other = NixKeypair::generate("cache-1").unwrap();
+        let public_key = NixPublicKey::from_str(&keypair.export_public_key()).unwrap();
+        let other_public_key = NixPublicKey::from_str(&other.export_public_key()).unwrap();
+
+        let mut narinfo = NarInfo::from_str(NARINFO).unwrap();
+        assert!(!narinfo.is_signed_by(&public_key));
+
+        let fingerprint = signing::fingerprint(
+            &narinfo.store_path,
+            &narinfo.nar_hash,
+            narinfo.nar_size,
+            &narinfo.references,
+        );
+        narinfo.signatures.push(other.sign(&fingerprint));
+        assert!(!narinfo.is_signed_by(&public_key));
+
+        narinfo.signatures.push(keypair.sign(&fingerprint));
+        assert!(narinfo.is_signed_by(&public_key));
+        assert!(narinfo.is_signed_by(&other_public_key));
+
+        // Tampering invalidates the signature
+        narinfo.nar_size += 1;
+        assert!(!narinfo.is_signed_by(&public_key));
+    }
+}
@@ -1,14 +1,15 @@
 //! Nix configuration files.
 //!
-//! We automatically edit the user's `nix.conf` to add new
-//! binary caches while trying to keep the formatting intact.
+//! We automatically edit the user's or system's `nix.conf` to add
+//! new binary caches while trying to keep the formatting intact.
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Result};
 use lazy_static::lazy_static;
 use regex::Regex;
 use tokio::fs;
+#[cfg(feature = "nix_store")]
 use xdg::BaseDirectories;
 
 lazy_static! {
@@ -30,6 +31,9 @@ const CACHE_NIXOS_ORG_SUBSTITUTER: &str = "https://cache.nixos.org";
 /// The public key of cache.nixos.org.
 const CACHE_NIXOS_ORG_KEY: &str = "cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=";
 
+/// Path to the system-wide `nix.conf`.
+pub const SYSTEM_NIX_CONF: &str = "/etc/nix/nix.conf";
+
 #[derive(Debug)]
 pub struct NixConfig {
     /// Path to write the modified configuration back to.
@@ -54,10 +58,19 @@ enum Line {
 }
 
 impl NixConfig {
+    /// Loads the user's `nix.conf`.
+    #[cfg(feature = "nix_store")]
     pub async fn load() -> Result<Self> {
         let nix_base = BaseDirectories::with_prefix("nix")?;
         let path = nix_base.place_config_file("nix.conf")?;
 
+        Self::load_from(path).await
+    }
+
+    /// Loads a `nix.conf` from a path.
+    ///
+    /// The file doesn't need to exist.
+    pub async fn load_from(path: PathBuf) -> Result<Self> {
         let lines = if path.exists() {
             let content = fs::read_to_string(&path).await?;
             Line::from_lines(&content)?
@@ -71,10 +84,28 @@ impl NixConfig {
         })
     }
 
+    /// Returns an empty configuration that can't be saved.
+    pub fn empty() -> Self {
+        Self {
+            path: None,
+            lines: Vec::new(),
+        }
+    }
+
+    /// Returns the path to the configuration file.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
     /// Saves the modified configuration file.
     pub async fn save(&self) -> Result<()> {
         if let Some(path) = &self.path {
-            fs::write(path, self.to_string()).await?;
+            let mut content = self.to_string();
+            if !content.is_empty() {
+                content.push('\n');
+            }
+
+            fs::write(path, content).await?;
             Ok(())
         } else {
             Err(anyhow!("Don't know how to save the nix.conf"))
@@ -113,13 +144,21 @@ impl NixConfig {
     }
 
     fn prepend_to_list(&mut self, key: &str, value: &str, default_tail: &str) {
+        // Already added with `extra-` by something else
+        let extra_key = format!("extra-{key}");
+        if let Some(Line::KV { value: list, .. }) = self.find_key(&extra_key) {
+            if list.split_whitespace().any(|el| el == value) {
+                return;
+            }
+        }
+
         if let Some(kv) = self.find_key(key) {
             if let Line::KV {
                 value: ref mut list,
                 ..
             } = kv
             {
-                if !list.split(' ').any(|el| el == value) {
+                if !list.split_whitespace().any(|el| el == value) {
                     *list = format!("{value} {list}");
                 }
                 return;
@@ -262,4 +301,73 @@ mod tests {
             assert_eq!(case, line.to_string());
         }
     }
+
+    fn parse(content: &str) -> NixConfig {
+        NixConfig {
+            path: None,
+            lines: Line::from_lines(content).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_nix_config_duplicate_substituter() {
+        let mut config = parse(
+            "# managed by hand\n\
+            substituters = https://a.example https://cache.nixos.org # keep\n\
+            extra-substituters = https://b.example",
+        );
+
+        config.add_substituter("https://a.example");
+        config.add_substituter("https://b.example");
+        assert_eq!(
+            "# managed by hand\n\
+            substituters = https://a.example https://cache.nixos.org # keep\n\
+            extra-substituters = https://b.example",
+            config.to_string()
+        );
+
+        config.add_substituter("https://c.example");
+        assert_eq!(
+            "# managed by hand\n\
+            substituters = https://c.example https://a.example https://cache.nixos.org # keep\n\
+            extra-substituters = https://b.example",
+            config.to_string()
+        );
+
+        let mut config = NixConfig::empty();
+        config.add_substituter("https://a.example");
+        config.add_substituter("https://a.example");
+        assert_eq!(
+            "substituters = https://a.example https://cache.nixos.org",
+            config.to_string()
+        );
+    }
+
+    #[test]
+    fn test_nix_config_idempotent() {
+        fn apply(config: &mut NixConfig) {
+            config.add_substituter("https://attic.example/main");
+            config.add_trusted_public_key("main:AAAA");
+            config.set_netrc_file("/etc/nix/netrc");
+        }
+
+        let original = "experimental-features = nix-command flakes\n\
+            trusted-public-keys = cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=";
+
+        let mut config = parse(original);
+        apply(&mut config);
+        let once = config.to_string();
+
+        let mut config = parse(&once);
+        apply(&mut config);
+        assert_eq!(once, config.to_string());
+
+        assert_eq!(
+            "experimental-features = nix-command flakes\n\
+            trusted-public-keys = main:AAAA cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=\n\
+            substituters = https://attic.example/main https://cache.nixos.org\n\
+            netrc-file = /etc/nix/netrc",
+            once
+        );
+    }
 }
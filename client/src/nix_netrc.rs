@@ -1,62 +1,107 @@
 //! Nix netrc files.
 //!
-//! We automatically edit the user's `netrc` to add cache server
-//! tokens.
+//! We automatically edit the user's or system's `netrc` to add cache
+//! server tokens.
 //!
-//! This is a very naive implementation. The whole thing should be
-//! refactored to be cleaner and operate on streams.
+//! The netrc may hold credentials for other machines that we know
+//! nothing about, so we never reserialize the file. Instead, we
+//! locate the tokens we need to change and edit the original text
+//! in place, leaving everything else (comments, other machines,
+//! macros, formatting) untouched.
 
-use std::collections::HashMap;
-use std::fmt;
+#[cfg(feature = "nix_store")]
 use std::fs::Permissions;
+use std::io::ErrorKind;
+use std::ops::Range;
+#[cfg(feature = "nix_store")]
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Result};
-use tokio::fs::{self, OpenOptions};
-use tokio::io::AsyncWriteExt;
+use tokio::fs;
+#[cfg(feature = "nix_store")]
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+#[cfg(feature = "nix_store")]
 use xdg::BaseDirectories;
 
 /// The permission the configuration file should have.
+#[cfg(feature = "nix_store")]
 const FILE_MODE: u32 = 0o600;
 
+/// Path to the netrc used by the system-wide `nix.conf`.
+pub const SYSTEM_NETRC: &str = "/etc/nix/netrc";
+
 #[derive(Debug)]
 pub struct NixNetrc {
     /// Path to write the modified netrc back to.
     path: Option<PathBuf>,
 
-    /// Machines in the netrc file.
-    machines: HashMap<String, Machine>,
+    /// The content of the netrc when it was loaded.
+    original: String,
+
+    /// The modified content of the netrc.
+    content: String,
 }
 
+/// An entry in the netrc.
 #[derive(Debug, PartialEq, Eq)]
-struct Machine {
-    /// A password.
-    password: Option<String>,
+struct Entry {
+    /// The name of the machine, or `None` for the `default` entry.
+    name: Option<String>,
 
-    /// Any other tokens that we must preserve.
-    ///
-    /// We output in pairs when reserializing. Curl allows the key
-    /// and value to be on different lines, but who knows about other
-    /// implementations?
-    other: Vec<String>,
+    /// Where the `machine` or `default` keyword starts.
+    start: usize,
+
+    /// Where the machine name (or the `default` keyword) ends.
+    header_end: usize,
+
+    /// The password.
+    password: Option<Token>,
+}
+
+/// A token in the netrc.
+#[derive(Debug, PartialEq, Eq)]
+struct Token {
+    /// The value, with any quoting removed.
+    value: String,
+
+    /// The location of the token in the file.
+    span: Range<usize>,
+}
+
+/// A netrc tokenizer.
+struct Lexer<'a> {
+    netrc: &'a str,
+    pos: usize,
 }
 
 impl NixNetrc {
+    #[cfg(feature = "nix_store")]
     pub async fn load() -> Result<Self> {
         let nix_base = BaseDirectories::with_prefix("nix")?;
         let path = nix_base.place_config_file("netrc")?;
 
-        let machines = if path.exists() {
-            let content = fs::read_to_string(&path).await?;
-            parse_machines(&content)?
-        } else {
-            HashMap::new()
+        Self::load_from(path).await
+    }
+
+    /// Loads a netrc from a path.
+    ///
+    /// The file doesn't need to exist.
+    pub async fn load_from(path: PathBuf) -> Result<Self> {
+        let content = match fs::read_to_string(&path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
         };
 
+        // Refuse to touch files we don't understand
+        parse_entries(&content)
+            .map_err(|e| anyhow!("Failed to parse {}: {}", path.display(), e))?;
+
         Ok(Self {
             path: Some(path),
-            machines,
+            original: content.clone(),
+            content,
         })
     }
 
@@ -66,204 +111,465 @@ impl NixNetrc {
     }
 
     /// Saves the modified configuration file.
+    ///
+    /// The new content is written to a temporary file which then
+    /// replaces the netrc, so the netrc is never left half-written.
+    #[cfg(feature = "nix_store")]
     pub async fn save(&self) -> Result<()> {
-        if let Some(path) = &self.path {
-            let mut content = String::new();
-            serialize_machines(&mut content, &self.machines)?;
-
-            // This isn't atomic, so some other process might chmod it
-            // to something else before we write. We don't handle this case.
-            if path.exists() {
-                let permissions = Permissions::from_mode(FILE_MODE);
-                fs::set_permissions(path, permissions).await?;
-            }
+        let Some(path) = &self.path else {
+            return Err(anyhow!("Don't know how to save the netrc"));
+        };
 
+        // Replace the file a symlink points to, not the symlink itself
+        let path = match fs::canonicalize(path).await {
+            Ok(path) => path,
+            Err(e) if e.kind() == ErrorKind::NotFound => path.clone(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| anyhow!("Invalid netrc path {}", path.display()))?;
+        let temp_path = path.with_file_name(format!(
+            ".{}.{}.tmp",
+            file_name.to_string_lossy(),
+            std::process::id()
+        ));
+
+        let result = async {
             let mut file = OpenOptions::new()
                 .create(true)
                 .write(true)
+                .truncate(true)
                 .mode(FILE_MODE)
-                .open(path)
+                .open(&temp_path)
                 .await?;
 
-            file.write_all(content.as_bytes()).await?;
-            Ok(())
-        } else {
-            Err(anyhow!("Don't know how to save the netrc"))
+            // The file may have been left behind with other permissions
+            file.set_permissions(Permissions::from_mode(FILE_MODE))
+                .await?;
+
+            file.write_all(self.content.as_bytes()).await?;
+            file.sync_all().await?;
+
+            fs::rename(&temp_path, &path).await
+        }
+        .await;
+
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path).await;
         }
+
+        Ok(result?)
     }
 
     /// Adds a token as a password.
-    pub fn add_token(&mut self, machine: String, token: String) {
-        if let Some(m) = self.machines.get_mut(&machine) {
-            m.password = Some(token);
+    ///
+    /// Only the password of the machine is changed. If the machine
+    /// isn't in the netrc yet, it's added before the `default` entry
+    /// so it isn't shadowed.
+    pub fn add_token(&mut self, machine: String, token: String) -> Result<()> {
+        let entries = parse_entries(&self.content)?;
+        let password = quote_token(&token);
+
+        if let Some(entry) = entries
+            .iter()
+            .find(|e| e.name.as_deref() == Some(machine.as_str()))
+        {
+            if let Some(old) = &entry.password {
+                self.content.replace_range(old.span.clone(), &password);
+            } else {
+                self.content
+                    .insert_str(entry.header_end, &format!(" password {}", password));
+            }
         } else {
-            self.machines.insert(
-                machine,
-                Machine {
-                    password: Some(token),
-                    other: Vec::new(),
-                },
-            );
+            let new_entry = format!("machine {}\npassword {}\n", quote_token(&machine), password);
+
+            if let Some(default) = entries.iter().find(|e| e.name.is_none()) {
+                self.content.insert_str(default.start, &new_entry);
+            } else {
+                if !self.content.is_empty() && !self.content.ends_with('\n') {
+                    self.content.push('\n');
+                }
+                self.content.push_str(&new_entry);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns a diff of the changes to the netrc.
+    ///
+    /// Returns `None` if nothing changed.
+    pub fn diff(&self) -> Option<String> {
+        if self.original == self.content {
+            return None;
+        }
+
+        let old: Vec<_> = self.original.lines().collect();
+        let new: Vec<_> = self.content.lines().collect();
+
+        // Our changes are always in a single place
+        let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+        let suffix = old[prefix..]
+            .iter()
+            .rev()
+            .zip(new[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        let mut diff = String::new();
+        for line in &old[prefix..old.len() - suffix] {
+            diff.push_str(&format!("-{}\n", line));
+        }
+        for line in &new[prefix..new.len() - suffix] {
+            diff.push_str(&format!("+{}\n", line));
         }
+
+        Some(diff)
     }
 }
 
-fn parse_machines(netrc: &str) -> Result<HashMap<String, Machine>> {
-    let mut machines = HashMap::new();
-    let mut cur_machine = None;
+impl<'a> Lexer<'a> {
+    fn new(netrc: &'a str) -> Self {
+        Self { netrc, pos: 0 }
+    }
 
-    let mut cur;
-    let mut remaining = netrc;
-    while !remaining.is_empty() {
-        (cur, remaining) = get_next_token(remaining);
+    /// Returns the next token, skipping whitespace and comments.
+    fn next_token(&mut self) -> Result<Option<Token>> {
+        loop {
+            let rest = &self.netrc[self.pos..];
+            let trimmed = rest.trim_start();
+            self.pos += rest.len() - trimmed.len();
 
-        match cur {
-            "" => {
+            if trimmed.starts_with('#') {
+                self.pos += trimmed.find('\n').unwrap_or(trimmed.len());
+            } else {
                 break;
             }
-            "default" => {
-                if let Some((name, machine)) = cur_machine {
-                    machines.insert(name, machine);
-                }
+        }
 
-                cur_machine = Some((
-                    "".to_string(),
-                    Machine {
-                        password: None,
-                        other: Vec::new(),
-                    },
-                ));
-            }
-            "machine" => {
-                let (m_name, m_remaining) = get_next_token(remaining);
-                remaining = m_remaining;
+        let start = self.pos;
+        let rest = &self.netrc[start..];
 
-                if let Some((name, machine)) = cur_machine {
-                    machines.insert(name, machine);
-                }
+        if rest.is_empty() {
+            return Ok(None);
+        }
 
-                cur_machine = Some((
-                    m_name.to_string(),
-                    Machine {
-                        password: None,
-                        other: Vec::new(),
+        let value = if let Some(quoted) = rest.strip_prefix('"') {
+            let mut value = String::new();
+            let mut chars = quoted.char_indices();
+
+            loop {
+                match chars.next() {
+                    Some((i, '"')) => {
+                        self.pos = start + 1 + i + 1;
+                        break;
+                    }
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, 'n')) => value.push('\n'),
+                        Some((_, 'r')) => value.push('\r'),
+                        Some((_, 't')) => value.push('\t'),
+                        Some((_, c)) => value.push(c),
+                        None => return Err(anyhow!("Unterminated quoted token")),
                     },
-                ));
-            }
-            "password" => {
-                let (m_password, m_remaining) = get_next_token(remaining);
-                remaining = m_remaining;
-
-                if let Some((_, ref mut machine)) = &mut cur_machine {
-                    machine.password = Some(m_password.to_string());
-                } else {
-                    return Err(anyhow!("Password field outside a machine block"));
-                }
-            }
-            tok => {
-                if let Some((_, ref mut machine)) = &mut cur_machine {
-                    machine.other.push(tok.to_string());
-                } else {
-                    return Err(anyhow!("Unknown token {} outside a machine block", tok));
+                    Some((_, c)) => value.push(c),
+                    None => return Err(anyhow!("Unterminated quoted token")),
                 }
             }
-        }
-    }
 
-    if let Some((name, machine)) = cur_machine {
-        machines.insert(name, machine);
+            value
+        } else {
+            let len = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            self.pos += len;
+            rest[..len].to_string()
+        };
+
+        Ok(Some(Token {
+            value,
+            span: start..self.pos,
+        }))
     }
 
-    Ok(machines)
+    /// Skips the body of a macro, which ends at an empty line.
+    fn skip_macro(&mut self) {
+        self.pos = match self.netrc[self.pos..].find("\n\n") {
+            Some(idx) => self.pos + idx + 2,
+            None => self.netrc.len(),
+        };
+    }
 }
 
-fn serialize_machines(w: &mut impl fmt::Write, machines: &HashMap<String, Machine>) -> Result<()> {
-    for (name, machine) in machines.iter() {
-        if name.is_empty() {
-            writeln!(w, "default")?;
-        } else {
-            writeln!(w, "machine {}", name)?;
-        }
+fn parse_entries(netrc: &str) -> Result<Vec<Entry>> {
+    let mut lexer = Lexer::new(netrc);
+    let mut entries: Vec<Entry> = Vec::new();
 
-        if let Some(password) = &machine.password {
-            writeln!(w, "password {}", password)?;
-        }
+    while let Some(token) = lexer.next_token()? {
+        match token.value.as_str() {
+            "machine" => {
+                let name = lexer
+                    .next_token()?
+                    .ok_or_else(|| anyhow!("Missing machine name"))?;
+
+                entries.push(Entry {
+                    name: Some(name.value),
+                    start: token.span.start,
+                    header_end: name.span.end,
+                    password: None,
+                });
+            }
+            "default" => {
+                entries.push(Entry {
+                    name: None,
+                    start: token.span.start,
+                    header_end: token.span.end,
+                    password: None,
+                });
+            }
+            "macdef" => {
+                lexer
+                    .next_token()?
+                    .ok_or_else(|| anyhow!("Missing macro name"))?;
+                lexer.skip_macro();
+            }
+            key => {
+                let value = lexer
+                    .next_token()?
+                    .ok_or_else(|| anyhow!("Missing value for {}", key))?;
 
-        for chunk in machine.other.chunks(2) {
-            writeln!(w, "{}", chunk.join(" "))?;
+                let Some(entry) = entries.last_mut() else {
+                    return Err(anyhow!("Unknown token {} outside a machine block", key));
+                };
+
+                if key == "password" {
+                    entry.password = Some(value);
+                }
+            }
         }
     }
 
-    Ok(())
+    Ok(entries)
 }
 
-fn get_next_token(s: &str) -> (&str, &str) {
-    let s = strip_leading_whitespace(s);
-    if let Some(idx) = s.find(|c| c == '\n' || c == ' ' || c == '\t') {
-        (&s[..idx], strip_leading_whitespace(&s[idx + 1..]))
-    } else {
-        (s, "")
+/// Quotes a token if necessary.
+fn quote_token(token: &str) -> String {
+    let needs_quoting =
+        token.is_empty() || token.starts_with(['"', '#']) || token.contains(char::is_whitespace);
+
+    if !needs_quoting {
+        return token.to_string();
     }
-}
 
-fn strip_leading_whitespace(s: &str) -> &str {
-    if let Some(idx) = s.find(|c| c != '\n' && c != ' ' && c != '\t') {
-        &s[idx..]
-    } else {
-        ""
+    let mut quoted = String::from('"');
+    for c in token.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
     }
+    quoted.push('"');
+
+    quoted
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_netrc_strip() {
-        assert_eq!("", strip_leading_whitespace("   "));
-        assert_eq!("a", strip_leading_whitespace("   a"));
-        assert_eq!("abc", strip_leading_whitespace("   \t\t\n\nabc"));
-        assert_eq!("abc", strip_leading_whitespace("abc"));
+    const NETRC: &str = r#"# Work
+machine git.example.com
+  login alice
+  password "correct horse"
+  account ops
+
+machine cache.example.com login bob password old-token
+
+macdef init
+cd /pub
+machine not-a-machine
+
+# Everything else
+default login anonymous password guest@
+"#;
+
+    fn netrc(content: &str) -> NixNetrc {
+        NixNetrc {
+            path: None,
+            original: content.to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    fn passwords(content: &str) -> Vec<(Option<String>, Option<String>)> {
+        parse_entries(content)
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.name, e.password.map(|p| p.value)))
+            .collect()
     }
 
     #[test]
     fn test_netrc_tokenization() {
-        assert_eq!(("", ""), get_next_token(""));
-        assert_eq!(("", ""), get_next_token(" "));
-        assert_eq!(("", ""), get_next_token("\n"));
-        assert_eq!(("", ""), get_next_token("\t"));
-
-        assert_eq!(("a", ""), get_next_token("a "));
-        assert_eq!(("a", ""), get_next_token(" a"));
-        assert_eq!(("a", ""), get_next_token("  a "));
-
-        assert_eq!(("abc", ""), get_next_token("abc"));
+        let mut lexer = Lexer::new("  a\tb\n\n# comment c\n\"d e\\\"\\n\" f#g");
+        let mut tokens = Vec::new();
+        while let Some(token) = lexer.next_token().unwrap() {
+            tokens.push(token.value);
+        }
 
-        assert_eq!(("a", "b"), get_next_token("a b"));
-        assert_eq!(("a", "b c"), get_next_token("a b c"));
-        assert_eq!(("a", "b\nc"), get_next_token("a\nb\nc"));
-        assert_eq!(("a", "b\nc"), get_next_token("a\tb\nc"));
+        assert_eq!(vec!["a", "b", "d e\"\n", "f#g"], tokens);
 
-        assert_eq!(("a", "b c"), get_next_token("a       b c"));
-        assert_eq!(("a", "b\nc"), get_next_token("a\n\n\nb\nc"));
-        assert_eq!(("a", "b\nc"), get_next_token("a\n\t\nb\nc"));
+        assert!(Lexer::new("\"abc").next_token().is_err());
     }
 
     #[test]
     fn test_netrc_parse() {
-        let machines = parse_machines(
-            "default password hunter2 machine localhost login login password 114514",
-        )
-        .unwrap();
-        eprintln!("{:#?}", machines);
+        assert_eq!(
+            vec![
+                (
+                    Some("git.example.com".to_string()),
+                    Some("correct horse".to_string())
+                ),
+                (
+                    Some("cache.example.com".to_string()),
+                    Some("old-token".to_string())
+                ),
+                (None, Some("guest@".to_string())),
+            ],
+            passwords(NETRC)
+        );
+
+        assert!(parse_entries("").unwrap().is_empty());
+        assert!(parse_entries("password hunter2").is_err());
+        assert!(parse_entries("machine").is_err());
+        assert!(parse_entries("machine a login").is_err());
+    }
+
+    #[test]
+    fn test_netrc_replace_token() {
+        let mut netrc = netrc(NETRC);
+        netrc
+            .add_token("cache.example.com".to_string(), "new-token".to_string())
+            .unwrap();
+
+        assert_eq!(
+            NETRC.replace("password old-token", "password new-token"),
+            netrc.content
+        );
+        assert_eq!(
+            "-machine cache.example.com login bob password old-token\n+machine cache.example.com login bob password new-token\n",
+            netrc.diff().unwrap()
+        );
+
+        // Nothing else changes when the token is the same
+        let mut netrc = self::netrc(NETRC);
+        netrc
+            .add_token("cache.example.com".to_string(), "old-token".to_string())
+            .unwrap();
+        assert_eq!(None, netrc.diff());
+    }
 
-        assert_eq!(Some("114514".to_string()), machines["localhost"].password);
+    #[test]
+    fn test_netrc_add_password() {
+        let mut netrc = netrc("machine a login alice\nmachine b password x\n");
+        netrc
+            .add_token("a".to_string(), "token".to_string())
+            .unwrap();
+
+        assert_eq!(
+            "machine a password token login alice\nmachine b password x\n",
+            netrc.content
+        );
+    }
 
-        let mut serialized = String::new();
-        serialize_machines(&mut serialized, &machines).unwrap();
-        eprintln!("{}", serialized);
+    #[test]
+    fn test_netrc_add_machine() {
+        // New machines go before the default entry
+        let mut netrc = netrc(NETRC);
+        netrc
+            .add_token("new.example.com".to_string(), "token".to_string())
+            .unwrap();
+
+        assert_eq!(
+            NETRC.replace(
+                "default login",
+                "machine new.example.com\npassword token\ndefault login"
+            ),
+            netrc.content
+        );
+        assert_eq!(
+            Some((
+                Some("new.example.com".to_string()),
+                Some("token".to_string())
+            )),
+            passwords(&netrc.content).into_iter().nth(2)
+        );
+
+        let mut netrc = self::netrc("machine a password x");
+        netrc
+            .add_token("b".to_string(), "token with spaces".to_string())
+            .unwrap();
+        assert_eq!(
+            "machine a password x\nmachine b\npassword \"token with spaces\"\n",
+            netrc.content
+        );
+        assert_eq!(
+            Some("token with spaces".to_string()),
+            passwords(&netrc.content)[1].1
+        );
+
+        let mut netrc = self::netrc("");
+        netrc
+            .add_token("a".to_string(), "token".to_string())
+            .unwrap();
+        assert_eq!("machine a\npassword token\n", netrc.content);
+        assert_eq!("+machine a\n+password token\n", netrc.diff().unwrap());
+    }
 
-        let reparse = parse_machines(&serialized).unwrap();
-        assert_eq!(machines, reparse);
+    #[cfg(feature = "nix_store")]
+    #[tokio::test]
+    async fn test_netrc_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("netrc");
+
+        fs::write(&path, NETRC).await.unwrap();
+        fs::set_permissions(&path, Permissions::from_mode(0o644))
+            .await
+            .unwrap();
+
+        let mut netrc = NixNetrc::load_from(path.clone()).await.unwrap();
+        netrc
+            .add_token("cache.example.com".to_string(), "new-token".to_string())
+            .unwrap();
+        netrc.save().await.unwrap();
+
+        assert_eq!(netrc.content, fs::read_to_string(&path).await.unwrap());
+
+        let mode = fs::metadata(&path).await.unwrap().permissions().mode();
+        assert_eq!(FILE_MODE, mode & 0o777);
+
+        // No temporary files are left behind
+        let mut dir_entries = fs::read_dir(dir.path()).await.unwrap();
+        let mut names = Vec::new();
+        while let Some(entry) = dir_entries.next_entry().await.unwrap() {
+            names.push(entry.file_name());
+        }
+        assert_eq!(vec!["netrc"], names);
+
+        // Missing files are created
+        let path = dir.path().join("new-netrc");
+        let mut netrc = NixNetrc::load_from(path.clone()).await.unwrap();
+        netrc
+            .add_token("a".to_string(), "token".to_string())
+            .unwrap();
+        netrc.save().await.unwrap();
+
+        assert_eq!(
+            "machine a\npassword token\n",
+            fs::read_to_string(&path).await.unwrap()
+        );
     }
 }
@@ -0,0 +1,602 @@
+//! OAuth 2.0 login.
+//!
+//! Some deployments put Attic behind an identity provider that can mint
+//! Attic tokens. With `attic login --oauth`, we log in with the device
+//! authorization grant ([RFC 8628]) and trade the resulting access token
+//! for an Attic token at an exchange endpoint with a token exchange
+//! request ([RFC 8693]).
+//!
+//! The refresh token is stored next to the Attic token, and we
+//! transparently repeat the exchange when the Attic token is about
+//! to expire.
+//!
+//! [RFC 8628]: https://www.rfc-editor.org/rfc/rfc8628
+//! [RFC 8693]: https://www.rfc-editor.org/rfc/rfc8693
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use reqwest::{Client as HttpClient, Response};
+use serde::Deserialize;
+use tokio::time::{self, Instant};
+
+use crate::api::http_client_builder;
+use crate::cache::{CacheRef, ServerName};
+use crate::config::{Config, OAuthConfig, ServerConfig, ServerTokenConfig};
+
+/// The grant type of device access token requests.
+const GRANT_TYPE_DEVICE_CODE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+/// The grant type of token exchange requests.
+const GRANT_TYPE_TOKEN_EXCHANGE: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+
+/// The type of the subject token in token exchange requests.
+const TOKEN_TYPE_ACCESS_TOKEN: &str = "urn:ietf:params:oauth:token-type:access_token";
+
+/// The default polling interval of the device flow.
+const DEFAULT_POLL_INTERVAL: u64 = 5;
+
+/// How much to slow down polling when asked to.
+const SLOW_DOWN_INCREMENT: u64 = 5;
+
+/// How long before expiry we renew Attic tokens.
+const REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+/// Parameters of an OAuth login.
+#[derive(Debug, Clone)]
+pub struct LoginOptions<'a> {
+    /// The issuer URL of the identity provider.
+    pub issuer: &'a str,
+
+    /// The client ID registered with the identity provider.
+    pub client_id: &'a str,
+
+    /// The scopes to request.
+    pub scope: &'a str,
+
+    /// The endpoint exchanging access tokens for Attic tokens.
+    pub exchange_url: &'a str,
+}
+
+/// An Attic token obtained through OAuth.
+#[derive(Debug, Clone)]
+pub struct LoginResult {
+    /// The Attic token.
+    pub token: String,
+
+    /// State for renewing the token.
+    pub oauth: OAuthConfig,
+}
+
+/// OAuth client for an identity provider.
+struct OAuthClient {
+    client: HttpClient,
+}
+
+/// Relevant parts of the provider metadata.
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    device_authorization_endpoint: Option<String>,
+    token_endpoint: String,
+}
+
+/// A device authorization response.
+#[derive(Debug, Deserialize)]
+struct DeviceAuthorization {
+    device_code: String,
+    user_code: String,
+    verification_uri: String,
+    verification_uri_complete: Option<String>,
+    expires_in: u64,
+    interval: Option<u64>,
+}
+
+/// A successful token response.
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: Option<String>,
+    expires_in: Option<u64>,
+}
+
+/// An OAuth error response.
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    error: String,
+    error_description: Option<String>,
+}
+
+/// Logs in with the device authorization grant.
+pub async fn login(server: &ServerConfig, options: &LoginOptions<'_>) -> Result<LoginResult> {
+    let client = OAuthClient::new(server)?;
+    let metadata = client.discover(options.issuer).await?;
+    let device_endpoint = metadata.device_authorization_endpoint.ok_or_else(|| {
+        anyhow!(
+            "The identity provider at {} does not support the device authorization grant",
+            options.issuer
+        )
+    })?;
+
+    let device = client
+        .authorize_device(&device_endpoint, options.client_id, options.scope)
+        .await?;
+
+    eprintln!(
+        "🔑 To log in, open {} and enter the code {}",
+        device.verification_uri, device.user_code
+    );
+    if let Some(uri) = &device.verification_uri_complete {
+        eprintln!("   Or open {}", uri);
+    }
+
+    let tokens = client
+        .poll_device_token(&metadata.token_endpoint, options.client_id, &device)
+        .await?;
+    let exchanged = client
+        .exchange(options.exchange_url, &tokens.access_token)
+        .await?;
+
+    let oauth = OAuthConfig {
+        issuer: options.issuer.to_owned(),
+        client_id: options.client_id.to_owned(),
+        token_endpoint: metadata.token_endpoint,
+        exchange_url: options.exchange_url.to_owned(),
+        expires_at: expires_at(&exchanged, &tokens),
+        refresh_token: tokens.refresh_token,
+    };
+
+    Ok(LoginResult {
+        token: exchanged.access_token,
+        oauth,
+    })
+}
+
+/// Renews the Attic token of a server with the refresh token.
+pub async fn refresh(server: &ServerConfig, oauth: &OAuthConfig) -> Result<LoginResult> {
+    let refresh_token = oauth
+        .refresh_token
+        .as_deref()
+        .ok_or_else(|| anyhow!("No refresh token was issued"))?;
+
+    let client = OAuthClient::new(server)?;
+    let tokens = client
+        .refresh(&oauth.token_endpoint, &oauth.client_id, refresh_token)
+        .await?;
+    let exchanged = client
+        .exchange(&oauth.exchange_url, &tokens.access_token)
+        .await?;
+
+    let expires_at = expires_at(&exchanged, &tokens);
+    let oauth = OAuthConfig {
+        // Providers that don't rotate refresh tokens omit them
+        refresh_token: tokens.refresh_token.or_else(|| oauth.refresh_token.clone()),
+        expires_at,
+        ..oauth.clone()
+    };
+
+    Ok(LoginResult {
+        token: exchanged.access_token,
+        oauth,
+    })
+}
+
+/// Renews the tokens of the servers of some caches if they are about to expire.
+///
+/// Servers not logged in with OAuth are left alone. If a token cannot be
+/// renewed, we carry on with it until it has actually expired.
+pub async fn refresh_tokens<'a>(
+    config: &mut Config,
+    cache_refs: impl IntoIterator<Item = &'a CacheRef>,
+) -> Result<()> {
+    let mut servers: Vec<ServerName> = Vec::new();
+    for cache_ref in cache_refs {
+        let (name, _, _) = config.resolve_cache(cache_ref)?;
+        if !servers.contains(name) {
+            servers.push(name.clone());
+        }
+    }
+
+    let now = unix_now();
+    for name in servers {
+        let server = &config.servers[&name];
+        let Some(oauth) = &server.oauth else {
+            continue;
+        };
+        if !needs_refresh(oauth, now) {
+            continue;
+        }
+
+        tracing::debug!("Refreshing the token of server \"{}\"", name.as_str());
+
+        match refresh(server, oauth).await {
+            Ok(result) => {
+                let mut config_m = config.as_mut();
+                let server = config_m.servers.get_mut(&name).unwrap();
+                server.token = Some(ServerTokenConfig::Raw {
+                    token: result.token,
+                });
+                server.oauth = Some(result.oauth);
+            }
+            Err(e) if oauth.expires_at.is_some_and(|t| t > now) => {
+                eprintln!(
+                    "⚠️ Could not refresh the token of server \"{}\": {}",
+                    name.as_str(),
+                    e
+                );
+            }
+            Err(e) => {
+                return Err(e.context(format!(
+                    "The token of server \"{}\" has expired and could not be refreshed. \
+                    Log in again with `attic login --oauth`.",
+                    name.as_str()
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+impl OAuthClient {
+    fn new(server: &ServerConfig) -> Result<Self> {
+        Ok(Self {
+            client: http_client_builder(server).build()?,
+        })
+    }
+
+    /// Fetches the metadata of an identity provider.
+    async fn discover(&self, issuer: &str) -> Result<ProviderMetadata> {
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            issuer.trim_end_matches('/')
+        );
+        let res = self.client.get(&url).send().await?;
+
+        if res.status().is_success() {
+            Ok(res.json().await?)
+        } else {
+            Err(anyhow!(
+                "Could not fetch the provider metadata from {}: HTTP {}",
+                url,
+                res.status()
+            ))
+        }
+    }
+
+    /// Starts a device authorization.
+    async fn authorize_device(
+        &self,
+        endpoint: &str,
+        client_id: &str,
+        scope: &str,
+    ) -> Result<DeviceAuthorization> {
+        let res = self
+            .client
+            .post(endpoint)
+            .form(&[("client_id", client_id), ("scope", scope)])
+            .send()
+            .await?;
+
+        if res.status().is_success() {
+            Ok(res.json().await?)
+        } else {
+            Err(error_from_response(res).await)
+        }
+    }
+
+    /// Polls the token endpoint until the user has approved the device.
+    async fn poll_device_token(
+        &self,
+        endpoint: &str,
+        client_id: &str,
+        device: &DeviceAuthorization,
+    ) -> Result<TokenResponse> {
+        let deadline = Instant::now() + Duration::from_secs(device.expires_in);
+        let mut interval = device.interval.unwrap_or(DEFAULT_POLL_INTERVAL);
+
+        loop {
+            time::sleep(Duration::from_secs(interval)).await;
+
+            if Instant::now() >= deadline {
+                return Err(anyhow!("The login code has expired"));
+            }
+
+            let res = self
+                .client
+                .post(endpoint)
+                .form(&[
+                    ("grant_type", GRANT_TYPE_DEVICE_CODE),
+                    ("device_code", &device.device_code),
+                    ("client_id", client_id),
+                ])
+                .send()
+                .await?;
+
+            if res.status().is_success() {
+                return Ok(res.json().await?);
+            }
+
+            let status = res.status();
+            match res.json::<ErrorResponse>().await {
+                Ok(e) if e.error == "authorization_pending" => {}
+                Ok(e) if e.error == "slow_down" => {
+                    interval += SLOW_DOWN_INCREMENT;
+                }
+                Ok(e) if e.error == "expired_token" => {
+                    return Err(anyhow!("The login code has expired"));
+                }
+                Ok(e) if e.error == "access_denied" => {
+                    return Err(anyhow!("The login was denied"));
+                }
+                Ok(e) => return Err(e.into_error()),
+                Err(_) => return Err(anyhow!("The token endpoint returned HTTP {}", status)),
+            }
+        }
+    }
+
+    /// Obtains a new access token with a refresh token.
+    async fn refresh(
+        &self,
+        endpoint: &str,
+        client_id: &str,
+        refresh_token: &str,
+    ) -> Result<TokenResponse> {
+        let res = self
+            .client
+            .post(endpoint)
+            .form(&[
+                ("grant_type", "refresh_token"),
+                ("refresh_token", refresh_token),
+                ("client_id", client_id),
+            ])
+            .send()
+            .await?;
+
+        if res.status().is_success() {
+            Ok(res.json().await?)
+        } else {
+            Err(error_from_response(res).await)
+        }
+    }
+
+    /// Exchanges an access token for an Attic token.
+    async fn exchange(&self, endpoint: &str, access_token: &str) -> Result<TokenResponse> {
+        let res = self
+            .client
+            .post(endpoint)
+            .form(&[
+                ("grant_type", GRANT_TYPE_TOKEN_EXCHANGE),
+                ("subject_token", access_token),
+                ("subject_token_type", TOKEN_TYPE_ACCESS_TOKEN),
+            ])
+            .send()
+            .await?;
+
+        if res.status().is_success() {
+            Ok(res.json().await?)
+        } else {
+            Err(error_from_response(res)
+                .await
+                .context("Could not exchange the access token for an Attic token"))
+        }
+    }
+}
+
+impl ErrorResponse {
+    fn into_error(self) -> anyhow::Error {
+        if let Some(description) = self.error_description {
+            anyhow!("{}: {}", self.error, description)
+        } else {
+            anyhow!("{}", self.error)
+        }
+    }
+}
+
+async fn error_from_response(res: Response) -> anyhow::Error {
+    let status = res.status();
+    match res.json::<ErrorResponse>().await {
+        Ok(e) => e.into_error(),
+        Err(_) => anyhow!("HTTP {}", status),
+    }
+}
+
+/// Returns when the Attic token expires.
+///
+/// If the exchange endpoint doesn't say, we assume the Attic token lives
+/// as long as the access token.
+fn expires_at(exchanged: &TokenResponse, tokens: &TokenResponse) -> Option<u64> {
+    exchanged
+        .expires_in
+        .or(tokens.expires_in)
+        .map(|secs| unix_now() + secs)
+}
+
+fn needs_refresh(oauth: &OAuthConfig, now: u64) -> bool {
+    match oauth.expires_at {
+        Some(expires_at) => expires_at <= now + REFRESH_MARGIN.as_secs(),
+        None => false,
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use axum::{
+        extract::{Form, State},
+        http::StatusCode,
+        routing::{get, post},
+        Json, Router,
+    };
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use tokio::net::TcpListener;
+
+    /// A fake identity provider.
+    ///
+    /// The first device token poll is pending. Exchanged tokens are
+    /// the access token prefixed with `attic-`.
+    async fn start_provider() -> String {
+        async fn metadata(State(base): State<Arc<(String, AtomicUsize)>>) -> Json<Value> {
+            Json(json!({
+                "issuer": base.0,
+                "device_authorization_endpoint": format!("{}/device", base.0),
+                "token_endpoint": format!("{}/token", base.0),
+            }))
+        }
+
+        async fn device(Form(form): Form<HashMap<String, String>>) -> Json<Value> {
+            assert_eq!("attic", form["client_id"]);
+            Json(json!({
+                "device_code": "device",
+                "user_code": "ABCD-EFGH",
+                "verification_uri": "https://idp.example.com/device",
+                "expires_in": 60,
+                "interval": 0,
+            }))
+        }
+
+        async fn token(
+            State(state): State<Arc<(String, AtomicUsize)>>,
+            Form(form): Form<HashMap<String, String>>,
+        ) -> (StatusCode, Json<Value>) {
+            match form["grant_type"].as_str() {
+                GRANT_TYPE_DEVICE_CODE => {
+                    assert_eq!("device", form["device_code"]);
+                    if state.1.fetch_add(1, Ordering::SeqCst) == 0 {
+                        return (
+                            StatusCode::BAD_REQUEST,
+                            Json(json!({ "error": "authorization_pending" })),
+                        );
+                    }
+                    (
+                        StatusCode::OK,
+                        Json(json!({
+                            "access_token": "access-1",
+                            "refresh_token": "refresh-1",
+                            "expires_in": 3600,
+                        })),
+                    )
+                }
+                "refresh_token" if form["refresh_token"] == "refresh-1" => (
+                    StatusCode::OK,
+                    Json(json!({
+                        "access_token": "access-2",
+                        "expires_in": 3600,
+                    })),
+                ),
+                _ => (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({
+                        "error": "invalid_grant",
+                        "error_description": "Refresh token is revoked",
+                    })),
+                ),
+            }
+        }
+
+        async fn exchange(Form(form): Form<HashMap<String, String>>) -> Json<Value> {
+            assert_eq!(GRANT_TYPE_TOKEN_EXCHANGE, form["grant_type"]);
+            assert_eq!(TOKEN_TYPE_ACCESS_TOKEN, form["subject_token_type"]);
+            Json(json!({
+                "access_token": format!("attic-{}", form["subject_token"]),
+                "issued_token_type": TOKEN_TYPE_ACCESS_TOKEN,
+                "token_type": "Bearer",
+                "expires_in": 600,
+            }))
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new((base.clone(), AtomicUsize::new(0)));
+
+        let app = Router::new()
+            .route("/.well-known/openid-configuration", get(metadata))
+            .route("/device", post(device))
+            .route("/token", post(token))
+            .route("/exchange", post(exchange))
+            .with_state(state);
+
+        tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+
+        base
+    }
+
+    fn server_config() -> ServerConfig {
+        toml::from_str(r#"endpoint = "https://attic.example.com""#).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_login_and_refresh() {
+        let base = start_provider().await;
+        let server = server_config();
+        let exchange_url = format!("{base}/exchange");
+
+        let options = LoginOptions {
+            issuer: &format!("{base}/"),
+            client_id: "attic",
+            scope: "openid offline_access",
+            exchange_url: &exchange_url,
+        };
+        let login = login(&server, &options).await.unwrap();
+        assert_eq!("attic-access-1", login.token);
+        assert_eq!(format!("{base}/token"), login.oauth.token_endpoint);
+        assert_eq!(Some("refresh-1"), login.oauth.refresh_token.as_deref());
+
+        let expires_at = login.oauth.expires_at.unwrap();
+        assert!(expires_at.abs_diff(unix_now() + 600) <= 5);
+        assert!(!needs_refresh(&login.oauth, unix_now()));
+        assert!(needs_refresh(&login.oauth, expires_at - 60));
+
+        // The refresh token is kept if the provider doesn't rotate it
+        let refreshed = refresh(&server, &login.oauth).await.unwrap();
+        assert_eq!("attic-access-2", refreshed.token);
+        assert_eq!(Some("refresh-1"), refreshed.oauth.refresh_token.as_deref());
+
+        let revoked = OAuthConfig {
+            refresh_token: Some("revoked".to_string()),
+            ..login.oauth
+        };
+        let err = refresh(&server, &revoked).await.unwrap_err();
+        assert!(
+            err.to_string().contains("Refresh token is revoked"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_oauth_config() {
+        let mut server = server_config();
+        server.token = Some(ServerTokenConfig::Raw {
+            token: "attic-token".to_string(),
+        });
+        server.oauth = Some(OAuthConfig {
+            issuer: "https://idp.example.com".to_string(),
+            client_id: "attic".to_string(),
+            token_endpoint: "https://idp.example.com/token".to_string(),
+            exchange_url: "https://idp.example.com/exchange".to_string(),
+            refresh_token: Some("refresh".to_string()),
+            expires_at: Some(1700000000),
+        });
+
+        let serialized = toml::to_string(&server).unwrap();
+        let parsed: ServerConfig = toml::from_str(&serialized).unwrap();
+        assert_eq!(Some("attic-token".to_string()), parsed.token().unwrap());
+
+        let oauth = parsed.oauth.unwrap();
+        assert_eq!("https://idp.example.com/exchange", oauth.exchange_url);
+        assert_eq!(Some(1700000000), oauth.expires_at);
+    }
+}
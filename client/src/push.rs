@@ -1,6 +1,6 @@
 //! Store path uploader.
 //!
-//! There are two APIs: `Pusher` and `PushSession`.
+//! There are three APIs: `Pusher`, `PushSession`, and `MultiPusher`.
 //!
 //! A `Pusher` simply dispatches `ValidPathInfo`s for workers to push. Use this
 //! when you know all store paths to push beforehand. The push plan (closure, missing
@@ -13,43 +13,85 @@
 //! source (e.g., FS watcher, Unix Domain Socket) and a push plan cannot be
 //! created statically.
 //!
+//! A `MultiPusher` is like a `Pusher` but pushes to several caches at once.
+//! The closure and path metadata are computed once, and each NAR is read
+//! from the store once and streamed to all caches missing the path.
+//!
 //! TODO: Refactor out progress reporting and support a simple output style without progress bars
 
 use std::collections::{HashMap, HashSet};
 use std::fmt::Write;
+use std::future::Future;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex as StdMutex};
 use std::task::{Context, Poll};
 use std::time::{Duration, Instant};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context as _, Result};
 use async_channel as channel;
 use bytes::Bytes;
 use futures::future::join_all;
-use futures::stream::{Stream, TryStreamExt};
+use futures::stream::{self, Stream, StreamExt, TryStreamExt};
 use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressState, ProgressStyle};
+use serde::Serialize;
 use tokio::sync::{mpsc, Mutex};
 use tokio::task::{spawn, JoinHandle};
 use tokio::time;
 
 use crate::api::ApiClient;
+use crate::push_state::PushState;
 use attic::api::v1::cache_config::CacheConfig;
-use attic::api::v1::upload_path::{UploadPathNarInfo, UploadPathResult, UploadPathResultKind};
+use attic::api::v1::upload_path::{
+    UploadPathNarInfo, UploadPathPreflightResultKind, UploadPathResult, UploadPathResultKind,
+};
 use attic::cache::CacheName;
 use attic::error::AtticResult;
 use attic::nix_store::{NixStore, StorePath, StorePathHash, ValidPathInfo};
+use attic::signing::NixKeypair;
+
+/// The minimum NAR size to check whether the NAR needs to be uploaded.
+///
+/// Smaller NARs are cheaper to upload than an extra round-trip.
+const PREFLIGHT_NAR_SIZE_THRESHOLD: u64 = 1024 * 1024; // 1 MiB
+
+/// The number of NAR chunks buffered for each cache when pushing to multiple caches.
+const FAN_OUT_BUFFER: usize = 8;
 
 type JobSender = channel::Sender<ValidPathInfo>;
 type JobReceiver = channel::Receiver<ValidPathInfo>;
 
+/// The result of pushing a store path.
+pub type PushResult = (StorePath, Result<UploadPathResult>);
+
+/// A stream of results as store paths finish pushing.
+///
+/// The stream ends after the `Pusher` is dropped or waited on and all
+/// queued paths are pushed.
+pub type PushResults = Pin<Box<dyn Stream<Item = PushResult> + Send>>;
+
+type ResultSender = mpsc::UnboundedSender<PushResult>;
+type ResultReceiver = mpsc::UnboundedReceiver<PushResult>;
+
 /// Configuration for pushing store paths.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub struct PushConfig {
     /// The number of workers to spawn.
     pub num_workers: usize,
 
     /// Whether to always include the upload info in the PUT payload.
     pub force_preamble: bool,
+
+    /// A keypair to sign the paths with before uploading.
+    pub signing_keypair: Option<Arc<NixKeypair>>,
+
+    /// Whether to also add the signatures to the local store.
+    pub sign_local_store: bool,
+
+    /// Whether to suppress the line printed as each path finishes pushing.
+    pub quiet: bool,
+
+    /// Whether to compress NARs before uploading them.
+    pub compress_upload: bool,
 }
 
 /// Configuration for a push session.
@@ -72,8 +114,11 @@ pub struct Pusher {
     store: Arc<NixStore>,
     cache: CacheName,
     cache_config: CacheConfig,
-    workers: Vec<JoinHandle<HashMap<StorePath, Result<()>>>>,
+    workers: Vec<JoinHandle<()>>,
     sender: JobSender,
+
+    /// Receiver of results, until taken by `results`.
+    result_receiver: Option<ResultReceiver>,
 }
 
 /// A wrapper over a `Pusher` that accepts a stream of `StorePath`s.
@@ -102,8 +147,11 @@ pub struct PushSession {
     /// Sender to the batching future.
     sender: channel::Sender<SessionQueueCommand>,
 
-    /// Receiver of results.
-    result_receiver: mpsc::Receiver<Result<HashMap<StorePath, Result<()>>>>,
+    /// Results of individual paths, until taken by `results`.
+    results: Option<PushResults>,
+
+    /// Receiver of the outcome of the session.
+    done_receiver: mpsc::Receiver<Result<()>>,
 }
 
 enum SessionQueueCommand {
@@ -135,6 +183,66 @@ pub struct PushPlan {
     pub num_upstream: usize,
 }
 
+/// A handle to push store paths to multiple caches.
+///
+/// Each queued path is pushed to the given subset of the targets.
+/// The NAR is read from the store once and streamed to the uploads
+/// concurrently. Results are kept separately for each target.
+pub struct MultiPusher {
+    store: Arc<NixStore>,
+    targets: Arc<Vec<PushTarget>>,
+    workers: Vec<JoinHandle<()>>,
+    sender: channel::Sender<MultiPushJob>,
+
+    /// Receivers of results of each target, until taken by `results`.
+    result_receivers: Option<Vec<ResultReceiver>>,
+}
+
+/// A cache to push to with a `MultiPusher`.
+pub struct PushTarget {
+    pub api: ApiClient,
+    pub cache: CacheName,
+    pub cache_config: CacheConfig,
+
+    /// Whether the server accepts compressed uploads.
+    ///
+    /// NARs are only compressed if `PushConfig::compress_upload` is also set.
+    pub supports_compressed_upload: bool,
+}
+
+/// A store path to push with a `MultiPusher`.
+struct MultiPushJob {
+    path_info: ValidPathInfo,
+
+    /// Indices of the targets to push to.
+    targets: Vec<usize>,
+}
+
+#[derive(Debug)]
+pub struct MultiPushPlan {
+    /// Store paths to push to at least one target.
+    pub store_path_map: HashMap<StorePathHash, ValidPathInfo>,
+
+    /// The number of paths in the original full closure.
+    pub num_all_paths: usize,
+
+    /// Plans for each target, in the order of the targets.
+    pub targets: Vec<TargetPlan>,
+}
+
+/// What to push to a single cache.
+#[derive(Debug)]
+pub struct TargetPlan {
+    /// Store paths missing from the cache.
+    pub missing_paths: HashSet<StorePathHash>,
+
+    /// Number of paths that have been filtered out because they are already cached.
+    pub num_already_cached: usize,
+
+    /// Number of paths that have been filtered out because they are signed by an upstream cache.
+    pub num_upstream: usize,
+}
+
 /// Wrapper to update a progress bar as a NAR is streamed.
 struct NarStreamProgress<S> {
     stream: S,
@@ -151,16 +259,18 @@ impl Pusher {
         config: PushConfig,
     ) -> Self {
         let (sender, receiver) = channel::unbounded();
+        let (result_sender, result_receiver) = mpsc::unbounded_channel();
         let mut workers = Vec::new();
 
         for _ in 0..config.num_workers {
             workers.push(spawn(Self::worker(
                 receiver.clone(),
+                result_sender.clone(),
                 store.clone(),
                 api.clone(),
                 cache.clone(),
                 mp.clone(),
-                config,
+                config.clone(),
             )));
         }
 
@@ -171,6 +281,7 @@ impl Pusher {
             cache_config,
             workers,
             sender,
+            result_receiver: Some(result_receiver),
         }
     }
 
@@ -179,22 +290,36 @@ impl Pusher {
         self.sender.send(path_info).await.map_err(|e| anyhow!(e))
     }
 
-    /// Waits for all workers to terminate, returning all results.
+    /// Returns a stream of results as paths finish pushing.
     ///
-    /// TODO: Stream the results with another channel
-    pub async fn wait(self) -> HashMap<StorePath, Result<()>> {
+    /// This can only be called once, and results yielded here are not
+    /// returned by `wait`.
+    pub fn results(&mut self) -> PushResults {
+        let mut receiver = self
+            .result_receiver
+            .take()
+            .expect("Results have already been taken");
+
+        Box::pin(stream::poll_fn(move |cx| receiver.poll_recv(cx)))
+    }
+
+    /// Waits for all workers to terminate, returning results not taken by `results`.
+    pub async fn wait(self) -> HashMap<StorePath, Result<UploadPathResult>> {
         drop(self.sender);
 
-        let results = join_all(self.workers)
-            .await
-            .into_iter()
-            .map(|joinresult| joinresult.unwrap())
-            .fold(HashMap::new(), |mut acc, results| {
-                acc.extend(results);
-                acc
-            });
+        for joinresult in join_all(self.workers).await {
+            joinresult.unwrap();
+        }
 
-        results
+        // All senders are gone, so this ends after the buffered results
+        match self.result_receiver {
+            Some(mut receiver) => {
+                stream::poll_fn(move |cx| receiver.poll_recv(cx))
+                    .collect()
+                    .await
+            }
+            None => HashMap::new(),
+        }
     }
 
     /// Creates a push plan.
@@ -227,14 +352,13 @@ impl Pusher {
 
     async fn worker(
         receiver: JobReceiver,
+        result_sender: ResultSender,
         store: Arc<NixStore>,
         api: ApiClient,
         cache: CacheName,
         mp: MultiProgress,
         config: PushConfig,
-    ) -> HashMap<StorePath, Result<()>> {
-        let mut results = HashMap::new();
-
+    ) {
         loop {
             let path_info = match receiver.recv().await {
                 Ok(path_info) => path_info,
@@ -252,41 +376,240 @@ impl Pusher {
                 api.clone(),
                 &cache,
                 mp.clone(),
-                config.force_preamble,
+                &config,
             )
             .await;
 
-            results.insert(store_path, r);
+            // Nobody may be listening
+            let _ = result_sender.send((store_path, r));
         }
+    }
+}
 
-        results
+impl MultiPusher {
+    pub fn new(
+        store: Arc<NixStore>,
+        targets: Vec<PushTarget>,
+        mp: MultiProgress,
+        config: PushConfig,
+    ) -> Self {
+        let (sender, receiver) = channel::unbounded();
+        let (result_senders, result_receivers): (Vec<_>, Vec<_>) =
+            targets.iter().map(|_| mpsc::unbounded_channel()).unzip();
+        let targets = Arc::new(targets);
+        let mut workers = Vec::new();
+
+        for _ in 0..config.num_workers {
+            workers.push(spawn(Self::worker(
+                receiver.clone(),
+                result_senders.clone(),
+                store.clone(),
+                targets.clone(),
+                mp.clone(),
+                config.clone(),
+            )));
+        }
+
+        Self {
+            store,
+            targets,
+            workers,
+            sender,
+            result_receivers: Some(result_receivers),
+        }
+    }
+
+    /// Queues a store path to be pushed to some targets.
+    ///
+    /// `targets` are indices into the targets passed to `new`.
+    pub async fn queue(&self, path_info: ValidPathInfo, targets: Vec<usize>) -> Result<()> {
+        self.sender
+            .send(MultiPushJob { path_info, targets })
+            .await
+            .map_err(|e| anyhow!(e))
+    }
+
+    /// Returns a stream of results for each target as paths finish pushing.
+    ///
+    /// This can only be called once, and results yielded here are not
+    /// returned by `wait`.
+    pub fn results(&mut self) -> Vec<PushResults> {
+        let receivers = self
+            .result_receivers
+            .take()
+            .expect("Results have already been taken");
+
+        receivers
+            .into_iter()
+            .map(|mut receiver| -> PushResults {
+                Box::pin(stream::poll_fn(move |cx| receiver.poll_recv(cx)))
+            })
+            .collect()
+    }
+
+    /// Waits for all workers to terminate, returning results of each target not taken by `results`.
+    pub async fn wait(self) -> Vec<HashMap<StorePath, Result<UploadPathResult>>> {
+        drop(self.sender);
+
+        for joinresult in join_all(self.workers).await {
+            joinresult.unwrap();
+        }
+
+        match self.result_receivers {
+            Some(receivers) => {
+                let futures = receivers.into_iter().map(|mut receiver| {
+                    stream::poll_fn(move |cx| receiver.poll_recv(cx)).collect()
+                });
+                join_all(futures).await
+            }
+            None => self.targets.iter().map(|_| HashMap::new()).collect(),
+        }
+    }
+
+    /// Creates a push plan for all targets.
+    ///
+    /// The closure is computed once, and each target is queried for
+    /// missing paths.
+    pub async fn plan(
+        &self,
+        roots: Vec<StorePath>,
+        no_closure: bool,
+        ignore_upstream_filter: bool,
+    ) -> Result<MultiPushPlan> {
+        let mut store_path_map = query_closure(self.store.clone(), roots, no_closure).await?;
+        let num_all_paths = store_path_map.len();
+
+        let futures = self.targets.iter().map(|target| {
+            let store_path_map = &store_path_map;
+            async move {
+                TargetPlan::plan(
+                    &target.api,
+                    &target.cache,
+                    &target.cache_config,
+                    store_path_map,
+                    ignore_upstream_filter,
+                )
+                .await
+                .with_context(|| {
+                    format!(
+                        "Failed to query missing paths in \"{}\"",
+                        target.cache.as_str()
+                    )
+                })
+            }
+        });
+        let targets = join_all(futures)
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+
+        store_path_map.retain(|sph, _| targets.iter().any(|t| t.missing_paths.contains(sph)));
+
+        Ok(MultiPushPlan {
+            store_path_map,
+            num_all_paths,
+            targets,
+        })
+    }
+
+    async fn worker(
+        receiver: channel::Receiver<MultiPushJob>,
+        result_senders: Vec<ResultSender>,
+        store: Arc<NixStore>,
+        targets: Arc<Vec<PushTarget>>,
+        mp: MultiProgress,
+        config: PushConfig,
+    ) {
+        loop {
+            let job = match receiver.recv().await {
+                Ok(job) => job,
+                Err(_) => {
+                    // channel is closed - we are done
+                    break;
+                }
+            };
+
+            let store_path = job.path_info.path.clone();
+
+            let results = upload_path_multi(
+                job.path_info,
+                store.clone(),
+                &targets,
+                &job.targets,
+                mp.clone(),
+                &config,
+            )
+            .await;
+
+            for (&target, r) in job.targets.iter().zip(results) {
+                // Nobody may be listening
+                let _ = result_senders[target].send((store_path.clone(), r));
+            }
+        }
+    }
+}
+
+impl MultiPushPlan {
+    /// Returns the indices of the targets a path should be pushed to.
+    pub fn targets_for(&self, store_path_hash: &StorePathHash) -> Vec<usize> {
+        self.targets
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| t.missing_paths.contains(store_path_hash))
+            .map(|(i, _)| i)
+            .collect()
     }
 }
 
 impl PushSession {
     pub fn with_pusher(pusher: Pusher, config: PushSessionConfig) -> Self {
-        let (sender, receiver) = channel::unbounded();
-        let (result_sender, result_receiver) = mpsc::channel(1);
+        Self::with_state(pusher, config, None)
+    }
 
-        let known_paths_mutex = Arc::new(Mutex::new(HashSet::new()));
+    /// Creates a session that loads and persists known paths in a state file.
+    ///
+    /// Paths are recorded once they are pushed successfully.
+    pub fn with_state(
+        mut pusher: Pusher,
+        config: PushSessionConfig,
+        state: Option<PushState>,
+    ) -> Self {
+        let (sender, receiver) = channel::unbounded();
+        let (done_sender, done_receiver) = mpsc::channel(1);
+        let mut results = pusher.results();
+
+        let known_paths = state
+            .as_ref()
+            .map(|state| state.known_paths())
+            .unwrap_or_default();
+        let known_paths_mutex = Arc::new(Mutex::new(known_paths));
+
+        let state = state.map(|state| Arc::new(StdMutex::new(state)));
+        if let Some(state) = &state {
+            let state = state.clone();
+            results = Box::pin(results.inspect(move |(path, result)| {
+                if result.is_ok() {
+                    state.lock().unwrap().insert(&path.to_hash());
+                }
+            }));
+        }
 
         spawn(async move {
-            if let Err(e) = Self::worker(
+            let r = Self::worker(
                 pusher,
                 config,
                 known_paths_mutex.clone(),
+                state,
                 receiver.clone(),
-                result_sender.clone(),
             )
-            .await
-            {
-                let _ = result_sender.send(Err(e)).await;
-            }
+            .await;
+            let _ = done_sender.send(r).await;
         });
 
         Self {
             sender,
-            result_receiver,
+            results: Some(results),
+            done_receiver,
         }
     }
 
@@ -294,8 +617,8 @@ impl PushSession {
         pusher: Pusher,
         config: PushSessionConfig,
         known_paths_mutex: Arc<Mutex<HashSet<StorePathHash>>>,
+        state: Option<Arc<StdMutex<PushState>>>,
         receiver: channel::Receiver<SessionQueueCommand>,
-        result_sender: mpsc::Sender<Result<HashMap<StorePath, Result<()>>>>,
     ) -> Result<()> {
         let mut roots = HashSet::new();
 
@@ -364,25 +687,50 @@ impl PushSession {
 
             drop(known_paths);
 
+            // We get here at least every 10 seconds
+            if let Some(state) = &state {
+                if let Err(e) = state.lock().unwrap().save_debounced() {
+                    tracing::warn!("Could not write state file: {}", e);
+                }
+            }
+
             if done {
-                let result = pusher.wait().await;
-                result_sender.send(Ok(result)).await?;
+                pusher.wait().await;
                 return Ok(());
             }
         }
     }
 
-    /// Waits for all workers to terminate, returning all results.
-    pub async fn wait(mut self) -> Result<HashMap<StorePath, Result<()>>> {
+    /// Returns a stream of results as paths finish pushing.
+    ///
+    /// This can only be called once, and results yielded here are not
+    /// returned by `wait`.
+    pub fn results(&mut self) -> PushResults {
+        self.results
+            .take()
+            .expect("Results have already been taken")
+    }
+
+    /// Waits for all workers to terminate, returning results not taken by `results`.
+    ///
+    /// Returns an error if the session failed to compute what to push.
+    pub async fn wait(mut self) -> Result<HashMap<StorePath, Result<UploadPathResult>>> {
         self.flush()?;
 
         // The worker might have died
         let _ = self.sender.send(SessionQueueCommand::Terminate).await;
 
-        self.result_receiver
+        let results = match self.results.take() {
+            Some(results) => results.collect().await,
+            None => HashMap::new(),
+        };
+
+        self.done_receiver
             .recv()
             .await
-            .expect("Nothing in result channel")
+            .expect("Nothing in result channel")?;
+
+        Ok(results)
     }
 
     /// Queues multiple store paths to be pushed.
@@ -400,6 +748,155 @@ impl PushSession {
     }
 }
 
+/// Prints failures as paths finish pushing.
+///
+/// The cache is included in the output if specified, which is useful
+/// when pushing to multiple caches.
+///
+/// Returns an error after the stream ends if any path failed to push.
+pub async fn report_failures(
+    mut results: PushResults,
+    mp: MultiProgress,
+    cache: Option<CacheName>,
+) -> Result<()> {
+    let mut num_failed = 0;
+    let mut first_error = None;
+
+    while let Some((path, result)) = results.next().await {
+        if let Err(e) = result {
+            mp.suspend(|| match &cache {
+                Some(cache) => eprintln!(
+                    "❌ {} → \"{}\": {}",
+                    path.as_os_str().to_string_lossy(),
+                    cache.as_str(),
+                    e
+                ),
+                None => eprintln!("❌ {}: {}", path.as_os_str().to_string_lossy(), e),
+            });
+
+            num_failed += 1;
+            first_error.get_or_insert(e);
+        }
+    }
+
+    match first_error {
+        Some(e) => Err(e.context(format!(
+            "Failed to push {} path{}{}",
+            num_failed,
+            if num_failed == 1 { "" } else { "s" },
+            describe_destination(cache.as_ref()),
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Prints results as JSON lines on the standard output as paths finish pushing.
+///
+/// If the cache is specified, each object also includes the cache.
+///
+/// Returns an error after the stream ends if any path failed to push.
+pub async fn report_json(
+    mut results: PushResults,
+    store: Arc<NixStore>,
+    cache: Option<CacheName>,
+) -> Result<()> {
+    let mut num_failed = 0;
+
+    while let Some((path, result)) = results.next().await {
+        let full_path = store.get_full_path(&path).to_string_lossy().into_owned();
+        println!(
+            "{}",
+            JsonPushResult::new(full_path, cache.as_ref(), &result).to_json()?
+        );
+
+        if result.is_err() {
+            num_failed += 1;
+        }
+    }
+
+    if num_failed == 0 {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Failed to push {} path{}{}",
+            num_failed,
+            if num_failed == 1 { "" } else { "s" },
+            describe_destination(cache.as_ref()),
+        ))
+    }
+}
+
+/// Describes the cache paths failed to push to, if known.
+fn describe_destination(cache: Option<&CacheName>) -> String {
+    match cache {
+        Some(cache) => format!(" to \"{}\"", cache.as_str()),
+        None => String::new(),
+    }
+}
+
+/// A machine-readable result of pushing a store path.
+#[derive(Debug, Serialize)]
+struct JsonPushResult {
+    /// The full store path.
+    path: String,
+
+    /// The cache the path was pushed to, when pushing to multiple caches.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache: Option<String>,
+
+    /// What happened to the path.
+    result: JsonPushResultKind,
+
+    /// The compressed size of the NAR, if known.
+    file_size: Option<usize>,
+
+    /// The fraction of data that was deduplicated, if known.
+    frac_deduplicated: Option<f64>,
+
+    /// The error if the path failed to push.
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum JsonPushResultKind {
+    Uploaded,
+    Deduplicated,
+    Failed,
+}
+
+impl JsonPushResult {
+    fn new(path: String, cache: Option<&CacheName>, result: &Result<UploadPathResult>) -> Self {
+        let cache = cache.map(|cache| cache.as_str().to_owned());
+
+        match result {
+            Ok(r) => Self {
+                path,
+                cache,
+                result: match r.kind {
+                    UploadPathResultKind::Deduplicated => JsonPushResultKind::Deduplicated,
+                    _ => JsonPushResultKind::Uploaded,
+                },
+                file_size: r.file_size,
+                frac_deduplicated: r.frac_deduplicated,
+                error: None,
+            },
+            Err(e) => Self {
+                path,
+                cache,
+                result: JsonPushResultKind::Failed,
+                file_size: None,
+                frac_deduplicated: None,
+                error: Some(format!("{:#}", e)),
+            },
+        }
+    }
+
+    fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+}
+
 impl PushPlan {
     /// Creates a plan.
     async fn plan(
@@ -411,42 +908,39 @@ impl PushPlan {
         no_closure: bool,
         ignore_upstream_filter: bool,
     ) -> Result<Self> {
-        // Compute closure
-        let closure = if no_closure {
-            roots
-        } else {
-            store
-                .compute_fs_closure_multi(roots, false, false, false)
-                .await?
-        };
+        let mut store_path_map = query_closure(store, roots, no_closure).await?;
+        let num_all_paths = store_path_map.len();
 
-        let mut store_path_map: HashMap<StorePathHash, ValidPathInfo> = {
-            let futures = closure
-                .iter()
-                .map(|path| {
-                    let store = store.clone();
-                    let path = path.clone();
-                    let path_hash = path.to_hash();
-
-                    async move {
-                        let path_info = store.query_path_info(path).await?;
-                        Ok((path_hash, path_info))
-                    }
-                })
-                .collect::<Vec<_>>();
+        let target = TargetPlan::plan(
+            api,
+            cache,
+            cache_config,
+            &store_path_map,
+            ignore_upstream_filter,
+        )
+        .await?;
+        store_path_map.retain(|sph, _| target.missing_paths.contains(sph));
 
-            join_all(futures).await.into_iter().collect::<Result<_>>()?
-        };
+        Ok(Self {
+            store_path_map,
+            num_all_paths,
+            num_already_cached: target.num_already_cached,
+            num_upstream: target.num_upstream,
+        })
+    }
+}
 
+impl TargetPlan {
+    /// Determines which paths in a closure need to be pushed to a cache.
+    async fn plan(
+        api: &ApiClient,
+        cache: &CacheName,
+        cache_config: &CacheConfig,
+        store_path_map: &HashMap<StorePathHash, ValidPathInfo>,
+        ignore_upstream_filter: bool,
+    ) -> Result<Self> {
         let num_all_paths = store_path_map.len();
-        if store_path_map.is_empty() {
-            return Ok(Self {
-                store_path_map,
-                num_all_paths,
-                num_already_cached: 0,
-                num_upstream: 0,
-            });
-        }
+        let mut candidates: Vec<&ValidPathInfo> = store_path_map.values().collect();
 
         if !ignore_upstream_filter {
             // Filter out paths signed by upstream caches
@@ -454,7 +948,7 @@ impl PushPlan {
                 .upstream_cache_key_names
                 .as_ref()
                 .map_or([].as_slice(), |v| v.as_slice());
-            store_path_map.retain(|_, pi| {
+            candidates.retain(|pi| {
                 for sig in &pi.sigs {
                     if let Some((name, _)) = sig.split_once(':') {
                         if upstream_cache_key_names.iter().any(|u| name == u) {
@@ -467,34 +961,48 @@ impl PushPlan {
             });
         }
 
-        let num_filtered_paths = store_path_map.len();
-        if store_path_map.is_empty() {
+        let num_filtered_paths = candidates.len();
+        if candidates.is_empty() {
             return Ok(Self {
-                store_path_map,
-                num_all_paths,
+                missing_paths: HashSet::new(),
                 num_already_cached: 0,
                 num_upstream: num_all_paths - num_filtered_paths,
             });
         }
 
         // Query missing paths
-        let missing_path_hashes: HashSet<StorePathHash> = {
-            let store_path_hashes = store_path_map.keys().map(|sph| sph.to_owned()).collect();
+        let missing_paths: HashSet<StorePathHash> = {
+            let store_path_hashes = candidates.iter().map(|pi| pi.path.to_hash()).collect();
             let res = api.get_missing_paths(cache, store_path_hashes).await?;
             res.missing_paths.into_iter().collect()
         };
-        store_path_map.retain(|sph, _| missing_path_hashes.contains(sph));
-        let num_missing_paths = store_path_map.len();
+        let num_missing_paths = missing_paths.len();
 
         Ok(Self {
-            store_path_map,
-            num_all_paths,
+            missing_paths,
             num_already_cached: num_filtered_paths - num_missing_paths,
             num_upstream: num_all_paths - num_filtered_paths,
         })
     }
 }
 
+/// Computes the closure of store paths and queries their metadata.
+async fn query_closure(
+    store: Arc<NixStore>,
+    roots: Vec<StorePath>,
+    no_closure: bool,
+) -> Result<HashMap<StorePathHash, ValidPathInfo>> {
+    let closure = if no_closure {
+        roots
+    } else {
+        store
+            .compute_fs_closure_multi(roots, false, false, false)
+            .await?
+    };
+
+    Ok(store.query_path_infos(closure).await?)
+}
+
 /// Uploads a single path to a cache.
 pub async fn upload_path(
     path_info: ValidPathInfo,
@@ -502,40 +1010,225 @@ pub async fn upload_path(
     api: ApiClient,
     cache: &CacheName,
     mp: MultiProgress,
-    force_preamble: bool,
-) -> Result<()> {
+    config: &PushConfig,
+) -> Result<UploadPathResult> {
     let path = &path_info.path;
-    let upload_info = {
-        let full_path = store
-            .get_full_path(path)
-            .to_str()
-            .ok_or_else(|| anyhow!("Path contains non-UTF-8"))?
-            .to_string();
-
-        let references = path_info
-            .references
-            .into_iter()
-            .map(|pb| {
-                pb.to_str()
-                    .ok_or_else(|| anyhow!("Reference contains non-UTF-8"))
-                    .map(|s| s.to_owned())
-            })
-            .collect::<Result<Vec<String>, anyhow::Error>>()?;
-
-        UploadPathNarInfo {
-            cache: cache.to_owned(),
-            store_path_hash: path.to_hash(),
-            store_path: full_path,
-            references,
-            system: None,  // TODO
-            deriver: None, // TODO
-            sigs: path_info.sigs,
-            ca: path_info.ca,
-            nar_hash: path_info.nar_hash.to_owned(),
-            nar_size: path_info.nar_size as usize,
+    let upload_info = make_upload_info(&path_info, &store, cache, config).await?;
+
+    let bar = make_progress_bar(&mp, path, path_info.nar_size);
+    let start = Instant::now();
+
+    let result = match upload_without_nar(&api, &upload_info, config).await {
+        Some(r) => Ok(r),
+        None => {
+            let nar_stream =
+                NarStreamProgress::new(store.nar_from_path(path.to_owned()), bar.clone())
+                    .map_ok(Bytes::from);
+
+            api.upload_path(
+                upload_info,
+                nar_stream,
+                config.force_preamble,
+                config.compress_upload,
+            )
+            .await
+            .map(or_uploaded)
+        }
+    };
+
+    match result {
+        Ok(r) => {
+            if !config.quiet {
+                let info_string = describe_upload(&r, path_info.nar_size, start.elapsed());
+                mp.suspend(|| {
+                    eprintln!(
+                        "✅ {} ({})",
+                        path.as_os_str().to_string_lossy(),
+                        info_string
+                    );
+                });
+            }
+            bar.finish_and_clear();
+
+            Ok(r)
+        }
+        Err(e) => {
+            bar.finish_and_clear();
+            Err(e)
+        }
+    }
+}
+
+/// Uploads a single path to multiple caches.
+///
+/// The NAR is read from the store once and streamed to all caches
+/// that need it concurrently. Results are returned in the order of
+/// `indices`, which are indices into `targets`.
+pub async fn upload_path_multi(
+    path_info: ValidPathInfo,
+    store: Arc<NixStore>,
+    targets: &[PushTarget],
+    indices: &[usize],
+    mp: MultiProgress,
+    config: &PushConfig,
+) -> Vec<Result<UploadPathResult>> {
+    let path = &path_info.path;
+    let Some(&first) = indices.first() else {
+        return Vec::new();
+    };
+
+    // The signature doesn't cover the cache, so the same info is used for all targets
+    let upload_info =
+        match make_upload_info(&path_info, &store, &targets[first].cache, config).await {
+            Ok(upload_info) => upload_info,
+            Err(e) => {
+                let message = format!("{:#}", e);
+                return indices
+                    .iter()
+                    .map(|_| Err(anyhow!(message.clone())))
+                    .collect();
+            }
+        };
+
+    let bar = make_progress_bar(&mp, path, path_info.nar_size);
+    let start = Instant::now();
+
+    let upload_infos: Vec<UploadPathNarInfo> = indices
+        .iter()
+        .map(|&i| UploadPathNarInfo {
+            cache: targets[i].cache.to_owned(),
+            ..upload_info.clone()
+        })
+        .collect();
+
+    let mut results: Vec<Option<Result<UploadPathResult>>> = join_all(
+        indices
+            .iter()
+            .zip(&upload_infos)
+            .map(|(&i, upload_info)| upload_without_nar(&targets[i].api, upload_info, config)),
+    )
+    .await
+    .into_iter()
+    .map(|r| r.map(Ok))
+    .collect();
+
+    // Stream the NAR to the caches that still need it
+    let need_nar: Vec<usize> = (0..indices.len())
+        .filter(|&pos| results[pos].is_none())
+        .collect();
+
+    if !need_nar.is_empty() {
+        let nar_stream = NarStreamProgress::new(store.nar_from_path(path.to_owned()), bar.clone());
+        let (feeder, nar_streams) = fan_out(nar_stream, need_nar.len());
+
+        let uploads = need_nar.iter().zip(nar_streams).map(|(&pos, nar_stream)| {
+            let target = &targets[indices[pos]];
+            target.api.upload_path(
+                upload_infos[pos].clone(),
+                nar_stream,
+                config.force_preamble,
+                config.compress_upload && target.supports_compressed_upload,
+            )
+        });
+
+        let ((), nar_results) = tokio::join!(feeder, join_all(uploads));
+
+        for (&pos, r) in need_nar.iter().zip(nar_results) {
+            results[pos] = Some(r.map(or_uploaded));
         }
+    }
+
+    let elapsed = start.elapsed();
+    let results: Vec<Result<UploadPathResult>> = results
+        .into_iter()
+        .map(|r| r.expect("Upload did not finish"))
+        .collect();
+
+    if !config.quiet {
+        for (&i, r) in indices.iter().zip(&results) {
+            if let Ok(r) = r {
+                let info_string = describe_upload(r, path_info.nar_size, elapsed);
+                mp.suspend(|| {
+                    eprintln!(
+                        "✅ {} → \"{}\" ({})",
+                        path.as_os_str().to_string_lossy(),
+                        targets[i].cache.as_str(),
+                        info_string
+                    );
+                });
+            }
+        }
+    }
+    bar.finish_and_clear();
+
+    results
+}
+
+/// Builds the upload info of a path, signing it if configured.
+async fn make_upload_info(
+    path_info: &ValidPathInfo,
+    store: &NixStore,
+    cache: &CacheName,
+    config: &PushConfig,
+) -> Result<UploadPathNarInfo> {
+    let path = &path_info.path;
+    let full_path = store
+        .get_full_path(path)
+        .to_str()
+        .ok_or_else(|| anyhow!("Path contains non-UTF-8"))?
+        .to_string();
+
+    let references = path_info
+        .references
+        .iter()
+        .map(|pb| {
+            pb.to_str()
+                .ok_or_else(|| anyhow!("Reference contains non-UTF-8"))
+                .map(|s| s.to_owned())
+        })
+        .collect::<Result<Vec<String>, anyhow::Error>>()?;
+
+    // Derivations are often not present locally, in which case
+    // the system is simply omitted
+    let system = match &path_info.deriver {
+        Some(deriver) => store.query_derivation_system(deriver.to_owned()).await.ok(),
+        None => None,
     };
 
+    let deriver = path_info
+        .deriver
+        .as_ref()
+        .map(|deriver| deriver.as_os_str().to_string_lossy().into_owned());
+
+    let mut upload_info = UploadPathNarInfo {
+        cache: cache.to_owned(),
+        store_path_hash: path.to_hash(),
+        store_path: full_path,
+        references,
+        system,
+        deriver,
+        sigs: path_info.sigs.clone(),
+        ca: path_info.ca.clone(),
+        nar_hash: path_info.nar_hash.to_owned(),
+        nar_size: path_info.nar_size as usize,
+    };
+
+    if let Some(keypair) = &config.signing_keypair {
+        let signature = sign_upload_info(&mut upload_info, keypair);
+
+        if config.sign_local_store {
+            store
+                .add_signatures(path.to_owned(), vec![signature])
+                .await
+                .context("Failed to add the signature to the local store")?;
+        }
+    }
+
+    Ok(upload_info)
+}
+
+/// Creates a progress bar for uploading a path.
+fn make_progress_bar(mp: &MultiProgress, path: &StorePath, nar_size: u64) -> ProgressBar {
     let template = format!(
         "{{spinner}} {: <20.20} {{bar:40.green/blue}} {{human_bytes:10}} ({{average_speed}})",
         path.name(),
@@ -558,61 +1251,126 @@ pub async fn upload_path(
                 _ => write!(w, "-").unwrap(),
             },
         );
-    let bar = mp.add(ProgressBar::new(path_info.nar_size));
+    let bar = mp.add(ProgressBar::new(nar_size));
     bar.set_style(style);
-    let nar_stream = NarStreamProgress::new(store.nar_from_path(path.to_owned()), bar.clone())
-        .map_ok(Bytes::from);
+    bar
+}
 
-    let start = Instant::now();
+/// Uploads a path without the NAR if the server already has it.
+///
+/// Returns `None` if the NAR needs to be uploaded.
+async fn upload_without_nar(
+    api: &ApiClient,
+    upload_info: &UploadPathNarInfo,
+    config: &PushConfig,
+) -> Option<UploadPathResult> {
+    if (upload_info.nar_size as u64) < PREFLIGHT_NAR_SIZE_THRESHOLD
+        || !skip_nar(api, upload_info).await
+    {
+        return None;
+    }
+
+    let empty = stream::empty::<Result<Bytes, std::io::Error>>();
     match api
-        .upload_path(upload_info, nar_stream, force_preamble)
+        .upload_path(upload_info.clone(), empty, config.force_preamble, false)
         .await
     {
-        Ok(r) => {
-            let r = r.unwrap_or(UploadPathResult {
-                kind: UploadPathResultKind::Uploaded,
-                file_size: None,
-                frac_deduplicated: None,
-            });
+        Ok(r) => Some(or_uploaded(r)),
+        Err(e) => {
+            // The NAR may have gone away in the meantime
+            tracing::debug!("Failed to upload without the NAR, retrying: {}", e);
+            None
+        }
+    }
+}
 
-            let info_string: String = match r.kind {
-                UploadPathResultKind::Deduplicated => "deduplicated".to_string(),
-                _ => {
-                    let elapsed = start.elapsed();
-                    let seconds = elapsed.as_secs_f64();
-                    let speed = (path_info.nar_size as f64 / seconds) as u64;
+/// Fills in the result of an upload if the server didn't return one.
+fn or_uploaded(result: Option<UploadPathResult>) -> UploadPathResult {
+    result.unwrap_or(UploadPathResult {
+        kind: UploadPathResultKind::Uploaded,
+        file_size: None,
+        frac_deduplicated: None,
+    })
+}
 
-                    let mut s = format!("{}/s", HumanBytes(speed));
+/// Describes a finished upload for the progress output.
+fn describe_upload(r: &UploadPathResult, nar_size: u64, elapsed: Duration) -> String {
+    match r.kind {
+        UploadPathResultKind::Deduplicated => "deduplicated".to_string(),
+        _ => {
+            let seconds = elapsed.as_secs_f64();
+            let speed = (nar_size as f64 / seconds) as u64;
 
-                    if let Some(frac_deduplicated) = r.frac_deduplicated {
-                        if frac_deduplicated > 0.01f64 {
-                            s += &format!(", {:.1}% deduplicated", frac_deduplicated * 100.0);
-                        }
-                    }
+            let mut s = format!("{}/s", HumanBytes(speed));
 
-                    s
+            if let Some(frac_deduplicated) = r.frac_deduplicated {
+                if frac_deduplicated > 0.01f64 {
+                    s += &format!(", {:.1}% deduplicated", frac_deduplicated * 100.0);
                 }
+            }
+
+            s
+        }
+    }
+}
+
+/// Splits a NAR stream into `n` streams yielding the same chunks.
+///
+/// The returned future feeds the streams and must be polled alongside
+/// them. The source is read as fast as the slowest consumer, and
+/// consumers that are dropped (e.g., after a failed upload) are skipped.
+fn fan_out<S>(
+    mut stream: S,
+    n: usize,
+) -> (
+    impl Future<Output = ()>,
+    Vec<impl Stream<Item = Result<Bytes, std::io::Error>> + Send + Sync + 'static>,
+)
+where
+    S: Stream<Item = AtticResult<Vec<u8>>> + Unpin,
+{
+    let (mut senders, receivers): (Vec<_>, Vec<_>) =
+        (0..n).map(|_| mpsc::channel(FAN_OUT_BUFFER)).unzip();
+
+    let feeder = async move {
+        while !senders.is_empty() {
+            let item = match stream.next().await {
+                Some(item) => item,
+                None => break,
             };
 
-            mp.suspend(|| {
-                eprintln!(
-                    "✅ {} ({})",
-                    path.as_os_str().to_string_lossy(),
-                    info_string
-                );
-            });
-            bar.finish_and_clear();
+            match item {
+                Ok(data) => {
+                    let data = Bytes::from(data);
+                    let sent = join_all(senders.iter().map(|tx| tx.send(Ok(data.clone())))).await;
 
-            Ok(())
-        }
-        Err(e) => {
-            mp.suspend(|| {
-                eprintln!("❌ {}: {}", path.as_os_str().to_string_lossy(), e);
-            });
-            bar.finish_and_clear();
-            Err(e)
+                    let mut sent = sent.into_iter();
+                    senders.retain(|_| sent.next().unwrap().is_ok());
+                }
+                Err(e) => {
+                    let message = e.to_string();
+                    join_all(
+                        senders
+                            .iter()
+                            .map(|tx| tx.send(Err(std::io::Error::other(message.clone())))),
+                    )
+                    .await;
+                    break;
+                }
+            }
         }
-    }
+    };
+
+    let streams = receivers
+        .into_iter()
+        .map(
+            |mut receiver: mpsc::Receiver<Result<Bytes, std::io::Error>>| {
+                stream::poll_fn(move |cx| receiver.poll_recv(cx))
+            },
+        )
+        .collect();
+
+    (feeder, streams)
 }
 
 impl<S: Stream<Item = AtticResult<Vec<u8>>>> NarStreamProgress<S> {
@@ -638,9 +1396,134 @@ impl<S: Stream<Item = AtticResult<Vec<u8>>> + Unpin> Stream for NarStreamProgres
     }
 }
 
+/// Returns whether the NAR can be omitted from an upload.
+///
+/// Any failure is treated as the NAR being required.
+async fn skip_nar(api: &ApiClient, upload_info: &UploadPathNarInfo) -> bool {
+    match api.upload_path_preflight(upload_info).await {
+        Ok(Some(r)) => r.kind == UploadPathPreflightResultKind::Deduplicated,
+        Ok(None) => false,
+        Err(e) => {
+            tracing::debug!("Upload preflight failed: {}", e);
+            false
+        }
+    }
+}
+
+/// Signs the upload info with a keypair, returning the signature.
+///
+/// The signature is placed first, replacing any identical signature
+/// already attached to the path.
+fn sign_upload_info(upload_info: &mut UploadPathNarInfo, keypair: &NixKeypair) -> String {
+    let signature = keypair.sign(&upload_info.fingerprint());
+    upload_info.sigs.retain(|s| *s != signature);
+    upload_info.sigs.insert(0, signature.clone());
+    signature
+}
+
 // Just the average, no fancy sliding windows that cause wild fluctuations
 // <https://github.com/console-rs/indicatif/issues/394>
 fn average_speed(bytes: u64, duration: Duration) -> String {
     let speed = bytes as f64 * 1000_f64 / duration.as_millis() as f64;
     format!("{}/s", HumanBytes(speed as u64))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use attic::hash::Hash;
+    use attic::nix_store::StorePathHash;
+
+    #[test]
+    fn test_sign_upload_info() {
+        let keypair = NixKeypair::generate("attic-test").unwrap();
+        let existing = "cache.nixos.org-1:lo9EfNIL4eGRuNh7DTbAAffWPpI2SlYC/8uP7JnhgmfRIUNGhSbFe8qEaKN0mFS02TuhPpXFPNtRkFcCp0hGAQ==";
+
+        let mut upload_info = UploadPathNarInfo {
+            cache: "test".parse().unwrap(),
+            store_path_hash: StorePathHash::new("xcp9cav49dmsjbwdjlmkjxj10gkpx553".to_string())
+                .unwrap(),
+            store_path: "/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10".to_string(),
+            references: vec![
+                "563528481rvhc5kxwipjmg6rqrl95mdx-glibc-2.33-56".to_string(),
+                "xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10".to_string(),
+            ],
+            system: None,
+            deriver: None,
+            sigs: vec![existing.to_string()],
+            ca: None,
+            nar_hash: Hash::from_typed(
+                "sha256:91e129ac1959d062ad093d2b1f8b65afae0f712056fe3eac78ec530ff6a1bb9a",
+            )
+            .unwrap(),
+            nar_size: 206104,
+        };
+
+        let signature = sign_upload_info(&mut upload_info, &keypair);
+        keypair
+            .to_public_key()
+            .verify(&upload_info.fingerprint(), &signature)
+            .expect("Could not verify signature");
+        assert_eq!(
+            vec![signature.clone(), existing.to_string()],
+            upload_info.sigs
+        );
+
+        // Signing again doesn't duplicate the signature
+        sign_upload_info(&mut upload_info, &keypair);
+        assert_eq!(vec![signature, existing.to_string()], upload_info.sigs);
+    }
+
+    #[tokio::test]
+    async fn test_fan_out() {
+        let chunks: Vec<AtticResult<Vec<u8>>> = (0..20u8).map(|i| Ok(vec![i])).collect();
+        let expected: Vec<Bytes> = (0..20u8).map(|i| Bytes::from(vec![i])).collect();
+
+        let (feeder, mut streams) = fan_out(stream::iter(chunks), 3);
+
+        // A consumer going away doesn't hold up the others
+        drop(streams.pop());
+
+        let consumers = streams.into_iter().map(|s| s.try_collect::<Vec<Bytes>>());
+        let ((), results) = tokio::join!(feeder, join_all(consumers));
+
+        assert_eq!(2, results.len());
+        for r in results {
+            assert_eq!(expected, r.unwrap());
+        }
+    }
+
+    #[test]
+    fn test_json_push_result() {
+        let path = "/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10".to_string();
+
+        let deduplicated = Ok(UploadPathResult {
+            kind: UploadPathResultKind::Deduplicated,
+            file_size: None,
+            frac_deduplicated: Some(1.0),
+        });
+        assert_eq!(
+            r#"{"path":"/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10","result":"deduplicated","file_size":null,"frac_deduplicated":1.0,"error":null}"#,
+            JsonPushResult::new(path.clone(), None, &deduplicated)
+                .to_json()
+                .unwrap()
+        );
+
+        let failed = Err(anyhow!("Connection reset").context("Failed to upload"));
+        assert_eq!(
+            r#"{"path":"/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10","result":"failed","file_size":null,"frac_deduplicated":null,"error":"Failed to upload: Connection reset"}"#,
+            JsonPushResult::new(path.clone(), None, &failed)
+                .to_json()
+                .unwrap()
+        );
+
+        let cache: CacheName = "regional".parse().unwrap();
+        assert_eq!(
+            r#"{"path":"/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10","cache":"regional","result":"deduplicated","file_size":null,"frac_deduplicated":1.0,"error":null}"#,
+            JsonPushResult::new(path, Some(&cache), &deduplicated)
+                .to_json()
+                .unwrap()
+        );
+    }
+}
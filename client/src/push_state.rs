@@ -0,0 +1,280 @@
+//! Persisted push session state.
+//!
+//! A `PushSession` remembers which paths it has already pushed so it
+//! doesn't ask the server about them again. With a state file, this
+//! set survives restarts of long-running sessions like `attic watch-store`.
+//!
+//! The file records the server and cache it belongs to, as well as when
+//! each path was pushed. Entries older than the maximum age are dropped
+//! on load so the server is consulted again every once in a while, in
+//! case the path was garbage-collected or deleted.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use attic::cache::CacheName;
+use attic::nix_store::StorePathHash;
+
+/// The version of the state file format.
+const FORMAT_VERSION: u32 = 1;
+
+/// The minimum interval between writes to the state file.
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Known paths of a push session, backed by a file.
+#[derive(Debug)]
+pub struct PushState {
+    /// Path to the state file.
+    path: PathBuf,
+
+    /// Contents of the state file.
+    data: PushStateData,
+
+    /// Whether there are changes not yet written to the file.
+    dirty: bool,
+
+    /// When the file was last written.
+    last_saved: Instant,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PushStateData {
+    /// The version of the format.
+    version: u32,
+
+    /// The API endpoint of the server.
+    endpoint: String,
+
+    /// The cache the paths were pushed to.
+    cache: CacheName,
+
+    /// Unix timestamps when each store path hash was pushed.
+    paths: HashMap<String, u64>,
+}
+
+impl PushState {
+    /// Loads the state file for a cache.
+    ///
+    /// If the file doesn't exist, is corrupted, or belongs to another
+    /// cache, we start with an empty state. Entries older than `max_age`
+    /// are dropped.
+    pub fn load(path: PathBuf, endpoint: &str, cache: &CacheName, max_age: Duration) -> Self {
+        Self::load_at(path, endpoint, cache, max_age, now())
+    }
+
+    fn load_at(
+        path: PathBuf,
+        endpoint: &str,
+        cache: &CacheName,
+        max_age: Duration,
+        now: u64,
+    ) -> Self {
+        let empty = || PushStateData {
+            version: FORMAT_VERSION,
+            endpoint: endpoint.to_string(),
+            cache: cache.to_owned(),
+            paths: HashMap::new(),
+        };
+
+        let mut data = match read_data(&path) {
+            Ok(Some(data)) if data.version != FORMAT_VERSION => {
+                tracing::warn!(
+                    "Ignoring state file {} with unsupported version {}",
+                    path.display(),
+                    data.version
+                );
+                empty()
+            }
+            Ok(Some(data)) if data.endpoint != endpoint || data.cache != *cache => {
+                tracing::warn!(
+                    "Ignoring state file {} for \"{}\" on {}",
+                    path.display(),
+                    data.cache.as_str(),
+                    data.endpoint
+                );
+                empty()
+            }
+            Ok(Some(data)) => data,
+            Ok(None) => empty(),
+            Err(e) => {
+                tracing::warn!("Ignoring corrupted state file {}: {}", path.display(), e);
+                empty()
+            }
+        };
+
+        let num_paths = data.paths.len();
+        data.paths
+            .retain(|_, pushed_at| now.saturating_sub(*pushed_at) < max_age.as_secs());
+
+        Self {
+            path,
+            dirty: data.paths.len() != num_paths,
+            data,
+            last_saved: Instant::now(),
+        }
+    }
+
+    /// Returns the store path hashes known to be in the cache.
+    pub fn known_paths(&self) -> HashSet<StorePathHash> {
+        self.data
+            .paths
+            .keys()
+            .filter_map(|hash| StorePathHash::new(hash.clone()).ok())
+            .collect()
+    }
+
+    /// Records that a path has been pushed.
+    pub fn insert(&mut self, store_path_hash: &StorePathHash) {
+        self.insert_at(store_path_hash, now());
+    }
+
+    fn insert_at(&mut self, store_path_hash: &StorePathHash, now: u64) {
+        self.data
+            .paths
+            .insert(store_path_hash.as_str().to_string(), now);
+        self.dirty = true;
+    }
+
+    /// Writes the state file if there are changes and it hasn't been
+    /// written recently.
+    pub fn save_debounced(&mut self) -> Result<()> {
+        if self.last_saved.elapsed() < SAVE_INTERVAL {
+            return Ok(());
+        }
+
+        self.save()
+    }
+
+    /// Writes the state file if there are changes.
+    pub fn save(&mut self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        write_data(&self.path, &self.data)?;
+        self.dirty = false;
+        self.last_saved = Instant::now();
+
+        Ok(())
+    }
+}
+
+impl Drop for PushState {
+    fn drop(&mut self) {
+        if let Err(e) = self.save() {
+            tracing::warn!("Could not write state file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Reads the state file, returning `None` if it doesn't exist.
+fn read_data(path: &Path) -> Result<Option<PushStateData>> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(Some(serde_json::from_slice(&contents)?))
+}
+
+/// Writes the state file atomically.
+fn write_data(path: &Path, data: &PushStateData) -> Result<()> {
+    let temp_path = path.with_extension(format!("tmp.{}", std::process::id()));
+    fs::write(&temp_path, serde_json::to_vec(data)?)?;
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENDPOINT: &str = "https://attic.example.com/";
+    const MAX_AGE: Duration = Duration::from_secs(1000);
+
+    fn hash(c: char) -> StorePathHash {
+        StorePathHash::new(c.to_string().repeat(32)).unwrap()
+    }
+
+    #[test]
+    fn test_push_state_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let cache: CacheName = "test".parse().unwrap();
+
+        let mut state = PushState::load_at(path.clone(), ENDPOINT, &cache, MAX_AGE, 1000);
+        assert!(state.known_paths().is_empty());
+
+        // Nothing is written until the interval has passed
+        state.insert_at(&hash('a'), 1000);
+        state.insert_at(&hash('b'), 1500);
+        state.save_debounced().unwrap();
+        assert!(!path.exists());
+
+        state.save().unwrap();
+        assert!(path.exists());
+        drop(state);
+
+        let state = PushState::load_at(path.clone(), ENDPOINT, &cache, MAX_AGE, 1999);
+        assert_eq!(HashSet::from([hash('a'), hash('b')]), state.known_paths());
+
+        // Old entries are pruned
+        let state = PushState::load_at(path.clone(), ENDPOINT, &cache, MAX_AGE, 2000);
+        assert_eq!(HashSet::from([hash('b')]), state.known_paths());
+    }
+
+    #[test]
+    fn test_push_state_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let cache: CacheName = "test".parse().unwrap();
+        let other: CacheName = "other".parse().unwrap();
+
+        let mut state = PushState::load_at(path.clone(), ENDPOINT, &cache, MAX_AGE, 1000);
+        state.insert_at(&hash('a'), 1000);
+        drop(state);
+
+        let state = PushState::load_at(path.clone(), ENDPOINT, &other, MAX_AGE, 1000);
+        assert!(state.known_paths().is_empty());
+
+        let state = PushState::load_at(
+            path.clone(),
+            "https://other.example.com/",
+            &cache,
+            MAX_AGE,
+            1000,
+        );
+        assert!(state.known_paths().is_empty());
+    }
+
+    #[test]
+    fn test_push_state_corrupted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let cache: CacheName = "test".parse().unwrap();
+
+        fs::write(&path, b"{\"version\": 1, \"paths\":").unwrap();
+        let mut state = PushState::load_at(path.clone(), ENDPOINT, &cache, MAX_AGE, 1000);
+        assert!(state.known_paths().is_empty());
+
+        // The corrupted file is replaced
+        state.insert_at(&hash('a'), 1000);
+        drop(state);
+
+        let state = PushState::load_at(path, ENDPOINT, &cache, MAX_AGE, 1000);
+        assert_eq!(HashSet::from([hash('a')]), state.known_paths());
+    }
+}
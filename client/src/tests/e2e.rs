@@ -0,0 +1,340 @@
+//! End-to-end tests.
+//!
+//! These tests bring up an in-process server with
+//! `attic_server::test_support`, push the test NARs with the `Pusher`,
+//! then pull them back into a shadow store with vanilla `nix-store -r`.
+//!
+//! They require a working Nix installation, and the current user must
+//! be trusted by the nix-daemon to import the test NARs. Run them with:
+//!
+//! ```text
+//! cargo test -p attic-client --features e2e-tests
+//! ```
+
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+use indicatif::{MultiProgress, ProgressDrawTarget};
+
+use crate::api::ApiClient;
+use crate::config::{ServerConfig, ServerTokenConfig};
+use crate::push::{PushConfig, Pusher};
+use attic::api::v1::cache_config::{CreateCacheRequest, KeypairConfig};
+use attic::cache::CacheName;
+use attic::nix_store::NixStore;
+use attic::signing::{self, NixKeypair};
+use attic::testing::shadow_store::ShadowStore;
+
+/// Test NARs in dependency order.
+///
+/// See `attic/src/nix_store/tests/README.md` for details.
+const TEST_NARS: &[&str] = &[
+    "3k1wymic8p7h5pfcqfhh0jan8ny2a712-attic-test-with-deps-c-final",
+    "544qcchwgcgpz3xi1bbml28f8jj6009p-attic-test-with-deps-b",
+    "n7q4i7rlmbk4xz8qdsxpm6jbhrnxraq2-attic-test-with-deps-a",
+    "nm1w9sdm6j6icmhd2q3260hl1w9zj6li-attic-test-no-deps",
+];
+
+const WITH_DEPS_A: &str = "n7q4i7rlmbk4xz8qdsxpm6jbhrnxraq2-attic-test-with-deps-a";
+const NO_DEPS: &str = "nm1w9sdm6j6icmhd2q3260hl1w9zj6li-attic-test-no-deps";
+
+/// An in-process Attic server.
+struct TestServer {
+    /// The server, which is stopped when dropped.
+    _server: attic_server::test_support::TestServer,
+
+    /// The API endpoint.
+    endpoint: String,
+
+    /// A token with all permissions.
+    token: String,
+}
+
+impl TestServer {
+    async fn start() -> Self {
+        let server = attic_server::test_support::TestServer::start()
+            .await
+            .expect("Failed to start server");
+
+        Self {
+            endpoint: server.endpoint(),
+            token: server.admin_token.clone(),
+            _server: server,
+        }
+    }
+
+    fn api(&self) -> ApiClient {
+        ApiClient::from_server_config(ServerConfig {
+            endpoint: self.endpoint.clone(),
+            token: Some(ServerTokenConfig::Raw {
+                token: self.token.clone(),
+            }),
+            request_timeout: None,
+            connect_timeout: None,
+            pool_max_idle_per_host: None,
+            oauth: None,
+        })
+        .unwrap()
+    }
+
+    async fn create_cache(&self, name: &str, is_public: bool) -> CacheName {
+        let cache: CacheName = name.parse().unwrap();
+        let request = CreateCacheRequest {
+            keypair: KeypairConfig::Generate,
+            is_public,
+            store_dir: "/nix/store".to_string(),
+            priority: 41,
+            upstream_cache_key_names: Vec::new(),
+        };
+
+        self.api()
+            .create_cache(&cache, request)
+            .await
+            .expect("Failed to create cache");
+
+        cache
+    }
+
+    /// Pushes the closures of some store paths, returning the public key of the cache.
+    ///
+    /// If `signing_keypair` is set, the paths are signed locally before uploading.
+    async fn push(
+        &self,
+        cache: &CacheName,
+        roots: &[&str],
+        signing_keypair: Option<Arc<NixKeypair>>,
+    ) -> String {
+        let store = Arc::new(NixStore::connect().expect("Failed to connect to the Nix store"));
+        let api = self.api();
+        let cache_config = api
+            .get_cache_config(cache)
+            .await
+            .expect("Failed to get cache config");
+        let public_key = cache_config.public_key.clone().unwrap();
+
+        let pusher = Pusher::new(
+            store.clone(),
+            api,
+            cache.to_owned(),
+            cache_config,
+            MultiProgress::with_draw_target(ProgressDrawTarget::hidden()),
+            PushConfig {
+                num_workers: 2,
+                force_preamble: false,
+                signing_keypair,
+                sign_local_store: false,
+                quiet: false,
+                compress_upload: false,
+            },
+        );
+
+        let roots = roots
+            .iter()
+            .map(|p| store.follow_store_path(store_path(p)).unwrap())
+            .collect();
+        let plan = pusher.plan(roots, false, true).await.unwrap();
+
+        for (_, path_info) in plan.store_path_map {
+            pusher.queue(path_info).await.unwrap();
+        }
+
+        for (path, result) in pusher.wait().await {
+            if let Err(e) = result {
+                panic!("Failed to push {:?}: {}", path, e);
+            }
+        }
+
+        public_key
+    }
+
+    /// Fetches the narinfo of a store path from a cache.
+    async fn get_narinfo(&self, cache: &CacheName, base_name: &str) -> String {
+        let hash = &base_name[..32];
+        reqwest::Client::new()
+            .get(format!(
+                "{}{}/{}.narinfo",
+                self.endpoint,
+                cache.as_str(),
+                hash
+            ))
+            .bearer_auth(&self.token)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .expect("Failed to fetch narinfo")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    /// Realizes a store path in a fresh shadow store from a cache.
+    ///
+    /// Returns the shadow store if successful.
+    fn pull(
+        &self,
+        cache: &CacheName,
+        public_key: &str,
+        with_token: bool,
+        base_name: &str,
+    ) -> Option<ShadowStore> {
+        let shadow = ShadowStore::new();
+        let conf_dir = shadow.path().join("etc/nix");
+        let netrc_path = conf_dir.join("netrc");
+
+        fs::write(
+            conf_dir.join("nix.conf"),
+            format!(
+                "substituters = {}{}\ntrusted-public-keys = {}\nnetrc-file = {}\n",
+                self.endpoint,
+                cache.as_str(),
+                public_key,
+                netrc_path.display(),
+            ),
+        )
+        .unwrap();
+
+        let netrc = if with_token {
+            format!("machine 127.0.0.1 password {}\n", self.token)
+        } else {
+            String::new()
+        };
+        fs::write(&netrc_path, netrc).unwrap();
+
+        let status = Command::new(shadow.nix_store_cmd())
+            .arg("-r")
+            .arg(store_path(base_name))
+            .status()
+            .expect("Failed to run nix-store");
+
+        if status.success() {
+            Some(shadow)
+        } else {
+            None
+        }
+    }
+}
+
+fn store_path(base_name: &str) -> PathBuf {
+    Path::new("/nix/store").join(base_name)
+}
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("../attic/src/nix_store/tests/nar")
+        .join(name)
+}
+
+/// Imports the test NARs into the local Nix store.
+fn import_test_nars() {
+    for base_name in TEST_NARS {
+        let export = File::open(fixture(&format!("{}.export", base_name))).unwrap();
+        let status = Command::new("nix-store")
+            .arg("--import")
+            .stdin(export)
+            .stdout(Stdio::null())
+            .status()
+            .expect("Failed to run nix-store");
+
+        assert!(status.success(), "Failed to import {}", base_name);
+    }
+}
+
+/// Asserts that a path in the shadow store matches the fixture.
+fn assert_realized(shadow: &ShadowStore, base_name: &str) {
+    let realized = shadow.path().join("nix/store").join(base_name);
+    let actual = fs::read(&realized)
+        .unwrap_or_else(|e| panic!("Failed to read {}: {}", realized.display(), e));
+    let expected = fs::read(fixture(base_name)).unwrap();
+
+    assert_eq!(expected, actual, "{} has unexpected contents", base_name);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_push_pull_private() {
+    import_test_nars();
+
+    let server = TestServer::start().await;
+    let cache = server.create_cache("e2e-private", false).await;
+
+    let public_key = server.push(&cache, &[WITH_DEPS_A, NO_DEPS], None).await;
+
+    // Anonymous users cannot pull from a private cache
+    assert!(server.pull(&cache, &public_key, false, NO_DEPS).is_none());
+
+    let shadow = server
+        .pull(&cache, &public_key, true, WITH_DEPS_A)
+        .expect("Failed to realize path with dependencies");
+    for base_name in &TEST_NARS[..3] {
+        assert_realized(&shadow, base_name);
+    }
+
+    let shadow = server
+        .pull(&cache, &public_key, true, NO_DEPS)
+        .expect("Failed to realize path without dependencies");
+    assert_realized(&shadow, NO_DEPS);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_push_pull_public_anonymous() {
+    import_test_nars();
+
+    let server = TestServer::start().await;
+    let cache = server.create_cache("e2e-public", true).await;
+
+    let public_key = server.push(&cache, &[NO_DEPS], None).await;
+
+    let shadow = server
+        .pull(&cache, &public_key, false, NO_DEPS)
+        .expect("Failed to realize path anonymously");
+    assert_realized(&shadow, NO_DEPS);
+
+    // Signatures from the wrong key must be rejected
+    let wrong_key = "e2e-public:KmfKk/KwUscRJ8obZd4w6LgaqHZcn6uhfh7FYW02DzA=";
+    assert!(server.pull(&cache, wrong_key, false, NO_DEPS).is_none());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_push_pull_locally_signed() {
+    import_test_nars();
+
+    let server = TestServer::start().await;
+    let cache = server.create_cache("e2e-signed", true).await;
+    let keypair = Arc::new(NixKeypair::generate("e2e-org-1").unwrap());
+
+    server.push(&cache, &[NO_DEPS], Some(keypair.clone())).await;
+
+    // The served narinfo carries the local signature
+    let store = NixStore::connect().unwrap();
+    let path = store.follow_store_path(store_path(NO_DEPS)).unwrap();
+    let path_info = store.query_path_info(path).await.unwrap();
+    let references: Vec<String> = path_info
+        .references
+        .iter()
+        .map(|r| r.to_str().unwrap().to_string())
+        .collect();
+    let fingerprint = signing::fingerprint(
+        &store_path(NO_DEPS),
+        &path_info.nar_hash,
+        path_info.nar_size as usize,
+        &references,
+    );
+
+    let narinfo = server.get_narinfo(&cache, NO_DEPS).await;
+    let public_key = keypair.to_public_key();
+    assert!(
+        narinfo
+            .lines()
+            .filter_map(|line| line.strip_prefix("Sig: "))
+            .any(|sig| public_key.verify(&fingerprint, sig).is_ok()),
+        "Local signature is missing from the narinfo:\n{}",
+        narinfo
+    );
+
+    // Consumers trusting only the local key can substitute
+    let shadow = server
+        .pull(&cache, &keypair.export_public_key(), false, NO_DEPS)
+        .expect("Failed to realize path with the local key");
+    assert_realized(&shadow, NO_DEPS);
+}
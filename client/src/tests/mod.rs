@@ -0,0 +1,4 @@
+//! Tests that span multiple modules.
+
+#[cfg(feature = "e2e-tests")]
+mod e2e;
@@ -36,6 +36,7 @@ in
       attic.nix-versions = {
         versions = {
           default = pkgs.nix;
+          "2.18" = pkgs.nixVersions.nix_2_18;
           "2.20" = pkgs.nixVersions.nix_2_20;
           "2.24" = pkgs.nixVersions.nix_2_24;
         };
@@ -1,8 +1,15 @@
 //! HTTP middlewares for access control.
 
+use std::net::{IpAddr, SocketAddr};
+
 use attic::cache::CacheName;
 use attic_token::util::parse_authorization_header;
-use axum::{extract::Request, middleware::Next, response::Response};
+use axum::{
+    extract::{ConnectInfo, Request},
+    middleware::Next,
+    response::Response,
+};
+use ipnet::IpNet;
 use sea_orm::DatabaseConnection;
 use tokio::sync::OnceCell;
 
@@ -101,14 +108,18 @@ pub async fn apply_auth(req: Request, next: Next) -> Response {
         .and_then(parse_authorization_header)
         .and_then(|jwt| {
             let state = req.extensions().get::<State>().unwrap();
-            let signature_type = state.config.jwt.signing_config.clone().into();
+            let signature_types = state.config.jwt.verification_signature_types();
 
             let res_token = Token::from_jwt(
                 &jwt,
-                &signature_type,
+                &signature_types,
                 &state.config.jwt.token_bound_issuer,
                 &state.config.jwt.token_bound_audiences,
-            );
+            )
+            .and_then(|mut token| {
+                token.expand_claim_group(&state.config.jwt.claim_groups)?;
+                Ok(token)
+            });
 
             if let Err(e) = &res_token {
                 tracing::debug!("Ignoring bad JWT token: {}", e);
@@ -117,6 +128,22 @@ pub async fn apply_auth(req: Request, next: Next) -> Response {
             res_token.ok()
         });
 
+    let token = token.filter(|token| {
+        let state = req.extensions().get::<State>().unwrap();
+
+        match client_ip(&req, &state.config.trusted_proxies) {
+            Some(ip) if token.is_ip_allowed(ip) => true,
+            Some(ip) => {
+                tracing::debug!("Ignoring JWT token used from disallowed IP {}", ip);
+                false
+            }
+            None => {
+                // No connection info available - Fail closed
+                !token.is_ip_restricted()
+            }
+        }
+    });
+
     if let Some(token) = token {
         let req_state = req.extensions().get::<RequestState>().unwrap();
         req_state.auth.token.set(token).unwrap();
@@ -125,3 +152,175 @@ pub async fn apply_auth(req: Request, next: Next) -> Response {
 
     next.run(req).await
 }
+
+/// Returns the IP address of the client making a request.
+///
+/// Returns None if no connection info is available.
+pub(crate) fn client_ip(req: &Request, trusted_proxies: &[IpNet]) -> Option<IpAddr> {
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ci| ci.0.ip())?;
+
+    let forwarded_for = req
+        .headers()
+        .get("X-Forwarded-For")
+        .and_then(|v| v.to_str().ok());
+
+    Some(resolve_client_ip(peer, forwarded_for, trusted_proxies))
+}
+
+/// Returns the IP address of the client.
+///
+/// `X-Forwarded-For` is only consulted if the immediate peer is a
+/// trusted proxy. In that case, we walk the list from the right and
+/// return the first address that isn't a trusted proxy itself, since
+/// anything to its left can be forged by the client.
+///
+/// IPv4-mapped IPv6 addresses are treated as IPv4, since that's how
+/// IPv4 peers show up on a dual-stack listener.
+fn resolve_client_ip(
+    peer: IpAddr,
+    forwarded_for: Option<&str>,
+    trusted_proxies: &[IpNet],
+) -> IpAddr {
+    let peer = unmap_ipv4(peer);
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+
+    if !is_trusted(&peer) {
+        return peer;
+    }
+
+    let forwarded_for = match forwarded_for {
+        Some(v) => v,
+        None => return peer,
+    };
+
+    let mut client = peer;
+    for hop in forwarded_for.rsplit(',') {
+        match hop.trim().parse::<IpAddr>() {
+            Ok(ip) => {
+                let ip = unmap_ipv4(ip);
+                client = ip;
+                if !is_trusted(&ip) {
+                    break;
+                }
+            }
+            Err(_) => {
+                // Garbage in the header - Stop at the last good hop
+                break;
+            }
+        }
+    }
+
+    client
+}
+
+/// Converts an IPv4-mapped IPv6 address (::ffff:a.b.c.d) to IPv4.
+fn unmap_ipv4(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6
+            .to_ipv4_mapped()
+            .map(IpAddr::V4)
+            .unwrap_or(IpAddr::V6(v6)),
+        v4 => v4,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_resolve_client_ip() {
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()];
+
+        // Direct connections
+        assert_eq!(
+            ip("192.0.2.1"),
+            resolve_client_ip(ip("192.0.2.1"), None, &trusted)
+        );
+        assert_eq!(
+            ip("2001:db8::1"),
+            resolve_client_ip(ip("2001:db8::1"), None, &trusted)
+        );
+
+        // Through a trusted proxy
+        assert_eq!(
+            ip("192.0.2.1"),
+            resolve_client_ip(ip("10.0.0.1"), Some("192.0.2.1"), &trusted)
+        );
+        assert_eq!(
+            ip("2001:db8::1"),
+            resolve_client_ip(ip("fd00::1"), Some("2001:db8::1, fd00::2"), &trusted)
+        );
+
+        // Trusted proxy without the header
+        assert_eq!(
+            ip("10.0.0.1"),
+            resolve_client_ip(ip("10.0.0.1"), None, &trusted)
+        );
+    }
+
+    #[test]
+    fn test_resolve_client_ip_spoofing() {
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+
+        // Untrusted peer trying to claim an allowed address
+        assert_eq!(
+            ip("192.0.2.1"),
+            resolve_client_ip(ip("192.0.2.1"), Some("10.1.2.3"), &trusted)
+        );
+
+        // Nothing is trusted by default
+        assert_eq!(
+            ip("10.0.0.1"),
+            resolve_client_ip(ip("10.0.0.1"), Some("192.0.2.1"), &[])
+        );
+
+        // Client prepending a forged hop before going through the proxy
+        assert_eq!(
+            ip("192.0.2.1"),
+            resolve_client_ip(ip("10.0.0.1"), Some("10.9.9.9, 192.0.2.1"), &trusted)
+        );
+
+        // Garbage in the header
+        assert_eq!(
+            ip("192.0.2.1"),
+            resolve_client_ip(ip("10.0.0.1"), Some("nonsense, 192.0.2.1"), &trusted)
+        );
+        assert_eq!(
+            ip("10.0.0.1"),
+            resolve_client_ip(ip("10.0.0.1"), Some("nonsense"), &trusted)
+        );
+    }
+
+    #[test]
+    fn test_resolve_client_ip_mapped() {
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+
+        // IPv4 proxy connecting to a dual-stack listener
+        assert_eq!(
+            ip("192.0.2.1"),
+            resolve_client_ip(ip("::ffff:10.0.0.1"), Some("192.0.2.1"), &trusted)
+        );
+        assert_eq!(
+            ip("192.0.2.1"),
+            resolve_client_ip(
+                ip("::ffff:10.0.0.1"),
+                Some("::ffff:192.0.2.1, ::ffff:10.0.0.2"),
+                &trusted
+            )
+        );
+
+        // Untrusted mapped peers are still returned as IPv4
+        assert_eq!(
+            ip("192.0.2.1"),
+            resolve_client_ip(ip("::ffff:192.0.2.1"), Some("10.1.2.3"), &trusted)
+        );
+    }
+}
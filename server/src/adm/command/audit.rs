@@ -0,0 +1,97 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
+use sea_orm::ActiveEnum;
+
+use crate::Opts;
+use attic::cache::CacheName;
+use attic_server::audit::{self, AuditLogFilter};
+use attic_server::config::Config;
+
+/// Query the audit log.
+///
+/// Events are only stored in the database if `audit.store-in-database`
+/// is enabled.
+#[derive(Debug, Parser)]
+pub struct Audit {
+    #[clap(subcommand)]
+    command: AuditCommand,
+}
+
+#[derive(Debug, Subcommand)]
+enum AuditCommand {
+    Tail(Tail),
+}
+
+/// Show the most recent audit events.
+///
+/// Each event is printed on a line with its timestamp, action, cache,
+/// subject, store path and details:
+///
+/// $ atticadm audit tail --cache main --since "1 day"
+#[derive(Debug, Parser)]
+struct Tail {
+    /// Only show events on this cache.
+    #[clap(long)]
+    cache: Option<CacheName>,
+
+    /// Only show events at or after this time.
+    ///
+    /// This can be an RFC 3339 timestamp or a duration before now
+    /// (e.g., "2 hours").
+    #[clap(long, value_name = "TIME", value_parser = parse_time)]
+    since: Option<DateTime<Utc>>,
+
+    /// Only show events before this time.
+    ///
+    /// This accepts the same formats as `--since`.
+    #[clap(long, value_name = "TIME", value_parser = parse_time)]
+    until: Option<DateTime<Utc>>,
+
+    /// Maximum number of events to show.
+    #[clap(short = 'n', long, default_value = "50")]
+    limit: u64,
+}
+
+pub async fn run(config: Config, opts: Opts) -> Result<()> {
+    let sub = opts.command.as_audit().unwrap();
+
+    match &sub.command {
+        AuditCommand::Tail(tail) => run_tail(config, tail).await,
+    }
+}
+
+async fn run_tail(config: Config, tail: &Tail) -> Result<()> {
+    let filter = AuditLogFilter {
+        cache: tail.cache.clone(),
+        since: tail.since,
+        until: tail.until,
+        limit: tail.limit,
+    };
+
+    for event in audit::tail_audit_log(config, filter).await? {
+        println!(
+            "{} {} {} {} {} {}",
+            event.created_at.to_rfc3339(),
+            event.action.to_value(),
+            event.cache,
+            event.subject.as_deref().unwrap_or("-"),
+            event.store_path.as_deref().unwrap_or("-"),
+            event.details.as_deref().unwrap_or(""),
+        );
+    }
+
+    Ok(())
+}
+
+/// Parses an RFC 3339 timestamp or a duration before now.
+fn parse_time(s: &str) -> Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.with_timezone(&Utc));
+    }
+
+    let duration = humantime::parse_duration(s)
+        .map_err(|_| anyhow!("Expected an RFC 3339 timestamp or a duration"))?;
+
+    Ok(Utc::now() - chrono::Duration::from_std(duration)?)
+}
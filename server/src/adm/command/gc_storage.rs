@@ -0,0 +1,68 @@
+use anyhow::Result;
+use clap::Parser;
+use humantime::Duration;
+
+use crate::Opts;
+use attic_server::config::Config;
+use attic_server::gc::{self, StorageGcOptions};
+
+/// Delete files in the storage backend that no chunk refers to.
+///
+/// Files can be left behind if the server crashes in the middle of
+/// an upload. Only files named like chunks are considered, and recent
+/// files are kept since they may belong to uploads in progress.
+///
+/// This lists the entire storage backend, so it can take a while.
+/// The WebDAV backend is not supported.
+///
+/// To see what would be deleted:
+///
+/// $ atticadm gc-storage --dry-run
+#[derive(Debug, Parser)]
+pub struct GcStorage {
+    /// Only delete files last modified at least this long ago.
+    ///
+    /// This must be longer than the longest upload.
+    #[clap(long, default_value = "24h")]
+    min_age: Duration,
+
+    /// Only report orphan files without deleting them.
+    #[clap(long)]
+    dry_run: bool,
+}
+
+pub async fn run(config: Config, opts: Opts) -> Result<()> {
+    let sub = opts.command.as_gc_storage().unwrap();
+
+    let options = StorageGcOptions {
+        min_age: sub.min_age.into(),
+        dry_run: sub.dry_run,
+    };
+
+    let report = gc::run_storage_gc(config, options).await?;
+
+    for id in &report.orphaned {
+        println!("orphaned {}", id);
+    }
+
+    eprintln!("Listed {} files", report.listed);
+    eprintln!("  Referenced: {}", report.referenced);
+    eprintln!("  Not chunks (ignored): {}", report.ignored);
+    eprintln!("  Unreferenced but too recent: {}", report.recent);
+    eprintln!(
+        "  Orphaned: {} ({} bytes)",
+        report.orphaned.len(),
+        report.orphaned_bytes
+    );
+
+    if sub.dry_run {
+        eprintln!("Dry run: No files were deleted");
+    } else {
+        eprintln!("Deleted {} files", report.deleted);
+        if report.failed != 0 {
+            eprintln!("Failed to delete {} files", report.failed);
+        }
+    }
+
+    Ok(())
+}
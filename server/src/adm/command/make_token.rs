@@ -1,11 +1,20 @@
+use std::collections::HashMap;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
 use anyhow::{anyhow, Result};
 use chrono::{Duration as ChronoDuration, Utc};
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use humantime::Duration;
+use ipnet::IpNet;
+use serde::Deserialize;
+use toml::Spanned;
 
 use crate::Opts;
 use attic::cache::CacheNamePattern;
-use attic_server::access::Token;
+use attic_server::access::{CachePermission, Token};
 use attic_server::config::Config;
 
 /// Generate a new token.
@@ -15,23 +24,57 @@ use attic_server::config::Config;
 /// expiring in 2 years:
 ///
 /// $ atticadm make-token --sub "alice" --validity "2y" --pull "dev-*" --push "dev-*" --pull "prod"
+///
+/// Tokens can also be described in a TOML template, with variables
+/// substituted from the command line:
+///
+/// $ atticadm make-token --template team.toml --var team=frontend
+///
+/// ```toml
+/// sub = "team-{team}"
+/// validity = "1y"
+///
+/// [[permissions]]
+/// caches = ["{team}-*"]
+/// grant = ["pull", "push"]
+/// ```
 #[derive(Debug, Parser)]
 pub struct MakeToken {
     /// The subject of the JWT token.
-    #[clap(long)]
-    sub: String,
+    ///
+    /// This overrides the subject in the template.
+    #[clap(long, required_unless_present = "template")]
+    sub: Option<String>,
 
     /// The validity period of the JWT token.
     ///
     /// You can use expressions like "2 years", "3 months"
-    /// and "1y".
-    #[clap(long)]
-    validity: Duration,
+    /// and "1y". This overrides the validity in the template.
+    #[clap(long, required_unless_present = "template")]
+    validity: Option<Duration>,
 
     /// Dump the claims without signing and encoding it.
-    #[clap(long)]
+    #[clap(long, visible_alias = "dry-run")]
     dump_claims: bool,
 
+    /// A TOML template describing the token.
+    ///
+    /// Permissions granted with the other flags are added to the
+    /// ones in the template.
+    #[clap(long, value_name = "FILE")]
+    template: Option<PathBuf>,
+
+    /// A variable to substitute into the template.
+    ///
+    /// `{name}` in the template is replaced with the value.
+    /// Specify this flag multiple times to set multiple variables.
+    #[clap(long = "var", value_name = "NAME=VALUE", value_parser = parse_var)]
+    vars: Vec<(String, String)>,
+
+    /// The output format.
+    #[clap(long, value_enum, default_value = "text")]
+    output: OutputFormat,
+
     /// A cache that the token may pull from.
     ///
     /// The value may contain wildcards. Specify this flag multiple
@@ -80,6 +123,89 @@ pub struct MakeToken {
     /// times to allow multiple patterns.
     #[clap(long = "destroy-cache", value_name = "PATTERN")]
     destroy_cache_patterns: Vec<CacheNamePattern>,
+
+    /// A cache that the token may not access at all.
+    ///
+    /// This overrides less specific wildcard grants matching the cache
+    /// as well as any other grants to the same pattern. Specify this
+    /// flag multiple times to deny multiple patterns.
+    #[clap(long = "deny", value_name = "PATTERN")]
+    deny_patterns: Vec<CacheNamePattern>,
+
+    /// A claim group defined in the server configuration.
+    ///
+    /// The token references the group by name instead of listing
+    /// its permissions, which keeps the token small. Permissions
+    /// granted with the other flags take precedence.
+    #[clap(long, value_name = "NAME")]
+    claim_group: Option<String>,
+
+    /// An IP range that the token may be used from, in CIDR notation.
+    ///
+    /// Specify this flag multiple times to allow multiple ranges.
+    /// If unspecified, the token can be used from anywhere.
+    #[clap(long = "allowed-ip-range", value_name = "CIDR")]
+    allowed_ip_ranges: Vec<IpNet>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// The encoded token only.
+    Text,
+
+    /// A JSON object with the encoded token and its claims.
+    Json,
+}
+
+/// A token template.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "kebab-case")]
+struct TokenTemplate {
+    /// The subject of the token.
+    sub: Option<Spanned<String>>,
+
+    /// The validity period of the token.
+    validity: Option<Spanned<String>>,
+
+    /// A claim group defined in the server configuration.
+    claim_group: Option<Spanned<String>>,
+
+    /// IP ranges that the token may be used from.
+    #[serde(default)]
+    allowed_ip_ranges: Vec<Spanned<String>>,
+
+    /// Permissions to grant.
+    #[serde(default)]
+    permissions: Vec<TemplatePermissions>,
+}
+
+/// A set of permissions on some caches.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TemplatePermissions {
+    /// Cache name patterns.
+    caches: Vec<Spanned<String>>,
+
+    /// Names of the permissions to grant.
+    grant: Vec<Spanned<String>>,
+}
+
+/// A token template with variables substituted and values validated.
+#[derive(Debug, Default)]
+struct ResolvedTemplate {
+    sub: Option<String>,
+    validity: Option<Duration>,
+    claim_group: Option<String>,
+    allowed_ip_ranges: Vec<IpNet>,
+    permissions: Vec<(CacheNamePattern, Vec<String>)>,
+}
+
+/// Context for resolving a template.
+struct TemplateResolver<'a> {
+    path: &'a Path,
+    source: &'a str,
+    vars: &'a HashMap<String, String>,
 }
 
 macro_rules! grant_permissions {
@@ -91,14 +217,130 @@ macro_rules! grant_permissions {
     };
 }
 
+impl TemplateResolver<'_> {
+    /// Resolves a template.
+    fn resolve(&self) -> Result<ResolvedTemplate> {
+        let template: TokenTemplate =
+            toml::from_str(self.source).map_err(|e| anyhow!("{}: {}", self.path.display(), e))?;
+
+        let mut resolved = ResolvedTemplate {
+            sub: template
+                .sub
+                .as_ref()
+                .map(|sub| self.substitute(sub))
+                .transpose()?,
+            claim_group: template
+                .claim_group
+                .as_ref()
+                .map(|group| self.substitute(group))
+                .transpose()?,
+            ..Default::default()
+        };
+
+        if let Some(validity) = &template.validity {
+            let value = self.substitute(validity)?;
+            let validity = Duration::from_str(&value)
+                .map_err(|e| self.error(validity.span(), format!("Invalid validity: {}", e)))?;
+            resolved.validity = Some(validity);
+        }
+
+        for range in &template.allowed_ip_ranges {
+            let value = self.substitute(range)?;
+            let ip_range = IpNet::from_str(&value)
+                .map_err(|e| self.error(range.span(), format!("Invalid IP range: {}", e)))?;
+            resolved.allowed_ip_ranges.push(ip_range);
+        }
+
+        for permissions in &template.permissions {
+            // Validate the names early to report the offending line
+            let mut scratch = CachePermission::default();
+            let mut grant = Vec::new();
+            for name in &permissions.grant {
+                grant_permission(&mut scratch, name.get_ref())
+                    .map_err(|e| self.error(name.span(), e.to_string()))?;
+                grant.push(name.get_ref().to_owned());
+            }
+
+            for cache in &permissions.caches {
+                let value = self.substitute(cache)?;
+                let pattern = CacheNamePattern::new(value)
+                    .map_err(|e| self.error(cache.span(), format!("Invalid pattern: {}", e)))?;
+                resolved.permissions.push((pattern, grant.clone()));
+            }
+        }
+
+        Ok(resolved)
+    }
+
+    /// Substitutes `{name}` in a string with the value of the variable.
+    fn substitute(&self, s: &Spanned<String>) -> Result<String> {
+        let mut result = String::new();
+        let mut rest = s.get_ref().as_str();
+
+        while let Some(start) = rest.find('{') {
+            result.push_str(&rest[..start]);
+
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| self.error(s.span(), "Unterminated variable".to_string()))?;
+            let name = &rest[start + 1..start + end];
+            let value = self
+                .vars
+                .get(name)
+                .ok_or_else(|| self.error(s.span(), format!("Variable \"{}\" is not set", name)))?;
+
+            result.push_str(value);
+            rest = &rest[start + end + 1..];
+        }
+
+        result.push_str(rest);
+        Ok(result)
+    }
+
+    /// Returns an error pointing at the line of a span.
+    fn error(&self, span: Range<usize>, message: String) -> anyhow::Error {
+        let line = self.source[..span.start].matches('\n').count() + 1;
+        anyhow!("{}:{}: {}", self.path.display(), line, message)
+    }
+}
+
 pub async fn run(config: Config, opts: Opts) -> Result<()> {
     let sub = opts.command.as_make_token().unwrap();
-    let duration = ChronoDuration::from_std(sub.validity.into())?;
+
+    let template = if let Some(path) = &sub.template {
+        let source = fs::read_to_string(path)?;
+        let vars = sub.vars.iter().cloned().collect();
+        TemplateResolver {
+            path,
+            source: &source,
+            vars: &vars,
+        }
+        .resolve()?
+    } else {
+        ResolvedTemplate::default()
+    };
+
+    let token_sub =
+        sub.sub.clone().or(template.sub).ok_or_else(|| {
+            anyhow!("The subject must be specified with --sub or in the template")
+        })?;
+    let validity = sub.validity.or(template.validity).ok_or_else(|| {
+        anyhow!("The validity must be specified with --validity or in the template")
+    })?;
+
+    let duration = ChronoDuration::from_std(validity.into())?;
     let exp = Utc::now()
         .checked_add_signed(duration)
         .ok_or_else(|| anyhow!("Expiry timestamp overflowed"))?;
 
-    let mut token = Token::new(sub.sub.to_owned(), &exp);
+    let mut token = Token::new(token_sub, &exp);
+
+    for (pattern, grant) in template.permissions {
+        let perm = token.get_or_insert_permission_mut(pattern);
+        for name in &grant {
+            grant_permission(perm, name)?;
+        }
+    }
 
     grant_permissions!(token, &sub.pull_patterns, pull);
     grant_permissions!(token, &sub.push_patterns, push);
@@ -112,18 +354,187 @@ pub async fn run(config: Config, opts: Opts) -> Result<()> {
     );
     grant_permissions!(token, &sub.destroy_cache_patterns, destroy_cache);
 
+    for pattern in &sub.deny_patterns {
+        *token.get_or_insert_permission_mut(pattern.to_owned()) = CachePermission::default();
+    }
+
+    for range in template
+        .allowed_ip_ranges
+        .iter()
+        .chain(&sub.allowed_ip_ranges)
+    {
+        token.add_allowed_ip_range(*range);
+    }
+
+    if let Some(group) = sub.claim_group.as_ref().or(template.claim_group.as_ref()) {
+        if !config.jwt.claim_groups.contains_key(group) {
+            return Err(anyhow!("Unknown claim group \"{}\"", group));
+        }
+
+        token.set_claim_group(Some(group.to_owned()));
+    }
+
     if sub.dump_claims {
-        println!("{}", serde_json::to_string(token.opaque_claims())?);
+        match sub.output {
+            OutputFormat::Text => println!("{}", serde_json::to_string(token.opaque_claims())?),
+            OutputFormat::Json => println!(
+                "{}",
+                serde_json::json!({
+                    "claims": token.opaque_claims(),
+                })
+            ),
+        }
     } else {
         let signature_type = config.jwt.signing_config.into();
+        let encode = |token: &Token| {
+            token.encode(
+                &signature_type,
+                &config.jwt.token_bound_issuer,
+                &config.jwt.token_bound_audiences,
+            )
+        };
+
+        let encoded_token = encode(&token)?;
+
+        if token.claim_group().is_some() {
+            let mut inlined = token.clone();
+            inlined.expand_claim_group(&config.jwt.claim_groups)?;
+            inlined.set_claim_group(None);
 
-        let encoded_token = token.encode(
-            &signature_type,
-            &config.jwt.token_bound_issuer,
-            &config.jwt.token_bound_audiences,
-        )?;
-        println!("{}", encoded_token);
+            eprintln!(
+                "Token size: {} bytes ({} bytes with the claim group inlined)",
+                encoded_token.len(),
+                encode(&inlined)?.len(),
+            );
+        }
+
+        match sub.output {
+            OutputFormat::Text => println!("{}", encoded_token),
+            OutputFormat::Json => println!(
+                "{}",
+                serde_json::json!({
+                    "token": encoded_token,
+                    "claims": token.opaque_claims(),
+                })
+            ),
+        }
     }
 
     Ok(())
 }
+
+/// Grants a permission by its name.
+///
+/// The names are the same as the corresponding flags.
+fn grant_permission(perm: &mut CachePermission, name: &str) -> Result<()> {
+    match name {
+        "pull" => perm.pull = true,
+        "push" => perm.push = true,
+        "delete" => perm.delete = true,
+        "create-cache" => perm.create_cache = true,
+        "configure-cache" => perm.configure_cache = true,
+        "configure-cache-retention" => perm.configure_cache_retention = true,
+        "destroy-cache" => perm.destroy_cache = true,
+        _ => return Err(anyhow!("Unknown permission \"{}\"", name)),
+    }
+
+    Ok(())
+}
+
+/// Parses a `NAME=VALUE` variable.
+fn parse_var(s: &str) -> Result<(String, String)> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Expected NAME=VALUE"))?;
+
+    Ok((name.to_owned(), value.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use attic::cache::CacheName;
+
+    fn resolve(source: &str, vars: &[(&str, &str)]) -> Result<ResolvedTemplate> {
+        let vars = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+
+        TemplateResolver {
+            path: Path::new("team.toml"),
+            source,
+            vars: &vars,
+        }
+        .resolve()
+    }
+
+    #[test]
+    fn test_template() {
+        let template = resolve(
+            r#"
+sub = "team-{team}"
+validity = "1y"
+allowed-ip-ranges = ["10.0.0.0/8"]
+
+[[permissions]]
+caches = ["{team}-*", "shared"]
+grant = ["pull"]
+
+[[permissions]]
+caches = ["{team}-*"]
+grant = ["push", "configure-cache"]
+"#,
+            &[("team", "frontend")],
+        )
+        .unwrap();
+
+        assert_eq!(Some("team-frontend"), template.sub.as_deref());
+        assert!(template.validity.is_some());
+        assert_eq!(1, template.allowed_ip_ranges.len());
+
+        let cache = |name: &str| CacheName::new(name.to_string()).unwrap();
+        let grants: Vec<_> = template
+            .permissions
+            .iter()
+            .map(|(pattern, grant)| {
+                (
+                    pattern.matches(&cache("frontend-ci")),
+                    pattern.matches(&cache("shared")),
+                    grant.join(","),
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (true, false, "pull".to_string()),
+                (false, true, "pull".to_string()),
+                (true, false, "push,configure-cache".to_string()),
+            ],
+            grants
+        );
+    }
+
+    #[test]
+    fn test_template_errors() {
+        let source = r#"
+[[permissions]]
+caches = ["{team}-*"]
+grant = ["pull", "fly"]
+"#;
+
+        let e = resolve(source, &[("team", "frontend")]).unwrap_err();
+        assert_eq!("team.toml:4: Unknown permission \"fly\"", e.to_string());
+
+        let source = source.replace(", \"fly\"", "");
+
+        let e = resolve(&source, &[]).unwrap_err();
+        assert!(e.to_string().starts_with("team.toml:3: Variable \"team\""));
+
+        let e = resolve(&source, &[("team", "Not Valid")]).unwrap_err();
+        assert!(e.to_string().starts_with("team.toml:3: Invalid pattern"));
+
+        assert!(resolve("unknown = 1", &[]).is_err());
+    }
+}
@@ -1 +1,6 @@
+pub mod audit;
+pub mod gc_storage;
 pub mod make_token;
+pub mod stats;
+pub mod test_chunking;
+pub mod verify_chunks;
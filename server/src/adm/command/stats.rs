@@ -0,0 +1,54 @@
+use anyhow::Result;
+use clap::Parser;
+
+use crate::Opts;
+use attic_server::compaction;
+use attic_server::config::Config;
+
+/// Show statistics on the chunk store.
+///
+/// Chunks are summarized by compression, along with the NARs waiting
+/// to be recompressed by compaction with the current `compaction`
+/// settings.
+#[derive(Debug, Parser)]
+pub struct Stats {}
+
+pub async fn run(config: Config, _opts: Opts) -> Result<()> {
+    let enabled = config.compaction.enable;
+    let target = config.compaction.r#type;
+    let stats = compaction::get_compaction_stats(config).await?;
+
+    println!(
+        "{:<12} {:>12} {:>16} {:>16} {:>8}",
+        "COMPRESSION", "CHUNKS", "CHUNK BYTES", "FILE BYTES", "RATIO"
+    );
+
+    for chunks in &stats.chunks {
+        let ratio = if chunks.file_bytes == 0 {
+            "-".to_string()
+        } else {
+            format!(
+                "{:.3}",
+                chunks.chunk_bytes as f64 / chunks.file_bytes as f64
+            )
+        };
+
+        println!(
+            "{:<12} {:>12} {:>16} {:>16} {:>8}",
+            chunks.compression, chunks.count, chunks.chunk_bytes, chunks.file_bytes, ratio
+        );
+    }
+
+    println!();
+    println!(
+        "Compaction: {} (target: {})",
+        if enabled { "enabled" } else { "disabled" },
+        format!("{:?}", target).to_lowercase()
+    );
+    println!(
+        "Pending: {} NARs, {} bytes",
+        stats.pending_nars, stats.pending_bytes
+    );
+
+    Ok(())
+}
@@ -0,0 +1,165 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use clap::{Parser, ValueEnum};
+use futures::stream::TryStreamExt;
+use tokio::fs::File;
+
+use crate::Opts;
+use attic::chunking::{chunk_stream, ChunkingAlgorithm};
+use attic::hash::Hash;
+use attic_server::config::{ChunkingAlgorithmType, Config};
+
+/// Test chunking parameters on a set of files.
+///
+/// Each file is split into chunks and the chunks are deduplicated
+/// across all files, simulating what would happen if the files were
+/// uploaded as NARs. Unless overridden, the parameters are taken from
+/// the `chunking` section of the config.
+///
+/// The size and hash of each chunk are printed to stdout, and the
+/// summary is printed to stderr:
+///
+/// $ atticadm test-chunking --avg 131072 /nix/store/*.nar > chunks.txt
+#[derive(Debug, Parser)]
+pub struct TestChunking {
+    /// The preferred minimum size of a chunk, in bytes.
+    #[clap(long, value_name = "BYTES")]
+    min: Option<usize>,
+
+    /// The preferred average size of a chunk, in bytes.
+    #[clap(long, value_name = "BYTES")]
+    avg: Option<usize>,
+
+    /// The preferred maximum size of a chunk, in bytes.
+    #[clap(long, value_name = "BYTES")]
+    max: Option<usize>,
+
+    /// The FastCDC variant to use.
+    #[clap(long, value_enum)]
+    algorithm: Option<Algorithm>,
+
+    /// The normalization level of the v2020 variant, from 0 to 3.
+    #[clap(long, value_name = "LEVEL")]
+    normalization_level: Option<u32>,
+
+    /// The files to chunk.
+    #[clap(required = true)]
+    paths: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Algorithm {
+    Ronomon,
+    V2020,
+}
+
+/// Chunking statistics.
+#[derive(Debug, Default)]
+struct Stats {
+    /// Hashes of the chunks seen so far.
+    seen: HashSet<String>,
+
+    total_chunks: usize,
+    total_bytes: usize,
+    unique_chunks: usize,
+    unique_bytes: usize,
+}
+
+pub async fn run(config: Config, opts: Opts) -> Result<()> {
+    let sub = opts.command.as_test_chunking().unwrap();
+    let chunking = &config.chunking;
+
+    let min_size = sub.min.unwrap_or(chunking.min_size);
+    let avg_size = sub.avg.unwrap_or(chunking.avg_size);
+    let max_size = sub.max.unwrap_or(chunking.max_size);
+    let normalization_level = sub
+        .normalization_level
+        .unwrap_or(chunking.normalization_level);
+
+    let algorithm = sub.algorithm.unwrap_or(match chunking.algorithm {
+        ChunkingAlgorithmType::Ronomon => Algorithm::Ronomon,
+        ChunkingAlgorithmType::V2020 => Algorithm::V2020,
+    });
+    let algorithm = match algorithm {
+        Algorithm::Ronomon => ChunkingAlgorithm::Ronomon,
+        Algorithm::V2020 => ChunkingAlgorithm::V2020 {
+            normalization_level,
+        },
+    };
+
+    eprintln!(
+        "Chunking with {:?}, min={}, avg={}, max={}",
+        algorithm, min_size, avg_size, max_size
+    );
+
+    let mut stats = Stats::default();
+
+    for path in &sub.paths {
+        let file = File::open(path)
+            .await
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+
+        let mut chunks = chunk_stream(file, algorithm, min_size, avg_size, max_size);
+        let mut offset = 0;
+
+        while let Some(chunk) = chunks
+            .try_next()
+            .await
+            .with_context(|| format!("Failed to chunk {}", path.display()))?
+        {
+            let hash = Hash::sha256_from_bytes(&chunk).to_typed_base16();
+            let unique = stats.add(hash.clone(), chunk.len());
+
+            println!(
+                "{} {} {} {}{}",
+                path.display(),
+                offset,
+                chunk.len(),
+                hash,
+                if unique { "" } else { " dup" }
+            );
+
+            offset += chunk.len();
+        }
+    }
+
+    eprintln!(
+        "Total: {} chunks, {} bytes",
+        stats.total_chunks, stats.total_bytes
+    );
+    eprintln!(
+        "Unique: {} chunks, {} bytes",
+        stats.unique_chunks, stats.unique_bytes
+    );
+
+    if let Some(avg) = stats.total_bytes.checked_div(stats.total_chunks) {
+        eprintln!("Average chunk size: {} bytes", avg);
+    }
+
+    if stats.unique_bytes != 0 {
+        eprintln!(
+            "Dedup ratio: {:.3}",
+            stats.total_bytes as f64 / stats.unique_bytes as f64
+        );
+    }
+
+    Ok(())
+}
+
+impl Stats {
+    /// Records a chunk, returning whether it hasn't been seen before.
+    fn add(&mut self, hash: String, size: usize) -> bool {
+        self.total_chunks += 1;
+        self.total_bytes += size;
+
+        if self.seen.insert(hash) {
+            self.unique_chunks += 1;
+            self.unique_bytes += size;
+            true
+        } else {
+            false
+        }
+    }
+}
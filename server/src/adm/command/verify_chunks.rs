@@ -0,0 +1,167 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use tokio::fs;
+
+use crate::Opts;
+use attic::cache::CacheName;
+use attic_server::config::Config;
+use attic_server::verify::{self, ChunkVerificationOptions};
+
+/// Verify the integrity of chunks in the storage backend.
+///
+/// Each chunk is downloaded and checked against the file hash and
+/// size in the database. Missing or corrupted chunks are detached
+/// from their NARs so they can be repaired by pushing the affected
+/// paths again, and are deleted by the next garbage collection.
+///
+/// Chunks that fail to download for reasons other than not existing
+/// are reported but left alone. The run is aborted if many chunks in a
+/// row fail, since the storage backend is likely unavailable.
+///
+/// When verifying the whole chunk store, the reference counts of
+/// chunks are also checked and corrected.
+///
+/// To resume an interrupted run over a large chunk store:
+///
+/// $ atticadm verify-chunks --checkpoint verify.state
+///
+/// To spot-check a random 5% of chunks:
+///
+/// $ atticadm verify-chunks --sample 5%
+#[derive(Debug, Parser)]
+pub struct VerifyChunks {
+    /// Only verify chunks referenced by this cache.
+    #[clap(long)]
+    cache: Option<CacheName>,
+
+    /// Only verify chunks with IDs greater than this.
+    #[clap(long, value_name = "ID")]
+    start_after: Option<i64>,
+
+    /// File to persist progress to.
+    ///
+    /// If the file exists, verification resumes from the chunk ID
+    /// recorded in it. It's updated after each batch of chunks.
+    #[clap(long, value_name = "PATH")]
+    checkpoint: Option<PathBuf>,
+
+    /// Maximum number of chunks to download concurrently.
+    #[clap(short = 'j', long, default_value = "10")]
+    jobs: usize,
+
+    /// Only report bad chunks without detaching them.
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Only verify a random sample of chunks, like `5%`.
+    #[clap(long, value_name = "PERCENT", value_parser = parse_sample)]
+    sample: Option<f64>,
+}
+
+pub async fn run(config: Config, opts: Opts) -> Result<()> {
+    let sub = opts.command.as_verify_chunks().unwrap();
+
+    let checkpoint = match &sub.checkpoint {
+        Some(path) if path.exists() => {
+            let contents = fs::read_to_string(path).await?;
+            let id = contents
+                .trim()
+                .parse::<i64>()
+                .with_context(|| format!("Invalid checkpoint file {}", path.display()))?;
+            Some(id)
+        }
+        _ => None,
+    };
+
+    let options = ChunkVerificationOptions {
+        cache: sub.cache.clone(),
+        start_after: sub.start_after.or(checkpoint).unwrap_or(0),
+        concurrency: sub.jobs,
+        dry_run: sub.dry_run,
+        sample: sub.sample,
+    };
+
+    if options.start_after != 0 {
+        eprintln!("Resuming after chunk {}", options.start_after);
+    }
+
+    let report = verify::run_chunk_verification(config, options, |report| {
+        if let Some(path) = &sub.checkpoint {
+            std::fs::write(path, format!("{}\n", report.high_water_mark))?;
+        }
+        Ok(())
+    })
+    .await?;
+
+    eprintln!("Checked {} chunks", report.checked);
+    if sub.sample.is_some() {
+        eprintln!("  Not sampled: {}", report.skipped);
+    }
+    eprintln!("  Valid: {}", report.valid);
+    eprintln!("  Unconfirmed (skipped): {}", report.unconfirmed);
+    eprintln!("  Mismatched: {}", report.mismatched.len());
+    eprintln!("  Missing: {}", report.missing.len());
+    eprintln!("  Failed to download: {}", report.failed.len());
+
+    if sub.cache.is_none() {
+        eprintln!("  Wrong reference counts: {}", report.miscounted.len());
+    }
+
+    for id in &report.mismatched {
+        println!("mismatched {}", id);
+    }
+    for id in &report.missing {
+        println!("missing {}", id);
+    }
+    for id in &report.failed {
+        println!("failed {}", id);
+    }
+    for id in &report.miscounted {
+        println!("miscounted {}", id);
+    }
+
+    if sub.dry_run {
+        eprintln!("Dry run: No chunks were detached or corrected");
+    } else {
+        eprintln!("Detached {} chunk references", report.chunkrefs_detached);
+        eprintln!("Corrected {} reference counts", report.miscounted.len());
+    }
+
+    eprintln!("High-water mark: {}", report.high_water_mark);
+
+    Ok(())
+}
+
+/// Parses a percentage into a fraction.
+fn parse_sample(s: &str) -> Result<f64> {
+    let percent = s
+        .strip_suffix('%')
+        .unwrap_or(s)
+        .parse::<f64>()
+        .map_err(|_| anyhow!("Expected a percentage like 5%"))?;
+
+    if !(percent > 0.0 && percent <= 100.0) {
+        return Err(anyhow!("The sample must be between 0% and 100%"));
+    }
+
+    Ok(percent / 100.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sample() {
+        assert_eq!(0.05, parse_sample("5%").unwrap());
+        assert_eq!(0.5, parse_sample("50").unwrap());
+        assert_eq!(1.0, parse_sample("100%").unwrap());
+        assert_eq!(0.001, parse_sample("0.1%").unwrap());
+
+        for s in ["0%", "101%", "-5%", "NaN%", "five%", "%", ""] {
+            assert!(parse_sample(s).is_err(), "{}", s);
+        }
+    }
+}
@@ -7,7 +7,12 @@ use clap::{Parser, Subcommand};
 use enum_as_inner::EnumAsInner;
 
 use attic_server::config;
+use command::audit::{self, Audit};
+use command::gc_storage::{self, GcStorage};
 use command::make_token::{self, MakeToken};
+use command::stats::{self, Stats};
+use command::test_chunking::{self, TestChunking};
+use command::verify_chunks::{self, VerifyChunks};
 
 /// Attic server administration utilities.
 #[derive(Debug, Parser)]
@@ -25,16 +30,26 @@ pub struct Opts {
 
 #[derive(Debug, Subcommand, EnumAsInner)]
 pub enum Command {
-    MakeToken(MakeToken),
+    MakeToken(Box<MakeToken>),
+    VerifyChunks(VerifyChunks),
+    TestChunking(TestChunking),
+    Audit(Audit),
+    Stats(Stats),
+    GcStorage(GcStorage),
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let opts = Opts::parse();
-    let config = config::load_config(opts.config.as_deref(), false).await?;
+    let config = config::load_config(opts.config.as_deref(), None).await?;
 
     match opts.command {
         Command::MakeToken(_) => make_token::run(config, opts).await?,
+        Command::VerifyChunks(_) => verify_chunks::run(config, opts).await?,
+        Command::TestChunking(_) => test_chunking::run(config, opts).await?,
+        Command::Audit(_) => audit::run(config, opts).await?,
+        Command::Stats(_) => stats::run(config, opts).await?,
+        Command::GcStorage(_) => gc_storage::run(config, opts).await?,
     }
 
     Ok(())
@@ -5,35 +5,49 @@
 //! The implementation is based on the specifications at <https://github.com/fzakaria/nix-http-binary-cache-api-spec>.
 
 use std::collections::VecDeque;
-use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
 use std::path::PathBuf;
 use std::sync::Arc;
 
+use async_compression::tokio::bufread::{
+    BrotliDecoder, BrotliEncoder, Lz4Decoder, Lz4Encoder, XzDecoder, XzEncoder, ZstdDecoder,
+    ZstdEncoder,
+};
+use async_stream::try_stream;
 use axum::{
     body::Body,
     extract::{Extension, Path},
-    http::StatusCode,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
     response::{IntoResponse, Redirect, Response},
     routing::get,
     Router,
 };
-use futures::stream::BoxStream;
+use bytes::Bytes;
+use futures::stream::{self, BoxStream};
 use futures::TryStreamExt as _;
 use serde::Serialize;
-use tokio_util::io::ReaderStream;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, BufReader};
+use tokio::sync::mpsc;
+use tokio_util::io::{ReaderStream, StreamReader};
 use tracing::instrument;
 
+use crate::config::{CompressionConfig, CompressionType};
 use crate::database::entity::chunk::ChunkModel;
 use crate::database::AtticDatabase;
-use crate::error::{ErrorKind, ServerResult};
-use crate::narinfo::NarInfo;
+use crate::error::{ErrorKind, ServerError, ServerResult};
+use crate::narinfo::{Compression, NarInfo};
 use crate::nix_manifest;
 use crate::storage::{Download, StorageBackend};
 use crate::{RequestState, State};
 use attic::cache::CacheName;
+use attic::hash::Hash;
 use attic::mime;
 use attic::nix_store::StorePathHash;
-use attic::stream::merge_chunks;
+use attic::stream::{merge_chunks, StreamHasher};
+
+/// Number of pieces of a NAR buffered for verification.
+const VERIFY_BUFFER: usize = 16;
 
 /// Nix cache information.
 ///
@@ -46,14 +60,14 @@ use attic::stream::merge_chunks;
 /// ```
 #[derive(Debug, Clone, Serialize)]
 struct NixCacheInfo {
-    /// Whether this binary cache supports bulk queries.
-    #[serde(rename = "WantMassQuery")]
-    want_mass_query: bool,
-
     /// The Nix store path this binary cache uses.
     #[serde(rename = "StoreDir")]
     store_dir: PathBuf,
 
+    /// Whether this binary cache supports bulk queries.
+    #[serde(rename = "WantMassQuery")]
+    want_mass_query: bool,
+
     /// The priority of the binary cache.
     ///
     /// A lower number denotes a higher priority.
@@ -95,8 +109,8 @@ async fn get_nix_cache_info(
     req_state.set_public_cache(cache.is_public);
 
     let info = NixCacheInfo {
-        want_mass_query: true,
         store_dir: cache.store_dir.into(),
+        want_mass_query: true,
         priority: cache.priority,
     };
 
@@ -135,10 +149,20 @@ async fn get_store_path_info(
         cache_name
     );
 
-    let (object, cache, nar, _) = state
+    if let Some((narinfo, is_public)) = state.narinfo_cache.get(&cache_name, &store_path_hash) {
+        let permission = req_state
+            .auth
+            .get_permission_for_cache(&cache_name, is_public);
+        permission.require_pull()?;
+
+        req_state.set_public_cache(is_public);
+        return Ok(narinfo);
+    }
+
+    let (object, cache, nar, chunks) = state
         .database()
         .await?
-        .find_object_and_chunks_by_store_path_hash(&cache_name, &store_path_hash, false)
+        .find_object_and_chunks_by_store_path_hash(&cache_name, &store_path_hash, true)
         .await?;
 
     let permission = req_state
@@ -149,15 +173,94 @@ async fn get_store_path_info(
     req_state.set_public_cache(cache.is_public);
 
     let mut narinfo = object.to_nar_info(&nar)?;
+    (narinfo.file_hash, narinfo.file_size) = get_file_info(&chunks)?;
+
+    if let Some(target) = get_serve_recompression(&state.config.compression, narinfo.compression) {
+        narinfo.compression = target.into();
+
+        // The stored file no longer matches what we serve
+        narinfo.file_hash = None;
+        narinfo.file_size = None;
+    }
 
-    if narinfo.signature().is_none() {
-        let keypair = cache.keypair()?;
+    if let Some(nar_url_base) = &cache.nar_url_base {
+        // The base always has a trailing slash
+        narinfo.url = format!("{}{}", nar_url_base, narinfo.url);
+    }
+
+    if !cache.preserve_upstream_signatures {
+        // Signatures the uploader made with other keys are kept
+        let upstream = &cache.upstream_cache_key_names.0;
+        narinfo.signatures.retain(|sig| {
+            !matches!(sig.split_once(':'), Some((name, _)) if upstream.iter().any(|u| u == name))
+        });
+    }
+
+    let keypair = cache.keypair()?;
+    if !narinfo.is_signed_by(&keypair) {
         narinfo.sign(&keypair);
     }
 
+    // Clients that haven't switched to the new key yet
+    for previous in cache.previous_keypairs(state.config.key_rotation_grace_period)? {
+        if !narinfo.is_signed_by(&previous) {
+            narinfo.sign(&previous);
+        }
+    }
+
+    state
+        .narinfo_cache
+        .insert(&cache_name, &store_path_hash, &narinfo, cache.is_public);
+
     Ok(narinfo)
 }
 
+/// Returns the hash and size of the file served for a NAR.
+///
+/// A chunked NAR has no hash of the whole file, but its size is the
+/// sum of its compressed chunks. Both are unknown if any chunk is
+/// missing or hasn't had its file hash confirmed.
+fn get_file_info(chunks: &[Option<ChunkModel>]) -> ServerResult<(Option<Hash>, Option<usize>)> {
+    let file_size: Option<i64> = chunks
+        .iter()
+        .map(|chunk| chunk.as_ref().and_then(|chunk| chunk.file_size))
+        .sum();
+
+    let file_hash = match chunks {
+        [Some(chunk)] => chunk
+            .file_hash
+            .as_deref()
+            .map(Hash::from_typed)
+            .transpose()
+            .map_err(ServerError::database_error)?,
+        _ => None,
+    };
+
+    Ok((file_hash, file_size.map(|size| size as usize)))
+}
+
+/// A requested byte range of a file.
+///
+/// Both ends are inclusive, as in the `Range` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ByteRange {
+    start: u64,
+    end: u64,
+}
+
+/// The outcome of parsing a `Range` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RangeRequest {
+    /// The whole file should be served.
+    Full,
+
+    /// Only part of the file should be served.
+    Partial(ByteRange),
+
+    /// The range lies outside the file.
+    Unsatisfiable,
+}
+
 /// Gets a NAR.
 ///
 /// - GET `:cache/nar/{storePathHash}.nar`
@@ -165,11 +268,15 @@ async fn get_store_path_info(
 /// Here we use the store path hash not the NAR hash or file hash
 /// for better logging. In reality, the files are deduplicated by
 /// content-addressing.
+///
+/// Single byte ranges are supported if the NAR is served as stored
+/// and the sizes of all chunks are known.
 #[instrument(skip_all, fields(cache_name, path))]
 async fn get_nar(
     Extension(state): Extension<State>,
     Extension(req_state): Extension<RequestState>,
     Path((cache_name, path)): Path<(CacheName, String)>,
+    headers: HeaderMap,
 ) -> ServerResult<Response> {
     let components: Vec<&str> = path.splitn(2, '.').collect();
 
@@ -191,7 +298,7 @@ async fn get_nar(
 
     let database = state.database().await?;
 
-    let (object, cache, _nar, chunks) = database
+    let (object, cache, nar, chunks) = database
         .find_object_and_chunks_by_store_path_hash(&cache_name, &store_path_hash, true)
         .await?;
 
@@ -209,58 +316,390 @@ async fn get_nar(
 
     database.bump_object_last_accessed(object.id).await?;
 
+    let stored_compression: Compression = nar.compression.parse()?;
+    let recompress = get_serve_recompression(&state.config.compression, stored_compression)
+        .map(|target| (stored_compression, target));
+
+    let chunks: Vec<ChunkModel> = chunks.into_iter().map(Option::unwrap).collect();
+
+    // Offsets into the served file are only meaningful if we serve the
+    // chunks as-is and know how large each of them is
+    let file_sizes: Option<Vec<u64>> = if recompress.is_none() {
+        chunks
+            .iter()
+            .map(|chunk| chunk.file_size.map(|size| size as u64))
+            .collect()
+    } else {
+        None
+    };
+
+    let range = match (&file_sizes, headers.get(header::RANGE)) {
+        (Some(file_sizes), Some(value)) => match value.to_str() {
+            Ok(value) => parse_range(value, file_sizes.iter().sum()),
+            Err(_) => RangeRequest::Full,
+        },
+        _ => RangeRequest::Full,
+    };
+
+    if range == RangeRequest::Unsatisfiable {
+        let total: u64 = file_sizes.unwrap().iter().sum();
+        return Ok(Response::builder()
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, format!("bytes */{}", total))
+            .body(Body::empty())
+            .unwrap());
+    }
+
+    // Only full downloads can be checked against the NAR hash
+    let verify = if state.config.verify_nar_downloads && range == RangeRequest::Full {
+        Some((Hash::from_typed(&nar.nar_hash)?, nar.nar_size as usize))
+    } else {
+        None
+    };
+    let maybe_verify = |stream: BoxStream<'static, IoResult<Bytes>>| match &verify {
+        Some((nar_hash, nar_size)) => {
+            verify_nar_stream(stream, stored_compression, nar_hash.clone(), *nar_size)
+        }
+        None => stream,
+    };
+
+    let storage = state.storage().await?;
+
     if chunks.len() == 1 {
         // single chunk
-        let chunk = chunks[0].as_ref().unwrap();
-        let remote_file = &chunk.remote_file.0;
-        let storage = state.storage().await?;
-        match storage.download_file_db(remote_file, false).await? {
-            Download::Url(url) => Ok(Redirect::temporary(&url).into_response()),
-            Download::AsyncRead(stream) => {
-                let stream = ReaderStream::new(stream).map_err(|e| {
-                    tracing::error!(%e, "Stream error");
-                    e
-                });
-                let body = Body::from_stream(stream);
-
-                Ok(body.into_response())
+        let remote_file = &chunks[0].remote_file.0;
+        let prefer_stream = recompress.is_some() || verify.is_some();
+        match storage.download_file_db(remote_file, prefer_stream).await? {
+            // The storage backend handles any range itself
+            Download::Url(url) if !prefer_stream => {
+                return Ok(Redirect::temporary(&url).into_response());
+            }
+            Download::Url(_) => {
+                return Err(ErrorKind::StorageError(anyhow::anyhow!(
+                    "Storage backend did not return a stream for recompression or verification"
+                ))
+                .into());
+            }
+            Download::AsyncRead(stream) if range == RangeRequest::Full => {
+                let stream: BoxStream<_> = Box::pin(ReaderStream::new(stream));
+                let response = make_nar_response(maybe_verify(stream), recompress);
+                return Ok(accept_ranges(response, file_sizes.is_some()));
+            }
+            Download::AsyncRead(_) => {
+                // Reopened at the right offset below
             }
         }
-    } else {
-        // reassemble NAR
-        fn io_error<E: std::error::Error + Send + Sync + 'static>(e: E) -> IoError {
-            IoError::new(IoErrorKind::Other, e)
-        }
+    }
 
-        let streamer = |chunk: ChunkModel, storage: Arc<Box<dyn StorageBackend + 'static>>| async move {
-            match storage
-                .download_file_db(&chunk.remote_file.0, true)
-                .await
-                .map_err(io_error)?
-            {
-                Download::Url(_) => Err(IoError::new(
-                    IoErrorKind::Other,
-                    "URLs not supported for NAR reassembly",
-                )),
-                Download::AsyncRead(stream) => {
-                    let stream: BoxStream<_> = Box::pin(ReaderStream::new(stream));
-                    Ok(stream)
-                }
-            }
-        };
+    // reassemble NAR
+    fn io_error<E: std::error::Error + Send + Sync + 'static>(e: E) -> IoError {
+        IoError::new(IoErrorKind::Other, e)
+    }
+
+    let streamer = |(chunk, offset): (ChunkModel, u64),
+                    storage: Arc<Box<dyn StorageBackend + 'static>>| async move {
+        let stream = storage
+            .download_file_db_from(&chunk.remote_file.0, offset)
+            .await
+            .map_err(io_error)?;
+
+        let stream: BoxStream<_> = Box::pin(ReaderStream::new(stream));
+        Ok(stream)
+    };
+
+    let storage = storage.clone();
 
-        let chunks: VecDeque<_> = chunks.into_iter().map(Option::unwrap).collect();
-        let storage = state.storage().await?.clone();
+    let RangeRequest::Partial(range) = range else {
+        let chunks: VecDeque<_> = chunks.into_iter().map(|chunk| (chunk, 0)).collect();
 
         // TODO: Make num_prefetch configurable
         // The ideal size depends on the average chunk size
-        let merged = merge_chunks(chunks, streamer, storage, 2).map_err(|e| {
-            tracing::error!(%e, "Stream error");
-            e
+        let merged = merge_chunks(chunks, streamer, storage, 2);
+
+        let response = make_nar_response(maybe_verify(Box::pin(merged)), recompress);
+        return Ok(accept_ranges(response, file_sizes.is_some()));
+    };
+
+    let file_sizes = file_sizes.unwrap();
+    let total: u64 = file_sizes.iter().sum();
+    let (first, last, offset) = locate_range(&file_sizes, range);
+
+    let chunks: VecDeque<_> = chunks
+        .into_iter()
+        .enumerate()
+        .skip(first)
+        .take(last - first + 1)
+        .map(|(i, chunk)| (chunk, if i == first { offset } else { 0 }))
+        .collect();
+
+    let length = range.end - range.start + 1;
+    let merged = merge_chunks(chunks, streamer, storage, 2);
+    let truncated = StreamReader::new(merged).take(length);
+
+    let mut response = make_nar_response(Box::pin(ReaderStream::new(truncated)), None);
+    *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_RANGE,
+        HeaderValue::from_str(&format!("bytes {}-{}/{}", range.start, range.end, total)).unwrap(),
+    );
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+
+    Ok(accept_ranges(response, true))
+}
+
+/// Parses a `Range` header for a file of a certain size.
+///
+/// Anything other than a single valid byte range is ignored, in which
+/// case the whole file is served as allowed by RFC 9110.
+fn parse_range(value: &str, size: u64) -> RangeRequest {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else {
+        return RangeRequest::Full;
+    };
+
+    if spec.contains(',') {
+        return RangeRequest::Full;
+    }
+
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return RangeRequest::Full;
+    };
+
+    if start.is_empty() {
+        // suffix range: the last N bytes
+        let Ok(suffix) = end.parse::<u64>() else {
+            return RangeRequest::Full;
+        };
+
+        if suffix == 0 || size == 0 {
+            return RangeRequest::Unsatisfiable;
+        }
+
+        return RangeRequest::Partial(ByteRange {
+            start: size.saturating_sub(suffix),
+            end: size - 1,
         });
-        let body = Body::from_stream(merged);
+    }
+
+    let Ok(start) = start.parse::<u64>() else {
+        return RangeRequest::Full;
+    };
+
+    let end = if end.is_empty() {
+        u64::MAX
+    } else {
+        match end.parse::<u64>() {
+            Ok(end) if end >= start => end,
+            _ => return RangeRequest::Full,
+        }
+    };
+
+    if start >= size {
+        return RangeRequest::Unsatisfiable;
+    }
+
+    RangeRequest::Partial(ByteRange {
+        start,
+        end: end.min(size - 1),
+    })
+}
+
+/// Finds the chunks covering a byte range.
+///
+/// Returns the indices of the first and last chunks, as well as the
+/// offset of the start of the range in the first chunk. The range
+/// must lie within the file.
+fn locate_range(file_sizes: &[u64], range: ByteRange) -> (usize, usize, u64) {
+    let mut first = None;
+    let mut chunk_start = 0;
+
+    for (i, size) in file_sizes.iter().enumerate() {
+        let chunk_end = chunk_start + size;
+
+        if first.is_none() && range.start < chunk_end {
+            first = Some((i, range.start - chunk_start));
+        }
+
+        if range.end < chunk_end {
+            let (first, offset) = first.unwrap();
+            return (first, i, offset);
+        }
+
+        chunk_start = chunk_end;
+    }
+
+    unreachable!("Range lies outside the file");
+}
+
+/// Advertises support for range requests on a response.
+fn accept_ranges(mut response: Response, seekable: bool) -> Response {
+    if seekable {
+        response
+            .headers_mut()
+            .insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    }
 
-        Ok(body.into_response())
+    response
+}
+
+/// Returns the compression type to recompress a NAR to when serving it.
+///
+/// Returns `None` if the NAR should be served as-is.
+fn get_serve_recompression(
+    config: &CompressionConfig,
+    stored: Compression,
+) -> Option<CompressionType> {
+    let target = config.serve_recompress?;
+
+    // We can't decompress bzip2, but we never produce it either
+    if stored == Compression::from(target) || stored == Compression::Bzip2 {
+        None
+    } else {
+        Some(target)
+    }
+}
+
+/// Returns a response streaming a NAR, recompressing it if requested.
+fn make_nar_response(
+    stream: BoxStream<'static, IoResult<Bytes>>,
+    recompress: Option<(Compression, CompressionType)>,
+) -> Response {
+    let stream = match recompress {
+        Some((from, to)) => recompress_stream(stream, from, to),
+        None => stream,
+    };
+
+    let stream = stream.map_err(|e| {
+        tracing::error!(%e, "Stream error");
+        e
+    });
+
+    Body::from_stream(stream).into_response()
+}
+
+/// Verifies a stored NAR against its hash as it's streamed.
+///
+/// The stored stream is passed through as-is while a copy is
+/// decompressed and hashed in a separate task. The last piece is
+/// held back until the NAR is verified, so the client never receives
+/// a corrupted NAR in full.
+fn verify_nar_stream(
+    stream: BoxStream<'static, IoResult<Bytes>>,
+    compression: Compression,
+    nar_hash: Hash,
+    nar_size: usize,
+) -> BoxStream<'static, IoResult<Bytes>> {
+    let (sender, receiver) = mpsc::channel::<IoResult<Bytes>>(VERIFY_BUFFER);
+
+    let verifier = tokio::spawn(async move {
+        let copy = Box::pin(stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|item| (item, receiver))
+        }));
+        let decompressed = decompress_stream(StreamReader::new(copy), compression);
+
+        let (mut hashed, nar_compute) = StreamHasher::new(decompressed, Sha256::new());
+        tokio::io::copy(&mut hashed, &mut tokio::io::sink()).await?;
+
+        let (hash, size) = nar_compute.get().unwrap();
+        let hash = Hash::Sha256(hash.as_slice().try_into().unwrap());
+
+        IoResult::Ok((hash, *size))
+    });
+
+    Box::pin(try_stream! {
+        let mut stream = stream;
+        let mut held = None;
+
+        while let Some(bytes) = stream.try_next().await? {
+            // If the verifier has failed, we find out at the end
+            let _ = sender.send(Ok(bytes.clone())).await;
+
+            if let Some(previous) = held.replace(bytes) {
+                yield previous;
+            }
+        }
+
+        drop(sender);
+
+        let (hash, size) = verifier
+            .await
+            .map_err(IoError::other)?
+            .map_err(|e| IoError::new(IoErrorKind::InvalidData, e))?;
+
+        if hash != nar_hash || size != nar_size {
+            tracing::error!(
+                "NAR {} doesn't match the database: Got {} with {} bytes",
+                nar_hash.to_typed_base16(),
+                hash.to_typed_base16(),
+                size
+            );
+
+            Err(IoError::new(IoErrorKind::InvalidData, "NAR hash mismatch"))?;
+        }
+
+        if let Some(last) = held {
+            yield last;
+        }
+    })
+}
+
+/// Decompresses a NAR stream and compresses it with another type.
+///
+/// The stream may consist of multiple independently-compressed
+/// chunks concatenated together.
+fn recompress_stream(
+    stream: BoxStream<'static, IoResult<Bytes>>,
+    from: Compression,
+    to: CompressionType,
+) -> BoxStream<'static, IoResult<Bytes>> {
+    let decompressed = BufReader::new(decompress_stream(StreamReader::new(stream), from));
+    let level = to.default_level();
+
+    let compressed: Box<dyn AsyncRead + Unpin + Send> = match to {
+        CompressionType::None => Box::new(decompressed),
+        CompressionType::Brotli => Box::new(BrotliEncoder::with_quality(decompressed, level)),
+        CompressionType::Zstd => Box::new(ZstdEncoder::with_quality(decompressed, level)),
+        CompressionType::Xz => Box::new(XzEncoder::with_quality(decompressed, level)),
+        CompressionType::Lz4 => Box::new(Lz4Encoder::with_quality(decompressed, level)),
+    };
+
+    Box::pin(ReaderStream::new(compressed))
+}
+
+/// Decompresses a stream of one or more concatenated compressed members.
+///
+/// Panics on bzip2, which we can't decompress.
+pub(crate) fn decompress_stream<R>(
+    reader: R,
+    from: Compression,
+) -> Box<dyn AsyncRead + Unpin + Send>
+where
+    R: AsyncBufRead + Unpin + Send + 'static,
+{
+    match from {
+        Compression::None => Box::new(reader),
+        Compression::Xz => {
+            let mut decoder = XzDecoder::new(reader);
+            decoder.multiple_members(true);
+            Box::new(decoder)
+        }
+        Compression::Brotli => {
+            let mut decoder = BrotliDecoder::new(reader);
+            decoder.multiple_members(true);
+            Box::new(decoder)
+        }
+        Compression::Zstd => {
+            let mut decoder = ZstdDecoder::new(reader);
+            decoder.multiple_members(true);
+            Box::new(decoder)
+        }
+        Compression::Lz4 => {
+            let mut decoder = Lz4Decoder::new(reader);
+            decoder.multiple_members(true);
+            Box::new(decoder)
+        }
+        Compression::Bzip2 => unreachable!("bzip2 is never recompressed"),
     }
 }
 
@@ -270,3 +709,669 @@ pub fn get_router() -> Router {
         .route("/:cache/:path", get(get_store_path_info))
         .route("/:cache/nar/:path", get(get_nar))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::AtomicBool;
+
+    use chrono::Utc;
+    use sea_orm::entity::prelude::*;
+    use sea_orm::ActiveValue::Set;
+    use sea_orm::EntityTrait;
+
+    use crate::access::http::AuthState;
+    use crate::config::Config;
+    use crate::database::entity::cache::{self, Entity as Cache};
+    use crate::database::entity::chunk::{self, ChunkState, Entity as Chunk};
+    use crate::database::entity::chunkref::{self, Entity as ChunkRef};
+    use crate::database::entity::nar::{self, Entity as Nar, NarState};
+    use crate::database::entity::object::{self, Entity as Object};
+    use crate::database::entity::Json as DbJson;
+    use crate::database::migration::{Migrator, MigratorTrait};
+    use crate::storage::{LocalRemoteFile, RemoteFile};
+    use crate::{RequestStateInner, StateInner};
+    use attic::signing::NixKeypair;
+
+    const STORE_PATH_HASH: &str = "xcp9cav49dmsjbwdjlmkjxj10gkpx553";
+
+    const FILE_HASH: &str =
+        "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    async fn make_state(
+        nar_url_base: Option<&str>,
+        compression: &str,
+        serve_recompress: Option<&str>,
+    ) -> State {
+        make_state_with(nar_url_base, compression, |config| {
+            config.compression.serve_recompress =
+                serve_recompress.map(|t| toml::Value::from(t).try_into().unwrap());
+        })
+        .await
+    }
+
+    async fn make_state_with(
+        nar_url_base: Option<&str>,
+        compression: &str,
+        configure: impl FnOnce(&mut Config),
+    ) -> State {
+        let mut config: Config = toml::from_str(
+            r#"
+[database]
+url = "sqlite::memory:"
+
+[storage]
+type = "local"
+path = "/nonexistent"
+
+[chunking]
+nar-size-threshold = 0
+min-size = 16384
+avg-size = 65536
+max-size = 262144
+
+[jwt.signing]
+token-hs256-secret-base64 = "dmVyeSBzZWN1cmUgc2VjcmV0"
+"#,
+        )
+        .unwrap();
+        configure(&mut config);
+
+        let state = StateInner::new(config).await;
+        let db = state.database().await.unwrap();
+        Migrator::up(db, None).await.unwrap();
+
+        let cache_id = Cache::insert(cache::ActiveModel {
+            name: Set("demo".to_string()),
+            keypair: Set(NixKeypair::generate("demo").unwrap().export_keypair()),
+            is_public: Set(true),
+            store_dir: Set("/nix/store".to_string()),
+            priority: Set(41),
+            upstream_cache_key_names: Set(DbJson(Vec::new())),
+            created_at: Set(Utc::now()),
+            nar_url_base: Set(nar_url_base.map(str::to_string)),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap()
+        .last_insert_id;
+
+        let nar_id = Nar::insert(nar::ActiveModel {
+            state: Set(NarState::Valid),
+            nar_hash: Set(
+                "sha256:df3404eaf1481506db9ca155e0a871d5b4d22e62a96961e8bf4ad1a8ca525330"
+                    .to_string(),
+            ),
+            nar_size: Set(226560),
+            compression: Set(compression.to_string()),
+            num_chunks: Set(0),
+            completeness_hint: Set(true),
+            holders_count: Set(0),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap()
+        .last_insert_id;
+
+        Object::insert(object::ActiveModel {
+            cache_id: Set(cache_id),
+            nar_id: Set(nar_id),
+            store_path_hash: Set(STORE_PATH_HASH.to_string()),
+            store_path: Set(format!("/nix/store/{}-hello-2.10", STORE_PATH_HASH)),
+            references: Set(DbJson(Vec::new())),
+            sigs: Set(DbJson(Vec::new())),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap();
+
+        set_chunks(&state, &[(Some(FILE_HASH), Some(1000))]).await;
+
+        state
+    }
+
+    /// Replaces the chunks of the NAR with ones of the given file hashes and sizes.
+    async fn set_chunks(state: &State, chunks: &[(Option<&str>, Option<i64>)]) {
+        let db = state.database().await.unwrap();
+        let nar = Nar::find().one(db).await.unwrap().unwrap();
+
+        ChunkRef::delete_many()
+            .filter(chunkref::Column::NarId.eq(nar.id))
+            .exec(db)
+            .await
+            .unwrap();
+
+        for (seq, (file_hash, file_size)) in chunks.iter().enumerate() {
+            let remote_file_id = uuid::Uuid::new_v4().to_string();
+            let chunk_id = Chunk::insert(chunk::ActiveModel {
+                state: Set(ChunkState::Valid),
+                chunk_hash: Set(format!("sha256:{}", remote_file_id)),
+                chunk_size: Set(2000),
+                file_hash: Set(file_hash.map(str::to_string)),
+                file_size: Set(*file_size),
+                compression: Set(nar.compression.clone()),
+                remote_file: Set(DbJson(RemoteFile::Local(LocalRemoteFile {
+                    name: remote_file_id.clone(),
+                    path: None,
+                }))),
+                remote_file_id: Set(remote_file_id),
+                holders_count: Set(0),
+                reference_count: Set(1),
+                created_at: Set(Utc::now()),
+                ..Default::default()
+            })
+            .exec(db)
+            .await
+            .unwrap()
+            .last_insert_id;
+
+            ChunkRef::insert(chunkref::ActiveModel {
+                nar_id: Set(nar.id),
+                seq: Set(seq as i32),
+                chunk_id: Set(Some(chunk_id)),
+                chunk_hash: Set(String::new()),
+                compression: Set(nar.compression.clone()),
+                ..Default::default()
+            })
+            .exec(db)
+            .await
+            .unwrap();
+        }
+
+        let num_chunks = chunks.len() as i32;
+        Nar::update(nar::ActiveModel {
+            id: Set(nar.id),
+            num_chunks: Set(num_chunks),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap();
+    }
+
+    fn make_req_state() -> RequestState {
+        Arc::new(RequestStateInner {
+            auth: AuthState::new(),
+            api_endpoint: Some("https://attic.example.com/".to_string()),
+            substituter_endpoint: None,
+            host: "attic.example.com".to_string(),
+            client_claims_https: true,
+            public_cache: AtomicBool::new(false),
+        })
+    }
+
+    async fn get_narinfo(state: State) -> NarInfo {
+        let req_state = make_req_state();
+
+        let narinfo = get_store_path_info(
+            Extension(state),
+            Extension(req_state.clone()),
+            Path((
+                "demo".parse().unwrap(),
+                format!("{}.narinfo", STORE_PATH_HASH),
+            )),
+        )
+        .await
+        .unwrap();
+
+        // The substituter endpoint is unaffected
+        assert_eq!(
+            "https://attic.example.com/demo",
+            req_state
+                .substituter_endpoint("demo".parse().unwrap())
+                .unwrap()
+        );
+
+        narinfo
+    }
+
+    async fn get_narinfo_url(nar_url_base: Option<&str>) -> String {
+        get_narinfo(make_state(nar_url_base, "zstd", None).await)
+            .await
+            .url
+    }
+
+    #[tokio::test]
+    async fn test_nix_cache_info() {
+        let state = make_state(None, "zstd", None).await;
+        let info = get_nix_cache_info(
+            Extension(state),
+            Extension(make_req_state()),
+            Path("demo".parse().unwrap()),
+        )
+        .await
+        .unwrap();
+
+        let response = info.into_response();
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(
+            mime::NIX_CACHE_INFO,
+            response.headers().get(header::CONTENT_TYPE).unwrap()
+        );
+
+        // Nix reads the priority from here, so it must be the one configured
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        assert_eq!(
+            vec!["StoreDir: /nix/store", "WantMassQuery: 1", "Priority: 41"],
+            body.lines().collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_narinfo_url_relative() {
+        assert_eq!(
+            format!("nar/{}.nar", STORE_PATH_HASH),
+            get_narinfo_url(None).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_narinfo_url_absolute() {
+        assert_eq!(
+            format!("https://cdn.example.com/nar/{}.nar", STORE_PATH_HASH),
+            get_narinfo_url(Some("https://cdn.example.com/")).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_narinfo_url_base_with_path() {
+        assert_eq!(
+            format!("https://cdn.example.com/demo/nar/{}.nar", STORE_PATH_HASH),
+            get_narinfo_url(Some("https://cdn.example.com/demo/")).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_narinfo_serve_recompress() {
+        let narinfo = get_narinfo(make_state(None, "xz", None).await).await;
+        assert_eq!(Compression::Xz, narinfo.compression);
+
+        let narinfo = get_narinfo(make_state(None, "xz", Some("zstd")).await).await;
+        assert_eq!(Compression::Zstd, narinfo.compression);
+        assert!(narinfo.file_hash.is_none());
+        assert!(narinfo.file_size.is_none());
+
+        let narinfo = get_narinfo(make_state(None, "zstd", Some("zstd")).await).await;
+        assert_eq!(Compression::Zstd, narinfo.compression);
+    }
+
+    #[tokio::test]
+    async fn test_narinfo_file_info() {
+        let file_hash = Hash::from_typed(FILE_HASH).unwrap();
+
+        // Unchunked
+        let narinfo = get_narinfo(make_state(None, "xz", None).await).await;
+        assert_eq!(Some(file_hash.clone()), narinfo.file_hash);
+        assert_eq!(Some(1000), narinfo.file_size);
+
+        let rendered = narinfo.to_string().unwrap();
+        assert!(rendered.contains(&format!("FileHash: {}\n", file_hash.to_typed_base16())));
+        assert!(rendered.contains("FileSize: 1000\n"));
+
+        // Chunked
+        let state = make_state(None, "xz", None).await;
+        set_chunks(
+            &state,
+            &[
+                (Some(FILE_HASH), Some(1000)),
+                (Some(FILE_HASH), Some(200)),
+                (Some(FILE_HASH), Some(30)),
+            ],
+        )
+        .await;
+
+        let narinfo = get_narinfo(state).await;
+        assert_eq!(None, narinfo.file_hash);
+        assert_eq!(Some(1230), narinfo.file_size);
+
+        let rendered = narinfo.to_string().unwrap();
+        assert!(!rendered.contains("FileHash:"));
+        assert!(rendered.contains("FileSize: 1230\n"));
+
+        // Unconfirmed chunk
+        let state = make_state(None, "xz", None).await;
+        set_chunks(&state, &[(Some(FILE_HASH), Some(1000)), (None, None)]).await;
+
+        let narinfo = get_narinfo(state).await;
+        assert_eq!(None, narinfo.file_hash);
+        assert_eq!(None, narinfo.file_size);
+    }
+
+    #[tokio::test]
+    async fn test_narinfo_previous_keypairs() {
+        use crate::database::entity::cache::PreviousKeypair;
+        use crate::database::AtticDatabase;
+
+        let state = make_state_with(None, "zstd", |config| {
+            config.key_rotation_grace_period = std::time::Duration::from_secs(3600);
+        })
+        .await;
+
+        let previous = NixKeypair::generate("demo-old").unwrap();
+        let expired = NixKeypair::generate("demo-expired").unwrap();
+
+        let db = state.database().await.unwrap();
+        let cache = db.find_cache(&"demo".parse().unwrap()).await.unwrap();
+        let current = cache.keypair().unwrap();
+        Cache::update(cache::ActiveModel {
+            id: Set(cache.id),
+            previous_keypairs: Set(DbJson(vec![
+                PreviousKeypair {
+                    keypair: previous.export_keypair(),
+                    replaced_at: Utc::now(),
+                },
+                PreviousKeypair {
+                    keypair: expired.export_keypair(),
+                    replaced_at: Utc::now() - chrono::Duration::hours(2),
+                },
+            ])),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap();
+
+        let narinfo = get_narinfo(state).await;
+        assert!(narinfo.is_signed_by(&current));
+        assert!(narinfo.is_signed_by(&previous));
+        assert!(!narinfo.is_signed_by(&expired));
+    }
+
+    #[tokio::test]
+    async fn test_narinfo_client_signature() {
+        use crate::database::AtticDatabase;
+
+        let state = make_state(None, "zstd", None).await;
+        let db = state.database().await.unwrap();
+
+        let cache = db.find_cache(&"demo".parse().unwrap()).await.unwrap();
+        let keypair = cache.keypair().unwrap();
+
+        let narinfo = get_narinfo(state.clone()).await;
+        let client = NixKeypair::generate("org-1").unwrap();
+        let signature = client.sign(&narinfo.fingerprint());
+
+        Object::update_many()
+            .set(object::ActiveModel {
+                sigs: Set(DbJson(vec![signature.clone()])),
+                ..Default::default()
+            })
+            .exec(db)
+            .await
+            .unwrap();
+
+        // Signed paths still carry the cache's own signature
+        let narinfo = get_narinfo(state).await;
+        assert_eq!(signature, narinfo.signatures()[0]);
+        assert!(narinfo.is_signed_by(&keypair));
+        assert!(narinfo.is_signed_by(&client));
+    }
+
+    #[tokio::test]
+    async fn test_narinfo_upstream_signatures() {
+        use crate::database::AtticDatabase;
+
+        const UPSTREAM: &str = "cache.nixos.org-1:lo9EfNIL4eGRuNh7DTbAAffWPpI2SlYC/8uP7JnhgmfRIUNGhSbFe8qEaKN0mFS02TuhPpXFPNtRkFcCp0hGAQ==";
+
+        let state = make_state(None, "zstd", None).await;
+        let db = state.database().await.unwrap();
+
+        let cache = db.find_cache(&"demo".parse().unwrap()).await.unwrap();
+        let keypair = cache.keypair().unwrap();
+
+        // Made with `attic push --sign-key`
+        let narinfo = get_narinfo(state.clone()).await;
+        let local = NixKeypair::generate("org-1").unwrap();
+        let signature = local.sign(&narinfo.fingerprint());
+
+        Object::update_many()
+            .set(object::ActiveModel {
+                sigs: Set(DbJson(vec![UPSTREAM.to_string(), signature.clone()])),
+                ..Default::default()
+            })
+            .exec(db)
+            .await
+            .unwrap();
+
+        Cache::update(cache::ActiveModel {
+            id: Set(cache.id),
+            upstream_cache_key_names: Set(DbJson(vec!["cache.nixos.org-1".to_string()])),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap();
+
+        // Upstream signatures are stripped by default, local ones are kept
+        let narinfo = get_narinfo(state.clone()).await;
+        assert_eq!(2, narinfo.signatures().len());
+        assert_eq!(signature, narinfo.signatures()[0]);
+        assert!(narinfo.is_signed_by(&keypair));
+
+        Cache::update(cache::ActiveModel {
+            id: Set(cache.id),
+            preserve_upstream_signatures: Set(true),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap();
+
+        let narinfo = get_narinfo(state).await;
+        assert_eq!(UPSTREAM, narinfo.signatures()[0]);
+        assert_eq!(signature, narinfo.signatures()[1]);
+        assert!(narinfo.is_signed_by(&keypair));
+
+        let rendered = narinfo.to_string().unwrap();
+        assert_eq!(
+            3,
+            rendered.lines().filter(|l| l.starts_with("Sig: ")).count()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_narinfo_invalid_store_path() {
+        use sea_orm::sea_query::Expr;
+
+        let state = make_state(None, "zstd", None).await;
+        Object::update_many()
+            .col_expr(object::Column::StorePath, Expr::value("/nix/store"))
+            .exec(state.database().await.unwrap())
+            .await
+            .unwrap();
+
+        let e = get_store_path_info(
+            Extension(state),
+            Extension(make_req_state()),
+            Path((
+                "demo".parse().unwrap(),
+                format!("{}.narinfo", STORE_PATH_HASH),
+            )),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            e.into_response().status()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_narinfo_cache() {
+        let state = make_state_with(None, "zstd", |config| {
+            config.narinfo_cache.size = 10;
+        })
+        .await;
+        let cache_name: CacheName = "demo".parse().unwrap();
+
+        let narinfo = get_narinfo(state.clone()).await;
+
+        // Served from the cache even though the object is gone
+        Object::delete_many()
+            .exec(state.database().await.unwrap())
+            .await
+            .unwrap();
+        assert_eq!(narinfo.url, get_narinfo(state.clone()).await.url);
+
+        state
+            .narinfo_cache
+            .invalidate(&cache_name, [STORE_PATH_HASH]);
+        let e = get_store_path_info(
+            Extension(state.clone()),
+            Extension(make_req_state()),
+            Path((cache_name, format!("{}.narinfo", STORE_PATH_HASH))),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(StatusCode::NOT_FOUND, e.into_response().status());
+
+        let stats = state.narinfo_cache.stats();
+        assert_eq!(1, stats.hits);
+        assert_eq!(2, stats.misses);
+    }
+
+    #[test]
+    fn test_parse_range() {
+        fn partial(start: u64, end: u64) -> RangeRequest {
+            RangeRequest::Partial(ByteRange { start, end })
+        }
+
+        assert_eq!(partial(0, 99), parse_range("bytes=0-99", 1000));
+        assert_eq!(partial(500, 999), parse_range("bytes=500-", 1000));
+        assert_eq!(partial(900, 999), parse_range("bytes=-100", 1000));
+        assert_eq!(partial(0, 999), parse_range("bytes=-2000", 1000));
+        assert_eq!(partial(990, 999), parse_range("bytes=990-2000", 1000));
+        assert_eq!(partial(999, 999), parse_range("bytes=999-999", 1000));
+
+        assert_eq!(
+            RangeRequest::Unsatisfiable,
+            parse_range("bytes=1000-", 1000)
+        );
+        assert_eq!(RangeRequest::Unsatisfiable, parse_range("bytes=-0", 1000));
+        assert_eq!(RangeRequest::Unsatisfiable, parse_range("bytes=0-", 0));
+
+        // Ignored
+        assert_eq!(RangeRequest::Full, parse_range("bytes=0-9,20-29", 1000));
+        assert_eq!(RangeRequest::Full, parse_range("bytes=9-0", 1000));
+        assert_eq!(RangeRequest::Full, parse_range("bytes=a-b", 1000));
+        assert_eq!(RangeRequest::Full, parse_range("bytes=10", 1000));
+        assert_eq!(RangeRequest::Full, parse_range("items=0-9", 1000));
+    }
+
+    #[test]
+    fn test_locate_range() {
+        let file_sizes = [100, 50, 200];
+        let range = |start, end| ByteRange { start, end };
+
+        assert_eq!((0, 0, 0), locate_range(&file_sizes, range(0, 99)));
+        assert_eq!((0, 0, 10), locate_range(&file_sizes, range(10, 20)));
+        assert_eq!((0, 1, 99), locate_range(&file_sizes, range(99, 100)));
+        assert_eq!((1, 1, 0), locate_range(&file_sizes, range(100, 149)));
+        assert_eq!((1, 2, 49), locate_range(&file_sizes, range(149, 150)));
+        assert_eq!((0, 2, 0), locate_range(&file_sizes, range(0, 349)));
+        assert_eq!((2, 2, 199), locate_range(&file_sizes, range(349, 349)));
+    }
+
+    #[tokio::test]
+    async fn test_recompress_stream() {
+        use tokio::io::AsyncReadExt;
+
+        async fn xz(data: &[u8]) -> Bytes {
+            let mut compressed = Vec::new();
+            XzEncoder::new(data)
+                .read_to_end(&mut compressed)
+                .await
+                .unwrap();
+            compressed.into()
+        }
+
+        let first: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let second = b"hello world".repeat(1000);
+
+        // Two independently-compressed chunks
+        let stream: BoxStream<'static, IoResult<Bytes>> = Box::pin(futures::stream::iter([
+            Ok(xz(&first).await),
+            Ok(xz(&second).await),
+        ]));
+
+        let mut recompressed = Vec::new();
+        StreamReader::new(recompress_stream(
+            stream,
+            Compression::Xz,
+            CompressionType::Zstd,
+        ))
+        .read_to_end(&mut recompressed)
+        .await
+        .unwrap();
+
+        let mut decompressed = Vec::new();
+        ZstdDecoder::new(recompressed.as_slice())
+            .read_to_end(&mut decompressed)
+            .await
+            .unwrap();
+
+        assert_eq!([first, second].concat(), decompressed);
+    }
+
+    #[tokio::test]
+    async fn test_verify_nar_stream() {
+        use tokio::io::AsyncReadExt;
+
+        let nar = b"nix-archive-1".repeat(10000);
+        let nar_hash = Hash::Sha256(Sha256::digest(&nar).into());
+
+        let mut compressed = Vec::new();
+        ZstdEncoder::new(nar.as_slice())
+            .read_to_end(&mut compressed)
+            .await
+            .unwrap();
+
+        let verify = |compressed: Vec<u8>, nar_hash: Hash, nar_size: usize| async move {
+            let pieces: Vec<IoResult<Bytes>> = compressed
+                .chunks(1000)
+                .map(|piece| Ok(Bytes::copy_from_slice(piece)))
+                .collect();
+
+            let stream = verify_nar_stream(
+                Box::pin(futures::stream::iter(pieces)),
+                Compression::Zstd,
+                nar_hash,
+                nar_size,
+            );
+
+            let mut served = Vec::new();
+            let result = StreamReader::new(stream).read_to_end(&mut served).await;
+            (result, served)
+        };
+
+        // The stored file is served as-is
+        let (result, served) = verify(compressed.clone(), nar_hash.clone(), nar.len()).await;
+        result.unwrap();
+        assert_eq!(compressed, served);
+
+        // Wrong hash or size
+        let other_hash = Hash::Sha256(Sha256::digest(b"other").into());
+        let (result, served) = verify(compressed.clone(), other_hash, nar.len()).await;
+        assert!(result.is_err());
+        assert!(served.len() < compressed.len());
+
+        let (result, _) = verify(compressed.clone(), nar_hash.clone(), nar.len() + 1).await;
+        assert!(result.is_err());
+
+        // Corrupted data
+        let mut corrupted = compressed.clone();
+        let middle = corrupted.len() / 2;
+        corrupted[middle] ^= 0xff;
+        let (result, _) = verify(corrupted, nar_hash, nar.len()).await;
+        assert!(result.is_err());
+    }
+}
@@ -1,17 +1,18 @@
 //! HTTP API.
 
-mod binary_cache;
-mod v1;
+pub(crate) mod binary_cache;
+pub(crate) mod v1;
 
 use axum::{response::Html, routing::get, Router};
+use tower_http::cors::CorsLayer;
 
 async fn placeholder() -> Html<&'static str> {
     Html(include_str!("placeholder.html"))
 }
 
-pub(crate) fn get_router() -> Router {
+pub(crate) fn get_router(cors: Option<CorsLayer>) -> Router {
     Router::new()
         .route("/", get(placeholder))
         .merge(binary_cache::get_router())
-        .merge(v1::get_router())
+        .merge(v1::get_router(cors))
 }
@@ -8,12 +8,18 @@ use sea_orm::ActiveValue::Set;
 use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
 use tracing::instrument;
 
-use crate::database::entity::cache::{self, Entity as Cache};
+use super::upload_path::UPLOAD_ENCODINGS;
+use crate::access::CachePermission;
+use crate::audit::{self, AuditEvent};
+use crate::config::ChunkingConfig as ServerChunkingConfig;
+use crate::database::entity::audit_log::AuditAction;
+use crate::database::entity::cache::{self, Entity as Cache, PreviousKeypair};
 use crate::database::entity::Json as DbJson;
 use crate::error::{ErrorKind, ServerError, ServerResult};
 use crate::{RequestState, State};
 use attic::api::v1::cache_config::{
-    CacheConfig, CreateCacheRequest, KeypairConfig, RetentionPeriodConfig,
+    CacheConfig, CachePublicKey, ChunkingConfig, ChunkingOverrides, CreateCacheRequest,
+    KeypairConfig, NarUrlBaseConfig, RetentionPeriodConfig,
 };
 use attic::cache::CacheName;
 use attic::signing::NixKeypair;
@@ -34,6 +40,11 @@ pub(crate) async fn get_cache_config(
         .await?;
 
     let public_key = cache.keypair()?.export_public_key();
+    let previous_public_keys = cache
+        .previous_keypairs(state.config.key_rotation_grace_period)?
+        .iter()
+        .map(|keypair| keypair.export_public_key())
+        .collect();
 
     let retention_period_config = if let Some(period) = cache.retention_period {
         RetentionPeriodConfig::Period(period as u32)
@@ -41,16 +52,70 @@ pub(crate) async fn get_cache_config(
         RetentionPeriodConfig::Global
     };
 
-    Ok(Json(CacheConfig {
+    let nar_url_base_config = if let Some(base) = cache.nar_url_base {
+        NarUrlBaseConfig::Base(base)
+    } else {
+        NarUrlBaseConfig::Relative
+    };
+
+    let chunking_overrides = ChunkingOverrides {
+        nar_size_threshold: cache.chunking_nar_size_threshold.map(|v| v as usize),
+        min_size: cache.chunking_min_size.map(|v| v as usize),
+        avg_size: cache.chunking_avg_size.map(|v| v as usize),
+        max_size: cache.chunking_max_size.map(|v| v as usize),
+    };
+
+    let chunking_config = if chunking_overrides.is_empty() {
+        ChunkingConfig::Global
+    } else {
+        ChunkingConfig::Override(chunking_overrides)
+    };
+
+    let config = CacheConfig {
         substituter_endpoint: Some(req_state.substituter_endpoint(cache_name)?),
         api_endpoint: Some(req_state.api_endpoint()?),
         keypair: None,
         public_key: Some(public_key),
+        previous_public_keys: Some(previous_public_keys),
+        upload_encodings: Some(UPLOAD_ENCODINGS.iter().map(|e| e.to_string()).collect()),
         is_public: Some(cache.is_public),
         store_dir: Some(cache.store_dir),
         priority: Some(cache.priority),
         upstream_cache_key_names: Some(cache.upstream_cache_key_names.0),
         retention_period: Some(retention_period_config),
+        nar_url_base: Some(nar_url_base_config),
+        preserve_upstream_signatures: Some(cache.preserve_upstream_signatures),
+        chunking: Some(chunking_config),
+    };
+
+    Ok(Json(config))
+}
+
+/// Returns the public key and substituter endpoint of a cache.
+///
+/// This is all `attic use` needs, and is available to anyone who can
+/// discover the cache, including anonymous clients of public caches.
+///
+/// - GET `/_api/v1/cache-config/:cache/public-key`
+#[instrument(skip_all, fields(cache_name))]
+pub(crate) async fn get_cache_public_key(
+    Extension(state): Extension<State>,
+    Extension(req_state): Extension<RequestState>,
+    Path(cache_name): Path<CacheName>,
+) -> ServerResult<Json<CachePublicKey>> {
+    let database = state.database().await?;
+    let cache = req_state
+        .auth
+        .auth_cache(database, &cache_name, |cache, permission| {
+            permission.require_discover()?;
+            Ok(cache)
+        })
+        .await?;
+
+    Ok(Json(CachePublicKey {
+        public_key: cache.keypair()?.export_public_key(),
+        substituter_endpoint: req_state.substituter_endpoint(cache_name)?,
+        priority: cache.priority,
     }))
 }
 
@@ -62,11 +127,25 @@ pub(crate) async fn configure_cache(
     Json(payload): Json<CacheConfig>,
 ) -> ServerResult<()> {
     let database = state.database().await?;
-    let (cache, permission) = req_state
+    let cache = req_state
         .auth
         .auth_cache(database, &cache_name, |cache, permission| {
-            permission.require_configure_cache()?;
-            Ok((cache, permission.clone()))
+            if !permission.configure_cache && !permission.configure_cache_retention {
+                permission.require_configure_cache()?;
+            }
+
+            let rejected = rejected_fields(&payload, permission);
+            if !rejected.is_empty() {
+                let fields = rejected
+                    .iter()
+                    .map(|(field, required)| format!("{} (requires {})", field, required))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+
+                return Err(ErrorKind::FieldPermissionDenied { fields }.into());
+            }
+
+            Ok(cache)
         })
         .await?;
 
@@ -75,7 +154,7 @@ pub(crate) async fn configure_cache(
         ..Default::default()
     };
 
-    let mut modified = false;
+    let mut modified = Vec::new();
 
     if let Some(keypair_cfg) = payload.keypair {
         let keypair = match keypair_cfg {
@@ -83,32 +162,52 @@ pub(crate) async fn configure_cache(
             KeypairConfig::Keypair(k) => k,
         };
         update.keypair = Set(keypair.export_keypair());
-        modified = true;
+
+        // Keep signing with the old keypair while clients switch over
+        let grace_period = state.config.key_rotation_grace_period;
+        let mut previous_keypairs: Vec<_> = cache
+            .previous_keypairs
+            .0
+            .iter()
+            .filter(|previous| previous.is_retained(grace_period))
+            .cloned()
+            .collect();
+
+        if !grace_period.is_zero() {
+            previous_keypairs.insert(
+                0,
+                PreviousKeypair {
+                    keypair: cache.keypair.clone(),
+                    replaced_at: Utc::now(),
+                },
+            );
+        }
+
+        update.previous_keypairs = Set(DbJson(previous_keypairs));
+        modified.push("keypair");
     }
 
     if let Some(is_public) = payload.is_public {
         update.is_public = Set(is_public);
-        modified = true;
+        modified.push("is_public");
     }
 
     if let Some(store_dir) = payload.store_dir {
         update.store_dir = Set(store_dir);
-        modified = true;
+        modified.push("store_dir");
     }
 
     if let Some(priority) = payload.priority {
         update.priority = Set(priority);
-        modified = true;
+        modified.push("priority");
     }
 
     if let Some(upstream_cache_key_names) = payload.upstream_cache_key_names {
         update.upstream_cache_key_names = Set(DbJson(upstream_cache_key_names));
-        modified = true;
+        modified.push("upstream_cache_key_names");
     }
 
     if let Some(retention_period_config) = payload.retention_period {
-        permission.require_configure_cache_retention()?;
-
         match retention_period_config {
             RetentionPeriodConfig::Global => {
                 update.retention_period = Set(None);
@@ -121,15 +220,85 @@ pub(crate) async fn configure_cache(
             }
         }
 
-        modified = true;
+        modified.push("retention_period");
+    }
+
+    if let Some(nar_url_base_config) = payload.nar_url_base {
+        nar_url_base_config
+            .validate()
+            .map_err(|e| ErrorKind::RequestError(anyhow!(e)))?;
+
+        match nar_url_base_config {
+            NarUrlBaseConfig::Relative => {
+                update.nar_url_base = Set(None);
+            }
+            NarUrlBaseConfig::Base(base) => {
+                update.nar_url_base = Set(Some(base));
+            }
+        }
+
+        modified.push("nar_url_base");
+    }
+
+    if let Some(preserve) = payload.preserve_upstream_signatures {
+        update.preserve_upstream_signatures = Set(preserve);
+        modified.push("preserve_upstream_signatures");
     }
 
-    if modified {
+    if let Some(chunking_config) = payload.chunking {
+        let mut updated = cache.clone();
+
+        match chunking_config {
+            ChunkingConfig::Global => {
+                updated.chunking_nar_size_threshold = None;
+                updated.chunking_min_size = None;
+                updated.chunking_avg_size = None;
+                updated.chunking_max_size = None;
+            }
+            ChunkingConfig::Override(overrides) => {
+                overrides
+                    .validate()
+                    .map_err(|e| ErrorKind::RequestError(anyhow!(e)))?;
+
+                let merge = |value: Option<usize>, current: Option<i64>| match value {
+                    Some(value) => i64::try_from(value)
+                        .map(Some)
+                        .map_err(ServerError::request_error),
+                    None => Ok(current),
+                };
+
+                updated.chunking_nar_size_threshold = merge(
+                    overrides.nar_size_threshold,
+                    cache.chunking_nar_size_threshold,
+                )?;
+                updated.chunking_min_size = merge(overrides.min_size, cache.chunking_min_size)?;
+                updated.chunking_avg_size = merge(overrides.avg_size, cache.chunking_avg_size)?;
+                updated.chunking_max_size = merge(overrides.max_size, cache.chunking_max_size)?;
+            }
+        }
+
+        validate_chunking(&updated.chunking(&state.config.chunking))?;
+
+        update.chunking_nar_size_threshold = Set(updated.chunking_nar_size_threshold);
+        update.chunking_min_size = Set(updated.chunking_min_size);
+        update.chunking_avg_size = Set(updated.chunking_avg_size);
+        update.chunking_max_size = Set(updated.chunking_max_size);
+        modified.push("chunking");
+    }
+
+    if !modified.is_empty() {
         Cache::update(update)
             .exec(database)
             .await
             .map_err(ServerError::database_error)?;
 
+        // The signing key or the store directory may have changed
+        state.narinfo_cache.invalidate_cache(&cache_name);
+
+        let event = AuditEvent::new(AuditAction::ConfigureCache, &req_state, &cache_name)
+            .details(modified.join(", "));
+        audit::record(&state, event).await;
+
         Ok(())
     } else {
         Err(ErrorKind::RequestError(anyhow!("No modifiable fields were set.")).into())
@@ -163,9 +332,7 @@ pub(crate) async fn destroy_cache(
 
         if deletion.rows_affected == 0 {
             // Someone raced to (soft) delete the cache before us
-            Err(ErrorKind::NoSuchCache.into())
-        } else {
-            Ok(())
+            return Err(ErrorKind::NoSuchCache.into());
         }
     } else {
         // Perform hard deletion
@@ -178,11 +345,16 @@ pub(crate) async fn destroy_cache(
 
         if deletion.rows_affected == 0 {
             // Someone raced to (soft) delete the cache before us
-            Err(ErrorKind::NoSuchCache.into())
-        } else {
-            Ok(())
+            return Err(ErrorKind::NoSuchCache.into());
         }
     }
+
+    state.narinfo_cache.invalidate_cache(&cache_name);
+
+    let event = AuditEvent::new(AuditAction::DestroyCache, &req_state, &cache_name);
+    audit::record(&state, event).await;
+
+    Ok(())
 }
 
 #[instrument(skip_all, fields(cache_name, payload))]
@@ -223,8 +395,430 @@ pub(crate) async fn create_cache(
 
     if num_inserted == 0 {
         // The cache already exists
-        Err(ErrorKind::CacheAlreadyExists.into())
-    } else {
-        Ok(())
+        return Err(ErrorKind::CacheAlreadyExists.into());
+    }
+
+    let event = AuditEvent::new(AuditAction::CreateCache, &req_state, &cache_name);
+    audit::record(&state, event).await;
+
+    Ok(())
+}
+
+/// Checks the effective chunking parameters of a cache.
+///
+/// Overrides are combined with the server configuration, so a
+/// partial override can still produce an unusable set of sizes.
+fn validate_chunking(chunking: &ServerChunkingConfig) -> ServerResult<()> {
+    if chunking.min_size > chunking.avg_size || chunking.avg_size > chunking.max_size {
+        return Err(ErrorKind::RequestError(anyhow!(
+            "Chunk sizes must satisfy min-size <= avg-size <= max-size, got {}/{}/{}",
+            chunking.min_size,
+            chunking.avg_size,
+            chunking.max_size
+        ))
+        .into());
+    }
+
+    chunking
+        .algorithm()
+        .validate(chunking.min_size, chunking.avg_size, chunking.max_size)
+        .map_err(ServerError::request_error)
+}
+
+/// Returns the fields in a patch that cannot be modified with a permission.
+///
+/// Each entry is a `(field, required_permission)` tuple. Retention
+/// settings require `configure_cache_retention`, and everything else
+/// requires `configure_cache`.
+fn rejected_fields(
+    payload: &CacheConfig,
+    permission: &CachePermission,
+) -> Vec<(&'static str, &'static str)> {
+    let mut rejected = Vec::new();
+
+    if !permission.configure_cache {
+        let fields = [
+            ("keypair", payload.keypair.is_some()),
+            ("is_public", payload.is_public.is_some()),
+            ("store_dir", payload.store_dir.is_some()),
+            ("priority", payload.priority.is_some()),
+            (
+                "upstream_cache_key_names",
+                payload.upstream_cache_key_names.is_some(),
+            ),
+            ("nar_url_base", payload.nar_url_base.is_some()),
+            (
+                "preserve_upstream_signatures",
+                payload.preserve_upstream_signatures.is_some(),
+            ),
+            ("chunking", payload.chunking.is_some()),
+        ];
+
+        for (field, is_set) in fields {
+            if is_set {
+                rejected.push((field, "configure_cache"));
+            }
+        }
+    }
+
+    if !permission.configure_cache_retention && payload.retention_period.is_some() {
+        rejected.push(("retention_period", "configure_cache_retention"));
+    }
+
+    rejected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+    use chrono::Duration as ChronoDuration;
+
+    use crate::access::http::AuthState;
+    use crate::access::Token;
+    use crate::config::Config;
+    use crate::database::migration::{Migrator, MigratorTrait};
+    use crate::database::AtticDatabase;
+    use crate::{RequestStateInner, StateInner};
+
+    fn permission(configure_cache: bool, configure_cache_retention: bool) -> CachePermission {
+        CachePermission {
+            configure_cache,
+            configure_cache_retention,
+            ..Default::default()
+        }
+    }
+
+    fn retention_patch() -> CacheConfig {
+        let mut patch = CacheConfig::blank();
+        patch.retention_period = Some(RetentionPeriodConfig::Period(3600));
+        patch
+    }
+
+    fn general_patch() -> CacheConfig {
+        let mut patch = CacheConfig::blank();
+        patch.is_public = Some(true);
+        patch.priority = Some(42);
+        patch
+    }
+
+    fn mixed_patch() -> CacheConfig {
+        let mut patch = general_patch();
+        patch.retention_period = Some(RetentionPeriodConfig::Global);
+        patch
+    }
+
+    #[test]
+    fn test_rejected_fields() {
+        let cr = permission(true, false);
+        let cq = permission(false, true);
+        let both = permission(true, true);
+
+        // Retention-only patches only need `cq`
+        assert!(rejected_fields(&retention_patch(), &cq).is_empty());
+        assert!(rejected_fields(&retention_patch(), &both).is_empty());
+        assert_eq!(
+            vec![("retention_period", "configure_cache_retention")],
+            rejected_fields(&retention_patch(), &cr)
+        );
+
+        // Other fields only need `cr`
+        assert!(rejected_fields(&general_patch(), &cr).is_empty());
+        assert!(rejected_fields(&general_patch(), &both).is_empty());
+        assert_eq!(
+            vec![
+                ("is_public", "configure_cache"),
+                ("priority", "configure_cache")
+            ],
+            rejected_fields(&general_patch(), &cq)
+        );
+
+        // Mixed patches need both
+        assert!(rejected_fields(&mixed_patch(), &both).is_empty());
+        assert_eq!(
+            vec![("retention_period", "configure_cache_retention")],
+            rejected_fields(&mixed_patch(), &cr)
+        );
+        assert_eq!(
+            vec![
+                ("is_public", "configure_cache"),
+                ("priority", "configure_cache")
+            ],
+            rejected_fields(&mixed_patch(), &cq)
+        );
+
+        // Read-only fields are ignored
+        let mut patch = CacheConfig::blank();
+        patch.public_key = Some("test:key".to_string());
+        assert!(rejected_fields(&patch, &permission(false, false)).is_empty());
+    }
+
+    async fn make_state() -> State {
+        make_state_with("").await
+    }
+
+    async fn make_state_with(global: &str) -> State {
+        let config: Config = toml::from_str(&format!(
+            r#"
+{global}
+
+[database]
+url = "sqlite::memory:"
+
+[storage]
+type = "local"
+path = "/nonexistent"
+
+[chunking]
+nar-size-threshold = 0
+min-size = 16384
+avg-size = 65536
+max-size = 262144
+
+[jwt.signing]
+token-hs256-secret-base64 = "dmVyeSBzZWN1cmUgc2VjcmV0"
+"#
+        ))
+        .unwrap();
+
+        let state = StateInner::new(config).await;
+        let db = state.database().await.unwrap();
+        Migrator::up(db, None).await.unwrap();
+
+        for (name, is_public) in [("private", false), ("public", true)] {
+            Cache::insert(cache::ActiveModel {
+                name: Set(name.to_string()),
+                keypair: Set(NixKeypair::generate(name).unwrap().export_keypair()),
+                is_public: Set(is_public),
+                store_dir: Set("/nix/store".to_string()),
+                priority: Set(41),
+                upstream_cache_key_names: Set(DbJson(Vec::new())),
+                created_at: Set(Utc::now()),
+                ..Default::default()
+            })
+            .exec(db)
+            .await
+            .unwrap();
+        }
+
+        state
+    }
+
+    /// Returns a request state with a token only allowed to push to the private cache.
+    fn make_req_state(token: bool) -> RequestState {
+        if token {
+            make_req_state_with(|permission| permission.push = true)
+        } else {
+            make_anonymous_req_state()
+        }
+    }
+
+    /// Returns a request state with a token on the private cache.
+    fn make_req_state_with(grant: impl FnOnce(&mut CachePermission)) -> RequestState {
+        let mut token = Token::new("meow".to_string(), &(Utc::now() + ChronoDuration::days(1)));
+        grant(token.get_or_insert_permission_mut("private".parse().unwrap()));
+
+        let req_state = make_anonymous_req_state();
+        req_state.auth.token.set(token).unwrap();
+        req_state
+    }
+
+    fn make_anonymous_req_state() -> RequestState {
+        let auth = AuthState::new();
+
+        Arc::new(RequestStateInner {
+            auth,
+            api_endpoint: Some("https://attic.example.com/".to_string()),
+            substituter_endpoint: None,
+            host: "localhost".to_string(),
+            client_claims_https: false,
+            public_cache: AtomicBool::new(false),
+        })
+    }
+
+    async fn get_public_key(
+        state: &State,
+        token: bool,
+        cache_name: &str,
+    ) -> ServerResult<CachePublicKey> {
+        get_cache_public_key(
+            Extension(state.clone()),
+            Extension(make_req_state(token)),
+            Path(cache_name.parse().unwrap()),
+        )
+        .await
+        .map(|Json(public_key)| public_key)
+    }
+
+    #[tokio::test]
+    async fn test_get_cache_config_upload_encodings() {
+        let state = make_state().await;
+
+        let Json(config) = get_cache_config(
+            Extension(state),
+            Extension(make_req_state_with(|permission| permission.pull = true)),
+            Path("private".parse().unwrap()),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(Some(vec!["zstd".to_string()]), config.upload_encodings);
+    }
+
+    #[tokio::test]
+    async fn test_get_cache_public_key() {
+        let state = make_state().await;
+
+        // Tokens without pull permission can still configure substituters
+        let e = get_cache_config(
+            Extension(state.clone()),
+            Extension(make_req_state(true)),
+            Path("private".parse().unwrap()),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(StatusCode::FORBIDDEN, e.into_response().status());
+
+        let public_key = get_public_key(&state, true, "private").await.unwrap();
+        assert!(public_key.public_key.starts_with("private:"));
+        assert_eq!(
+            "https://attic.example.com/private",
+            public_key.substituter_endpoint
+        );
+        assert_eq!(41, public_key.priority);
+
+        // Anonymous clients can only discover public caches
+        get_public_key(&state, false, "public").await.unwrap();
+
+        let e = get_public_key(&state, false, "private").await.unwrap_err();
+        assert_eq!(StatusCode::UNAUTHORIZED, e.into_response().status());
+
+        // Caches outside the token don't leak their existence
+        let e = get_public_key(&state, true, "missing").await.unwrap_err();
+        assert_eq!(StatusCode::UNAUTHORIZED, e.into_response().status());
+    }
+
+    #[tokio::test]
+    async fn test_configure_chunking() {
+        let state = make_state().await;
+
+        let req_state = make_req_state_with(|permission| {
+            permission.pull = true;
+            permission.configure_cache = true;
+        });
+
+        let configure = |chunking: ChunkingConfig| {
+            let mut patch = CacheConfig::blank();
+            patch.chunking = Some(chunking);
+            configure_cache(
+                Extension(state.clone()),
+                Extension(req_state.clone()),
+                Path("private".parse().unwrap()),
+                Json(patch),
+            )
+        };
+
+        let get_chunking = || async {
+            let Json(config) = get_cache_config(
+                Extension(state.clone()),
+                Extension(req_state.clone()),
+                Path("private".parse().unwrap()),
+            )
+            .await
+            .unwrap();
+            config.chunking.unwrap()
+        };
+
+        let overrides = |min_size, max_size| {
+            ChunkingConfig::Override(ChunkingOverrides {
+                min_size,
+                max_size,
+                ..Default::default()
+            })
+        };
+
+        assert_eq!(ChunkingConfig::Global, get_chunking().await);
+
+        // Partial overrides are combined with the global avg-size of 65536
+        let e = configure(overrides(Some(131072), None)).await.unwrap_err();
+        assert_eq!(StatusCode::BAD_REQUEST, e.into_response().status());
+
+        configure(overrides(None, Some(1048576))).await.unwrap();
+        configure(overrides(Some(65536), None)).await.unwrap();
+        assert_eq!(overrides(Some(65536), Some(1048576)), get_chunking().await);
+
+        let db = state.database().await.unwrap();
+        let cache = db.find_cache(&"private".parse().unwrap()).await.unwrap();
+        let chunking = cache.chunking(&state.config.chunking);
+        assert_eq!(
+            (0, 65536, 65536, 1048576),
+            (
+                chunking.nar_size_threshold,
+                chunking.min_size,
+                chunking.avg_size,
+                chunking.max_size
+            )
+        );
+
+        configure(ChunkingConfig::Global).await.unwrap();
+        assert_eq!(ChunkingConfig::Global, get_chunking().await);
+    }
+
+    #[tokio::test]
+    async fn test_key_rotation() {
+        let rotate_and_get = |state: State| async move {
+            let req_state = make_req_state_with(|permission| {
+                permission.pull = true;
+                permission.configure_cache = true;
+            });
+
+            let mut patch = CacheConfig::blank();
+            patch.keypair = Some(KeypairConfig::Generate);
+            configure_cache(
+                Extension(state.clone()),
+                Extension(req_state.clone()),
+                Path("private".parse().unwrap()),
+                Json(patch),
+            )
+            .await
+            .unwrap();
+
+            let Json(config) = get_cache_config(
+                Extension(state),
+                Extension(req_state),
+                Path("private".parse().unwrap()),
+            )
+            .await
+            .unwrap();
+            config
+        };
+
+        let state = make_state_with(r#"key-rotation-grace-period = "1 day""#).await;
+        let db = state.database().await.unwrap();
+        let old = db
+            .find_cache(&"private".parse().unwrap())
+            .await
+            .unwrap()
+            .keypair()
+            .unwrap()
+            .export_public_key();
+
+        let config = rotate_and_get(state.clone()).await;
+        assert_ne!(Some(&old), config.public_key.as_ref());
+        assert_eq!(Some(vec![old.clone()]), config.previous_public_keys);
+
+        // The most recent keypair comes first
+        let middle = config.public_key.unwrap();
+        let config = rotate_and_get(state).await;
+        assert_eq!(Some(vec![middle, old]), config.previous_public_keys);
+
+        // Previous keypairs are discarded without a grace period
+        let config = rotate_and_get(make_state().await).await;
+        assert_eq!(Some(Vec::new()), config.previous_public_keys);
     }
 }
@@ -0,0 +1,46 @@
+//! Cache event stream endpoint.
+
+use std::time::Duration;
+
+use axum::extract::{Extension, Json, Path, Query};
+use tracing::instrument;
+
+use crate::error::ServerResult;
+use crate::events::wait_for_events;
+use crate::{RequestState, State};
+use attic::api::v1::cache_events::{CacheEvents, CacheEventsQuery};
+use attic::cache::CacheName;
+
+/// Maximum time to hold a request waiting for events.
+const MAX_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Returns events in a cache after a sequence number.
+///
+/// - GET `/_api/v1/cache/:cache/events?since=:seq&timeout=:secs`
+///
+/// If there are no such events, the request is held until one
+/// arrives or the timeout elapses.
+#[instrument(skip_all, fields(cache_name))]
+pub(crate) async fn get_cache_events(
+    Extension(state): Extension<State>,
+    Extension(req_state): Extension<RequestState>,
+    Path(cache_name): Path<CacheName>,
+    Query(query): Query<CacheEventsQuery>,
+) -> ServerResult<Json<CacheEvents>> {
+    let database = state.database().await?;
+    let cache = req_state
+        .auth
+        .auth_cache(database, &cache_name, |cache, permission| {
+            permission.require_pull()?;
+            Ok(cache)
+        })
+        .await?;
+
+    let since = query.since.unwrap_or(0);
+    let timeout = Duration::from_secs(query.timeout.unwrap_or(0)).min(MAX_TIMEOUT);
+
+    let events = wait_for_events(&state, cache.id, since, timeout).await?;
+    let last_seq = events.last().map(|event| event.seq).unwrap_or(since);
+
+    Ok(Json(CacheEvents { events, last_seq }))
+}
@@ -0,0 +1,337 @@
+//! Cache garbage collection endpoint.
+
+use std::time::Instant;
+
+use axum::extract::{Extension, Json, Path};
+use tracing::instrument;
+
+use crate::audit::{self, AuditEvent};
+use crate::database::entity::audit_log::AuditAction;
+use crate::error::{ErrorKind, ServerResult};
+use crate::gc::{count_expired_objects, run_cache_garbage_collection};
+use crate::{RequestState, State};
+use attic::api::v1::cache_gc::{CacheGcJob, CacheGcStatus};
+use attic::cache::CacheName;
+
+/// Maximum number of expired objects to collect within the request.
+///
+/// Larger runs continue in the background.
+const SYNC_OBJECT_LIMIT: u64 = 1000;
+
+/// Runs time-based garbage collection on a cache.
+///
+/// - POST `/_api/v1/cache/:cache/gc`
+#[instrument(skip_all, fields(cache_name))]
+pub(crate) async fn run_cache_gc(
+    Extension(state): Extension<State>,
+    Extension(req_state): Extension<RequestState>,
+    Path(cache_name): Path<CacheName>,
+) -> ServerResult<Json<CacheGcJob>> {
+    let database = state.database().await?;
+    let cache = req_state
+        .auth
+        .auth_cache(database, &cache_name, |cache, permission| {
+            permission.require_configure_cache_retention()?;
+            Ok(cache)
+        })
+        .await?;
+
+    let event = AuditEvent::new(AuditAction::CollectGarbage, &req_state, &cache_name);
+    audit::record(&state, event).await;
+
+    let cooldown = state.config.garbage_collection.cache_gc_cooldown;
+    let id = state
+        .cache_gc_jobs
+        .start(cache.id, cooldown, Instant::now())
+        .map_err(|retry_after| ErrorKind::RateLimited {
+            retry_after_secs: retry_after.as_secs().max(1),
+        })?;
+
+    let event = AuditEvent::new(AuditAction::CollectGarbage, &req_state, &cache_name)
+        .details(format!("job {}", id));
+    audit::record(&state, event).await;
+
+    let num_expired = match count_expired_objects(&state, &cache).await {
+        Ok(num_expired) => num_expired,
+        Err(e) => {
+            let status = state.cache_gc_jobs.finish(&id, &Err(e), Instant::now());
+            return Ok(Json(CacheGcJob { id, status }));
+        }
+    };
+
+    if num_expired <= SYNC_OBJECT_LIMIT {
+        let result = run_cache_garbage_collection(&state, &cache).await;
+        let status = state.cache_gc_jobs.finish(&id, &result, Instant::now());
+
+        return Ok(Json(CacheGcJob { id, status }));
+    }
+
+    tracing::info!(
+        "Collecting {} objects from {} in the background",
+        num_expired,
+        cache.name
+    );
+
+    let job_id = id.clone();
+    tokio::spawn(async move {
+        let result = run_cache_garbage_collection(&state, &cache).await;
+        if let Err(e) = &result {
+            tracing::warn!("Garbage collection on {} failed: {}", cache.name, e);
+        }
+
+        state.cache_gc_jobs.finish(&job_id, &result, Instant::now());
+    });
+
+    Ok(Json(CacheGcJob {
+        id,
+        status: CacheGcStatus::Running,
+    }))
+}
+
+/// Returns the status of a garbage collection job.
+///
+/// - GET `/_api/v1/cache/:cache/gc/:job`
+#[instrument(skip_all, fields(cache_name, job_id))]
+pub(crate) async fn get_cache_gc_job(
+    Extension(state): Extension<State>,
+    Extension(req_state): Extension<RequestState>,
+    Path((cache_name, job_id)): Path<(CacheName, String)>,
+) -> ServerResult<Json<CacheGcJob>> {
+    let database = state.database().await?;
+    let cache = req_state
+        .auth
+        .auth_cache(database, &cache_name, |cache, permission| {
+            permission.require_configure_cache_retention()?;
+            Ok(cache)
+        })
+        .await?;
+
+    let status = state
+        .cache_gc_jobs
+        .get(cache.id, &job_id)
+        .ok_or(ErrorKind::NotFound)?;
+
+    Ok(Json(CacheGcJob { id: job_id, status }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+    use chrono::{Duration as ChronoDuration, Utc};
+    use sea_orm::ActiveValue::Set;
+    use sea_orm::EntityTrait;
+
+    use crate::access::http::AuthState;
+    use crate::access::Token;
+    use crate::config::Config;
+    use crate::database::entity::cache::{self, Entity as Cache};
+    use crate::database::entity::nar::{self, Entity as Nar, NarState};
+    use crate::database::entity::object::{self, Entity as Object};
+    use crate::database::entity::Json as DbJson;
+    use crate::database::migration::{Migrator, MigratorTrait};
+    use crate::database::AtticDatabase;
+    use crate::{RequestStateInner, StateInner};
+
+    async fn make_state(cooldown: &str) -> State {
+        let config: Config = toml::from_str(&format!(
+            r#"
+[database]
+url = "sqlite::memory:"
+
+[storage]
+type = "local"
+path = "/nonexistent"
+
+[chunking]
+nar-size-threshold = 0
+min-size = 16384
+avg-size = 65536
+max-size = 262144
+
+[garbage-collection]
+cache-gc-cooldown = "{cooldown}"
+
+[jwt.signing]
+token-hs256-secret-base64 = "dmVyeSBzZWN1cmUgc2VjcmV0"
+"#
+        ))
+        .unwrap();
+
+        let state = StateInner::new(config).await;
+        let db = state.database().await.unwrap();
+        Migrator::up(db, None).await.unwrap();
+
+        let cache_id = Cache::insert(cache::ActiveModel {
+            name: Set("demo".to_string()),
+            keypair: Set(String::new()),
+            is_public: Set(true),
+            store_dir: Set("/nix/store".to_string()),
+            priority: Set(41),
+            upstream_cache_key_names: Set(DbJson(Vec::new())),
+            created_at: Set(Utc::now()),
+            retention_period: Set(Some(86400)),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap()
+        .last_insert_id;
+
+        let old = Utc::now() - ChronoDuration::days(30);
+        for (i, created_at) in [old, old, Utc::now()].into_iter().enumerate() {
+            let nar_id = Nar::insert(nar::ActiveModel {
+                state: Set(NarState::Valid),
+                nar_hash: Set(format!("sha256:{}", i)),
+                nar_size: Set(1000),
+                compression: Set("none".to_string()),
+                num_chunks: Set(0),
+                completeness_hint: Set(true),
+                holders_count: Set(0),
+                created_at: Set(created_at),
+                ..Default::default()
+            })
+            .exec(db)
+            .await
+            .unwrap()
+            .last_insert_id;
+
+            Object::insert(object::ActiveModel {
+                cache_id: Set(cache_id),
+                nar_id: Set(nar_id),
+                store_path_hash: Set(format!("{:0>32}", i)),
+                store_path: Set(format!("/nix/store/{:0>32}-test", i)),
+                references: Set(DbJson(Vec::new())),
+                sigs: Set(DbJson(Vec::new())),
+                created_at: Set(created_at),
+                ..Default::default()
+            })
+            .exec(db)
+            .await
+            .unwrap();
+        }
+
+        state
+    }
+
+    fn make_req_state(configure_cache: bool, configure_cache_retention: bool) -> RequestState {
+        let auth = AuthState::new();
+
+        let mut token = Token::new("meow".to_string(), &(Utc::now() + ChronoDuration::days(1)));
+        let permission = token.get_or_insert_permission_mut("demo".parse().unwrap());
+        permission.configure_cache = configure_cache;
+        permission.configure_cache_retention = configure_cache_retention;
+        auth.token.set(token).unwrap();
+
+        Arc::new(RequestStateInner {
+            auth,
+            api_endpoint: None,
+            substituter_endpoint: None,
+            host: "localhost".to_string(),
+            client_claims_https: false,
+            public_cache: AtomicBool::new(false),
+        })
+    }
+
+    async fn run(state: &State, req_state: &RequestState) -> ServerResult<CacheGcJob> {
+        run_cache_gc(
+            Extension(state.clone()),
+            Extension(req_state.clone()),
+            Path("demo".parse().unwrap()),
+        )
+        .await
+        .map(|Json(job)| job)
+    }
+
+    #[tokio::test]
+    async fn test_cache_gc_permission() {
+        let state = make_state("0s").await;
+
+        // Public caches can be pulled from but not garbage collected
+        let e = run(&state, &make_req_state(false, false))
+            .await
+            .unwrap_err();
+        assert_eq!(StatusCode::FORBIDDEN, e.into_response().status());
+
+        let e = run(&state, &make_req_state(true, false)).await.unwrap_err();
+        assert_eq!(StatusCode::FORBIDDEN, e.into_response().status());
+
+        let job = run(&state, &make_req_state(false, true)).await.unwrap();
+        assert_eq!(
+            CacheGcStatus::Completed {
+                objects_deleted: 2,
+                bytes_freed: 2000,
+            },
+            job.status
+        );
+
+        // The result can be polled
+        let Json(polled) = get_cache_gc_job(
+            Extension(state.clone()),
+            Extension(make_req_state(false, true)),
+            Path(("demo".parse().unwrap(), job.id.clone())),
+        )
+        .await
+        .unwrap();
+        assert_eq!(job, polled);
+
+        let e = get_cache_gc_job(
+            Extension(state.clone()),
+            Extension(make_req_state(true, false)),
+            Path(("demo".parse().unwrap(), job.id.clone())),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(StatusCode::FORBIDDEN, e.into_response().status());
+
+        // Nothing left to collect
+        let job = run(&state, &make_req_state(false, true)).await.unwrap();
+        assert_eq!(
+            CacheGcStatus::Completed {
+                objects_deleted: 0,
+                bytes_freed: 0,
+            },
+            job.status
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cache_gc_events() {
+        use crate::events::query_events;
+        use attic::api::v1::cache_events::CacheEventKind;
+
+        let state = make_state("0s").await;
+        run(&state, &make_req_state(false, true)).await.unwrap();
+
+        let db = state.database().await.unwrap();
+        let cache = db.find_cache(&"demo".parse().unwrap()).await.unwrap();
+        let events = query_events(db, cache.id, 0).await.unwrap();
+
+        let mut deleted: Vec<_> = events
+            .iter()
+            .map(|e| {
+                assert_eq!(CacheEventKind::Delete, e.kind);
+                e.store_path_hash.as_str()
+            })
+            .collect();
+        deleted.sort();
+
+        assert_eq!(vec![format!("{:0>32}", 0), format!("{:0>32}", 1)], deleted);
+    }
+
+    #[tokio::test]
+    async fn test_cache_gc_rate_limit() {
+        let state = make_state("10 minutes").await;
+        let req_state = make_req_state(false, true);
+
+        run(&state, &req_state).await.unwrap();
+
+        let e = run(&state, &req_state).await.unwrap_err();
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, e.into_response().status());
+    }
+}
@@ -0,0 +1,547 @@
+//! Path deletion endpoint.
+
+use std::collections::HashSet;
+
+use anyhow::anyhow;
+use axum::extract::{Extension, Json, Path, Query};
+use chrono::{DateTime, Utc};
+use sea_orm::entity::prelude::*;
+use sea_orm::sea_query::{Expr, LikeExpr};
+use sea_orm::{Condition, ConnectionTrait, QuerySelect, TransactionTrait};
+use tracing::instrument;
+
+use crate::audit::{self, AuditEvent};
+use crate::database::entity::audit_log::AuditAction;
+use crate::database::entity::event::EventKind;
+use crate::database::entity::object::{self, Entity as Object};
+use crate::error::{ErrorKind, ServerError, ServerResult};
+use crate::events::append_events;
+use crate::{RequestState, State};
+use attic::api::v1::delete_path::{
+    DeleteObjectsRequest, DeleteObjectsResult, DeletePathQuery, DeletePathResult,
+};
+use attic::cache::CacheName;
+use attic::nix_store::StorePathHash;
+
+/// The length of the hash portion of a store path.
+const STORE_PATH_HASH_LEN: usize = 32;
+
+/// Deletes a path from a cache.
+///
+/// - DELETE `/_api/v1/cache/:cache/path/:store_path_hash?with_referrers=true`
+///
+/// With `with_referrers`, paths referencing the path are deleted
+/// as well, recursively. Either all paths are deleted or none.
+#[instrument(skip_all, fields(cache_name, store_path_hash))]
+pub(crate) async fn delete_path(
+    Extension(state): Extension<State>,
+    Extension(req_state): Extension<RequestState>,
+    Path((cache_name, store_path_hash)): Path<(CacheName, String)>,
+    Query(query): Query<DeletePathQuery>,
+) -> ServerResult<Json<DeletePathResult>> {
+    let store_path_hash = StorePathHash::new(store_path_hash)?;
+
+    let database = state.database().await?;
+    let cache = req_state
+        .auth
+        .auth_cache(database, &cache_name, |cache, permission| {
+            permission.require_delete()?;
+            Ok(cache)
+        })
+        .await?;
+
+    let txn = database
+        .begin()
+        .await
+        .map_err(ServerError::database_error)?;
+
+    let deleted = delete_objects(&txn, cache.id, &store_path_hash, query.with_referrers).await?;
+
+    let store_path_hashes = deleted
+        .iter()
+        .map(|object| object.store_path_hash.clone())
+        .collect();
+    let event_seq = append_events(&txn, cache.id, EventKind::Delete, store_path_hashes).await?;
+
+    txn.commit().await.map_err(ServerError::database_error)?;
+    state.cache_events.notify(cache.id, event_seq);
+    state.narinfo_cache.invalidate(
+        &cache_name,
+        deleted.iter().map(|object| &object.store_path_hash),
+    );
+
+    let mut deleted_paths = Vec::new();
+    for object in deleted {
+        let event = AuditEvent::new(AuditAction::DeletePath, &req_state, &cache_name)
+            .store_path(object.store_path.clone());
+        audit::record(&state, event).await;
+
+        deleted_paths.push(object.store_path);
+    }
+
+    Ok(Json(DeletePathResult { deleted_paths }))
+}
+
+/// A deleted object.
+#[derive(Debug)]
+struct DeletedObject {
+    store_path_hash: String,
+    store_path: String,
+}
+
+/// Deletes an object and optionally its referrers from a cache.
+///
+/// This should be called in a transaction. The deleted objects are
+/// returned with the requested one first.
+async fn delete_objects<C: ConnectionTrait>(
+    conn: &C,
+    cache_id: i64,
+    store_path_hash: &StorePathHash,
+    with_referrers: bool,
+) -> ServerResult<Vec<DeletedObject>> {
+    let root: Option<(i64, String, String)> = Object::find()
+        .select_only()
+        .column(object::Column::Id)
+        .column(object::Column::StorePathHash)
+        .column(object::Column::StorePath)
+        .filter(object::Column::CacheId.eq(cache_id))
+        .filter(object::Column::StorePathHash.eq(store_path_hash.as_str()))
+        .lock_exclusive()
+        .into_tuple()
+        .one(conn)
+        .await
+        .map_err(ServerError::database_error)?;
+
+    let root = root.ok_or(ErrorKind::NoSuchObject)?;
+
+    let mut ids = HashSet::from([root.0]);
+    let mut deleted = vec![DeletedObject {
+        store_path_hash: root.1.clone(),
+        store_path: root.2,
+    }];
+
+    let mut pending = if with_referrers {
+        vec![root.1]
+    } else {
+        Vec::new()
+    };
+
+    while let Some(hash) = pending.pop() {
+        // References are stored as a JSON array of base names
+        let referrers: Vec<(i64, String, String)> = Object::find()
+            .select_only()
+            .column(object::Column::Id)
+            .column(object::Column::StorePathHash)
+            .column(object::Column::StorePath)
+            .filter(object::Column::CacheId.eq(cache_id))
+            .filter(object::Column::References.contains(format!("\"{}-", hash)))
+            .lock_exclusive()
+            .into_tuple()
+            .all(conn)
+            .await
+            .map_err(ServerError::database_error)?;
+
+        for (id, store_path_hash, store_path) in referrers {
+            if ids.insert(id) {
+                pending.push(store_path_hash.clone());
+                deleted.push(DeletedObject {
+                    store_path_hash,
+                    store_path,
+                });
+            }
+        }
+    }
+
+    Object::delete_many()
+        .filter(object::Column::Id.is_in(ids))
+        .exec(conn)
+        .await
+        .map_err(ServerError::database_error)?;
+
+    Ok(deleted)
+}
+
+/// Deletes all objects matching a filter from a cache.
+///
+/// - POST `/_api/v1/cache/:cache/delete-objects`
+///
+/// Only the objects are deleted. NARs and chunks no longer
+/// referenced are left for garbage collection to clean up.
+#[instrument(skip_all, fields(cache_name))]
+pub(crate) async fn delete_objects_matching(
+    Extension(state): Extension<State>,
+    Extension(req_state): Extension<RequestState>,
+    Path(cache_name): Path<CacheName>,
+    Json(request): Json<DeleteObjectsRequest>,
+) -> ServerResult<Json<DeleteObjectsResult>> {
+    let filter = objects_filter(&request)?;
+
+    let database = state.database().await?;
+    let cache = req_state
+        .auth
+        .auth_cache(database, &cache_name, |cache, permission| {
+            permission.require_delete()?;
+            Ok(cache)
+        })
+        .await?;
+
+    let txn = database
+        .begin()
+        .await
+        .map_err(ServerError::database_error)?;
+
+    let filter = filter.add(object::Column::CacheId.eq(cache.id));
+
+    // The hashes are needed for the event stream
+    let store_path_hashes: Vec<String> = Object::find()
+        .select_only()
+        .column(object::Column::StorePathHash)
+        .filter(filter.clone())
+        .lock_exclusive()
+        .into_tuple()
+        .all(&txn)
+        .await
+        .map_err(ServerError::database_error)?;
+
+    let num_deleted = store_path_hashes.len() as u64;
+
+    if request.dry_run || num_deleted == 0 {
+        txn.rollback().await.map_err(ServerError::database_error)?;
+        return Ok(Json(DeleteObjectsResult { num_deleted }));
+    }
+
+    Object::delete_many()
+        .filter(filter)
+        .exec(&txn)
+        .await
+        .map_err(ServerError::database_error)?;
+
+    let event_seq = append_events(&txn, cache.id, EventKind::Delete, store_path_hashes).await?;
+
+    txn.commit().await.map_err(ServerError::database_error)?;
+    state.cache_events.notify(cache.id, event_seq);
+    state.narinfo_cache.invalidate_cache(&cache_name);
+
+    let event = AuditEvent::new(AuditAction::DeleteObjects, &req_state, &cache_name)
+        .details(describe_request(&request, num_deleted));
+    audit::record(&state, event).await;
+
+    Ok(Json(DeleteObjectsResult { num_deleted }))
+}
+
+/// Returns the condition selecting objects matching a bulk deletion request.
+///
+/// Requests without any filter are rejected so a mistake can't empty
+/// a cache.
+fn objects_filter(request: &DeleteObjectsRequest) -> ServerResult<Condition> {
+    let mut filter = Condition::all();
+    let mut has_filter = false;
+
+    if let Some(name_glob) = &request.name_glob {
+        // Match the name after the hash, regardless of the store directory
+        let pattern = format!(
+            "%/{}-{}",
+            "_".repeat(STORE_PATH_HASH_LEN),
+            glob_to_like(name_glob)
+        );
+        filter = filter.add(
+            Expr::col((Object, object::Column::StorePath))
+                .like(LikeExpr::new(pattern).escape('\\')),
+        );
+        has_filter = true;
+    }
+
+    if let Some(created_before) = request.created_before {
+        let created_before = DateTime::<Utc>::from_timestamp(created_before, 0)
+            .ok_or_else(|| ErrorKind::RequestError(anyhow!("created_before is out of range")))?;
+        filter = filter.add(object::Column::CreatedAt.lt(created_before));
+        has_filter = true;
+    }
+
+    if let Some(created_by) = &request.created_by {
+        filter = filter.add(object::Column::CreatedBy.eq(created_by.as_str()));
+        has_filter = true;
+    }
+
+    if !has_filter {
+        return Err(ErrorKind::RequestError(anyhow!("At least one filter must be set")).into());
+    }
+
+    Ok(filter)
+}
+
+/// Converts a shell-style glob to a LIKE pattern.
+fn glob_to_like(glob: &str) -> String {
+    let mut pattern = String::with_capacity(glob.len());
+    for c in glob.chars() {
+        match c {
+            '*' => pattern.push('%'),
+            '?' => pattern.push('_'),
+            '%' | '_' | '\\' => {
+                pattern.push('\\');
+                pattern.push(c);
+            }
+            _ => pattern.push(c),
+        }
+    }
+    pattern
+}
+
+fn describe_request(request: &DeleteObjectsRequest, num_deleted: u64) -> String {
+    let mut filters = Vec::new();
+
+    if let Some(name_glob) = &request.name_glob {
+        filters.push(format!("name-glob={}", name_glob));
+    }
+    if let Some(created_before) = request.created_before {
+        filters.push(format!("created-before={}", created_before));
+    }
+    if let Some(created_by) = &request.created_by {
+        filters.push(format!("created-by={}", created_by));
+    }
+
+    format!("{} objects matching {}", num_deleted, filters.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::Utc;
+    use sea_orm::ActiveValue::Set;
+    use uuid::Uuid;
+
+    use crate::config::Config;
+    use crate::database::entity::cache::{self, Entity as Cache};
+    use crate::database::entity::nar::{self, Entity as Nar, NarState};
+    use crate::database::entity::Json as DbJson;
+    use crate::database::migration::{Migrator, MigratorTrait};
+    use crate::StateInner;
+
+    async fn make_state() -> State {
+        let storage_path = std::env::temp_dir().join(format!("attic-test-{}", Uuid::new_v4()));
+        let storage_path = storage_path.display();
+
+        let config = format!(
+            r#"
+[database]
+url = "sqlite::memory:"
+
+[storage]
+type = "local"
+path = "{storage_path}"
+
+[chunking]
+nar-size-threshold = 0
+min-size = 16384
+avg-size = 65536
+max-size = 262144
+
+[jwt.signing]
+token-hs256-secret-base64 = "dmVyeSBzZWN1cmUgc2VjcmV0"
+"#
+        );
+
+        let config: Config = toml::from_str(&config).unwrap();
+        let state = StateInner::new(config).await;
+
+        let db = state.database().await.unwrap();
+        Migrator::up(db, None).await.unwrap();
+
+        state
+    }
+
+    async fn insert_cache(state: &State, name: &str) -> i64 {
+        let db = state.database().await.unwrap();
+        Cache::insert(cache::ActiveModel {
+            name: Set(name.to_string()),
+            keypair: Set(String::new()),
+            is_public: Set(false),
+            store_dir: Set("/nix/store".to_string()),
+            priority: Set(41),
+            upstream_cache_key_names: Set(DbJson(Vec::new())),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap()
+        .last_insert_id
+    }
+
+    async fn insert_object(state: &State, cache_id: i64, base_name: &str, references: &[&str]) {
+        let db = state.database().await.unwrap();
+        let nar_id = Nar::insert(nar::ActiveModel {
+            state: Set(NarState::Valid),
+            nar_hash: Set(format!("sha256:{}", base_name)),
+            nar_size: Set(0),
+            compression: Set("none".to_string()),
+            num_chunks: Set(0),
+            completeness_hint: Set(true),
+            holders_count: Set(0),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap()
+        .last_insert_id;
+
+        Object::insert(object::ActiveModel {
+            cache_id: Set(cache_id),
+            nar_id: Set(nar_id),
+            store_path_hash: Set(base_name[..32].to_string()),
+            store_path: Set(format!("/nix/store/{}", base_name)),
+            references: Set(DbJson(references.iter().map(|r| r.to_string()).collect())),
+            sigs: Set(DbJson(Vec::new())),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap();
+    }
+
+    async fn count_objects(state: &State, cache_id: i64) -> u64 {
+        let db = state.database().await.unwrap();
+        Object::find()
+            .filter(object::Column::CacheId.eq(cache_id))
+            .count(db)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_delete_objects_with_referrers() {
+        let glibc = "563528481rvhc5kxwipjmg6rqrl95mdx-glibc-2.33-56";
+        let hello = "xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10";
+        let env = "vvb4wxmnjixmrkhmj2xb75z62hrr41i7-env";
+        let other = "ia70ss13m22znbl8khrf2hq72qmh5drr-ruby-2.7.5";
+
+        let state = make_state().await;
+        let main = insert_cache(&state, "main").await;
+        let unrelated = insert_cache(&state, "unrelated").await;
+
+        insert_object(&state, main, glibc, &[glibc]).await;
+        insert_object(&state, main, hello, &[glibc, hello]).await;
+        insert_object(&state, main, env, &[hello]).await;
+        insert_object(&state, main, other, &[]).await;
+        insert_object(&state, unrelated, hello, &[glibc, hello]).await;
+
+        let db = state.database().await.unwrap();
+        let hash = |base_name: &str| StorePathHash::new(base_name[..32].to_string()).unwrap();
+
+        // Without referrers
+        let txn = db.begin().await.unwrap();
+        let deleted = delete_objects(&txn, main, &hash(glibc), false)
+            .await
+            .unwrap();
+        assert_eq!(1, deleted.len());
+        txn.rollback().await.unwrap();
+
+        // With referrers
+        let txn = db.begin().await.unwrap();
+        let deleted = delete_objects(&txn, main, &hash(glibc), true)
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+
+        let deleted: Vec<_> = deleted.into_iter().map(|o| o.store_path).collect();
+        assert_eq!(
+            vec![
+                format!("/nix/store/{}", glibc),
+                format!("/nix/store/{}", hello),
+                format!("/nix/store/{}", env),
+            ],
+            deleted
+        );
+
+        assert_eq!(1, count_objects(&state, main).await);
+        assert_eq!(1, count_objects(&state, unrelated).await);
+
+        // Already deleted
+        let txn = db.begin().await.unwrap();
+        assert!(delete_objects(&txn, main, &hash(glibc), true)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_objects_filter() {
+        let glibc = "563528481rvhc5kxwipjmg6rqrl95mdx-glibc-2.33-56";
+        let hello = "xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10";
+        let hello_env = "vvb4wxmnjixmrkhmj2xb75z62hrr41i7-hello_env";
+        let other = "ia70ss13m22znbl8khrf2hq72qmh5drr-hello-2.10";
+
+        let state = make_state().await;
+        let main = insert_cache(&state, "main").await;
+        let unrelated = insert_cache(&state, "unrelated").await;
+
+        insert_object(&state, main, glibc, &[]).await;
+        insert_object(&state, main, hello, &[]).await;
+        insert_object(&state, main, hello_env, &[]).await;
+        insert_object(&state, unrelated, other, &[]).await;
+
+        let db = state.database().await.unwrap();
+        let matching = |request: DeleteObjectsRequest| async move {
+            let filter = objects_filter(&request)
+                .unwrap()
+                .add(object::Column::CacheId.eq(main));
+            let mut names: Vec<String> = Object::find()
+                .select_only()
+                .column(object::Column::StorePath)
+                .filter(filter)
+                .into_tuple()
+                .all(db)
+                .await
+                .unwrap();
+            names.sort();
+            names
+        };
+
+        let by_glob = matching(DeleteObjectsRequest {
+            name_glob: Some("hello-*".to_string()),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(vec![format!("/nix/store/{}", hello)], by_glob);
+
+        // `_` is matched literally
+        let by_underscore = matching(DeleteObjectsRequest {
+            name_glob: Some("hello_*".to_string()),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(vec![format!("/nix/store/{}", hello_env)], by_underscore);
+
+        // The glob must match the whole name
+        let by_partial = matching(DeleteObjectsRequest {
+            name_glob: Some("2.10".to_string()),
+            ..Default::default()
+        })
+        .await;
+        assert!(by_partial.is_empty());
+
+        let by_time = matching(DeleteObjectsRequest {
+            created_before: Some(Utc::now().timestamp() + 60),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(3, by_time.len());
+
+        let by_old_time = matching(DeleteObjectsRequest {
+            name_glob: Some("*".to_string()),
+            created_before: Some(0),
+            ..Default::default()
+        })
+        .await;
+        assert!(by_old_time.is_empty());
+
+        assert!(objects_filter(&DeleteObjectsRequest::default()).is_err());
+        assert!(objects_filter(&DeleteObjectsRequest {
+            dry_run: true,
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
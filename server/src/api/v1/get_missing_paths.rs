@@ -70,3 +70,213 @@ pub(crate) async fn get_missing_paths(
 
     Ok(Json(GetMissingPathsResponse { missing_paths }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    use chrono::{Duration as ChronoDuration, Utc};
+    use sea_orm::ActiveValue::Set;
+    use sea_orm::TransactionTrait;
+    use uuid::Uuid;
+
+    use crate::access::http::AuthState;
+    use crate::access::Token;
+    use crate::config::Config;
+    use crate::database::entity::cache::Entity as Cache;
+    use crate::database::entity::chunk::{self, ChunkState, Entity as Chunk};
+    use crate::database::entity::chunkref;
+    use crate::database::entity::nar::{Entity as Nar, NarState};
+    use crate::database::entity::Json as DbJson;
+    use crate::database::insert_chunkref;
+    use crate::database::migration::{Migrator, MigratorTrait};
+    use crate::verify::{verify_chunks, ChunkVerificationOptions};
+    use crate::{RequestStateInner, StateInner};
+
+    const STORE_PATH_HASH: &str = "xcp9cav49dmsjbwdjlmkjxj10gkpx553";
+
+    async fn make_state() -> State {
+        let storage_path = std::env::temp_dir().join(format!("attic-test-{}", Uuid::new_v4()));
+
+        let config: Config = toml::from_str(&format!(
+            r#"
+[database]
+url = "sqlite::memory:"
+
+[storage]
+type = "local"
+path = "{}"
+
+[chunking]
+nar-size-threshold = 0
+min-size = 16384
+avg-size = 65536
+max-size = 262144
+
+[jwt.signing]
+token-hs256-secret-base64 = "dmVyeSBzZWN1cmUgc2VjcmV0"
+"#,
+            storage_path.display()
+        ))
+        .unwrap();
+
+        let state = StateInner::new(config).await;
+        let db = state.database().await.unwrap();
+        Migrator::up(db, None).await.unwrap();
+
+        state
+    }
+
+    fn make_req_state() -> RequestState {
+        let auth = AuthState::new();
+
+        let mut token = Token::new("meow".to_string(), &(Utc::now() + ChronoDuration::days(1)));
+        let permission = token.get_or_insert_permission_mut("demo".parse().unwrap());
+        permission.push = true;
+        auth.token.set(token).unwrap();
+
+        Arc::new(RequestStateInner {
+            auth,
+            api_endpoint: None,
+            substituter_endpoint: None,
+            host: "localhost".to_string(),
+            client_claims_https: false,
+            public_cache: AtomicBool::new(false),
+        })
+    }
+
+    /// Inserts a path whose only chunk has been deleted from storage.
+    async fn insert_object_with_deleted_chunk(state: &State) {
+        let db = state.database().await.unwrap();
+        let storage = state.storage().await.unwrap();
+        let txn = db.begin().await.unwrap();
+
+        let file = storage
+            .upload_file("deleted.chunk".to_string(), &mut &b"hello"[..])
+            .await
+            .unwrap();
+        storage.delete_file_db(&file).await.unwrap();
+
+        let cache_id = Cache::insert(cache::ActiveModel {
+            name: Set("demo".to_string()),
+            keypair: Set(String::new()),
+            is_public: Set(false),
+            store_dir: Set("/nix/store".to_string()),
+            priority: Set(41),
+            upstream_cache_key_names: Set(DbJson(Vec::new())),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(&txn)
+        .await
+        .unwrap()
+        .last_insert_id;
+
+        let chunk_id = Chunk::insert(chunk::ActiveModel {
+            state: Set(ChunkState::Valid),
+            chunk_hash: Set(format!("sha256:{}", Uuid::new_v4())),
+            chunk_size: Set(5),
+            file_hash: Set(Some(format!("sha256:{}", Uuid::new_v4()))),
+            file_size: Set(Some(5)),
+            compression: Set("none".to_string()),
+            remote_file_id: Set(file.remote_file_id()),
+            remote_file: Set(DbJson(file)),
+            holders_count: Set(0),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(&txn)
+        .await
+        .unwrap()
+        .last_insert_id;
+
+        let nar_id = Nar::insert(nar::ActiveModel {
+            state: Set(NarState::Valid),
+            nar_hash: Set(format!("sha256:{}", Uuid::new_v4())),
+            nar_size: Set(5),
+            compression: Set("none".to_string()),
+            num_chunks: Set(1),
+            completeness_hint: Set(true),
+            holders_count: Set(0),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(&txn)
+        .await
+        .unwrap()
+        .last_insert_id;
+
+        insert_chunkref(
+            &txn,
+            chunkref::ActiveModel {
+                nar_id: Set(nar_id),
+                seq: Set(0),
+                chunk_id: Set(Some(chunk_id)),
+                chunk_hash: Set(String::new()),
+                compression: Set("none".to_string()),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        Object::insert(object::ActiveModel {
+            cache_id: Set(cache_id),
+            nar_id: Set(nar_id),
+            store_path_hash: Set(STORE_PATH_HASH.to_string()),
+            store_path: Set(format!("/nix/store/{}-hello-2.10", STORE_PATH_HASH)),
+            references: Set(DbJson(Vec::new())),
+            sigs: Set(DbJson(Vec::new())),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(&txn)
+        .await
+        .unwrap();
+
+        txn.commit().await.unwrap();
+    }
+
+    async fn missing_paths(state: &State) -> Vec<StorePathHash> {
+        let Json(response) = get_missing_paths(
+            Extension(state.clone()),
+            Extension(make_req_state()),
+            Json(GetMissingPathsRequest {
+                cache: "demo".parse().unwrap(),
+                store_path_hashes: vec![StorePathHash::new(STORE_PATH_HASH.to_string()).unwrap()],
+            }),
+        )
+        .await
+        .unwrap();
+
+        response.missing_paths
+    }
+
+    #[tokio::test]
+    async fn test_missing_after_chunk_verification() {
+        let state = make_state().await;
+        insert_object_with_deleted_chunk(&state).await;
+
+        // Not noticed until the chunks are verified
+        assert!(missing_paths(&state).await.is_empty());
+
+        let options = ChunkVerificationOptions {
+            cache: None,
+            start_after: 0,
+            concurrency: 1,
+            dry_run: false,
+            sample: None,
+        };
+        let report = verify_chunks(&state, options, |_| Ok(())).await.unwrap();
+        assert_eq!(1, report.missing.len());
+
+        let missing = missing_paths(&state).await;
+        assert_eq!(
+            vec![STORE_PATH_HASH],
+            missing.iter().map(|h| h.as_str()).collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,203 @@
+//! Object metadata endpoint.
+
+use axum::extract::{Extension, Json, Path};
+use tracing::instrument;
+
+use crate::database::AtticDatabase;
+use crate::error::ServerResult;
+use crate::{RequestState, State};
+use attic::api::v1::list_objects::ObjectInfo;
+use attic::cache::CacheName;
+use attic::nix_store::StorePathHash;
+
+/// Returns the metadata of an object in a cache.
+///
+/// - GET `/_api/v1/cache/:cache/path/:store_path_hash`
+///
+/// Unlike the narinfo, this includes who uploaded the object and
+/// when. It requires the same `pull` permission as the narinfo.
+#[instrument(skip_all, fields(cache_name, store_path_hash))]
+pub(crate) async fn get_object_info(
+    Extension(state): Extension<State>,
+    Extension(req_state): Extension<RequestState>,
+    Path((cache_name, store_path_hash)): Path<(CacheName, String)>,
+) -> ServerResult<Json<ObjectInfo>> {
+    let store_path_hash = StorePathHash::new(store_path_hash)?;
+
+    let database = state.database().await?;
+    req_state
+        .auth
+        .auth_cache(database, &cache_name, |_, permission| {
+            permission.require_pull()?;
+            Ok(())
+        })
+        .await?;
+
+    let (object, _, nar, _) = database
+        .find_object_and_chunks_by_store_path_hash(&cache_name, &store_path_hash, false)
+        .await?;
+
+    Ok(Json(ObjectInfo {
+        store_path: object.store_path,
+        nar_size: nar.nar_size as u64,
+        num_chunks: Some(nar.num_chunks as u64),
+        created_at: object.created_at.timestamp(),
+        created_by: object.created_by,
+        last_accessed_at: object.last_accessed_at.map(|t| t.timestamp()),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+    use chrono::{Duration as ChronoDuration, Utc};
+    use sea_orm::ActiveValue::Set;
+    use sea_orm::EntityTrait;
+
+    use crate::access::http::AuthState;
+    use crate::access::{CachePermission, Token};
+    use crate::config::Config;
+    use crate::database::entity::cache::{self, Entity as Cache};
+    use crate::database::entity::nar::{self, Entity as Nar, NarState};
+    use crate::database::entity::object::{self, Entity as Object};
+    use crate::database::entity::Json as DbJson;
+    use crate::database::migration::{Migrator, MigratorTrait};
+    use crate::{RequestStateInner, StateInner};
+
+    const BASE_NAME: &str = "xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10";
+
+    async fn make_state() -> State {
+        let config: Config = toml::from_str(
+            r#"
+[database]
+url = "sqlite::memory:"
+
+[storage]
+type = "local"
+path = "/nonexistent"
+
+[chunking]
+nar-size-threshold = 0
+min-size = 16384
+avg-size = 65536
+max-size = 262144
+
+[jwt.signing]
+token-hs256-secret-base64 = "dmVyeSBzZWN1cmUgc2VjcmV0"
+"#,
+        )
+        .unwrap();
+
+        let state = StateInner::new(config).await;
+        let db = state.database().await.unwrap();
+        Migrator::up(db, None).await.unwrap();
+
+        let cache_id = Cache::insert(cache::ActiveModel {
+            name: Set("private".to_string()),
+            keypair: Set(String::new()),
+            is_public: Set(false),
+            store_dir: Set("/nix/store".to_string()),
+            priority: Set(41),
+            upstream_cache_key_names: Set(DbJson(Vec::new())),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap()
+        .last_insert_id;
+
+        let nar_id = Nar::insert(nar::ActiveModel {
+            state: Set(NarState::Valid),
+            nar_hash: Set(format!("sha256:{}", BASE_NAME)),
+            nar_size: Set(1234),
+            compression: Set("none".to_string()),
+            num_chunks: Set(0),
+            completeness_hint: Set(true),
+            holders_count: Set(0),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap()
+        .last_insert_id;
+
+        Object::insert(object::ActiveModel {
+            cache_id: Set(cache_id),
+            nar_id: Set(nar_id),
+            store_path_hash: Set(BASE_NAME[..32].to_string()),
+            store_path: Set(format!("/nix/store/{}", BASE_NAME)),
+            references: Set(DbJson(Vec::new())),
+            sigs: Set(DbJson(Vec::new())),
+            created_at: Set(chrono::DateTime::from_timestamp(1000, 0).unwrap()),
+            created_by: Set(Some("alice".to_string())),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap();
+
+        state
+    }
+
+    fn make_req_state_with(grant: impl FnOnce(&mut CachePermission)) -> RequestState {
+        let mut token = Token::new("meow".to_string(), &(Utc::now() + ChronoDuration::days(1)));
+        grant(token.get_or_insert_permission_mut("private".parse().unwrap()));
+
+        let req_state = Arc::new(RequestStateInner {
+            auth: AuthState::new(),
+            api_endpoint: Some("https://attic.example.com/".to_string()),
+            substituter_endpoint: None,
+            host: "localhost".to_string(),
+            client_claims_https: false,
+            public_cache: AtomicBool::new(false),
+        });
+        req_state.auth.token.set(token).unwrap();
+        req_state
+    }
+
+    async fn get(
+        state: &State,
+        req_state: RequestState,
+        store_path_hash: &str,
+    ) -> ServerResult<ObjectInfo> {
+        get_object_info(
+            Extension(state.clone()),
+            Extension(req_state),
+            Path(("private".parse().unwrap(), store_path_hash.to_string())),
+        )
+        .await
+        .map(|Json(info)| info)
+    }
+
+    #[tokio::test]
+    async fn test_get_object_info() {
+        let state = make_state().await;
+        let puller = || make_req_state_with(|permission| permission.pull = true);
+
+        let info = get(&state, puller(), &BASE_NAME[..32]).await.unwrap();
+        assert_eq!(format!("/nix/store/{}", BASE_NAME), info.store_path);
+        assert_eq!(1234, info.nar_size);
+        assert_eq!(Some(0), info.num_chunks);
+        assert_eq!(1000, info.created_at);
+        assert_eq!(Some("alice".to_string()), info.created_by);
+        assert_eq!(None, info.last_accessed_at);
+
+        let e = get(&state, puller(), "00000000000000000000000000000000")
+            .await
+            .unwrap_err();
+        assert_eq!(StatusCode::NOT_FOUND, e.into_response().status());
+
+        // Pushing alone doesn't allow reading attribution
+        let pusher = make_req_state_with(|permission| permission.push = true);
+        let e = get(&state, pusher, &BASE_NAME[..32]).await.unwrap_err();
+        assert_eq!(StatusCode::FORBIDDEN, e.into_response().status());
+    }
+}
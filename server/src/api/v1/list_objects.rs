@@ -0,0 +1,581 @@
+//! Object listing endpoint.
+
+use anyhow::anyhow;
+use axum::extract::{Extension, Json, Path, Query};
+use chrono::{DateTime, Utc};
+use sea_orm::entity::prelude::*;
+use sea_orm::sea_query::{Expr, LikeExpr};
+use sea_orm::{Condition, FromQueryResult, JoinType, QueryOrder, QuerySelect};
+use tracing::instrument;
+
+use crate::database::entity::nar;
+use crate::database::entity::object::{self, Entity as Object};
+use crate::error::{ErrorKind, ServerError, ServerResult};
+use crate::{RequestState, State};
+use attic::api::v1::list_objects::{
+    CacheObjectsPage, CacheObjectsQuery, ListObjectsQuery, ObjectInfo, ObjectList,
+};
+use attic::cache::CacheName;
+
+/// The number of objects returned if the client doesn't specify a limit.
+const DEFAULT_LIMIT: u64 = 100;
+
+/// The maximum number of objects returned in a single page.
+const MAX_LIMIT: u64 = 1000;
+
+#[derive(Debug, FromQueryResult)]
+struct ObjectRow {
+    id: i64,
+    store_path: String,
+    nar_size: i64,
+    num_chunks: i32,
+    created_at: DateTime<Utc>,
+    created_by: Option<String>,
+    last_accessed_at: Option<DateTime<Utc>>,
+}
+
+/// Lists objects in a cache.
+///
+/// - GET `/_api/v1/cache/:cache/objects?name=:substring&cursor=:cursor`
+///
+/// Objects are returned in the order they were first uploaded. Pass
+/// `next_cursor` back as `cursor` to get the next page.
+#[instrument(skip_all, fields(cache_name))]
+pub(crate) async fn list_objects(
+    Extension(state): Extension<State>,
+    Extension(req_state): Extension<RequestState>,
+    Path(cache_name): Path<CacheName>,
+    Query(query): Query<ListObjectsQuery>,
+) -> ServerResult<Json<ObjectList>> {
+    let database = state.database().await?;
+    let cache = req_state
+        .auth
+        .auth_cache(database, &cache_name, |cache, permission| {
+            permission.require_pull()?;
+            Ok(cache)
+        })
+        .await?;
+
+    let list = query_objects(database, cache.id, &query).await?;
+
+    Ok(Json(list))
+}
+
+/// Returns a page of objects in a cache matching the filters.
+///
+/// The scan walks `idx-object-cache-id` and stops once a page is
+/// filled. The name filter is a substring match and cannot use an
+/// index, so sparse matches in a large cache still cost a longer scan.
+async fn query_objects(
+    database: &impl ConnectionTrait,
+    cache_id: i64,
+    query: &ListObjectsQuery,
+) -> ServerResult<ObjectList> {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let mut select = select_objects(cache_id)
+        .order_by_asc(object::Column::Id)
+        // Fetch one extra row to tell whether there is a next page
+        .limit(limit + 1);
+
+    if let Some(cursor) = query.cursor {
+        select = select.filter(object::Column::Id.gt(cursor));
+    }
+
+    if let Some(name) = &query.name {
+        let pattern = format!("%{}%", escape_like(name));
+        select = select.filter(
+            Expr::col((Object, object::Column::StorePath))
+                .like(LikeExpr::new(pattern).escape('\\')),
+        );
+    }
+
+    if let Some(min_size) = query.min_size {
+        select = select.filter(nar::Column::NarSize.gte(to_db_size(min_size)));
+    }
+
+    if let Some(max_size) = query.max_size {
+        select = select.filter(nar::Column::NarSize.lte(to_db_size(max_size)));
+    }
+
+    if let Some(created_by) = &query.created_by {
+        select = select.filter(object::Column::CreatedBy.eq(created_by.as_str()));
+    }
+
+    if let Some(created_after) = query.created_after {
+        let created_after = DateTime::<Utc>::from_timestamp(created_after, 0)
+            .ok_or_else(|| ErrorKind::RequestError(anyhow!("created_after is out of range")))?;
+        select = select.filter(object::Column::CreatedAt.gt(created_after));
+    }
+
+    let mut rows = select
+        .into_model::<ObjectRow>()
+        .all(database)
+        .await
+        .map_err(ServerError::database_error)?;
+
+    let next_cursor = if rows.len() as u64 > limit {
+        rows.truncate(limit as usize);
+        rows.last().map(|row| row.id)
+    } else {
+        None
+    };
+
+    let objects = rows.into_iter().map(ObjectInfo::from).collect();
+
+    Ok(ObjectList {
+        objects,
+        next_cursor,
+    })
+}
+
+/// Lists all objects in a cache by creation time.
+///
+/// - GET `/_api/v1/cache-objects/:cache?limit=:limit&cursor=:cursor`
+///
+/// Re-uploading a path moves it to the end of the listing. Pass
+/// `next_cursor` back as `cursor` to get the next page.
+#[instrument(skip_all, fields(cache_name))]
+pub(crate) async fn list_cache_objects(
+    Extension(state): Extension<State>,
+    Extension(req_state): Extension<RequestState>,
+    Path(cache_name): Path<CacheName>,
+    Query(query): Query<CacheObjectsQuery>,
+) -> ServerResult<Json<CacheObjectsPage>> {
+    let database = state.database().await?;
+    let cache = req_state
+        .auth
+        .auth_cache(database, &cache_name, |cache, permission| {
+            permission.require_pull()?;
+            Ok(cache)
+        })
+        .await?;
+
+    let page = query_cache_objects(database, cache.id, &query).await?;
+
+    Ok(Json(page))
+}
+
+/// Returns a page of objects in a cache ordered by creation time.
+///
+/// This is a keyset scan over `idx-object-cache-created-at`, with the
+/// object ID breaking ties between objects created at the same time.
+async fn query_cache_objects(
+    database: &impl ConnectionTrait,
+    cache_id: i64,
+    query: &CacheObjectsQuery,
+) -> ServerResult<CacheObjectsPage> {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let mut select = select_objects(cache_id)
+        .order_by_asc(object::Column::CreatedAt)
+        .order_by_asc(object::Column::Id)
+        // Fetch one extra row to tell whether there is a next page
+        .limit(limit + 1);
+
+    if let Some(cursor) = &query.cursor {
+        let (created_at, id) = parse_cursor(cursor)?;
+        select = select.filter(
+            Condition::any()
+                .add(object::Column::CreatedAt.gt(created_at))
+                .add(
+                    Condition::all()
+                        .add(object::Column::CreatedAt.eq(created_at))
+                        .add(object::Column::Id.gt(id)),
+                ),
+        );
+    }
+
+    let mut rows = select
+        .into_model::<ObjectRow>()
+        .all(database)
+        .await
+        .map_err(ServerError::database_error)?;
+
+    let next_cursor = if rows.len() as u64 > limit {
+        rows.truncate(limit as usize);
+        rows.last().map(format_cursor).transpose()?
+    } else {
+        None
+    };
+
+    let objects = rows.into_iter().map(ObjectInfo::from).collect();
+
+    Ok(CacheObjectsPage {
+        objects,
+        next_cursor,
+    })
+}
+
+/// Returns a query for the listing fields of objects in a cache.
+fn select_objects(cache_id: i64) -> Select<Object> {
+    Object::find()
+        .select_only()
+        .column(object::Column::Id)
+        .column(object::Column::StorePath)
+        .column(nar::Column::NarSize)
+        .column(nar::Column::NumChunks)
+        .column(object::Column::CreatedAt)
+        .column(object::Column::CreatedBy)
+        .column(object::Column::LastAccessedAt)
+        .join(JoinType::InnerJoin, object::Relation::Nar.def())
+        .filter(object::Column::CacheId.eq(cache_id))
+}
+
+/// Encodes the position after a row as `<created_at in ns>:<id>`.
+fn format_cursor(row: &ObjectRow) -> ServerResult<String> {
+    let nanos = row
+        .created_at
+        .timestamp_nanos_opt()
+        .ok_or_else(|| ErrorKind::DatabaseError(anyhow!("Object timestamp is out of range")))?;
+
+    Ok(format!("{}:{}", nanos, row.id))
+}
+
+fn parse_cursor(cursor: &str) -> ServerResult<(DateTime<Utc>, i64)> {
+    let invalid = || ErrorKind::RequestError(anyhow!("Invalid cursor"));
+
+    let (nanos, id) = cursor.split_once(':').ok_or_else(invalid)?;
+    let nanos: i64 = nanos.parse().map_err(|_| invalid())?;
+    let id: i64 = id.parse().map_err(|_| invalid())?;
+
+    Ok((DateTime::from_timestamp_nanos(nanos), id))
+}
+
+impl From<ObjectRow> for ObjectInfo {
+    fn from(row: ObjectRow) -> Self {
+        Self {
+            store_path: row.store_path,
+            nar_size: row.nar_size as u64,
+            num_chunks: Some(row.num_chunks as u64),
+            created_at: row.created_at.timestamp(),
+            created_by: row.created_by,
+            last_accessed_at: row.last_accessed_at.map(|t| t.timestamp()),
+        }
+    }
+}
+
+/// Escapes LIKE wildcards so the name is matched literally.
+fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Converts a size bound to the signed type the database uses.
+fn to_db_size(size: u64) -> i64 {
+    size.min(i64::MAX as u64) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sea_orm::ActiveValue::Set;
+    use uuid::Uuid;
+
+    use crate::config::Config;
+    use crate::database::entity::cache::{self, Entity as Cache};
+    use crate::database::entity::nar::{Entity as Nar, NarState};
+    use crate::database::entity::Json as DbJson;
+    use crate::database::migration::{Migrator, MigratorTrait};
+    use crate::StateInner;
+
+    async fn make_state() -> State {
+        let storage_path = std::env::temp_dir().join(format!("attic-test-{}", Uuid::new_v4()));
+        let storage_path = storage_path.display();
+
+        let config = format!(
+            r#"
+[database]
+url = "sqlite::memory:"
+
+[storage]
+type = "local"
+path = "{storage_path}"
+
+[chunking]
+nar-size-threshold = 0
+min-size = 16384
+avg-size = 65536
+max-size = 262144
+
+[jwt.signing]
+token-hs256-secret-base64 = "dmVyeSBzZWN1cmUgc2VjcmV0"
+"#
+        );
+
+        let config: Config = toml::from_str(&config).unwrap();
+        let state = StateInner::new(config).await;
+
+        let db = state.database().await.unwrap();
+        Migrator::up(db, None).await.unwrap();
+
+        state
+    }
+
+    async fn insert_cache(state: &State, name: &str) -> i64 {
+        let db = state.database().await.unwrap();
+        Cache::insert(cache::ActiveModel {
+            name: Set(name.to_string()),
+            keypair: Set(String::new()),
+            is_public: Set(false),
+            store_dir: Set("/nix/store".to_string()),
+            priority: Set(41),
+            upstream_cache_key_names: Set(DbJson(Vec::new())),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap()
+        .last_insert_id
+    }
+
+    async fn insert_object(
+        state: &State,
+        cache_id: i64,
+        base_name: &str,
+        nar_size: i64,
+        created_at: i64,
+        created_by: Option<&str>,
+    ) {
+        let db = state.database().await.unwrap();
+        let nar_id = Nar::insert(nar::ActiveModel {
+            state: Set(NarState::Valid),
+            nar_hash: Set(format!("sha256:{}", base_name)),
+            nar_size: Set(nar_size),
+            compression: Set("none".to_string()),
+            num_chunks: Set(0),
+            completeness_hint: Set(true),
+            holders_count: Set(0),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap()
+        .last_insert_id;
+
+        Object::insert(object::ActiveModel {
+            cache_id: Set(cache_id),
+            nar_id: Set(nar_id),
+            store_path_hash: Set(base_name[..32].to_string()),
+            store_path: Set(format!("/nix/store/{}", base_name)),
+            references: Set(DbJson(Vec::new())),
+            sigs: Set(DbJson(Vec::new())),
+            created_at: Set(DateTime::from_timestamp(created_at, 0).unwrap()),
+            created_by: Set(created_by.map(str::to_string)),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap();
+    }
+
+    async fn list(state: &State, cache_id: i64, query: ListObjectsQuery) -> Vec<String> {
+        let db = state.database().await.unwrap();
+        query_objects(db, cache_id, &query)
+            .await
+            .unwrap()
+            .objects
+            .into_iter()
+            .map(|o| o.store_path.rsplit_once('-').unwrap().1.to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_list_objects_filters() {
+        let state = make_state().await;
+        let main = insert_cache(&state, "main").await;
+        let other = insert_cache(&state, "other").await;
+
+        #[rustfmt::skip]
+        {
+            insert_object(&state, main, "563528481rvhc5kxwipjmg6rqrl95mdx-glibc", 1000, 100, Some("alice")).await;
+            insert_object(&state, main, "xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello", 10, 200, Some("bob")).await;
+            insert_object(&state, main, "vvb4wxmnjixmrkhmj2xb75z62hrr41i7-hello_world", 500, 300, None).await;
+            insert_object(&state, other, "ia70ss13m22znbl8khrf2hq72qmh5drr-hello", 10, 200, Some("bob")).await;
+        };
+
+        let all = list(&state, main, ListObjectsQuery::default()).await;
+        assert_eq!(vec!["glibc", "hello", "hello_world"], all);
+
+        let by_name = list(
+            &state,
+            main,
+            ListObjectsQuery {
+                name: Some("hello".to_string()),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(vec!["hello", "hello_world"], by_name);
+
+        // Wildcards are matched literally
+        let by_wildcard = list(
+            &state,
+            main,
+            ListObjectsQuery {
+                name: Some("_".to_string()),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(vec!["hello_world"], by_wildcard);
+
+        let by_size = list(
+            &state,
+            main,
+            ListObjectsQuery {
+                min_size: Some(100),
+                max_size: Some(500),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(vec!["hello_world"], by_size);
+
+        let by_creator = list(
+            &state,
+            main,
+            ListObjectsQuery {
+                created_by: Some("bob".to_string()),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(vec!["hello"], by_creator);
+
+        let by_time = list(
+            &state,
+            main,
+            ListObjectsQuery {
+                created_after: Some(100),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(vec!["hello", "hello_world"], by_time);
+    }
+
+    #[tokio::test]
+    async fn test_list_objects_pagination() {
+        let state = make_state().await;
+        let main = insert_cache(&state, "main").await;
+
+        insert_object(
+            &state,
+            main,
+            "563528481rvhc5kxwipjmg6rqrl95mdx-a",
+            1,
+            0,
+            None,
+        )
+        .await;
+        insert_object(
+            &state,
+            main,
+            "xcp9cav49dmsjbwdjlmkjxj10gkpx553-b",
+            1,
+            0,
+            None,
+        )
+        .await;
+        insert_object(
+            &state,
+            main,
+            "vvb4wxmnjixmrkhmj2xb75z62hrr41i7-c",
+            1,
+            0,
+            None,
+        )
+        .await;
+
+        let db = state.database().await.unwrap();
+        let mut query = ListObjectsQuery {
+            limit: Some(2),
+            ..Default::default()
+        };
+
+        let first = query_objects(db, main, &query).await.unwrap();
+        assert_eq!(2, first.objects.len());
+        assert!(first.next_cursor.is_some());
+
+        query.cursor = first.next_cursor;
+        let second = query_objects(db, main, &query).await.unwrap();
+        assert_eq!(1, second.objects.len());
+        assert_eq!(
+            "/nix/store/vvb4wxmnjixmrkhmj2xb75z62hrr41i7-c",
+            second.objects[0].store_path
+        );
+        assert_eq!(None, second.next_cursor);
+    }
+
+    #[tokio::test]
+    async fn test_list_cache_objects_by_creation_time() {
+        let state = make_state().await;
+        let main = insert_cache(&state, "main").await;
+
+        insert_object(
+            &state,
+            main,
+            "563528481rvhc5kxwipjmg6rqrl95mdx-a",
+            1,
+            300,
+            None,
+        )
+        .await;
+        insert_object(
+            &state,
+            main,
+            "xcp9cav49dmsjbwdjlmkjxj10gkpx553-b",
+            1,
+            100,
+            None,
+        )
+        .await;
+        insert_object(
+            &state,
+            main,
+            "vvb4wxmnjixmrkhmj2xb75z62hrr41i7-c",
+            1,
+            200,
+            None,
+        )
+        .await;
+        insert_object(
+            &state,
+            main,
+            "ia70ss13m22znbl8khrf2hq72qmh5drr-d",
+            1,
+            200,
+            None,
+        )
+        .await;
+
+        let db = state.database().await.unwrap();
+        let mut query = CacheObjectsQuery {
+            limit: Some(1),
+            ..Default::default()
+        };
+
+        let mut names = Vec::new();
+        loop {
+            let page = query_cache_objects(db, main, &query).await.unwrap();
+            for object in page.objects {
+                names.push(object.store_path.rsplit_once('-').unwrap().1.to_string());
+            }
+
+            match page.next_cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => break,
+            }
+        }
+
+        assert_eq!(vec!["b", "c", "d", "a"], names);
+
+        query.cursor = Some("garbage".to_string());
+        assert!(query_cache_objects(db, main, &query).await.is_err());
+    }
+}
@@ -1,27 +1,39 @@
 mod cache_config;
+mod cache_events;
+mod cache_gc;
+mod delete_path;
 mod get_missing_paths;
-mod upload_path;
+mod get_object_info;
+mod list_objects;
+mod narinfo_cache;
+pub(crate) mod upload_path;
+mod upload_path_preflight;
 
 use axum::{
     routing::{delete, get, patch, post, put},
     Router,
 };
+use tower_http::cors::CorsLayer;
 
-pub(crate) fn get_router() -> Router {
-    Router::new()
+pub(crate) fn get_router(cors: Option<CorsLayer>) -> Router {
+    let mut api = Router::new()
         .route(
             "/_api/v1/get-missing-paths",
             post(get_missing_paths::get_missing_paths),
         )
         .route("/_api/v1/upload-path", put(upload_path::upload_path))
         .route(
-            "/:cache/attic-cache-info",
-            get(cache_config::get_cache_config),
+            "/_api/v1/upload-path/preflight",
+            post(upload_path_preflight::upload_path_preflight),
         )
         .route(
             "/_api/v1/cache-config/:cache",
             get(cache_config::get_cache_config),
         )
+        .route(
+            "/_api/v1/cache-config/:cache/public-key",
+            get(cache_config::get_cache_public_key),
+        )
         .route(
             "/_api/v1/cache-config/:cache",
             post(cache_config::create_cache),
@@ -34,4 +46,45 @@ pub(crate) fn get_router() -> Router {
             "/_api/v1/cache-config/:cache",
             delete(cache_config::destroy_cache),
         )
+        .route(
+            "/_api/v1/cache-objects/:cache",
+            get(list_objects::list_cache_objects),
+        )
+        .route(
+            "/_api/v1/cache/:cache/events",
+            get(cache_events::get_cache_events),
+        )
+        .route(
+            "/_api/v1/cache/:cache/objects",
+            get(list_objects::list_objects),
+        )
+        .route(
+            "/_api/v1/cache/:cache/path/:store_path_hash",
+            get(get_object_info::get_object_info).delete(delete_path::delete_path),
+        )
+        .route(
+            "/_api/v1/cache/:cache/delete-objects",
+            post(delete_path::delete_objects_matching),
+        )
+        .route("/_api/v1/cache/:cache/gc", post(cache_gc::run_cache_gc))
+        .route(
+            "/_api/v1/cache/:cache/gc/:job",
+            get(cache_gc::get_cache_gc_job),
+        )
+        .route(
+            "/_api/v1/debug/narinfo-cache",
+            get(narinfo_cache::get_narinfo_cache_stats),
+        );
+
+    // Only the API routes are exposed to browsers
+    if let Some(cors) = cors {
+        api = api.layer(cors);
+    }
+
+    Router::new()
+        .route(
+            "/:cache/attic-cache-info",
+            get(cache_config::get_cache_config),
+        )
+        .merge(api)
 }
@@ -0,0 +1,26 @@
+//! Narinfo cache statistics.
+
+use axum::extract::{Extension, Json};
+use tracing::instrument;
+
+use crate::error::{ErrorKind, ServerResult};
+use crate::narinfo_cache::NarInfoCacheStats;
+use crate::{RequestState, State};
+
+/// Returns the statistics of the in-memory narinfo cache.
+///
+/// - GET `/_api/v1/debug/narinfo-cache`
+///
+/// The statistics are per process. Any authenticated client may
+/// read them.
+#[instrument(skip_all)]
+pub(crate) async fn get_narinfo_cache_stats(
+    Extension(state): Extension<State>,
+    Extension(req_state): Extension<RequestState>,
+) -> ServerResult<Json<NarInfoCacheStats>> {
+    if req_state.auth.token.get().is_none() {
+        return Err(ErrorKind::Unauthorized.into());
+    }
+
+    Ok(Json(state.narinfo_cache.stats()))
+}
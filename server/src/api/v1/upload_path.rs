@@ -2,15 +2,21 @@ use std::io;
 
 use std::io::Cursor;
 use std::marker::Unpin;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 
 use anyhow::anyhow;
-use async_compression::tokio::bufread::{BrotliEncoder, XzEncoder, ZstdEncoder};
+use async_compression::tokio::bufread::{
+    BrotliEncoder, Lz4Encoder, XzEncoder, ZstdDecoder, ZstdEncoder,
+};
+use async_compression::zstd::CParameter as ZstdParameter;
 use async_compression::Level as CompressionLevel;
 use axum::{
     body::Body,
     extract::{Extension, Json},
-    http::HeaderMap,
+    http::{header::CONTENT_ENCODING, HeaderMap},
 };
 use bytes::{Bytes, BytesMut};
 use chrono::Utc;
@@ -18,9 +24,9 @@ use digest::Output as DigestOutput;
 use futures::future::join_all;
 use futures::StreamExt;
 use sea_orm::entity::prelude::*;
-use sea_orm::sea_query::Expr;
+use sea_orm::sea_query::{Alias, Expr};
 use sea_orm::ActiveValue::Set;
-use sea_orm::{QuerySelect, TransactionTrait};
+use sea_orm::{JoinType, QuerySelect, SqlErr, TransactionTrait};
 use sha2::{Digest, Sha256};
 use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, BufReader};
 use tokio::sync::{OnceCell, Semaphore};
@@ -29,9 +35,11 @@ use tokio_util::io::StreamReader;
 use tracing::instrument;
 use uuid::Uuid;
 
-use crate::config::CompressionType;
+use crate::config::{CompressionType, UploadSignaturePolicy, UploadSignaturesConfig};
 use crate::error::{ErrorKind, ServerError, ServerResult};
+use crate::events::append_events;
 use crate::narinfo::Compression;
+use crate::storage::StorageBackend;
 use crate::{RequestState, State};
 use attic::api::v1::upload_path::{
     UploadPathNarInfo, UploadPathResult, UploadPathResultKind, ATTIC_NAR_INFO,
@@ -39,31 +47,39 @@ use attic::api::v1::upload_path::{
 };
 use attic::chunking::chunk_stream;
 use attic::hash::Hash;
-use attic::stream::{read_chunk_async, StreamHasher};
+use attic::nix_store::{ContentAddress, StorePath};
+use attic::stream::{read_chunk_async, StreamHasher, StreamLimiter};
 use attic::util::Finally;
 
+use crate::audit::{self, AuditEvent};
+use crate::database::entity::audit_log::AuditAction;
 use crate::database::entity::cache;
 use crate::database::entity::chunk::{self, ChunkState, Entity as Chunk};
 use crate::database::entity::chunkref::{self, Entity as ChunkRef};
+use crate::database::entity::event::EventKind;
 use crate::database::entity::nar::{self, Entity as Nar, NarState};
 use crate::database::entity::object::{self, Entity as Object, InsertExt};
 use crate::database::entity::Json as DbJson;
-use crate::database::{AtticDatabase, ChunkGuard, NarGuard};
+use crate::database::{
+    add_chunk_references, delete_nars, insert_chunkref, insert_chunkrefs, AtticDatabase,
+    ChunkGuard, NarGuard,
+};
 
 /// Number of chunks to upload to the storage backend at once.
 ///
 /// TODO: Make this configurable
 const CONCURRENT_CHUNK_UPLOADS: usize = 10;
 
-/// The maximum size of the upload info JSON.
-///
-/// TODO: Make this configurable
-const MAX_NAR_INFO_SIZE: usize = 1 * 1024 * 1024; // 1 MiB
+/// The maximum length of the system of an uploaded path.
+const MAX_SYSTEM_LENGTH: usize = 64;
+
+/// Content encodings accepted in upload bodies.
+pub(crate) const UPLOAD_ENCODINGS: &[&str] = &["zstd"];
 
 type CompressorFn<C> = Box<dyn FnOnce(C) -> Box<dyn AsyncRead + Unpin + Send> + Send>;
 
 /// Data of a chunk.
-enum ChunkData {
+pub(crate) enum ChunkData {
     /// Some bytes in memory.
     Bytes(Bytes),
 
@@ -71,10 +87,38 @@ enum ChunkData {
     Stream(Box<dyn AsyncRead + Send + Unpin + 'static>, Hash, usize),
 }
 
+/// What an uploaded chunk may be deduplicated against.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ChunkDedup {
+    /// Whether the full chunk must be received to use an existing one.
+    pub require_proof_of_possession: bool,
+
+    /// The cache to limit deduplication to, if any.
+    pub cache_id: Option<i64>,
+}
+
+impl ChunkDedup {
+    /// Returns what uploads to a cache may be deduplicated against.
+    pub fn for_cache(state: &State, cache: &cache::Model) -> Self {
+        Self {
+            require_proof_of_possession: state.config.require_proof_of_possession,
+            cache_id: state.config.per_cache_deduplication.then_some(cache.id),
+        }
+    }
+
+    /// Returns a policy that deduplicates against all chunks.
+    pub fn global(require_proof_of_possession: bool) -> Self {
+        Self {
+            require_proof_of_possession,
+            cache_id: None,
+        }
+    }
+}
+
 /// Result of a chunk upload.
-struct UploadChunkResult {
-    guard: ChunkGuard,
-    deduplicated: bool,
+pub(crate) struct UploadChunkResult {
+    pub guard: ChunkGuard,
+    pub deduplicated: bool,
 }
 
 /// Applies compression to a stream, computing hashes along the way.
@@ -105,6 +149,16 @@ struct CompressionStream {
 }
 
 trait UploadPathNarInfoExt {
+    /// Checks fields that are emitted verbatim in narinfos.
+    fn validate(&self) -> ServerResult<()>;
+
+    /// Checks that the store path is directly in the store directory
+    /// and agrees with the store path hash.
+    fn validate_store_path(&self, store_dir: &str) -> ServerResult<()>;
+
+    /// Checks the supplied signatures against the trusted keys.
+    fn verify_signatures(&mut self, config: &UploadSignaturesConfig) -> ServerResult<()>;
+
     fn to_active_model(&self) -> object::ActiveModel;
 }
 
@@ -124,11 +178,13 @@ pub(crate) async fn upload_path(
     body: Body,
 ) -> ServerResult<Json<UploadPathResult>> {
     let stream = body.into_data_stream();
-    let mut stream = StreamReader::new(
+    let stream = StreamReader::new(
         stream.map(|r| r.map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))),
     );
+    let mut stream = decode_body(&headers, stream)?;
+    let limits = &state.config.limits;
 
-    let upload_info: UploadPathNarInfo = {
+    let mut upload_info: UploadPathNarInfo = {
         if let Some(preamble_size_bytes) = headers.get(ATTIC_NAR_INFO_PREAMBLE_SIZE) {
             // Read from the beginning of the PUT body
             let preamble_size: usize = preamble_size_bytes
@@ -147,9 +203,7 @@ pub(crate) async fn upload_path(
                     ))
                 })?;
 
-            if preamble_size > MAX_NAR_INFO_SIZE {
-                return Err(ErrorKind::RequestError(anyhow!("Upload info is too large")).into());
-            }
+            check_size("upload info", preamble_size, Some(limits.max_nar_info_size))?;
 
             let buf = BytesMut::with_capacity(preamble_size);
             let preamble = read_chunk_async(&mut stream, buf)
@@ -166,13 +220,27 @@ pub(crate) async fn upload_path(
             serde_json::from_slice(&preamble).map_err(ServerError::request_error)?
         } else if let Some(nar_info_bytes) = headers.get(ATTIC_NAR_INFO) {
             // Read from X-Attic-Nar-Info header
+            check_size(
+                "upload info",
+                nar_info_bytes.len(),
+                Some(limits.max_nar_info_size),
+            )?;
+
             serde_json::from_slice(nar_info_bytes.as_bytes()).map_err(ServerError::request_error)?
         } else {
             return Err(ErrorKind::RequestError(anyhow!("{} must be set", ATTIC_NAR_INFO)).into());
         }
     };
+    upload_info.validate()?;
+    upload_info.verify_signatures(&state.config.upload_signatures)?;
+    check_size("NAR", upload_info.nar_size, limits.max_nar_size)?;
     let cache_name = &upload_info.cache;
 
+    // Anything beyond the claimed size fails the size check anyway.
+    // Stop there so a small compressed body can't expand without bound,
+    // and so a client can't stream garbage forever against a known hash.
+    let stream = StreamLimiter::new(stream, upload_info.nar_size as u64);
+
     let database = state.database().await?;
     let cache = req_state
         .auth
@@ -182,11 +250,32 @@ pub(crate) async fn upload_path(
         })
         .await?;
 
+    upload_info.validate_store_path(&cache.store_dir)?;
+
     let username = req_state.auth.username().map(str::to_string);
 
+    state
+        .upload_limiter
+        .check_upload(
+            cache_name,
+            username.as_deref(),
+            upload_info.nar_size,
+            Instant::now(),
+        )
+        .map_err(|retry_after| ErrorKind::RateLimited {
+            retry_after_secs: retry_after.as_secs_f64().ceil().max(1.0) as u64,
+        })?;
+
+    let audit_event = AuditEvent::new(AuditAction::Push, &req_state, cache_name)
+        .store_path(upload_info.store_path.clone())
+        .nar_hash(upload_info.nar_hash.to_typed_base16());
+
     // Try to acquire a lock on an existing NAR
-    let existing_nar = database.find_and_lock_nar(&upload_info.nar_hash).await?;
-    match existing_nar {
+    let dedup = ChunkDedup::for_cache(&state, &cache);
+    let existing_nar = database
+        .find_and_lock_nar(&upload_info.nar_hash, dedup.cache_id)
+        .await?;
+    let result = match existing_nar {
         Some(existing_nar) => {
             // Deduplicate?
             let missing_chunk = ChunkRef::find()
@@ -218,6 +307,37 @@ pub(crate) async fn upload_path(
             // New NAR
             upload_path_new(username, cache, upload_info, stream, database, &state).await
         }
+    }?;
+
+    audit::record(&state, audit_event).await;
+
+    Ok(result)
+}
+
+/// Returns an error if part of an upload is over a size limit.
+fn check_size(what: &'static str, size: usize, limit: Option<usize>) -> ServerResult<()> {
+    match limit {
+        Some(limit) if size > limit => Err(ErrorKind::PayloadTooLarge { what, limit }.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Decodes an upload body according to its `Content-Encoding`.
+fn decode_body(
+    headers: &HeaderMap,
+    body: impl AsyncBufRead + Unpin + Send + 'static,
+) -> ServerResult<Box<dyn AsyncRead + Unpin + Send>> {
+    let Some(encoding) = headers.get(CONTENT_ENCODING) else {
+        return Ok(Box::new(body));
+    };
+
+    match encoding.to_str().map(str::trim) {
+        Ok("identity") => Ok(Box::new(body)),
+        Ok("zstd") => Ok(Box::new(ZstdDecoder::new(body))),
+        _ => Err(ErrorKind::UnsupportedContentEncoding {
+            encoding: String::from_utf8_lossy(encoding.as_bytes()).into_owned(),
+        }
+        .into()),
     }
 }
 
@@ -250,6 +370,24 @@ async fn upload_path_dedup(
         }
     }
 
+    // Sum up the compressed sizes of the existing chunks
+    let file_size = ChunkRef::find()
+        .select_only()
+        .column_as(
+            Expr::col((Chunk, chunk::Column::FileSize))
+                .sum()
+                .cast_as(Alias::new("BIGINT")),
+            "file_size",
+        )
+        .join(JoinType::InnerJoin, chunkref::Relation::Chunk.def())
+        .filter(chunkref::Column::NarId.eq(existing_nar.id))
+        .into_tuple::<Option<i64>>()
+        .one(database)
+        .await
+        .map_err(ServerError::database_error)?
+        .flatten()
+        .map(|file_size| file_size as usize);
+
     // Finally...
     let txn = database
         .begin()
@@ -270,6 +408,14 @@ async fn upload_path_dedup(
     .await
     .map_err(ServerError::database_error)?;
 
+    let event_seq = append_events(
+        &txn,
+        cache.id,
+        EventKind::Upload,
+        vec![upload_info.store_path_hash.to_string()],
+    )
+    .await?;
+
     // Also mark the NAR as complete again
     //
     // This is racy (a chunkref might have been broken in the
@@ -286,14 +432,18 @@ async fn upload_path_dedup(
     .map_err(ServerError::database_error)?;
 
     txn.commit().await.map_err(ServerError::database_error)?;
+    state.cache_events.notify(cache.id, event_seq);
+    state
+        .narinfo_cache
+        .invalidate(&upload_info.cache, [upload_info.store_path_hash.as_str()]);
 
     // Ensure it's not unlocked earlier
     drop(existing_nar);
 
     Ok(Json(UploadPathResult {
         kind: UploadPathResultKind::Deduplicated,
-        file_size: None, // TODO: Sum the chunks
-        frac_deduplicated: None,
+        file_size,
+        frac_deduplicated: Some(1.0),
     }))
 }
 
@@ -310,7 +460,7 @@ async fn upload_path_new(
     database: &DatabaseConnection,
     state: &State,
 ) -> ServerResult<Json<UploadPathResult>> {
-    let nar_size_threshold = state.config.chunking.nar_size_threshold;
+    let nar_size_threshold = cache.chunking(&state.config.chunking).nar_size_threshold;
 
     if nar_size_threshold == 0 || upload_info.nar_size < nar_size_threshold {
         upload_path_new_unchunked(username, cache, upload_info, stream, database, state).await
@@ -328,11 +478,12 @@ async fn upload_path_new_chunked(
     database: &DatabaseConnection,
     state: &State,
 ) -> ServerResult<Json<UploadPathResult>> {
-    let chunking_config = &state.config.chunking;
+    let chunking_config = cache.chunking(&state.config.chunking);
     let compression_config = &state.config.compression;
     let compression_type = compression_config.r#type;
     let compression_level = compression_config.level();
     let compression: Compression = compression_type.into();
+    let dedup = ChunkDedup::for_cache(state, &cache);
 
     let nar_size_db = i64::try_from(upload_info.nar_size).map_err(ServerError::request_error)?;
 
@@ -361,15 +512,20 @@ async fn upload_path_new_chunked(
 
     let cleanup = Finally::new({
         let database = database.clone();
-        let nar_model = nar::ActiveModel {
-            id: Set(nar_id),
-            ..Default::default()
-        };
 
         async move {
             tracing::warn!("Error occurred - Cleaning up NAR entry");
 
-            if let Err(e) = Nar::delete(nar_model).exec(&database).await {
+            let result = async {
+                let txn = database
+                    .begin()
+                    .await
+                    .map_err(ServerError::database_error)?;
+                delete_nars(&txn, vec![nar_id]).await?;
+                txn.commit().await.map_err(ServerError::database_error)
+            };
+
+            if let Err(e) = result.await {
                 tracing::warn!("Failed to unregister failed NAR: {}", e);
             }
         }
@@ -379,6 +535,7 @@ async fn upload_path_new_chunked(
     let (stream, nar_compute) = StreamHasher::new(stream, Sha256::new());
     let mut chunks = chunk_stream(
         stream,
+        chunking_config.algorithm(),
         chunking_config.min_size,
         chunking_config.avg_size,
         chunking_config.max_size,
@@ -387,7 +544,6 @@ async fn upload_path_new_chunked(
     let upload_chunk_limit = Arc::new(Semaphore::new(CONCURRENT_CHUNK_UPLOADS));
     let mut futures = Vec::new();
 
-    let mut chunk_idx = 0;
     while let Some(bytes) = chunks.next().await {
         let bytes = bytes.map_err(ServerError::request_error)?;
         let data = ChunkData::Bytes(bytes);
@@ -400,38 +556,23 @@ async fn upload_path_new_chunked(
         futures.push({
             let database = database.clone();
             let state = state.clone();
-            let require_proof_of_possession = state.config.require_proof_of_possession;
 
             spawn(async move {
                 let chunk = upload_chunk(
                     data,
                     compression_type,
                     compression_level,
-                    database.clone(),
+                    0,
+                    database,
                     state,
-                    require_proof_of_possession,
+                    dedup,
                 )
                 .await?;
 
-                // Create mapping from the NAR to the chunk
-                ChunkRef::insert(chunkref::ActiveModel {
-                    nar_id: Set(nar_id),
-                    seq: Set(chunk_idx),
-                    chunk_id: Set(Some(chunk.guard.id)),
-                    chunk_hash: Set(chunk.guard.chunk_hash.clone()),
-                    compression: Set(chunk.guard.compression.clone()),
-                    ..Default::default()
-                })
-                .exec(&database)
-                .await
-                .map_err(ServerError::database_error)?;
-
                 drop(permit);
                 Ok(chunk)
             })
         });
-
-        chunk_idx += 1;
     }
 
     // Confirm that the NAR Hash and Size are correct
@@ -450,19 +591,13 @@ async fn upload_path_new_chunked(
         .map(|join_result| join_result.unwrap())
         .collect::<ServerResult<Vec<_>>>()?;
 
-    let (file_size, deduplicated_size) =
-        chunks
-            .iter()
-            .fold((0, 0), |(file_size, deduplicated_size), c| {
-                (
-                    file_size + c.guard.file_size.unwrap() as usize,
-                    if c.deduplicated {
-                        deduplicated_size + c.guard.chunk_size as usize
-                    } else {
-                        deduplicated_size
-                    },
-                )
-            });
+    let (file_size, frac_deduplicated) = summarize_chunks(chunks.iter().map(|c| {
+        (
+            c.guard.chunk_size as usize,
+            c.guard.file_size.unwrap() as usize,
+            c.deduplicated,
+        )
+    }));
 
     // Finally...
     let txn = database
@@ -470,6 +605,24 @@ async fn upload_path_new_chunked(
         .await
         .map_err(ServerError::database_error)?;
 
+    // Create mappings from the NAR to the chunks
+    //
+    // The guards keep the chunks alive until then. Identical chunks
+    // in the NAR share a chunk, so it gets one reference per mapping.
+    let chunkrefs = chunks
+        .iter()
+        .enumerate()
+        .map(|(seq, chunk)| chunkref::ActiveModel {
+            nar_id: Set(nar_id),
+            seq: Set(seq as i32),
+            chunk_id: Set(Some(chunk.guard.id)),
+            chunk_hash: Set(chunk.guard.chunk_hash.clone()),
+            compression: Set(chunk.guard.compression.clone()),
+            ..Default::default()
+        })
+        .collect();
+    insert_chunkrefs(&txn, chunkrefs).await?;
+
     // Set num_chunks and mark the NAR as Valid
     Nar::update(nar::ActiveModel {
         id: Set(nar_id),
@@ -495,7 +648,19 @@ async fn upload_path_new_chunked(
     .await
     .map_err(ServerError::database_error)?;
 
+    let event_seq = append_events(
+        &txn,
+        cache.id,
+        EventKind::Upload,
+        vec![upload_info.store_path_hash.to_string()],
+    )
+    .await?;
+
     txn.commit().await.map_err(ServerError::database_error)?;
+    state.cache_events.notify(cache.id, event_seq);
+    state
+        .narinfo_cache
+        .invalidate(&upload_info.cache, [upload_info.store_path_hash.as_str()]);
 
     cleanup.cancel();
 
@@ -504,7 +669,7 @@ async fn upload_path_new_chunked(
         file_size: Some(file_size),
 
         // Currently, frac_deduplicated is computed from size before compression
-        frac_deduplicated: Some(deduplicated_size as f64 / *nar_size as f64),
+        frac_deduplicated: Some(frac_deduplicated),
     }))
 }
 
@@ -534,12 +699,17 @@ async fn upload_path_new_unchunked(
         data,
         compression_type,
         compression_config.level(),
+        compression_config.workers,
         database.clone(),
         state.clone(),
-        state.config.require_proof_of_possession,
+        ChunkDedup::for_cache(state, &cache),
     )
     .await?;
-    let file_size = chunk.guard.file_size.unwrap() as usize;
+    let (file_size, frac_deduplicated) = summarize_chunks(std::iter::once((
+        chunk.guard.chunk_size as usize,
+        chunk.guard.file_size.unwrap() as usize,
+        chunk.deduplicated,
+    )));
 
     // Finally...
     let txn = database
@@ -571,17 +741,18 @@ async fn upload_path_new_unchunked(
     };
 
     // Create a mapping from the NAR to the chunk
-    ChunkRef::insert(chunkref::ActiveModel {
-        nar_id: Set(nar_id),
-        seq: Set(0),
-        chunk_id: Set(Some(chunk.guard.id)),
-        chunk_hash: Set(upload_info.nar_hash.to_typed_base16()),
-        compression: Set(compression.to_string()),
-        ..Default::default()
-    })
-    .exec(&txn)
-    .await
-    .map_err(ServerError::database_error)?;
+    insert_chunkref(
+        &txn,
+        chunkref::ActiveModel {
+            nar_id: Set(nar_id),
+            seq: Set(0),
+            chunk_id: Set(Some(chunk.guard.id)),
+            chunk_hash: Set(upload_info.nar_hash.to_typed_base16()),
+            compression: Set(compression.to_string()),
+            ..Default::default()
+        },
+    )
+    .await?;
 
     // Create a mapping granting the local cache access to the NAR
     Object::insert({
@@ -597,25 +768,38 @@ async fn upload_path_new_unchunked(
     .await
     .map_err(ServerError::database_error)?;
 
+    let event_seq = append_events(
+        &txn,
+        cache.id,
+        EventKind::Upload,
+        vec![upload_info.store_path_hash.to_string()],
+    )
+    .await?;
+
     txn.commit().await.map_err(ServerError::database_error)?;
+    state.cache_events.notify(cache.id, event_seq);
+    state
+        .narinfo_cache
+        .invalidate(&upload_info.cache, [upload_info.store_path_hash.as_str()]);
 
     Ok(Json(UploadPathResult {
         kind: UploadPathResultKind::Uploaded,
         file_size: Some(file_size),
-        frac_deduplicated: None,
+        frac_deduplicated: Some(frac_deduplicated),
     }))
 }
 
 /// Uploads a chunk with the desired compression.
 ///
 /// This will automatically perform deduplication if the chunk exists.
-async fn upload_chunk(
+pub(crate) async fn upload_chunk(
     data: ChunkData,
     compression_type: CompressionType,
     compression_level: CompressionLevel,
+    compression_workers: u32,
     database: DatabaseConnection,
     state: State,
-    require_proof_of_possession: bool,
+    dedup: ChunkDedup,
 ) -> ServerResult<UploadChunkResult> {
     let compression: Compression = compression_type.into();
 
@@ -623,12 +807,12 @@ async fn upload_chunk(
     let given_chunk_size = data.size();
 
     if let Some(existing_chunk) = database
-        .find_and_lock_chunk(&given_chunk_hash, compression)
+        .find_and_lock_chunk(&given_chunk_hash, compression, dedup.cache_id)
         .await?
     {
         // There's an existing chunk matching the hash
-        if require_proof_of_possession && !data.is_hash_trusted() {
-            let stream = data.into_async_read();
+        if dedup.require_proof_of_possession && !data.is_hash_trusted() {
+            let stream = StreamLimiter::new(data.into_async_read(), given_chunk_size as u64);
 
             let (mut stream, nar_compute) = StreamHasher::new(stream, Sha256::new());
             tokio::io::copy(&mut stream, &mut tokio::io::sink())
@@ -686,34 +870,30 @@ async fn upload_chunk(
         insertion.last_insert_id
     };
 
+    // Set once the file is moved to its content-addressed name, where
+    // it may be shared with a concurrent upload
+    let renamed = Arc::new(AtomicBool::new(false));
+
     let cleanup = Finally::new({
         let database = database.clone();
-        let chunk_model = chunk::ActiveModel {
-            id: Set(chunk_id),
-            ..Default::default()
-        };
         let backend = backend.clone();
         let key = key.clone();
+        let renamed = renamed.clone();
 
         async move {
             tracing::warn!("Error occurred - Cleaning up uploaded file and chunk entry");
 
-            if let Err(e) = backend.delete_file(key).await {
-                tracing::warn!("Failed to clean up failed upload: {}", e);
-            }
-
-            if let Err(e) = Chunk::delete(chunk_model).exec(&database).await {
-                tracing::warn!("Failed to unregister failed chunk: {}", e);
-            }
+            let key = (!renamed.load(Ordering::SeqCst)).then_some(key);
+            discard_chunk(&database, &**backend, chunk_id, key).await;
         }
     });
 
     // Compress and stream to the storage backend
-    let compressor = get_compressor_fn(compression_type, compression_level);
+    let compressor = get_compressor_fn(compression_type, compression_level, compression_workers);
     let mut stream = CompressionStream::new(data.into_async_read(), compressor);
 
     backend
-        .upload_file(key, stream.stream())
+        .upload_file(key.clone(), stream.stream())
         .await
         .map_err(ServerError::storage_error)?;
 
@@ -728,6 +908,55 @@ async fn upload_chunk(
         return Err(ErrorKind::RequestError(anyhow!("Bad chunk hash or size")).into());
     }
 
+    let mut content_addressed_file = None;
+    if state.config.content_addressed_chunks {
+        let ca_key = content_addressed_key(&file_hash);
+        let ca_file = backend.make_db_reference(ca_key.clone()).await?;
+
+        let owner_state: Option<ChunkState> = Chunk::find()
+            .select_only()
+            .column(chunk::Column::State)
+            .filter(chunk::Column::RemoteFileId.eq(ca_file.remote_file_id()))
+            .into_tuple()
+            .one(&database)
+            .await
+            .map_err(ServerError::database_error)?;
+
+        match owner_state {
+            Some(owner_state) => {
+                // Another upload of the same chunk got there first
+                if owner_state == ChunkState::Valid {
+                    // We have the full chunk, so sharing the file doesn't reveal
+                    // anything as long as it's not reported as deduplicated
+                    if let Some(existing_chunk) = database
+                        .find_and_lock_chunk(&chunk_hash, compression, None)
+                        .await?
+                    {
+                        cleanup.cancel();
+                        discard_chunk(&database, &***backend, chunk_id, Some(key)).await;
+
+                        return Ok(UploadChunkResult {
+                            guard: existing_chunk,
+                            deduplicated: dedup.cache_id.is_none(),
+                        });
+                    }
+                }
+
+                // Otherwise the file may be about to be deleted by the
+                // garbage collector, so we keep ours under its random name
+            }
+            None => match backend.rename_file(key.clone(), ca_key).await {
+                Ok(file) => {
+                    renamed.store(true, Ordering::SeqCst);
+                    content_addressed_file = Some(file);
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to rename chunk file {}: {}", key, e);
+                }
+            },
+        }
+    }
+
     // Finally...
     let txn = database
         .begin()
@@ -736,17 +965,47 @@ async fn upload_chunk(
 
     // Update the file hash and size, and set the chunk to valid
     let file_size_db = i64::try_from(*file_size).map_err(ServerError::request_error)?;
-    let chunk = Chunk::update(chunk::ActiveModel {
+    let mut update = chunk::ActiveModel {
         id: Set(chunk_id),
         state: Set(ChunkState::Valid),
         file_hash: Set(Some(file_hash.to_typed_base16())),
         file_size: Set(Some(file_size_db)),
         holders_count: Set(1),
         ..Default::default()
-    })
-    .exec(&txn)
-    .await
-    .map_err(ServerError::database_error)?;
+    };
+
+    if let Some(file) = &content_addressed_file {
+        update.remote_file_id = Set(file.remote_file_id());
+        update.remote_file = Set(DbJson(file.clone()));
+    }
+
+    let chunk = match Chunk::update(update).exec(&txn).await {
+        Ok(chunk) => chunk,
+        Err(e)
+            if content_addressed_file.is_some()
+                && matches!(e.sql_err(), Some(SqlErr::UniqueConstraintViolation(_))) =>
+        {
+            // A concurrent upload of the same chunk claimed the file
+            // between our check and now, and the file is theirs now
+            drop(txn);
+
+            let existing_chunk = database
+                .find_and_lock_chunk(&chunk_hash, compression, None)
+                .await?
+                .ok_or_else(|| {
+                    ErrorKind::StorageError(anyhow!("Chunk disappeared during upload"))
+                })?;
+
+            cleanup.cancel();
+            discard_chunk(&database, &***backend, chunk_id, None).await;
+
+            return Ok(UploadChunkResult {
+                guard: existing_chunk,
+                deduplicated: dedup.cache_id.is_none(),
+            });
+        }
+        Err(e) => return Err(ServerError::database_error(e)),
+    };
 
     // Also repair broken chunk references pointing at the same chunk
     let repaired = ChunkRef::update_many()
@@ -758,6 +1017,8 @@ async fn upload_chunk(
         .await
         .map_err(ServerError::database_error)?;
 
+    add_chunk_references(&txn, vec![chunk_id], repaired.rows_affected as i64).await?;
+
     txn.commit().await.map_err(ServerError::database_error)?;
 
     cleanup.cancel();
@@ -772,18 +1033,79 @@ async fn upload_chunk(
     })
 }
 
+/// Deletes a chunk that won't become valid, along with its file if `key` is set.
+async fn discard_chunk(
+    database: &DatabaseConnection,
+    backend: &dyn StorageBackend,
+    chunk_id: i64,
+    key: Option<String>,
+) {
+    if let Some(key) = key {
+        if let Err(e) = backend.delete_file(key).await {
+            tracing::warn!("Failed to clean up failed upload: {}", e);
+        }
+    }
+
+    if let Err(e) = Chunk::delete_by_id(chunk_id).exec(database).await {
+        tracing::warn!("Failed to unregister failed chunk: {}", e);
+    }
+}
+
+/// Returns the storage key of a chunk file named after its contents.
+fn content_addressed_key(file_hash: &Hash) -> String {
+    format!("{}.chunk", hex::encode(file_hash.data()))
+}
+
+/// Returns the total compressed size and the fraction of deduplicated data.
+///
+/// Each item is a `(chunk_size, file_size, deduplicated)` tuple. The
+/// fraction is computed from the uncompressed chunk sizes.
+fn summarize_chunks(chunks: impl Iterator<Item = (usize, usize, bool)>) -> (usize, f64) {
+    let (total_size, file_size, deduplicated_size) = chunks.fold(
+        (0, 0, 0),
+        |(total_size, file_size, deduplicated_size),
+         (chunk_size, chunk_file_size, deduplicated)| {
+            (
+                total_size + chunk_size,
+                file_size + chunk_file_size,
+                if deduplicated {
+                    deduplicated_size + chunk_size
+                } else {
+                    deduplicated_size
+                },
+            )
+        },
+    );
+
+    let frac_deduplicated = if total_size == 0 {
+        0.0
+    } else {
+        deduplicated_size as f64 / total_size as f64
+    };
+
+    (file_size, frac_deduplicated)
+}
+
 /// Returns a compressor function that takes some stream as input.
+///
+/// If `workers` is non-zero, zstd compression is multithreaded.
 fn get_compressor_fn<C: AsyncBufRead + Unpin + Send + 'static>(
     ctype: CompressionType,
     level: CompressionLevel,
+    workers: u32,
 ) -> CompressorFn<C> {
     match ctype {
         CompressionType::None => Box::new(|c| Box::new(c)),
         CompressionType::Brotli => {
             Box::new(move |s| Box::new(BrotliEncoder::with_quality(s, level)))
         }
+        CompressionType::Zstd if workers > 0 => Box::new(move |s| {
+            let params = [ZstdParameter::nb_workers(workers)];
+            Box::new(ZstdEncoder::with_quality_and_params(s, level, &params))
+        }),
         CompressionType::Zstd => Box::new(move |s| Box::new(ZstdEncoder::with_quality(s, level))),
         CompressionType::Xz => Box::new(move |s| Box::new(XzEncoder::with_quality(s, level))),
+        CompressionType::Lz4 => Box::new(move |s| Box::new(Lz4Encoder::with_quality(s, level))),
     }
 }
 
@@ -891,11 +1213,85 @@ impl CompressionStream {
 }
 
 impl UploadPathNarInfoExt for UploadPathNarInfo {
+    fn validate(&self) -> ServerResult<()> {
+        if let Some(system) = &self.system {
+            let valid = !system.is_empty()
+                && system.len() <= MAX_SYSTEM_LENGTH
+                && !system.contains(|c: char| c.is_whitespace() || c.is_control());
+
+            if !valid {
+                return Err(ErrorKind::RequestError(anyhow!("Invalid system")).into());
+            }
+        }
+
+        if let Some(deriver) = &self.deriver {
+            let valid = deriver.ends_with(".drv")
+                && StorePath::from_base_name(PathBuf::from(deriver)).is_ok();
+
+            if !valid {
+                return Err(ErrorKind::RequestError(anyhow!("Invalid deriver")).into());
+            }
+        }
+
+        if let Some(ca) = &self.ca {
+            ContentAddress::parse(ca)?;
+        }
+
+        Ok(())
+    }
+
+    fn validate_store_path(&self, store_dir: &str) -> ServerResult<()> {
+        let store_path =
+            StorePath::from_full_path(Path::new(store_dir), Path::new(&self.store_path))?;
+
+        if store_path.to_hash() != self.store_path_hash {
+            return Err(ErrorKind::RequestError(anyhow!(
+                "Store path hash does not match the store path"
+            ))
+            .into());
+        }
+
+        Ok(())
+    }
+
+    fn verify_signatures(&mut self, config: &UploadSignaturesConfig) -> ServerResult<()> {
+        if config.policy == UploadSignaturePolicy::Keep {
+            return Ok(());
+        }
+
+        let fingerprint = self.fingerprint();
+        let is_trusted = |sig: &String| {
+            config
+                .trusted_public_keys
+                .iter()
+                .any(|key| key.verify(&fingerprint, sig).is_ok())
+        };
+
+        match config.policy {
+            UploadSignaturePolicy::Keep => {}
+            UploadSignaturePolicy::Strip => {
+                self.sigs.retain(is_trusted);
+            }
+            UploadSignaturePolicy::Reject => {
+                if let Some(sig) = self.sigs.iter().find(|sig| !is_trusted(sig)) {
+                    return Err(ErrorKind::RequestError(anyhow!(
+                        "Signature \"{}\" is not from a trusted key",
+                        sig
+                    ))
+                    .into());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     fn to_active_model(&self) -> object::ActiveModel {
         object::ActiveModel {
             store_path_hash: Set(self.store_path_hash.to_string()),
             store_path: Set(self.store_path.clone()),
             references: Set(DbJson(self.references.clone())),
+            system: Set(self.system.clone()),
             deriver: Set(self.deriver.clone()),
             sigs: Set(DbJson(self.sigs.clone())),
             ca: Set(self.ca.clone()),
@@ -903,3 +1299,896 @@ impl UploadPathNarInfoExt for UploadPathNarInfo {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+
+    use attic::nix_store::StorePathHash;
+    use attic::signing::NixKeypair;
+
+    #[test]
+    fn test_validate_system() {
+        let upload_info = |system: Option<&str>| UploadPathNarInfo {
+            cache: "test".parse().unwrap(),
+            store_path_hash: StorePathHash::new("xcp9cav49dmsjbwdjlmkjxj10gkpx553".to_string())
+                .unwrap(),
+            store_path: "/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10".to_string(),
+            references: Vec::new(),
+            system: system.map(str::to_string),
+            deriver: None,
+            sigs: Vec::new(),
+            ca: None,
+            nar_hash: Hash::Sha256([0; 32]),
+            nar_size: 0,
+        };
+
+        assert!(upload_info(None).validate().is_ok());
+        assert!(upload_info(Some("x86_64-linux")).validate().is_ok());
+        assert!(upload_info(Some("aarch64-darwin")).validate().is_ok());
+
+        assert!(upload_info(Some("")).validate().is_err());
+        assert!(upload_info(Some("x86_64-linux\nSig: evil"))
+            .validate()
+            .is_err());
+        assert!(upload_info(Some("x86_64 linux")).validate().is_err());
+        assert!(upload_info(Some(&"x".repeat(65))).validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_deriver() {
+        let upload_info = |deriver: Option<&str>| UploadPathNarInfo {
+            cache: "test".parse().unwrap(),
+            store_path_hash: StorePathHash::new("xcp9cav49dmsjbwdjlmkjxj10gkpx553".to_string())
+                .unwrap(),
+            store_path: "/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10".to_string(),
+            references: Vec::new(),
+            system: None,
+            deriver: deriver.map(str::to_string),
+            sigs: Vec::new(),
+            ca: None,
+            nar_hash: Hash::Sha256([0; 32]),
+            nar_size: 0,
+        };
+
+        assert!(upload_info(None).validate().is_ok());
+        assert!(
+            upload_info(Some("vvb4wxmnjixmrkhmj2xb75z62hrr41i7-hello-2.10.drv"))
+                .validate()
+                .is_ok()
+        );
+
+        assert!(upload_info(Some("")).validate().is_err());
+        assert!(upload_info(Some("unknown-deriver")).validate().is_err());
+        assert!(
+            upload_info(Some("vvb4wxmnjixmrkhmj2xb75z62hrr41i7-hello-2.10"))
+                .validate()
+                .is_err()
+        );
+        assert!(upload_info(Some(
+            "/nix/store/vvb4wxmnjixmrkhmj2xb75z62hrr41i7-hello-2.10.drv"
+        ))
+        .validate()
+        .is_err());
+        assert!(upload_info(Some(
+            "vvb4wxmnjixmrkhmj2xb75z62hrr41i7-hello\nSig: evil.drv"
+        ))
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_validate_ca() {
+        let upload_info = |ca: Option<&str>| UploadPathNarInfo {
+            cache: "test".parse().unwrap(),
+            store_path_hash: StorePathHash::new("xcp9cav49dmsjbwdjlmkjxj10gkpx553".to_string())
+                .unwrap(),
+            store_path: "/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10".to_string(),
+            references: Vec::new(),
+            system: None,
+            deriver: None,
+            sigs: Vec::new(),
+            ca: ca.map(str::to_string),
+            nar_hash: Hash::Sha256([0; 32]),
+            nar_size: 0,
+        };
+
+        assert!(upload_info(None).validate().is_ok());
+        assert!(upload_info(Some(
+            "fixed:r:sha256:094qif9n4cq4fdg459qzbhg1c6wywawwaaivx0k0x8xhbyx4vwic"
+        ))
+        .validate()
+        .is_ok());
+        assert!(upload_info(Some(
+            "text:sha256:094qif9n4cq4fdg459qzbhg1c6wywawwaaivx0k0x8xhbyx4vwic"
+        ))
+        .validate()
+        .is_ok());
+
+        for ca in [
+            "",
+            "fixed:r:sha256:meow",
+            "text:sha1:9m1skbnr5i43n3yypvda5s65vhfwdx5a",
+            "fixed:r:sha256:094qif9n4cq4fdg459qzbhg1c6wywawwaaivx0k0x8xhbyx4vwic\nSig: evil",
+        ] {
+            let e = upload_info(Some(ca)).validate().unwrap_err();
+            assert_eq!(
+                StatusCode::BAD_REQUEST,
+                e.into_response().status(),
+                "{}",
+                ca
+            );
+        }
+    }
+
+    #[test]
+    fn test_validate_store_path() {
+        let upload_info = |store_path: &str| UploadPathNarInfo {
+            cache: "test".parse().unwrap(),
+            store_path_hash: StorePathHash::new("xcp9cav49dmsjbwdjlmkjxj10gkpx553".to_string())
+                .unwrap(),
+            store_path: store_path.to_string(),
+            references: Vec::new(),
+            system: None,
+            deriver: None,
+            sigs: Vec::new(),
+            ca: None,
+            nar_hash: Hash::Sha256([0; 32]),
+            nar_size: 0,
+        };
+        let validate = |store_path: &str| upload_info(store_path).validate_store_path("/nix/store");
+
+        validate("/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10").unwrap();
+        upload_info("/gnu/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10")
+            .validate_store_path("/gnu/store")
+            .unwrap();
+
+        for bad in [
+            "/",
+            "",
+            "nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10",
+            "xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10",
+            "/nix/store",
+            "/nix/store/",
+            "/gnu/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10",
+            "/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10/",
+            "/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10/bin/hello",
+            "/nix/store//xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10",
+            "/nix/store/./xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10",
+            "/nix/store/../store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10",
+            "/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-",
+            "/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello\nSig: evil",
+            "/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello\u{0}",
+            "/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-h\u{0435}llo",
+            "/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello 2.10",
+            // Hash doesn't match `store_path_hash`
+            "/nix/store/ia70ss13m22znbl8khrf2hq72qmh5drr-hello-2.10",
+        ] {
+            let e = validate(bad).unwrap_err();
+            assert_eq!(
+                StatusCode::BAD_REQUEST,
+                e.into_response().status(),
+                "{:?}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn test_verify_signatures() {
+        let trusted = NixKeypair::generate("upstream-1").unwrap();
+        let forger = NixKeypair::generate("upstream-1").unwrap();
+
+        let mut upload_info = UploadPathNarInfo {
+            cache: "test".parse().unwrap(),
+            store_path_hash: StorePathHash::new("xcp9cav49dmsjbwdjlmkjxj10gkpx553".to_string())
+                .unwrap(),
+            store_path: "/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10".to_string(),
+            references: Vec::new(),
+            system: None,
+            deriver: None,
+            sigs: Vec::new(),
+            ca: None,
+            nar_hash: Hash::Sha256([0; 32]),
+            nar_size: 0,
+        };
+
+        let good = trusted.sign(&upload_info.fingerprint());
+        let forged = forger.sign(&upload_info.fingerprint());
+        upload_info.sigs = vec![good.clone(), forged];
+
+        let config = |policy| UploadSignaturesConfig {
+            policy,
+            trusted_public_keys: vec![trusted.to_public_key()],
+        };
+
+        let mut keep = upload_info.clone();
+        keep.verify_signatures(&config(UploadSignaturePolicy::Keep))
+            .unwrap();
+        assert_eq!(2, keep.sigs.len());
+
+        let mut strip = upload_info.clone();
+        strip
+            .verify_signatures(&config(UploadSignaturePolicy::Strip))
+            .unwrap();
+        assert_eq!(vec![good.clone()], strip.sigs);
+
+        let mut reject = upload_info.clone();
+        reject
+            .verify_signatures(&config(UploadSignaturePolicy::Reject))
+            .unwrap_err();
+
+        let mut reject = upload_info.clone();
+        reject.sigs = vec![good];
+        reject
+            .verify_signatures(&config(UploadSignaturePolicy::Reject))
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_decode_body() {
+        use axum::http::{HeaderValue, StatusCode};
+        use axum::response::IntoResponse;
+
+        let data = b"some NAR contents ".repeat(1000);
+
+        let mut compressed = Vec::new();
+        ZstdEncoder::new(&data[..])
+            .read_to_end(&mut compressed)
+            .await
+            .unwrap();
+
+        let decode = |encoding: Option<&'static str>, body: Vec<u8>| async move {
+            let mut headers = HeaderMap::new();
+            if let Some(encoding) = encoding {
+                headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding));
+            }
+
+            let mut decoded = Vec::new();
+            decode_body(&headers, Cursor::new(body))?
+                .read_to_end(&mut decoded)
+                .await
+                .map_err(ServerError::request_error)?;
+
+            ServerResult::Ok(decoded)
+        };
+
+        assert_eq!(data, decode(None, data.clone()).await.unwrap());
+        assert_eq!(data, decode(Some("identity"), data.clone()).await.unwrap());
+        assert_eq!(
+            data,
+            decode(Some("zstd"), compressed.clone()).await.unwrap()
+        );
+
+        // Truncated streams are errors rather than short NARs
+        for len in [0, 1, compressed.len() / 2, compressed.len() - 1] {
+            let truncated = compressed[..len].to_vec();
+            assert!(
+                decode(Some("zstd"), truncated).await.is_err(),
+                "Truncated to {} bytes",
+                len
+            );
+        }
+
+        // Uncompressed data isn't mistaken for a zstd stream
+        assert!(decode(Some("zstd"), data.clone()).await.is_err());
+
+        let e = decode(Some("gzip"), data).await.unwrap_err();
+        assert_eq!(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            e.into_response().status()
+        );
+    }
+
+    #[test]
+    fn test_summarize_chunks() {
+        // Nothing deduplicated
+        let (file_size, frac) =
+            summarize_chunks(vec![(100, 50, false), (300, 100, false)].into_iter());
+        assert_eq!(150, file_size);
+        assert_eq!(0.0, frac);
+
+        // Partially deduplicated
+        let (file_size, frac) =
+            summarize_chunks(vec![(100, 50, true), (300, 100, false)].into_iter());
+        assert_eq!(150, file_size);
+        assert_eq!(0.25, frac);
+
+        // Everything deduplicated
+        let (file_size, frac) = summarize_chunks(std::iter::once((100, 50, true)));
+        assert_eq!(50, file_size);
+        assert_eq!(1.0, frac);
+
+        // Empty
+        let (file_size, frac) = summarize_chunks(std::iter::empty());
+        assert_eq!(0, file_size);
+        assert_eq!(0.0, frac);
+    }
+
+    #[tokio::test]
+    async fn test_multithreaded_zstd() {
+        use async_compression::tokio::bufread::ZstdDecoder;
+
+        let data: Vec<u8> = (0..4_000_000u32).map(|i| (i % 251) as u8).collect();
+
+        let compressor = get_compressor_fn(CompressionType::Zstd, CompressionLevel::Default, 2);
+        let mut compressed = Vec::new();
+        compressor(std::io::Cursor::new(data.clone()))
+            .read_to_end(&mut compressed)
+            .await
+            .unwrap();
+
+        let mut decompressed = Vec::new();
+        ZstdDecoder::new(compressed.as_slice())
+            .read_to_end(&mut decompressed)
+            .await
+            .unwrap();
+
+        assert_eq!(data, decompressed);
+    }
+
+    #[tokio::test]
+    async fn test_content_addressed_chunks() {
+        use crate::config::Config;
+        use crate::database::migration::{Migrator, MigratorTrait};
+        use crate::StateInner;
+
+        let storage_path = std::env::temp_dir().join(format!("attic-test-{}", Uuid::new_v4()));
+        let config: Config = toml::from_str(&format!(
+            r#"
+content-addressed-chunks = true
+
+[database]
+url = "sqlite::memory:"
+
+[storage]
+type = "local"
+path = "{}"
+
+[chunking]
+nar-size-threshold = 0
+min-size = 16384
+avg-size = 65536
+max-size = 262144
+
+[jwt.signing]
+token-hs256-secret-base64 = "dmVyeSBzZWN1cmUgc2VjcmV0"
+"#,
+            storage_path.display()
+        ))
+        .unwrap();
+
+        let state = StateInner::new(config).await;
+        let database = state.database().await.unwrap().clone();
+        Migrator::up(&database, None).await.unwrap();
+
+        let data = Bytes::from_static(b"some chunk contents");
+        let upload = || {
+            upload_chunk(
+                ChunkData::Bytes(data.clone()),
+                CompressionType::None,
+                CompressionLevel::Default,
+                0,
+                database.clone(),
+                state.clone(),
+                ChunkDedup::global(true),
+            )
+        };
+
+        // Racing uploads end up with the same chunk
+        let (a, b) = tokio::join!(upload(), upload());
+        let (a, b) = (a.unwrap(), b.unwrap());
+        assert_eq!(a.guard.id, b.guard.id);
+        assert!(a.deduplicated || b.deduplicated);
+
+        let key = content_addressed_key(&Hash::sha256_from_bytes(&data));
+        let remote_file = state
+            .storage()
+            .await
+            .unwrap()
+            .make_db_reference(key)
+            .await
+            .unwrap();
+        assert_eq!(remote_file.remote_file_id(), a.guard.remote_file_id);
+        assert_eq!(1, Chunk::find().count(&database).await.unwrap());
+
+        // Later uploads are deduplicated as usual
+        let c = upload().await.unwrap();
+        assert_eq!(a.guard.id, c.guard.id);
+        assert!(c.deduplicated);
+
+        // No temporary files are left behind
+        let mut files = Vec::new();
+        let mut dirs = vec![storage_path.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if path.extension() == Some("chunk".as_ref()) {
+                    files.push(path);
+                }
+            }
+        }
+        assert_eq!(1, files.len());
+
+        drop((a, b, c));
+        std::fs::remove_dir_all(&storage_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_per_cache_deduplication() {
+        use crate::config::Config;
+        use crate::database::entity::cache::Entity as Cache;
+        use crate::database::migration::{Migrator, MigratorTrait};
+        use crate::StateInner;
+
+        let storage_path = std::env::temp_dir().join(format!("attic-test-{}", Uuid::new_v4()));
+        let config: Config = toml::from_str(&format!(
+            r#"
+[database]
+url = "sqlite::memory:"
+
+[storage]
+type = "local"
+path = "{}"
+
+[chunking]
+nar-size-threshold = 0
+min-size = 16384
+avg-size = 65536
+max-size = 262144
+
+[jwt.signing]
+token-hs256-secret-base64 = "dmVyeSBzZWN1cmUgc2VjcmV0"
+"#,
+            storage_path.display()
+        ))
+        .unwrap();
+
+        let state = StateInner::new(config).await;
+        let database = state.database().await.unwrap().clone();
+        Migrator::up(&database, None).await.unwrap();
+
+        let cache_id = Cache::insert(cache::ActiveModel {
+            name: Set("isolated".to_string()),
+            keypair: Set(String::new()),
+            is_public: Set(false),
+            store_dir: Set("/nix/store".to_string()),
+            priority: Set(41),
+            upstream_cache_key_names: Set(DbJson(Vec::new())),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(&database)
+        .await
+        .unwrap()
+        .last_insert_id;
+
+        let data = Bytes::from_static(b"some chunk contents");
+        let upload = |dedup: ChunkDedup| {
+            upload_chunk(
+                ChunkData::Bytes(data.clone()),
+                CompressionType::None,
+                CompressionLevel::Default,
+                0,
+                database.clone(),
+                state.clone(),
+                dedup,
+            )
+        };
+        let isolated = ChunkDedup {
+            require_proof_of_possession: false,
+            cache_id: Some(cache_id),
+        };
+
+        // A chunk in no cache
+        let a = upload(ChunkDedup::global(false)).await.unwrap();
+        assert!(!a.deduplicated);
+
+        // Isn't visible to the cache
+        let b = upload(isolated).await.unwrap();
+        assert!(!b.deduplicated);
+        assert_ne!(a.guard.id, b.guard.id);
+
+        // Until an object in the cache references it
+        let nar_id = Nar::insert(nar::ActiveModel {
+            state: Set(NarState::Valid),
+            nar_hash: Set(Hash::sha256_from_bytes(&data).to_typed_base16()),
+            nar_size: Set(data.len() as i64),
+            compression: Set("none".to_string()),
+            num_chunks: Set(1),
+            completeness_hint: Set(true),
+            holders_count: Set(0),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(&database)
+        .await
+        .unwrap()
+        .last_insert_id;
+
+        insert_chunkref(
+            &database,
+            chunkref::ActiveModel {
+                nar_id: Set(nar_id),
+                seq: Set(0),
+                chunk_id: Set(Some(a.guard.id)),
+                chunk_hash: Set(a.guard.chunk_hash.clone()),
+                compression: Set("none".to_string()),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        Object::insert(object::ActiveModel {
+            cache_id: Set(cache_id),
+            nar_id: Set(nar_id),
+            store_path_hash: Set("xcp9cav49dmsjbwdjlmkjxj10gkpx553".to_string()),
+            store_path: Set("/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10".to_string()),
+            references: Set(DbJson(Vec::new())),
+            sigs: Set(DbJson(Vec::new())),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(&database)
+        .await
+        .unwrap();
+
+        let c = upload(isolated).await.unwrap();
+        assert!(c.deduplicated);
+        assert_eq!(a.guard.id, c.guard.id);
+
+        drop((a, b, c));
+        std::fs::remove_dir_all(&storage_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_chunk_proof_of_possession_limit() {
+        use crate::config::Config;
+        use crate::database::migration::{Migrator, MigratorTrait};
+        use crate::StateInner;
+
+        let storage_path = std::env::temp_dir().join(format!("attic-test-{}", Uuid::new_v4()));
+        let config: Config = toml::from_str(&format!(
+            r#"
+[database]
+url = "sqlite::memory:"
+
+[storage]
+type = "local"
+path = "{}"
+
+[chunking]
+nar-size-threshold = 0
+min-size = 16384
+avg-size = 65536
+max-size = 262144
+
+[jwt.signing]
+token-hs256-secret-base64 = "dmVyeSBzZWN1cmUgc2VjcmV0"
+"#,
+            storage_path.display()
+        ))
+        .unwrap();
+
+        let state = StateInner::new(config).await;
+        let database = state.database().await.unwrap().clone();
+        Migrator::up(&database, None).await.unwrap();
+
+        let data = Bytes::from_static(b"some chunk contents");
+        let existing = upload_chunk(
+            ChunkData::Bytes(data.clone()),
+            CompressionType::None,
+            CompressionLevel::Default,
+            0,
+            database.clone(),
+            state.clone(),
+            ChunkDedup::global(false),
+        )
+        .await
+        .unwrap();
+
+        // An endless stream claiming to be the existing chunk
+        let garbage = ChunkData::Stream(
+            Box::new(tokio::io::repeat(0)),
+            Hash::sha256_from_bytes(&data),
+            data.len(),
+        );
+        let result = tokio::time::timeout(
+            std::time::Duration::from_secs(30),
+            upload_chunk(
+                garbage,
+                CompressionType::None,
+                CompressionLevel::Default,
+                0,
+                database.clone(),
+                state.clone(),
+                ChunkDedup::global(true),
+            ),
+        )
+        .await
+        .expect("Stream wasn't cut off");
+
+        let Err(e) = result else {
+            panic!("Endless stream was accepted");
+        };
+        assert_eq!(StatusCode::BAD_REQUEST, e.into_response().status());
+
+        drop(existing);
+        std::fs::remove_dir_all(&storage_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_size_limits() {
+        use axum::body::Body;
+        use axum::http::{header, Method, Request, StatusCode};
+        use tower::ServiceExt;
+
+        use crate::config::Config;
+        use crate::{make_router, StateInner};
+
+        let storage_path = std::env::temp_dir().join(format!("attic-test-{}", Uuid::new_v4()));
+        let config: Config = toml::from_str(&format!(
+            r#"
+[database]
+url = "sqlite::memory:"
+
+[storage]
+type = "local"
+path = "{}"
+
+[chunking]
+nar-size-threshold = 0
+min-size = 16384
+avg-size = 65536
+max-size = 262144
+
+[limits]
+max-nar-info-size = 1024
+max-nar-size = 4096
+
+[jwt.signing]
+token-hs256-secret-base64 = "dmVyeSBzZWN1cmUgc2VjcmV0"
+"#,
+            storage_path.display()
+        ))
+        .unwrap();
+
+        let app = make_router(StateInner::new(config).await);
+
+        let upload_info = |nar_size: usize| UploadPathNarInfo {
+            cache: "test".parse().unwrap(),
+            store_path_hash: StorePathHash::new("xcp9cav49dmsjbwdjlmkjxj10gkpx553".to_string())
+                .unwrap(),
+            store_path: "/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10".to_string(),
+            references: Vec::new(),
+            system: None,
+            deriver: None,
+            sigs: Vec::new(),
+            ca: None,
+            nar_hash: Hash::Sha256([0; 32]),
+            nar_size,
+        };
+
+        let request = |headers: &[(&str, String)], body: Vec<u8>| {
+            let mut builder = Request::builder()
+                .method(Method::PUT)
+                .uri("/_api/v1/upload-path")
+                .header(header::HOST, "localhost");
+            for (name, value) in headers {
+                builder = builder.header(*name, value);
+            }
+            builder.body(Body::from(body)).unwrap()
+        };
+
+        // Oversized preamble is rejected before it's read
+        let res = app
+            .clone()
+            .oneshot(request(
+                &[(ATTIC_NAR_INFO_PREAMBLE_SIZE, "1000000000".to_string())],
+                Vec::new(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, res.status());
+
+        // Oversized header
+        let mut big_info = upload_info(0);
+        big_info.sigs = vec!["x".repeat(2048)];
+        let res = app
+            .clone()
+            .oneshot(request(
+                &[(ATTIC_NAR_INFO, serde_json::to_string(&big_info).unwrap())],
+                Vec::new(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, res.status());
+
+        // Oversized NAR
+        let res = app
+            .clone()
+            .oneshot(request(
+                &[(
+                    ATTIC_NAR_INFO,
+                    serde_json::to_string(&upload_info(4097)).unwrap(),
+                )],
+                Vec::new(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, res.status());
+
+        // Within the limits, the request proceeds to authorization
+        let res = app
+            .oneshot(request(
+                &[(
+                    ATTIC_NAR_INFO,
+                    serde_json::to_string(&upload_info(4096)).unwrap(),
+                )],
+                Vec::new(),
+            ))
+            .await
+            .unwrap();
+        assert_ne!(StatusCode::PAYLOAD_TOO_LARGE, res.status());
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_chunks() {
+        use std::collections::HashSet;
+
+        use axum::http::{header, Method, Request, StatusCode};
+        use tower::ServiceExt;
+
+        use crate::config::Config;
+        use crate::database::entity::cache::Entity as Cache;
+        use crate::database::migration::{Migrator, MigratorTrait};
+        use crate::{make_router, StateInner};
+
+        let storage_path = std::env::temp_dir().join(format!("attic-test-{}", Uuid::new_v4()));
+        let config: Config = toml::from_str(&format!(
+            r#"
+[database]
+url = "sqlite::memory:"
+
+[storage]
+type = "local"
+path = "{}"
+
+[chunking]
+nar-size-threshold = 1
+min-size = 64
+avg-size = 256
+max-size = 1024
+
+[compression]
+type = "none"
+
+[jwt.signing]
+token-hs256-secret-base64 = "dmVyeSBzZWN1cmUgc2VjcmV0"
+"#,
+            storage_path.display()
+        ))
+        .unwrap();
+
+        let state = StateInner::new(config).await;
+        let database = state.database().await.unwrap().clone();
+        Migrator::up(&database, None).await.unwrap();
+
+        let keypair = NixKeypair::generate("test").unwrap();
+        let cache_id = Cache::insert(cache::ActiveModel {
+            name: Set("test".to_string()),
+            keypair: Set(keypair.export_keypair()),
+            is_public: Set(true),
+            store_dir: Set("/nix/store".to_string()),
+            priority: Set(41),
+            upstream_cache_key_names: Set(DbJson(Vec::new())),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(&database)
+        .await
+        .unwrap()
+        .last_insert_id;
+        let cache = Cache::find_by_id(cache_id)
+            .one(&database)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Two identical files chunk the same way once the chunker
+        // resynchronizes, so most chunks appear twice
+        let mut seed = 0x2545f4914f6cdd1du64;
+        let file: Vec<u8> = (0..32768)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect();
+        let nar = [file.as_slice(), file.as_slice()].concat();
+
+        let upload_info = UploadPathNarInfo {
+            cache: "test".parse().unwrap(),
+            store_path_hash: StorePathHash::new("xcp9cav49dmsjbwdjlmkjxj10gkpx553".to_string())
+                .unwrap(),
+            store_path: "/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10".to_string(),
+            references: Vec::new(),
+            system: None,
+            deriver: None,
+            sigs: Vec::new(),
+            ca: None,
+            nar_hash: Hash::sha256_from_bytes(&nar),
+            nar_size: nar.len(),
+        };
+
+        let Json(result) = upload_path_new_chunked(
+            None,
+            cache,
+            upload_info,
+            Cursor::new(nar.clone()),
+            &database,
+            &state,
+        )
+        .await
+        .unwrap();
+        assert!(result.frac_deduplicated.unwrap() > 0.0);
+
+        let chunkrefs = ChunkRef::find().all(&database).await.unwrap();
+        let chunk_ids: Vec<i64> = chunkrefs.iter().filter_map(|cr| cr.chunk_id).collect();
+        let distinct: HashSet<i64> = chunk_ids.iter().copied().collect();
+        assert_eq!(chunkrefs.len(), chunk_ids.len());
+        assert!(distinct.len() < chunk_ids.len(), "No duplicate chunks");
+
+        let mut seqs: Vec<i32> = chunkrefs.iter().map(|cr| cr.seq).collect();
+        seqs.sort();
+        assert_eq!((0..chunkrefs.len() as i32).collect::<Vec<_>>(), seqs);
+
+        // Each reference is counted
+        for chunk in Chunk::find().all(&database).await.unwrap() {
+            let count = chunk_ids.iter().filter(|id| **id == chunk.id).count();
+            assert_eq!(count as i64, chunk.reference_count);
+        }
+
+        // The NAR is served intact
+        let app = make_router(state);
+        let get = |uri: String| {
+            app.clone().oneshot(
+                Request::builder()
+                    .method(Method::GET)
+                    .uri(uri)
+                    .header(header::HOST, "localhost")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let res = get("/test/xcp9cav49dmsjbwdjlmkjxj10gkpx553.narinfo".to_string())
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let narinfo = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let narinfo = String::from_utf8(narinfo.to_vec()).unwrap();
+        let url = narinfo
+            .lines()
+            .find_map(|line| line.strip_prefix("URL: "))
+            .unwrap();
+
+        let res = get(format!("/test/{}", url)).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let served = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(served == nar, "Served NAR differs");
+
+        std::fs::remove_dir_all(&storage_path).unwrap();
+    }
+}
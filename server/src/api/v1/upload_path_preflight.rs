@@ -0,0 +1,314 @@
+//! Upload preflight endpoint.
+
+use axum::extract::{Extension, Json};
+use sea_orm::entity::prelude::*;
+use sea_orm::QuerySelect;
+use tracing::instrument;
+
+use crate::api::v1::upload_path::ChunkDedup;
+use crate::database::entity::chunkref::{self, Entity as ChunkRef};
+use crate::database::entity::nar::{self, Entity as Nar, NarState};
+use crate::database::nar_ids_in_cache;
+use crate::error::{ServerError, ServerResult};
+use crate::{RequestState, State};
+use attic::api::v1::upload_path::{
+    UploadPathPreflightRequest, UploadPathPreflightResult, UploadPathPreflightResultKind,
+};
+
+/// Checks whether a NAR needs to be uploaded.
+///
+/// This lets clients skip streaming NARs that would be deduplicated
+/// anyway. Requires "push" permission as it allows probing of the
+/// global NAR store.
+#[instrument(skip_all, fields(payload))]
+pub(crate) async fn upload_path_preflight(
+    Extension(state): Extension<State>,
+    Extension(req_state): Extension<RequestState>,
+    Json(payload): Json<UploadPathPreflightRequest>,
+) -> ServerResult<Json<UploadPathPreflightResult>> {
+    let database = state.database().await?;
+    let cache = req_state
+        .auth
+        .auth_cache(database, &payload.cache, |cache, permission| {
+            permission.require_push()?;
+            Ok(cache)
+        })
+        .await?;
+
+    let dedup = ChunkDedup::for_cache(&state, &cache);
+    let kind = if has_complete_nar(database, &payload, dedup.cache_id).await? {
+        if state.config.require_proof_of_possession {
+            UploadPathPreflightResultKind::ProofOfPossession
+        } else {
+            UploadPathPreflightResultKind::Deduplicated
+        }
+    } else {
+        UploadPathPreflightResultKind::Required
+    };
+
+    Ok(Json(UploadPathPreflightResult { kind }))
+}
+
+/// Returns whether a NAR exists with all of its chunks.
+///
+/// If `cache_id` is specified, only NARs in the cache are considered.
+async fn has_complete_nar(
+    database: &DatabaseConnection,
+    payload: &UploadPathPreflightRequest,
+    cache_id: Option<i64>,
+) -> ServerResult<bool> {
+    let mut query = Nar::find()
+        .filter(nar::Column::NarHash.eq(payload.nar_hash.to_typed_base16()))
+        .filter(nar::Column::NarSize.eq(payload.nar_size as i64))
+        .filter(nar::Column::State.eq(NarState::Valid))
+        .filter(nar::Column::CompletenessHint.eq(true));
+    if let Some(cache_id) = cache_id {
+        query = query.filter(nar::Column::Id.in_subquery(nar_ids_in_cache(cache_id)));
+    }
+
+    let nar = query
+        .one(database)
+        .await
+        .map_err(ServerError::database_error)?;
+
+    let Some(nar) = nar else {
+        return Ok(false);
+    };
+
+    // Detached chunks must be repaired with a full upload
+    let missing_chunk = ChunkRef::find()
+        .filter(chunkref::Column::NarId.eq(nar.id))
+        .filter(chunkref::Column::ChunkId.is_null())
+        .limit(1)
+        .one(database)
+        .await
+        .map_err(ServerError::database_error)?;
+
+    Ok(missing_chunk.is_none())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+    use chrono::{Duration as ChronoDuration, Utc};
+    use sea_orm::ActiveValue::Set;
+
+    use crate::access::http::AuthState;
+    use crate::access::Token;
+    use crate::config::Config;
+    use crate::database::entity::cache::{self, Entity as Cache};
+    use crate::database::entity::Json as DbJson;
+    use crate::database::migration::{Migrator, MigratorTrait};
+    use crate::{RequestStateInner, StateInner};
+    use attic::hash::Hash;
+
+    async fn make_state(require_proof_of_possession: bool) -> State {
+        make_state_with(&format!(
+            "require-proof-of-possession = {require_proof_of_possession}"
+        ))
+        .await
+    }
+
+    async fn make_state_with(options: &str) -> State {
+        let config: Config = toml::from_str(&format!(
+            r#"
+{options}
+
+[database]
+url = "sqlite::memory:"
+
+[storage]
+type = "local"
+path = "/nonexistent"
+
+[chunking]
+nar-size-threshold = 0
+min-size = 16384
+avg-size = 65536
+max-size = 262144
+
+[jwt.signing]
+token-hs256-secret-base64 = "dmVyeSBzZWN1cmUgc2VjcmV0"
+"#
+        ))
+        .unwrap();
+
+        let state = StateInner::new(config).await;
+        let db = state.database().await.unwrap();
+        Migrator::up(db, None).await.unwrap();
+
+        Cache::insert(cache::ActiveModel {
+            name: Set("demo".to_string()),
+            keypair: Set(String::new()),
+            is_public: Set(false),
+            store_dir: Set("/nix/store".to_string()),
+            priority: Set(41),
+            upstream_cache_key_names: Set(DbJson(Vec::new())),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap();
+
+        state
+    }
+
+    fn make_req_state(push: bool) -> RequestState {
+        let auth = AuthState::new();
+
+        let mut token = Token::new("meow".to_string(), &(Utc::now() + ChronoDuration::days(1)));
+        let permission = token.get_or_insert_permission_mut("demo".parse().unwrap());
+        permission.pull = true;
+        permission.push = push;
+        auth.token.set(token).unwrap();
+
+        Arc::new(RequestStateInner {
+            auth,
+            api_endpoint: None,
+            substituter_endpoint: None,
+            host: "localhost".to_string(),
+            client_claims_https: false,
+            public_cache: AtomicBool::new(false),
+        })
+    }
+
+    fn nar_hash() -> Hash {
+        Hash::from_typed("sha256:91e129ac1959d062ad093d2b1f8b65afae0f712056fe3eac78ec530ff6a1bb9a")
+            .unwrap()
+    }
+
+    async fn insert_nar(state: &State, num_missing_chunks: usize) -> i64 {
+        let db = state.database().await.unwrap();
+
+        let nar_id = Nar::insert(nar::ActiveModel {
+            state: Set(NarState::Valid),
+            nar_hash: Set(nar_hash().to_typed_base16()),
+            nar_size: Set(1000),
+            compression: Set("none".to_string()),
+            num_chunks: Set(num_missing_chunks as i32),
+            completeness_hint: Set(true),
+            holders_count: Set(0),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap()
+        .last_insert_id;
+
+        for seq in 0..num_missing_chunks {
+            ChunkRef::insert(chunkref::ActiveModel {
+                nar_id: Set(nar_id),
+                seq: Set(seq as i32),
+                chunk_id: Set(None),
+                chunk_hash: Set(String::new()),
+                compression: Set("none".to_string()),
+                ..Default::default()
+            })
+            .exec(db)
+            .await
+            .unwrap();
+        }
+
+        nar_id
+    }
+
+    async fn preflight(
+        state: &State,
+        req_state: RequestState,
+        nar_size: usize,
+    ) -> ServerResult<UploadPathPreflightResultKind> {
+        upload_path_preflight(
+            Extension(state.clone()),
+            Extension(req_state),
+            Json(UploadPathPreflightRequest {
+                cache: "demo".parse().unwrap(),
+                nar_hash: nar_hash(),
+                nar_size,
+            }),
+        )
+        .await
+        .map(|Json(result)| result.kind)
+    }
+
+    #[tokio::test]
+    async fn test_preflight() {
+        let state = make_state(false).await;
+
+        let kind = preflight(&state, make_req_state(true), 1000).await.unwrap();
+        assert_eq!(UploadPathPreflightResultKind::Required, kind);
+
+        insert_nar(&state, 0).await;
+
+        let kind = preflight(&state, make_req_state(true), 1000).await.unwrap();
+        assert_eq!(UploadPathPreflightResultKind::Deduplicated, kind);
+
+        // The size must match as well
+        let kind = preflight(&state, make_req_state(true), 1001).await.unwrap();
+        assert_eq!(UploadPathPreflightResultKind::Required, kind);
+
+        let e = preflight(&state, make_req_state(false), 1000)
+            .await
+            .unwrap_err();
+        assert_eq!(StatusCode::FORBIDDEN, e.into_response().status());
+    }
+
+    #[tokio::test]
+    async fn test_preflight_proof_of_possession() {
+        let state = make_state(true).await;
+        insert_nar(&state, 0).await;
+
+        let kind = preflight(&state, make_req_state(true), 1000).await.unwrap();
+        assert_eq!(UploadPathPreflightResultKind::ProofOfPossession, kind);
+    }
+
+    #[tokio::test]
+    async fn test_preflight_missing_chunks() {
+        let state = make_state(false).await;
+        insert_nar(&state, 1).await;
+
+        let kind = preflight(&state, make_req_state(true), 1000).await.unwrap();
+        assert_eq!(UploadPathPreflightResultKind::Required, kind);
+    }
+
+    #[tokio::test]
+    async fn test_preflight_per_cache_deduplication() {
+        use crate::database::entity::object::{self, Entity as Object};
+        use crate::database::AtticDatabase;
+
+        let state =
+            make_state_with("require-proof-of-possession = false\nper-cache-deduplication = true")
+                .await;
+        let nar_id = insert_nar(&state, 0).await;
+
+        // The NAR isn't in this cache
+        let kind = preflight(&state, make_req_state(true), 1000).await.unwrap();
+        assert_eq!(UploadPathPreflightResultKind::Required, kind);
+
+        let db = state.database().await.unwrap();
+        let cache = db.find_cache(&"demo".parse().unwrap()).await.unwrap();
+        Object::insert(object::ActiveModel {
+            cache_id: Set(cache.id),
+            nar_id: Set(nar_id),
+            store_path_hash: Set("xcp9cav49dmsjbwdjlmkjxj10gkpx553".to_string()),
+            store_path: Set("/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10".to_string()),
+            references: Set(DbJson(Vec::new())),
+            sigs: Set(DbJson(Vec::new())),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap();
+
+        let kind = preflight(&state, make_req_state(true), 1000).await.unwrap();
+        assert_eq!(UploadPathPreflightResultKind::Deduplicated, kind);
+    }
+}
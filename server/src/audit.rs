@@ -0,0 +1,326 @@
+//! Audit logging.
+//!
+//! Actions that change what a cache contains or how it's configured
+//! are emitted as structured events to the `attic::audit` tracing
+//! target. If `audit.store-in-database` is enabled, they are also
+//! recorded in the `audit_log` table, which can be queried with
+//! `atticadm audit tail`.
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use sea_orm::entity::prelude::*;
+use sea_orm::query::{QueryOrder, QuerySelect};
+use sea_orm::ActiveValue::Set;
+
+use crate::config::Config;
+use crate::database::entity::audit_log::{self, AuditAction, AuditLogModel, Entity as AuditLog};
+use crate::error::{ServerError, ServerResult};
+use crate::{RequestState, State, StateInner};
+use attic::cache::CacheName;
+
+/// Filters for querying the audit log.
+#[derive(Debug, Clone)]
+pub struct AuditLogFilter {
+    /// Only return events on this cache.
+    pub cache: Option<CacheName>,
+
+    /// Only return events at or after this time.
+    pub since: Option<DateTime<Utc>>,
+
+    /// Only return events before this time.
+    pub until: Option<DateTime<Utc>>,
+
+    /// Maximum number of events to return.
+    pub limit: u64,
+}
+
+/// An audit event.
+#[derive(Debug, Clone)]
+pub(crate) struct AuditEvent {
+    action: AuditAction,
+    subject: Option<String>,
+    cache: String,
+    store_path: Option<String>,
+    nar_hash: Option<String>,
+    details: Option<String>,
+}
+
+impl AuditEvent {
+    /// Creates an event for an action performed by the requester.
+    pub fn new(action: AuditAction, req_state: &RequestState, cache: &CacheName) -> Self {
+        Self {
+            action,
+            subject: req_state.auth.username().map(str::to_string),
+            cache: cache.to_string(),
+            store_path: None,
+            nar_hash: None,
+            details: None,
+        }
+    }
+
+    /// Creates an event for an action performed by the server itself.
+    ///
+    /// The subject names the component responsible, like `gc`.
+    pub fn internal(action: AuditAction, subject: &str, cache: &CacheName) -> Self {
+        Self {
+            action,
+            subject: Some(subject.to_string()),
+            cache: cache.to_string(),
+            store_path: None,
+            nar_hash: None,
+            details: None,
+        }
+    }
+
+    /// Sets the store path the action was performed on.
+    pub fn store_path(mut self, store_path: impl Into<String>) -> Self {
+        self.store_path = Some(store_path.into());
+        self
+    }
+
+    /// Sets the hash of the NAR the action was performed on.
+    pub fn nar_hash(mut self, nar_hash: impl Into<String>) -> Self {
+        self.nar_hash = Some(nar_hash.into());
+        self
+    }
+
+    /// Sets additional details about the action.
+    pub fn details(mut self, details: impl Into<String>) -> Self {
+        self.details = Some(details.into());
+        self
+    }
+}
+
+/// Records an audit event.
+///
+/// This is best-effort: the action has already been performed, so
+/// failing to store the event is logged instead of failing the request.
+pub(crate) async fn record(state: &State, event: AuditEvent) {
+    tracing::info!(
+        target: "attic::audit",
+        action = %event.action.to_value(),
+        subject = event.subject.as_deref(),
+        cache = %event.cache,
+        store_path = event.store_path.as_deref(),
+        nar_hash = event.nar_hash.as_deref(),
+        details = event.details.as_deref(),
+        "Audit event"
+    );
+
+    if !state.config.audit.store_in_database {
+        return;
+    }
+
+    let result = async {
+        let database = state.database().await?;
+
+        AuditLog::insert(audit_log::ActiveModel {
+            action: Set(event.action),
+            subject: Set(event.subject),
+            cache: Set(event.cache),
+            store_path: Set(event.store_path),
+            nar_hash: Set(event.nar_hash),
+            details: Set(event.details),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(database)
+        .await
+        .map_err(ServerError::database_error)?;
+
+        ServerResult::Ok(())
+    };
+
+    if let Err(e) = result.await {
+        tracing::warn!("Failed to store audit event: {}", e);
+    }
+}
+
+/// Returns the most recent events in the audit log, oldest first.
+pub async fn tail_audit_log(config: Config, filter: AuditLogFilter) -> Result<Vec<AuditLogModel>> {
+    let state = StateInner::new(config).await;
+    let db = state.database().await?;
+
+    query_audit_log(db, &filter).await
+}
+
+async fn query_audit_log(
+    db: &DatabaseConnection,
+    filter: &AuditLogFilter,
+) -> Result<Vec<AuditLogModel>> {
+    let mut query = AuditLog::find()
+        .order_by_desc(audit_log::Column::CreatedAt)
+        .order_by_desc(audit_log::Column::Id)
+        .limit(filter.limit);
+
+    if let Some(cache) = &filter.cache {
+        query = query.filter(audit_log::Column::Cache.eq(cache.as_str()));
+    }
+
+    if let Some(since) = filter.since {
+        query = query.filter(audit_log::Column::CreatedAt.gte(since));
+    }
+
+    if let Some(until) = filter.until {
+        query = query.filter(audit_log::Column::CreatedAt.lt(until));
+    }
+
+    let mut events = query.all(db).await?;
+    events.reverse();
+
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    use chrono::Duration as ChronoDuration;
+
+    use crate::access::http::AuthState;
+    use crate::access::Token;
+    use crate::database::migration::{Migrator, MigratorTrait};
+    use crate::RequestStateInner;
+
+    async fn make_state(store_in_database: bool) -> State {
+        let config: Config = toml::from_str(&format!(
+            r#"
+[database]
+url = "sqlite::memory:"
+
+[storage]
+type = "local"
+path = "/nonexistent"
+
+[chunking]
+nar-size-threshold = 0
+min-size = 16384
+avg-size = 65536
+max-size = 262144
+
+[audit]
+store-in-database = {store_in_database}
+
+[jwt.signing]
+token-hs256-secret-base64 = "dmVyeSBzZWN1cmUgc2VjcmV0"
+"#
+        ))
+        .unwrap();
+
+        let state = StateInner::new(config).await;
+        let db = state.database().await.unwrap();
+        Migrator::up(db, None).await.unwrap();
+
+        state
+    }
+
+    fn make_req_state(sub: Option<&str>) -> RequestState {
+        let auth = AuthState::new();
+
+        if let Some(sub) = sub {
+            let token = Token::new(sub.to_string(), &(Utc::now() + ChronoDuration::days(1)));
+            auth.token.set(token).unwrap();
+        }
+
+        Arc::new(RequestStateInner {
+            auth,
+            api_endpoint: None,
+            substituter_endpoint: None,
+            host: "localhost".to_string(),
+            client_claims_https: false,
+            public_cache: AtomicBool::new(false),
+        })
+    }
+
+    fn filter() -> AuditLogFilter {
+        AuditLogFilter {
+            cache: None,
+            since: None,
+            until: None,
+            limit: 50,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let state = make_state(true).await;
+        let db = state.database().await.unwrap();
+        let alice = make_req_state(Some("alice"));
+        let main: CacheName = "main".parse().unwrap();
+        let other: CacheName = "other".parse().unwrap();
+
+        record(
+            &state,
+            AuditEvent::new(AuditAction::CreateCache, &alice, &main),
+        )
+        .await;
+        record(
+            &state,
+            AuditEvent::new(AuditAction::Push, &make_req_state(None), &other)
+                .store_path("/nix/store/00000000000000000000000000000000-test")
+                .nar_hash("sha256:0"),
+        )
+        .await;
+        record(
+            &state,
+            AuditEvent::new(AuditAction::ConfigureCache, &alice, &main)
+                .details("is_public, priority"),
+        )
+        .await;
+
+        // Oldest first
+        let events = query_audit_log(db, &filter()).await.unwrap();
+        let actions: Vec<_> = events.iter().map(|event| event.action).collect();
+        assert_eq!(
+            vec![
+                AuditAction::CreateCache,
+                AuditAction::Push,
+                AuditAction::ConfigureCache
+            ],
+            actions
+        );
+        assert_eq!(Some("alice"), events[0].subject.as_deref());
+        assert_eq!(None, events[1].subject);
+        assert_eq!(Some("sha256:0"), events[1].nar_hash.as_deref());
+        assert_eq!(Some("is_public, priority"), events[2].details.as_deref());
+
+        // Most recent events are kept
+        let mut latest = filter();
+        latest.limit = 2;
+        let events = query_audit_log(db, &latest).await.unwrap();
+        assert_eq!(AuditAction::Push, events[0].action);
+        assert_eq!(AuditAction::ConfigureCache, events[1].action);
+
+        let mut by_cache = filter();
+        by_cache.cache = Some(other);
+        let events = query_audit_log(db, &by_cache).await.unwrap();
+        assert_eq!(1, events.len());
+        assert_eq!(AuditAction::Push, events[0].action);
+
+        let mut future = filter();
+        future.since = Some(Utc::now() + ChronoDuration::hours(1));
+        assert!(query_audit_log(db, &future).await.unwrap().is_empty());
+
+        let mut past = filter();
+        past.until = Some(Utc::now() - ChronoDuration::hours(1));
+        assert!(query_audit_log(db, &past).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_disabled() {
+        let state = make_state(false).await;
+        let db = state.database().await.unwrap();
+        let main: CacheName = "main".parse().unwrap();
+
+        record(
+            &state,
+            AuditEvent::new(AuditAction::DestroyCache, &make_req_state(None), &main),
+        )
+        .await;
+
+        assert!(query_audit_log(db, &filter()).await.unwrap().is_empty());
+    }
+}
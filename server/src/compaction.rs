@@ -0,0 +1,874 @@
+//! Background recompression of cold NARs.
+//!
+//! NARs whose chunks are only referenced by objects that haven't been
+//! accessed for `compaction.min-age` are recompressed with the
+//! configured codec. Each chunk is downloaded, decompressed, and
+//! uploaded again with the target compression, after which the chunk
+//! references of the NAR are repointed to the new chunks in the same
+//! transaction that changes the compression of the NAR.
+//!
+//! All chunks of a NAR must share its compression, so NARs are
+//! compacted as a whole. A NAR sharing any chunk with a NAR that is
+//! still hot is left alone. The old chunks are left to the orphan
+//! chunk sweep of the garbage collector.
+//!
+//! Compaction runs in the API server process so it can back off
+//! while the server is busy, and it's limited to an hourly budget
+//! of uncompressed bytes.
+
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use sea_orm::entity::prelude::*;
+use sea_orm::query::{QueryOrder, QuerySelect};
+use sea_orm::sea_query::{Alias, Expr, Func, IntoColumnRef, Query, SimpleExpr};
+use sea_orm::{Condition, JoinType, TransactionTrait};
+use tokio::io::BufReader;
+use tokio::time;
+use tracing::instrument;
+
+use super::{State, StateInner};
+use crate::api::binary_cache::decompress_stream;
+use crate::api::v1::upload_path::{upload_chunk, ChunkData, ChunkDedup};
+use crate::config::Config;
+use crate::database::add_chunk_references;
+use crate::database::entity::chunk::{self, ChunkState, Entity as Chunk};
+use crate::database::entity::chunkref::{self, Entity as ChunkRef};
+use crate::database::entity::nar::{self, Entity as Nar, NarState};
+use crate::database::entity::object::{self, Entity as Object};
+use crate::database::ChunkGuard;
+use crate::narinfo::Compression;
+use crate::storage::Download;
+use attic::hash::Hash;
+
+/// Number of NARs to fetch from the database at a time.
+const BATCH_SIZE: u64 = 100;
+
+/// The period the byte budget applies to.
+const BUDGET_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// How long to pause for when the server is busy.
+const PAUSE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The minimum period to measure the request rate over.
+const MIN_SAMPLE_PERIOD: Duration = Duration::from_secs(1);
+
+/// Statistics on the compression of the chunk store.
+#[derive(Debug, Clone, Default)]
+pub struct CompactionStats {
+    /// Valid chunks by compression.
+    pub chunks: Vec<ChunkStats>,
+
+    /// Number of NARs waiting to be recompressed.
+    pub pending_nars: u64,
+
+    /// Total uncompressed size of NARs waiting to be recompressed, in bytes.
+    pub pending_bytes: u64,
+}
+
+/// Statistics on chunks with a compression.
+#[derive(Debug, Clone)]
+pub struct ChunkStats {
+    /// The compression of the chunks.
+    pub compression: String,
+
+    /// Number of chunks.
+    pub count: u64,
+
+    /// Total uncompressed size of the chunks, in bytes.
+    pub chunk_bytes: u64,
+
+    /// Total size of the chunk files, in bytes.
+    ///
+    /// Chunks whose file sizes aren't confirmed aren't counted.
+    pub file_bytes: u64,
+}
+
+/// Summary of a compaction run.
+#[derive(Debug, Clone, Copy, Default)]
+struct CompactionReport {
+    nars: usize,
+    old_file_bytes: u64,
+    new_file_bytes: u64,
+}
+
+/// An hourly budget of uncompressed bytes to recompress.
+///
+/// A chunk started within budget is allowed to overrun it, and the
+/// excess is carried over to the next window.
+#[derive(Debug)]
+struct ByteBudget {
+    limit: u64,
+    window_start: Instant,
+    used: u64,
+}
+
+/// Measures the request rate of the server.
+#[derive(Debug)]
+struct RequestRateMeter {
+    last_count: u64,
+    last_sample: Instant,
+    rate: f64,
+}
+
+/// Keeps compaction from competing with foreground traffic.
+#[derive(Debug)]
+struct Throttle {
+    budget: ByteBudget,
+    meter: RequestRateMeter,
+    max_request_rate: f64,
+}
+
+/// Runs compaction periodically.
+///
+/// This never returns if compaction is enabled.
+pub(crate) async fn run_compaction(state: State) {
+    let config = &state.config.compaction;
+    if !config.enable {
+        return;
+    }
+
+    let mut throttle = Throttle {
+        budget: ByteBudget::new(config.hourly_byte_budget, Instant::now()),
+        meter: RequestRateMeter::new(state.request_count.load(Ordering::Relaxed), Instant::now()),
+        max_request_rate: config.max_request_rate,
+    };
+
+    loop {
+        if let Err(e) = run_compaction_once(&state, &mut throttle).await {
+            tracing::warn!("Compaction failed: {}", e);
+        }
+
+        time::sleep(config.interval).await;
+    }
+}
+
+/// Returns statistics on the compression of the chunk store.
+pub async fn get_compaction_stats(config: Config) -> Result<CompactionStats> {
+    let state = StateInner::new(config).await;
+    compaction_stats(&state).await
+}
+
+async fn compaction_stats(state: &State) -> Result<CompactionStats> {
+    let db = state.database().await?;
+
+    let chunks: Vec<(String, i64, Option<i64>, Option<i64>)> = Chunk::find()
+        .select_only()
+        .column(chunk::Column::Compression)
+        .column_as(chunk::Column::Id.count(), "count")
+        .column_as(sum(chunk::Column::ChunkSize), "chunk_bytes")
+        .column_as(sum(chunk::Column::FileSize), "file_bytes")
+        .filter(chunk::Column::State.eq(ChunkState::Valid))
+        .group_by(chunk::Column::Compression)
+        .order_by_asc(chunk::Column::Compression)
+        .into_tuple()
+        .all(db)
+        .await?;
+
+    let chunks = chunks
+        .into_iter()
+        .map(|(compression, count, chunk_bytes, file_bytes)| ChunkStats {
+            compression,
+            count: count as u64,
+            chunk_bytes: chunk_bytes.unwrap_or(0).max(0) as u64,
+            file_bytes: file_bytes.unwrap_or(0).max(0) as u64,
+        })
+        .collect();
+
+    let compaction = &state.config.compaction;
+    let Some(cutoff) = compaction_cutoff(state, Utc::now()) else {
+        return Ok(CompactionStats {
+            chunks,
+            ..Default::default()
+        });
+    };
+
+    let (pending_nars, pending_bytes): (i64, Option<i64>) = Nar::find()
+        .select_only()
+        .column_as(nar::Column::Id.count(), "count")
+        .column_as(sum(nar::Column::NarSize), "bytes")
+        .filter(cold_nars(cutoff, compaction.r#type.into()))
+        .into_tuple()
+        .one(db)
+        .await?
+        .unwrap_or((0, None));
+
+    Ok(CompactionStats {
+        chunks,
+        pending_nars: pending_nars as u64,
+        pending_bytes: pending_bytes.unwrap_or(0).max(0) as u64,
+    })
+}
+
+/// Recompresses all cold NARs.
+#[instrument(skip_all)]
+async fn run_compaction_once(state: &State, throttle: &mut Throttle) -> Result<()> {
+    let db = state.database().await?;
+    let target: Compression = state.config.compaction.r#type.into();
+
+    let Some(cutoff) = compaction_cutoff(state, Utc::now()) else {
+        return Ok(());
+    };
+
+    let mut report = CompactionReport::default();
+    let mut last_id = 0;
+
+    loop {
+        let nar_ids: Vec<i64> = Nar::find()
+            .select_only()
+            .column(nar::Column::Id)
+            .filter(cold_nars(cutoff, target))
+            .filter(nar::Column::Id.gt(last_id))
+            .order_by_asc(nar::Column::Id)
+            .limit(BATCH_SIZE)
+            .into_tuple()
+            .all(db)
+            .await?;
+
+        let Some(&last) = nar_ids.last() else {
+            break;
+        };
+        last_id = last;
+
+        for nar_id in nar_ids {
+            match compact_nar(state, nar_id, cutoff, throttle).await {
+                Ok(Some((old_file_bytes, new_file_bytes))) => {
+                    tracing::debug!(
+                        "Recompressed NAR {} from {} to {} bytes",
+                        nar_id,
+                        old_file_bytes,
+                        new_file_bytes
+                    );
+
+                    report.nars += 1;
+                    report.old_file_bytes += old_file_bytes;
+                    report.new_file_bytes += new_file_bytes;
+                }
+                Ok(None) => {
+                    tracing::debug!("NAR {} changed during recompression", nar_id);
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to recompress NAR {}: {}", nar_id, e);
+                }
+            }
+        }
+    }
+
+    if report.nars != 0 {
+        tracing::info!(
+            "Recompressed {} NARs from {} to {} bytes",
+            report.nars,
+            report.old_file_bytes,
+            report.new_file_bytes
+        );
+    }
+
+    Ok(())
+}
+
+/// Recompresses a NAR with the target compression.
+///
+/// Returns the total sizes of the chunk files before and after, or
+/// `None` if the NAR changed or became hot in the meantime.
+async fn compact_nar(
+    state: &State,
+    nar_id: i64,
+    cutoff: DateTime<Utc>,
+    throttle: &mut Throttle,
+) -> Result<Option<(u64, u64)>> {
+    let db = state.database().await?;
+    let target: Compression = state.config.compaction.r#type.into();
+
+    let Some(nar) = Nar::find_by_id(nar_id).one(db).await? else {
+        return Ok(None);
+    };
+
+    let chunkrefs = ChunkRef::find()
+        .filter(chunkref::Column::NarId.eq(nar_id))
+        .order_by_asc(chunkref::Column::Seq)
+        .find_also_related(Chunk)
+        .all(db)
+        .await?;
+
+    let mut new_chunks: HashMap<i64, ChunkGuard> = HashMap::new();
+    let mut old_file_bytes = 0;
+    let mut new_file_bytes = 0;
+
+    for (_, chunk) in chunkrefs {
+        let Some(chunk) = chunk else {
+            return Ok(None);
+        };
+
+        if new_chunks.contains_key(&chunk.id) {
+            continue;
+        }
+
+        throttle.wait(state).await;
+        let new_chunk = recompress_chunk(state, &chunk).await?;
+        throttle.budget.consume(chunk.chunk_size.max(0) as u64);
+
+        old_file_bytes += chunk.file_size.unwrap_or(0).max(0) as u64;
+        new_file_bytes += new_chunk.file_size.unwrap_or(0).max(0) as u64;
+        new_chunks.insert(chunk.id, new_chunk);
+    }
+
+    let txn = db.begin().await?;
+
+    // Lock the NAR so it can't be deleted underneath us, and make
+    // sure it's still cold
+    let locked = Nar::find_by_id(nar_id)
+        .filter(nar::Column::Compression.eq(nar.compression))
+        .filter(cold_nars(cutoff, target))
+        .lock_exclusive()
+        .one(&txn)
+        .await?;
+
+    if locked.is_none() {
+        return Ok(None);
+    }
+
+    for (old_chunk_id, new_chunk) in &new_chunks {
+        let repointed = ChunkRef::update_many()
+            .col_expr(chunkref::Column::ChunkId, Expr::value(new_chunk.id))
+            .col_expr(
+                chunkref::Column::Compression,
+                Expr::value(target.to_string()),
+            )
+            .filter(chunkref::Column::NarId.eq(nar_id))
+            .filter(chunkref::Column::ChunkId.eq(*old_chunk_id))
+            .exec(&txn)
+            .await?
+            .rows_affected as i64;
+
+        add_chunk_references(&txn, vec![new_chunk.id], repointed).await?;
+        add_chunk_references(&txn, vec![*old_chunk_id], -repointed).await?;
+    }
+
+    // Chunk references detached or repointed since we looked would
+    // leave the NAR with mixed compressions
+    let leftover = ChunkRef::find()
+        .filter(chunkref::Column::NarId.eq(nar_id))
+        .filter(chunkref::Column::Compression.ne(target.to_string()))
+        .count(&txn)
+        .await?;
+
+    if leftover != 0 {
+        return Ok(None);
+    }
+
+    Nar::update_many()
+        .col_expr(nar::Column::Compression, Expr::value(target.to_string()))
+        .filter(nar::Column::Id.eq(nar_id))
+        .exec(&txn)
+        .await?;
+
+    txn.commit().await?;
+
+    // Narinfos of single-chunk NARs point to the chunk files directly,
+    // and we don't know which objects use this NAR
+    state.narinfo_cache.clear();
+
+    Ok(Some((old_file_bytes, new_file_bytes)))
+}
+
+/// Uploads a chunk again with the target compression.
+///
+/// The chunk is deduplicated against existing chunks with the target
+/// compression, and the chunk hash is verified otherwise.
+async fn recompress_chunk(state: &State, chunk: &chunk::Model) -> Result<ChunkGuard> {
+    let config = &state.config.compaction;
+    let db = state.database().await?;
+    let storage = state.storage().await?;
+
+    let compression: Compression = chunk.compression.parse()?;
+    if compression == Compression::Bzip2 {
+        return Err(anyhow!("Cannot decompress bzip2 chunks"));
+    }
+
+    let stream = match storage.download_file_db(&chunk.remote_file.0, true).await? {
+        Download::AsyncRead(stream) => stream,
+        Download::Url(_) => return Err(anyhow!("Storage backend did not return a stream")),
+    };
+
+    let data = ChunkData::Stream(
+        decompress_stream(BufReader::new(stream), compression),
+        Hash::from_typed(&chunk.chunk_hash)?,
+        chunk.chunk_size as usize,
+    );
+
+    let result = upload_chunk(
+        data,
+        config.r#type,
+        config.level(),
+        0,
+        db.clone(),
+        state.clone(),
+        ChunkDedup::global(false),
+    )
+    .await?;
+
+    Ok(result.guard)
+}
+
+/// Returns the sum of a column as a 64-bit integer.
+fn sum<C: IntoColumnRef>(col: C) -> SimpleExpr {
+    SimpleExpr::from(Func::cast_as(
+        Func::sum(Expr::col(col)),
+        Alias::new("BIGINT"),
+    ))
+}
+
+/// Returns the time before which objects must have been last accessed to be cold.
+fn compaction_cutoff(state: &State, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    chrono::Duration::from_std(state.config.compaction.min_age)
+        .ok()
+        .and_then(|min_age| now.checked_sub_signed(min_age))
+}
+
+/// Returns the condition for NARs to recompress.
+///
+/// These are valid and complete NARs with objects, not already in the
+/// target compression, and none of whose chunks are shared with a NAR
+/// whose objects have been accessed since the cutoff.
+fn cold_nars(cutoff: DateTime<Utc>, target: Compression) -> Condition {
+    let own = Alias::new("own");
+    let shared = Alias::new("shared");
+
+    let has_objects = Query::select()
+        .expr(Expr::val(1))
+        .from(Object)
+        .and_where(Expr::col((Object, object::Column::NarId)).equals((Nar, nar::Column::Id)))
+        .to_owned();
+
+    let has_missing_chunks = Query::select()
+        .expr(Expr::val(1))
+        .from(ChunkRef)
+        .and_where(Expr::col((ChunkRef, chunkref::Column::NarId)).equals((Nar, nar::Column::Id)))
+        .and_where(Expr::col((ChunkRef, chunkref::Column::ChunkId)).is_null())
+        .to_owned();
+
+    let last_accessed_at = Func::coalesce([
+        Expr::col((Object, object::Column::LastAccessedAt)).into(),
+        Expr::col((Object, object::Column::CreatedAt)).into(),
+    ]);
+
+    let has_hot_objects = Query::select()
+        .expr(Expr::val(1))
+        .from_as(ChunkRef, own.clone())
+        .join_as(
+            JoinType::InnerJoin,
+            ChunkRef,
+            shared.clone(),
+            Expr::col((shared.clone(), chunkref::Column::ChunkId))
+                .equals((own.clone(), chunkref::Column::ChunkId)),
+        )
+        .inner_join(
+            Object,
+            Expr::col((Object, object::Column::NarId))
+                .equals((shared.clone(), chunkref::Column::NarId)),
+        )
+        .and_where(Expr::col((own.clone(), chunkref::Column::NarId)).equals((Nar, nar::Column::Id)))
+        .and_where(Expr::expr(last_accessed_at).gte(cutoff))
+        .to_owned();
+
+    Condition::all()
+        .add(nar::Column::State.eq(NarState::Valid))
+        .add(nar::Column::Compression.ne(target.to_string()))
+        .add(nar::Column::Compression.ne(Compression::Bzip2.to_string()))
+        .add(Expr::exists(has_objects))
+        .add(Expr::exists(has_missing_chunks).not())
+        .add(Expr::exists(has_hot_objects).not())
+}
+
+impl ByteBudget {
+    fn new(limit: u64, now: Instant) -> Self {
+        Self {
+            limit,
+            window_start: now,
+            used: 0,
+        }
+    }
+
+    /// Returns how long to wait before more bytes can be recompressed.
+    fn wait_time(&mut self, now: Instant) -> Option<Duration> {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        let windows = (elapsed.as_secs() / BUDGET_WINDOW.as_secs()) as u32;
+
+        if windows != 0 {
+            self.window_start += BUDGET_WINDOW * windows;
+            self.used = self
+                .used
+                .saturating_sub(self.limit.saturating_mul(windows as u64));
+        }
+
+        if self.used < self.limit {
+            None
+        } else {
+            Some(self.window_start + BUDGET_WINDOW - now)
+        }
+    }
+
+    /// Records bytes that were recompressed.
+    fn consume(&mut self, bytes: u64) {
+        self.used = self.used.saturating_add(bytes);
+    }
+}
+
+impl RequestRateMeter {
+    fn new(count: u64, now: Instant) -> Self {
+        Self {
+            last_count: count,
+            last_sample: now,
+            rate: 0.0,
+        }
+    }
+
+    /// Returns the request rate in requests per second.
+    ///
+    /// The rate is measured since the previous sample, as long as it
+    /// was taken long enough ago.
+    fn sample(&mut self, count: u64, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.last_sample);
+
+        if elapsed >= MIN_SAMPLE_PERIOD {
+            self.rate = count.saturating_sub(self.last_count) as f64 / elapsed.as_secs_f64();
+            self.last_count = count;
+            self.last_sample = now;
+        }
+
+        self.rate
+    }
+}
+
+impl Throttle {
+    /// Waits until the budget allows more work and the server isn't busy.
+    async fn wait(&mut self, state: &State) {
+        loop {
+            if let Some(wait) = self.budget.wait_time(Instant::now()) {
+                tracing::info!("Compaction budget exhausted, resuming in {:?}", wait);
+                time::sleep(wait).await;
+                continue;
+            }
+
+            if self.max_request_rate > 0.0 {
+                let count = state.request_count.load(Ordering::Relaxed);
+                let rate = self.meter.sample(count, Instant::now());
+
+                if rate > self.max_request_rate {
+                    tracing::info!("Pausing compaction at {:.1} requests per second", rate);
+                    time::sleep(PAUSE_INTERVAL).await;
+                    continue;
+                }
+            }
+
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_compression::Level as CompressionLevel;
+    use bytes::Bytes;
+    use chrono::Duration as ChronoDuration;
+    use sea_orm::ActiveValue::Set;
+    use tokio::io::AsyncReadExt;
+    use uuid::Uuid;
+
+    use crate::config::CompressionType;
+    use crate::database::entity::cache::{self, Entity as Cache};
+    use crate::database::entity::Json as DbJson;
+    use crate::database::insert_chunkref;
+    use crate::database::migration::{Migrator, MigratorTrait};
+
+    async fn make_state(storage_path: &std::path::Path) -> State {
+        let config: Config = toml::from_str(&format!(
+            r#"
+[database]
+url = "sqlite::memory:"
+
+[storage]
+type = "local"
+path = "{}"
+
+[chunking]
+nar-size-threshold = 0
+min-size = 16384
+avg-size = 65536
+max-size = 262144
+
+[compaction]
+enable = true
+min-age = "1 day"
+type = "xz"
+
+[jwt.signing]
+token-hs256-secret-base64 = "dmVyeSBzZWN1cmUgc2VjcmV0"
+"#,
+            storage_path.display()
+        ))
+        .unwrap();
+
+        let state = StateInner::new(config).await;
+        let db = state.database().await.unwrap();
+        Migrator::up(db, None).await.unwrap();
+
+        Cache::insert(cache::ActiveModel {
+            name: Set("demo".to_string()),
+            keypair: Set(String::new()),
+            is_public: Set(false),
+            store_dir: Set("/nix/store".to_string()),
+            priority: Set(41),
+            upstream_cache_key_names: Set(DbJson(Vec::new())),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap();
+
+        state
+    }
+
+    /// Inserts a zstd-compressed NAR with an object last accessed at some time.
+    async fn insert_nar(
+        state: &State,
+        name: &str,
+        chunks: &[&'static [u8]],
+        accessed_at: DateTime<Utc>,
+    ) -> i64 {
+        let db = state.database().await.unwrap();
+
+        let nar_id = Nar::insert(nar::ActiveModel {
+            state: Set(NarState::Valid),
+            nar_hash: Set(format!("sha256:{}", name)),
+            nar_size: Set(chunks.iter().map(|c| c.len() as i64).sum()),
+            compression: Set("zstd".to_string()),
+            num_chunks: Set(chunks.len() as i32),
+            completeness_hint: Set(true),
+            holders_count: Set(0),
+            created_at: Set(accessed_at),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap()
+        .last_insert_id;
+
+        for (seq, data) in chunks.iter().enumerate() {
+            let result = upload_chunk(
+                ChunkData::Bytes(Bytes::from_static(data)),
+                CompressionType::Zstd,
+                CompressionLevel::Default,
+                0,
+                db.clone(),
+                state.clone(),
+                ChunkDedup::global(false),
+            )
+            .await
+            .unwrap();
+
+            insert_chunkref(
+                db,
+                chunkref::ActiveModel {
+                    nar_id: Set(nar_id),
+                    seq: Set(seq as i32),
+                    chunk_id: Set(Some(result.guard.id)),
+                    chunk_hash: Set(result.guard.chunk_hash.clone()),
+                    compression: Set("zstd".to_string()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        }
+
+        Object::insert(object::ActiveModel {
+            cache_id: Set(1),
+            nar_id: Set(nar_id),
+            store_path_hash: Set(format!("{:0>32}", name)),
+            store_path: Set(format!("/nix/store/{:0>32}-{}", name, name)),
+            references: Set(DbJson(Vec::new())),
+            sigs: Set(DbJson(Vec::new())),
+            created_at: Set(accessed_at),
+            last_accessed_at: Set(Some(accessed_at)),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap();
+
+        nar_id
+    }
+
+    async fn run(state: &State) {
+        let mut throttle = Throttle {
+            budget: ByteBudget::new(u64::MAX, Instant::now()),
+            meter: RequestRateMeter::new(0, Instant::now()),
+            max_request_rate: 0.0,
+        };
+
+        run_compaction_once(state, &mut throttle).await.unwrap();
+    }
+
+    async fn nar_compression(state: &State, nar_id: i64) -> String {
+        let db = state.database().await.unwrap();
+        Nar::find_by_id(nar_id)
+            .one(db)
+            .await
+            .unwrap()
+            .unwrap()
+            .compression
+    }
+
+    /// Returns the chunks of a NAR, checking they are consistent with the NAR.
+    async fn nar_chunks(state: &State, nar_id: i64) -> Vec<chunk::Model> {
+        let db = state.database().await.unwrap();
+        let compression = nar_compression(state, nar_id).await;
+
+        ChunkRef::find()
+            .filter(chunkref::Column::NarId.eq(nar_id))
+            .order_by_asc(chunkref::Column::Seq)
+            .find_also_related(Chunk)
+            .all(db)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(chunkref, chunk)| {
+                let chunk = chunk.unwrap();
+                assert_eq!(compression, chunkref.compression);
+                assert_eq!(compression, chunk.compression);
+                chunk
+            })
+            .collect()
+    }
+
+    async fn read_chunk(state: &State, chunk: &chunk::Model) -> Vec<u8> {
+        let storage = state.storage().await.unwrap();
+        let Download::AsyncRead(stream) = storage
+            .download_file_db(&chunk.remote_file.0, true)
+            .await
+            .unwrap()
+        else {
+            panic!("Local storage did not return a stream");
+        };
+
+        let compression: Compression = chunk.compression.parse().unwrap();
+        let mut data = Vec::new();
+        decompress_stream(BufReader::new(stream), compression)
+            .read_to_end(&mut data)
+            .await
+            .unwrap();
+
+        data
+    }
+
+    #[tokio::test]
+    async fn test_cold_nar_selection() {
+        let storage_path = std::env::temp_dir().join(format!("attic-test-{}", Uuid::new_v4()));
+        let state = make_state(&storage_path).await;
+        let db = state.database().await.unwrap();
+
+        let old = Utc::now() - ChronoDuration::days(30);
+        let cold = insert_nar(&state, "cold", &[b"aaaa", b"shared"], old).await;
+        let hot = insert_nar(&state, "hot", &[b"shared", b"bbbb"], Utc::now()).await;
+        let alone = insert_nar(&state, "alone", &[b"cccc", b"cccc"], old).await;
+
+        // A chunk shared with a hot object keeps the cold NAR as-is
+        run(&state).await;
+        assert_eq!("zstd", nar_compression(&state, cold).await);
+        assert_eq!("zstd", nar_compression(&state, hot).await);
+        assert_eq!("xz", nar_compression(&state, alone).await);
+
+        let chunks = nar_chunks(&state, alone).await;
+        assert_eq!(chunks[0].id, chunks[1].id);
+        assert_eq!(2, chunks[0].reference_count);
+        assert_eq!(b"cccc".to_vec(), read_chunk(&state, &chunks[0]).await);
+
+        // Nothing else is eligible yet
+        let stats = compaction_stats(&state).await.unwrap();
+        assert_eq!(0, stats.pending_nars);
+
+        // Once it cools down, both are recompressed
+        Object::update_many()
+            .col_expr(object::Column::LastAccessedAt, Expr::value(old))
+            .exec(db)
+            .await
+            .unwrap();
+
+        let stats = compaction_stats(&state).await.unwrap();
+        assert_eq!(2, stats.pending_nars);
+        assert_eq!(20, stats.pending_bytes);
+
+        run(&state).await;
+        assert_eq!("xz", nar_compression(&state, cold).await);
+        assert_eq!("xz", nar_compression(&state, hot).await);
+
+        let cold_chunks = nar_chunks(&state, cold).await;
+        let hot_chunks = nar_chunks(&state, hot).await;
+        assert_eq!(cold_chunks[1].id, hot_chunks[0].id);
+        assert_eq!(2, cold_chunks[1].reference_count);
+        assert_eq!(
+            b"shared".to_vec(),
+            read_chunk(&state, &cold_chunks[1]).await
+        );
+
+        // The old chunks are left for the orphan sweep
+        let orphans: Vec<chunk::Model> = Chunk::find()
+            .filter(chunk::Column::Compression.eq("zstd"))
+            .all(db)
+            .await
+            .unwrap();
+        assert_eq!(4, orphans.len());
+        assert!(orphans.iter().all(|chunk| chunk.reference_count == 0));
+
+        std::fs::remove_dir_all(&storage_path).unwrap();
+    }
+
+    #[test]
+    fn test_byte_budget() {
+        let start = Instant::now();
+        let minutes = |m: u64| Duration::from_secs(m * 60);
+        let mut budget = ByteBudget::new(1000, start);
+
+        assert_eq!(None, budget.wait_time(start));
+        budget.consume(600);
+        assert_eq!(None, budget.wait_time(start));
+
+        // The last chunk may overrun the budget
+        budget.consume(600);
+        assert_eq!(Some(minutes(60)), budget.wait_time(start));
+        assert_eq!(Some(minutes(50)), budget.wait_time(start + minutes(10)));
+
+        // The overrun is carried over
+        assert_eq!(None, budget.wait_time(start + minutes(60)));
+        budget.consume(900);
+        assert_eq!(Some(minutes(30)), budget.wait_time(start + minutes(90)));
+
+        // Idle windows don't accumulate budget
+        assert_eq!(None, budget.wait_time(start + minutes(600)));
+        budget.consume(1000);
+        assert_eq!(Some(minutes(60)), budget.wait_time(start + minutes(600)));
+    }
+
+    #[test]
+    fn test_request_rate_meter() {
+        let start = Instant::now();
+        let mut meter = RequestRateMeter::new(100, start);
+
+        assert_eq!(50.0, meter.sample(200, start + Duration::from_secs(2)));
+
+        // Too soon to measure again
+        assert_eq!(
+            50.0,
+            meter.sample(1000, start + Duration::from_millis(2500))
+        );
+
+        assert_eq!(0.5, meter.sample(201, start + Duration::from_secs(4)));
+    }
+}
@@ -2,24 +2,34 @@
 
 use std::collections::HashSet;
 use std::env;
+use std::io;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use async_compression::Level as CompressionLevel;
 use attic_token::SignatureType;
+use axum::http::{HeaderName, HeaderValue, Method};
 use base64::{engine::general_purpose::STANDARD as BASE64_STANDARD, Engine};
 use derivative::Derivative;
+use ipnet::IpNet;
 use serde::{de, Deserialize};
 use xdg::BaseDirectories;
 
 use crate::access::{
+    decode_token_eddsa_pubkey_base64, decode_token_eddsa_secret_base64,
+    decode_token_es256_pubkey_base64, decode_token_es256_secret_base64,
     decode_token_hs256_secret_base64, decode_token_rs256_pubkey_base64,
-    decode_token_rs256_secret_base64, HS256Key, RS256KeyPair, RS256PublicKey,
+    decode_token_rs256_secret_base64, ClaimGroups, ES256KeyPair, ES256PublicKey, Ed25519KeyPair,
+    Ed25519PublicKey, HS256Key, RS256KeyPair, RS256PublicKey,
 };
 use crate::narinfo::Compression as NixCompression;
-use crate::storage::{LocalStorageConfig, S3StorageConfig};
+use crate::oobe::{self, OobeOptions};
+use crate::storage::{LocalStorageConfig, S3StorageConfig, WebDavStorageConfig};
+use attic::cache::CacheNamePattern;
+use attic::chunking::ChunkingAlgorithm;
+use attic::signing::NixPublicKey;
 
 /// Application prefix in XDG base directories.
 ///
@@ -43,6 +53,22 @@ const ENV_TOKEN_RS256_SECRET_BASE64: &str = "ATTIC_SERVER_TOKEN_RS256_SECRET_BAS
 /// received JWTs only).
 const ENV_TOKEN_RS256_PUBKEY_BASE64: &str = "ATTIC_SERVER_TOKEN_RS256_PUBKEY_BASE64";
 
+/// Environment variable storing the base64-encoded ECDSA P-256 PEM private key (used for signing
+/// and verifying received JWTs).
+const ENV_TOKEN_ES256_SECRET_BASE64: &str = "ATTIC_SERVER_TOKEN_ES256_SECRET_BASE64";
+
+/// Environment variable storing the base64-encoded ECDSA P-256 PEM public key (used for verifying
+/// received JWTs only).
+const ENV_TOKEN_ES256_PUBKEY_BASE64: &str = "ATTIC_SERVER_TOKEN_ES256_PUBKEY_BASE64";
+
+/// Environment variable storing the base64-encoded Ed25519 PEM private key (used for signing and
+/// verifying received JWTs).
+const ENV_TOKEN_EDDSA_SECRET_BASE64: &str = "ATTIC_SERVER_TOKEN_EDDSA_SECRET_BASE64";
+
+/// Environment variable storing the base64-encoded Ed25519 PEM public key (used for verifying
+/// received JWTs only).
+const ENV_TOKEN_EDDSA_PUBKEY_BASE64: &str = "ATTIC_SERVER_TOKEN_EDDSA_PUBKEY_BASE64";
+
 /// Environment variable storing the database connection string.
 const ENV_DATABASE_URL: &str = "ATTIC_SERVER_DATABASE_URL";
 
@@ -85,6 +111,17 @@ pub struct Config {
     #[serde(rename = "substituter-endpoint")]
     pub substituter_endpoint: Option<String>,
 
+    /// Trusted reverse proxies.
+    ///
+    /// If the immediate peer of a connection is within one of these
+    /// IP ranges, the client address is taken from the `X-Forwarded-For`
+    /// header instead. This is used to enforce IP restrictions in tokens.
+    ///
+    /// If unconfigured, `X-Forwarded-For` is never trusted.
+    #[serde(rename = "trusted-proxies")]
+    #[serde(default = "Vec::new")]
+    pub trusted_proxies: Vec<IpNet>,
+
     /// Whether to soft-delete caches.
     ///
     /// If this is enabled, caches are soft-deleted instead of actually
@@ -104,6 +141,50 @@ pub struct Config {
     #[serde(default = "default_require_proof_of_possession")]
     pub require_proof_of_possession: bool,
 
+    /// Whether to only deduplicate uploads against data in the same cache.
+    ///
+    /// By default, NARs and chunks are shared by all caches, so an
+    /// uploader can find out whether some data exists in another cache
+    /// by the upload being deduplicated. If enabled, an upload is only
+    /// deduplicated against NARs and chunks referenced by objects in
+    /// the cache it's uploaded to.
+    #[serde(rename = "per-cache-deduplication")]
+    #[serde(default)]
+    pub per_cache_deduplication: bool,
+
+    /// Whether to name chunk files after their contents.
+    ///
+    /// If enabled, newly-uploaded chunks are stored under the hash of
+    /// the compressed file instead of a random name. Concurrent uploads
+    /// of the same chunk then end up in a single file in the storage
+    /// backend instead of one file each.
+    #[serde(rename = "content-addressed-chunks")]
+    #[serde(default)]
+    pub content_addressed_chunks: bool,
+
+    /// Whether to verify NARs against their hashes while serving them.
+    ///
+    /// If enabled, the server decompresses and hashes each NAR it
+    /// streams to a client, and aborts the response if the hash
+    /// doesn't match. This catches storage corruption before Nix
+    /// rejects the download, at the cost of CPU. Downloads are then
+    /// always streamed through the server instead of being redirected
+    /// to the storage backend. Partial downloads aren't verified.
+    #[serde(rename = "verify-nar-downloads")]
+    #[serde(default)]
+    pub verify_nar_downloads: bool,
+
+    /// How long to keep signing with a cache's previous keypair after it's replaced.
+    ///
+    /// During this period, narinfos carry signatures from both the
+    /// new and the previous keypairs, so clients can switch to the new
+    /// public key at their own pace instead of all at once.
+    ///
+    /// Zero (default) means previous keypairs are discarded immediately.
+    #[serde(rename = "key-rotation-grace-period")]
+    #[serde(with = "humantime_serde", default = "Duration::default")]
+    pub key_rotation_grace_period: Duration,
+
     /// Database connection.
     pub database: DatabaseConfig,
 
@@ -122,6 +203,36 @@ pub struct Config {
     #[serde(default = "Default::default")]
     pub garbage_collection: GarbageCollectionConfig,
 
+    /// Background recompression of cold objects.
+    #[serde(default = "Default::default")]
+    pub compaction: CompactionConfig,
+
+    /// Audit logging.
+    #[serde(default = "Default::default")]
+    pub audit: AuditConfig,
+
+    /// Cache event stream.
+    #[serde(default = "Default::default")]
+    pub events: EventsConfig,
+
+    /// Verification of signatures supplied by uploaders.
+    #[serde(rename = "upload-signatures")]
+    #[serde(default = "Default::default")]
+    pub upload_signatures: UploadSignaturesConfig,
+
+    /// Cross-Origin Resource Sharing for the API.
+    #[serde(default = "Default::default")]
+    pub cors: CorsConfig,
+
+    /// Rate limits.
+    #[serde(default = "Default::default")]
+    pub limits: LimitsConfig,
+
+    /// In-memory cache of narinfo responses.
+    #[serde(rename = "narinfo-cache")]
+    #[serde(default = "Default::default")]
+    pub narinfo_cache: NarInfoCacheConfig,
+
     /// JSON Web Token.
     #[serde(default = "Default::default")]
     pub jwt: JWTConfig,
@@ -157,15 +268,42 @@ pub struct JWTConfig {
     #[serde(default = "Default::default")]
     pub token_bound_audiences: Option<HashSet<String>>,
 
+    /// Named sets of cache permissions.
+    ///
+    /// Tokens can reference a group with the `cg` claim instead of
+    /// listing all permissions, which keeps large tokens small.
+    #[serde(rename = "claim-groups")]
+    #[serde(default = "Default::default")]
+    pub claim_groups: ClaimGroups,
+
     /// JSON Web Token signing.
     #[serde(rename = "signing")]
     #[serde(default = "load_jwt_signing_config_from_env")]
     #[derivative(Debug = "ignore")]
     pub signing_config: JWTSigningConfig,
+
+    /// Additional keys to accept JWTs signed with.
+    ///
+    /// These are only used for verification, so tokens signed with an
+    /// old key keep working while the signing key is being rotated.
+    #[serde(rename = "verification-keys")]
+    #[serde(default = "Default::default")]
+    #[derivative(Debug = "ignore")]
+    pub verification_keys: Vec<JWTSigningConfig>,
+}
+
+impl JWTConfig {
+    /// Returns the keys to verify JWTs with, starting with the signing key.
+    pub fn verification_signature_types(&self) -> Vec<SignatureType> {
+        std::iter::once(&self.signing_config)
+            .chain(&self.verification_keys)
+            .map(|config| config.clone().into())
+            .collect()
+    }
 }
 
 /// JSON Web Token signing configuration.
-#[derive(Clone, Deserialize)]
+#[derive(Deserialize)]
 pub enum JWTSigningConfig {
     /// JSON Web Token RSA pubkey.
     ///
@@ -182,6 +320,36 @@ pub enum JWTSigningConfig {
     #[serde(deserialize_with = "deserialize_token_rs256_secret_base64")]
     RS256SignAndVerify(RS256KeyPair),
 
+    /// JSON Web Token ECDSA P-256 pubkey.
+    ///
+    /// Set this to the base64-encoded ECDSA P-256 PEM public key to use for verifying JWTs only.
+    #[serde(rename = "token-es256-pubkey-base64")]
+    #[serde(deserialize_with = "deserialize_token_es256_pubkey_base64")]
+    ES256VerifyOnly(ES256PublicKey),
+
+    /// JSON Web Token ECDSA P-256 secret.
+    ///
+    /// Set this to the base64-encoded ECDSA P-256 PEM private key to use for signing and
+    /// verifying JWTs.
+    #[serde(rename = "token-es256-secret-base64")]
+    #[serde(deserialize_with = "deserialize_token_es256_secret_base64")]
+    ES256SignAndVerify(ES256KeyPair),
+
+    /// JSON Web Token Ed25519 pubkey.
+    ///
+    /// Set this to the base64-encoded Ed25519 PEM public key to use for verifying JWTs only.
+    #[serde(rename = "token-eddsa-pubkey-base64")]
+    #[serde(deserialize_with = "deserialize_token_eddsa_pubkey_base64")]
+    EdDSAVerifyOnly(Ed25519PublicKey),
+
+    /// JSON Web Token Ed25519 secret.
+    ///
+    /// Set this to the base64-encoded Ed25519 PEM private key to use for signing and verifying
+    /// JWTs.
+    #[serde(rename = "token-eddsa-secret-base64")]
+    #[serde(deserialize_with = "deserialize_token_eddsa_secret_base64")]
+    EdDSASignAndVerify(Ed25519KeyPair),
+
     /// JSON Web Token HMAC secret.
     ///
     /// Set this to the base64-encoded HMAC secret to use for signing and verifying JWTs.
@@ -195,11 +363,34 @@ impl From<JWTSigningConfig> for SignatureType {
         match value {
             JWTSigningConfig::RS256VerifyOnly(key) => Self::RS256PubkeyOnly(key),
             JWTSigningConfig::RS256SignAndVerify(key) => Self::RS256(key),
+            JWTSigningConfig::ES256VerifyOnly(key) => Self::ES256PubkeyOnly(key),
+            JWTSigningConfig::ES256SignAndVerify(key) => Self::ES256(key),
+            JWTSigningConfig::EdDSAVerifyOnly(key) => Self::EdDSAPubkeyOnly(key),
+            JWTSigningConfig::EdDSASignAndVerify(key) => Self::EdDSA(key),
             JWTSigningConfig::HS256SignAndVerify(key) => Self::HS256(key),
         }
     }
 }
 
+impl Clone for JWTSigningConfig {
+    fn clone(&self) -> Self {
+        match self {
+            Self::RS256VerifyOnly(key) => Self::RS256VerifyOnly(key.clone()),
+            Self::RS256SignAndVerify(key) => Self::RS256SignAndVerify(key.clone()),
+            Self::ES256VerifyOnly(key) => Self::ES256VerifyOnly(key.clone()),
+            Self::ES256SignAndVerify(key) => {
+                // ES256KeyPair doesn't implement Clone
+                let key = ES256KeyPair::from_bytes(&key.to_bytes())
+                    .expect("Failed to copy the ES256 keypair");
+                Self::ES256SignAndVerify(key)
+            }
+            Self::EdDSAVerifyOnly(key) => Self::EdDSAVerifyOnly(key.clone()),
+            Self::EdDSASignAndVerify(key) => Self::EdDSASignAndVerify(key.clone()),
+            Self::HS256SignAndVerify(key) => Self::HS256SignAndVerify(key.clone()),
+        }
+    }
+}
+
 /// Database connection configuration.
 #[derive(Debug, Clone, Deserialize)]
 pub struct DatabaseConfig {
@@ -212,6 +403,44 @@ pub struct DatabaseConfig {
     /// If enabled, a heartbeat query will be sent every minute.
     #[serde(default = "default_db_heartbeat")]
     pub heartbeat: bool,
+
+    /// Whether to release stale holds on NARs and chunks at startup.
+    ///
+    /// Holds are tracked in `holders_count` and are leaked if the
+    /// server exits while requests are in flight, which prevents the
+    /// affected NARs and chunks from ever being garbage collected.
+    /// This must be disabled if multiple API servers share the database.
+    #[serde(rename = "reset-holders-on-startup")]
+    #[serde(default = "default_db_reset_holders_on_startup")]
+    pub reset_holders_on_startup: bool,
+
+    /// Maximum number of connections in the pool.
+    ///
+    /// Each server process has its own pool. If unset, the SeaORM
+    /// default is used, or 1 for SQLite.
+    #[serde(rename = "max-connections")]
+    #[serde(default = "Default::default")]
+    pub max_connections: Option<u32>,
+
+    /// Minimum number of idle connections kept in the pool.
+    #[serde(rename = "min-connections")]
+    #[serde(default = "Default::default")]
+    pub min_connections: Option<u32>,
+
+    /// Timeout for establishing a connection.
+    #[serde(rename = "connect-timeout")]
+    #[serde(with = "humantime_serde", default = "Default::default")]
+    pub connect_timeout: Option<Duration>,
+
+    /// Timeout for acquiring a connection from the pool.
+    #[serde(rename = "acquire-timeout")]
+    #[serde(with = "humantime_serde", default = "Default::default")]
+    pub acquire_timeout: Option<Duration>,
+
+    /// How long a connection may stay idle before it's closed.
+    #[serde(rename = "idle-timeout")]
+    #[serde(with = "humantime_serde", default = "Default::default")]
+    pub idle_timeout: Option<Duration>,
 }
 
 /// File storage configuration.
@@ -225,6 +454,10 @@ pub enum StorageConfig {
     /// S3 storage.
     #[serde(rename = "s3")]
     S3(S3StorageConfig),
+
+    /// WebDAV storage.
+    #[serde(rename = "webdav")]
+    WebDav(WebDavStorageConfig),
 }
 
 /// Data chunking.
@@ -265,6 +498,33 @@ pub struct ChunkingConfig {
     /// The preferred maximum size of a chunk, in bytes.
     #[serde(rename = "max-size")]
     pub max_size: usize,
+
+    /// The FastCDC variant to use.
+    ///
+    /// By default, the ronomon variant is used. Changing this
+    /// affects the cutpoints like the values above.
+    #[serde(default)]
+    pub algorithm: ChunkingAlgorithmType,
+
+    /// The normalization level of the v2020 variant, from 0 to 3.
+    ///
+    /// By default, the level is 1.
+    #[serde(rename = "normalization-level")]
+    #[serde(default = "default_normalization_level")]
+    pub normalization_level: u32,
+}
+
+/// FastCDC variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+pub enum ChunkingAlgorithmType {
+    /// The ronomon variant.
+    #[default]
+    #[serde(rename = "ronomon")]
+    Ronomon,
+
+    /// The 2020 variant with normalized chunking.
+    #[serde(rename = "v2020")]
+    V2020,
 }
 
 /// Compression configuration.
@@ -277,6 +537,28 @@ pub struct CompressionConfig {
     ///
     /// If unspecified, Attic will choose a default one.
     pub level: Option<i32>,
+
+    /// Number of worker threads to compress unchunked NARs with.
+    ///
+    /// Only applies to zstd. NARs below the chunking threshold are
+    /// compressed as a single stream, so this avoids serializing
+    /// large ones on one core. Chunked NARs are already compressed
+    /// in parallel.
+    ///
+    /// By default, compression is single-threaded.
+    #[serde(default)]
+    pub workers: u32,
+
+    /// Compression type to recompress NARs to when serving them.
+    ///
+    /// If set, NARs stored with a different compression type are
+    /// decompressed and recompressed on the fly. This is useful
+    /// after switching away from a slow algorithm like xz, at the
+    /// cost of server CPU. Such NARs are always streamed through
+    /// the server instead of redirecting to the storage backend.
+    #[serde(rename = "serve-recompress")]
+    #[serde(default)]
+    pub serve_recompress: Option<CompressionType>,
 }
 
 /// Compression type.
@@ -297,6 +579,13 @@ pub enum CompressionType {
     /// XZ.
     #[serde(rename = "xz")]
     Xz,
+
+    /// LZ4.
+    ///
+    /// This is much faster than the other algorithms at the cost
+    /// of a lower compression ratio.
+    #[serde(rename = "lz4")]
+    Lz4,
 }
 
 /// Garbage collection config.
@@ -320,53 +609,379 @@ pub struct GarbageCollectionConfig {
     #[serde(rename = "default-retention-period")]
     #[serde(with = "humantime_serde", default = "default_default_retention_period")]
     pub default_retention_period: Duration,
+
+    /// Name patterns of caches to destroy automatically once empty.
+    ///
+    /// Empty (default) means automatic cleanup of empty caches
+    /// is disabled.
+    #[serde(rename = "empty-cache-patterns")]
+    #[serde(default = "Vec::new")]
+    pub empty_cache_patterns: Vec<CacheNamePattern>,
+
+    /// How long a cache must stay empty before it's destroyed.
+    ///
+    /// Caches created more recently than this are never destroyed.
+    #[serde(rename = "empty-cache-grace-period")]
+    #[serde(with = "humantime_serde", default = "default_empty_cache_grace_period")]
+    pub empty_cache_grace_period: Duration,
+
+    /// Minimum interval between garbage collection runs requested
+    /// through the API for a single cache.
+    ///
+    /// Zero means the runs are not rate-limited.
+    #[serde(rename = "cache-gc-cooldown")]
+    #[serde(with = "humantime_serde", default = "default_cache_gc_cooldown")]
+    pub cache_gc_cooldown: Duration,
+
+    /// The frequency to reclaim unreferenced chunks at.
+    ///
+    /// Chunks whose last references were deleted are removed from
+    /// the storage backend on this interval instead of waiting for
+    /// the next garbage collection run.
+    ///
+    /// Zero (default) means eager reclamation is disabled.
+    #[serde(rename = "reclaim-interval")]
+    #[serde(with = "humantime_serde", default = "Duration::default")]
+    pub reclaim_interval: Duration,
+}
+
+/// Compaction config.
+///
+/// Compaction recompresses NARs that haven't been accessed in a
+/// while with a codec that trades speed for a better ratio.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompactionConfig {
+    /// Whether to recompress cold NARs in the background.
+    #[serde(default)]
+    pub enable: bool,
+
+    /// How long all objects sharing a NAR's chunks must go unaccessed
+    /// before it's recompressed.
+    ///
+    /// This should be longer than how long clients cache narinfos,
+    /// since the compression advertised to them changes.
+    #[serde(rename = "min-age")]
+    #[serde(with = "humantime_serde", default = "default_compaction_min_age")]
+    pub min_age: Duration,
+
+    /// Compression type to recompress to.
+    #[serde(default = "default_compaction_type")]
+    pub r#type: CompressionType,
+
+    /// Compression level.
+    ///
+    /// If unspecified, Attic will choose a default one.
+    pub level: Option<i32>,
+
+    /// Maximum number of uncompressed bytes to recompress per hour.
+    #[serde(rename = "hourly-byte-budget")]
+    #[serde(default = "default_compaction_hourly_byte_budget")]
+    pub hourly_byte_budget: u64,
+
+    /// The frequency to look for cold NARs at.
+    #[serde(with = "humantime_serde", default = "default_compaction_interval")]
+    pub interval: Duration,
+
+    /// Request rate above which compaction is paused, in requests
+    /// per second.
+    ///
+    /// Zero means compaction is never paused.
+    #[serde(rename = "max-request-rate")]
+    #[serde(default = "default_compaction_max_request_rate")]
+    pub max_request_rate: f64,
+}
+
+/// Audit logging config.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuditConfig {
+    /// Whether to record audit events in the database.
+    ///
+    /// Audit events are always emitted to the `attic::audit` tracing
+    /// target. If enabled, they are also stored in the `audit_log`
+    /// table and can be queried with `atticadm audit tail`.
+    #[serde(rename = "store-in-database")]
+    #[serde(default)]
+    pub store_in_database: bool,
+}
+
+/// Cache event stream config.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EventsConfig {
+    /// How long to keep events around.
+    ///
+    /// Older events are deleted by garbage collection. Consumers
+    /// that fall further behind miss events and should resync.
+    ///
+    /// Zero means events are kept forever.
+    #[serde(rename = "retention-period")]
+    #[serde(with = "humantime_serde", default = "default_events_retention_period")]
+    pub retention_period: Duration,
+
+    /// How often long-polling requests check the database for events.
+    ///
+    /// Requests are woken immediately by events from the same server
+    /// process. This bounds the delay of events from other processes,
+    /// like other API servers or a separate garbage collector.
+    ///
+    /// Zero means the database is only checked when woken.
+    #[serde(rename = "poll-interval")]
+    #[serde(with = "humantime_serde", default = "default_events_poll_interval")]
+    pub poll_interval: Duration,
+}
+
+/// Narinfo cache config.
+///
+/// Each server process keeps its own cache and only invalidates it on
+/// changes made through itself. With multiple server processes, or a
+/// separate garbage collector, another process may serve a stale
+/// narinfo for up to `ttl` after a change.
+#[derive(Debug, Clone, Deserialize)]
+pub struct NarInfoCacheConfig {
+    /// Maximum number of narinfos to cache.
+    ///
+    /// Zero (default) disables the cache.
+    #[serde(default)]
+    pub size: usize,
+
+    /// How long a cached narinfo is served.
+    #[serde(with = "humantime_serde", default = "default_narinfo_cache_ttl")]
+    pub ttl: Duration,
+}
+
+/// Upload signature verification config.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct UploadSignaturesConfig {
+    /// What to do with signatures that no trusted key verifies.
+    #[serde(default)]
+    pub policy: UploadSignaturePolicy,
+
+    /// Public keys trusted to sign uploaded paths.
+    ///
+    /// These are usually the keys of upstream caches like
+    /// `cache.nixos.org-1`.
+    #[serde(rename = "trusted-public-keys")]
+    #[serde(default)]
+    pub trusted_public_keys: Vec<NixPublicKey>,
+}
+
+/// Cross-Origin Resource Sharing (CORS) config.
+///
+/// This only applies to the `/_api/` routes. The binary cache
+/// routes never send CORS headers.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CorsConfig {
+    /// Origins allowed to make cross-origin requests.
+    ///
+    /// If empty and `permissive` is not set, CORS is disabled.
+    #[serde(rename = "allowed-origins")]
+    #[serde(default = "Vec::new")]
+    #[serde(deserialize_with = "deserialize_parsed_vec")]
+    pub allowed_origins: Vec<HeaderValue>,
+
+    /// Allow requests from any origin, with any method and headers.
+    ///
+    /// This is intended for development only.
+    #[serde(default)]
+    pub permissive: bool,
+
+    /// Methods allowed in cross-origin requests.
+    #[serde(rename = "allowed-methods")]
+    #[serde(default = "default_cors_allowed_methods")]
+    #[serde(deserialize_with = "deserialize_parsed_vec")]
+    pub allowed_methods: Vec<Method>,
+
+    /// Request headers allowed in cross-origin requests.
+    #[serde(rename = "allowed-headers")]
+    #[serde(default = "default_cors_allowed_headers")]
+    #[serde(deserialize_with = "deserialize_parsed_vec")]
+    pub allowed_headers: Vec<HeaderName>,
+
+    /// How long browsers may cache preflight responses.
+    #[serde(rename = "max-age")]
+    #[serde(with = "humantime_serde", default = "default_cors_max_age")]
+    pub max_age: Duration,
+}
+
+/// Rate and size limit config.
+///
+/// Rate limits are tracked separately by each server process.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LimitsConfig {
+    /// Sustained number of uploads per second to each cache.
+    ///
+    /// If unset, the number of uploads is unlimited.
+    #[serde(rename = "upload-requests-per-second")]
+    #[serde(default)]
+    pub upload_requests_per_second: Option<f64>,
+
+    /// Number of uploads allowed in a burst.
+    ///
+    /// Defaults to one second worth of uploads.
+    #[serde(rename = "upload-request-burst")]
+    #[serde(default)]
+    pub upload_request_burst: Option<u64>,
+
+    /// Sustained number of bytes per second uploaded to each cache.
+    ///
+    /// This counts the uncompressed NAR size claimed by the uploader.
+    /// If unset, the throughput is unlimited.
+    #[serde(rename = "upload-bytes-per-second")]
+    #[serde(default)]
+    pub upload_bytes_per_second: Option<u64>,
+
+    /// Number of bytes allowed in a burst.
+    ///
+    /// Defaults to one second worth of bytes. Larger uploads are let
+    /// through after waiting for the full burst.
+    #[serde(rename = "upload-byte-burst")]
+    #[serde(default)]
+    pub upload_byte_burst: Option<u64>,
+
+    /// Whether to limit each token subject separately.
+    ///
+    /// If disabled, all uploaders to a cache share the same limits.
+    #[serde(rename = "per-subject")]
+    #[serde(default)]
+    pub per_subject: bool,
+
+    /// Maximum size of the upload info sent with each upload, in bytes.
+    ///
+    /// This applies to both the `X-Attic-Nar-Info` header and the
+    /// preamble in the body.
+    #[serde(rename = "max-nar-info-size")]
+    #[serde(default = "default_max_nar_info_size")]
+    pub max_nar_info_size: usize,
+
+    /// Maximum size of uploaded NARs, in bytes.
+    ///
+    /// If unset, NARs of any size are accepted.
+    #[serde(rename = "max-nar-size")]
+    #[serde(default)]
+    pub max_nar_size: Option<usize>,
+
+    /// Sustained number of requests per second without a token from each IP.
+    ///
+    /// If unset, requests without a token are unlimited.
+    #[serde(rename = "anonymous-requests-per-second")]
+    #[serde(default)]
+    pub anonymous_requests_per_second: Option<f64>,
+
+    /// Number of requests without a token allowed in a burst from each IP.
+    ///
+    /// Defaults to one second worth of requests.
+    #[serde(rename = "anonymous-request-burst")]
+    #[serde(default)]
+    pub anonymous_request_burst: Option<u64>,
+
+    /// Sustained number of tokens per second failing verification from each IP.
+    ///
+    /// Once exceeded, requests with a token are rejected without
+    /// verifying the token. If unset, failures are unlimited.
+    #[serde(rename = "auth-failures-per-second")]
+    #[serde(default)]
+    pub auth_failures_per_second: Option<f64>,
+
+    /// Number of tokens failing verification allowed in a burst from each IP.
+    ///
+    /// Defaults to one second worth of failures.
+    #[serde(rename = "auth-failure-burst")]
+    #[serde(default)]
+    pub auth_failure_burst: Option<u64>,
+}
+
+/// What to do with unverified signatures supplied by uploaders.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum UploadSignaturePolicy {
+    /// Store all signatures without verification.
+    #[default]
+    #[serde(rename = "keep")]
+    Keep,
+
+    /// Drop signatures that no trusted key verifies.
+    #[serde(rename = "strip")]
+    Strip,
+
+    /// Reject uploads with signatures that no trusted key verifies.
+    #[serde(rename = "reject")]
+    Reject,
 }
 
+/// Loads the JWT signing configuration from the environment.
+///
+/// This is the serde default when `[jwt.signing]` is missing. The
+/// environment is checked by `check_env_fallbacks` beforehand, so
+/// this only panics if the configuration wasn't loaded through
+/// `load_config`.
 fn load_jwt_signing_config_from_env() -> JWTSigningConfig {
-    let config = if let Some(config) = load_token_rs256_pubkey_from_env() {
-        config
-    } else if let Some(config) = load_token_rs256_secret_from_env() {
-        config
-    } else if let Some(config) = load_token_hs256_secret_from_env() {
-        config
-    } else {
-        panic!(
-            "\n\
-            You must configure JWT signing and verification inside your TOML \
-            configuration by setting one of the following options in the \
-            [jwt.signing] block:\n\
-            \n\
-            * token-rs256-pubkey-base64\n\
-            * token-rs256-secret-base64\n\
-            * token-hs256-secret-base64\n\
-            \n\
-            or by setting one of the following environment variables:\n\
-            \n\
-            * {ENV_TOKEN_RS256_PUBKEY_BASE64}\n\
-            * {ENV_TOKEN_RS256_SECRET_BASE64}\n\
-            * {ENV_TOKEN_HS256_SECRET_BASE64}\n\
-            \n\
-            Options will be tried in that same order (configuration options \
-            first, then environment options if none of the configuration options \
-            were set, starting with the respective RSA pubkey option, the RSA \
-            secret option, and finally the HMAC secret option). \
-            The first option that is found will be used.\n\
-            \n\
-            If an RS256 pubkey (asymmetric RSA PEM PKCS1 public key) is \
-            provided, it will only be possible to verify received JWTs, and not \
-            sign new JWTs.\n\
-            \n\
-            If an RS256 secret (asymmetric RSA PEM PKCS1 private key) is \
-            provided, it will be used for both signing new JWTs and verifying \
-            received JWTs.\n\
-            \n\
-            If an HS256 secret (symmetric HMAC secret) is provided, it will be \
-            used for both signing new JWTs and verifying received JWTs.\n\
-            "
-        )
-    };
+    try_load_jwt_signing_config_from_env()
+        .and_then(|config| config.ok_or_else(missing_jwt_signing_config))
+        .unwrap_or_else(|e| panic!("{:#}", e))
+}
 
-    config
+/// Loads the JWT signing configuration from the first environment variable set.
+fn try_load_jwt_signing_config_from_env() -> Result<Option<JWTSigningConfig>> {
+    let loaders: [fn() -> Result<Option<JWTSigningConfig>>; 7] = [
+        load_token_rs256_pubkey_from_env,
+        load_token_rs256_secret_from_env,
+        load_token_es256_pubkey_from_env,
+        load_token_es256_secret_from_env,
+        load_token_eddsa_pubkey_from_env,
+        load_token_eddsa_secret_from_env,
+        load_token_hs256_secret_from_env,
+    ];
+
+    for load in loaders {
+        if let Some(config) = load()? {
+            return Ok(Some(config));
+        }
+    }
+
+    Ok(None)
+}
+
+fn missing_jwt_signing_config() -> anyhow::Error {
+    anyhow!(
+        "\n\
+        You must configure JWT signing and verification inside your TOML \
+        configuration by setting one of the following options in the \
+        [jwt.signing] block:\n\
+        \n\
+        * token-rs256-pubkey-base64\n\
+        * token-rs256-secret-base64\n\
+        * token-es256-pubkey-base64\n\
+        * token-es256-secret-base64\n\
+        * token-eddsa-pubkey-base64\n\
+        * token-eddsa-secret-base64\n\
+        * token-hs256-secret-base64\n\
+        \n\
+        or by setting one of the following environment variables:\n\
+        \n\
+        * {ENV_TOKEN_RS256_PUBKEY_BASE64}\n\
+        * {ENV_TOKEN_RS256_SECRET_BASE64}\n\
+        * {ENV_TOKEN_ES256_PUBKEY_BASE64}\n\
+        * {ENV_TOKEN_ES256_SECRET_BASE64}\n\
+        * {ENV_TOKEN_EDDSA_PUBKEY_BASE64}\n\
+        * {ENV_TOKEN_EDDSA_SECRET_BASE64}\n\
+        * {ENV_TOKEN_HS256_SECRET_BASE64}\n\
+        \n\
+        Options will be tried in that same order (configuration options \
+        first, then environment options if none of the configuration options \
+        were set, starting with the respective RSA pubkey option, the RSA \
+        secret option, the ECDSA and Ed25519 options, and finally the HMAC \
+        secret option). The first option that is found will be used.\n\
+        \n\
+        If a pubkey (RS256 PEM PKCS1, ES256 or Ed25519 PEM public key) is \
+        provided, it will only be possible to verify received JWTs, and not \
+        sign new JWTs.\n\
+        \n\
+        If a secret (RS256 PEM PKCS1, ES256 or Ed25519 PEM private key) is \
+        provided, it will be used for both signing new JWTs and verifying \
+        received JWTs.\n\
+        \n\
+        If an HS256 secret (symmetric HMAC secret) is provided, it will be \
+        used for both signing new JWTs and verifying received JWTs.\n\
+        "
+    )
 }
 
 fn read_non_empty_var(key: &str) -> Result<Option<String>> {
@@ -374,7 +989,7 @@ fn read_non_empty_var(key: &str) -> Result<Option<String>> {
         Err(env::VarError::NotPresent) => {
             return Ok(None);
         }
-        r => r?,
+        r => r.with_context(|| format!("Cannot read {}", key))?,
     };
 
     if value.is_empty() {
@@ -384,38 +999,91 @@ fn read_non_empty_var(key: &str) -> Result<Option<String>> {
     }
 }
 
-fn load_token_hs256_secret_from_env() -> Option<JWTSigningConfig> {
-    let s = read_non_empty_var(ENV_TOKEN_HS256_SECRET_BASE64)
-        .expect("HS256 environment cannot be read")?;
+/// Reads and decodes a key from an environment variable.
+fn load_key_from_env<T>(
+    key: &str,
+    decode: impl FnOnce(&str) -> attic_token::Result<T>,
+) -> Result<Option<T>> {
+    let Some(s) = read_non_empty_var(key)? else {
+        return Ok(None);
+    };
+
+    let decoded = decode(&s).with_context(|| format!("{} cannot be decoded", key))?;
 
-    let secret = decode_token_hs256_secret_base64(&s).expect("HS256 secret cannot be decoded");
+    Ok(Some(decoded))
+}
 
-    Some(JWTSigningConfig::HS256SignAndVerify(secret))
+fn load_token_hs256_secret_from_env() -> Result<Option<JWTSigningConfig>> {
+    let secret = load_key_from_env(
+        ENV_TOKEN_HS256_SECRET_BASE64,
+        decode_token_hs256_secret_base64,
+    )?;
+    Ok(secret.map(JWTSigningConfig::HS256SignAndVerify))
 }
 
-fn load_token_rs256_secret_from_env() -> Option<JWTSigningConfig> {
-    let s = read_non_empty_var(ENV_TOKEN_RS256_SECRET_BASE64)
-        .expect("RS256 environment cannot be read")?;
+fn load_token_rs256_secret_from_env() -> Result<Option<JWTSigningConfig>> {
+    let secret = load_key_from_env(
+        ENV_TOKEN_RS256_SECRET_BASE64,
+        decode_token_rs256_secret_base64,
+    )?;
+    Ok(secret.map(JWTSigningConfig::RS256SignAndVerify))
+}
 
-    let secret = decode_token_rs256_secret_base64(&s).expect("RS256 cannot be decoded");
+fn load_token_rs256_pubkey_from_env() -> Result<Option<JWTSigningConfig>> {
+    let pubkey = load_key_from_env(
+        ENV_TOKEN_RS256_PUBKEY_BASE64,
+        decode_token_rs256_pubkey_base64,
+    )?;
+    Ok(pubkey.map(JWTSigningConfig::RS256VerifyOnly))
+}
 
-    Some(JWTSigningConfig::RS256SignAndVerify(secret))
+fn load_token_es256_secret_from_env() -> Result<Option<JWTSigningConfig>> {
+    let secret = load_key_from_env(
+        ENV_TOKEN_ES256_SECRET_BASE64,
+        decode_token_es256_secret_base64,
+    )?;
+    Ok(secret.map(JWTSigningConfig::ES256SignAndVerify))
 }
 
-fn load_token_rs256_pubkey_from_env() -> Option<JWTSigningConfig> {
-    let s = read_non_empty_var(ENV_TOKEN_RS256_PUBKEY_BASE64)
-        .expect("RS256 pubkey environment cannot be read")?;
+fn load_token_es256_pubkey_from_env() -> Result<Option<JWTSigningConfig>> {
+    let pubkey = load_key_from_env(
+        ENV_TOKEN_ES256_PUBKEY_BASE64,
+        decode_token_es256_pubkey_base64,
+    )?;
+    Ok(pubkey.map(JWTSigningConfig::ES256VerifyOnly))
+}
 
-    let pubkey = decode_token_rs256_pubkey_base64(&s).expect("RS256 pubkey cannot be decoded");
+fn load_token_eddsa_secret_from_env() -> Result<Option<JWTSigningConfig>> {
+    let secret = load_key_from_env(
+        ENV_TOKEN_EDDSA_SECRET_BASE64,
+        decode_token_eddsa_secret_base64,
+    )?;
+    Ok(secret.map(JWTSigningConfig::EdDSASignAndVerify))
+}
 
-    Some(JWTSigningConfig::RS256VerifyOnly(pubkey))
+fn load_token_eddsa_pubkey_from_env() -> Result<Option<JWTSigningConfig>> {
+    let pubkey = load_key_from_env(
+        ENV_TOKEN_EDDSA_PUBKEY_BASE64,
+        decode_token_eddsa_pubkey_base64,
+    )?;
+    Ok(pubkey.map(JWTSigningConfig::EdDSAVerifyOnly))
 }
 
+/// Loads the database URL from the environment.
+///
+/// Like `load_jwt_signing_config_from_env`, this is checked by
+/// `check_env_fallbacks` beforehand.
 fn load_database_url_from_env() -> String {
-    env::var(ENV_DATABASE_URL).expect(&format!(
+    read_non_empty_var(ENV_DATABASE_URL)
+        .and_then(|url| url.ok_or_else(missing_database_url))
+        .unwrap_or_else(|e| panic!("{:#}", e))
+}
+
+fn missing_database_url() -> anyhow::Error {
+    anyhow!(
         "Database URL must be specified in either database.url \
         or the {ENV_DATABASE_URL} environment."
-    ))
+    )
 }
 
 impl Default for JWTConfig {
@@ -423,7 +1091,20 @@ impl Default for JWTConfig {
         Self {
             token_bound_issuer: None,
             token_bound_audiences: None,
+            claim_groups: Default::default(),
             signing_config: load_jwt_signing_config_from_env(),
+            verification_keys: Vec::new(),
+        }
+    }
+}
+
+impl ChunkingConfig {
+    pub fn algorithm(&self) -> ChunkingAlgorithm {
+        match self.algorithm {
+            ChunkingAlgorithmType::Ronomon => ChunkingAlgorithm::Ronomon,
+            ChunkingAlgorithmType::V2020 => ChunkingAlgorithm::V2020 {
+                normalization_level: self.normalization_level,
+            },
         }
     }
 }
@@ -434,10 +1115,17 @@ impl CompressionConfig {
             return CompressionLevel::Precise(level);
         }
 
-        match self.r#type {
-            CompressionType::Brotli => CompressionLevel::Precise(5),
-            CompressionType::Zstd => CompressionLevel::Precise(8),
-            CompressionType::Xz => CompressionLevel::Precise(2),
+        self.r#type.default_level()
+    }
+}
+
+impl CompressionType {
+    /// Returns the default compression level of this type.
+    pub fn default_level(&self) -> CompressionLevel {
+        match self {
+            Self::Brotli => CompressionLevel::Precise(5),
+            Self::Zstd => CompressionLevel::Precise(8),
+            Self::Xz => CompressionLevel::Precise(2),
             _ => CompressionLevel::Default,
         }
     }
@@ -448,6 +1136,8 @@ impl Default for CompressionConfig {
         Self {
             r#type: CompressionType::Zstd,
             level: None,
+            workers: 0,
+            serve_recompress: None,
         }
     }
 }
@@ -459,6 +1149,7 @@ impl From<CompressionType> for NixCompression {
             CompressionType::Brotli => NixCompression::Brotli,
             CompressionType::Zstd => NixCompression::Zstd,
             CompressionType::Xz => NixCompression::Xz,
+            CompressionType::Lz4 => NixCompression::Lz4,
         }
     }
 }
@@ -468,10 +1159,103 @@ impl Default for GarbageCollectionConfig {
         Self {
             interval: Duration::from_secs(43200),
             default_retention_period: Duration::ZERO,
+            empty_cache_patterns: Vec::new(),
+            empty_cache_grace_period: default_empty_cache_grace_period(),
+            cache_gc_cooldown: default_cache_gc_cooldown(),
+            reclaim_interval: Duration::ZERO,
+        }
+    }
+}
+
+impl CompactionConfig {
+    pub fn level(&self) -> CompressionLevel {
+        if let Some(level) = self.level {
+            return CompressionLevel::Precise(level);
+        }
+
+        self.r#type.default_level()
+    }
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            min_age: default_compaction_min_age(),
+            r#type: default_compaction_type(),
+            level: None,
+            hourly_byte_budget: default_compaction_hourly_byte_budget(),
+            interval: default_compaction_interval(),
+            max_request_rate: default_compaction_max_request_rate(),
+        }
+    }
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        Self {
+            retention_period: default_events_retention_period(),
+            poll_interval: default_events_poll_interval(),
+        }
+    }
+}
+
+impl Default for NarInfoCacheConfig {
+    fn default() -> Self {
+        Self {
+            size: 0,
+            ttl: default_narinfo_cache_ttl(),
         }
     }
 }
 
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            upload_requests_per_second: None,
+            upload_request_burst: None,
+            upload_bytes_per_second: None,
+            upload_byte_burst: None,
+            per_subject: false,
+            max_nar_info_size: default_max_nar_info_size(),
+            max_nar_size: None,
+            anonymous_requests_per_second: None,
+            anonymous_request_burst: None,
+            auth_failures_per_second: None,
+            auth_failure_burst: None,
+        }
+    }
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            permissive: false,
+            allowed_methods: default_cors_allowed_methods(),
+            allowed_headers: default_cors_allowed_headers(),
+            max_age: default_cors_max_age(),
+        }
+    }
+}
+
+fn deserialize_parsed_vec<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: de::Deserializer<'de>,
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    use de::Error;
+
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| {
+            s.parse()
+                .map_err(|e| Error::custom(format!("Invalid value \"{}\": {}", s, e)))
+        })
+        .collect()
+}
+
 fn deserialize_deprecated_token_hs256_secret<'de, D>(
     _deserializer: D,
 ) -> Result<Option<String>, D::Error>
@@ -534,6 +1318,60 @@ where
     Ok(key)
 }
 
+fn deserialize_token_es256_secret_base64<'de, D>(deserializer: D) -> Result<ES256KeyPair, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    use de::Error;
+
+    let s = String::deserialize(deserializer)?;
+    let key = decode_token_es256_secret_base64(&s).map_err(Error::custom)?;
+
+    Ok(key)
+}
+
+fn deserialize_token_es256_pubkey_base64<'de, D>(
+    deserializer: D,
+) -> Result<ES256PublicKey, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    use de::Error;
+
+    let s = String::deserialize(deserializer)?;
+    let key = decode_token_es256_pubkey_base64(&s).map_err(Error::custom)?;
+
+    Ok(key)
+}
+
+fn deserialize_token_eddsa_secret_base64<'de, D>(
+    deserializer: D,
+) -> Result<Ed25519KeyPair, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    use de::Error;
+
+    let s = String::deserialize(deserializer)?;
+    let key = decode_token_eddsa_secret_base64(&s).map_err(Error::custom)?;
+
+    Ok(key)
+}
+
+fn deserialize_token_eddsa_pubkey_base64<'de, D>(
+    deserializer: D,
+) -> Result<Ed25519PublicKey, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    use de::Error;
+
+    let s = String::deserialize(deserializer)?;
+    let key = decode_token_eddsa_pubkey_base64(&s).map_err(Error::custom)?;
+
+    Ok(key)
+}
+
 fn default_listen_address() -> SocketAddr {
     "[::]:8080".parse().unwrap()
 }
@@ -542,6 +1380,10 @@ fn default_db_heartbeat() -> bool {
     false
 }
 
+fn default_db_reset_holders_on_startup() -> bool {
+    true
+}
+
 fn default_soft_delete_caches() -> bool {
     false
 }
@@ -558,32 +1400,154 @@ fn default_default_retention_period() -> Duration {
     Duration::ZERO
 }
 
+fn default_normalization_level() -> u32 {
+    1
+}
+
+fn default_empty_cache_grace_period() -> Duration {
+    Duration::from_secs(7 * 24 * 60 * 60)
+}
+
+fn default_cache_gc_cooldown() -> Duration {
+    Duration::from_secs(10 * 60)
+}
+
+fn default_compaction_min_age() -> Duration {
+    Duration::from_secs(90 * 24 * 60 * 60)
+}
+
+fn default_compaction_type() -> CompressionType {
+    CompressionType::Xz
+}
+
+fn default_compaction_hourly_byte_budget() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn default_compaction_interval() -> Duration {
+    Duration::from_secs(60 * 60)
+}
+
+fn default_compaction_max_request_rate() -> f64 {
+    10.0
+}
+
+fn default_events_retention_period() -> Duration {
+    Duration::from_secs(7 * 24 * 60 * 60)
+}
+
+fn default_events_poll_interval() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_narinfo_cache_ttl() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_max_nar_info_size() -> usize {
+    1024 * 1024
+}
+
+fn default_cors_allowed_methods() -> Vec<Method> {
+    vec![
+        Method::GET,
+        Method::POST,
+        Method::PUT,
+        Method::PATCH,
+        Method::DELETE,
+    ]
+}
+
+fn default_cors_allowed_headers() -> Vec<HeaderName> {
+    vec![
+        axum::http::header::AUTHORIZATION,
+        axum::http::header::CONTENT_TYPE,
+    ]
+}
+
+fn default_cors_max_age() -> Duration {
+    Duration::from_secs(3600)
+}
+
 fn load_config_from_path(path: &Path) -> Result<Config> {
     tracing::info!("Using configurations: {:?}", path);
 
-    let config = std::fs::read_to_string(path)?;
-    Ok(toml::from_str(&config)?)
+    let config = std::fs::read_to_string(path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => anyhow!("No configuration found at {}", path.display()),
+        io::ErrorKind::PermissionDenied => anyhow!(
+            "Cannot read the configuration at {}: permission denied. \
+            Make sure it's readable by the user running this command.",
+            path.display()
+        ),
+        _ => anyhow::Error::new(e).context(format!(
+            "Failed to read the configuration at {}",
+            path.display()
+        )),
+    })?;
+
+    parse_config(&config).with_context(|| format!("Invalid configuration at {}", path.display()))
 }
 
-fn load_config_from_str(s: &str) -> Result<Config> {
+fn load_config_from_env(config_env: &str) -> Result<Config> {
     tracing::info!("Using configurations from environment variable");
-    Ok(toml::from_str(s)?)
+
+    let decoded = BASE64_STANDARD
+        .decode(config_env.trim().as_bytes())
+        .with_context(|| format!("{} is not valid Base64", ENV_CONFIG_BASE64))?;
+    let decoded = String::from_utf8(decoded)
+        .with_context(|| format!("{} does not contain valid UTF-8", ENV_CONFIG_BASE64))?;
+
+    parse_config(&decoded)
+        .with_context(|| format!("Invalid configuration in {}", ENV_CONFIG_BASE64))
+}
+
+fn parse_config(s: &str) -> Result<Config> {
+    let table: toml::Table = toml::from_str(s)?;
+    check_env_fallbacks(&table)?;
+
+    Ok(Config::deserialize(table)?)
+}
+
+/// Checks settings that fall back to environment variables if unset.
+///
+/// The fallbacks are serde defaults, which can't fail, so we catch
+/// missing or malformed environment variables here instead.
+fn check_env_fallbacks(table: &toml::Table) -> Result<()> {
+    let has = |section: &str, key: &str| {
+        table
+            .get(section)
+            .and_then(|section| section.get(key))
+            .is_some()
+    };
+
+    if !has("database", "url") && read_non_empty_var(ENV_DATABASE_URL)?.is_none() {
+        return Err(missing_database_url());
+    }
+
+    if !has("jwt", "signing") && try_load_jwt_signing_config_from_env()?.is_none() {
+        return Err(missing_jwt_signing_config());
+    }
+
+    Ok(())
 }
 
 /// Loads the configuration in the standard order.
-pub async fn load_config(config_path: Option<&Path>, allow_oobe: bool) -> Result<Config> {
+///
+/// If `oobe` is set and there is no configuration, a simple one is
+/// generated.
+pub async fn load_config(config_path: Option<&Path>, oobe: Option<&OobeOptions>) -> Result<Config> {
     if let Some(config_path) = config_path {
         load_config_from_path(config_path)
-    } else if let Ok(config_env) = env::var(ENV_CONFIG_BASE64) {
-        let decoded = String::from_utf8(BASE64_STANDARD.decode(config_env.as_bytes())?)?;
-        load_config_from_str(&decoded)
+    } else if let Some(config_env) = read_non_empty_var(ENV_CONFIG_BASE64)? {
+        load_config_from_env(&config_env)
     } else {
-        // Config from XDG
-        let config_path = get_xdg_config_path()?;
+        // Config from the systemd configuration directory or XDG
+        let config_out = oobe.and_then(|options| options.config_out.as_deref());
+        let config_path = oobe::resolve_config_path(config_out, oobe::env_var)?;
 
-        if allow_oobe {
+        if let Some(options) = oobe {
             // Special OOBE sequence
-            crate::oobe::run_oobe().await?;
+            oobe::run_oobe(&config_path, options).await?;
         }
 
         load_config_from_path(&config_path)
@@ -603,3 +1567,82 @@ pub fn get_xdg_data_path() -> anyhow::Result<PathBuf> {
 
     Ok(data_path)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // These assume the ATTIC_SERVER_* fallbacks are unset in the test environment.
+
+    const SECRET: &str = "c2VjcmV0LXNlY3JldC1zZWNyZXQtc2VjcmV0LXNlY3JldA==";
+
+    fn valid_config() -> String {
+        format!(
+            r#"
+[database]
+url = "sqlite::memory:"
+
+[storage]
+type = "local"
+path = "/tmp/attic"
+
+[chunking]
+nar-size-threshold = 65536
+min-size = 16384
+avg-size = 65536
+max-size = 262144
+
+[jwt.signing]
+token-hs256-secret-base64 = "{SECRET}"
+"#
+        )
+    }
+
+    #[test]
+    fn test_parse_config() {
+        parse_config(&valid_config()).unwrap();
+
+        let config = valid_config().replace("url = \"sqlite::memory:\"", "");
+        let err = format!("{:#}", parse_config(&config).unwrap_err());
+        assert!(err.contains(ENV_DATABASE_URL), "{err}");
+
+        let config = valid_config().replace("[jwt.signing]", "[jwt]");
+        let config = config.replace("token-hs256-secret-base64", "unused");
+        let err = format!("{:#}", parse_config(&config).unwrap_err());
+        assert!(err.contains(ENV_TOKEN_HS256_SECRET_BASE64), "{err}");
+
+        assert!(parse_config("not toml").is_err());
+    }
+
+    #[test]
+    fn test_verification_keys() {
+        let config = parse_config(&valid_config()).unwrap();
+        assert_eq!(1, config.jwt.verification_signature_types().len());
+
+        let config = format!(
+            "{}\n[[jwt.verification-keys]]\ntoken-hs256-secret-base64 = \"b2xkIHNlY3JldA==\"\n",
+            valid_config()
+        );
+        let config = parse_config(&config).unwrap();
+        let signature_types = config.jwt.verification_signature_types();
+        assert_eq!(2, signature_types.len());
+        assert!(matches!(signature_types[0], SignatureType::HS256(_)));
+    }
+
+    #[test]
+    fn test_load_config_from_env() {
+        let encoded = BASE64_STANDARD.encode(valid_config());
+        load_config_from_env(&format!("{encoded}\n")).unwrap();
+
+        let err = format!("{:#}", load_config_from_env("!!!").unwrap_err());
+        assert!(err.contains("not valid Base64"), "{err}");
+
+        let encoded = BASE64_STANDARD.encode([0xff, 0xfe]);
+        let err = format!("{:#}", load_config_from_env(&encoded).unwrap_err());
+        assert!(err.contains("valid UTF-8"), "{err}");
+
+        let encoded = BASE64_STANDARD.encode("[database]\n");
+        let err = format!("{:#}", load_config_from_env(&encoded).unwrap_err());
+        assert!(err.contains("Invalid configuration"), "{err}");
+    }
+}
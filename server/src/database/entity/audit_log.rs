@@ -0,0 +1,80 @@
+//! An audit log entry.
+
+use sea_orm::entity::prelude::*;
+
+pub type AuditLogModel = Model;
+
+/// An action recorded in the audit log.
+#[derive(EnumIter, DeriveActiveEnum, Debug, Clone, Copy, PartialEq, Eq)]
+#[sea_orm(rs_type = "String", db_type = "String(Some(20))")]
+pub enum AuditAction {
+    /// A store path was pushed to a cache.
+    #[sea_orm(string_value = "push")]
+    Push,
+
+    /// A store path was deleted from a cache.
+    #[sea_orm(string_value = "delete-path")]
+    DeletePath,
+
+    /// Objects matching a filter were deleted from a cache.
+    #[sea_orm(string_value = "delete-objects")]
+    DeleteObjects,
+
+    /// Garbage collection was triggered on a cache.
+    #[sea_orm(string_value = "collect-garbage")]
+    CollectGarbage,
+
+    /// A cache was created.
+    #[sea_orm(string_value = "create-cache")]
+    CreateCache,
+
+    /// A cache was reconfigured.
+    #[sea_orm(string_value = "configure-cache")]
+    ConfigureCache,
+
+    /// A cache was destroyed.
+    #[sea_orm(string_value = "destroy-cache")]
+    DestroyCache,
+}
+
+/// An audit log entry.
+#[derive(Debug, Clone, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "audit_log")]
+pub struct Model {
+    /// Unique numeric ID of the entry.
+    #[sea_orm(primary_key)]
+    pub id: i64,
+
+    /// The action that was performed.
+    pub action: AuditAction,
+
+    /// The subject of the token that performed the action.
+    ///
+    /// This is `None` for anonymous requests.
+    pub subject: Option<String>,
+
+    /// The name of the cache the action was performed on.
+    #[sea_orm(indexed)]
+    pub cache: String,
+
+    /// The store path the action was performed on.
+    pub store_path: Option<String>,
+
+    /// The hash of the NAR the action was performed on.
+    pub nar_hash: Option<String>,
+
+    /// Additional details about the action.
+    ///
+    /// For example, the fields that were changed when a cache is
+    /// reconfigured.
+    pub details: Option<String>,
+
+    /// Timestamp when the action was performed.
+    #[sea_orm(indexed)]
+    pub created_at: ChronoDateTimeUtc,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -1,8 +1,13 @@
 //! A binary cache.
 
+use std::time::Duration;
+
+use chrono::Utc;
 use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
 
 use super::Json;
+use crate::config::ChunkingConfig;
 use attic::error::AtticResult;
 use attic::signing::NixKeypair;
 
@@ -49,18 +54,116 @@ pub struct Model {
 
     /// The retention period of the cache, in seconds.
     pub retention_period: Option<i32>,
+
+    /// Timestamp when the cache was first seen empty by the garbage collector.
+    ///
+    /// This is only tracked for caches subject to automatic cleanup
+    /// and is reset once the cache has objects again.
+    pub empty_since: Option<ChronoDateTimeUtc>,
+
+    /// Base URL of NARs in narinfos.
+    ///
+    /// If unset, NAR URLs are relative to the binary cache endpoint.
+    pub nar_url_base: Option<String>,
+
+    /// The sequence number of the last event appended for the cache.
+    ///
+    /// This is bumped in the transactions that append events, and
+    /// never decreases even when old events are cleaned up.
+    pub last_event_seq: i64,
+
+    /// Override of the minimum NAR size to trigger chunking.
+    ///
+    /// This and the other chunking overrides fall back to the
+    /// server configuration if unset. Changing them only affects
+    /// new uploads, which will deduplicate poorly against
+    /// existing chunks.
+    pub chunking_nar_size_threshold: Option<i64>,
+
+    /// Override of the preferred minimum chunk size.
+    pub chunking_min_size: Option<i64>,
+
+    /// Override of the preferred average chunk size.
+    pub chunking_avg_size: Option<i64>,
+
+    /// Override of the preferred maximum chunk size.
+    pub chunking_max_size: Option<i64>,
+
+    /// Keypairs the cache signed with before the current one.
+    ///
+    /// Narinfos are also signed with these until they are past the
+    /// key rotation grace period. The most recent one comes first.
+    pub previous_keypairs: Json<Vec<PreviousKeypair>>,
+
+    /// Whether signatures by upstream caches are served in narinfos.
+    ///
+    /// Signatures by keys in `upstream_cache_key_names` are otherwise
+    /// stripped, while other signatures supplied by uploaders are always
+    /// served. They are kept in the database either way, so this can be
+    /// toggled without reuploading.
+    pub preserve_upstream_signatures: bool,
+}
+
+/// A keypair that was replaced.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PreviousKeypair {
+    /// The keypair.
+    pub keypair: String,
+
+    /// Timestamp when the keypair was replaced.
+    pub replaced_at: ChronoDateTimeUtc,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {
     #[sea_orm(has_many = "super::object::Entity")]
     Object,
+
+    #[sea_orm(has_many = "super::event::Entity")]
+    Event,
 }
 
 impl Model {
     pub fn keypair(&self) -> AtticResult<NixKeypair> {
         NixKeypair::from_str(&self.keypair)
     }
+
+    /// Returns the previous keypairs that are still within a grace period.
+    pub fn previous_keypairs(&self, grace_period: Duration) -> AtticResult<Vec<NixKeypair>> {
+        self.previous_keypairs
+            .0
+            .iter()
+            .filter(|previous| previous.is_retained(grace_period))
+            .map(|previous| NixKeypair::from_str(&previous.keypair))
+            .collect()
+    }
+
+    /// Returns the chunking parameters of the cache.
+    pub fn chunking(&self, global: &ChunkingConfig) -> ChunkingConfig {
+        let value =
+            |value: Option<i64>, default: usize| value.map(|v| v as usize).unwrap_or(default);
+
+        ChunkingConfig {
+            nar_size_threshold: value(self.chunking_nar_size_threshold, global.nar_size_threshold),
+            min_size: value(self.chunking_min_size, global.min_size),
+            avg_size: value(self.chunking_avg_size, global.avg_size),
+            max_size: value(self.chunking_max_size, global.max_size),
+            ..global.clone()
+        }
+    }
+}
+
+impl PreviousKeypair {
+    /// Returns whether the keypair is still within a grace period.
+    pub fn is_retained(&self, grace_period: Duration) -> bool {
+        let Ok(grace_period) = chrono::Duration::from_std(grace_period) else {
+            return true;
+        };
+
+        self.replaced_at
+            .checked_add_signed(grace_period)
+            .is_none_or(|until| Utc::now() < until)
+    }
 }
 
 impl Related<super::object::Entity> for Entity {
@@ -69,4 +172,10 @@ impl Related<super::object::Entity> for Entity {
     }
 }
 
+impl Related<super::event::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Event.def()
+    }
+}
+
 impl ActiveModelBehavior for ActiveModel {}
@@ -98,6 +98,14 @@ pub struct Model {
     /// there are no existing NAR references.
     pub holders_count: i32,
 
+    /// Number of chunk references pointing at this chunk.
+    ///
+    /// This is maintained in the same transactions that create and
+    /// delete chunk references. When it drops to zero and there are
+    /// no holders, the chunk is queued for deletion right away
+    /// instead of waiting for the orphan chunk sweep.
+    pub reference_count: i64,
+
     /// Timestamp when the chunk is created.
     pub created_at: ChronoDateTimeUtc,
 }
@@ -0,0 +1,79 @@
+//! An event in a binary cache.
+
+use sea_orm::entity::prelude::*;
+
+use attic::api::v1::cache_events::{CacheEvent, CacheEventKind};
+
+pub type EventModel = Model;
+
+/// The kind of an event.
+#[derive(EnumIter, DeriveActiveEnum, Debug, Clone, Copy, PartialEq, Eq)]
+#[sea_orm(rs_type = "String", db_type = "String(Some(10))")]
+pub enum EventKind {
+    /// A path was uploaded to the cache.
+    #[sea_orm(string_value = "upload")]
+    Upload,
+
+    /// A path was deleted from the cache.
+    #[sea_orm(string_value = "delete")]
+    Delete,
+}
+
+/// An event in a binary cache.
+#[derive(Debug, Clone, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "event")]
+pub struct Model {
+    /// Unique numeric ID of the event.
+    #[sea_orm(primary_key)]
+    pub id: i64,
+
+    /// ID of the binary cache the event happened in.
+    pub cache_id: i64,
+
+    /// The sequence number of the event within the cache.
+    pub seq: i64,
+
+    /// What happened.
+    pub kind: EventKind,
+
+    /// The hash of the store path affected.
+    #[sea_orm(column_type = "String(Some(32))")]
+    pub store_path_hash: String,
+
+    /// Timestamp when the event happened.
+    #[sea_orm(indexed)]
+    pub created_at: ChronoDateTimeUtc,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::cache::Entity",
+        from = "Column::CacheId",
+        to = "super::cache::Column::Id"
+    )]
+    Cache,
+}
+
+impl Model {
+    /// Converts this event to its API representation.
+    pub fn to_cache_event(&self) -> CacheEvent {
+        CacheEvent {
+            seq: self.seq,
+            kind: match self.kind {
+                EventKind::Upload => CacheEventKind::Upload,
+                EventKind::Delete => CacheEventKind::Delete,
+            },
+            store_path_hash: self.store_path_hash.clone(),
+            timestamp: self.created_at.timestamp(),
+        }
+    }
+}
+
+impl Related<super::cache::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Cache.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
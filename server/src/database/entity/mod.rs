@@ -2,9 +2,11 @@
 //!
 //! We use SeaORM and target PostgreSQL (production) and SQLite (development).
 
+pub mod audit_log;
 pub mod cache;
 pub mod chunk;
 pub mod chunkref;
+pub mod event;
 pub mod nar;
 pub mod object;
 
@@ -122,21 +122,31 @@ impl Model {
             .try_into()
             .map_err(ServerError::database_error)?;
 
-        Ok(NarInfo {
+        let narinfo = NarInfo {
             store_path: PathBuf::from(self.store_path.to_owned()),
             url: format!("nar/{}.nar", self.store_path_hash.as_str()),
 
             compression: Compression::from_str(&nar.compression)?,
-            file_hash: None, // FIXME
-            file_size: None, // FIXME
+            // Filled in from the chunks, which aren't known here
+            file_hash: None,
+            file_size: None,
             nar_hash: Hash::from_typed(&nar.nar_hash)?,
             nar_size,
             system: self.system.to_owned(),
             references: self.references.0.to_owned(),
             deriver: self.deriver.to_owned(),
-            signature: None,
+            // Client-supplied signatures are served verbatim
+            signatures: self.sigs.0.to_owned(),
             ca: self.ca.to_owned(),
-        })
+        };
+
+        // The store path was validated on upload, so a bad one means
+        // the database is corrupted
+        narinfo
+            .validate_store_path()
+            .map_err(ServerError::database_error)?;
+
+        Ok(narinfo)
     }
 }
 
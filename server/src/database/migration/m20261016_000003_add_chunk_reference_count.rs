@@ -0,0 +1,55 @@
+use sea_orm::ConnectionTrait;
+use sea_orm_migration::prelude::*;
+
+use crate::database::entity::chunk::*;
+use crate::database::entity::chunkref;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261016_000003_add_chunk_reference_count"
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Entity)
+                    .add_column(
+                        ColumnDef::new(Column::ReferenceCount)
+                            .big_integer()
+                            .not_null()
+                            .default(0),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        // Count the existing references
+        let count = Query::select()
+            .expr(Expr::col((chunkref::Entity, chunkref::Column::Id)).count())
+            .from(chunkref::Entity)
+            .and_where(
+                Expr::col((chunkref::Entity, chunkref::Column::ChunkId))
+                    .equals((Entity, Column::Id)),
+            )
+            .to_owned();
+
+        let update = Query::update()
+            .table(Entity)
+            .value(
+                Column::ReferenceCount,
+                SimpleExpr::SubQuery(None, Box::new(count.into_sub_query_statement())),
+            )
+            .to_owned();
+
+        let update_stmt = manager.get_database_backend().build(&update);
+        manager.get_connection().execute(update_stmt).await?;
+
+        Ok(())
+    }
+}
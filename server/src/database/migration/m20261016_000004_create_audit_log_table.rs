@@ -0,0 +1,64 @@
+use sea_orm_migration::prelude::*;
+
+use crate::database::entity::audit_log::*;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261016_000004_create_audit_log_table"
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Entity)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(Column::Id)
+                            .big_integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(Column::Action).string_len(20).not_null())
+                    .col(ColumnDef::new(Column::Subject).string().null())
+                    .col(ColumnDef::new(Column::Cache).string_len(50).not_null())
+                    .col(ColumnDef::new(Column::StorePath).string().null())
+                    .col(ColumnDef::new(Column::NarHash).string().null())
+                    .col(ColumnDef::new(Column::Details).string().null())
+                    .col(
+                        ColumnDef::new(Column::CreatedAt)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-audit-log-cache-created-at")
+                    .table(Entity)
+                    .col(Column::Cache)
+                    .col(Column::CreatedAt)
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-audit-log-created-at")
+                    .table(Entity)
+                    .col(Column::CreatedAt)
+                    .to_owned(),
+            )
+            .await
+    }
+}
@@ -0,0 +1,35 @@
+use sea_orm_migration::prelude::*;
+
+use crate::database::entity::cache::*;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261016_000007_add_cache_chunking"
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // SQLite only supports one alteration per statement
+        for column in [
+            Column::ChunkingNarSizeThreshold,
+            Column::ChunkingMinSize,
+            Column::ChunkingAvgSize,
+            Column::ChunkingMaxSize,
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Entity)
+                        .add_column(ColumnDef::new(column).big_integer().null())
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+}
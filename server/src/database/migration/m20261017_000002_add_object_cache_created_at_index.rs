@@ -0,0 +1,31 @@
+use sea_orm_migration::prelude::*;
+
+use crate::database::entity::object::*;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261017_000002_add_object_cache_created_at_index"
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Object listing pages through a cache in creation order
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-object-cache-created-at")
+                    .table(Entity)
+                    .col(Column::CacheId)
+                    .col(Column::CreatedAt)
+                    .col(Column::Id)
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
@@ -14,6 +14,17 @@ mod m20230112_000003_add_nar_num_chunks;
 mod m20230112_000004_migrate_nar_remote_files_to_chunks;
 mod m20230112_000005_drop_old_nar_columns;
 mod m20230112_000006_add_nar_completeness_hint;
+mod m20261016_000001_add_cache_empty_since;
+mod m20261016_000002_add_cache_nar_url_base;
+mod m20261016_000003_add_chunk_reference_count;
+mod m20261016_000004_create_audit_log_table;
+mod m20261016_000005_add_cache_last_event_seq;
+mod m20261016_000006_create_event_table;
+mod m20261016_000007_add_cache_chunking;
+mod m20261016_000008_add_cache_previous_keypairs;
+mod m20261017_000001_add_object_cache_id_index;
+mod m20261017_000002_add_object_cache_created_at_index;
+mod m20261017_000003_add_cache_preserve_upstream_signatures;
 
 pub struct Migrator;
 
@@ -33,6 +44,17 @@ impl MigratorTrait for Migrator {
             Box::new(m20230112_000004_migrate_nar_remote_files_to_chunks::Migration),
             Box::new(m20230112_000005_drop_old_nar_columns::Migration),
             Box::new(m20230112_000006_add_nar_completeness_hint::Migration),
+            Box::new(m20261016_000001_add_cache_empty_since::Migration),
+            Box::new(m20261016_000002_add_cache_nar_url_base::Migration),
+            Box::new(m20261016_000003_add_chunk_reference_count::Migration),
+            Box::new(m20261016_000004_create_audit_log_table::Migration),
+            Box::new(m20261016_000005_add_cache_last_event_seq::Migration),
+            Box::new(m20261016_000006_create_event_table::Migration),
+            Box::new(m20261016_000007_add_cache_chunking::Migration),
+            Box::new(m20261016_000008_add_cache_previous_keypairs::Migration),
+            Box::new(m20261017_000001_add_object_cache_id_index::Migration),
+            Box::new(m20261017_000002_add_object_cache_created_at_index::Migration),
+            Box::new(m20261017_000003_add_cache_preserve_upstream_signatures::Migration),
         ]
     }
 }
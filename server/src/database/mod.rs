@@ -1,6 +1,8 @@
 pub mod entity;
 pub mod migration;
 
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
 use std::ops::Deref;
 
 use anyhow::anyhow;
@@ -9,9 +11,11 @@ use chrono::Utc;
 use sea_orm::entity::prelude::*;
 use sea_orm::entity::Iterable as EnumIterable;
 use sea_orm::query::{JoinType, QueryOrder, QuerySelect, QueryTrait};
-use sea_orm::sea_query::{Expr, LockBehavior, LockType, Query, Value};
-use sea_orm::{ActiveValue::Set, ConnectionTrait, DatabaseConnection, FromQueryResult};
-use tokio::task;
+use sea_orm::sea_query::{Expr, LockBehavior, LockType, Query, SelectStatement, Value};
+use sea_orm::{
+    ActiveValue, ActiveValue::Set, ConnectionTrait, DatabaseConnection, FromQueryResult,
+};
+use tokio::runtime::Handle;
 
 use crate::error::{ErrorKind, ServerError, ServerResult};
 use crate::narinfo::Compression;
@@ -20,7 +24,7 @@ use attic::hash::Hash;
 use attic::nix_store::StorePathHash;
 use entity::cache::{self, CacheModel, Entity as Cache};
 use entity::chunk::{self, ChunkModel, ChunkState, Entity as Chunk};
-use entity::chunkref;
+use entity::chunkref::{self, Entity as ChunkRef};
 use entity::nar::{self, Entity as Nar, NarModel, NarState};
 use entity::object::{self, Entity as Object, ObjectModel};
 
@@ -31,6 +35,11 @@ const SELECT_NAR: &str = "N_";
 const SELECT_CHUNK: &str = "CH_";
 const SELECT_CHUNKREF: &str = "CHR_";
 
+/// Maximum number of values to bind in a single `IN` list.
+///
+/// SQLite limits the number of variables in a statement.
+pub(crate) const MAX_IN_LIST_SIZE: usize = 500;
+
 #[async_trait]
 pub trait AtticDatabase: Send + Sync {
     /// Retrieves an object in a binary cache by its store path hash, returning all its
@@ -46,13 +55,24 @@ pub trait AtticDatabase: Send + Sync {
     async fn find_cache(&self, cache: &CacheName) -> ServerResult<CacheModel>;
 
     /// Retrieves and locks a valid NAR matching a NAR Hash.
-    async fn find_and_lock_nar(&self, nar_hash: &Hash) -> ServerResult<Option<NarGuard>>;
+    ///
+    /// If `cache_id` is specified, only NARs referenced by objects
+    /// in the cache are considered.
+    async fn find_and_lock_nar(
+        &self,
+        nar_hash: &Hash,
+        cache_id: Option<i64>,
+    ) -> ServerResult<Option<NarGuard>>;
 
     /// Retrieves and locks a valid chunk matching a chunk Hash.
+    ///
+    /// If `cache_id` is specified, only chunks of NARs referenced by
+    /// objects in the cache are considered.
     async fn find_and_lock_chunk(
         &self,
         chunk_hash: &Hash,
         compression: Compression,
+        cache_id: Option<i64>,
     ) -> ServerResult<Option<ChunkGuard>>;
 
     /// Bumps the last accessed timestamp of an object.
@@ -69,6 +89,29 @@ pub struct ChunkGuard {
     chunk: ChunkModel,
 }
 
+/// Selects the IDs of NARs referenced by objects in a cache.
+pub(crate) fn nar_ids_in_cache(cache_id: i64) -> SelectStatement {
+    Query::select()
+        .column(object::Column::NarId)
+        .from(Object)
+        .and_where(object::Column::CacheId.eq(cache_id))
+        .to_owned()
+}
+
+/// Selects the IDs of chunks of NARs referenced by objects in a cache.
+fn chunk_ids_in_cache(cache_id: i64) -> SelectStatement {
+    Query::select()
+        .column((ChunkRef, chunkref::Column::ChunkId))
+        .from(ChunkRef)
+        .inner_join(
+            Object,
+            Expr::col((Object, object::Column::NarId)).equals((ChunkRef, chunkref::Column::NarId)),
+        )
+        .and_where(Expr::col((Object, object::Column::CacheId)).eq(cache_id))
+        .and_where(Expr::col((ChunkRef, chunkref::Column::ChunkId)).is_not_null())
+        .to_owned()
+}
+
 fn prefix_column<E: EntityTrait, S: QuerySelect>(mut select: S, prefix: &str) -> S {
     for col in <E::Column as EnumIterable>::iter() {
         let alias = format!("{}{}", prefix, Iden::to_string(&col));
@@ -233,9 +276,13 @@ impl AtticDatabase for DatabaseConnection {
             .ok_or_else(|| ErrorKind::NoSuchCache.into())
     }
 
-    async fn find_and_lock_nar(&self, nar_hash: &Hash) -> ServerResult<Option<NarGuard>> {
+    async fn find_and_lock_nar(
+        &self,
+        nar_hash: &Hash,
+        cache_id: Option<i64>,
+    ) -> ServerResult<Option<NarGuard>> {
         let one = Value::Unsigned(Some(1));
-        let matched_ids = Query::select()
+        let mut matched_ids = Query::select()
             .from(Nar)
             .and_where(nar::Column::NarHash.eq(nar_hash.to_typed_base16()))
             .and_where(nar::Column::State.eq(NarState::Valid))
@@ -243,6 +290,9 @@ impl AtticDatabase for DatabaseConnection {
             .lock_with_behavior(LockType::Update, LockBehavior::SkipLocked)
             .limit(1)
             .to_owned();
+        if let Some(cache_id) = cache_id {
+            matched_ids.and_where(nar::Column::Id.in_subquery(nar_ids_in_cache(cache_id)));
+        }
         let incr_holders = Query::update()
             .table(Nar)
             .values([(
@@ -271,9 +321,10 @@ impl AtticDatabase for DatabaseConnection {
         &self,
         chunk_hash: &Hash,
         compression: Compression,
+        cache_id: Option<i64>,
     ) -> ServerResult<Option<ChunkGuard>> {
         let one = Value::Unsigned(Some(1));
-        let matched_ids = Query::select()
+        let mut matched_ids = Query::select()
             .from(Chunk)
             .and_where(chunk::Column::ChunkHash.eq(chunk_hash.to_typed_base16()))
             .and_where(chunk::Column::State.eq(ChunkState::Valid))
@@ -282,6 +333,9 @@ impl AtticDatabase for DatabaseConnection {
             .lock_with_behavior(LockType::Update, LockBehavior::SkipLocked)
             .limit(1)
             .to_owned();
+        if let Some(cache_id) = cache_id {
+            matched_ids.and_where(chunk::Column::Id.in_subquery(chunk_ids_in_cache(cache_id)));
+        }
         let incr_holders = Query::update()
             .table(Chunk)
             .values([(
@@ -321,6 +375,239 @@ impl AtticDatabase for DatabaseConnection {
     }
 }
 
+/// Creates a chunk reference and increments the reference count of the chunk.
+///
+/// This should be called in a transaction.
+pub async fn insert_chunkref<C: ConnectionTrait>(
+    conn: &C,
+    model: chunkref::ActiveModel,
+) -> ServerResult<()> {
+    let chunk_id = match &model.chunk_id {
+        ActiveValue::Set(chunk_id) | ActiveValue::Unchanged(chunk_id) => *chunk_id,
+        ActiveValue::NotSet => None,
+    };
+
+    ChunkRef::insert(model)
+        .exec(conn)
+        .await
+        .map_err(ServerError::database_error)?;
+
+    if let Some(chunk_id) = chunk_id {
+        add_chunk_references(conn, vec![chunk_id], 1).await?;
+    }
+
+    Ok(())
+}
+
+/// Creates chunk references in bulk and increments the reference counts of the chunks.
+///
+/// A chunk may be referenced more than once, like when a NAR contains
+/// identical files. This should be called in a transaction.
+pub async fn insert_chunkrefs<C: ConnectionTrait>(
+    conn: &C,
+    models: Vec<chunkref::ActiveModel>,
+) -> ServerResult<()> {
+    let mut counts: HashMap<i64, i64> = HashMap::new();
+    for model in &models {
+        if let ActiveValue::Set(Some(chunk_id)) | ActiveValue::Unchanged(Some(chunk_id)) =
+            &model.chunk_id
+        {
+            *counts.entry(*chunk_id).or_default() += 1;
+        }
+    }
+
+    for batch in models.chunks(MAX_IN_LIST_SIZE) {
+        ChunkRef::insert_many(batch.to_vec())
+            .exec(conn)
+            .await
+            .map_err(ServerError::database_error)?;
+    }
+
+    let mut by_count: BTreeMap<i64, Vec<i64>> = BTreeMap::new();
+    for (chunk_id, count) in counts {
+        by_count.entry(count).or_default().push(chunk_id);
+    }
+
+    for (count, chunk_ids) in by_count {
+        add_chunk_references(conn, chunk_ids, count).await?;
+    }
+
+    Ok(())
+}
+
+/// Adjusts the reference counts of chunks.
+///
+/// This should be called in the same transaction that creates or
+/// deletes the chunk references.
+pub async fn add_chunk_references<C: ConnectionTrait>(
+    conn: &C,
+    chunk_ids: Vec<i64>,
+    delta: i64,
+) -> ServerResult<()> {
+    if chunk_ids.is_empty() || delta == 0 {
+        return Ok(());
+    }
+
+    for batch in chunk_ids.chunks(MAX_IN_LIST_SIZE) {
+        Chunk::update_many()
+            .col_expr(
+                chunk::Column::ReferenceCount,
+                Expr::col(chunk::Column::ReferenceCount).add(delta),
+            )
+            .filter(chunk::Column::Id.is_in(batch.iter().copied()))
+            .exec(conn)
+            .await
+            .map_err(ServerError::database_error)?;
+    }
+
+    Ok(())
+}
+
+/// Deletes NARs and releases their references to chunks.
+///
+/// Chunks left without references or holders are transitioned into
+/// the `Deleted` state so they can be removed from the storage
+/// backend. Returns the number of such chunks.
+///
+/// This should be called in a transaction.
+pub async fn delete_nars<C: ConnectionTrait>(conn: &C, nar_ids: Vec<i64>) -> ServerResult<u64> {
+    if nar_ids.is_empty() {
+        return Ok(0);
+    }
+
+    // Lock the NARs first so no new references can be created
+    // between counting and deleting them
+    Nar::find()
+        .select_only()
+        .column(nar::Column::Id)
+        .filter(nar::Column::Id.is_in(nar_ids.clone()))
+        .lock_exclusive()
+        .into_tuple::<i64>()
+        .all(conn)
+        .await
+        .map_err(ServerError::database_error)?;
+
+    let released: Vec<(i64, i64)> = ChunkRef::find()
+        .select_only()
+        .column(chunkref::Column::ChunkId)
+        .column_as(chunkref::Column::Id.count(), "count")
+        .filter(chunkref::Column::NarId.is_in(nar_ids.clone()))
+        .filter(chunkref::Column::ChunkId.is_not_null())
+        .group_by(chunkref::Column::ChunkId)
+        .into_tuple()
+        .all(conn)
+        .await
+        .map_err(ServerError::database_error)?;
+
+    // Chunk references are deleted along with the NARs
+    Nar::delete_many()
+        .filter(nar::Column::Id.is_in(nar_ids))
+        .exec(conn)
+        .await
+        .map_err(ServerError::database_error)?;
+
+    let mut by_count: BTreeMap<i64, Vec<i64>> = BTreeMap::new();
+    for (chunk_id, count) in &released {
+        by_count.entry(*count).or_default().push(*chunk_id);
+    }
+
+    for (count, chunk_ids) in by_count {
+        add_chunk_references(conn, chunk_ids, -count).await?;
+    }
+
+    let chunk_ids: Vec<i64> = released.into_iter().map(|(chunk_id, _)| chunk_id).collect();
+    let mut queued = 0;
+    for batch in chunk_ids.chunks(MAX_IN_LIST_SIZE) {
+        queued += Chunk::update_many()
+            .col_expr(chunk::Column::State, Expr::value(ChunkState::Deleted))
+            .filter(chunk::Column::Id.is_in(batch.iter().copied()))
+            .filter(chunk::Column::State.eq(ChunkState::Valid))
+            .filter(chunk::Column::ReferenceCount.lte(0))
+            .filter(chunk::Column::HoldersCount.eq(0))
+            .exec(conn)
+            .await
+            .map_err(ServerError::database_error)?
+            .rows_affected;
+    }
+
+    Ok(queued)
+}
+
+/// Resets the holders counts of all NARs and chunks.
+///
+/// Holders are only released by dropping guards, so counts left over
+/// from a process that exited abruptly would otherwise prevent the
+/// NARs and chunks from ever being garbage collected. This must only
+/// be called when no other server is running against the database.
+///
+/// Returns the number of NARs and chunks that were reset.
+pub async fn reset_holders<C: ConnectionTrait>(conn: &C) -> ServerResult<(u64, u64)> {
+    let nars = Nar::update_many()
+        .col_expr(nar::Column::HoldersCount, Expr::value(0))
+        .filter(nar::Column::HoldersCount.ne(0))
+        .exec(conn)
+        .await
+        .map_err(ServerError::database_error)?;
+
+    let chunks = Chunk::update_many()
+        .col_expr(chunk::Column::HoldersCount, Expr::value(0))
+        .filter(chunk::Column::HoldersCount.ne(0))
+        .exec(conn)
+        .await
+        .map_err(ServerError::database_error)?;
+
+    Ok((nars.rows_affected, chunks.rows_affected))
+}
+
+/// Spawns a task to release a guard.
+///
+/// Failures are logged, since a hold that is never released blocks
+/// garbage collection until the holders counts are reset.
+fn spawn_unlock<F>(kind: &'static str, id: i64, unlock: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    /// Logs if the unlock task is dropped before completion.
+    ///
+    /// This happens when the runtime shuts down before the task runs.
+    struct PendingUnlock {
+        kind: &'static str,
+        id: i64,
+        done: bool,
+    }
+
+    impl Drop for PendingUnlock {
+        fn drop(&mut self) {
+            if !self.done {
+                tracing::warn!(
+                    "Unlocking {} {} was cancelled; it will stay held until the holders counts are reset",
+                    self.kind,
+                    self.id
+                );
+            }
+        }
+    }
+
+    let Ok(handle) = Handle::try_current() else {
+        tracing::error!(
+            "Cannot unlock {} {} outside of a runtime; it will stay held until the holders counts are reset",
+            kind,
+            id
+        );
+        return;
+    };
+
+    handle.spawn(async move {
+        let mut pending = PendingUnlock {
+            kind,
+            id,
+            done: false,
+        };
+        unlock.await;
+        pending.done = true;
+    });
+}
+
 impl Deref for NarGuard {
     type Target = NarModel;
 
@@ -334,7 +621,7 @@ impl Drop for NarGuard {
         let database = self.database.clone();
         let nar_id = self.nar.id;
 
-        task::spawn(async move {
+        spawn_unlock("NAR", nar_id, async move {
             tracing::debug!("Unlocking NAR");
 
             let one = Value::Unsigned(Some(1));
@@ -374,7 +661,7 @@ impl Drop for ChunkGuard {
         let database = self.database.clone();
         let chunk_id = self.chunk.id;
 
-        task::spawn(async move {
+        spawn_unlock("chunk", chunk_id, async move {
             tracing::debug!("Unlocking chunk");
 
             let one = Value::Unsigned(Some(1));
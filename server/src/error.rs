@@ -2,15 +2,18 @@
 
 use std::error::Error as StdError;
 use std::fmt;
+use std::io;
 
 use anyhow::Error as AnyError;
-use axum::http::StatusCode;
+use axum::http::{header::RETRY_AFTER, HeaderValue, StatusCode};
 use axum::response::{IntoResponse, Response};
 use axum::Json;
 use displaydoc::Display;
 use serde::Serialize;
 use tracing_error::SpanTrace;
 
+use crate::storage::FileNotFound;
+use attic::api::error::ErrorCode;
 use attic::error::AtticError;
 
 pub type ServerResult<T> = Result<T, ServerError>;
@@ -57,6 +60,9 @@ pub enum ErrorKind {
     /// The requested NAR has missing chunks and needs to be repaired.
     IncompleteNar,
 
+    /// Unsupported content encoding "{encoding}".
+    UnsupportedContentEncoding { encoding: String },
+
     /// Database error: {0:#}
     DatabaseError(AnyError),
 
@@ -69,6 +75,15 @@ pub enum ErrorKind {
     /// Access error: {0}
     AccessError(super::access::Error),
 
+    /// Permission denied for the following fields: {fields}
+    FieldPermissionDenied { fields: String },
+
+    /// Too many requests. Try again in {retry_after_secs} seconds.
+    RateLimited { retry_after_secs: u64 },
+
+    /// The {what} is larger than the limit of {limit} bytes.
+    PayloadTooLarge { what: &'static str, limit: usize },
+
     /// General request error: {0:#}
     RequestError(AnyError),
 
@@ -76,10 +91,14 @@ pub enum ErrorKind {
     AtticError(AtticError),
 }
 
+/// The body of an error response.
+///
+/// See [`attic::api::error`] for which fields are stable.
 #[derive(Serialize)]
 pub struct ErrorResponse {
     code: u16,
     error: String,
+    error_code: u16,
     message: String,
 }
 
@@ -99,6 +118,22 @@ impl ServerError {
     pub fn set_discovery_permission(&mut self, perm: bool) {
         self.discovery_permission = perm;
     }
+
+    /// Returns whether this is a storage error meaning the file doesn't exist.
+    ///
+    /// Other storage errors may be transient.
+    pub fn is_storage_not_found(&self) -> bool {
+        let ErrorKind::StorageError(e) = &self.kind else {
+            return false;
+        };
+
+        e.chain().any(|cause| {
+            cause.is::<FileNotFound>()
+                || cause
+                    .downcast_ref::<io::Error>()
+                    .is_some_and(|e| e.kind() == io::ErrorKind::NotFound)
+        })
+    }
 }
 
 impl fmt::Display for ServerError {
@@ -156,34 +191,62 @@ impl IntoResponse for ServerError {
         let sanitized = kind.into_clients();
 
         let status_code = sanitized.http_status_code();
+        let retry_after = match &sanitized {
+            ErrorKind::RateLimited { retry_after_secs } => Some(*retry_after_secs),
+            _ => None,
+        };
+        let error_code = sanitized.error_code();
         let error_response = ErrorResponse {
             code: status_code.as_u16(),
             message: sanitized.to_string(),
-            error: sanitized.name().to_string(),
+            error: error_code.name().to_string(),
+            error_code: error_code.code(),
         };
 
-        (status_code, Json(error_response)).into_response()
+        let mut response = (status_code, Json(error_response)).into_response();
+        if let Some(retry_after) = retry_after {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+
+        response
     }
 }
 
 impl ErrorKind {
-    fn name(&self) -> &'static str {
+    /// Returns the stable code of this error.
+    fn error_code(&self) -> ErrorCode {
         match self {
-            Self::NotFound => "NotFound",
-            Self::Unauthorized => "Unauthorized",
-            Self::InternalServerError => "InternalServerError",
-
-            Self::NoSuchObject => "NoSuchObject",
-            Self::NoSuchCache => "NoSuchCache",
-            Self::CacheAlreadyExists => "CacheAlreadyExists",
-            Self::InvalidCompressionType { .. } => "InvalidCompressionType",
-            Self::IncompleteNar => "IncompleteNar",
-            Self::AtticError(e) => e.name(),
-            Self::DatabaseError(_) => "DatabaseError",
-            Self::StorageError(_) => "StorageError",
-            Self::ManifestSerializationError(_) => "ManifestSerializationError",
-            Self::AccessError(_) => "AccessError",
-            Self::RequestError(_) => "RequestError",
+            Self::NotFound => ErrorCode::NotFound,
+            Self::Unauthorized => ErrorCode::Unauthorized,
+            Self::InternalServerError => ErrorCode::InternalServerError,
+
+            Self::NoSuchObject => ErrorCode::NoSuchObject,
+            Self::NoSuchCache => ErrorCode::NoSuchCache,
+            Self::CacheAlreadyExists => ErrorCode::CacheAlreadyExists,
+            Self::InvalidCompressionType { .. } => ErrorCode::InvalidCompressionType,
+            Self::IncompleteNar => ErrorCode::IncompleteNar,
+            Self::UnsupportedContentEncoding { .. } => ErrorCode::UnsupportedContentEncoding,
+            Self::AtticError(e) => match e {
+                AtticError::InvalidStorePath { .. } => ErrorCode::InvalidStorePath,
+                AtticError::InvalidStorePathName { .. } => ErrorCode::InvalidStorePathName,
+                AtticError::InvalidStorePathHash { .. } => ErrorCode::InvalidStorePathHash,
+                AtticError::InvalidCacheName { .. } => ErrorCode::InvalidCacheName,
+                AtticError::InvalidContentAddress { .. } => ErrorCode::InvalidContentAddress,
+                AtticError::SigningError(_) => ErrorCode::SigningError,
+                AtticError::HashError(_) => ErrorCode::HashError,
+                AtticError::IoError { .. } => ErrorCode::IoError,
+                AtticError::CxxError { .. } => ErrorCode::CxxError,
+            },
+            Self::DatabaseError(_) => ErrorCode::DatabaseError,
+            Self::StorageError(_) => ErrorCode::StorageError,
+            Self::ManifestSerializationError(_) => ErrorCode::ManifestSerializationError,
+            Self::AccessError(_) => ErrorCode::PermissionDenied,
+            Self::FieldPermissionDenied { .. } => ErrorCode::FieldPermissionDenied,
+            Self::RateLimited { .. } => ErrorCode::RateLimited,
+            Self::PayloadTooLarge { .. } => ErrorCode::PayloadTooLarge,
+            Self::RequestError(_) => ErrorCode::RequestError,
         }
     }
 
@@ -194,6 +257,7 @@ impl ErrorKind {
             Self::NoSuchCache => Self::Unauthorized,
             Self::NoSuchObject => Self::Unauthorized,
             Self::AccessError(_) => Self::Unauthorized,
+            Self::FieldPermissionDenied { .. } => Self::Unauthorized,
 
             _ => self,
         }
@@ -219,14 +283,110 @@ impl ErrorKind {
             Self::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
 
             Self::AccessError(_) => StatusCode::FORBIDDEN,
+            Self::FieldPermissionDenied { .. } => StatusCode::FORBIDDEN,
+            Self::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Self::PayloadTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
             Self::NoSuchCache => StatusCode::NOT_FOUND,
             Self::NoSuchObject => StatusCode::NOT_FOUND,
-            Self::CacheAlreadyExists => StatusCode::BAD_REQUEST,
+            Self::CacheAlreadyExists => StatusCode::CONFLICT,
             Self::IncompleteNar => StatusCode::SERVICE_UNAVAILABLE,
             Self::ManifestSerializationError(_) => StatusCode::BAD_REQUEST,
             Self::RequestError(_) => StatusCode::BAD_REQUEST,
             Self::InvalidCompressionType { .. } => StatusCode::BAD_REQUEST,
+            Self::UnsupportedContentEncoding { .. } => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::AtticError(
+                AtticError::InvalidStorePath { .. }
+                | AtticError::InvalidStorePathName { .. }
+                | AtticError::InvalidStorePathHash { .. }
+                | AtticError::InvalidContentAddress { .. },
+            ) => StatusCode::BAD_REQUEST,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+    use std::path::PathBuf;
+
+    use anyhow::anyhow;
+    use axum::body::to_bytes;
+    use serde_json::Value;
+
+    fn all_kinds() -> Vec<ErrorKind> {
+        vec![
+            ErrorKind::NotFound,
+            ErrorKind::Unauthorized,
+            ErrorKind::InternalServerError,
+            ErrorKind::NoSuchCache,
+            ErrorKind::CacheAlreadyExists,
+            ErrorKind::NoSuchObject,
+            ErrorKind::InvalidCompressionType {
+                name: "meow".to_string(),
+            },
+            ErrorKind::IncompleteNar,
+            ErrorKind::UnsupportedContentEncoding {
+                encoding: "meow".to_string(),
+            },
+            ErrorKind::DatabaseError(anyhow!("Database")),
+            ErrorKind::StorageError(anyhow!("Storage")),
+            ErrorKind::ManifestSerializationError(super::super::nix_manifest::Error::Unexpected(
+                "meow",
+            )),
+            ErrorKind::AccessError(super::super::access::Error::PermissionDenied),
+            ErrorKind::FieldPermissionDenied {
+                fields: "priority".to_string(),
+            },
+            ErrorKind::RateLimited {
+                retry_after_secs: 1,
+            },
+            ErrorKind::PayloadTooLarge {
+                what: "NAR",
+                limit: 1,
+            },
+            ErrorKind::RequestError(anyhow!("Request")),
+            ErrorKind::AtticError(AtticError::InvalidStorePath {
+                path: PathBuf::from("/meow"),
+                reason: "meow",
+            }),
+            ErrorKind::AtticError(AtticError::InvalidCacheName {
+                name: "Meow".to_string(),
+            }),
+            ErrorKind::AtticError(AtticError::InvalidContentAddress {
+                ca: "meow".to_string(),
+                reason: "meow",
+            }),
+        ]
+    }
+
+    #[test]
+    fn test_error_codes_distinct() {
+        let kinds = all_kinds();
+        let codes: HashSet<u16> = kinds.iter().map(|k| k.error_code().code()).collect();
+        assert_eq!(kinds.len(), codes.len());
+    }
+
+    #[tokio::test]
+    async fn test_error_response_roundtrip() {
+        for (kind, copy) in all_kinds().into_iter().zip(all_kinds()) {
+            let expected = copy.into_clients();
+            let expected_code = expected.error_code();
+
+            let response = ServerError::from(kind).into_response();
+            assert_eq!(expected.http_status_code(), response.status());
+
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+
+            let code = body["error_code"].as_u64().unwrap() as u16;
+            assert_eq!(Some(expected_code), ErrorCode::from_code(code));
+            assert_eq!(
+                Some(expected_code),
+                ErrorCode::from_name(body["error"].as_str().unwrap())
+            );
+        }
+    }
+}
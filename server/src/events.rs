@@ -0,0 +1,366 @@
+//! Cache event stream.
+//!
+//! Uploads and deletions are appended to the `event` table in the
+//! same transactions that make the changes. Each cache has its own
+//! sequence of events, and consumers resume from the last sequence
+//! number they have seen.
+//!
+//! Requests waiting for events are woken through an in-process
+//! notifier. Events appended by other processes sharing the database
+//! are picked up by polling at `events.poll-interval`.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::Utc;
+use sea_orm::entity::prelude::*;
+use sea_orm::query::{QueryOrder, QuerySelect};
+use sea_orm::sea_query::Expr;
+use sea_orm::ActiveValue::Set;
+use sea_orm::ConnectionTrait;
+use tokio::sync::watch;
+use tokio::time::{self, Instant};
+
+use crate::database::entity::cache::{self, Entity as Cache};
+use crate::database::entity::event::{self, Entity as Event, EventKind};
+use crate::error::{ServerError, ServerResult};
+use crate::State;
+use attic::api::v1::cache_events::CacheEvent;
+
+/// Maximum number of events to return at once.
+const MAX_EVENTS: u64 = 1000;
+
+/// Number of events to insert in a single statement.
+const INSERT_BATCH_SIZE: usize = 100;
+
+/// Wakes requests waiting for events.
+#[derive(Debug, Default)]
+pub(crate) struct CacheEventNotifier {
+    /// Senders keyed by cache IDs, carrying the last sequence number.
+    senders: Mutex<HashMap<i64, watch::Sender<i64>>>,
+}
+
+impl CacheEventNotifier {
+    /// Subscribes to events in a cache.
+    pub fn subscribe(&self, cache_id: i64) -> watch::Receiver<i64> {
+        let mut senders = self.senders.lock().unwrap();
+        senders
+            .entry(cache_id)
+            .or_insert_with(|| watch::channel(0).0)
+            .subscribe()
+    }
+
+    /// Wakes requests waiting for events in a cache.
+    ///
+    /// This must be called after the events are committed.
+    pub fn notify(&self, cache_id: i64, seq: i64) {
+        let mut senders = self.senders.lock().unwrap();
+        if let Some(sender) = senders.get(&cache_id) {
+            if sender.receiver_count() == 0 {
+                senders.remove(&cache_id);
+            } else {
+                sender.send_replace(seq);
+            }
+        }
+    }
+}
+
+/// Appends events to a cache, returning the sequence number of the last one.
+///
+/// This should be called in the transaction making the changes.
+/// Concurrent transactions appending to the same cache are
+/// serialized on the cache row, so events become visible in
+/// sequence order.
+pub(crate) async fn append_events<C: ConnectionTrait>(
+    conn: &C,
+    cache_id: i64,
+    kind: EventKind,
+    store_path_hashes: Vec<String>,
+) -> ServerResult<i64> {
+    let count = store_path_hashes.len() as i64;
+
+    Cache::update_many()
+        .col_expr(
+            cache::Column::LastEventSeq,
+            Expr::col(cache::Column::LastEventSeq).add(count),
+        )
+        .filter(cache::Column::Id.eq(cache_id))
+        .exec(conn)
+        .await
+        .map_err(ServerError::database_error)?;
+
+    let last_seq: i64 = Cache::find_by_id(cache_id)
+        .select_only()
+        .column(cache::Column::LastEventSeq)
+        .into_tuple()
+        .one(conn)
+        .await
+        .map_err(ServerError::database_error)?
+        .unwrap_or(0);
+
+    let now = Utc::now();
+    let first_seq = last_seq - count + 1;
+    let events: Vec<_> = store_path_hashes
+        .into_iter()
+        .enumerate()
+        .map(|(i, store_path_hash)| event::ActiveModel {
+            cache_id: Set(cache_id),
+            seq: Set(first_seq + i as i64),
+            kind: Set(kind),
+            store_path_hash: Set(store_path_hash),
+            created_at: Set(now),
+            ..Default::default()
+        })
+        .collect();
+
+    for batch in events.chunks(INSERT_BATCH_SIZE) {
+        Event::insert_many(batch.to_vec())
+            .exec(conn)
+            .await
+            .map_err(ServerError::database_error)?;
+    }
+
+    Ok(last_seq)
+}
+
+/// Returns events in a cache after a sequence number.
+pub(crate) async fn query_events<C: ConnectionTrait>(
+    conn: &C,
+    cache_id: i64,
+    since: i64,
+) -> ServerResult<Vec<CacheEvent>> {
+    let events = Event::find()
+        .filter(event::Column::CacheId.eq(cache_id))
+        .filter(event::Column::Seq.gt(since))
+        .order_by_asc(event::Column::Seq)
+        .limit(MAX_EVENTS)
+        .all(conn)
+        .await
+        .map_err(ServerError::database_error)?;
+
+    Ok(events.iter().map(event::Model::to_cache_event).collect())
+}
+
+/// Returns events in a cache after a sequence number, waiting for them if necessary.
+///
+/// Returns an empty list if no events arrive before the timeout.
+pub(crate) async fn wait_for_events(
+    state: &State,
+    cache_id: i64,
+    since: i64,
+    timeout: Duration,
+) -> ServerResult<Vec<CacheEvent>> {
+    let db = state.database().await?;
+    let poll_interval = state.config.events.poll_interval;
+    let deadline = Instant::now() + timeout;
+
+    // Subscribe first so events committed while we query aren't missed
+    let mut notified = state.cache_events.subscribe(cache_id);
+
+    loop {
+        let events = query_events(db, cache_id, since).await?;
+
+        let now = Instant::now();
+        if !events.is_empty() || now >= deadline {
+            return Ok(events);
+        }
+
+        let mut wait = deadline - now;
+        if !poll_interval.is_zero() {
+            wait = wait.min(poll_interval);
+        }
+
+        let _ = time::timeout(wait, notified.changed()).await;
+    }
+}
+
+/// Deletes events older than the retention period.
+pub(crate) async fn reap_old_events(state: &State) -> ServerResult<u64> {
+    let retention_period = state.config.events.retention_period;
+    if retention_period.is_zero() {
+        return Ok(0);
+    }
+
+    let Some(cutoff) = chrono::Duration::from_std(retention_period)
+        .ok()
+        .and_then(|period| Utc::now().checked_sub_signed(period))
+    else {
+        return Ok(0);
+    };
+
+    let db = state.database().await?;
+
+    let deletion = Event::delete_many()
+        .filter(event::Column::CreatedAt.lt(cutoff))
+        .exec(db)
+        .await
+        .map_err(ServerError::database_error)?;
+
+    Ok(deletion.rows_affected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sea_orm::TransactionTrait;
+
+    use crate::config::Config;
+    use crate::database::entity::Json as DbJson;
+    use crate::database::migration::{Migrator, MigratorTrait};
+    use crate::StateInner;
+    use attic::api::v1::cache_events::CacheEventKind;
+
+    async fn make_state(poll_interval: &str) -> State {
+        let config: Config = toml::from_str(&format!(
+            r#"
+[database]
+url = "sqlite::memory:"
+
+[storage]
+type = "local"
+path = "/nonexistent"
+
+[chunking]
+nar-size-threshold = 0
+min-size = 16384
+avg-size = 65536
+max-size = 262144
+
+[events]
+poll-interval = "{poll_interval}"
+
+[jwt.signing]
+token-hs256-secret-base64 = "dmVyeSBzZWN1cmUgc2VjcmV0"
+"#
+        ))
+        .unwrap();
+
+        let state = StateInner::new(config).await;
+        let db = state.database().await.unwrap();
+        Migrator::up(db, None).await.unwrap();
+
+        state
+    }
+
+    async fn insert_cache(state: &State, name: &str) -> i64 {
+        let db = state.database().await.unwrap();
+
+        Cache::insert(cache::ActiveModel {
+            name: Set(name.to_string()),
+            keypair: Set(String::new()),
+            is_public: Set(false),
+            store_dir: Set("/nix/store".to_string()),
+            priority: Set(41),
+            upstream_cache_key_names: Set(DbJson(Vec::new())),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap()
+        .last_insert_id
+    }
+
+    async fn append(state: &State, cache_id: i64, kind: EventKind, hashes: &[&str]) -> i64 {
+        let db = state.database().await.unwrap();
+        let txn = db.begin().await.unwrap();
+        let hashes = hashes.iter().map(|h| h.to_string()).collect();
+        let seq = append_events(&txn, cache_id, kind, hashes).await.unwrap();
+        txn.commit().await.unwrap();
+
+        state.cache_events.notify(cache_id, seq);
+        seq
+    }
+
+    fn summarize(events: &[CacheEvent]) -> Vec<(i64, CacheEventKind, &str)> {
+        events
+            .iter()
+            .map(|e| (e.seq, e.kind, e.store_path_hash.as_str()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_event_sequence() {
+        let state = make_state("5s").await;
+        let db = state.database().await.unwrap();
+        let a = insert_cache(&state, "a").await;
+        let b = insert_cache(&state, "b").await;
+
+        assert!(query_events(db, a, 0).await.unwrap().is_empty());
+
+        assert_eq!(2, append(&state, a, EventKind::Upload, &["x", "y"]).await);
+        assert_eq!(1, append(&state, b, EventKind::Upload, &["z"]).await);
+        assert_eq!(3, append(&state, a, EventKind::Delete, &["x"]).await);
+
+        // Sequences are per-cache
+        assert_eq!(
+            vec![
+                (1, CacheEventKind::Upload, "x"),
+                (2, CacheEventKind::Upload, "y"),
+                (3, CacheEventKind::Delete, "x"),
+            ],
+            summarize(&query_events(db, a, 0).await.unwrap())
+        );
+        assert_eq!(
+            vec![(1, CacheEventKind::Upload, "z")],
+            summarize(&query_events(db, b, 0).await.unwrap())
+        );
+
+        // Resuming only returns newer events
+        assert_eq!(
+            vec![(3, CacheEventKind::Delete, "x")],
+            summarize(&query_events(db, a, 2).await.unwrap())
+        );
+        assert!(query_events(db, a, 3).await.unwrap().is_empty());
+
+        // Sequence numbers are never reused after old events are deleted
+        Event::delete_many().exec(db).await.unwrap();
+        assert_eq!(4, append(&state, a, EventKind::Upload, &["y"]).await);
+        assert_eq!(
+            vec![(4, CacheEventKind::Upload, "y")],
+            summarize(&query_events(db, a, 3).await.unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_event_wakeup() {
+        // Polling alone would never find the event in time
+        let state = make_state("1 hour").await;
+        let cache_id = insert_cache(&state, "a").await;
+
+        // Times out without events
+        let events = wait_for_events(&state, cache_id, 0, Duration::from_millis(10))
+            .await
+            .unwrap();
+        assert!(events.is_empty());
+
+        let waiter = tokio::spawn({
+            let state = state.clone();
+            async move {
+                wait_for_events(&state, cache_id, 0, Duration::from_secs(3600))
+                    .await
+                    .unwrap()
+            }
+        });
+
+        // Let the waiter subscribe and find nothing
+        time::sleep(Duration::from_millis(100)).await;
+        assert!(!waiter.is_finished());
+
+        append(&state, cache_id, EventKind::Upload, &["x"]).await;
+
+        let events = time::timeout(Duration::from_secs(10), waiter)
+            .await
+            .expect("Waiter was not woken")
+            .unwrap();
+        assert_eq!(vec![(1, CacheEventKind::Upload, "x")], summarize(&events));
+
+        // Events already there are returned immediately
+        let events = wait_for_events(&state, cache_id, 0, Duration::from_secs(3600))
+            .await
+            .unwrap();
+        assert_eq!(1, events.len());
+    }
+}
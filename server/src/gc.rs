@@ -1,26 +1,56 @@
 //! Garbage collection.
 
-use std::sync::Arc;
-use std::time::Duration;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Result};
-use chrono::{Duration as ChronoDuration, Utc};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
 use futures::future::join_all;
+use futures::join;
 use sea_orm::entity::prelude::*;
 use sea_orm::query::QuerySelect;
-use sea_orm::sea_query::{LockBehavior, LockType, Query};
-use sea_orm::{ConnectionTrait, FromQueryResult};
+use sea_orm::sea_query::{Alias, Expr, Func, LockBehavior, LockType, Query, SimpleExpr};
+use sea_orm::{
+    Condition, ConnectionTrait, DatabaseTransaction, DbBackend, FromQueryResult, JoinType,
+    Statement, TransactionTrait,
+};
 use tokio::sync::Semaphore;
 use tokio::time;
 use tracing::instrument;
+use uuid::Uuid;
 
 use super::{State, StateInner};
+use crate::audit::{self, AuditEvent};
 use crate::config::Config;
-use crate::database::entity::cache::{self, Entity as Cache};
+use crate::database::delete_nars;
+use crate::database::entity::audit_log::AuditAction;
+use crate::database::entity::cache::{self, CacheModel, Entity as Cache};
 use crate::database::entity::chunk::{self, ChunkState, Entity as Chunk};
 use crate::database::entity::chunkref::{self, Entity as ChunkRef};
+use crate::database::entity::event::EventKind;
 use crate::database::entity::nar::{self, Entity as Nar, NarState};
 use crate::database::entity::object::{self, Entity as Object};
+use crate::events::{append_events, reap_old_events};
+use attic::api::v1::cache_gc::CacheGcStatus;
+use attic::cache::CacheName;
+
+/// How long to keep finished jobs around for polling.
+const CACHE_GC_JOB_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+/// Number of orphan NARs to delete in a single transaction.
+const ORPHAN_NAR_BATCH_SIZE: u64 = 100;
+
+/// Key of the advisory lock held during garbage collection.
+///
+/// This is "atticgc!" in ASCII.
+const GC_ADVISORY_LOCK_KEY: i64 = 0x6174_7469_6367_6321;
+
+/// Number of expired objects to delete in a single transaction.
+const EXPIRED_OBJECT_BATCH_SIZE: u64 = 500;
+
+/// Number of stored files to look up in the database at a time.
+const STORAGE_GC_LOOKUP_BATCH_SIZE: usize = 500;
 
 #[derive(Debug, FromQueryResult)]
 struct CacheIdAndRetentionPeriod {
@@ -29,8 +59,204 @@ struct CacheIdAndRetentionPeriod {
     retention_period: i32,
 }
 
+#[derive(Debug, FromQueryResult)]
+struct CacheObjectCount {
+    id: i64,
+    name: String,
+    created_at: DateTime<Utc>,
+    empty_since: Option<DateTime<Utc>>,
+    num_objects: i64,
+}
+
+/// Lock preventing concurrent garbage collection runs.
+///
+/// On PostgreSQL, this is a transaction-level advisory lock taken
+/// in an otherwise idle transaction. It's held for a single run, and
+/// a crashed instance releases it when its connection goes away.
+/// Other databases only have a single writer, so nothing is locked.
+struct GcLock {
+    txn: Option<DatabaseTransaction>,
+}
+
+/// Result of garbage collection on a single cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheGcReport {
+    /// Number of objects deleted.
+    pub objects_deleted: u64,
+
+    /// Total size of NARs orphaned by the deletion, in bytes.
+    pub bytes_freed: u64,
+}
+
+/// Options for storage garbage collection.
+#[derive(Debug, Clone)]
+pub struct StorageGcOptions {
+    /// Only delete files last modified at least this long ago.
+    ///
+    /// Uploads write files before they are recorded in the database,
+    /// so recent files may belong to uploads in progress.
+    pub min_age: Duration,
+
+    /// Whether to only report orphan files without deleting them.
+    pub dry_run: bool,
+}
+
+/// Summary of a storage garbage collection run.
+#[derive(Debug, Clone, Default)]
+pub struct StorageGcReport {
+    /// Number of files in the storage backend.
+    pub listed: usize,
+
+    /// Number of files not named like chunks, which are left alone.
+    pub ignored: usize,
+
+    /// Number of files referenced by chunks.
+    pub referenced: usize,
+
+    /// Number of unreferenced files too recent to delete.
+    pub recent: usize,
+
+    /// Remote file IDs of unreferenced files old enough to delete.
+    pub orphaned: Vec<String>,
+
+    /// Total size of the orphan files, in bytes.
+    pub orphaned_bytes: u64,
+
+    /// Number of orphan files deleted.
+    pub deleted: usize,
+
+    /// Number of orphan files that could not be deleted.
+    pub failed: usize,
+}
+
+/// Garbage collection jobs requested through the API.
+///
+/// This is tracked in memory, so jobs are only visible on the
+/// server instance that is running them.
+#[derive(Debug, Default)]
+pub(crate) struct CacheGcJobs {
+    inner: Mutex<CacheGcJobsInner>,
+}
+
+#[derive(Debug, Default)]
+struct CacheGcJobsInner {
+    /// Jobs keyed by their IDs.
+    jobs: HashMap<String, CacheGcJobEntry>,
+
+    /// When the last job was started on each cache.
+    last_started: HashMap<i64, Instant>,
+}
+
+#[derive(Debug)]
+struct CacheGcJobEntry {
+    cache_id: i64,
+    status: CacheGcStatus,
+    updated_at: Instant,
+}
+
+/// What to do with a cache subject to empty cache cleanup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EmptyCacheAction {
+    /// Leave the cache alone.
+    Keep,
+
+    /// Record that the cache has become empty.
+    MarkEmpty,
+
+    /// Record that the cache is no longer empty.
+    ClearEmpty,
+
+    /// Destroy the cache.
+    Destroy,
+}
+
+impl CacheGcJobs {
+    /// Starts a job on a cache, returning its ID.
+    ///
+    /// If a job was started on the cache less than `cooldown` ago,
+    /// returns how long to wait before trying again.
+    pub fn start(
+        &self,
+        cache_id: i64,
+        cooldown: Duration,
+        now: Instant,
+    ) -> Result<String, Duration> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if let Some(last_started) = inner.last_started.get(&cache_id) {
+            let elapsed = now.saturating_duration_since(*last_started);
+            if elapsed < cooldown {
+                return Err(cooldown - elapsed);
+            }
+        }
+
+        // Forget about old runs
+        inner.jobs.retain(|_, job| {
+            !job.status.is_finished()
+                || now.saturating_duration_since(job.updated_at) < CACHE_GC_JOB_RETENTION
+        });
+        inner
+            .last_started
+            .retain(|_, started| now.saturating_duration_since(*started) < cooldown);
+
+        let id = Uuid::new_v4().to_string();
+        inner.jobs.insert(
+            id.clone(),
+            CacheGcJobEntry {
+                cache_id,
+                status: CacheGcStatus::Running,
+                updated_at: now,
+            },
+        );
+        inner.last_started.insert(cache_id, now);
+
+        Ok(id)
+    }
+
+    /// Records the result of a job.
+    pub fn finish(&self, id: &str, result: &Result<CacheGcReport>, now: Instant) -> CacheGcStatus {
+        let status = match result {
+            Ok(report) => CacheGcStatus::Completed {
+                objects_deleted: report.objects_deleted,
+                bytes_freed: report.bytes_freed,
+            },
+            Err(e) => CacheGcStatus::Failed {
+                error: e.to_string(),
+            },
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(job) = inner.jobs.get_mut(id) {
+            job.status = status.clone();
+            job.updated_at = now;
+        }
+
+        status
+    }
+
+    /// Returns the status of a job on a cache.
+    pub fn get(&self, cache_id: i64, id: &str) -> Option<CacheGcStatus> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .jobs
+            .get(id)
+            .filter(|job| job.cache_id == cache_id)
+            .map(|job| job.status.clone())
+    }
+}
+
 /// Runs garbage collection periodically.
+///
+/// If enabled, chunks released by deleted objects are also
+/// reclaimed on a shorter interval.
 pub async fn run_garbage_collection(config: Config) {
+    join!(
+        run_full_garbage_collection(config.clone()),
+        run_chunk_reclamation(config),
+    );
+}
+
+async fn run_full_garbage_collection(config: Config) {
     let interval = config.garbage_collection.interval;
 
     if interval == Duration::ZERO {
@@ -48,19 +274,116 @@ pub async fn run_garbage_collection(config: Config) {
     }
 }
 
+/// Reclaims chunks without references periodically.
+///
+/// This deletes orphan NARs and the chunks they were the last
+/// references to, without the expensive orphan chunk sweep.
+async fn run_chunk_reclamation(config: Config) {
+    let interval = config.garbage_collection.reclaim_interval;
+
+    if interval == Duration::ZERO {
+        // disabled
+        return;
+    }
+
+    let state = StateInner::new(config).await;
+
+    loop {
+        if let Err(e) = run_chunk_reclamation_once(&state).await {
+            tracing::warn!("Chunk reclamation failed: {}", e);
+        }
+
+        time::sleep(interval).await;
+    }
+}
+
+#[instrument(skip_all)]
+async fn run_chunk_reclamation_once(state: &State) -> Result<()> {
+    let Some(lock) = GcLock::try_acquire(state).await? else {
+        tracing::info!("Garbage collection is running elsewhere, skipping chunk reclamation");
+        return Ok(());
+    };
+
+    run_reap_orphan_nars(state).await?;
+    run_delete_chunks(state).await?;
+
+    lock.release().await
+}
+
 /// Runs garbage collection once.
+///
+/// The run is skipped if another instance is collecting garbage
+/// in the same database.
 #[instrument(skip_all)]
 pub async fn run_garbage_collection_once(config: Config) -> Result<()> {
-    tracing::info!("Running garbage collection...");
-
     let state = StateInner::new(config).await;
-    run_time_based_garbage_collection(&state).await?;
-    run_reap_orphan_nars(&state).await?;
-    run_reap_orphan_chunks(&state).await?;
+    run_garbage_collection_locked(&state).await?;
 
     Ok(())
 }
 
+/// Runs garbage collection once, returning whether it actually ran.
+async fn run_garbage_collection_locked(state: &State) -> Result<bool> {
+    let Some(lock) = GcLock::try_acquire(state).await? else {
+        tracing::info!("Garbage collection is running elsewhere, skipping");
+        return Ok(false);
+    };
+
+    tracing::info!("Running garbage collection...");
+
+    run_time_based_garbage_collection(state).await?;
+    run_reap_empty_caches(state).await?;
+    run_reap_orphan_nars(state).await?;
+    run_reap_orphan_chunks(state).await?;
+    run_reap_old_events(state).await?;
+
+    lock.release().await?;
+
+    Ok(true)
+}
+
+impl GcLock {
+    /// Tries to acquire the lock, returning `None` if it's held elsewhere.
+    async fn try_acquire(state: &State) -> Result<Option<Self>> {
+        let db = state.database().await?;
+
+        if db.get_database_backend() != DbBackend::Postgres {
+            return Ok(Some(Self { txn: None }));
+        }
+
+        let txn = db.begin().await?;
+        let stmt = Statement::from_sql_and_values(
+            DbBackend::Postgres,
+            "SELECT pg_try_advisory_xact_lock($1)",
+            [GC_ADVISORY_LOCK_KEY.into()],
+        );
+
+        let locked: bool = txn
+            .query_one(stmt)
+            .await?
+            .ok_or_else(|| anyhow!("Advisory lock query returned no rows"))?
+            .try_get_by_index(0)?;
+
+        if locked {
+            Ok(Some(Self { txn: Some(txn) }))
+        } else {
+            txn.rollback().await?;
+            Ok(None)
+        }
+    }
+
+    /// Releases the lock.
+    ///
+    /// Dropping the lock also releases it, but may do so later.
+    async fn release(self) -> Result<()> {
+        if let Some(txn) = self.txn {
+            txn.commit().await?;
+        }
+
+        Ok(())
+    }
+}
+
 #[instrument(skip_all)]
 async fn run_time_based_garbage_collection(state: &State) -> Result<()> {
     let db = state.database().await?;
@@ -97,24 +420,15 @@ async fn run_time_based_garbage_collection(state: &State) -> Result<()> {
             )
         })?;
 
-        let deletion = Object::delete_many()
-            .filter(object::Column::CacheId.eq(cache.id))
-            .filter(object::Column::CreatedAt.lt(cutoff))
-            .filter(
-                object::Column::LastAccessedAt
-                    .is_null()
-                    .or(object::Column::LastAccessedAt.lt(cutoff)),
-            )
-            .exec(db)
-            .await?;
+        let deleted = delete_expired_objects(state, cache.id, &cache.name, cutoff).await?;
 
         tracing::info!(
             "Deleted {} objects from {} (ID {})",
-            deletion.rows_affected,
+            deleted,
             cache.name,
             cache.id
         );
-        objects_deleted += deletion.rows_affected;
+        objects_deleted += deleted;
     }
 
     tracing::info!("Deleted {} objects in total", objects_deleted);
@@ -122,50 +436,391 @@ async fn run_time_based_garbage_collection(state: &State) -> Result<()> {
     Ok(())
 }
 
-#[instrument(skip_all)]
-async fn run_reap_orphan_nars(state: &State) -> Result<()> {
+/// Runs time-based garbage collection on a single cache.
+#[instrument(skip_all, fields(cache = cache.name))]
+pub(crate) async fn run_cache_garbage_collection(
+    state: &State,
+    cache: &CacheModel,
+) -> Result<CacheGcReport> {
     let db = state.database().await?;
 
-    // find all orphan NARs...
-    let orphan_nar_ids = Query::select()
-        .from(Nar)
-        .expr(nar::Column::Id.into_expr())
-        .left_join(
-            Object,
-            object::Column::NarId
-                .into_expr()
-                .eq(nar::Column::Id.into_expr()),
-        )
-        .and_where(object::Column::Id.is_null())
-        .and_where(nar::Column::State.eq(NarState::Valid))
-        .and_where(nar::Column::HoldersCount.eq(0))
-        .lock_with_tables_behavior(LockType::Update, [Nar], LockBehavior::SkipLocked)
+    let Some(cutoff) = cache_retention_cutoff(state, cache, Utc::now())? else {
+        return Ok(CacheGcReport::default());
+    };
+
+    // NARs only referenced by expired objects will be orphaned
+    let expiring_nars = Query::select()
+        .column(object::Column::NarId)
+        .from(Object)
+        .cond_where(expired_objects(cache.id, cutoff))
+        .to_owned();
+    let retained_nars = Query::select()
+        .column(object::Column::NarId)
+        .from(Object)
+        .cond_where(expired_objects(cache.id, cutoff).not())
         .to_owned();
 
-    // ... and simply delete them
-    let deletion = Nar::delete_many()
-        .filter(nar::Column::Id.in_subquery(orphan_nar_ids))
-        .exec(db)
+    let bytes_freed: Option<i64> = Nar::find()
+        .select_only()
+        .column_as(
+            SimpleExpr::from(Func::cast_as(
+                Func::sum(Expr::col(nar::Column::NarSize)),
+                Alias::new("BIGINT"),
+            )),
+            "bytes_freed",
+        )
+        .filter(nar::Column::Id.in_subquery(expiring_nars))
+        .filter(nar::Column::Id.not_in_subquery(retained_nars))
+        .into_tuple()
+        .one(db)
+        .await?
+        .flatten();
+
+    let objects_deleted = delete_expired_objects(state, cache.id, &cache.name, cutoff).await?;
+
+    tracing::info!(
+        "Deleted {} objects from {} (ID {})",
+        objects_deleted,
+        cache.name,
+        cache.id
+    );
+
+    Ok(CacheGcReport {
+        objects_deleted,
+        bytes_freed: bytes_freed.unwrap_or(0).max(0) as u64,
+    })
+}
+
+/// Returns the number of objects time-based garbage collection would delete from a cache.
+pub(crate) async fn count_expired_objects(state: &State, cache: &CacheModel) -> Result<u64> {
+    let db = state.database().await?;
+
+    let Some(cutoff) = cache_retention_cutoff(state, cache, Utc::now())? else {
+        return Ok(0);
+    };
+
+    let count = Object::find()
+        .filter(expired_objects(cache.id, cutoff))
+        .count(db)
+        .await?;
+
+    Ok(count)
+}
+
+/// Returns the cutoff of time-based garbage collection for a cache.
+///
+/// Returns `None` if time-based garbage collection is disabled for the cache.
+fn cache_retention_cutoff(
+    state: &State,
+    cache: &CacheModel,
+    now: DateTime<Utc>,
+) -> Result<Option<DateTime<Utc>>> {
+    let retention_period = match cache.retention_period {
+        Some(period) => i64::from(period),
+        None => state
+            .config
+            .garbage_collection
+            .default_retention_period
+            .as_secs() as i64,
+    };
+
+    if retention_period == 0 {
+        return Ok(None);
+    }
+
+    let cutoff = now
+        .checked_sub_signed(ChronoDuration::seconds(retention_period))
+        .ok_or_else(|| {
+            anyhow!(
+                "Somehow subtracting retention period for cache {} underflowed",
+                cache.name
+            )
+        })?;
+
+    Ok(Some(cutoff))
+}
+
+/// Returns the condition matching objects in a cache not accessed since the cutoff.
+fn expired_objects(cache_id: i64, cutoff: DateTime<Utc>) -> Condition {
+    Condition::all()
+        .add(object::Column::CacheId.eq(cache_id))
+        .add(object::Column::CreatedAt.lt(cutoff))
+        .add(
+            object::Column::LastAccessedAt
+                .is_null()
+                .or(object::Column::LastAccessedAt.lt(cutoff)),
+        )
+}
+
+/// Deletes objects in a cache not accessed since the cutoff.
+///
+/// A deletion event is appended for each object.
+async fn delete_expired_objects(
+    state: &State,
+    cache_id: i64,
+    cache_name: &str,
+    cutoff: DateTime<Utc>,
+) -> Result<u64> {
+    let db = state.database().await?;
+    let cache_name: Option<CacheName> = cache_name.parse().ok();
+    let mut objects_deleted = 0;
+
+    loop {
+        let txn = db.begin().await?;
+
+        // Lock the objects so they can't be accessed or replaced
+        // before the deletion is recorded
+        let expired: Vec<(i64, String)> = Object::find()
+            .select_only()
+            .column(object::Column::Id)
+            .column(object::Column::StorePathHash)
+            .filter(expired_objects(cache_id, cutoff))
+            .limit(EXPIRED_OBJECT_BATCH_SIZE)
+            .lock_exclusive()
+            .into_tuple()
+            .all(&txn)
+            .await?;
+
+        if expired.is_empty() {
+            break;
+        }
+
+        let (ids, store_path_hashes): (Vec<_>, Vec<_>) = expired.into_iter().unzip();
+
+        let deletion = Object::delete_many()
+            .filter(object::Column::Id.is_in(ids))
+            .exec(&txn)
+            .await?;
+        let event_seq =
+            append_events(&txn, cache_id, EventKind::Delete, store_path_hashes.clone()).await?;
+
+        txn.commit().await?;
+        state.cache_events.notify(cache_id, event_seq);
+        if let Some(cache_name) = &cache_name {
+            state
+                .narinfo_cache
+                .invalidate(cache_name, &store_path_hashes);
+        }
+
+        objects_deleted += deletion.rows_affected;
+    }
+
+    Ok(objects_deleted)
+}
+
+#[instrument(skip_all)]
+async fn run_reap_empty_caches(state: &State) -> Result<()> {
+    let config = &state.config.garbage_collection;
+
+    if config.empty_cache_patterns.is_empty() {
+        // disabled
+        return Ok(());
+    }
+
+    let db = state.database().await?;
+    let now = Utc::now();
+    let cutoff = now
+        .checked_sub_signed(ChronoDuration::from_std(config.empty_cache_grace_period)?)
+        .ok_or_else(|| anyhow!("Somehow subtracting the empty cache grace period underflowed"))?;
+
+    let caches = Cache::find()
+        .select_only()
+        .column(cache::Column::Id)
+        .column(cache::Column::Name)
+        .column(cache::Column::CreatedAt)
+        .column(cache::Column::EmptySince)
+        .column_as(
+            Expr::col((Object, object::Column::Id)).count(),
+            "num_objects",
+        )
+        .join(JoinType::LeftJoin, cache::Relation::Object.def())
+        .filter(cache::Column::DeletedAt.is_null())
+        .group_by(cache::Column::Id)
+        .group_by(cache::Column::Name)
+        .group_by(cache::Column::CreatedAt)
+        .group_by(cache::Column::EmptySince)
+        .into_model::<CacheObjectCount>()
+        .all(db)
         .await?;
 
-    tracing::info!("Deleted {} orphan NARs", deletion.rows_affected,);
+    let mut caches_destroyed = 0;
+
+    for cache in caches {
+        let Ok(name) = CacheName::new(cache.name.clone()) else {
+            continue;
+        };
+
+        if !config
+            .empty_cache_patterns
+            .iter()
+            .any(|pattern| pattern.matches(&name))
+        {
+            continue;
+        }
+
+        let action = empty_cache_action(
+            cache.num_objects,
+            cache.created_at,
+            cache.empty_since,
+            cutoff,
+        );
+
+        match action {
+            EmptyCacheAction::Keep => {}
+            EmptyCacheAction::MarkEmpty | EmptyCacheAction::ClearEmpty => {
+                let empty_since = if action == EmptyCacheAction::MarkEmpty {
+                    Some(now)
+                } else {
+                    None
+                };
+
+                Cache::update_many()
+                    .col_expr(cache::Column::EmptySince, Expr::value(empty_since))
+                    .filter(cache::Column::Id.eq(cache.id))
+                    .exec(db)
+                    .await?;
+            }
+            EmptyCacheAction::Destroy => {
+                // Objects may have been uploaded since we counted them
+                let has_objects = Query::select()
+                    .from(Object)
+                    .column(object::Column::CacheId)
+                    .and_where(object::Column::CacheId.eq(cache.id))
+                    .to_owned();
+
+                let rows_affected = if state.config.soft_delete_caches {
+                    Cache::update_many()
+                        .col_expr(cache::Column::DeletedAt, Expr::value(Some(now)))
+                        .filter(cache::Column::Id.eq(cache.id))
+                        .filter(cache::Column::DeletedAt.is_null())
+                        .filter(cache::Column::Id.not_in_subquery(has_objects))
+                        .exec(db)
+                        .await?
+                        .rows_affected
+                } else {
+                    Cache::delete_many()
+                        .filter(cache::Column::Id.eq(cache.id))
+                        .filter(cache::Column::DeletedAt.is_null())
+                        .filter(cache::Column::Id.not_in_subquery(has_objects))
+                        .exec(db)
+                        .await?
+                        .rows_affected
+                };
+
+                if rows_affected != 0 {
+                    tracing::info!(
+                        "Destroyed empty cache {} (ID {}), empty since {}",
+                        cache.name,
+                        cache.id,
+                        cache.empty_since.unwrap(),
+                    );
+                    caches_destroyed += 1;
+
+                    let event =
+                        AuditEvent::internal(AuditAction::DestroyCache, "gc", &name).details(
+                            format!("Empty since {}", cache.empty_since.unwrap().to_rfc3339()),
+                        );
+                    audit::record(state, event).await;
+                }
+            }
+        }
+    }
+
+    tracing::info!("Destroyed {} empty caches", caches_destroyed);
 
     Ok(())
 }
 
+/// Decides what to do with a cache subject to empty cache cleanup.
+///
+/// A cache is only destroyed if it has been seen empty and was
+/// created before the cutoff.
+fn empty_cache_action(
+    num_objects: i64,
+    created_at: DateTime<Utc>,
+    empty_since: Option<DateTime<Utc>>,
+    cutoff: DateTime<Utc>,
+) -> EmptyCacheAction {
+    if num_objects > 0 {
+        return if empty_since.is_some() {
+            EmptyCacheAction::ClearEmpty
+        } else {
+            EmptyCacheAction::Keep
+        };
+    }
+
+    match empty_since {
+        None => EmptyCacheAction::MarkEmpty,
+        Some(since) if since < cutoff && created_at < cutoff => EmptyCacheAction::Destroy,
+        Some(_) => EmptyCacheAction::Keep,
+    }
+}
+
 #[instrument(skip_all)]
-async fn run_reap_orphan_chunks(state: &State) -> Result<()> {
+async fn run_reap_orphan_nars(state: &State) -> Result<()> {
     let db = state.database().await?;
-    let storage = state.storage().await?;
 
-    let orphan_chunk_limit = match db.get_database_backend() {
-        // Arbitrarily chosen sensible value since there's no good default to choose from for MySQL
-        sea_orm::DatabaseBackend::MySql => 1000,
-        // Panic limit set by sqlx for postgresql: https://github.com/launchbadge/sqlx/issues/671#issuecomment-687043510
-        sea_orm::DatabaseBackend::Postgres => u64::from(u16::MAX),
-        // Default statement limit imposed by sqlite: https://www.sqlite.org/limits.html#max_variable_number
-        sea_orm::DatabaseBackend::Sqlite => 500,
-    };
+    let mut nars_deleted = 0;
+    let mut chunks_released = 0;
+
+    loop {
+        let txn = db.begin().await?;
+
+        // find a batch of orphan NARs...
+        let orphan_nar_ids = Query::select()
+            .from(Nar)
+            .expr(nar::Column::Id.into_expr())
+            .left_join(
+                Object,
+                object::Column::NarId
+                    .into_expr()
+                    .eq(nar::Column::Id.into_expr()),
+            )
+            .and_where(object::Column::Id.is_null())
+            .and_where(nar::Column::State.eq(NarState::Valid))
+            .and_where(nar::Column::HoldersCount.eq(0))
+            .limit(ORPHAN_NAR_BATCH_SIZE)
+            .lock_with_tables_behavior(LockType::Update, [Nar], LockBehavior::SkipLocked)
+            .to_owned();
+        let orphan_nar_ids = txn
+            .query_all(txn.get_database_backend().build(&orphan_nar_ids))
+            .await?
+            .into_iter()
+            .map(|row| row.try_get_by_index::<i64>(0))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if orphan_nar_ids.is_empty() {
+            break;
+        }
+
+        // ... and delete them, releasing their chunks
+        nars_deleted += orphan_nar_ids.len();
+        chunks_released += delete_nars(&txn, orphan_nar_ids).await?;
+
+        txn.commit().await?;
+    }
+
+    tracing::info!(
+        "Deleted {} orphan NARs, releasing {} chunks",
+        nars_deleted,
+        chunks_released
+    );
+
+    Ok(())
+}
+
+#[instrument(skip_all)]
+async fn run_reap_old_events(state: &State) -> Result<()> {
+    let events_deleted = reap_old_events(state).await?;
+
+    tracing::info!("Deleted {} old events", events_deleted);
+
+    Ok(())
+}
+
+#[instrument(skip_all)]
+async fn run_reap_orphan_chunks(state: &State) -> Result<()> {
+    let db = state.database().await?;
 
     // find all orphan chunks...
     let orphan_chunk_ids = Query::select()
@@ -197,6 +852,24 @@ async fn run_reap_orphan_chunks(state: &State) -> Result<()> {
 
     db.execute(transition_statement).await?;
 
+    run_delete_chunks(state).await
+}
+
+/// Deletes chunks in the `Deleted` state from the storage backend and the database.
+#[instrument(skip_all)]
+async fn run_delete_chunks(state: &State) -> Result<()> {
+    let db = state.database().await?;
+    let storage = state.storage().await?;
+
+    let orphan_chunk_limit = match db.get_database_backend() {
+        // Arbitrarily chosen sensible value since there's no good default to choose from for MySQL
+        sea_orm::DatabaseBackend::MySql => 1000,
+        // Panic limit set by sqlx for postgresql: https://github.com/launchbadge/sqlx/issues/671#issuecomment-687043510
+        sea_orm::DatabaseBackend::Postgres => u64::from(u16::MAX),
+        // Default statement limit imposed by sqlite: https://www.sqlite.org/limits.html#max_variable_number
+        sea_orm::DatabaseBackend::Sqlite => 500,
+    };
+
     let orphan_chunks: Vec<chunk::Model> = Chunk::find()
         .filter(chunk::Column::State.eq(ChunkState::Deleted))
         .limit(orphan_chunk_limit)
@@ -251,3 +924,756 @@ async fn run_reap_orphan_chunks(state: &State) -> Result<()> {
 
     Ok(())
 }
+
+/// Deletes files in the storage backend that no chunk refers to.
+///
+/// Such files are left behind when the server crashes between writing
+/// a chunk to the storage backend and recording it in the database, or
+/// before a failed upload is cleaned up. Only files named like chunks
+/// are considered.
+#[instrument(skip_all)]
+pub async fn run_storage_gc(config: Config, options: StorageGcOptions) -> Result<StorageGcReport> {
+    let state = StateInner::new(config).await;
+    run_reap_orphan_files(&state, &options).await
+}
+
+async fn run_reap_orphan_files(
+    state: &State,
+    options: &StorageGcOptions,
+) -> Result<StorageGcReport> {
+    let db = state.database().await?;
+    let storage = state.storage().await?;
+
+    let cutoff = Utc::now() - ChronoDuration::from_std(options.min_age)?;
+    let delete_limit = Arc::new(Semaphore::new(20));
+    let mut report = StorageGcReport::default();
+    let mut continuation = None;
+
+    loop {
+        let page = storage.list_files(continuation).await?;
+        report.listed += page.files.len();
+
+        let (candidates, ignored): (Vec<_>, Vec<_>) = page
+            .files
+            .into_iter()
+            .partition(|file| file.name.ends_with(".chunk"));
+        report.ignored += ignored.len();
+
+        for batch in candidates.chunks(STORAGE_GC_LOOKUP_BATCH_SIZE) {
+            let remote_file_ids: Vec<String> =
+                batch.iter().map(|f| f.file.remote_file_id()).collect();
+
+            let referenced: HashSet<String> = Chunk::find()
+                .select_only()
+                .column(chunk::Column::RemoteFileId)
+                .filter(chunk::Column::RemoteFileId.is_in(remote_file_ids.clone()))
+                .into_tuple()
+                .all(db)
+                .await?
+                .into_iter()
+                .collect();
+
+            let mut orphans = Vec::new();
+            for (file, remote_file_id) in batch.iter().zip(&remote_file_ids) {
+                if referenced.contains(remote_file_id) {
+                    report.referenced += 1;
+                } else if file.modified.is_some_and(|modified| modified < cutoff) {
+                    report.orphaned.push(remote_file_id.clone());
+                    report.orphaned_bytes += file.size;
+                    orphans.push(file);
+                } else {
+                    // Possibly an upload in progress
+                    report.recent += 1;
+                }
+            }
+
+            if options.dry_run {
+                continue;
+            }
+
+            let futures = orphans.into_iter().map(|file| {
+                let delete_limit = delete_limit.clone();
+                async move {
+                    let _permit = delete_limit.acquire().await?;
+                    storage.delete_file_db(&file.file).await?;
+                    Result::<_, anyhow::Error>::Ok(())
+                }
+            });
+
+            for result in join_all(futures).await {
+                match result {
+                    Ok(()) => report.deleted += 1,
+                    Err(e) => {
+                        tracing::warn!("Deletion failed: {}", e);
+                        report.failed += 1;
+                    }
+                }
+            }
+        }
+
+        match page.next {
+            Some(next) => continuation = Some(next),
+            None => break,
+        }
+    }
+
+    tracing::info!(
+        "Found {} orphan files, deleted {}",
+        report.orphaned.len(),
+        report.deleted
+    );
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sea_orm::ActiveValue::Set;
+
+    use crate::database::entity::audit_log::Entity as AuditLog;
+    use crate::database::entity::nar::NarState;
+    use crate::database::entity::Json as DbJson;
+    use crate::database::migration::{Migrator, MigratorTrait};
+    use crate::database::{insert_chunkref, reset_holders};
+    use crate::storage::{LocalRemoteFile, RemoteFile};
+    use crate::verify::reconcile_reference_counts;
+
+    async fn make_state(soft_delete_caches: bool) -> State {
+        make_state_at("sqlite::memory:", soft_delete_caches).await
+    }
+
+    async fn make_state_at(database_url: &str, soft_delete_caches: bool) -> State {
+        let storage_path = std::env::temp_dir().join(format!("attic-test-{}", Uuid::new_v4()));
+        let storage_path = storage_path.display();
+
+        let config = format!(
+            r#"
+soft-delete-caches = {soft_delete_caches}
+
+[database]
+url = "{database_url}"
+
+[storage]
+type = "local"
+path = "{storage_path}"
+
+[chunking]
+nar-size-threshold = 0
+min-size = 16384
+avg-size = 65536
+max-size = 262144
+
+[garbage-collection]
+empty-cache-patterns = ["pr-*"]
+empty-cache-grace-period = "1 day"
+
+[audit]
+store-in-database = true
+
+[jwt.signing]
+token-hs256-secret-base64 = "dmVyeSBzZWN1cmUgc2VjcmV0"
+"#
+        );
+
+        let config: Config = toml::from_str(&config).unwrap();
+        let state = StateInner::new(config).await;
+
+        let db = state.database().await.unwrap();
+        Migrator::up(db, None).await.unwrap();
+
+        state
+    }
+
+    async fn insert_cache(
+        state: &State,
+        name: &str,
+        created_at: DateTime<Utc>,
+        empty_since: Option<DateTime<Utc>>,
+    ) -> i64 {
+        let db = state.database().await.unwrap();
+        Cache::insert(cache::ActiveModel {
+            name: Set(name.to_string()),
+            keypair: Set(String::new()),
+            is_public: Set(false),
+            store_dir: Set("/nix/store".to_string()),
+            priority: Set(41),
+            upstream_cache_key_names: Set(DbJson(Vec::new())),
+            created_at: Set(created_at),
+            empty_since: Set(empty_since),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap()
+        .last_insert_id
+    }
+
+    async fn insert_object(state: &State, cache_id: i64) {
+        let db = state.database().await.unwrap();
+        let nar_id = Nar::insert(nar::ActiveModel {
+            state: Set(NarState::Valid),
+            nar_hash: Set(format!("sha256:{}", cache_id)),
+            nar_size: Set(0),
+            compression: Set("none".to_string()),
+            num_chunks: Set(0),
+            completeness_hint: Set(true),
+            holders_count: Set(0),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap()
+        .last_insert_id;
+
+        Object::insert(object::ActiveModel {
+            cache_id: Set(cache_id),
+            nar_id: Set(nar_id),
+            store_path_hash: Set("xcp9cav49dmsjbwdjlmkjxj10gkpx553".to_string()),
+            store_path: Set("/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10".to_string()),
+            references: Set(DbJson(Vec::new())),
+            sigs: Set(DbJson(Vec::new())),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap();
+    }
+
+    async fn find_cache(state: &State, id: i64) -> Option<cache::Model> {
+        let db = state.database().await.unwrap();
+        Cache::find_by_id(id).one(db).await.unwrap()
+    }
+
+    #[test]
+    fn test_empty_cache_action() {
+        let now = Utc::now();
+        let cutoff = now - ChronoDuration::days(1);
+        let old = now - ChronoDuration::days(2);
+
+        assert_eq!(
+            EmptyCacheAction::MarkEmpty,
+            empty_cache_action(0, old, None, cutoff)
+        );
+        assert_eq!(
+            EmptyCacheAction::Destroy,
+            empty_cache_action(0, old, Some(old), cutoff)
+        );
+        assert_eq!(
+            EmptyCacheAction::Keep,
+            empty_cache_action(0, old, Some(now), cutoff)
+        );
+        assert_eq!(
+            EmptyCacheAction::Keep,
+            empty_cache_action(0, now, Some(old), cutoff)
+        );
+        assert_eq!(
+            EmptyCacheAction::Keep,
+            empty_cache_action(1, old, None, cutoff)
+        );
+        assert_eq!(
+            EmptyCacheAction::ClearEmpty,
+            empty_cache_action(1, old, Some(old), cutoff)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reap_empty_caches() {
+        let state = make_state(false).await;
+        let now = Utc::now();
+        let old = now - ChronoDuration::days(30);
+
+        let empty_old = insert_cache(&state, "pr-1", old, Some(old)).await;
+        let empty_unmarked = insert_cache(&state, "pr-2", old, None).await;
+        let empty_new = insert_cache(&state, "pr-3", now, Some(old)).await;
+        let non_empty = insert_cache(&state, "pr-4", old, Some(old)).await;
+        let non_matching = insert_cache(&state, "main", old, Some(old)).await;
+        insert_object(&state, non_empty).await;
+
+        run_reap_empty_caches(&state).await.unwrap();
+
+        assert!(find_cache(&state, empty_old).await.is_none());
+        assert!(find_cache(&state, empty_new).await.is_some());
+        assert!(find_cache(&state, non_matching).await.is_some());
+
+        // Newly-seen empty caches are only marked
+        let cache = find_cache(&state, empty_unmarked).await.unwrap();
+        assert!(cache.empty_since.is_some());
+
+        // Non-empty caches are unmarked
+        let cache = find_cache(&state, non_empty).await.unwrap();
+        assert!(cache.empty_since.is_none());
+
+        // The destruction is attributed to the garbage collector
+        let db = state.database().await.unwrap();
+        let events = AuditLog::find().all(db).await.unwrap();
+        assert_eq!(1, events.len());
+        assert_eq!(AuditAction::DestroyCache, events[0].action);
+        assert_eq!(Some("gc"), events[0].subject.as_deref());
+        assert_eq!("pr-1", events[0].cache);
+    }
+
+    #[tokio::test]
+    async fn test_reap_empty_caches_soft_delete() {
+        let state = make_state(true).await;
+        let old = Utc::now() - ChronoDuration::days(30);
+
+        let empty_old = insert_cache(&state, "pr-1", old, Some(old)).await;
+
+        run_reap_empty_caches(&state).await.unwrap();
+
+        let cache = find_cache(&state, empty_old).await.unwrap();
+        assert!(cache.deleted_at.is_some());
+    }
+
+    async fn insert_chunk(state: &State, holders_count: i32) -> i64 {
+        let db = state.database().await.unwrap();
+        let remote_file_id = Uuid::new_v4().to_string();
+
+        Chunk::insert(chunk::ActiveModel {
+            state: Set(ChunkState::Valid),
+            chunk_hash: Set(format!("sha256:{}", remote_file_id)),
+            chunk_size: Set(100),
+            compression: Set("none".to_string()),
+            remote_file: Set(DbJson(RemoteFile::Local(LocalRemoteFile {
+                name: remote_file_id.clone(),
+                path: None,
+            }))),
+            remote_file_id: Set(remote_file_id),
+            holders_count: Set(holders_count),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap()
+        .last_insert_id
+    }
+
+    #[tokio::test]
+    async fn test_reap_orphan_files() {
+        let state = make_state(false).await;
+        let db = state.database().await.unwrap();
+        let storage = state.storage().await.unwrap();
+
+        let mut files = Vec::new();
+        for name in ["referenced.chunk", "orphan.chunk", "README"] {
+            let file = storage
+                .upload_file(name.to_string(), &mut &b"hello"[..])
+                .await
+                .unwrap();
+            files.push(file);
+        }
+
+        Chunk::insert(chunk::ActiveModel {
+            state: Set(ChunkState::Valid),
+            chunk_hash: Set(format!("sha256:{}", Uuid::new_v4())),
+            chunk_size: Set(5),
+            compression: Set("none".to_string()),
+            remote_file: Set(DbJson(files[0].clone())),
+            remote_file_id: Set(files[0].remote_file_id()),
+            holders_count: Set(0),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap();
+
+        let exists = |i: usize| {
+            let file = files[i].clone();
+            async move { storage.download_file_db(&file, true).await.is_ok() }
+        };
+
+        // Recent files may belong to uploads in progress
+        let mut options = StorageGcOptions {
+            min_age: Duration::from_secs(3600),
+            dry_run: false,
+        };
+        let report = run_reap_orphan_files(&state, &options).await.unwrap();
+        assert_eq!(3, report.listed);
+        assert_eq!(1, report.ignored);
+        assert_eq!(1, report.referenced);
+        assert_eq!(1, report.recent);
+        assert!(report.orphaned.is_empty());
+
+        options.min_age = Duration::ZERO;
+        options.dry_run = true;
+        let report = run_reap_orphan_files(&state, &options).await.unwrap();
+        assert_eq!(vec![files[1].remote_file_id()], report.orphaned);
+        assert_eq!(5, report.orphaned_bytes);
+        assert_eq!(0, report.deleted);
+        assert!(exists(1).await);
+
+        options.dry_run = false;
+        let report = run_reap_orphan_files(&state, &options).await.unwrap();
+        assert_eq!(1, report.deleted);
+        assert_eq!(0, report.failed);
+        assert!(exists(0).await);
+        assert!(!exists(1).await);
+        assert!(exists(2).await);
+    }
+
+    async fn insert_nar(state: &State, chunk_ids: &[i64]) -> i64 {
+        let db = state.database().await.unwrap();
+        let txn = db.begin().await.unwrap();
+
+        let nar_id = Nar::insert(nar::ActiveModel {
+            state: Set(NarState::Valid),
+            nar_hash: Set(format!("sha256:{}", Uuid::new_v4())),
+            nar_size: Set(0),
+            compression: Set("none".to_string()),
+            num_chunks: Set(chunk_ids.len() as i32),
+            completeness_hint: Set(true),
+            holders_count: Set(0),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(&txn)
+        .await
+        .unwrap()
+        .last_insert_id;
+
+        for (seq, chunk_id) in chunk_ids.iter().enumerate() {
+            insert_chunkref(
+                &txn,
+                chunkref::ActiveModel {
+                    nar_id: Set(nar_id),
+                    seq: Set(seq as i32),
+                    chunk_id: Set(Some(*chunk_id)),
+                    chunk_hash: Set(String::new()),
+                    compression: Set("none".to_string()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        }
+
+        txn.commit().await.unwrap();
+        nar_id
+    }
+
+    async fn delete_nar(state: &State, nar_id: i64) -> u64 {
+        let db = state.database().await.unwrap();
+        let txn = db.begin().await.unwrap();
+        let released = delete_nars(&txn, vec![nar_id]).await.unwrap();
+        txn.commit().await.unwrap();
+        released
+    }
+
+    /// Returns the reference count and state of a chunk.
+    async fn find_chunk(state: &State, id: i64) -> (i64, ChunkState) {
+        let db = state.database().await.unwrap();
+        Chunk::find_by_id(id)
+            .select_only()
+            .column(chunk::Column::ReferenceCount)
+            .column(chunk::Column::State)
+            .into_tuple()
+            .one(db)
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_chunk_reference_counting() {
+        let state = make_state(false).await;
+
+        let a = insert_chunk(&state, 0).await;
+        let b = insert_chunk(&state, 0).await;
+        let first = insert_nar(&state, &[a, b, a]).await;
+        let second = insert_nar(&state, &[a]).await;
+
+        assert_eq!(3, find_chunk(&state, a).await.0);
+        assert_eq!(1, find_chunk(&state, b).await.0);
+
+        // b is no longer referenced
+        assert_eq!(1, delete_nar(&state, first).await);
+
+        assert_eq!((1, ChunkState::Valid), find_chunk(&state, a).await);
+
+        assert_eq!((0, ChunkState::Deleted), find_chunk(&state, b).await);
+
+        assert_eq!(1, delete_nar(&state, second).await);
+
+        assert_eq!((0, ChunkState::Deleted), find_chunk(&state, a).await);
+    }
+
+    #[tokio::test]
+    async fn test_chunk_reference_counting_held() {
+        let state = make_state(false).await;
+
+        // An upload is about to deduplicate against the chunk
+        let held = insert_chunk(&state, 1).await;
+        let nar_id = insert_nar(&state, &[held]).await;
+
+        assert_eq!(0, delete_nar(&state, nar_id).await);
+
+        assert_eq!((0, ChunkState::Valid), find_chunk(&state, held).await);
+    }
+
+    #[tokio::test]
+    async fn test_reap_orphan_nars_releases_chunks() {
+        let state = make_state(false).await;
+        let db = state.database().await.unwrap();
+        let cache_id = insert_cache(&state, "demo", Utc::now(), None).await;
+
+        let shared = insert_chunk(&state, 0).await;
+        let orphaned = insert_nar(&state, &[shared]).await;
+        let retained = insert_nar(&state, &[shared]).await;
+
+        for (i, nar_id) in [orphaned, retained].into_iter().enumerate() {
+            Object::insert(object::ActiveModel {
+                cache_id: Set(cache_id),
+                nar_id: Set(nar_id),
+                store_path_hash: Set(format!("{:0>32}", i)),
+                store_path: Set(format!("/nix/store/{:0>32}-test", i)),
+                references: Set(DbJson(Vec::new())),
+                sigs: Set(DbJson(Vec::new())),
+                created_at: Set(Utc::now()),
+                ..Default::default()
+            })
+            .exec(db)
+            .await
+            .unwrap();
+        }
+
+        Object::delete_many()
+            .filter(object::Column::NarId.eq(orphaned))
+            .exec(db)
+            .await
+            .unwrap();
+
+        run_reap_orphan_nars(&state).await.unwrap();
+
+        assert!(Nar::find_by_id(orphaned).one(db).await.unwrap().is_none());
+        assert_eq!((1, ChunkState::Valid), find_chunk(&state, shared).await);
+
+        Object::delete_many().exec(db).await.unwrap();
+        run_reap_orphan_nars(&state).await.unwrap();
+
+        assert_eq!((0, ChunkState::Deleted), find_chunk(&state, shared).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_chunk_reference_counting_concurrent() {
+        let state = make_state(false).await;
+        let shared = insert_chunk(&state, 0).await;
+
+        // Keep one reference around so the chunk stays valid
+        insert_nar(&state, &[shared]).await;
+
+        let tasks: Vec<_> = (0..32)
+            .map(|i| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    let nar_id = insert_nar(&state, &[shared, shared]).await;
+                    if i % 2 == 0 {
+                        delete_nar(&state, nar_id).await;
+                    }
+                })
+            })
+            .collect();
+
+        for task in join_all(tasks).await {
+            task.unwrap();
+        }
+
+        let db = state.database().await.unwrap();
+        let actual = ChunkRef::find()
+            .filter(chunkref::Column::ChunkId.eq(shared))
+            .count(db)
+            .await
+            .unwrap();
+
+        assert_eq!(1 + 16 * 2, actual);
+        assert_eq!(
+            (actual as i64, ChunkState::Valid),
+            find_chunk(&state, shared).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reset_holders() {
+        let state = make_state(false).await;
+        let db = state.database().await.unwrap();
+
+        let held = insert_chunk(&state, 2).await;
+        let released = insert_chunk(&state, 0).await;
+        let nar_id = insert_nar(&state, &[held]).await;
+
+        Nar::update_many()
+            .col_expr(nar::Column::HoldersCount, Expr::value(1))
+            .filter(nar::Column::Id.eq(nar_id))
+            .exec(db)
+            .await
+            .unwrap();
+
+        assert_eq!((1, 1), reset_holders(db).await.unwrap());
+        assert_eq!((0, 0), reset_holders(db).await.unwrap());
+
+        let holders: Vec<i32> = Chunk::find()
+            .select_only()
+            .column(chunk::Column::HoldersCount)
+            .filter(chunk::Column::Id.is_in([held, released]))
+            .into_tuple()
+            .all(db)
+            .await
+            .unwrap();
+        assert_eq!(vec![0, 0], holders);
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_reference_counts() {
+        let state = make_state(false).await;
+        let db = state.database().await.unwrap();
+
+        let correct = insert_chunk(&state, 0).await;
+        let drifted = insert_chunk(&state, 0).await;
+        insert_nar(&state, &[correct, drifted, drifted]).await;
+
+        Chunk::update_many()
+            .col_expr(chunk::Column::ReferenceCount, Expr::value(5))
+            .filter(chunk::Column::Id.eq(drifted))
+            .exec(db)
+            .await
+            .unwrap();
+
+        let miscounted = reconcile_reference_counts(&state, true).await.unwrap();
+        assert_eq!(vec![drifted], miscounted);
+        assert_eq!(5, find_chunk(&state, drifted).await.0);
+
+        let miscounted = reconcile_reference_counts(&state, false).await.unwrap();
+        assert_eq!(vec![drifted], miscounted);
+        assert_eq!(2, find_chunk(&state, drifted).await.0);
+
+        assert!(reconcile_reference_counts(&state, false)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_gc_lock_sqlite() {
+        let state = make_state(false).await;
+
+        // Nothing to lock with a single writer
+        let lock = GcLock::try_acquire(&state).await.unwrap().unwrap();
+        assert!(run_garbage_collection_locked(&state).await.unwrap());
+        lock.release().await.unwrap();
+    }
+
+    /// Tests concurrent garbage collection against a real PostgreSQL database.
+    ///
+    /// Set `ATTIC_TEST_POSTGRES_URL` to a scratch database to run this.
+    #[tokio::test]
+    async fn test_gc_lock_postgres() {
+        let Ok(url) = std::env::var("ATTIC_TEST_POSTGRES_URL") else {
+            eprintln!("ATTIC_TEST_POSTGRES_URL is not set, skipping");
+            return;
+        };
+
+        // Two instances sharing the database
+        let first = make_state_at(&url, false).await;
+        let second = make_state_at(&url, false).await;
+
+        let lock = GcLock::try_acquire(&first).await.unwrap().unwrap();
+        assert!(GcLock::try_acquire(&second).await.unwrap().is_none());
+        assert!(!run_garbage_collection_locked(&second).await.unwrap());
+
+        lock.release().await.unwrap();
+        assert!(run_garbage_collection_locked(&second).await.unwrap());
+
+        // Concurrent runs either run one after another or get skipped
+        let name = format!("gc-lock-{}", Uuid::new_v4().simple());
+        let cache_id = insert_cache(&first, &name, Utc::now(), None).await;
+        insert_object(&first, cache_id).await;
+
+        let (a, b) = join!(
+            run_garbage_collection_locked(&first),
+            run_garbage_collection_locked(&second),
+        );
+        assert!(a.unwrap() || b.unwrap());
+
+        // A lock that's dropped without being released is also freed
+        let lock = GcLock::try_acquire(&first).await.unwrap().unwrap();
+        drop(lock);
+        time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Some(lock) = GcLock::try_acquire(&second).await.unwrap() {
+                    lock.release().await.unwrap();
+                    break;
+                }
+                time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("Dropped lock was not freed");
+
+        let db = first.database().await.unwrap();
+        Cache::delete_by_id(cache_id).exec(db).await.unwrap();
+    }
+
+    #[test]
+    fn test_cache_gc_jobs() {
+        let jobs = CacheGcJobs::default();
+        let cooldown = Duration::from_secs(600);
+        let now = Instant::now();
+
+        let id = jobs.start(1, cooldown, now).unwrap();
+        assert_eq!(Some(CacheGcStatus::Running), jobs.get(1, &id));
+
+        // Jobs are scoped to their caches
+        assert_eq!(None, jobs.get(2, &id));
+        assert_eq!(None, jobs.get(1, "nonexistent"));
+
+        // Rate-limited per cache
+        assert_eq!(
+            Err(Duration::from_secs(540)),
+            jobs.start(1, cooldown, now + Duration::from_secs(60))
+        );
+        let other_id = jobs.start(2, cooldown, now).unwrap();
+        assert_ne!(id, other_id);
+
+        let report = CacheGcReport {
+            objects_deleted: 2,
+            bytes_freed: 2000,
+        };
+        let status = jobs.finish(&id, &Ok(report), now + Duration::from_secs(1));
+        assert_eq!(
+            CacheGcStatus::Completed {
+                objects_deleted: 2,
+                bytes_freed: 2000,
+            },
+            status
+        );
+        assert_eq!(Some(status), jobs.get(1, &id));
+
+        jobs.finish(
+            &other_id,
+            &Err(anyhow!("Database is on fire")),
+            now + Duration::from_secs(1),
+        );
+        assert_eq!(
+            Some(CacheGcStatus::Failed {
+                error: "Database is on fire".to_string(),
+            }),
+            jobs.get(2, &other_id)
+        );
+
+        // Finishing doesn't lift the rate limit
+        assert!(jobs
+            .start(1, cooldown, now + Duration::from_secs(120))
+            .is_err());
+
+        // Finished jobs are eventually forgotten
+        let later = now + CACHE_GC_JOB_RETENTION + Duration::from_secs(10);
+        let new_id = jobs.start(1, cooldown, later).unwrap();
+        assert_eq!(None, jobs.get(1, &id));
+        assert_eq!(Some(CacheGcStatus::Running), jobs.get(1, &new_id));
+    }
+}
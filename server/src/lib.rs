@@ -15,19 +15,27 @@
 
 pub mod access;
 mod api;
+pub mod audit;
+pub mod compaction;
 pub mod config;
 pub mod database;
 pub mod error;
+mod events;
 pub mod gc;
+mod limits;
 mod middleware;
 mod narinfo;
+mod narinfo_cache;
 pub mod nix_manifest;
 pub mod oobe;
 mod storage;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+pub mod verify;
 
 use std::future::IntoFuture;
 use std::net::SocketAddr;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -37,7 +45,7 @@ use axum::{
     http::{uri::Scheme, Uri},
     Router,
 };
-use sea_orm::{query::Statement, ConnectionTrait, Database, DatabaseConnection};
+use sea_orm::{query::Statement, ConnectOptions, ConnectionTrait, Database, DatabaseConnection};
 use tokio::net::TcpListener;
 use tokio::sync::OnceCell;
 use tokio::time;
@@ -46,15 +54,25 @@ use tower_http::trace::TraceLayer;
 
 use access::http::{apply_auth, AuthState};
 use attic::cache::CacheName;
-use config::{Config, StorageConfig};
+use config::{Config, DatabaseConfig, StorageConfig};
 use database::migration::{Migrator, MigratorTrait};
 use error::{ErrorKind, ServerError, ServerResult};
-use middleware::{init_request_state, restrict_host, set_visibility_header};
-use storage::{LocalBackend, S3Backend, StorageBackend};
+use events::CacheEventNotifier;
+use gc::CacheGcJobs;
+use limits::{ClientLimiter, UploadLimiter};
+use middleware::{
+    init_request_state, limit_unauthenticated, make_cors_layer, restrict_host,
+    set_visibility_header,
+};
+use narinfo_cache::NarInfoCache;
+use storage::{LocalBackend, S3Backend, StorageBackend, WebDavBackend};
 
 type State = Arc<StateInner>;
 type RequestState = Arc<RequestStateInner>;
 
+/// Timeout for connecting to the database in `check_database`.
+const CHECK_DATABASE_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// Global server state.
 #[derive(Debug)]
 pub struct StateInner {
@@ -66,6 +84,25 @@ pub struct StateInner {
 
     /// Handle to the storage backend.
     storage: OnceCell<Arc<Box<dyn StorageBackend>>>,
+
+    /// Garbage collection jobs requested through the API.
+    cache_gc_jobs: CacheGcJobs,
+
+    /// Notifier for requests waiting for cache events.
+    cache_events: CacheEventNotifier,
+
+    /// Upload rate limits.
+    upload_limiter: UploadLimiter,
+
+    /// Per-IP limits on requests without a valid token.
+    client_limiter: ClientLimiter,
+
+    /// Cache of rendered narinfos.
+    narinfo_cache: NarInfoCache,
+
+    /// Number of requests received, for background work to back off
+    /// while the server is busy.
+    request_count: AtomicU64,
 }
 
 /// Request state.
@@ -96,9 +133,15 @@ struct RequestStateInner {
 impl StateInner {
     async fn new(config: Config) -> State {
         Arc::new(Self {
+            upload_limiter: UploadLimiter::new(&config.limits),
+            client_limiter: ClientLimiter::new(&config.limits),
+            narinfo_cache: NarInfoCache::new(&config.narinfo_cache),
             config,
             database: OnceCell::new(),
             storage: OnceCell::new(),
+            cache_gc_jobs: CacheGcJobs::default(),
+            cache_events: CacheEventNotifier::default(),
+            request_count: AtomicU64::new(0),
         })
     }
 
@@ -106,7 +149,7 @@ impl StateInner {
     async fn database(&self) -> ServerResult<&DatabaseConnection> {
         self.database
             .get_or_try_init(|| async {
-                let db = Database::connect(&self.config.database.url)
+                let db = Database::connect(connect_options(&self.config.database))
                     .await
                     .map_err(ServerError::database_error);
                 if let Ok(DatabaseConnection::SqlxSqlitePoolConnection(ref conn)) = db {
@@ -147,6 +190,11 @@ impl StateInner {
                         let boxed: Box<dyn StorageBackend> = Box::new(s3);
                         Ok(Arc::new(boxed))
                     }
+                    StorageConfig::WebDav(webdav_config) => {
+                        let webdav = WebDavBackend::new(webdav_config.clone()).await?;
+                        let boxed: Box<dyn StorageBackend> = Box::new(webdav);
+                        Ok(Arc::new(boxed))
+                    }
                 }
             })
             .await
@@ -219,48 +267,165 @@ async fn fallback(_: Uri) -> ServerResult<()> {
 pub async fn run_api_server(cli_listen: Option<SocketAddr>, config: Config) -> Result<()> {
     eprintln!("Starting API server...");
 
-    let state = StateInner::new(config).await;
-
     let listen = if let Some(cli_listen) = cli_listen {
         cli_listen
     } else {
-        state.config.listen.to_owned()
+        config.listen.to_owned()
     };
 
-    let rest = Router::new()
-        .merge(api::get_router())
+    eprintln!("Listening on {:?}...", listen);
+
+    let listener = TcpListener::bind(&listen).await?;
+
+    serve_api(listener, config).await
+}
+
+/// Serves the API on an already-bound listener.
+///
+/// This is useful for running the server in-process, e.g.,
+/// in tests on an ephemeral port.
+pub async fn serve_api(listener: TcpListener, config: Config) -> Result<()> {
+    let state = StateInner::new(config).await;
+    serve_state(listener, state).await
+}
+
+/// Serves the API with an existing state.
+///
+/// In-memory SQLite databases live as long as the connection pool in
+/// the state, so callers that need to set up the database first must
+/// share the state with the server.
+async fn serve_state(listener: TcpListener, state: State) -> Result<()> {
+    let rest = make_router(state.clone());
+
+    let (server_ret, _, _) = tokio::join!(
+        axum::serve(
+            listener,
+            rest.into_make_service_with_connect_info::<SocketAddr>()
+        )
+        .into_future(),
+        async {
+            if state.config.database.heartbeat {
+                let _ = state.run_db_heartbeat().await;
+            }
+        },
+        compaction::run_compaction(state.clone()),
+    );
+
+    server_ret?;
+
+    Ok(())
+}
+
+/// Returns the database connection options.
+fn connect_options(config: &DatabaseConfig) -> ConnectOptions {
+    let mut options = ConnectOptions::new(&config.url);
+
+    if let Some(max_connections) = config.max_connections {
+        options.max_connections(max_connections);
+    }
+    if let Some(min_connections) = config.min_connections {
+        options.min_connections(min_connections);
+    }
+    if let Some(connect_timeout) = config.connect_timeout {
+        options.connect_timeout(connect_timeout);
+    }
+    if let Some(acquire_timeout) = config.acquire_timeout {
+        options.acquire_timeout(acquire_timeout);
+    }
+    if let Some(idle_timeout) = config.idle_timeout {
+        options.idle_timeout(idle_timeout);
+    }
+
+    options
+}
+
+/// Describes an optional pool setting.
+fn describe<T: std::fmt::Debug>(value: Option<T>) -> String {
+    value.map_or_else(|| "default".to_string(), |v| format!("{:?}", v))
+}
+
+/// Returns the API router with all middlewares applied.
+fn make_router(state: State) -> Router {
+    let cors = make_cors_layer(&state.config.cors);
+
+    Router::new()
+        .merge(api::get_router(cors))
         .fallback(fallback)
         // middlewares
         .layer(axum::middleware::from_fn(apply_auth))
+        .layer(axum::middleware::from_fn(limit_unauthenticated))
         .layer(axum::middleware::from_fn(set_visibility_header))
         .layer(axum::middleware::from_fn(init_request_state))
         .layer(axum::middleware::from_fn(restrict_host))
-        .layer(Extension(state.clone()))
+        .layer(Extension(state))
         .layer(TraceLayer::new_for_http())
-        .layer(CatchPanicLayer::new());
+        .layer(CatchPanicLayer::new())
+}
 
-    eprintln!("Listening on {:?}...", listen);
+/// Runs database migrations.
+pub async fn run_migrations(config: Config) -> Result<()> {
+    eprintln!("Running migrations...");
 
-    let listener = TcpListener::bind(&listen).await?;
+    let state = StateInner::new(config).await;
+    let db = state.database().await?;
+    Migrator::up(db, None).await?;
 
-    let (server_ret, _) = tokio::join!(axum::serve(listener, rest).into_future(), async {
-        if state.config.database.heartbeat {
-            let _ = state.run_db_heartbeat().await;
-        }
-    },);
+    Ok(())
+}
 
-    server_ret?;
+/// Checks that the database is reachable.
+///
+/// This connects with a short timeout and reports the pool settings,
+/// so bad credentials are caught before the first real request.
+pub async fn check_database(mut config: Config) -> Result<()> {
+    let options = connect_options(&config.database);
+    eprintln!(
+        "Database pool: max-connections = {}, min-connections = {}, \
+        connect-timeout = {}, acquire-timeout = {}, idle-timeout = {}",
+        describe(options.get_max_connections()),
+        describe(options.get_min_connections()),
+        describe(options.get_connect_timeout()),
+        describe(options.get_acquire_timeout()),
+        describe(options.get_idle_timeout()),
+    );
+
+    let timeout = |configured: Option<Duration>| {
+        Some(configured.map_or(CHECK_DATABASE_TIMEOUT, |t| t.min(CHECK_DATABASE_TIMEOUT)))
+    };
+    config.database.connect_timeout = timeout(config.database.connect_timeout);
+    config.database.acquire_timeout = timeout(config.database.acquire_timeout);
+
+    let state = StateInner::new(config).await;
+    let db = state.database().await?;
+    db.ping().await?;
+
+    eprintln!("Database connection OK");
 
     Ok(())
 }
 
-/// Runs database migrations.
-pub async fn run_migrations(config: Config) -> Result<()> {
-    eprintln!("Running migrations...");
+/// Releases holds left behind by a previous server process.
+///
+/// This must run before the API server starts accepting requests,
+/// since no guards can be live at that point. It's skipped if
+/// `database.reset-holders-on-startup` is disabled, because another
+/// API server sharing the database may have uploads in progress.
+pub async fn reset_stale_holders(config: Config) -> Result<()> {
+    if !config.database.reset_holders_on_startup {
+        return Ok(());
+    }
 
     let state = StateInner::new(config).await;
     let db = state.database().await?;
-    Migrator::up(db, None).await?;
+    let (nars, chunks) = database::reset_holders(db).await?;
+
+    if nars != 0 || chunks != 0 {
+        tracing::warn!(
+            "Released stale holds on {} NARs and {} chunks",
+            nars,
+            chunks
+        );
+    }
 
     Ok(())
 }
@@ -0,0 +1,488 @@
+//! Rate limiting.
+//!
+//! Uploads are limited with token buckets keyed by the cache name
+//! and optionally the token subject. Requests without a valid token
+//! are limited with token buckets keyed by the client IP. Limits are
+//! tracked per server process, so the effective limits are multiplied
+//! by the number of API servers.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::LimitsConfig;
+use attic::cache::CacheName;
+
+/// How often full buckets are forgotten.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Key of an upload token bucket.
+type UploadKey = (CacheName, Option<String>);
+
+/// Upload rate limits.
+#[derive(Debug)]
+pub(crate) struct UploadLimiter {
+    /// Limit on the number of upload requests.
+    requests: Option<RateLimiter<UploadKey>>,
+
+    /// Limit on the number of uploaded bytes.
+    bytes: Option<RateLimiter<UploadKey>>,
+
+    /// Whether to limit each token subject separately.
+    per_subject: bool,
+}
+
+/// Per-IP limits on requests without a valid token.
+#[derive(Debug)]
+pub(crate) struct ClientLimiter {
+    /// Limit on the number of requests without a token.
+    anonymous: Option<RateLimiter<IpAddr>>,
+
+    /// Limit on the number of tokens failing verification.
+    auth_failures: Option<RateLimiter<IpAddr>>,
+}
+
+/// A token bucket rate limiter.
+#[derive(Debug)]
+struct RateLimiter<K> {
+    /// Amount added to each bucket per second.
+    rate: f64,
+
+    /// Capacity of each bucket.
+    burst: f64,
+
+    buckets: Mutex<Buckets<K>>,
+}
+
+#[derive(Debug)]
+struct Buckets<K> {
+    buckets: HashMap<K, Bucket>,
+
+    /// When full buckets were last forgotten.
+    cleaned_at: Instant,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    /// Amount left in the bucket.
+    ///
+    /// This becomes negative if a request larger than the bucket
+    /// was let through.
+    level: f64,
+
+    updated_at: Instant,
+}
+
+impl UploadLimiter {
+    pub fn new(config: &LimitsConfig) -> Self {
+        let requests = config.upload_requests_per_second.map(|rate| {
+            let burst = config
+                .upload_request_burst
+                .unwrap_or(rate.ceil().max(1.0) as u64);
+            RateLimiter::new(rate, burst as f64)
+        });
+
+        let bytes = config.upload_bytes_per_second.map(|rate| {
+            let burst = config.upload_byte_burst.unwrap_or(rate);
+            RateLimiter::new(rate as f64, burst as f64)
+        });
+
+        Self {
+            requests,
+            bytes,
+            per_subject: config.per_subject,
+        }
+    }
+
+    /// Accounts for an upload of `nar_size` bytes to a cache.
+    ///
+    /// If the upload exceeds the limits, returns how long to wait
+    /// before trying again.
+    pub fn check_upload(
+        &self,
+        cache: &CacheName,
+        subject: Option<&str>,
+        nar_size: usize,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let subject = if self.per_subject {
+            subject.map(str::to_string)
+        } else {
+            None
+        };
+        let key = (cache.to_owned(), subject);
+
+        if let Some(requests) = &self.requests {
+            requests.take(&key, 1.0, now)?;
+        }
+
+        if let Some(bytes) = &self.bytes {
+            if let Err(retry_after) = bytes.take(&key, nar_size as f64, now) {
+                // Don't count requests that weren't let through
+                if let Some(requests) = &self.requests {
+                    requests.give_back(&key, 1.0);
+                }
+
+                return Err(retry_after);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ClientLimiter {
+    pub fn new(config: &LimitsConfig) -> Self {
+        let per_ip_limiter = |rate: Option<f64>, burst: Option<u64>| {
+            rate.map(|rate| {
+                let burst = burst.unwrap_or(rate.ceil().max(1.0) as u64);
+                RateLimiter::new(rate, burst as f64)
+            })
+        };
+
+        Self {
+            anonymous: per_ip_limiter(
+                config.anonymous_requests_per_second,
+                config.anonymous_request_burst,
+            ),
+            auth_failures: per_ip_limiter(
+                config.auth_failures_per_second,
+                config.auth_failure_burst,
+            ),
+        }
+    }
+
+    /// Accounts for a request without a token.
+    ///
+    /// If the client exceeds the limit, returns how long to wait
+    /// before trying again.
+    pub fn check_anonymous(&self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        match &self.anonymous {
+            Some(anonymous) => anonymous.take(&client, 1.0, now),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks whether a client may attempt to authenticate.
+    ///
+    /// This doesn't count as a failure. If the client has failed too
+    /// many times, returns how long to wait before trying again.
+    pub fn check_auth_attempt(&self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        match &self.auth_failures {
+            Some(auth_failures) => auth_failures.check(&client, 1.0, now),
+            None => Ok(()),
+        }
+    }
+
+    /// Records a token failing verification.
+    pub fn record_auth_failure(&self, client: IpAddr, now: Instant) {
+        if let Some(auth_failures) = &self.auth_failures {
+            // Attempts are checked beforehand, so this can only exceed
+            // the limit with concurrent requests
+            let _ = auth_failures.take(&client, 1.0, now);
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash> RateLimiter<K> {
+    fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                cleaned_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes an amount from the bucket of a key.
+    ///
+    /// Amounts larger than the capacity are let through once the bucket
+    /// is full, leaving the bucket in debt. If there isn't enough left,
+    /// returns how long to wait before trying again.
+    fn take(&self, key: &K, amount: f64, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        // Forget about full buckets
+        if now.saturating_duration_since(buckets.cleaned_at) >= CLEANUP_INTERVAL {
+            buckets
+                .buckets
+                .retain(|_, bucket| self.refill(bucket, now) < self.burst);
+            buckets.cleaned_at = now;
+        }
+
+        let bucket = buckets.buckets.entry(key.to_owned()).or_insert(Bucket {
+            level: self.burst,
+            updated_at: now,
+        });
+
+        bucket.level = self.refill(bucket, now);
+        bucket.updated_at = now;
+
+        self.wait_for(bucket.level, amount)?;
+        bucket.level -= amount;
+
+        Ok(())
+    }
+
+    /// Checks whether an amount could be taken from the bucket of a key.
+    fn check(&self, key: &K, amount: f64, now: Instant) -> Result<(), Duration> {
+        let buckets = self.buckets.lock().unwrap();
+
+        let level = buckets
+            .buckets
+            .get(key)
+            .map_or(self.burst, |bucket| self.refill(bucket, now));
+
+        self.wait_for(level, amount)
+    }
+
+    /// Returns how long to wait for an amount to be available, if at all.
+    fn wait_for(&self, level: f64, amount: f64) -> Result<(), Duration> {
+        let required = amount.min(self.burst);
+        if level < required {
+            let wait = (required - level) / self.rate;
+            return Err(Duration::from_secs_f64(wait));
+        }
+
+        Ok(())
+    }
+
+    /// Returns an amount to the bucket of a key.
+    fn give_back(&self, key: &K, amount: f64) {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if let Some(bucket) = buckets.buckets.get_mut(key) {
+            bucket.level = (bucket.level + amount).min(self.burst);
+        }
+    }
+
+    /// Returns the level of a bucket at a point in time.
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated_at);
+        (bucket.level + elapsed.as_secs_f64() * self.rate).min(self.burst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(name: &str) -> CacheName {
+        CacheName::new(name.to_string()).unwrap()
+    }
+
+    fn limiter(config: &str) -> UploadLimiter {
+        let config: LimitsConfig = toml::from_str(config).unwrap();
+        UploadLimiter::new(&config)
+    }
+
+    fn retry_after(result: Result<(), Duration>) -> Option<u64> {
+        result.err().map(|d| d.as_secs_f64().ceil() as u64)
+    }
+
+    #[test]
+    fn test_request_limit() {
+        let limiter = limiter(
+            r#"
+            upload-requests-per-second = 0.5
+            upload-request-burst = 2
+            "#,
+        );
+        let now = Instant::now();
+        let main = cache("main");
+
+        assert_eq!(None, retry_after(limiter.check_upload(&main, None, 1, now)));
+        assert_eq!(None, retry_after(limiter.check_upload(&main, None, 1, now)));
+        assert_eq!(
+            Some(2),
+            retry_after(limiter.check_upload(&main, None, 1, now))
+        );
+
+        // Caches are limited separately
+        assert_eq!(
+            None,
+            retry_after(limiter.check_upload(&cache("other"), None, 1, now))
+        );
+
+        // Subjects share the limit by default
+        assert!(retry_after(limiter.check_upload(&main, Some("ci"), 1, now)).is_some());
+
+        // Refilled over time
+        let later = now + Duration::from_secs(2);
+        assert_eq!(
+            None,
+            retry_after(limiter.check_upload(&main, None, 1, later))
+        );
+        assert!(retry_after(limiter.check_upload(&main, None, 1, later)).is_some());
+    }
+
+    #[test]
+    fn test_byte_limit() {
+        let limiter = limiter(
+            r#"
+            upload-bytes-per-second = 1000
+            "#,
+        );
+        let now = Instant::now();
+        let main = cache("main");
+
+        assert_eq!(
+            None,
+            retry_after(limiter.check_upload(&main, None, 600, now))
+        );
+        assert_eq!(
+            Some(1),
+            retry_after(limiter.check_upload(&main, None, 600, now))
+        );
+
+        // Uploads larger than the burst go through once the bucket is full
+        let later = now + Duration::from_secs(1);
+        assert_eq!(
+            None,
+            retry_after(limiter.check_upload(&main, None, 5000, later))
+        );
+        assert_eq!(
+            Some(5),
+            retry_after(limiter.check_upload(&main, None, 1, later))
+        );
+    }
+
+    #[test]
+    fn test_per_subject() {
+        let limiter = limiter(
+            r#"
+            upload-requests-per-second = 1
+            per-subject = true
+            "#,
+        );
+        let now = Instant::now();
+        let main = cache("main");
+
+        assert_eq!(
+            None,
+            retry_after(limiter.check_upload(&main, Some("alice"), 1, now))
+        );
+        assert!(retry_after(limiter.check_upload(&main, Some("alice"), 1, now)).is_some());
+        assert_eq!(
+            None,
+            retry_after(limiter.check_upload(&main, Some("bob"), 1, now))
+        );
+        assert_eq!(None, retry_after(limiter.check_upload(&main, None, 1, now)));
+    }
+
+    fn client_limiter(config: &str) -> ClientLimiter {
+        let config: LimitsConfig = toml::from_str(config).unwrap();
+        ClientLimiter::new(&config)
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_anonymous_limit() {
+        let limiter = client_limiter(
+            r#"
+            anonymous-requests-per-second = 2
+            anonymous-request-burst = 3
+            "#,
+        );
+        let now = Instant::now();
+        let client = ip("192.0.2.1");
+
+        // Burst
+        for _ in 0..3 {
+            assert_eq!(None, retry_after(limiter.check_anonymous(client, now)));
+        }
+        assert_eq!(Some(1), retry_after(limiter.check_anonymous(client, now)));
+
+        // Clients are limited separately
+        assert_eq!(
+            None,
+            retry_after(limiter.check_anonymous(ip("2001:db8::1"), now))
+        );
+
+        // Recovery at the sustained rate
+        let later = now + Duration::from_millis(500);
+        assert_eq!(None, retry_after(limiter.check_anonymous(client, later)));
+        assert!(retry_after(limiter.check_anonymous(client, later)).is_some());
+
+        let much_later = now + Duration::from_secs(10);
+        for _ in 0..3 {
+            assert_eq!(
+                None,
+                retry_after(limiter.check_anonymous(client, much_later))
+            );
+        }
+        assert!(retry_after(limiter.check_anonymous(client, much_later)).is_some());
+    }
+
+    #[test]
+    fn test_auth_failure_limit() {
+        let limiter = client_limiter(
+            r#"
+            auth-failures-per-second = 0.1
+            auth-failure-burst = 2
+            "#,
+        );
+        let now = Instant::now();
+        let client = ip("192.0.2.1");
+
+        // Checking alone doesn't count
+        for _ in 0..10 {
+            assert_eq!(None, retry_after(limiter.check_auth_attempt(client, now)));
+        }
+
+        limiter.record_auth_failure(client, now);
+        assert_eq!(None, retry_after(limiter.check_auth_attempt(client, now)));
+        limiter.record_auth_failure(client, now);
+        assert_eq!(
+            Some(10),
+            retry_after(limiter.check_auth_attempt(client, now))
+        );
+
+        // Anonymous requests are unlimited
+        assert_eq!(None, retry_after(limiter.check_anonymous(client, now)));
+
+        let later = now + Duration::from_secs(10);
+        assert_eq!(None, retry_after(limiter.check_auth_attempt(client, later)));
+    }
+
+    #[test]
+    fn test_cleanup() {
+        let limiter = RateLimiter::new(1.0, 1.0);
+        let now = Instant::now();
+
+        limiter.take(&ip("192.0.2.1"), 1.0, now).unwrap();
+        limiter.take(&ip("192.0.2.2"), 1.0, now).unwrap();
+        assert_eq!(2, limiter.buckets.lock().unwrap().buckets.len());
+
+        // Full buckets are forgotten periodically
+        let later = now + CLEANUP_INTERVAL;
+        limiter.take(&ip("192.0.2.3"), 1.0, later).unwrap();
+        assert_eq!(1, limiter.buckets.lock().unwrap().buckets.len());
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let limiter = limiter("");
+        let now = Instant::now();
+
+        for _ in 0..100 {
+            limiter
+                .check_upload(&cache("main"), None, usize::MAX, now)
+                .unwrap();
+        }
+
+        let limiter = client_limiter("");
+        for _ in 0..100 {
+            limiter.check_anonymous(ip("192.0.2.1"), now).unwrap();
+            limiter.record_auth_failure(ip("192.0.2.1"), now);
+            limiter.check_auth_attempt(ip("192.0.2.1"), now).unwrap();
+        }
+    }
+}
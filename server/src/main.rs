@@ -11,6 +11,7 @@ use tracing_subscriber::prelude::*;
 use tracing_subscriber::EnvFilter;
 
 use attic_server::config;
+use attic_server::oobe::OobeOptions;
 
 /// Nix binary cache server.
 #[derive(Debug, Parser)]
@@ -31,6 +32,21 @@ struct Opts {
     #[clap(long, default_value = "monolithic")]
     mode: ServerMode,
 
+    /// Directory to store the database and NARs in during initial setup.
+    ///
+    /// Defaults to `$STATE_DIRECTORY` under systemd, or
+    /// `$XDG_DATA_HOME/attic` otherwise. Only used in monolithic mode
+    /// when there is no config.
+    #[clap(long)]
+    state_dir: Option<PathBuf>,
+
+    /// Path to write the generated config to during initial setup.
+    ///
+    /// Defaults to `$CONFIGURATION_DIRECTORY/server.toml` under systemd,
+    /// or `$XDG_CONFIG_HOME/attic/server.toml` otherwise.
+    #[clap(long)]
+    config_out: Option<PathBuf>,
+
     /// Whether to enable tokio-console.
     ///
     /// The console server will listen on its default port.
@@ -66,12 +82,17 @@ async fn main() -> Result<()> {
     init_logging(opts.tokio_console);
     dump_version();
 
-    let config =
-        config::load_config(opts.config.as_deref(), opts.mode == ServerMode::Monolithic).await?;
+    let oobe = OobeOptions {
+        state_dir: opts.state_dir.clone(),
+        config_out: opts.config_out.clone(),
+    };
+    let allow_oobe = opts.mode == ServerMode::Monolithic;
+    let config = config::load_config(opts.config.as_deref(), allow_oobe.then_some(&oobe)).await?;
 
     match opts.mode {
         ServerMode::Monolithic => {
             attic_server::run_migrations(config.clone()).await?;
+            attic_server::reset_stale_holders(config.clone()).await?;
 
             let (api_server, _) = join!(
                 attic_server::run_api_server(opts.listen, config.clone()),
@@ -81,6 +102,7 @@ async fn main() -> Result<()> {
             api_server?;
         }
         ServerMode::ApiServer => {
+            attic_server::reset_stale_holders(config.clone()).await?;
             attic_server::run_api_server(opts.listen, config).await?;
         }
         ServerMode::GarbageCollector => {
@@ -93,7 +115,8 @@ async fn main() -> Result<()> {
             attic_server::gc::run_garbage_collection_once(config).await?;
         }
         ServerMode::CheckConfig => {
-            // config is valid, let's just exit :)
+            // config is valid, let's check that the database is reachable
+            attic_server::check_database(config).await?;
         }
     }
 
@@ -1,16 +1,24 @@
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::anyhow;
+use attic_token::util::parse_authorization_header;
 use axum::{
     extract::{Extension, Host, Request},
-    http::HeaderValue,
+    http::{
+        header::{AUTHORIZATION, USER_AGENT},
+        HeaderName, HeaderValue,
+    },
     middleware::Next,
     response::Response,
 };
+use tower_http::cors::{AllowOrigin, CorsLayer};
 
 use super::{AuthState, RequestState, RequestStateInner, State};
-use crate::error::{ErrorKind, ServerResult};
+use crate::access::http::client_ip;
+use crate::config::CorsConfig;
+use crate::error::{ErrorKind, ServerError, ServerResult};
 use attic::api::binary_cache::ATTIC_CACHE_VISIBILITY;
 
 /// Initializes per-request state.
@@ -20,6 +28,8 @@ pub async fn init_request_state(
     mut req: Request,
     next: Next,
 ) -> Response {
+    state.request_count.fetch_add(1, Ordering::Relaxed);
+
     // X-Forwarded-Proto is an untrusted header
     let client_claims_https =
         if let Some(x_forwarded_proto) = req.headers().get("x-forwarded-proto") {
@@ -28,6 +38,13 @@ pub async fn init_request_state(
             false
         };
 
+    if let Some(user_agent) = req.headers().get(USER_AGENT) {
+        tracing::debug!(
+            "User-Agent: {}",
+            String::from_utf8_lossy(user_agent.as_bytes())
+        );
+    }
+
     let req_state = Arc::new(RequestStateInner {
         auth: AuthState::new(),
         api_endpoint: state.config.api_endpoint.to_owned(),
@@ -60,6 +77,63 @@ pub async fn restrict_host(
     Ok(next.run(req).await)
 }
 
+/// Rate limits requests without a valid token.
+///
+/// This must run before `apply_auth`. Requests without a token take
+/// from the client's anonymous bucket. Requests with a token are
+/// rejected without verifying the token once the client has failed
+/// verification too many times. Requests with a valid token are
+/// never counted.
+pub(crate) async fn limit_unauthenticated(
+    Extension(state): Extension<State>,
+    Extension(req_state): Extension<RequestState>,
+    req: Request,
+    next: Next,
+) -> ServerResult<Response> {
+    let client = match client_ip(&req, &state.config.trusted_proxies) {
+        Some(client) => client,
+        None => return Ok(next.run(req).await),
+    };
+
+    let has_token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|bytes| bytes.to_str().ok())
+        .and_then(parse_authorization_header)
+        .is_some();
+
+    let limiter = &state.client_limiter;
+    if !has_token {
+        limiter
+            .check_anonymous(client, Instant::now())
+            .map_err(rate_limited)?;
+
+        return Ok(next.run(req).await);
+    }
+
+    limiter
+        .check_auth_attempt(client, Instant::now())
+        .map_err(|retry_after| {
+            tracing::debug!("Rejecting token from {} after repeated failures", client);
+            rate_limited(retry_after)
+        })?;
+
+    let response = next.run(req).await;
+
+    if req_state.auth.token.get().is_none() {
+        limiter.record_auth_failure(client, Instant::now());
+    }
+
+    Ok(response)
+}
+
+fn rate_limited(retry_after: Duration) -> ServerError {
+    ErrorKind::RateLimited {
+        retry_after_secs: retry_after.as_secs_f64().ceil().max(1.0) as u64,
+    }
+    .into()
+}
+
 /// Sets the `X-Attic-Cache-Visibility` header in responses.
 pub(crate) async fn set_visibility_header(
     Extension(req_state): Extension<RequestState>,
@@ -76,3 +150,197 @@ pub(crate) async fn set_visibility_header(
 
     Ok(response)
 }
+
+/// Returns the CORS layer for the API routes.
+///
+/// Returns None if CORS is disabled. Preflight requests are answered
+/// by the layer directly and never reach the handlers.
+pub(crate) fn make_cors_layer(config: &CorsConfig) -> Option<CorsLayer> {
+    let layer = if config.permissive {
+        CorsLayer::permissive()
+    } else if !config.allowed_origins.is_empty() {
+        CorsLayer::new()
+            .allow_origin(AllowOrigin::list(config.allowed_origins.clone()))
+            .allow_methods(config.allowed_methods.clone())
+            .allow_headers(config.allowed_headers.clone())
+    } else {
+        return None;
+    };
+
+    let layer = layer
+        .expose_headers([HeaderName::from_bytes(ATTIC_CACHE_VISIBILITY.as_bytes()).unwrap()])
+        .max_age(config.max_age);
+
+    Some(layer)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::extract::Request;
+    use axum::http::{header, Method, StatusCode};
+    use tower::ServiceExt;
+    use uuid::Uuid;
+
+    use crate::config::Config;
+    use crate::database::migration::{Migrator, MigratorTrait};
+    use crate::{make_router, StateInner};
+
+    async fn make_app(cors: &str) -> axum::Router {
+        let storage_path = std::env::temp_dir().join(format!("attic-test-{}", Uuid::new_v4()));
+        let storage_path = storage_path.display();
+
+        let config = format!(
+            r#"
+[database]
+url = "sqlite::memory:"
+
+[storage]
+type = "local"
+path = "{storage_path}"
+
+[chunking]
+nar-size-threshold = 0
+min-size = 16384
+avg-size = 65536
+max-size = 262144
+
+[cors]
+{cors}
+
+[jwt.signing]
+token-hs256-secret-base64 = "dmVyeSBzZWN1cmUgc2VjcmV0"
+"#
+        );
+
+        let config: Config = toml::from_str(&config).unwrap();
+        let state = StateInner::new(config).await;
+
+        let db = state.database().await.unwrap();
+        Migrator::up(db, None).await.unwrap();
+
+        make_router(state)
+    }
+
+    fn preflight(uri: &str, origin: &str) -> Request {
+        Request::builder()
+            .method(Method::OPTIONS)
+            .uri(uri)
+            .header(header::HOST, "localhost")
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "authorization")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    fn get(uri: &str, origin: &str) -> Request {
+        Request::builder()
+            .uri(uri)
+            .header(header::HOST, "localhost")
+            .header(header::ORIGIN, origin)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cors_allowed_origins() {
+        let app = make_app(r#"allowed-origins = ["https://dashboard.internal"]"#).await;
+
+        // Preflight succeeds without authentication
+        let res = app
+            .clone()
+            .oneshot(preflight(
+                "/_api/v1/get-missing-paths",
+                "https://dashboard.internal",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(
+            "https://dashboard.internal",
+            res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN]
+        );
+        assert!(res
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_METHODS));
+        assert_eq!("3600", res.headers()[header::ACCESS_CONTROL_MAX_AGE]);
+
+        // Actual requests still go through auth
+        let res = app
+            .clone()
+            .oneshot(get(
+                "/_api/v1/cache-config/test",
+                "https://dashboard.internal",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+        assert_eq!(
+            "https://dashboard.internal",
+            res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN]
+        );
+
+        // Disallowed origin
+        let res = app
+            .clone()
+            .oneshot(preflight(
+                "/_api/v1/get-missing-paths",
+                "https://evil.example",
+            ))
+            .await
+            .unwrap();
+        assert!(!res
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        // Binary cache routes never send CORS headers
+        let res = app
+            .clone()
+            .oneshot(get("/test/nix-cache-info", "https://dashboard.internal"))
+            .await
+            .unwrap();
+        assert!(!res
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        let res = app
+            .oneshot(get("/test/attic-cache-info", "https://dashboard.internal"))
+            .await
+            .unwrap();
+        assert!(!res
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    #[tokio::test]
+    async fn test_cors_permissive() {
+        let app = make_app("permissive = true").await;
+
+        let res = app
+            .oneshot(preflight(
+                "/_api/v1/get-missing-paths",
+                "https://anything.example",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!("*", res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN]);
+    }
+
+    #[tokio::test]
+    async fn test_cors_disabled() {
+        let app = make_app("").await;
+
+        let res = app
+            .oneshot(get(
+                "/_api/v1/cache-config/test",
+                "https://dashboard.internal",
+            ))
+            .await
+            .unwrap();
+        assert!(!res
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+}
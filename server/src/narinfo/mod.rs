@@ -37,7 +37,6 @@
 //! 1;{storePath};{narHash};{narSize};{commaDelimitedReferences}
 //! ```
 
-use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::string::ToString;
@@ -50,16 +49,18 @@ use serde_with::serde_as;
 
 use crate::error::{ErrorKind, ServerError, ServerResult};
 use crate::nix_manifest::{self, SpaceDelimitedList};
+use attic::error::AtticError;
 use attic::hash::Hash;
 use attic::mime;
-use attic::signing::NixKeypair;
+use attic::nix_store::StorePath;
+use attic::signing::{self, NixKeypair};
 
 #[cfg(test)]
 mod tests;
 
 /// NAR information.
 #[serde_as]
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NarInfo {
     /// The full store path being cached, including the store directory.
     ///
@@ -94,7 +95,7 @@ pub struct NarInfo {
 
     /// The size of the compressed file.
     ///
-    /// We may not know the file size if it's chunked.
+    /// If it's chunked, this is the total size of the compressed chunks.
     #[serde(rename = "FileSize")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub file_size: Option<usize>,
@@ -134,13 +135,13 @@ pub struct NarInfo {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub deriver: Option<String>,
 
-    /// The signature of the object.
+    /// The signatures of the object.
     ///
-    /// The `Sig` field can be duplicated to include multiple
-    /// signatures, but we only support one for now.
+    /// Each signature is a separate `Sig` line.
     #[serde(rename = "Sig")]
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub signature: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub signatures: Vec<String>,
 
     /// The content address of the object.
     #[serde(rename = "CA")]
@@ -161,6 +162,8 @@ pub enum Compression {
     Brotli,
     #[serde(rename = "zstd")]
     Zstd,
+    #[serde(rename = "lz4")]
+    Lz4,
 }
 
 impl NarInfo {
@@ -174,57 +177,65 @@ impl NarInfo {
         nix_manifest::to_string(self)
     }
 
-    /// Returns the signature of this object, if it exists.
-    pub fn signature(&self) -> Option<&String> {
-        self.signature.as_ref()
+    /// Returns the signatures of this object.
+    pub fn signatures(&self) -> &[String] {
+        &self.signatures
+    }
+
+    /// Returns whether the object has a valid signature from a keypair.
+    pub fn is_signed_by(&self, keypair: &NixKeypair) -> bool {
+        let fingerprint = self.fingerprint();
+        self.signatures
+            .iter()
+            .any(|signature| keypair.verify(&fingerprint, signature).is_ok())
     }
 
     /// Returns the store directory of this object.
-    pub fn store_dir(&self) -> &Path {
-        // FIXME: Validate store_path
-        self.store_path.parent().unwrap()
+    pub fn store_dir(&self) -> ServerResult<&Path> {
+        self.store_path
+            .parent()
+            .filter(|store_dir| !store_dir.as_os_str().is_empty())
+            .ok_or_else(|| {
+                ErrorKind::AtticError(AtticError::InvalidStorePath {
+                    path: self.store_path.clone(),
+                    reason: "Path has no store directory",
+                })
+                .into()
+            })
+    }
+
+    /// Validates the store path of this object.
+    ///
+    /// The store path must be an absolute path directly in the store
+    /// directory with a valid base name.
+    pub fn validate_store_path(&self) -> ServerResult<StorePath> {
+        if !self.store_path.is_absolute() {
+            return Err(ErrorKind::AtticError(AtticError::InvalidStorePath {
+                path: self.store_path.clone(),
+                reason: "Path is not absolute",
+            })
+            .into());
+        }
+
+        let store_path = StorePath::from_full_path(self.store_dir()?, &self.store_path)?;
+
+        Ok(store_path)
     }
 
     /// Signs the narinfo and adds the signature to the narinfo.
     pub fn sign(&mut self, keypair: &NixKeypair) {
         let signature = self.sign_readonly(keypair);
-        self.signature = Some(signature);
+        self.signatures.push(signature);
     }
 
     /// Returns the fingerprint of the object.
     pub fn fingerprint(&self) -> Vec<u8> {
-        let store_dir = self.store_dir();
-        let mut fingerprint = b"1;".to_vec();
-
-        // 1;{storePath};{narHash};{narSize};{commaDelimitedReferences}
-
-        // storePath
-        fingerprint.extend(self.store_path.as_os_str().as_bytes());
-        fingerprint.extend(b";");
-
-        // narHash
-        fingerprint.extend(self.nar_hash.to_typed_base32().as_bytes());
-        fingerprint.extend(b";");
-
-        // narSize
-        let mut buf = itoa::Buffer::new();
-        let nar_size = buf.format(self.nar_size);
-        fingerprint.extend(nar_size.as_bytes());
-        fingerprint.extend(b";");
-
-        // commaDelimitedReferences
-        let mut iter = self.references.iter().peekable();
-        while let Some(reference) = iter.next() {
-            fingerprint.extend(store_dir.as_os_str().as_bytes());
-            fingerprint.extend(b"/");
-            fingerprint.extend(reference.as_bytes());
-
-            if iter.peek().is_some() {
-                fingerprint.extend(b",");
-            }
-        }
-
-        fingerprint
+        signing::fingerprint(
+            &self.store_path,
+            &self.nar_hash,
+            self.nar_size,
+            &self.references,
+        )
     }
 
     /// Signs the narinfo with a keypair, returning the signature.
@@ -256,6 +267,7 @@ impl Compression {
             Self::Bzip2 => "bzip2",
             Self::Brotli => "br",
             Self::Zstd => "zstd",
+            Self::Lz4 => "lz4",
         }
     }
 }
@@ -270,6 +282,7 @@ impl FromStr for Compression {
             "bzip2" => Ok(Self::Bzip2),
             "br" => Ok(Self::Brotli),
             "zstd" => Ok(Self::Zstd),
+            "lz4" => Ok(Self::Lz4),
             _ => Err(ErrorKind::InvalidCompressionType {
                 name: s.to_string(),
             }
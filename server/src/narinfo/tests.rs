@@ -2,6 +2,8 @@ use super::*;
 
 use std::path::Path;
 
+use attic::api::v1::upload_path::UploadPathNarInfo;
+use attic::nix_store::StorePathHash;
 use attic::signing::NixPublicKey;
 
 #[test]
@@ -26,7 +28,7 @@ Sig: cache.nixos.org-1:lo9EfNIL4eGRuNh7DTbAAffWPpI2SlYC/8uP7JnhgmfRIUNGhSbFe8qEa
             Path::new("/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10"),
             narinfo.store_path
         );
-        assert_eq!(Path::new("/nix/store"), narinfo.store_dir());
+        assert_eq!(Path::new("/nix/store"), narinfo.store_dir().unwrap());
         assert_eq!(
             "nar/0nqgf15qfiacfxrgm2wkw0gwwncjqqzzalj8rs14w9srkydkjsk9.nar.xz",
             narinfo.url
@@ -53,7 +55,7 @@ Sig: cache.nixos.org-1:lo9EfNIL4eGRuNh7DTbAAffWPpI2SlYC/8uP7JnhgmfRIUNGhSbFe8qEa
             Some("vvb4wxmnjixmrkhmj2xb75z62hrr41i7-hello-2.10.drv".to_string()),
             narinfo.deriver
         );
-        assert_eq!(Some("cache.nixos.org-1:lo9EfNIL4eGRuNh7DTbAAffWPpI2SlYC/8uP7JnhgmfRIUNGhSbFe8qEaKN0mFS02TuhPpXFPNtRkFcCp0hGAQ==".to_string()), narinfo.signature);
+        assert_eq!(vec!["cache.nixos.org-1:lo9EfNIL4eGRuNh7DTbAAffWPpI2SlYC/8uP7JnhgmfRIUNGhSbFe8qEaKN0mFS02TuhPpXFPNtRkFcCp0hGAQ==".to_string()], narinfo.signatures);
     }
 
     verify_narinfo(&narinfo);
@@ -123,6 +125,170 @@ Sig: cache.nixos.org-1:lo9EfNIL4eGRuNh7DTbAAffWPpI2SlYC/8uP7JnhgmfRIUNGhSbFe8qEa
     assert_eq!(correct_fingerprint, fingerprint.as_slice());
 
     public_key
-        .verify(&narinfo.fingerprint(), narinfo.signature().unwrap())
+        .verify(&narinfo.fingerprint(), &narinfo.signatures()[0])
         .expect("Could not verify signature");
 }
+
+#[test]
+fn test_upload_info_fingerprint() {
+    let s = r#"
+StorePath: /nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10
+URL: nar/0nqgf15qfiacfxrgm2wkw0gwwncjqqzzalj8rs14w9srkydkjsk9.nar.xz
+Compression: xz
+FileHash: sha256:0nqgf15qfiacfxrgm2wkw0gwwncjqqzzalj8rs14w9srkydkjsk9
+FileSize: 41104
+NarHash: sha256:91e129ac1959d062ad093d2b1f8b65afae0f712056fe3eac78ec530ff6a1bb9a
+NarSize: 206104
+References: 563528481rvhc5kxwipjmg6rqrl95mdx-glibc-2.33-56 xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10
+Deriver: vvb4wxmnjixmrkhmj2xb75z62hrr41i7-hello-2.10.drv
+Sig: cache.nixos.org-1:lo9EfNIL4eGRuNh7DTbAAffWPpI2SlYC/8uP7JnhgmfRIUNGhSbFe8qEaKN0mFS02TuhPpXFPNtRkFcCp0hGAQ==
+    "#;
+
+    let narinfo = NarInfo::from_str(s).expect("Could not parse narinfo");
+
+    // What the client would send when pushing the same path
+    let upload_info = UploadPathNarInfo {
+        cache: "test".parse().unwrap(),
+        store_path_hash: StorePathHash::new("xcp9cav49dmsjbwdjlmkjxj10gkpx553".to_string())
+            .unwrap(),
+        store_path: "/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10".to_string(),
+        references: narinfo.references.clone(),
+        system: None,
+        deriver: narinfo.deriver.clone(),
+        sigs: Vec::new(),
+        ca: None,
+        nar_hash: narinfo.nar_hash.clone(),
+        nar_size: narinfo.nar_size,
+    };
+
+    assert_eq!(narinfo.fingerprint(), upload_info.fingerprint());
+
+    let public_key =
+        NixPublicKey::from_str("cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=")
+            .expect("Could not import cache.nixos.org public key");
+
+    public_key
+        .verify(&upload_info.fingerprint(), &narinfo.signatures()[0])
+        .expect("Could not verify signature");
+}
+
+#[test]
+fn test_multiple_signatures() {
+    let s = r#"
+StorePath: /nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10
+URL: nar/0nqgf15qfiacfxrgm2wkw0gwwncjqqzzalj8rs14w9srkydkjsk9.nar.xz
+Compression: xz
+NarHash: sha256:16mvl7v0ylzcg2n3xzjn41qhzbmgcn5iyarx16nn5l2r36n2kqci
+NarSize: 206104
+References: 563528481rvhc5kxwipjmg6rqrl95mdx-glibc-2.33-56 xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10
+Sig: cache.nixos.org-1:lo9EfNIL4eGRuNh7DTbAAffWPpI2SlYC/8uP7JnhgmfRIUNGhSbFe8qEaKN0mFS02TuhPpXFPNtRkFcCp0hGAQ==
+Sig: attic-test:dGVzdA==
+    "#;
+
+    let mut narinfo = NarInfo::from_str(s).expect("Could not parse narinfo");
+    assert_eq!(2, narinfo.signatures().len());
+    assert_eq!("attic-test:dGVzdA==", narinfo.signatures()[1]);
+
+    let keypair = NixKeypair::generate("attic-test").unwrap();
+    assert!(!narinfo.is_signed_by(&keypair));
+
+    // Signing appends to the existing signatures
+    narinfo.sign(&keypair);
+    assert_eq!(3, narinfo.signatures().len());
+    assert!(narinfo.is_signed_by(&keypair));
+
+    let round_trip = narinfo.to_string().expect("Could not serialize narinfo");
+    assert_eq!(3, round_trip.matches("\nSig: ").count());
+
+    let reparsed = NarInfo::from_str(&round_trip).expect("Could not reparse narinfo");
+    assert_eq!(narinfo.signatures, reparsed.signatures);
+}
+
+#[test]
+fn test_no_signatures() {
+    let s = r#"
+StorePath: /nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10
+URL: nar/0nqgf15qfiacfxrgm2wkw0gwwncjqqzzalj8rs14w9srkydkjsk9.nar.xz
+Compression: xz
+NarHash: sha256:16mvl7v0ylzcg2n3xzjn41qhzbmgcn5iyarx16nn5l2r36n2kqci
+NarSize: 206104
+References: 563528481rvhc5kxwipjmg6rqrl95mdx-glibc-2.33-56
+    "#;
+
+    let narinfo = NarInfo::from_str(s).expect("Could not parse narinfo");
+    assert!(narinfo.signatures().is_empty());
+
+    let round_trip = narinfo.to_string().expect("Could not serialize narinfo");
+    assert!(!round_trip.contains("Sig"));
+}
+
+#[test]
+fn test_compression_lz4() {
+    let s = r#"
+StorePath: /nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10
+URL: nar/xcp9cav49dmsjbwdjlmkjxj10gkpx553.nar
+Compression: lz4
+NarHash: sha256:16mvl7v0ylzcg2n3xzjn41qhzbmgcn5iyarx16nn5l2r36n2kqci
+NarSize: 206104
+References: 563528481rvhc5kxwipjmg6rqrl95mdx-glibc-2.33-56
+    "#;
+
+    let narinfo = NarInfo::from_str(s).expect("Could not parse narinfo");
+    assert_eq!(Compression::Lz4, narinfo.compression);
+
+    let round_trip = narinfo.to_string().expect("Could not serialize narinfo");
+    assert!(round_trip.contains("Compression: lz4\n"));
+
+    assert_eq!(Compression::Lz4, Compression::from_str("lz4").unwrap());
+    assert_eq!("lz4", Compression::Lz4.as_str());
+}
+
+#[test]
+fn test_store_dir_without_parent() {
+    let s = r#"
+StorePath: /
+URL: nar/xcp9cav49dmsjbwdjlmkjxj10gkpx553.nar
+Compression: none
+NarHash: sha256:16mvl7v0ylzcg2n3xzjn41qhzbmgcn5iyarx16nn5l2r36n2kqci
+NarSize: 206104
+References:
+    "#;
+
+    let mut narinfo = NarInfo::from_str(s).expect("Could not parse narinfo");
+    assert!(narinfo.store_dir().is_err());
+
+    narinfo.store_path = PathBuf::from("xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10");
+    assert!(narinfo.store_dir().is_err());
+}
+
+#[test]
+fn test_validate_store_path() {
+    let s = r#"
+StorePath: /nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10
+URL: nar/xcp9cav49dmsjbwdjlmkjxj10gkpx553.nar
+Compression: none
+NarHash: sha256:16mvl7v0ylzcg2n3xzjn41qhzbmgcn5iyarx16nn5l2r36n2kqci
+NarSize: 206104
+References:
+    "#;
+
+    let mut narinfo = NarInfo::from_str(s).expect("Could not parse narinfo");
+    let store_path = narinfo.validate_store_path().unwrap();
+    assert_eq!(
+        "xcp9cav49dmsjbwdjlmkjxj10gkpx553",
+        store_path.to_hash().as_str()
+    );
+
+    for invalid in [
+        "/",
+        "/nix/store",
+        "nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10",
+        "xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10",
+        "/nix/store/hello-2.10",
+        "/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10/bin",
+        "/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10/",
+    ] {
+        narinfo.store_path = PathBuf::from(invalid);
+        assert!(narinfo.validate_store_path().is_err(), "{}", invalid);
+    }
+}
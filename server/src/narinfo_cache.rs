@@ -0,0 +1,293 @@
+//! In-memory cache of narinfo responses.
+//!
+//! Rendering a narinfo takes a join over several tables, which adds
+//! up when many clients query the same paths at once. Rendered
+//! narinfos are kept in an LRU cache keyed by cache name and store
+//! path hash.
+//!
+//! Entries are invalidated when the object is uploaded or deleted
+//! through this process, and all entries of a cache are invalidated
+//! when the cache is reconfigured since the signing key may change.
+//! Changes made by other processes are only picked up after the TTL.
+
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+use serde::Serialize;
+
+use crate::config::NarInfoCacheConfig;
+use crate::narinfo::NarInfo;
+use attic::cache::CacheName;
+use attic::nix_store::StorePathHash;
+
+type Key = (CacheName, String);
+
+/// A cache of narinfo responses.
+#[derive(Debug)]
+pub struct NarInfoCache {
+    /// The cached entries, or `None` if disabled.
+    entries: Option<Mutex<LruCache<Key, Entry>>>,
+
+    /// How long entries are served.
+    ttl: Duration,
+
+    /// Number of lookups served from the cache.
+    hits: AtomicU64,
+
+    /// Number of lookups not served from the cache.
+    misses: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    narinfo: NarInfo,
+
+    /// Whether the cache was public, for authorization.
+    is_public: bool,
+
+    inserted_at: Instant,
+}
+
+/// Statistics of a narinfo cache.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NarInfoCacheStats {
+    pub enabled: bool,
+    pub entries: usize,
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl NarInfoCache {
+    pub fn new(config: &NarInfoCacheConfig) -> Self {
+        let entries = NonZeroUsize::new(config.size)
+            .filter(|_| !config.ttl.is_zero())
+            .map(|size| Mutex::new(LruCache::new(size)));
+
+        Self {
+            entries,
+            ttl: config.ttl,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns a cached narinfo and whether the cache is public.
+    pub fn get(
+        &self,
+        cache: &CacheName,
+        store_path_hash: &StorePathHash,
+    ) -> Option<(NarInfo, bool)> {
+        let entries = self.entries.as_ref()?;
+        let key = (cache.clone(), store_path_hash.to_string());
+
+        let mut entries = entries.lock().unwrap();
+        let hit = match entries.get(&key) {
+            Some(entry) if entry.inserted_at.elapsed() < self.ttl => {
+                Some((entry.narinfo.clone(), entry.is_public))
+            }
+            Some(_) => {
+                entries.pop(&key);
+                None
+            }
+            None => None,
+        };
+        drop(entries);
+
+        if hit.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+
+        hit
+    }
+
+    /// Caches a narinfo.
+    pub fn insert(
+        &self,
+        cache: &CacheName,
+        store_path_hash: &StorePathHash,
+        narinfo: &NarInfo,
+        is_public: bool,
+    ) {
+        let Some(entries) = &self.entries else {
+            return;
+        };
+
+        let entry = Entry {
+            narinfo: narinfo.clone(),
+            is_public,
+            inserted_at: Instant::now(),
+        };
+
+        entries
+            .lock()
+            .unwrap()
+            .put((cache.clone(), store_path_hash.to_string()), entry);
+    }
+
+    /// Invalidates the narinfos of some objects in a cache.
+    pub fn invalidate<S: AsRef<str>>(
+        &self,
+        cache: &CacheName,
+        store_path_hashes: impl IntoIterator<Item = S>,
+    ) {
+        let Some(entries) = &self.entries else {
+            return;
+        };
+
+        let mut entries = entries.lock().unwrap();
+        for store_path_hash in store_path_hashes {
+            entries.pop(&(cache.clone(), store_path_hash.as_ref().to_string()));
+        }
+    }
+
+    /// Invalidates all narinfos of a cache.
+    pub fn invalidate_cache(&self, cache: &CacheName) {
+        let Some(entries) = &self.entries else {
+            return;
+        };
+
+        let mut entries = entries.lock().unwrap();
+        let keys: Vec<Key> = entries
+            .iter()
+            .filter(|((c, _), _)| c == cache)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in keys {
+            entries.pop(&key);
+        }
+    }
+
+    /// Invalidates all narinfos.
+    pub fn clear(&self) {
+        if let Some(entries) = &self.entries {
+            entries.lock().unwrap().clear();
+        }
+    }
+
+    pub fn stats(&self) -> NarInfoCacheStats {
+        let (entries, capacity) = match &self.entries {
+            Some(entries) => {
+                let entries = entries.lock().unwrap();
+                (entries.len(), entries.cap().get())
+            }
+            None => (0, 0),
+        };
+
+        NarInfoCacheStats {
+            enabled: self.entries.is_some(),
+            entries,
+            capacity,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use attic::hash::Hash;
+
+    use crate::narinfo::Compression;
+
+    fn make_narinfo(url: &str) -> NarInfo {
+        NarInfo {
+            store_path: PathBuf::from("/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10"),
+            url: url.to_string(),
+            compression: Compression::None,
+            file_hash: None,
+            file_size: None,
+            nar_hash: Hash::Sha256([0; 32]),
+            nar_size: 1234,
+            references: Vec::new(),
+            system: None,
+            deriver: None,
+            signatures: Vec::new(),
+            ca: None,
+        }
+    }
+
+    fn hash(c: char) -> StorePathHash {
+        StorePathHash::new(c.to_string().repeat(32)).unwrap()
+    }
+
+    fn make_cache(size: usize, ttl: Duration) -> NarInfoCache {
+        NarInfoCache::new(&NarInfoCacheConfig { size, ttl })
+    }
+
+    #[test]
+    fn test_narinfo_cache() {
+        let cache = make_cache(2, Duration::from_secs(60));
+        let a: CacheName = "a".parse().unwrap();
+        let b: CacheName = "b".parse().unwrap();
+
+        assert!(cache.get(&a, &hash('a')).is_none());
+
+        cache.insert(&a, &hash('a'), &make_narinfo("nar/a.nar"), true);
+        cache.insert(&b, &hash('a'), &make_narinfo("nar/b.nar"), false);
+
+        let (narinfo, is_public) = cache.get(&a, &hash('a')).unwrap();
+        assert_eq!("nar/a.nar", narinfo.url);
+        assert!(is_public);
+
+        let (narinfo, is_public) = cache.get(&b, &hash('a')).unwrap();
+        assert_eq!("nar/b.nar", narinfo.url);
+        assert!(!is_public);
+
+        // The least recently used entry is evicted
+        cache.get(&a, &hash('a')).unwrap();
+        cache.insert(&a, &hash('b'), &make_narinfo("nar/c.nar"), true);
+        assert!(cache.get(&b, &hash('a')).is_none());
+        assert!(cache.get(&a, &hash('a')).is_some());
+
+        cache.invalidate(&a, [hash('a').as_str()]);
+        assert!(cache.get(&a, &hash('a')).is_none());
+        assert!(cache.get(&a, &hash('b')).is_some());
+
+        cache.invalidate_cache(&a);
+        assert!(cache.get(&a, &hash('b')).is_none());
+
+        assert_eq!(
+            NarInfoCacheStats {
+                enabled: true,
+                entries: 0,
+                capacity: 2,
+                hits: 5,
+                misses: 4,
+            },
+            cache.stats()
+        );
+    }
+
+    #[test]
+    fn test_narinfo_cache_expiry() {
+        let cache = make_cache(2, Duration::from_millis(1));
+        let a: CacheName = "a".parse().unwrap();
+
+        cache.insert(&a, &hash('a'), &make_narinfo("nar/a.nar"), true);
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(cache.get(&a, &hash('a')).is_none());
+        assert_eq!(0, cache.stats().entries);
+    }
+
+    #[test]
+    fn test_narinfo_cache_disabled() {
+        let cache = make_cache(0, Duration::from_secs(60));
+        let a: CacheName = "a".parse().unwrap();
+
+        cache.insert(&a, &hash('a'), &make_narinfo("nar/a.nar"), true);
+        assert!(cache.get(&a, &hash('a')).is_none());
+        assert!(!cache.stats().enabled);
+        assert_eq!(0, cache.stats().misses);
+    }
+}
@@ -2,24 +2,49 @@
 //!
 //! This maps the manifest format into the serde data model.
 
+use std::collections::HashSet;
 use std::ops::{AddAssign, MulAssign};
 
-use serde::de::{DeserializeSeed, IntoDeserializer, MapAccess, Visitor};
+use serde::de::{DeserializeSeed, IntoDeserializer, MapAccess, SeqAccess, Visitor};
 use serde::{de, forward_to_deserialize_any};
 
 use super::{Error, Result};
 
 /// The main deserializer.
 pub struct Deserializer<'de> {
+    original: &'de str,
     input: &'de str,
+
+    /// The key whose value is to be deserialized next.
+    current_key: Option<&'de str>,
+
+    /// Offsets of lines whose values have already been consumed.
+    ///
+    /// Those are later occurrences of a repeated key.
+    consumed: HashSet<usize>,
 }
 
 /// Deserializer for values.
-pub struct ValueDeserializer<'a, 'de: 'a>(&'a mut Deserializer<'de>);
+///
+/// If a key is repeated, the values of all occurrences are
+/// collected and can be deserialized as a sequence.
+pub struct ValueDeserializer<'de> {
+    values: Vec<&'de str>,
+}
+
+/// Access to the values of a repeated key.
+struct ValueSeqAccess<'de> {
+    values: std::vec::IntoIter<&'de str>,
+}
 
 impl<'de> Deserializer<'de> {
     pub fn from_str(input: &'de str) -> Self {
-        Deserializer { input }
+        Deserializer {
+            original: input,
+            input,
+            current_key: None,
+            consumed: HashSet::new(),
+        }
     }
 }
 
@@ -60,40 +85,72 @@ impl<'de> Deserializer<'de> {
         Ok(s)
     }
 
-    fn parse_unsigned<T>(&mut self) -> Result<T>
-    where
-        T: AddAssign<T> + MulAssign<T> + From<u8>,
-    {
-        let mut int = match self.next_char()? {
-            ch @ '0'..='9' => T::from(ch as u8 - b'0'),
+    fn consume_inline_whitespace(&mut self) {
+        self.input = self.input.trim_start_matches([' ', '\t']);
+    }
+
+    fn position(&self) -> usize {
+        self.original.len() - self.input.len()
+    }
+
+    /// Collects the values of all remaining lines with the same key.
+    ///
+    /// The lines are remembered so they are skipped when reading
+    /// the following keys.
+    fn parse_repeated_values(&mut self, key: &str) -> Vec<&'de str> {
+        let mut values = Vec::new();
+        let mut offset = self.position();
+
+        for line in self.input.split_inclusive('\n') {
+            let trimmed = line.trim_start_matches([' ', '\n', '\r', '\t']);
+            let start = offset + (line.len() - trimmed.len());
+            offset += line.len();
+
+            if let Some(value) = trimmed
+                .strip_prefix(key)
+                .and_then(|rest| rest.strip_prefix(':'))
+            {
+                let value = value.trim_start_matches([' ', '\t']);
+                let end = value.find(['\r', '\n']).unwrap_or(value.len());
+
+                values.push(&value[..end]);
+                self.consumed.insert(start);
+            }
+        }
+
+        values
+    }
+}
+
+fn parse_unsigned<T>(s: &str) -> Result<T>
+where
+    T: AddAssign<T> + MulAssign<T> + From<u8>,
+{
+    if s.is_empty() {
+        return Err(Error::ExpectedInteger);
+    }
+
+    let mut int = T::from(0);
+    for ch in s.chars() {
+        match ch {
+            '0'..='9' => {
+                int *= T::from(10);
+                int += T::from(ch as u8 - b'0');
+            }
             _ => {
                 return Err(Error::ExpectedInteger);
             }
-        };
-        loop {
-            match self.input.chars().next() {
-                Some(ch @ '0'..='9') => {
-                    self.input = &self.input[1..];
-                    int *= T::from(10);
-                    int += T::from(ch as u8 - b'0');
-                }
-                _ => {
-                    return Ok(int);
-                }
-            }
         }
     }
 
-    fn parse_bool(&mut self) -> Result<bool> {
-        if self.input.starts_with('1') {
-            self.input = &self.input["1".len()..];
-            Ok(true)
-        } else if self.input.starts_with('0') {
-            self.input = &self.input["0".len()..];
-            Ok(false)
-        } else {
-            Err(Error::ExpectedBoolean)
-        }
+    Ok(int)
+}
+
+fn parse_bool(s: &str) -> Result<bool> {
+    match s {
+        "1" => Ok(true),
+        "0" => Ok(false),
+        _ => Err(Error::ExpectedBoolean),
     }
 }
 
@@ -148,6 +205,7 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
         let identifier = &self.input[..colon];
 
         self.input = &self.input[colon..];
+        self.current_key = Some(identifier);
         visitor.visit_borrowed_str(identifier)
     }
 }
@@ -159,7 +217,16 @@ impl<'de> MapAccess<'de> for Deserializer<'de> {
     where
         K: DeserializeSeed<'de>,
     {
-        self.consume_whitespace()?;
+        loop {
+            self.consume_whitespace()?;
+
+            if !self.consumed.contains(&self.position()) {
+                break;
+            }
+
+            // Already collected with an earlier occurrence of the key
+            self.parse_until_eol()?;
+        }
 
         if self.input.is_empty() {
             return Ok(None);
@@ -176,13 +243,51 @@ impl<'de> MapAccess<'de> for Deserializer<'de> {
             return Err(Error::ExpectedColon);
         }
 
-        self.consume_whitespace()?;
+        self.consume_inline_whitespace();
+
+        let mut values = vec![self.parse_until_eol()?];
+
+        if let Some(key) = self.current_key.take() {
+            values.extend(self.parse_repeated_values(key));
+        }
+
+        seed.deserialize(&mut ValueDeserializer { values })
+    }
+}
+
+impl<'de> ValueDeserializer<'de> {
+    /// Returns the value, failing if the key was repeated.
+    fn single(&self) -> Result<&'de str> {
+        match self.values.as_slice() {
+            [value] => Ok(value),
+            _ => Err(Error::Unexpected("repeated key")),
+        }
+    }
+}
+
+impl<'de> SeqAccess<'de> for ValueSeqAccess<'de> {
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        match self.values.next() {
+            Some(value) => seed
+                .deserialize(&mut ValueDeserializer {
+                    values: vec![value],
+                })
+                .map(Some),
+            None => Ok(None),
+        }
+    }
 
-        seed.deserialize(&mut ValueDeserializer(self))
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.values.len())
     }
 }
 
-impl<'de, 'a> de::Deserializer<'de> for &'a mut ValueDeserializer<'a, 'de> {
+impl<'de> de::Deserializer<'de> for &mut ValueDeserializer<'de> {
     type Error = Error;
 
     fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
@@ -196,7 +301,7 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut ValueDeserializer<'a, 'de> {
     where
         V: Visitor<'de>,
     {
-        visitor.visit_bool(self.0.parse_bool()?)
+        visitor.visit_bool(parse_bool(self.single()?)?)
     }
 
     fn deserialize_i8<V>(self, _visitor: V) -> Result<V::Value>
@@ -231,28 +336,28 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut ValueDeserializer<'a, 'de> {
     where
         V: Visitor<'de>,
     {
-        visitor.visit_u8(self.0.parse_unsigned()?)
+        visitor.visit_u8(parse_unsigned(self.single()?)?)
     }
 
     fn deserialize_u16<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        visitor.visit_u16(self.0.parse_unsigned()?)
+        visitor.visit_u16(parse_unsigned(self.single()?)?)
     }
 
     fn deserialize_u32<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        visitor.visit_u32(self.0.parse_unsigned()?)
+        visitor.visit_u32(parse_unsigned(self.single()?)?)
     }
 
     fn deserialize_u64<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        visitor.visit_u64(self.0.parse_unsigned()?)
+        visitor.visit_u64(parse_unsigned(self.single()?)?)
     }
 
     fn deserialize_f32<V>(self, _visitor: V) -> Result<V::Value>
@@ -281,7 +386,7 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut ValueDeserializer<'a, 'de> {
     where
         V: Visitor<'de>,
     {
-        visitor.visit_borrowed_str(self.0.parse_until_eol()?.trim_start())
+        visitor.visit_borrowed_str(self.single()?)
     }
 
     // only accepted in maps
@@ -335,11 +440,14 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut ValueDeserializer<'a, 'de> {
         visitor.visit_newtype_struct(self)
     }
 
-    fn deserialize_seq<V>(self, _visitor: V) -> Result<V::Value>
+    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        Err(Error::Unsupported("Sequence"))
+        let values = std::mem::take(&mut self.values);
+        visitor.visit_seq(ValueSeqAccess {
+            values: values.into_iter(),
+        })
     }
 
     fn deserialize_tuple<V>(self, _len: usize, visitor: V) -> Result<V::Value>
@@ -389,7 +497,7 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut ValueDeserializer<'a, 'de> {
     where
         V: Visitor<'de>,
     {
-        let val = self.0.parse_until_eol()?;
+        let val = self.single()?;
         visitor.visit_enum(val.into_deserializer())
     }
 
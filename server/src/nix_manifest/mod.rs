@@ -16,6 +16,11 @@
 //! Priority: 40
 //! ```
 //!
+//! Some keys like `Sig` may appear multiple times. All occurrences
+//! of a key are collected, so such keys should be deserialized into
+//! sequences like `Vec<String>`. Sequences are serialized as the same
+//! key repeated once per element.
+//!
 //! [1] <https://github.com/NixOS/nix/blob/d581129ef9ef5d7d65e676f6a7bfe36c82f6ea6e/src/libstore/nar-info.cc#L28>
 
 mod deserializer;
@@ -10,6 +10,15 @@ use super::{Error, Result};
 pub struct Serializer {
     output: String,
     seen_map: bool,
+
+    /// The struct field being serialized and where it starts in the output.
+    current_field: Option<(&'static str, usize)>,
+
+    /// Number of elements serialized in the current sequence.
+    ///
+    /// A sequence is serialized as the same key repeated on
+    /// consecutive lines, once per element.
+    seq_len: Option<usize>,
 }
 
 impl Serializer {
@@ -17,6 +26,8 @@ impl Serializer {
         Self {
             output: String::new(),
             seen_map: false,
+            current_field: None,
+            seq_len: None,
         }
     }
 
@@ -159,7 +170,13 @@ impl<'a> ser::Serializer for &'a mut Serializer {
 
     // Compund types
     fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
-        Err(Error::Unsupported("Sequence"))
+        // Only supported as the value of a struct field
+        if self.current_field.is_none() || self.seq_len.is_some() {
+            return Err(Error::Unsupported("Sequence"));
+        }
+
+        self.seq_len = Some(0);
+        Ok(self)
     }
 
     fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple> {
@@ -213,16 +230,35 @@ impl<'a> ser::SerializeSeq for &'a mut Serializer {
     type Error = Error;
 
     // Serialize a single element of the sequence.
-    fn serialize_element<T>(&mut self, _value: &T) -> Result<()>
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
     where
         T: ?Sized + Serialize,
     {
-        Err(Error::Unsupported("Sequence"))
+        let (key, _) = self.current_field.ok_or(Error::Unsupported("Sequence"))?;
+        let len = self.seq_len.ok_or(Error::Unsupported("Sequence"))?;
+
+        if len > 0 {
+            self.output += "\n";
+            self.output += key;
+            self.output += ": ";
+        }
+
+        value.serialize(&mut **self)?;
+        self.seq_len = Some(len + 1);
+
+        Ok(())
     }
 
     // Close the sequence.
     fn end(self) -> Result<()> {
-        Err(Error::Unsupported("Sequence"))
+        if self.seq_len.take() == Some(0) {
+            // Empty sequences are omitted entirely
+            if let Some((_, start)) = self.current_field {
+                self.output.truncate(start);
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -307,10 +343,18 @@ impl<'a> ser::SerializeStruct for &'a mut Serializer {
     where
         T: ?Sized + Serialize,
     {
+        let start = self.output.len();
+        self.current_field = Some((key, start));
+
         key.serialize(&mut **self)?;
         self.output += ": ";
         value.serialize(&mut **self)?;
-        self.output += "\n";
+
+        if self.output.len() > start {
+            self.output += "\n";
+        }
+
+        self.current_field = None;
         Ok(())
     }
 
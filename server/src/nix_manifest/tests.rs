@@ -1,6 +1,7 @@
 use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
 
 /// A hypothetical manifest.
 #[derive(Debug, PartialEq, Deserialize, Serialize)]
@@ -53,3 +54,101 @@ WantMassQuery: 1
     let parsed = super::from_str::<HypotheticalManifest>(manifest).unwrap();
     assert_eq!(parsed, expected);
 }
+
+/// A manifest with a repeated key.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+struct RepeatedManifest {
+    #[serde(rename = "StoreDir")]
+    store_dir: PathBuf,
+
+    #[serde(rename = "Sig")]
+    #[serde(default)]
+    sigs: Vec<String>,
+}
+
+#[test]
+fn test_repeated_key() {
+    let manifest = r#"
+StoreDir: /nix/store
+Sig: a:1
+Sig: b:2
+    "#;
+
+    let expected = RepeatedManifest {
+        store_dir: PathBuf::from("/nix/store"),
+        sigs: vec!["a:1".to_string(), "b:2".to_string()],
+    };
+
+    let parsed = super::from_str::<RepeatedManifest>(manifest).unwrap();
+    assert_eq!(parsed, expected);
+
+    let round_trip = super::to_string(&parsed).unwrap();
+    assert_eq!(manifest.trim(), round_trip.trim());
+
+    // Empty sequences are omitted
+    let empty = RepeatedManifest {
+        store_dir: PathBuf::from("/nix/store"),
+        sigs: Vec::new(),
+    };
+    let round_trip = super::to_string(&empty).unwrap();
+    assert_eq!("StoreDir: /nix/store\n", round_trip);
+
+    // Scalar values cannot be repeated
+    let repeated_scalar = r#"
+StoreDir: /nix/store
+StoreDir: /nix/store
+    "#;
+    assert!(super::from_str::<RepeatedManifest>(repeated_scalar).is_err());
+}
+
+/// A manifest with repeated keys parsed with `serde_as`.
+#[serde_as]
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+struct RepeatedNumberManifest {
+    #[serde(rename = "Name")]
+    name: String,
+
+    #[serde(rename = "Ref")]
+    #[serde(default)]
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    refs: Vec<u32>,
+
+    #[serde(rename = "Sig")]
+    #[serde(default)]
+    sigs: Vec<String>,
+}
+
+#[test]
+fn test_repeated_key_non_consecutive() {
+    let manifest = r#"
+Ref: 1
+Sig: a:1
+Name: test
+Ref: 2
+Sig: b:2
+Ref: 3
+    "#;
+
+    let expected = RepeatedNumberManifest {
+        name: "test".to_string(),
+        refs: vec![1, 2, 3],
+        sigs: vec!["a:1".to_string(), "b:2".to_string()],
+    };
+
+    let parsed = super::from_str::<RepeatedNumberManifest>(manifest).unwrap();
+    assert_eq!(parsed, expected);
+
+    // Repeated keys are grouped when serializing
+    let round_trip = super::to_string(&parsed).unwrap();
+    assert_eq!(
+        "Name: test\nRef: 1\nRef: 2\nRef: 3\nSig: a:1\nSig: b:2\n",
+        round_trip
+    );
+
+    let parsed2 = super::from_str::<RepeatedNumberManifest>(&round_trip).unwrap();
+    assert_eq!(parsed2, expected);
+
+    // Bad elements are reported
+    let bad = "Name: test\nRef: 1\nRef: x\n";
+    assert!(super::from_str::<RepeatedNumberManifest>(bad).is_err());
+}
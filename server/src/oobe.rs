@@ -7,15 +7,29 @@
 //! permanent setup.
 //!
 //! Paths:
-//! - Config: `~/.config/attic/server.yaml`
+//! - Config: `~/.config/attic/server.toml`
 //! - SQLite: `~/.local/share/attic/server.db`
 //! - NARs: `~/.local/share/attic/storage`
+//!
+//! When run as a systemd service, `$CONFIGURATION_DIRECTORY` and
+//! `$STATE_DIRECTORY` take the place of the XDG directories. Both
+//! can be overridden on the command line.
+//!
+//! The generated files are only accessible by the user running the
+//! setup. If that's root but the server will run as a service user,
+//! the ownership needs to be changed, which we print instructions for.
+
+use std::ffi::OsString;
+use std::io;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use base64::{engine::general_purpose::STANDARD as BASE64_STANDARD, Engine};
 use chrono::{Months, Utc};
 use rsa::pkcs1::EncodeRsaPrivateKey;
 use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
 
 use crate::access::{decode_token_rs256_secret_base64, SignatureType, Token};
 use crate::config;
@@ -23,26 +37,143 @@ use attic::cache::CacheNamePattern;
 
 const CONFIG_TEMPLATE: &str = include_str!("config-template.toml");
 
-pub async fn run_oobe() -> Result<()> {
-    let config_path = config::get_xdg_config_path()?;
+/// Config directory set by systemd's `ConfigurationDirectory=`.
+const ENV_CONFIGURATION_DIRECTORY: &str = "CONFIGURATION_DIRECTORY";
+
+/// State directory set by systemd's `StateDirectory=`.
+const ENV_STATE_DIRECTORY: &str = "STATE_DIRECTORY";
+
+/// Name of the config file in config directories.
+const CONFIG_FILE_NAME: &str = "server.toml";
+
+/// Overrides for where the initial setup places its files.
+#[derive(Debug, Clone, Default)]
+pub struct OobeOptions {
+    /// Directory to store the SQLite database and NARs in.
+    pub state_dir: Option<PathBuf>,
+
+    /// Path to write the generated config to.
+    pub config_out: Option<PathBuf>,
+}
+
+/// Returns the path of the config to generate or load.
+///
+/// In order of precedence: `config_out`, `$CONFIGURATION_DIRECTORY/server.toml`,
+/// then `$XDG_CONFIG_HOME/attic/server.toml`.
+pub(crate) fn resolve_config_path<E>(config_out: Option<&Path>, env: E) -> Result<PathBuf>
+where
+    E: Fn(&str) -> Option<OsString>,
+{
+    if let Some(path) = config_out {
+        return Ok(path.to_owned());
+    }
 
-    if config_path.exists() {
+    if let Some(dir) = systemd_directory(&env, ENV_CONFIGURATION_DIRECTORY) {
+        return Ok(dir.join(CONFIG_FILE_NAME));
+    }
+
+    config::get_xdg_config_path()
+}
+
+/// Returns the directory to store the database and NARs in.
+///
+/// In order of precedence: `state_dir`, `$STATE_DIRECTORY`, then
+/// `$XDG_DATA_HOME/attic`.
+fn resolve_state_dir<E>(state_dir: Option<&Path>, env: E) -> Result<PathBuf>
+where
+    E: Fn(&str) -> Option<OsString>,
+{
+    if let Some(path) = state_dir {
+        return Ok(path.to_owned());
+    }
+
+    if let Some(dir) = systemd_directory(&env, ENV_STATE_DIRECTORY) {
+        return Ok(dir);
+    }
+
+    config::get_xdg_data_path()
+}
+
+/// Returns the first directory in a systemd directory variable.
+///
+/// systemd separates multiple directories with colons.
+fn systemd_directory<E>(env: &E, key: &str) -> Option<PathBuf>
+where
+    E: Fn(&str) -> Option<OsString>,
+{
+    let value = env(key)?;
+    let first = value.to_str()?.split(':').next()?;
+
+    if first.is_empty() {
+        None
+    } else {
+        Some(PathBuf::from(first))
+    }
+}
+
+pub(crate) fn env_var(key: &str) -> Option<OsString> {
+    std::env::var_os(key)
+}
+
+/// Returns whether a config already exists at the path.
+///
+/// Unlike `Path::exists`, this doesn't treat a config we aren't
+/// allowed to see as missing.
+fn config_exists(path: &Path) -> Result<bool> {
+    match std::fs::metadata(path) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Err(anyhow!(
+            "Cannot access the configuration at {}: permission denied. \
+            If it was generated by another user, make it accessible to the user running atticd.",
+            path.display()
+        )),
+        Err(e) => Err(e)
+            .with_context(|| format!("Failed to access the configuration at {}", path.display())),
+    }
+}
+
+/// Writes a file that's only readable by the current user.
+async fn write_private_file(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .mode(0o600)
+        .open(path)
+        .await
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+
+    file.write_all(contents).await?;
+    file.flush().await?;
+
+    Ok(())
+}
+
+/// Runs the initial setup if there is no config at `config_path`.
+pub async fn run_oobe(config_path: &Path, options: &OobeOptions) -> Result<()> {
+    if config_exists(config_path)? {
         return Ok(());
     }
 
-    let data_path = config::get_xdg_data_path()?;
+    let state_dir = resolve_state_dir(options.state_dir.as_deref(), env_var)?;
+    fs::create_dir_all(&state_dir)
+        .await
+        .with_context(|| format!("Failed to create {}", state_dir.display()))?;
+
+    if let Some(config_dir) = config_path.parent() {
+        fs::create_dir_all(config_dir)
+            .await
+            .with_context(|| format!("Failed to create {}", config_dir.display()))?;
+    }
 
     // Generate a simple config
-    let database_path = data_path.join("server.db");
+    let database_path = state_dir.join("server.db");
     let database_url = format!("sqlite://{}", database_path.to_str().unwrap());
-    OpenOptions::new()
-        .create(true)
-        .write(true)
-        .open(&database_path)
-        .await?;
+    write_private_file(&database_path, &[]).await?;
 
-    let storage_path = data_path.join("storage");
+    let storage_path = state_dir.join("storage");
     fs::create_dir_all(&storage_path).await?;
+    fs::set_permissions(&storage_path, std::fs::Permissions::from_mode(0o700)).await?;
 
     let rs256_secret_base64 = {
         let mut rng = rand::thread_rng();
@@ -57,7 +188,7 @@ pub async fn run_oobe() -> Result<()> {
         .replace("%storage_path%", storage_path.to_str().unwrap())
         .replace("%token_rs256_secret_base64%", &rs256_secret_base64);
 
-    fs::write(&config_path, config_content.as_bytes()).await?;
+    write_private_file(config_path, config_content.as_bytes()).await?;
 
     // Generate a JWT token
     let root_token = {
@@ -81,10 +212,24 @@ pub async fn run_oobe() -> Result<()> {
     eprintln!("-----------------");
     eprintln!("Welcome to Attic!");
     eprintln!();
-    eprintln!("A simple setup using SQLite and local storage has been configured for you in:");
+    eprintln!("A simple setup using SQLite and local storage has been configured for you:");
     eprintln!();
-    eprintln!("    {}", config_path.to_str().unwrap());
+    eprintln!("    Config:   {}", config_path.display());
+    eprintln!("    Database: {}", database_path.display());
+    eprintln!("    Storage:  {}", storage_path.display());
     eprintln!();
+
+    let owned_by_root = fs::metadata(config_path).await?.uid() == 0;
+    if owned_by_root {
+        eprintln!("These files are only accessible by root. If atticd will run as another");
+        eprintln!("user (such as the `atticd` system user), give it access with:");
+        eprintln!();
+        eprintln!("    chown atticd: {}", config_path.display());
+        eprintln!("    chown -R atticd: {}", state_dir.display());
+        eprintln!("    chmod 0700 {}", state_dir.display());
+        eprintln!();
+    }
+
     eprintln!("Run the following command to log into this server:");
     eprintln!();
     eprintln!("    attic login local http://localhost:8080 {root_token}");
@@ -99,3 +244,133 @@ pub async fn run_oobe() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::fs::Permissions;
+
+    use uuid::Uuid;
+
+    fn make_env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<OsString> {
+        let vars: HashMap<String, OsString> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), OsString::from(v)))
+            .collect();
+
+        move |key| vars.get(key).cloned()
+    }
+
+    fn make_temp_dir() -> PathBuf {
+        let path = std::env::temp_dir().join(format!("attic-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    /// Returns whether permissions are enforced for us.
+    ///
+    /// They aren't when the tests run as root.
+    fn permissions_enforced(dir: &Path) -> bool {
+        let probe = dir.join("probe");
+        std::fs::write(&probe, "").unwrap();
+        std::fs::set_permissions(&probe, Permissions::from_mode(0o000)).unwrap();
+        let enforced = std::fs::read(&probe).is_err();
+        std::fs::remove_file(&probe).unwrap();
+
+        if !enforced {
+            eprintln!("Running as root, skipping permission checks");
+        }
+
+        enforced
+    }
+
+    #[test]
+    fn test_resolve_config_path() {
+        let systemd = make_env(&[(ENV_CONFIGURATION_DIRECTORY, "/etc/atticd:/etc/other")]);
+        let override_path = Path::new("/srv/attic/server.toml");
+
+        assert_eq!(
+            override_path,
+            resolve_config_path(Some(override_path), &systemd).unwrap()
+        );
+        assert_eq!(
+            Path::new("/etc/atticd/server.toml"),
+            resolve_config_path(None, &systemd).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_resolve_state_dir() {
+        let systemd = make_env(&[(ENV_STATE_DIRECTORY, "/var/lib/atticd")]);
+        let override_path = Path::new("/srv/attic");
+
+        assert_eq!(
+            override_path,
+            resolve_state_dir(Some(override_path), &systemd).unwrap()
+        );
+        assert_eq!(
+            Path::new("/var/lib/atticd"),
+            resolve_state_dir(None, &systemd).unwrap()
+        );
+
+        // Empty values are ignored
+        assert_eq!(
+            None,
+            systemd_directory(&make_env(&[(ENV_STATE_DIRECTORY, "")]), ENV_STATE_DIRECTORY)
+        );
+        assert_eq!(None, systemd_directory(&make_env(&[]), ENV_STATE_DIRECTORY));
+    }
+
+    #[tokio::test]
+    async fn test_write_private_file() {
+        let dir = make_temp_dir();
+        let path = dir.join("server.toml");
+
+        write_private_file(&path, b"secret").await.unwrap();
+        assert_eq!(b"secret".to_vec(), std::fs::read(&path).unwrap());
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+
+        // Existing files are never overwritten
+        assert!(write_private_file(&path, b"other").await.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_config() {
+        let dir = make_temp_dir();
+        if !permissions_enforced(&dir) {
+            std::fs::remove_dir_all(&dir).unwrap();
+            return;
+        }
+
+        let config_dir = dir.join("config");
+        let config_path = config_dir.join("server.toml");
+        std::fs::create_dir(&config_dir).unwrap();
+        std::fs::write(&config_path, "").unwrap();
+
+        // Readable directory, unreadable file
+        std::fs::set_permissions(&config_path, Permissions::from_mode(0o000)).unwrap();
+        let e = config::load_config(Some(&config_path), None)
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("permission denied"), "{}", e);
+
+        // Inaccessible directory, which previously looked like a missing config
+        std::fs::set_permissions(&config_dir, Permissions::from_mode(0o000)).unwrap();
+        let options = OobeOptions {
+            state_dir: Some(dir.join("state")),
+            config_out: None,
+        };
+        let e = run_oobe(&config_path, &options).await.unwrap_err();
+        assert!(e.to_string().contains("permission denied"), "{}", e);
+        assert!(!dir.join("state").exists());
+
+        std::fs::set_permissions(&config_dir, Permissions::from_mode(0o700)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
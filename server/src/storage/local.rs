@@ -1,16 +1,17 @@
 //! Local file storage.
 
 use std::ffi::OsStr;
+use std::io::SeekFrom;
 use std::os::unix::ffi::OsStrExt;
-use std::path::Path;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use tokio::fs::{self, File};
-use tokio::io::{self, AsyncRead};
+use tokio::io::{self, AsyncRead, AsyncSeekExt};
 
-use super::{Download, RemoteFile, StorageBackend};
+use super::{Download, FileListPage, RemoteFile, StorageBackend, StoredFile};
 use crate::error::{ErrorKind, ServerError, ServerResult};
 
 #[derive(Debug)]
@@ -22,6 +23,27 @@ pub struct LocalBackend {
 pub struct LocalStorageConfig {
     /// The directory to store all files under.
     path: PathBuf,
+
+    /// How files are distributed into subdirectories.
+    #[serde(default)]
+    sharding: LocalSharding,
+}
+
+/// Layout of files under the storage directory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LocalSharding {
+    /// `a/ab/abcdef...`
+    ///
+    /// This is the layout that existing storage directories are upgraded to.
+    #[default]
+    Prefix,
+
+    /// `ab/cd/abcdef...`
+    ///
+    /// This spreads files over 65536 directories instead of 256,
+    /// which keeps directories small with millions of chunks.
+    Nested,
 }
 
 /// Reference to a file in local storage.
@@ -31,6 +53,13 @@ pub struct LocalStorageConfig {
 pub struct LocalRemoteFile {
     /// Name of the file.
     pub name: String,
+
+    /// Path of the file relative to the storage directory.
+    ///
+    /// References created before sharding was configurable don't
+    /// have this, and are stored in the `Prefix` layout.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
 }
 
 async fn read_version(storage_path: &Path) -> ServerResult<u32> {
@@ -118,10 +147,72 @@ impl LocalBackend {
         Ok(Self { config })
     }
 
-    fn get_path(&self, p: &str) -> PathBuf {
-        let level1 = &p[0..1];
-        let level2 = &p[0..2];
-        self.config.path.join(level1).join(level2).join(p)
+    /// Returns the absolute path of a file named with the current configuration.
+    fn get_path(&self, name: &str) -> PathBuf {
+        self.config
+            .path
+            .join(self.config.sharding.relative_path(name))
+    }
+
+    /// Returns the absolute path of a file from a database reference.
+    fn get_db_path(&self, file: &RemoteFile) -> ServerResult<PathBuf> {
+        let file = if let RemoteFile::Local(file) = file {
+            file
+        } else {
+            return Err(ErrorKind::StorageError(anyhow::anyhow!(
+                "Does not understand the remote file reference"
+            ))
+            .into());
+        };
+
+        let relative = match &file.path {
+            Some(path) => PathBuf::from(path),
+            None => LocalSharding::Prefix.relative_path(&file.name),
+        };
+
+        if relative.is_absolute()
+            || relative
+                .components()
+                .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            return Err(ErrorKind::StorageError(anyhow::anyhow!(
+                "Invalid path in the remote file reference"
+            ))
+            .into());
+        }
+
+        Ok(self.config.path.join(relative))
+    }
+
+    fn make_remote_file(&self, name: String) -> RemoteFile {
+        let path = self
+            .config
+            .sharding
+            .relative_path(&name)
+            .to_string_lossy()
+            .into_owned();
+
+        RemoteFile::Local(LocalRemoteFile {
+            name,
+            path: Some(path),
+        })
+    }
+}
+
+impl LocalSharding {
+    /// Returns the path of a file relative to the storage directory.
+    ///
+    /// Names too short to be sharded are stored at the top level.
+    fn relative_path(&self, name: &str) -> PathBuf {
+        let (level1, level2) = match self {
+            Self::Prefix => (name.get(0..1), name.get(0..2)),
+            Self::Nested => (name.get(0..2), name.get(2..4)),
+        };
+
+        match (level1, level2) {
+            (Some(level1), Some(level2)) => [level1, level2, name].iter().collect(),
+            _ => PathBuf::from(name),
+        }
     }
 }
 
@@ -142,10 +233,10 @@ impl StorageBackend for LocalBackend {
                     e
                 ))
             })?;
-        let mut file = File::create(self.get_path(&name)).await.map_err(|e| {
+        let mut file = File::create(&path).await.map_err(|e| {
             ErrorKind::StorageError(anyhow::anyhow!(
                 "Failed to create file {}: {}",
-                self.get_path(&name).display(),
+                path.display(),
                 e
             ))
         })?;
@@ -154,7 +245,7 @@ impl StorageBackend for LocalBackend {
             .await
             .map_err(ServerError::storage_error)?;
 
-        Ok(RemoteFile::Local(LocalRemoteFile { name }))
+        Ok(self.make_remote_file(name))
     }
 
     async fn delete_file(&self, name: String) -> ServerResult<()> {
@@ -166,16 +257,9 @@ impl StorageBackend for LocalBackend {
     }
 
     async fn delete_file_db(&self, file: &RemoteFile) -> ServerResult<()> {
-        let file = if let RemoteFile::Local(file) = file {
-            file
-        } else {
-            return Err(ErrorKind::StorageError(anyhow::anyhow!(
-                "Does not understand the remote file reference"
-            ))
-            .into());
-        };
+        let path = self.get_db_path(file)?;
 
-        fs::remove_file(self.get_path(&file.name))
+        fs::remove_file(&path)
             .await
             .map_err(ServerError::storage_error)?;
 
@@ -195,23 +279,264 @@ impl StorageBackend for LocalBackend {
         file: &RemoteFile,
         _prefer_stream: bool,
     ) -> ServerResult<Download> {
-        let file = if let RemoteFile::Local(file) = file {
-            file
-        } else {
-            return Err(ErrorKind::StorageError(anyhow::anyhow!(
-                "Does not understand the remote file reference"
-            ))
-            .into());
-        };
+        let path = self.get_db_path(file)?;
 
-        let file = File::open(self.get_path(&file.name))
+        let file = File::open(&path)
             .await
             .map_err(ServerError::storage_error)?;
 
         Ok(Download::AsyncRead(Box::new(file)))
     }
 
+    async fn download_file_db_from(
+        &self,
+        file: &RemoteFile,
+        offset: u64,
+    ) -> ServerResult<Box<dyn AsyncRead + Unpin + Send>> {
+        let path = self.get_db_path(file)?;
+
+        let mut file = File::open(&path)
+            .await
+            .map_err(ServerError::storage_error)?;
+
+        if offset != 0 {
+            file.seek(SeekFrom::Start(offset))
+                .await
+                .map_err(ServerError::storage_error)?;
+        }
+
+        Ok(Box::new(file))
+    }
+
+    async fn rename_file(&self, from: String, to: String) -> ServerResult<RemoteFile> {
+        let to_path = self.get_path(&to);
+        fs::create_dir_all(to_path.parent().unwrap())
+            .await
+            .map_err(ServerError::storage_error)?;
+
+        fs::rename(self.get_path(&from), &to_path)
+            .await
+            .map_err(ServerError::storage_error)?;
+
+        Ok(self.make_remote_file(to))
+    }
+
     async fn make_db_reference(&self, name: String) -> ServerResult<RemoteFile> {
-        Ok(RemoteFile::Local(LocalRemoteFile { name }))
+        Ok(self.make_remote_file(name))
+    }
+
+    async fn list_files(&self, continuation: Option<String>) -> ServerResult<FileListPage> {
+        // Each page covers one entry at the top level of the storage
+        // directory, which is a shard directory in both layouts.
+        // The continuation token is the name of the last entry.
+        let mut entries = Vec::new();
+        let mut dir = fs::read_dir(&self.config.path)
+            .await
+            .map_err(ServerError::storage_error)?;
+        while let Some(entry) = dir.next_entry().await.map_err(ServerError::storage_error)? {
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+
+            let seen = continuation.as_ref().is_some_and(|last| name <= *last);
+            if name != "VERSION" && !seen {
+                entries.push(name);
+            }
+        }
+        entries.sort();
+
+        let Some(first) = entries.first() else {
+            return Ok(FileListPage::default());
+        };
+
+        let mut files = Vec::new();
+        let mut pending = vec![self.config.path.join(first)];
+        while let Some(path) = pending.pop() {
+            let metadata = fs::metadata(&path)
+                .await
+                .map_err(ServerError::storage_error)?;
+
+            if metadata.is_dir() {
+                let mut dir = fs::read_dir(&path)
+                    .await
+                    .map_err(ServerError::storage_error)?;
+                while let Some(entry) =
+                    dir.next_entry().await.map_err(ServerError::storage_error)?
+                {
+                    pending.push(entry.path());
+                }
+            } else if let Some(name) = path.file_name().and_then(OsStr::to_str) {
+                let relative = path.strip_prefix(&self.config.path).unwrap();
+                files.push(StoredFile {
+                    name: name.to_owned(),
+                    file: RemoteFile::Local(LocalRemoteFile {
+                        name: name.to_owned(),
+                        path: Some(relative.to_string_lossy().into_owned()),
+                    }),
+                    size: metadata.len(),
+                    modified: metadata.modified().ok().map(DateTime::<Utc>::from),
+                });
+            }
+        }
+
+        Ok(FileListPage {
+            files,
+            next: (entries.len() > 1).then(|| first.clone()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use uuid::Uuid;
+
+    async fn make_backend(sharding: LocalSharding) -> LocalBackend {
+        let path = std::env::temp_dir().join(format!("attic-test-{}", Uuid::new_v4()));
+        LocalBackend::new(LocalStorageConfig { path, sharding })
+            .await
+            .unwrap()
+    }
+
+    async fn read(backend: &LocalBackend, file: &RemoteFile) -> Vec<u8> {
+        let Download::AsyncRead(mut stream) = backend.download_file_db(file, true).await.unwrap()
+        else {
+            panic!("Local backend returned a URL");
+        };
+
+        let mut contents = Vec::new();
+        io::copy(&mut stream, &mut contents).await.unwrap();
+        contents
+    }
+
+    #[test]
+    fn test_relative_path() {
+        assert_eq!(
+            PathBuf::from("a/ab/abcdef.chunk"),
+            LocalSharding::Prefix.relative_path("abcdef.chunk")
+        );
+        assert_eq!(
+            PathBuf::from("ab/cd/abcdef.chunk"),
+            LocalSharding::Nested.relative_path("abcdef.chunk")
+        );
+        assert_eq!(
+            PathBuf::from("abc"),
+            LocalSharding::Nested.relative_path("abc")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sharding() {
+        let backend = make_backend(LocalSharding::Nested).await;
+        let name = "abcdef.chunk".to_string();
+
+        let reference = backend.make_db_reference(name.clone()).await.unwrap();
+        let uploaded = backend
+            .upload_file(name.clone(), &mut &b"hello"[..])
+            .await
+            .unwrap();
+        assert_eq!(reference, uploaded);
+        assert!(backend.config.path.join("ab/cd/abcdef.chunk").is_file());
+        assert_eq!(b"hello".to_vec(), read(&backend, &reference).await);
+
+        // References record the actual path, so they keep working
+        // after the layout is changed
+        let prefix = LocalBackend {
+            config: LocalStorageConfig {
+                path: backend.config.path.clone(),
+                sharding: LocalSharding::Prefix,
+            },
+        };
+        assert_eq!(b"hello".to_vec(), read(&prefix, &reference).await);
+
+        // References without a path use the original layout
+        let legacy = RemoteFile::Local(LocalRemoteFile {
+            name: "012345.chunk".to_string(),
+            path: None,
+        });
+        prefix
+            .upload_file("012345.chunk".to_string(), &mut &b"world"[..])
+            .await
+            .unwrap();
+        assert!(backend.config.path.join("0/01/012345.chunk").is_file());
+        assert_eq!(b"world".to_vec(), read(&backend, &legacy).await);
+
+        backend.delete_file_db(&reference).await.unwrap();
+        assert!(!backend.config.path.join("ab/cd/abcdef.chunk").exists());
+
+        let escaping = RemoteFile::Local(LocalRemoteFile {
+            name: "escaping".to_string(),
+            path: Some("../escaping".to_string()),
+        });
+        assert!(backend.download_file_db(&escaping, true).await.is_err());
+
+        fs::remove_dir_all(&backend.config.path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_list_files() {
+        let backend = make_backend(LocalSharding::Nested).await;
+        let prefix = LocalBackend {
+            config: LocalStorageConfig {
+                path: backend.config.path.clone(),
+                sharding: LocalSharding::Prefix,
+            },
+        };
+
+        for name in ["abcdef.chunk", "abcd00.chunk", "ef0123.chunk", "x"] {
+            backend
+                .upload_file(name.to_string(), &mut &b"hello"[..])
+                .await
+                .unwrap();
+        }
+        prefix
+            .upload_file("012345.chunk".to_string(), &mut &b"world!"[..])
+            .await
+            .unwrap();
+
+        let mut pages = 0;
+        let mut files = Vec::new();
+        let mut continuation = None;
+        loop {
+            let page = backend.list_files(continuation).await.unwrap();
+            pages += 1;
+            files.extend(page.files);
+            match page.next {
+                Some(next) => continuation = Some(next),
+                None => break,
+            }
+        }
+
+        // One page for each of `0`, `ab`, `ef` and `x`
+        assert_eq!(4, pages);
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        let names: Vec<_> = files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(
+            vec![
+                "012345.chunk",
+                "abcd00.chunk",
+                "abcdef.chunk",
+                "ef0123.chunk",
+                "x"
+            ],
+            names
+        );
+
+        // Listed files can be deleted wherever they are
+        let legacy = &files[0];
+        assert_eq!(6, legacy.size);
+        assert!(legacy.modified.is_some());
+        assert_eq!(
+            prefix
+                .make_db_reference("012345.chunk".to_string())
+                .await
+                .unwrap(),
+            legacy.file
+        );
+        backend.delete_file_db(&legacy.file).await.unwrap();
+        assert!(!backend.config.path.join("0/01/012345.chunk").exists());
+
+        fs::remove_dir_all(&backend.config.path).await.unwrap();
     }
 }
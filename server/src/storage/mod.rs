@@ -1,15 +1,22 @@
 //! Remote file storage.
 
 mod local;
+mod retry;
 mod s3;
+mod webdav;
 
+use std::fmt;
+
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use tokio::io::AsyncRead;
 
 use crate::error::ServerResult;
 
 pub(crate) use self::local::{LocalBackend, LocalRemoteFile, LocalStorageConfig};
+pub(crate) use self::retry::{RetryConfig, Retryable};
 pub(crate) use self::s3::{S3Backend, S3RemoteFile, S3StorageConfig};
+pub(crate) use self::webdav::{WebDavBackend, WebDavStorageConfig};
 
 /// Reference to a location where a NAR is stored.
 ///
@@ -40,6 +47,49 @@ pub enum Download {
     AsyncRead(Box<dyn AsyncRead + Unpin + Send>),
 }
 
+/// A file does not exist in the storage backend.
+///
+/// Backends return this when a file is definitely missing, as opposed
+/// to errors that may be transient.
+#[derive(Debug)]
+pub struct FileNotFound(pub String);
+
+impl fmt::Display for FileNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "File {} does not exist in the storage backend", self.0)
+    }
+}
+
+impl std::error::Error for FileNotFound {}
+
+/// A file in the storage backend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredFile {
+    /// Name of the file, as passed to `upload_file`.
+    pub name: String,
+
+    /// Database reference to the file where it was found.
+    pub file: RemoteFile,
+
+    /// Size of the file in bytes.
+    pub size: u64,
+
+    /// When the file was last modified, if known.
+    pub modified: Option<DateTime<Utc>>,
+}
+
+/// A page of files in the storage backend.
+#[derive(Debug, Clone, Default)]
+pub struct FileListPage {
+    /// Files in this page.
+    pub files: Vec<StoredFile>,
+
+    /// Token to pass to `list_files` for the next page.
+    ///
+    /// This is `None` on the last page.
+    pub next: Option<String>,
+}
+
 // TODO: Maybe make RemoteFile the one true reference instead of having two sets of APIs?
 /// A storage backend.
 #[async_trait::async_trait]
@@ -67,8 +117,26 @@ pub trait StorageBackend: Send + Sync + std::fmt::Debug {
         prefer_stream: bool,
     ) -> ServerResult<Download>;
 
+    /// Streams a file using a database reference, starting at an offset.
+    ///
+    /// Unlike `download_file_db`, this never returns a URL.
+    async fn download_file_db_from(
+        &self,
+        file: &RemoteFile,
+        offset: u64,
+    ) -> ServerResult<Box<dyn AsyncRead + Unpin + Send>>;
+
+    /// Renames a file, replacing any existing file with the new name.
+    async fn rename_file(&self, from: String, to: String) -> ServerResult<RemoteFile>;
+
     /// Creates a database reference for a file.
     async fn make_db_reference(&self, name: String) -> ServerResult<RemoteFile>;
+
+    /// Lists files in the storage backend, a page at a time.
+    ///
+    /// Pass `None` for the first page, then the `next` token of the
+    /// previous page. Files may be listed in any order.
+    async fn list_files(&self, continuation: Option<String>) -> ServerResult<FileListPage>;
 }
 
 /// Reference to an HTTP link from which the file can be downloaded.
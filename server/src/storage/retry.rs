@@ -0,0 +1,188 @@
+//! Retries of storage operations.
+//!
+//! Operations that fail with transient errors (server errors,
+//! throttling, dropped connections) are retried with exponential
+//! backoff and jitter. Each backend decides which of its errors
+//! are transient by implementing [`Retryable`].
+
+use std::fmt::Display;
+use std::future::Future;
+use std::time::Duration;
+
+use rand::Rng;
+use serde::Deserialize;
+
+/// The longest delay between two attempts.
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Retry policy of storage operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct RetryConfig {
+    /// Maximum number of times to retry a failed operation.
+    ///
+    /// If 0, operations are never retried.
+    #[serde(rename = "max-retries")]
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+
+    /// The delay before the first retry.
+    ///
+    /// The delay doubles with each subsequent retry, with some
+    /// random jitter so concurrent operations don't retry in
+    /// lockstep.
+    #[serde(rename = "base-delay", with = "humantime_serde")]
+    #[serde(default = "default_base_delay")]
+    pub base_delay: Duration,
+}
+
+/// An error that may go away if the operation is retried.
+pub(crate) trait Retryable {
+    /// Returns whether the error is transient.
+    fn is_retryable(&self) -> bool;
+}
+
+impl RetryConfig {
+    /// Returns the delay before a retry.
+    ///
+    /// `attempt` is the number of attempts that have failed so far.
+    fn delay(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .base_delay
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(MAX_DELAY);
+
+        // Equal jitter: Somewhere between half and all of the ceiling
+        let half = ceiling / 2;
+        half + half.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+
+    /// Runs an operation, retrying it on transient errors.
+    ///
+    /// The last error is returned once the retries are exhausted.
+    pub(crate) async fn retry<T, E, F, Fut>(&self, operation: &str, mut f: F) -> Result<T, E>
+    where
+        E: Retryable + Display,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut attempt = 0;
+
+        loop {
+            match f().await {
+                Ok(value) => return Ok(value),
+                Err(e) if attempt < self.max_retries && e.is_retryable() => {
+                    attempt += 1;
+
+                    let delay = self.delay(attempt);
+                    tracing::warn!(
+                        "{} failed, retrying in {:?} ({}/{}): {}",
+                        operation,
+                        delay,
+                        attempt,
+                        self.max_retries,
+                        e
+                    );
+
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: default_max_retries(),
+            base_delay: default_base_delay(),
+        }
+    }
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_base_delay() -> Duration {
+    Duration::from_millis(200)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[derive(Debug)]
+    struct TestError(bool);
+
+    impl Display for TestError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "transient: {}", self.0)
+        }
+    }
+
+    impl Retryable for TestError {
+        fn is_retryable(&self) -> bool {
+            self.0
+        }
+    }
+
+    fn policy(max_retries: u32) -> RetryConfig {
+        RetryConfig {
+            max_retries,
+            base_delay: Duration::from_millis(1),
+        }
+    }
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryConfig {
+            max_retries: 100,
+            base_delay: Duration::from_secs(1),
+        };
+
+        for _ in 0..100 {
+            let delay = policy.delay(1);
+            assert!(delay >= Duration::from_millis(500) && delay <= Duration::from_secs(1));
+
+            let delay = policy.delay(3);
+            assert!(delay >= Duration::from_secs(2) && delay <= Duration::from_secs(4));
+
+            let delay = policy.delay(100);
+            assert!(delay >= MAX_DELAY / 2 && delay <= MAX_DELAY);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let attempts = AtomicU32::new(0);
+        let op = |fail_times: u32, transient: bool| {
+            attempts.store(0, Ordering::SeqCst);
+            let attempts = &attempts;
+            move || async move {
+                if attempts.fetch_add(1, Ordering::SeqCst) < fail_times {
+                    Err(TestError(transient))
+                } else {
+                    Ok(())
+                }
+            }
+        };
+
+        // Transient errors are retried
+        policy(3).retry("test", op(3, true)).await.unwrap();
+        assert_eq!(4, attempts.load(Ordering::SeqCst));
+
+        // The last error is returned once retries are exhausted
+        assert!(policy(3).retry("test", op(4, true)).await.is_err());
+        assert_eq!(4, attempts.load(Ordering::SeqCst));
+
+        // Permanent errors are returned immediately
+        assert!(policy(3).retry("test", op(1, false)).await.is_err());
+        assert_eq!(1, attempts.load(Ordering::SeqCst));
+
+        assert!(policy(0).retry("test", op(1, true)).await.is_err());
+        assert_eq!(1, attempts.load(Ordering::SeqCst));
+    }
+}
@@ -6,18 +6,22 @@ use async_trait::async_trait;
 use aws_config::BehaviorVersion;
 use aws_sdk_s3::{
     config::Builder as S3ConfigBuilder,
-    config::{Credentials, Region},
-    operation::get_object::builders::GetObjectFluentBuilder,
+    config::{retry::RetryConfig as SdkRetryConfig, Credentials, Region},
+    error::{ProvideErrorMetadata, SdkError},
     presigning::PresigningConfig,
     types::{CompletedMultipartUpload, CompletedPart},
     Client,
 };
 use bytes::BytesMut;
+use chrono::DateTime;
 use futures::future::join_all;
 use serde::{Deserialize, Serialize};
 use tokio::io::AsyncRead;
 
-use super::{Download, RemoteFile, StorageBackend};
+use super::{
+    Download, FileListPage, FileNotFound, RemoteFile, RetryConfig, Retryable, StorageBackend,
+    StoredFile,
+};
 use crate::error::{ErrorKind, ServerError, ServerResult};
 use attic::stream::read_chunk_async;
 use attic::util::Finally;
@@ -51,6 +55,44 @@ pub struct S3StorageConfig {
     /// If not specified, it's read from the `AWS_ACCESS_KEY_ID` and
     /// `AWS_SECRET_ACCESS_KEY` environment variables.
     credentials: Option<S3CredentialsConfig>,
+
+    /// Retry policy of S3 requests.
+    #[serde(default)]
+    retry: RetryConfig,
+
+    /// How long presigned download URLs stay valid.
+    ///
+    /// Clients on slow links need this to cover the entire download.
+    #[serde(rename = "presigned-expiry", with = "humantime_serde")]
+    #[serde(default = "default_presigned_expiry")]
+    presigned_expiry: Duration,
+
+    /// How to serve downloads to clients.
+    #[serde(rename = "serve-mode")]
+    #[serde(default)]
+    serve_mode: S3ServeMode,
+
+    /// The size below which files are streamed in the `auto` serve mode, in bytes.
+    #[serde(rename = "stream-threshold")]
+    #[serde(default = "default_stream_threshold")]
+    stream_threshold: u64,
+}
+
+/// How to serve downloads to clients.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum S3ServeMode {
+    /// Redirect clients to presigned URLs.
+    #[default]
+    Redirect,
+
+    /// Stream files through the server.
+    Stream,
+
+    /// Stream small files and redirect clients to large ones.
+    ///
+    /// Small files aren't worth the extra roundtrip of a redirect.
+    Auto,
 }
 
 /// S3 credential configuration.
@@ -80,6 +122,11 @@ pub struct S3RemoteFile {
 
 impl S3Backend {
     pub async fn new(config: S3StorageConfig) -> ServerResult<Self> {
+        // Reject bad expiries early rather than on every download
+        PresigningConfig::expires_in(config.presigned_expiry).map_err(|e| {
+            ErrorKind::StorageError(anyhow::anyhow!("Invalid presigned-expiry: {}", e))
+        })?;
+
         let s3_config = Self::config_builder(&config)
             .await?
             .region(Region::new(config.region.to_owned()))
@@ -93,7 +140,9 @@ impl S3Backend {
 
     async fn config_builder(config: &S3StorageConfig) -> ServerResult<S3ConfigBuilder> {
         let shared_config = aws_config::load_defaults(BehaviorVersion::v2024_03_28()).await;
-        let mut builder = S3ConfigBuilder::from(&shared_config);
+        // We retry requests ourselves according to the configured policy
+        let mut builder =
+            S3ConfigBuilder::from(&shared_config).retry_config(SdkRetryConfig::disabled());
 
         if let Some(credentials) = &config.credentials {
             builder = builder.credentials_provider(Credentials::new(
@@ -142,16 +191,42 @@ impl S3Backend {
 
     async fn get_download(
         &self,
-        req: GetObjectFluentBuilder,
+        client: &Client,
+        bucket: &str,
+        key: &str,
         prefer_stream: bool,
     ) -> ServerResult<Download> {
-        if prefer_stream {
-            let output = req.send().await.map_err(ServerError::storage_error)?;
+        let stream = prefer_stream
+            || match self.config.serve_mode {
+                S3ServeMode::Redirect => false,
+                S3ServeMode::Stream => true,
+                S3ServeMode::Auto => {
+                    let head = self
+                        .config
+                        .retry
+                        .retry("head_object", || {
+                            client.head_object().bucket(bucket).key(key).send()
+                        })
+                        .await
+                        .map_err(|e| download_error(key, e))?;
+
+                    self.config.is_small(head.content_length())
+                }
+            };
+
+        let req = client.get_object().bucket(bucket).key(key);
+
+        if stream {
+            let output = self
+                .config
+                .retry
+                .retry("get_object", || req.clone().send())
+                .await
+                .map_err(|e| download_error(key, e))?;
 
             Ok(Download::AsyncRead(Box::new(output.body.into_async_read())))
         } else {
-            // FIXME: Configurable expiration
-            let presign_config = PresigningConfig::expires_in(Duration::from_secs(600))
+            let presign_config = PresigningConfig::expires_in(self.config.presigned_expiry)
                 .map_err(ServerError::storage_error)?;
 
             let presigned = req
@@ -179,12 +254,16 @@ impl StorageBackend for S3Backend {
         if first_chunk.len() < CHUNK_SIZE {
             // do a normal PutObject
             let put_object = self
-                .client
-                .put_object()
-                .bucket(&self.config.bucket)
-                .key(&name)
-                .body(first_chunk.into())
-                .send()
+                .config
+                .retry
+                .retry("put_object", || {
+                    self.client
+                        .put_object()
+                        .bucket(&self.config.bucket)
+                        .key(&name)
+                        .body(first_chunk.clone().into())
+                        .send()
+                })
                 .await
                 .map_err(ServerError::storage_error)?;
 
@@ -198,31 +277,40 @@ impl StorageBackend for S3Backend {
         }
 
         let multipart = self
-            .client
-            .create_multipart_upload()
-            .bucket(&self.config.bucket)
-            .key(&name)
-            .send()
+            .config
+            .retry
+            .retry("create_multipart_upload", || {
+                self.client
+                    .create_multipart_upload()
+                    .bucket(&self.config.bucket)
+                    .key(&name)
+                    .send()
+            })
             .await
             .map_err(ServerError::storage_error)?;
 
         let upload_id = multipart.upload_id().unwrap();
 
+        // This also runs when parts fail after exhausting their retries
         let cleanup = Finally::new({
             let bucket = self.config.bucket.clone();
             let client = self.client.clone();
+            let retry = self.config.retry;
             let upload_id = upload_id.to_owned();
             let name = name.clone();
 
             async move {
                 tracing::warn!("Upload was interrupted - Aborting multipart upload");
 
-                let r = client
-                    .abort_multipart_upload()
-                    .bucket(bucket)
-                    .key(name)
-                    .upload_id(upload_id)
-                    .send()
+                let r = retry
+                    .retry("abort_multipart_upload", || {
+                        client
+                            .abort_multipart_upload()
+                            .bucket(&bucket)
+                            .key(&name)
+                            .upload_id(&upload_id)
+                            .send()
+                    })
                     .await;
 
                 if let Err(e) = r {
@@ -249,16 +337,27 @@ impl StorageBackend for S3Backend {
                 break;
             }
 
-            let client = self.client.clone();
             let fut = tokio::task::spawn({
-                client
-                    .upload_part()
-                    .bucket(&self.config.bucket)
-                    .key(&name)
-                    .upload_id(upload_id)
-                    .part_number(part_number)
-                    .body(chunk.clone().into())
-                    .send()
+                let client = self.client.clone();
+                let retry = self.config.retry;
+                let bucket = self.config.bucket.clone();
+                let name = name.clone();
+                let upload_id = upload_id.to_owned();
+
+                async move {
+                    retry
+                        .retry("upload_part", || {
+                            client
+                                .upload_part()
+                                .bucket(&bucket)
+                                .key(&name)
+                                .upload_id(&upload_id)
+                                .part_number(part_number)
+                                .body(chunk.clone().into())
+                                .send()
+                        })
+                        .await
+                }
             });
 
             parts.push(fut);
@@ -291,13 +390,17 @@ impl StorageBackend for S3Backend {
             .build();
 
         let completion = self
-            .client
-            .complete_multipart_upload()
-            .bucket(&self.config.bucket)
-            .key(&name)
-            .upload_id(upload_id)
-            .multipart_upload(completed_multipart_upload)
-            .send()
+            .config
+            .retry
+            .retry("complete_multipart_upload", || {
+                self.client
+                    .complete_multipart_upload()
+                    .bucket(&self.config.bucket)
+                    .key(&name)
+                    .upload_id(upload_id)
+                    .multipart_upload(completed_multipart_upload.clone())
+                    .send()
+            })
             .await
             .map_err(ServerError::storage_error)?;
 
@@ -314,11 +417,15 @@ impl StorageBackend for S3Backend {
 
     async fn delete_file(&self, name: String) -> ServerResult<()> {
         let deletion = self
-            .client
-            .delete_object()
-            .bucket(&self.config.bucket)
-            .key(&name)
-            .send()
+            .config
+            .retry
+            .retry("delete_object", || {
+                self.client
+                    .delete_object()
+                    .bucket(&self.config.bucket)
+                    .key(&name)
+                    .send()
+            })
             .await
             .map_err(ServerError::storage_error)?;
 
@@ -330,11 +437,16 @@ impl StorageBackend for S3Backend {
     async fn delete_file_db(&self, file: &RemoteFile) -> ServerResult<()> {
         let (client, file) = self.get_client_from_db_ref(file).await?;
 
-        let deletion = client
-            .delete_object()
-            .bucket(&file.bucket)
-            .key(&file.key)
-            .send()
+        let deletion = self
+            .config
+            .retry
+            .retry("delete_object", || {
+                client
+                    .delete_object()
+                    .bucket(&file.bucket)
+                    .key(&file.key)
+                    .send()
+            })
             .await
             .map_err(ServerError::storage_error)?;
 
@@ -344,13 +456,8 @@ impl StorageBackend for S3Backend {
     }
 
     async fn download_file(&self, name: String, prefer_stream: bool) -> ServerResult<Download> {
-        let req = self
-            .client
-            .get_object()
-            .bucket(&self.config.bucket)
-            .key(&name);
-
-        self.get_download(req, prefer_stream).await
+        self.get_download(&self.client, &self.config.bucket, &name, prefer_stream)
+            .await
     }
 
     async fn download_file_db(
@@ -360,9 +467,53 @@ impl StorageBackend for S3Backend {
     ) -> ServerResult<Download> {
         let (client, file) = self.get_client_from_db_ref(file).await?;
 
-        let req = client.get_object().bucket(&file.bucket).key(&file.key);
+        self.get_download(&client, &file.bucket, &file.key, prefer_stream)
+            .await
+    }
+
+    async fn download_file_db_from(
+        &self,
+        file: &RemoteFile,
+        offset: u64,
+    ) -> ServerResult<Box<dyn AsyncRead + Unpin + Send>> {
+        let (client, file) = self.get_client_from_db_ref(file).await?;
+
+        let mut req = client.get_object().bucket(&file.bucket).key(&file.key);
+        if offset != 0 {
+            req = req.range(format!("bytes={}-", offset));
+        }
+
+        let output = self
+            .config
+            .retry
+            .retry("get_object", || req.clone().send())
+            .await
+            .map_err(|e| download_error(&file.key, e))?;
+
+        Ok(Box::new(output.body.into_async_read()))
+    }
+
+    async fn rename_file(&self, from: String, to: String) -> ServerResult<RemoteFile> {
+        // S3 has no renames, so we copy then delete
+        let copy = self
+            .config
+            .retry
+            .retry("copy_object", || {
+                self.client
+                    .copy_object()
+                    .bucket(&self.config.bucket)
+                    .copy_source(format!("{}/{}", self.config.bucket, from))
+                    .key(&to)
+                    .send()
+            })
+            .await
+            .map_err(ServerError::storage_error)?;
+
+        tracing::debug!("copy_object -> {:#?}", copy);
+
+        self.delete_file(from).await?;
 
-        self.get_download(req, prefer_stream).await
+        self.make_db_reference(to).await
     }
 
     async fn make_db_reference(&self, name: String) -> ServerResult<RemoteFile> {
@@ -372,4 +523,443 @@ impl StorageBackend for S3Backend {
             key: name,
         }))
     }
+
+    async fn list_files(&self, continuation: Option<String>) -> ServerResult<FileListPage> {
+        let output = self
+            .config
+            .retry
+            .retry("list_objects_v2", || {
+                self.client
+                    .list_objects_v2()
+                    .bucket(&self.config.bucket)
+                    .set_continuation_token(continuation.clone())
+                    .send()
+            })
+            .await
+            .map_err(ServerError::storage_error)?;
+
+        let files = output
+            .contents()
+            .iter()
+            .filter_map(|object| {
+                let key = object.key()?;
+                Some(StoredFile {
+                    name: key.to_owned(),
+                    file: RemoteFile::S3(S3RemoteFile {
+                        region: self.config.region.clone(),
+                        bucket: self.config.bucket.clone(),
+                        key: key.to_owned(),
+                    }),
+                    size: object.size().unwrap_or(0).max(0) as u64,
+                    modified: object
+                        .last_modified()
+                        .and_then(|t| DateTime::from_timestamp(t.secs(), t.subsec_nanos())),
+                })
+            })
+            .collect();
+
+        let next = if output.is_truncated().unwrap_or(false) {
+            output.next_continuation_token().map(str::to_owned)
+        } else {
+            None
+        };
+
+        Ok(FileListPage { files, next })
+    }
+}
+
+impl S3StorageConfig {
+    /// Returns whether a file is small enough to stream in the `auto` serve mode.
+    fn is_small(&self, size: Option<i64>) -> bool {
+        size.is_some_and(|size| (size as u64) < self.stream_threshold)
+    }
+}
+
+impl<E: ProvideErrorMetadata> Retryable for SdkError<E> {
+    fn is_retryable(&self) -> bool {
+        match self {
+            SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) => true,
+            SdkError::ResponseError(_) => true,
+            SdkError::ServiceError(e) => {
+                let status = e.raw().status();
+                let throttled = matches!(
+                    e.err().code(),
+                    Some("SlowDown" | "Throttling" | "ThrottlingException" | "RequestTimeout")
+                );
+
+                status.is_server_error() || status.as_u16() == 429 || throttled
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Converts an error downloading an object, telling missing objects apart.
+fn download_error<E>(key: &str, error: SdkError<E>) -> ServerError
+where
+    E: ProvideErrorMetadata,
+    SdkError<E>: std::error::Error + Send + Sync + 'static,
+{
+    if let SdkError::ServiceError(e) = &error {
+        if e.raw().status().as_u16() == 404 || e.err().code() == Some("NoSuchKey") {
+            return ServerError::storage_error(FileNotFound(key.to_owned()));
+        }
+    }
+
+    ServerError::storage_error(error)
+}
+
+fn default_presigned_expiry() -> Duration {
+    Duration::from_secs(600)
+}
+
+fn default_stream_threshold() -> u64 {
+    1024 * 1024
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    use axum::body::Bytes;
+    use axum::extract::{DefaultBodyLimit, State};
+    use axum::http::{header, HeaderMap, Method, StatusCode, Uri};
+    use axum::response::{IntoResponse, Response};
+    use axum::Router;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    /// A tiny S3 server with a small and a large object.
+    async fn handle(method: Method, uri: Uri) -> impl IntoResponse {
+        let size = match uri.path() {
+            "/bucket/small" => 10,
+            "/bucket/large" => 10000,
+            _ => return (StatusCode::NOT_FOUND, HeaderMap::new(), Vec::new()),
+        };
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_LENGTH, size.into());
+
+        let body = if method == Method::HEAD {
+            Vec::new()
+        } else {
+            vec![b'a'; size]
+        };
+
+        (StatusCode::OK, headers, body)
+    }
+
+    /// An S3 server that fails some requests.
+    #[derive(Default)]
+    struct FlakyS3 {
+        /// Remaining failures of each operation, with the error to fail with.
+        failures: Mutex<HashMap<&'static str, (u32, StatusCode, &'static str)>>,
+
+        /// Operations received so far.
+        calls: Mutex<Vec<&'static str>>,
+    }
+
+    impl FlakyS3 {
+        fn fail(
+            &self,
+            operation: &'static str,
+            times: u32,
+            status: StatusCode,
+            code: &'static str,
+        ) {
+            self.failures
+                .lock()
+                .unwrap()
+                .insert(operation, (times, status, code));
+        }
+
+        fn calls(&self, operation: &str) -> usize {
+            let calls = self.calls.lock().unwrap();
+            calls.iter().filter(|op| **op == operation).count()
+        }
+    }
+
+    async fn handle_flaky(
+        State(s3): State<Arc<FlakyS3>>,
+        method: Method,
+        uri: Uri,
+        _body: Bytes,
+    ) -> Response {
+        let query = uri.query().unwrap_or("");
+        let operation = match method {
+            Method::PUT if query.contains("partNumber=") => "upload_part",
+            Method::PUT => "put_object",
+            Method::POST if query.contains("uploads") => "create_multipart_upload",
+            Method::POST => "complete_multipart_upload",
+            Method::DELETE if query.contains("uploadId=") => "abort_multipart_upload",
+            _ => return StatusCode::NOT_IMPLEMENTED.into_response(),
+        };
+        s3.calls.lock().unwrap().push(operation);
+
+        if let Some((times, status, code)) = s3.failures.lock().unwrap().get_mut(operation) {
+            if *times > 0 {
+                *times -= 1;
+                let body = format!("<Error><Code>{code}</Code><Message>Injected</Message></Error>");
+                return (*status, body).into_response();
+            }
+        }
+
+        let etag = [(header::ETAG, "\"etag\"")];
+        match operation {
+            "create_multipart_upload" => (
+                etag,
+                "<InitiateMultipartUploadResult><Bucket>bucket</Bucket><Key>file</Key>\
+                 <UploadId>upload</UploadId></InitiateMultipartUploadResult>",
+            )
+                .into_response(),
+            "complete_multipart_upload" => (
+                etag,
+                "<CompleteMultipartUploadResult><Bucket>bucket</Bucket><Key>file</Key>\
+                 <ETag>\"etag\"</ETag></CompleteMultipartUploadResult>",
+            )
+                .into_response(),
+            "abort_multipart_upload" => StatusCode::NO_CONTENT.into_response(),
+            _ => etag.into_response(),
+        }
+    }
+
+    /// Lists a bucket with two objects on two pages.
+    async fn handle_list(uri: Uri) -> Response {
+        let query = uri.query().unwrap_or("");
+        assert!(query.contains("list-type=2"), "{}", query);
+
+        let (key, size, next) = if query.contains("continuation-token=page2") {
+            ("b.chunk", 20, "<IsTruncated>false</IsTruncated>")
+        } else {
+            (
+                "a.chunk",
+                10,
+                "<IsTruncated>true</IsTruncated><NextContinuationToken>page2</NextContinuationToken>",
+            )
+        };
+
+        format!(
+            "<ListBucketResult><Name>bucket</Name>{next}<Contents><Key>{key}</Key>\
+             <LastModified>2024-01-01T00:00:00.000Z</LastModified><Size>{size}</Size>\
+             </Contents></ListBucketResult>"
+        )
+        .into_response()
+    }
+
+    async fn serve(router: Router) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        addr
+    }
+
+    async fn make_backend(extra: &str) -> S3Backend {
+        let addr = serve(Router::new().fallback(handle)).await;
+        make_backend_at(addr, extra).await
+    }
+
+    async fn make_flaky_backend() -> (S3Backend, Arc<FlakyS3>) {
+        let s3 = Arc::new(FlakyS3::default());
+        let router = Router::new()
+            .fallback(handle_flaky)
+            .layer(DefaultBodyLimit::disable())
+            .with_state(s3.clone());
+        let addr = serve(router).await;
+        let backend = make_backend_at(
+            addr,
+            r#"
+[retry]
+max-retries = 2
+base-delay = "1ms"
+"#,
+        )
+        .await;
+
+        (backend, s3)
+    }
+
+    async fn make_backend_at(addr: SocketAddr, extra: &str) -> S3Backend {
+        let config: S3StorageConfig = toml::from_str(&format!(
+            r#"
+region = "us-east-1"
+bucket = "bucket"
+endpoint = "http://{addr}"
+stream-threshold = 1000
+{extra}
+
+[credentials]
+access_key_id = "access"
+secret_access_key = "secret"
+"#
+        ))
+        .unwrap();
+
+        S3Backend::new(config).await.unwrap()
+    }
+
+    async fn download(backend: &S3Backend, name: &str, prefer_stream: bool) -> Download {
+        backend
+            .download_file(name.to_string(), prefer_stream)
+            .await
+            .unwrap()
+    }
+
+    async fn read(download: Download) -> usize {
+        let Download::AsyncRead(mut stream) = download else {
+            panic!("Download was redirected");
+        };
+
+        let mut contents = Vec::new();
+        stream.read_to_end(&mut contents).await.unwrap();
+        contents.len()
+    }
+
+    #[tokio::test]
+    async fn test_serve_mode_redirect() {
+        let backend = make_backend(r#"presigned-expiry = "6h""#).await;
+        assert_eq!(S3ServeMode::Redirect, backend.config.serve_mode);
+
+        let Download::Url(url) = download(&backend, "small", false).await else {
+            panic!("Download was not redirected");
+        };
+        assert!(url.contains("X-Amz-Expires=21600"), "{}", url);
+
+        // Some callers need the contents
+        assert_eq!(10, read(download(&backend, "small", true).await).await);
+    }
+
+    #[tokio::test]
+    async fn test_serve_mode_stream() {
+        let backend = make_backend(r#"serve-mode = "stream""#).await;
+
+        assert_eq!(10000, read(download(&backend, "large", false).await).await);
+    }
+
+    #[tokio::test]
+    async fn test_serve_mode_auto() {
+        let backend = make_backend(r#"serve-mode = "auto""#).await;
+
+        assert_eq!(10, read(download(&backend, "small", false).await).await);
+        assert!(matches!(
+            download(&backend, "large", false).await,
+            Download::Url(_)
+        ));
+    }
+
+    async fn upload(backend: &S3Backend, size: usize) -> ServerResult<RemoteFile> {
+        let mut data = &vec![b'a'; size][..];
+        backend.upload_file("file".to_string(), &mut data).await
+    }
+
+    #[tokio::test]
+    async fn test_put_object_retry() {
+        let (backend, s3) = make_flaky_backend().await;
+
+        s3.fail("put_object", 2, StatusCode::SERVICE_UNAVAILABLE, "SlowDown");
+        upload(&backend, 10).await.unwrap();
+        assert_eq!(3, s3.calls("put_object"));
+
+        // Permanent errors are not retried
+        s3.fail("put_object", 1, StatusCode::FORBIDDEN, "AccessDenied");
+        assert!(upload(&backend, 10).await.is_err());
+        assert_eq!(4, s3.calls("put_object"));
+    }
+
+    #[tokio::test]
+    async fn test_multipart_upload_retry() {
+        let (backend, s3) = make_flaky_backend().await;
+
+        s3.fail(
+            "upload_part",
+            2,
+            StatusCode::SERVICE_UNAVAILABLE,
+            "SlowDown",
+        );
+        s3.fail(
+            "complete_multipart_upload",
+            1,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "InternalError",
+        );
+        upload(&backend, CHUNK_SIZE + 1).await.unwrap();
+
+        assert_eq!(4, s3.calls("upload_part"));
+        assert_eq!(2, s3.calls("complete_multipart_upload"));
+        assert_eq!(0, s3.calls("abort_multipart_upload"));
+    }
+
+    #[tokio::test]
+    async fn test_multipart_upload_abort() {
+        let (backend, s3) = make_flaky_backend().await;
+
+        s3.fail(
+            "upload_part",
+            100,
+            StatusCode::SERVICE_UNAVAILABLE,
+            "SlowDown",
+        );
+        s3.fail(
+            "abort_multipart_upload",
+            1,
+            StatusCode::SERVICE_UNAVAILABLE,
+            "SlowDown",
+        );
+        assert!(upload(&backend, CHUNK_SIZE + 1).await.is_err());
+        assert_eq!(6, s3.calls("upload_part"));
+        assert_eq!(0, s3.calls("complete_multipart_upload"));
+
+        // The upload is aborted in the background
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while s3.calls("abort_multipart_upload") < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Multipart upload wasn't aborted");
+    }
+
+    #[tokio::test]
+    async fn test_list_files() {
+        let addr = serve(Router::new().fallback(handle_list)).await;
+        let backend = make_backend_at(addr, "").await;
+
+        let page = backend.list_files(None).await.unwrap();
+        assert_eq!(Some("page2"), page.next.as_deref());
+        assert_eq!(
+            vec![StoredFile {
+                name: "a.chunk".to_string(),
+                file: backend
+                    .make_db_reference("a.chunk".to_string())
+                    .await
+                    .unwrap(),
+                size: 10,
+                modified: DateTime::from_timestamp(1704067200, 0),
+            }],
+            page.files
+        );
+
+        let page = backend.list_files(page.next).await.unwrap();
+        assert_eq!(None, page.next);
+        assert_eq!(1, page.files.len());
+        assert_eq!("b.chunk", page.files[0].name);
+        assert_eq!(20, page.files[0].size);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_presigned_expiry() {
+        let config: S3StorageConfig = toml::from_str(
+            r#"
+region = "us-east-1"
+bucket = "bucket"
+presigned-expiry = "30 days"
+"#,
+        )
+        .unwrap();
+
+        assert!(S3Backend::new(config).await.is_err());
+    }
 }
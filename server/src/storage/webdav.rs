@@ -0,0 +1,479 @@
+//! WebDAV remote files.
+//!
+//! Files are stored in a single collection on a WebDAV server and
+//! referenced by their URLs, so this also works with plain HTTP
+//! servers that accept `PUT`, `DELETE`, and `MOVE`.
+
+use std::io;
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use derivative::Derivative;
+use futures::channel::mpsc;
+use futures::{join, SinkExt, TryStreamExt};
+use reqwest::header::{HeaderValue, RANGE};
+use reqwest::{Body, Client, Method, RequestBuilder, Response, StatusCode, Url};
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::io::StreamReader;
+
+use super::{Download, FileListPage, FileNotFound, HttpRemoteFile, RemoteFile, StorageBackend};
+use crate::error::{ErrorKind, ServerError, ServerResult};
+use attic::stream::read_chunk_async;
+
+/// The size of each piece of the request body in an upload.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// The WebDAV remote file storage backend.
+#[derive(Debug)]
+pub struct WebDavBackend {
+    client: Client,
+    endpoint: Url,
+    config: WebDavStorageConfig,
+}
+
+/// WebDAV remote file storage configuration.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebDavStorageConfig {
+    /// URL of the collection to store files in.
+    ///
+    /// The collection must already exist.
+    endpoint: String,
+
+    /// Credentials for HTTP Basic authentication.
+    credentials: Option<WebDavCredentialsConfig>,
+
+    /// Whether to redirect clients to the files instead of streaming
+    /// them through the server.
+    ///
+    /// The files must be readable without credentials.
+    #[serde(rename = "redirect-downloads")]
+    #[serde(default)]
+    redirect_downloads: bool,
+}
+
+/// WebDAV credential configuration.
+#[derive(Clone, Derivative, Deserialize)]
+#[derivative(Debug)]
+pub struct WebDavCredentialsConfig {
+    /// User name.
+    username: String,
+
+    /// Password.
+    #[derivative(Debug = "ignore")]
+    password: String,
+}
+
+impl WebDavBackend {
+    pub async fn new(config: WebDavStorageConfig) -> ServerResult<Self> {
+        let mut endpoint = config.endpoint.clone();
+        if !endpoint.ends_with('/') {
+            endpoint.push('/');
+        }
+
+        let endpoint = Url::parse(&endpoint).map_err(|e| {
+            ErrorKind::StorageError(anyhow::anyhow!("Invalid WebDAV endpoint: {}", e))
+        })?;
+
+        Ok(Self {
+            client: Client::new(),
+            endpoint,
+            config,
+        })
+    }
+
+    /// Returns the URL of a file.
+    fn get_url(&self, name: &str) -> ServerResult<Url> {
+        self.endpoint.join(name).map_err(ServerError::storage_error)
+    }
+
+    /// Returns the URL of a file from a database reference.
+    ///
+    /// Only URLs on the configured server are accepted so we never
+    /// send our credentials elsewhere.
+    fn get_url_from_db_ref(&self, file: &RemoteFile) -> ServerResult<Url> {
+        let url = if let RemoteFile::Http(file) = file {
+            Url::parse(&file.url).map_err(ServerError::storage_error)?
+        } else {
+            return Err(ErrorKind::StorageError(anyhow::anyhow!(
+                "Does not understand the remote file reference"
+            ))
+            .into());
+        };
+
+        if url.origin() != self.endpoint.origin() {
+            return Err(ErrorKind::StorageError(anyhow::anyhow!(
+                "The remote file is not on the configured WebDAV server"
+            ))
+            .into());
+        }
+
+        Ok(url)
+    }
+
+    fn request(&self, method: Method, url: Url) -> RequestBuilder {
+        let req = self.client.request(method, url);
+
+        if let Some(credentials) = &self.config.credentials {
+            req.basic_auth(&credentials.username, Some(&credentials.password))
+        } else {
+            req
+        }
+    }
+
+    async fn get_download(&self, url: Url, prefer_stream: bool) -> ServerResult<Download> {
+        if self.config.redirect_downloads && !prefer_stream {
+            Ok(Download::Url(url.to_string()))
+        } else {
+            Ok(Download::AsyncRead(self.get_stream(url, 0).await?))
+        }
+    }
+
+    async fn get_stream(
+        &self,
+        url: Url,
+        offset: u64,
+    ) -> ServerResult<Box<dyn AsyncRead + Unpin + Send>> {
+        let mut req = self.request(Method::GET, url);
+        if offset != 0 {
+            req = req.header(RANGE, format!("bytes={}-", offset));
+        }
+
+        let res = req.send().await.map_err(ServerError::storage_error)?;
+        if res.status() == StatusCode::NOT_FOUND {
+            return Err(ServerError::storage_error(FileNotFound(
+                res.url().to_string(),
+            )));
+        }
+
+        let res = check_response(res)?;
+        let partial = res.status() == StatusCode::PARTIAL_CONTENT;
+
+        let stream = res.bytes_stream().map_err(io::Error::other);
+        let mut reader: Box<dyn AsyncRead + Unpin + Send> = Box::new(StreamReader::new(stream));
+
+        if offset != 0 && !partial {
+            // The server ignored the range, so skip to the offset ourselves
+            tokio::io::copy(&mut (&mut reader).take(offset), &mut tokio::io::sink())
+                .await
+                .map_err(ServerError::storage_error)?;
+        }
+
+        Ok(reader)
+    }
+
+    async fn delete_url(&self, url: Url) -> ServerResult<()> {
+        let res = self
+            .request(Method::DELETE, url)
+            .send()
+            .await
+            .map_err(ServerError::storage_error)?;
+
+        // Already gone
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+
+        check_response(res)?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for WebDavBackend {
+    async fn upload_file(
+        &self,
+        name: String,
+        mut stream: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> ServerResult<RemoteFile> {
+        let url = self.get_url(&name)?;
+
+        // The request body must be 'static, so we feed it from here
+        let (mut sender, receiver) = mpsc::channel::<io::Result<Bytes>>(1);
+
+        let upload = self
+            .request(Method::PUT, url.clone())
+            .body(Body::wrap_stream(receiver))
+            .send();
+
+        let feed = async move {
+            loop {
+                let buf = BytesMut::with_capacity(CHUNK_SIZE);
+                match read_chunk_async(&mut stream, buf).await {
+                    Ok(chunk) if chunk.is_empty() => break,
+                    Ok(chunk) => {
+                        if sender.send(Ok(chunk)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        let _ = sender.send(Err(e)).await;
+                        break;
+                    }
+                }
+            }
+        };
+
+        let (res, _) = join!(upload, feed);
+        check_response(res.map_err(ServerError::storage_error)?)?;
+
+        Ok(RemoteFile::Http(HttpRemoteFile {
+            url: url.to_string(),
+        }))
+    }
+
+    async fn delete_file(&self, name: String) -> ServerResult<()> {
+        self.delete_url(self.get_url(&name)?).await
+    }
+
+    async fn delete_file_db(&self, file: &RemoteFile) -> ServerResult<()> {
+        self.delete_url(self.get_url_from_db_ref(file)?).await
+    }
+
+    async fn download_file(&self, name: String, prefer_stream: bool) -> ServerResult<Download> {
+        self.get_download(self.get_url(&name)?, prefer_stream).await
+    }
+
+    async fn download_file_db(
+        &self,
+        file: &RemoteFile,
+        prefer_stream: bool,
+    ) -> ServerResult<Download> {
+        self.get_download(self.get_url_from_db_ref(file)?, prefer_stream)
+            .await
+    }
+
+    async fn download_file_db_from(
+        &self,
+        file: &RemoteFile,
+        offset: u64,
+    ) -> ServerResult<Box<dyn AsyncRead + Unpin + Send>> {
+        self.get_stream(self.get_url_from_db_ref(file)?, offset)
+            .await
+    }
+
+    async fn rename_file(&self, from: String, to: String) -> ServerResult<RemoteFile> {
+        let move_method = Method::from_bytes(b"MOVE").unwrap();
+        let destination = self.get_url(&to)?;
+
+        let res = self
+            .request(move_method, self.get_url(&from)?)
+            .header("Destination", destination.as_str())
+            .header("Overwrite", HeaderValue::from_static("T"))
+            .send()
+            .await
+            .map_err(ServerError::storage_error)?;
+
+        check_response(res)?;
+
+        self.make_db_reference(to).await
+    }
+
+    async fn make_db_reference(&self, name: String) -> ServerResult<RemoteFile> {
+        Ok(RemoteFile::Http(HttpRemoteFile {
+            url: self.get_url(&name)?.to_string(),
+        }))
+    }
+
+    async fn list_files(&self, _continuation: Option<String>) -> ServerResult<FileListPage> {
+        Err(ErrorKind::StorageError(anyhow::anyhow!(
+            "Listing files is not supported by the WebDAV backend"
+        ))
+        .into())
+    }
+}
+
+/// Turns unsuccessful responses into errors.
+fn check_response(res: Response) -> ServerResult<Response> {
+    if res.status().is_success() {
+        Ok(res)
+    } else {
+        Err(ErrorKind::StorageError(anyhow::anyhow!(
+            "WebDAV server returned {} for {}",
+            res.status(),
+            res.url()
+        ))
+        .into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use axum::body::Bytes as AxumBytes;
+    use axum::extract::Extension;
+    use axum::http::{HeaderMap, Method as AxumMethod, StatusCode as AxumStatusCode, Uri};
+    use axum::Router;
+    use tokio::net::TcpListener;
+
+    type Files = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+    /// A tiny in-memory WebDAV server.
+    async fn handle(
+        Extension(files): Extension<Files>,
+        method: AxumMethod,
+        uri: Uri,
+        headers: HeaderMap,
+        body: AxumBytes,
+    ) -> (AxumStatusCode, Vec<u8>) {
+        // "user:pass"
+        if headers.get("authorization").and_then(|v| v.to_str().ok()) != Some("Basic dXNlcjpwYXNz")
+        {
+            return (AxumStatusCode::UNAUTHORIZED, Vec::new());
+        }
+
+        let path = uri.path().to_string();
+        let mut files = files.lock().unwrap();
+
+        match method.as_str() {
+            "PUT" => {
+                files.insert(path, body.to_vec());
+                (AxumStatusCode::CREATED, Vec::new())
+            }
+            "GET" => match files.get(&path) {
+                Some(contents) => {
+                    let offset = headers
+                        .get("range")
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|v| v.strip_prefix("bytes="))
+                        .and_then(|v| v.strip_suffix('-'))
+                        .and_then(|v| v.parse::<usize>().ok());
+
+                    match offset {
+                        Some(offset) => {
+                            (AxumStatusCode::PARTIAL_CONTENT, contents[offset..].to_vec())
+                        }
+                        None => (AxumStatusCode::OK, contents.clone()),
+                    }
+                }
+                None => (AxumStatusCode::NOT_FOUND, Vec::new()),
+            },
+            "DELETE" => match files.remove(&path) {
+                Some(_) => (AxumStatusCode::NO_CONTENT, Vec::new()),
+                None => (AxumStatusCode::NOT_FOUND, Vec::new()),
+            },
+            "MOVE" => {
+                let destination: Uri = headers["destination"].to_str().unwrap().parse().unwrap();
+                match files.remove(&path) {
+                    Some(contents) => {
+                        files.insert(destination.path().to_string(), contents);
+                        (AxumStatusCode::CREATED, Vec::new())
+                    }
+                    None => (AxumStatusCode::NOT_FOUND, Vec::new()),
+                }
+            }
+            _ => (AxumStatusCode::METHOD_NOT_ALLOWED, Vec::new()),
+        }
+    }
+
+    async fn make_server() -> (String, Files) {
+        let files = Files::default();
+        let app = Router::new()
+            .fallback(handle)
+            .layer(Extension(files.clone()));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        (format!("http://{}/dav", addr), files)
+    }
+
+    async fn make_backend(endpoint: &str, password: &str) -> WebDavBackend {
+        WebDavBackend::new(WebDavStorageConfig {
+            endpoint: endpoint.to_string(),
+            credentials: Some(WebDavCredentialsConfig {
+                username: "user".to_string(),
+                password: password.to_string(),
+            }),
+            redirect_downloads: false,
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn read(mut stream: Box<dyn AsyncRead + Unpin + Send>) -> Vec<u8> {
+        let mut contents = Vec::new();
+        tokio::io::copy(&mut stream, &mut contents).await.unwrap();
+        contents
+    }
+
+    #[tokio::test]
+    async fn test_webdav() {
+        let (endpoint, files) = make_server().await;
+        let backend = make_backend(&endpoint, "pass").await;
+
+        let reference = backend
+            .make_db_reference("a.chunk".to_string())
+            .await
+            .unwrap();
+        let uploaded = backend
+            .upload_file("a.chunk".to_string(), &mut &b"hello world"[..])
+            .await
+            .unwrap();
+        assert_eq!(reference, uploaded);
+        assert_eq!(
+            Some(b"hello world".to_vec()),
+            files.lock().unwrap().get("/dav/a.chunk").cloned()
+        );
+
+        let Download::AsyncRead(stream) =
+            backend.download_file_db(&reference, false).await.unwrap()
+        else {
+            panic!("Downloads were redirected");
+        };
+        assert_eq!(b"hello world".to_vec(), read(stream).await);
+
+        let stream = backend.download_file_db_from(&reference, 6).await.unwrap();
+        assert_eq!(b"world".to_vec(), read(stream).await);
+
+        let renamed = backend
+            .rename_file("a.chunk".to_string(), "b.chunk".to_string())
+            .await
+            .unwrap();
+        assert!(backend.download_file_db(&reference, true).await.is_err());
+
+        backend.delete_file_db(&renamed).await.unwrap();
+        assert!(files.lock().unwrap().is_empty());
+
+        // Deleting missing files is fine
+        backend.delete_file_db(&renamed).await.unwrap();
+
+        // Files elsewhere are never touched
+        let foreign = RemoteFile::Http(HttpRemoteFile {
+            url: "http://example.com/dav/b.chunk".to_string(),
+        });
+        assert!(backend.delete_file_db(&foreign).await.is_err());
+
+        let unauthorized = make_backend(&endpoint, "wrong").await;
+        assert!(unauthorized
+            .upload_file("c.chunk".to_string(), &mut &b"hello"[..])
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_redirect_downloads() {
+        let backend = WebDavBackend::new(WebDavStorageConfig {
+            endpoint: "https://dav.example.com/attic".to_string(),
+            credentials: None,
+            redirect_downloads: true,
+        })
+        .await
+        .unwrap();
+
+        let Download::Url(url) = backend
+            .download_file("a.chunk".to_string(), false)
+            .await
+            .unwrap()
+        else {
+            panic!("Downloads were not redirected");
+        };
+        assert_eq!("https://dav.example.com/attic/a.chunk", url);
+    }
+}
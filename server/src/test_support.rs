@@ -0,0 +1,350 @@
+//! Running the server in-process for integration tests.
+//!
+//! This is available with the `test-support` feature. [`TestServer`]
+//! serves the API on an ephemeral port with an in-memory SQLite
+//! database, local storage in a temporary directory, and a freshly
+//! generated HS256 key:
+//!
+//! ```ignore
+//! let server = TestServer::start().await?;
+//! let endpoint = server.endpoint(); // http://127.0.0.1:12345/
+//! let token = &server.admin_token;
+//! ```
+
+use std::net::SocketAddr;
+
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD as BASE64_STANDARD, Engine};
+use chrono::{Duration as ChronoDuration, Utc};
+use rand::RngCore;
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+use crate::access::{decode_token_hs256_secret_base64, SignatureType, Token};
+use crate::config::Config;
+use crate::database::migration::{Migrator, MigratorTrait};
+use crate::{serve_state, StateInner};
+
+/// An in-process Attic server.
+///
+/// The server is stopped when this is dropped.
+pub struct TestServer {
+    /// The address the server is listening on.
+    pub addr: SocketAddr,
+
+    /// A token with all permissions on all caches.
+    pub admin_token: String,
+
+    /// Temporary directory holding the storage.
+    _storage_dir: TempDir,
+
+    /// The server task.
+    handle: JoinHandle<()>,
+}
+
+impl TestServer {
+    /// Starts a server with the default configuration.
+    pub async fn start() -> Result<Self> {
+        Self::start_with("").await
+    }
+
+    /// Starts a server with additional configuration.
+    ///
+    /// `extra_config` is appended to the generated configuration, so it
+    /// should only contain tables not already set up by the harness,
+    /// like `[compression]` or `[garbage-collection]`.
+    pub async fn start_with(extra_config: &str) -> Result<Self> {
+        let storage_dir = TempDir::new()?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let mut secret = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let secret = BASE64_STANDARD.encode(secret);
+
+        // Chunk aggressively so tiny test NARs exercise chunking
+        let config = format!(
+            r#"
+listen = "{addr}"
+
+[database]
+url = "sqlite::memory:"
+
+[storage]
+type = "local"
+path = "{storage}"
+
+[chunking]
+nar-size-threshold = 1
+min-size = 64
+avg-size = 256
+max-size = 1024
+
+[jwt.signing]
+token-hs256-secret-base64 = "{secret}"
+
+{extra_config}
+"#,
+            storage = storage_dir.path().display(),
+        );
+        let config: Config = toml::from_str(&config)?;
+
+        // The in-memory database only lives as long as the state
+        let state = StateInner::new(config).await;
+        Migrator::up(state.database().await?, None).await?;
+
+        let handle = tokio::spawn(async move {
+            serve_state(listener, state).await.expect("Server failed");
+        });
+
+        let admin_token = {
+            let exp = Utc::now() + ChronoDuration::days(1);
+            let mut token = Token::new("admin".to_string(), &exp);
+            let perm = token.get_or_insert_permission_mut("*".parse()?);
+            perm.pull = true;
+            perm.push = true;
+            perm.delete = true;
+            perm.create_cache = true;
+            perm.configure_cache = true;
+            perm.configure_cache_retention = true;
+            perm.destroy_cache = true;
+
+            let key = decode_token_hs256_secret_base64(&secret)?;
+            token.encode(&SignatureType::HS256(key), &None, &None)?
+        };
+
+        Ok(Self {
+            addr,
+            admin_token,
+            _storage_dir: storage_dir,
+            handle,
+        })
+    }
+
+    /// Returns the API endpoint, with a trailing slash.
+    pub fn endpoint(&self) -> String {
+        format!("http://{}/", self.addr)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::Path;
+
+    use std::time::Duration;
+
+    use reqwest::header::{CONTENT_TYPE, RETRY_AFTER};
+    use reqwest::{Client, StatusCode};
+    use sha2::{Digest, Sha256};
+
+    use attic::api::v1::cache_config::{CreateCacheRequest, KeypairConfig};
+    use attic::api::v1::upload_path::{UploadPathNarInfo, ATTIC_NAR_INFO};
+    use attic::hash::Hash;
+    use attic::nix_store::StorePathHash;
+
+    const BASE_NAME: &str = "nm1w9sdm6j6icmhd2q3260hl1w9zj6li-attic-test-no-deps";
+
+    fn read_nar() -> Vec<u8> {
+        std::fs::read(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("../attic/src/nix_store/tests/nar")
+                .join(format!("{}.nar", BASE_NAME)),
+        )
+        .unwrap()
+    }
+
+    fn upload_info(nar: &[u8]) -> UploadPathNarInfo {
+        UploadPathNarInfo {
+            cache: "test".parse().unwrap(),
+            store_path_hash: StorePathHash::new(BASE_NAME[..32].to_string()).unwrap(),
+            store_path: format!("/nix/store/{}", BASE_NAME),
+            references: Vec::new(),
+            system: None,
+            deriver: None,
+            sigs: Vec::new(),
+            ca: None,
+            nar_hash: Hash::Sha256(Sha256::digest(nar).into()),
+            nar_size: nar.len(),
+        }
+    }
+
+    async fn create_cache(client: &Client, server: &TestServer) {
+        let request = CreateCacheRequest {
+            keypair: KeypairConfig::Generate,
+            is_public: false,
+            store_dir: "/nix/store".to_string(),
+            priority: 41,
+            upstream_cache_key_names: Vec::new(),
+        };
+        let res = client
+            .post(format!("{}_api/v1/cache-config/test", server.endpoint()))
+            .bearer_auth(&server.admin_token)
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&request).unwrap())
+            .send()
+            .await
+            .unwrap();
+        assert!(res.status().is_success(), "{:?}", res);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_push_pull() {
+        let server = TestServer::start_with("[compression]\ntype = \"none\"")
+            .await
+            .unwrap();
+        let endpoint = server.endpoint();
+        let token = &server.admin_token;
+        let client = Client::new();
+
+        let nar = read_nar();
+        create_cache(&client, &server).await;
+
+        let upload_info = upload_info(&nar);
+        let res = client
+            .put(format!("{}_api/v1/upload-path", endpoint))
+            .bearer_auth(token)
+            .header(ATTIC_NAR_INFO, serde_json::to_string(&upload_info).unwrap())
+            .body(nar.clone())
+            .send()
+            .await
+            .unwrap();
+        assert!(res.status().is_success(), "{:?}", res);
+
+        // Anonymous clients can't pull from the private cache
+        let narinfo_url = format!("{}test/{}.narinfo", endpoint, &BASE_NAME[..32]);
+        let res = client.get(&narinfo_url).send().await.unwrap();
+        assert!(res.status().is_client_error());
+
+        let narinfo = client
+            .get(&narinfo_url)
+            .bearer_auth(token)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(narinfo.contains(&format!("StorePath: /nix/store/{}\n", BASE_NAME)));
+        assert!(narinfo.contains("Sig: test:"));
+
+        let url = narinfo
+            .lines()
+            .find_map(|line| line.strip_prefix("URL: "))
+            .unwrap();
+        let pulled = client
+            .get(format!("{}test/{}", endpoint, url))
+            .bearer_auth(token)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap();
+        assert_eq!(nar, pulled);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_endless_upload() {
+        let server = TestServer::start().await.unwrap();
+        let endpoint = server.endpoint();
+        let token = &server.admin_token;
+        let client = Client::new();
+
+        let nar = read_nar();
+        create_cache(&client, &server).await;
+
+        let upload = |body: reqwest::Body| {
+            client
+                .put(format!("{}_api/v1/upload-path", endpoint))
+                .bearer_auth(token)
+                .header(
+                    ATTIC_NAR_INFO,
+                    serde_json::to_string(&upload_info(&nar)).unwrap(),
+                )
+                .body(body)
+                .send()
+        };
+
+        let res = upload(nar.clone().into()).await.unwrap();
+        assert!(res.status().is_success(), "{:?}", res);
+
+        // Claiming the known NAR while streaming forever is cut off
+        // once the claimed size is exceeded
+        let garbage = futures::stream::repeat_with(|| {
+            Ok::<_, std::io::Error>(bytes::Bytes::from_static(&[0; 4096]))
+        });
+        let res = tokio::time::timeout(
+            std::time::Duration::from_secs(30),
+            upload(reqwest::Body::wrap_stream(garbage)),
+        )
+        .await
+        .expect("Upload wasn't cut off")
+        .unwrap();
+        assert_eq!(reqwest::StatusCode::BAD_REQUEST, res.status());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_limit_unauthenticated() {
+        let server = TestServer::start_with(
+            r#"
+[limits]
+anonymous-requests-per-second = 10
+anonymous-request-burst = 2
+auth-failures-per-second = 10
+auth-failure-burst = 2
+"#,
+        )
+        .await
+        .unwrap();
+        let client = Client::new();
+        let url = format!("{}test/nix-cache-info", server.endpoint());
+
+        create_cache(&client, &server).await;
+
+        let anonymous = || async { client.get(&url).send().await.unwrap() };
+        let with_token = |token: &str| {
+            let req = client.get(&url).bearer_auth(token).send();
+            async { req.await.unwrap() }
+        };
+
+        // Anonymous burst, then rejected
+        for _ in 0..2 {
+            assert_ne!(StatusCode::TOO_MANY_REQUESTS, anonymous().await.status());
+        }
+        let res = anonymous().await;
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, res.status());
+        assert_eq!("1", res.headers()[RETRY_AFTER]);
+
+        // Valid tokens bypass the limiter
+        for _ in 0..5 {
+            assert!(with_token(&server.admin_token).await.status().is_success());
+        }
+
+        // Recovery
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_ne!(StatusCode::TOO_MANY_REQUESTS, anonymous().await.status());
+
+        // Bad tokens fail verification in a burst, then are rejected
+        for _ in 0..2 {
+            assert_eq!(StatusCode::UNAUTHORIZED, with_token("bad").await.status());
+        }
+        let res = with_token("bad").await;
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, res.status());
+        assert!(res.headers().contains_key(RETRY_AFTER));
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(with_token(&server.admin_token).await.status().is_success());
+        assert_eq!(StatusCode::UNAUTHORIZED, with_token("bad").await.status());
+    }
+}
@@ -0,0 +1,665 @@
+//! Chunk integrity verification.
+//!
+//! This downloads chunks from the storage backend and checks them
+//! against the compressed file hashes and sizes recorded in the
+//! database. Chunks that are missing or corrupted are detached from
+//! their NARs by nulling `chunkref.chunk_id`, which will be repaired
+//! the next time a client uploads a path containing the same chunk.
+//! The NARs are marked as incomplete so clients see the affected paths
+//! as missing and upload them again.
+//! Detached chunks are left for the garbage collector to clean up
+//! once nothing refers to them.
+//!
+//! Only chunks the storage backend reports as not found are considered
+//! missing. Other download errors may be transient, so those chunks are
+//! reported as failed and left alone.
+//!
+//! The reference counts of chunks are also checked against the chunk
+//! references, and corrected if they have drifted.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use futures::future::join_all;
+use rand::Rng;
+use sea_orm::entity::prelude::*;
+use sea_orm::query::{QueryOrder, QuerySelect};
+use sea_orm::sea_query::{Expr, Query};
+use sea_orm::{JoinType, TransactionTrait};
+use sha2::{Digest, Sha256};
+use tokio::sync::Semaphore;
+use tracing::instrument;
+
+use super::{State, StateInner};
+use crate::config::Config;
+use crate::database::entity::chunk::{self, ChunkState, Entity as Chunk};
+use crate::database::entity::chunkref::{self, Entity as ChunkRef};
+use crate::database::entity::nar::{self, Entity as Nar};
+use crate::database::entity::object::{self, Entity as Object};
+use crate::database::{add_chunk_references, AtticDatabase, MAX_IN_LIST_SIZE};
+use crate::error::{ErrorKind, ServerError, ServerResult};
+use crate::storage::Download;
+use attic::cache::CacheName;
+use attic::hash::Hash;
+use attic::stream::StreamHasher;
+
+/// Number of chunks to fetch from the database at a time.
+const BATCH_SIZE: u64 = 1000;
+
+/// Number of times to try downloading a chunk before giving up.
+const DOWNLOAD_ATTEMPTS: usize = 3;
+
+/// Number of consecutive download failures before aborting the run.
+///
+/// This many failures in a row likely means the storage backend is
+/// unavailable rather than that the chunks are bad.
+const MAX_CONSECUTIVE_FAILURES: usize = 20;
+
+/// Options for chunk verification.
+#[derive(Debug, Clone)]
+pub struct ChunkVerificationOptions {
+    /// Only verify chunks referenced by objects in this cache.
+    pub cache: Option<CacheName>,
+
+    /// Only verify chunks with IDs greater than this.
+    pub start_after: i64,
+
+    /// Maximum number of chunks to download concurrently.
+    pub concurrency: usize,
+
+    /// Whether to only report bad chunks without detaching them.
+    pub dry_run: bool,
+
+    /// Fraction of chunks to verify, chosen at random.
+    ///
+    /// If unset, all chunks are verified.
+    pub sample: Option<f64>,
+}
+
+/// Summary of a chunk verification run.
+#[derive(Debug, Clone, Default)]
+pub struct ChunkVerificationReport {
+    /// Number of chunks that were checked.
+    pub checked: usize,
+
+    /// Number of chunks that were not sampled.
+    pub skipped: usize,
+
+    /// Number of chunks with matching hashes and sizes.
+    pub valid: usize,
+
+    /// IDs of chunks whose content does not match the database.
+    pub mismatched: Vec<i64>,
+
+    /// IDs of chunks that do not exist in the storage backend.
+    pub missing: Vec<i64>,
+
+    /// IDs of chunks that could not be downloaded for other reasons.
+    ///
+    /// These chunks are not detached.
+    pub failed: Vec<i64>,
+
+    /// Number of chunks without confirmed file hashes.
+    pub unconfirmed: usize,
+
+    /// Number of chunk references that were detached.
+    pub chunkrefs_detached: u64,
+
+    /// IDs of chunks with reference counts not matching their references.
+    ///
+    /// This is only checked when verifying the whole chunk store.
+    pub miscounted: Vec<i64>,
+
+    /// The highest chunk ID that has been fully processed.
+    ///
+    /// Pass this as `start_after` to resume an interrupted run.
+    pub high_water_mark: i64,
+}
+
+/// The outcome of verifying a single chunk.
+#[derive(Debug)]
+enum ChunkStatus {
+    Valid,
+    Mismatched,
+    Missing,
+    Unconfirmed,
+    Failed(ServerError),
+}
+
+/// Verifies chunks in the global chunk store.
+///
+/// The callback is invoked with the report so far after each batch,
+/// which can be used to persist the high-water mark.
+#[instrument(skip_all)]
+pub async fn run_chunk_verification<F>(
+    config: Config,
+    options: ChunkVerificationOptions,
+    on_batch: F,
+) -> Result<ChunkVerificationReport>
+where
+    F: FnMut(&ChunkVerificationReport) -> Result<()>,
+{
+    let state = StateInner::new(config).await;
+    verify_chunks(&state, options, on_batch).await
+}
+
+/// Verifies chunks with an existing server state.
+pub(crate) async fn verify_chunks<F>(
+    state: &State,
+    options: ChunkVerificationOptions,
+    mut on_batch: F,
+) -> Result<ChunkVerificationReport>
+where
+    F: FnMut(&ChunkVerificationReport) -> Result<()>,
+{
+    let db = state.database().await?;
+
+    // Fail early if the storage backend can't be set up at all
+    state.storage().await?;
+
+    let cache_id = match &options.cache {
+        Some(name) => Some(db.find_cache(name).await?.id),
+        None => None,
+    };
+
+    let limit = Arc::new(Semaphore::new(options.concurrency.max(1)));
+    let mut report = ChunkVerificationReport {
+        high_water_mark: options.start_after,
+        ..Default::default()
+    };
+
+    loop {
+        let mut query = Chunk::find()
+            .filter(chunk::Column::State.eq(ChunkState::Valid))
+            .filter(chunk::Column::Id.gt(report.high_water_mark))
+            .order_by_asc(chunk::Column::Id)
+            .limit(BATCH_SIZE);
+
+        if let Some(cache_id) = cache_id {
+            let referenced = Query::select()
+                .from(ChunkRef)
+                .column(chunkref::Column::ChunkId)
+                .inner_join(
+                    Object,
+                    object::Column::NarId
+                        .into_expr()
+                        .eq(chunkref::Column::NarId.into_expr()),
+                )
+                .and_where(object::Column::CacheId.eq(cache_id))
+                .to_owned();
+
+            query = query.filter(chunk::Column::Id.in_subquery(referenced));
+        }
+
+        let chunks = query.all(db).await?;
+        let Some(last) = chunks.last() else {
+            break;
+        };
+        let last_id = last.id;
+
+        let chunks: Vec<_> = match options.sample {
+            Some(sample) => {
+                let mut rng = rand::thread_rng();
+                let (sampled, skipped): (Vec<_>, Vec<_>) = chunks
+                    .into_iter()
+                    .partition(|_| rng.gen_bool(sample.clamp(0.0, 1.0)));
+                report.skipped += skipped.len();
+                sampled
+            }
+            None => chunks,
+        };
+
+        let futures = chunks.into_iter().map(|chunk| {
+            let limit = limit.clone();
+            let state = state.clone();
+            async move {
+                let permit = limit.acquire().await?;
+                let status = verify_chunk(&state, &chunk).await?;
+                drop(permit);
+                Result::<_, anyhow::Error>::Ok((chunk.id, status))
+            }
+        });
+
+        let mut bad_chunk_ids = Vec::new();
+        let mut consecutive_failures = 0;
+        for result in join_all(futures).await {
+            let (chunk_id, status) = result?;
+            report.checked += 1;
+
+            if !matches!(status, ChunkStatus::Failed(_)) {
+                consecutive_failures = 0;
+            }
+
+            match status {
+                ChunkStatus::Valid => report.valid += 1,
+                ChunkStatus::Unconfirmed => report.unconfirmed += 1,
+                ChunkStatus::Mismatched => {
+                    tracing::warn!("Chunk {} does not match the database", chunk_id);
+                    report.mismatched.push(chunk_id);
+                    bad_chunk_ids.push(chunk_id);
+                }
+                ChunkStatus::Missing => {
+                    tracing::warn!("Chunk {} is missing from storage", chunk_id);
+                    report.missing.push(chunk_id);
+                    bad_chunk_ids.push(chunk_id);
+                }
+                ChunkStatus::Failed(e) => {
+                    tracing::warn!("Failed to download chunk {}: {}", chunk_id, e);
+                    report.failed.push(chunk_id);
+
+                    consecutive_failures += 1;
+                    if consecutive_failures >= MAX_CONSECUTIVE_FAILURES {
+                        return Err(anyhow!(
+                            "Aborting after {} chunks in a row failed to download, is the storage backend available? Last error: {}",
+                            consecutive_failures,
+                            e
+                        ));
+                    }
+                }
+            }
+        }
+
+        if !bad_chunk_ids.is_empty() && !options.dry_run {
+            report.chunkrefs_detached += detach_chunks(state, bad_chunk_ids).await?;
+        }
+
+        report.high_water_mark = last_id;
+        on_batch(&report)?;
+
+        tracing::info!(
+            "Verified {} chunks up to ID {}",
+            report.checked,
+            report.high_water_mark
+        );
+    }
+
+    if cache_id.is_none() {
+        report.miscounted = reconcile_reference_counts(state, options.dry_run).await?;
+    }
+
+    Ok(report)
+}
+
+/// Checks the reference counts of chunks against their chunk references.
+///
+/// Unless `dry_run` is set, drifted counts are corrected. A count is
+/// only corrected if it hasn't changed since it was checked, so
+/// concurrent uploads and deletions are not clobbered.
+pub(crate) async fn reconcile_reference_counts(state: &State, dry_run: bool) -> Result<Vec<i64>> {
+    let db = state.database().await?;
+
+    let actual_count = Expr::col((ChunkRef, chunkref::Column::Id)).count();
+    let miscounted: Vec<(i64, i64, i64)> = Chunk::find()
+        .select_only()
+        .column(chunk::Column::Id)
+        .column(chunk::Column::ReferenceCount)
+        .column_as(actual_count.clone(), "actual_count")
+        .join(JoinType::LeftJoin, chunk::Relation::ChunkRef.def())
+        .filter(chunk::Column::State.eq(ChunkState::Valid))
+        .group_by(chunk::Column::Id)
+        .group_by(chunk::Column::ReferenceCount)
+        .having(Expr::col((Chunk, chunk::Column::ReferenceCount)).ne(actual_count))
+        .order_by_asc(chunk::Column::Id)
+        .into_tuple()
+        .all(db)
+        .await?;
+
+    let mut chunk_ids = Vec::new();
+    for (chunk_id, reference_count, actual_count) in miscounted {
+        tracing::warn!(
+            "Chunk {} has a reference count of {} but {} references",
+            chunk_id,
+            reference_count,
+            actual_count
+        );
+        chunk_ids.push(chunk_id);
+
+        if !dry_run {
+            Chunk::update_many()
+                .col_expr(chunk::Column::ReferenceCount, Expr::value(actual_count))
+                .filter(chunk::Column::Id.eq(chunk_id))
+                .filter(chunk::Column::ReferenceCount.eq(reference_count))
+                .exec(db)
+                .await?;
+        }
+    }
+
+    Ok(chunk_ids)
+}
+
+/// Downloads a chunk and checks it against the database.
+async fn verify_chunk(state: &State, chunk: &chunk::Model) -> Result<ChunkStatus> {
+    let (Some(file_hash), Some(file_size)) = (&chunk.file_hash, chunk.file_size) else {
+        return Ok(ChunkStatus::Unconfirmed);
+    };
+
+    let mut attempt = 1;
+    loop {
+        match hash_remote_chunk(state, chunk).await {
+            Ok((actual_hash, actual_size)) => {
+                if actual_hash.to_typed_base16() == *file_hash && actual_size as i64 == file_size {
+                    return Ok(ChunkStatus::Valid);
+                } else {
+                    return Ok(ChunkStatus::Mismatched);
+                }
+            }
+            Err(e) if e.is_storage_not_found() => {
+                return Ok(ChunkStatus::Missing);
+            }
+            Err(e) if attempt >= DOWNLOAD_ATTEMPTS => {
+                return Ok(ChunkStatus::Failed(e));
+            }
+            Err(e) => {
+                tracing::debug!(
+                    "Failed to download chunk {} (attempt {}): {}",
+                    chunk.id,
+                    attempt,
+                    e
+                );
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Downloads a chunk, returning the hash and size of the compressed file.
+async fn hash_remote_chunk(state: &State, chunk: &chunk::Model) -> ServerResult<(Hash, usize)> {
+    let storage = state.storage().await?;
+
+    let stream = match storage.download_file_db(&chunk.remote_file.0, true).await? {
+        Download::AsyncRead(stream) => stream,
+        Download::Url(_) => {
+            return Err(ErrorKind::StorageError(anyhow::anyhow!(
+                "Storage backend did not return a stream"
+            ))
+            .into());
+        }
+    };
+
+    let (mut stream, file_compute) = StreamHasher::new(stream, Sha256::new());
+    tokio::io::copy(&mut stream, &mut tokio::io::sink())
+        .await
+        .map_err(ServerError::storage_error)?;
+
+    let (file_hash, file_size) = file_compute.get().unwrap();
+    let file_hash = Hash::Sha256(file_hash.as_slice().try_into().unwrap());
+
+    Ok((file_hash, *file_size))
+}
+
+/// Detaches bad chunks from their NARs.
+///
+/// Only the chunk references are nulled and the reference counts
+/// adjusted. The chunks themselves are left to the garbage collector,
+/// which deletes them once they are no longer referenced or held by
+/// uploads in progress.
+///
+/// The affected NARs are marked as incomplete, so the paths using them
+/// are reported as missing and get uploaded again.
+async fn detach_chunks(state: &State, chunk_ids: Vec<i64>) -> Result<u64> {
+    let db = state.database().await?;
+    let txn = db.begin().await?;
+
+    let referenced: Vec<(i64, Option<i64>)> = ChunkRef::find()
+        .select_only()
+        .column(chunkref::Column::NarId)
+        .column(chunkref::Column::ChunkId)
+        .filter(chunkref::Column::ChunkId.is_in(chunk_ids.clone()))
+        .into_tuple()
+        .all(&txn)
+        .await?;
+
+    let mut nar_ids = BTreeSet::new();
+    let mut counts: HashMap<i64, i64> = HashMap::new();
+    for (nar_id, chunk_id) in referenced {
+        nar_ids.insert(nar_id);
+        if let Some(chunk_id) = chunk_id {
+            *counts.entry(chunk_id).or_default() += 1;
+        }
+    }
+
+    let detached = ChunkRef::update_many()
+        .col_expr(chunkref::Column::ChunkId, Expr::value(Option::<i64>::None))
+        .filter(chunkref::Column::ChunkId.is_in(chunk_ids))
+        .exec(&txn)
+        .await?;
+
+    let mut by_count: BTreeMap<i64, Vec<i64>> = BTreeMap::new();
+    for (chunk_id, count) in counts {
+        by_count.entry(count).or_default().push(chunk_id);
+    }
+
+    for (count, chunk_ids) in by_count {
+        add_chunk_references(&txn, chunk_ids, -count).await?;
+    }
+
+    let nar_ids: Vec<i64> = nar_ids.into_iter().collect();
+    for batch in nar_ids.chunks(MAX_IN_LIST_SIZE) {
+        Nar::update_many()
+            .col_expr(nar::Column::CompletenessHint, Expr::value(false))
+            .filter(nar::Column::Id.is_in(batch.iter().copied()))
+            .exec(&txn)
+            .await?;
+    }
+
+    txn.commit().await?;
+
+    Ok(detached.rows_affected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use chrono::Utc;
+    use sea_orm::ActiveValue::Set;
+    use uuid::Uuid;
+
+    use crate::database::entity::nar::NarState;
+    use crate::database::entity::Json as DbJson;
+    use crate::database::insert_chunkref;
+    use crate::database::migration::{Migrator, MigratorTrait};
+    use crate::storage::{LocalRemoteFile, RemoteFile};
+
+    async fn make_state() -> (State, PathBuf) {
+        let storage_path = std::env::temp_dir().join(format!("attic-test-{}", Uuid::new_v4()));
+
+        let config = format!(
+            r#"
+[database]
+url = "sqlite::memory:"
+
+[storage]
+type = "local"
+path = "{}"
+
+[chunking]
+nar-size-threshold = 0
+min-size = 16384
+avg-size = 65536
+max-size = 262144
+
+[jwt.signing]
+token-hs256-secret-base64 = "dmVyeSBzZWN1cmUgc2VjcmV0"
+"#,
+            storage_path.display()
+        );
+
+        let config: Config = toml::from_str(&config).unwrap();
+        let state = StateInner::new(config).await;
+
+        let db = state.database().await.unwrap();
+        Migrator::up(db, None).await.unwrap();
+
+        (state, storage_path)
+    }
+
+    fn options() -> ChunkVerificationOptions {
+        ChunkVerificationOptions {
+            cache: None,
+            start_after: 0,
+            concurrency: 1,
+            dry_run: false,
+            sample: None,
+        }
+    }
+
+    /// Inserts a chunk stored at `path` relative to the storage directory.
+    async fn insert_chunk(state: &State, path: &str) -> i64 {
+        let db = state.database().await.unwrap();
+        let remote_file_id = Uuid::new_v4().to_string();
+
+        Chunk::insert(chunk::ActiveModel {
+            state: Set(ChunkState::Valid),
+            chunk_hash: Set(format!("sha256:{}", remote_file_id)),
+            chunk_size: Set(100),
+            file_hash: Set(Some(format!("sha256:{}", remote_file_id))),
+            file_size: Set(Some(100)),
+            compression: Set("none".to_string()),
+            remote_file: Set(DbJson(RemoteFile::Local(LocalRemoteFile {
+                name: remote_file_id.clone(),
+                path: Some(path.to_string()),
+            }))),
+            remote_file_id: Set(remote_file_id),
+            holders_count: Set(0),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap()
+        .last_insert_id
+    }
+
+    async fn insert_nar(state: &State, chunk_ids: &[i64]) {
+        let db = state.database().await.unwrap();
+        let txn = db.begin().await.unwrap();
+
+        let nar_id = Nar::insert(nar::ActiveModel {
+            state: Set(NarState::Valid),
+            nar_hash: Set(format!("sha256:{}", Uuid::new_v4())),
+            nar_size: Set(0),
+            compression: Set("none".to_string()),
+            num_chunks: Set(chunk_ids.len() as i32),
+            completeness_hint: Set(true),
+            holders_count: Set(0),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        })
+        .exec(&txn)
+        .await
+        .unwrap()
+        .last_insert_id;
+
+        for (seq, chunk_id) in chunk_ids.iter().enumerate() {
+            insert_chunkref(
+                &txn,
+                chunkref::ActiveModel {
+                    nar_id: Set(nar_id),
+                    seq: Set(seq as i32),
+                    chunk_id: Set(Some(*chunk_id)),
+                    chunk_hash: Set(String::new()),
+                    compression: Set("none".to_string()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        }
+
+        txn.commit().await.unwrap();
+    }
+
+    async fn find_chunk(state: &State, id: i64) -> (i64, ChunkState) {
+        let db = state.database().await.unwrap();
+        Chunk::find_by_id(id)
+            .select_only()
+            .column(chunk::Column::ReferenceCount)
+            .column(chunk::Column::State)
+            .into_tuple()
+            .one(db)
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    async fn count_chunkrefs(state: &State, chunk_id: i64) -> u64 {
+        let db = state.database().await.unwrap();
+        ChunkRef::find()
+            .filter(chunkref::Column::ChunkId.eq(chunk_id))
+            .count(db)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_verify_missing_chunk() {
+        let (state, _) = make_state().await;
+
+        let missing = insert_chunk(&state, "does-not-exist").await;
+        insert_nar(&state, &[missing, missing]).await;
+
+        let report = verify_chunks(&state, options(), |_| Ok(())).await.unwrap();
+
+        assert_eq!(vec![missing], report.missing);
+        assert!(report.failed.is_empty());
+        assert_eq!(2, report.chunkrefs_detached);
+        assert_eq!(0, count_chunkrefs(&state, missing).await);
+
+        // Left for the garbage collector
+        assert_eq!((0, ChunkState::Valid), find_chunk(&state, missing).await);
+    }
+
+    #[tokio::test]
+    async fn test_verify_download_error() {
+        let (state, storage_path) = make_state().await;
+
+        // Reading a directory fails with something other than not found
+        tokio::fs::create_dir_all(storage_path.join("unreadable"))
+            .await
+            .unwrap();
+
+        let unreadable = insert_chunk(&state, "unreadable").await;
+        insert_nar(&state, &[unreadable]).await;
+
+        let report = verify_chunks(&state, options(), |_| Ok(())).await.unwrap();
+
+        assert_eq!(vec![unreadable], report.failed);
+        assert!(report.missing.is_empty());
+        assert_eq!(0, report.chunkrefs_detached);
+        assert_eq!(1, count_chunkrefs(&state, unreadable).await);
+        assert_eq!((1, ChunkState::Valid), find_chunk(&state, unreadable).await);
+    }
+
+    #[tokio::test]
+    async fn test_verify_aborts_on_systemic_errors() {
+        let (state, storage_path) = make_state().await;
+
+        tokio::fs::create_dir_all(storage_path.join("unreadable"))
+            .await
+            .unwrap();
+
+        let missing = insert_chunk(&state, "does-not-exist").await;
+        let mut chunk_ids = vec![missing];
+        for _ in 0..MAX_CONSECUTIVE_FAILURES {
+            chunk_ids.push(insert_chunk(&state, "unreadable").await);
+        }
+        insert_nar(&state, &chunk_ids).await;
+
+        let mut batches = 0;
+        let result = verify_chunks(&state, options(), |_| {
+            batches += 1;
+            Ok(())
+        })
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(0, batches);
+
+        // Nothing in the aborted batch is detached
+        assert_eq!(1, count_chunkrefs(&state, missing).await);
+    }
+}
@@ -25,6 +25,34 @@
 //! Otherwise, the user will get a generic 401 response (Unauthorized)
 //! regardless of the request (or whether the cache exists or not).
 //!
+//! ## Deny entries
+//!
+//! A cache entry that grants no permission at all (`{}`) is a deny
+//! entry. It can be used to carve out exceptions from broader wildcard
+//! grants. Permissions for a cache are resolved as follows:
+//!
+//! 1. An entry with the exact cache name wins, whether it grants or denies.
+//! 2. Otherwise, the most specific matching wildcard entry wins. Patterns
+//!    with a longer literal prefix are more specific, followed by those
+//!    with fewer wildcards (`team-ci-*` over `team-*`, `team-*` over `*`).
+//! 3. If several entries are equally specific, deny entries win, followed
+//!    by the entry listed first.
+//!
+//! ## IP restrictions
+//!
+//! The `ipr` field optionally restricts the token to a list of
+//! source IP ranges in CIDR notation. If present, requests coming
+//! from outside the ranges are treated as if no token was supplied.
+//!
+//! ## Claim groups
+//!
+//! Tokens granting access to a large number of caches can get too
+//! big for some proxies. Instead, the cache permissions can be defined
+//! as a named claim group in the server configuration and referenced
+//! with the `cg` field. During verification, the group is expanded
+//! and merged with the `caches` map, with entries in `caches` taking
+//! precedence on conflict. Tokens referencing unknown groups are rejected.
+//!
 //! ## Supplying the token
 //!
 //! The JWT can be supplied to the server in one of two ways:
@@ -57,8 +85,10 @@
 //!         "w": 1,
 //!         "r": 1,
 //!         "cc": 1
-//!       }
-//!     }
+//!       },
+//!       "team-secret": {}
+//!     },
+//!     "ipr": ["10.0.0.0/8", "fd00::/8"]
 //!   }
 //! }
 //! ```
@@ -83,16 +113,24 @@ pub mod util;
 #[cfg(test)]
 mod tests;
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::error::Error as StdError;
+use std::net::IpAddr;
 
 use base64::{engine::general_purpose::STANDARD as BASE64_STANDARD, Engine};
 use chrono::{DateTime, Utc};
 use displaydoc::Display;
 use indexmap::IndexMap;
-use jwt_simple::prelude::{Duration, RSAKeyPairLike, RSAPublicKeyLike, VerificationOptions};
+use ipnet::IpNet;
+use jwt_simple::prelude::{
+    Duration, ECDSAP256KeyPairLike, ECDSAP256PublicKeyLike, EdDSAKeyPairLike, EdDSAPublicKeyLike,
+    RSAKeyPairLike, RSAPublicKeyLike, VerificationOptions,
+};
 pub use jwt_simple::{
-    algorithms::{HS256Key, MACLike, RS256KeyPair, RS256PublicKey},
+    algorithms::{
+        ES256KeyPair, ES256PublicKey, Ed25519KeyPair, Ed25519PublicKey, HS256Key, MACLike,
+        RS256KeyPair, RS256PublicKey,
+    },
     claims::{Claims, JWTClaims},
     prelude::UnixTimeStamp,
 };
@@ -128,8 +166,14 @@ macro_rules! require_permission_function {
     };
 }
 
+/// Cache permissions of a claim group.
+pub type ClaimGroup = IndexMap<CacheNamePattern, CachePermission>;
+
+/// Claim groups defined on the server, keyed by name.
+pub type ClaimGroups = HashMap<String, ClaimGroup>;
+
 /// A validated JSON Web Token.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Token(JWTClaims<TokenClaims>);
 
 /// Claims of a JSON Web Token.
@@ -148,7 +192,23 @@ pub struct AtticAccess {
     /// Cache permissions.
     ///
     /// Keys here may include wildcards.
+    #[serde(default)]
     caches: IndexMap<CacheNamePattern, CachePermission>,
+
+    /// Name of a server-side claim group.
+    ///
+    /// If set, the cache permissions in the group are merged
+    /// into `caches` during verification.
+    #[serde(rename = "cg")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    claim_group: Option<String>,
+
+    /// Allowed source IP ranges.
+    ///
+    /// If unset, the token can be used from anywhere.
+    #[serde(rename = "ipr")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    allowed_ip_ranges: Option<Vec<IpNet>>,
 }
 
 /// Permission to a single cache.
@@ -234,6 +294,12 @@ pub enum Error {
 
     /// Pubkey-only JWT authentication cannot create signed JWTs
     PubkeyOnlyCannotCreateToken,
+
+    /// No keys are configured to verify JWTs
+    NoVerificationKey,
+
+    /// Unknown claim group "{0}"
+    UnknownClaimGroup(String),
 }
 
 /// The supported JWT signature types.
@@ -241,13 +307,40 @@ pub enum SignatureType {
     HS256(HS256Key),
     RS256(RS256KeyPair),
     RS256PubkeyOnly(RS256PublicKey),
+    ES256(ES256KeyPair),
+    ES256PubkeyOnly(ES256PublicKey),
+    EdDSA(Ed25519KeyPair),
+    EdDSAPubkeyOnly(Ed25519PublicKey),
+}
+
+impl SignatureType {
+    /// Verifies a token with this key.
+    fn verify_token(
+        &self,
+        token: &str,
+        opts: VerificationOptions,
+    ) -> std::result::Result<JWTClaims<TokenClaims>, jwt_simple::Error> {
+        match self {
+            Self::HS256(key) => key.verify_token(token, Some(opts)),
+            Self::RS256(key) => key.public_key().verify_token(token, Some(opts)),
+            Self::RS256PubkeyOnly(key) => key.verify_token(token, Some(opts)),
+            Self::ES256(key) => key.public_key().verify_token(token, Some(opts)),
+            Self::ES256PubkeyOnly(key) => key.verify_token(token, Some(opts)),
+            Self::EdDSA(key) => key.public_key().verify_token(token, Some(opts)),
+            Self::EdDSAPubkeyOnly(key) => key.verify_token(token, Some(opts)),
+        }
+    }
 }
 
 impl Token {
     /// Verifies and decodes a token.
+    ///
+    /// The token is accepted if any of the keys verifies it. This allows
+    /// rotating keys while tokens signed with the old key are still
+    /// around. If none does, the error from the first key is returned.
     pub fn from_jwt(
         token: &str,
-        signature_type: &SignatureType,
+        signature_types: &[SignatureType],
         maybe_bound_issuer: &Option<String>,
         maybe_bound_audiences: &Option<HashSet<String>>,
     ) -> Result<Self> {
@@ -270,23 +363,17 @@ impl Token {
             artificial_time: None,
         };
 
-        match signature_type {
-            SignatureType::HS256(key) => key
-                .verify_token(token, Some(opts))
-                .map_err(Error::TokenError)
-                .map(Token),
-            SignatureType::RS256(key) => {
-                let public_key = key.public_key();
-                public_key
-                    .verify_token(token, Some(opts))
-                    .map_err(Error::TokenError)
-                    .map(Token)
+        let mut first_error = None;
+        for signature_type in signature_types {
+            match signature_type.verify_token(token, opts.clone()) {
+                Ok(claims) => return Ok(Token(claims)),
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
             }
-            SignatureType::RS256PubkeyOnly(key) => key
-                .verify_token(token, Some(opts))
-                .map_err(Error::TokenError)
-                .map(Token),
         }
+
+        Err(first_error.map_or(Error::NoVerificationKey, Error::TokenError))
     }
 
     /// Creates a new token with an expiration timestamp.
@@ -334,7 +421,11 @@ impl Token {
         match signature_type {
             SignatureType::HS256(key) => key.authenticate(token).map_err(Error::TokenError),
             SignatureType::RS256(key) => key.sign(token).map_err(Error::TokenError),
-            SignatureType::RS256PubkeyOnly(_) => {
+            SignatureType::ES256(key) => key.sign(token).map_err(Error::TokenError),
+            SignatureType::EdDSA(key) => key.sign(token).map_err(Error::TokenError),
+            SignatureType::RS256PubkeyOnly(_)
+            | SignatureType::ES256PubkeyOnly(_)
+            | SignatureType::EdDSAPubkeyOnly(_) => {
                 return Err(Error::PubkeyOnlyCannotCreateToken);
             }
         }
@@ -365,6 +456,10 @@ impl Token {
     }
 
     /// Returns explicit permission granted for a cache.
+    ///
+    /// An exact entry for the cache takes precedence. Otherwise, the
+    /// most specific matching wildcard entry is used. See the crate
+    /// documentation for the exact rules.
     pub fn get_permission_for_cache(&self, cache: &CacheName) -> CachePermission {
         let access = self.attic_access();
 
@@ -373,13 +468,99 @@ impl Token {
             return direct_match.clone();
         }
 
+        let mut best: Option<(&CacheNamePattern, &CachePermission)> = None;
         for (pattern, permission) in access.caches.iter() {
-            if pattern.matches(cache) {
-                return permission.clone();
+            if !pattern.matches(cache) {
+                continue;
+            }
+
+            let better = match best {
+                None => true,
+                Some((best_pattern, best_permission)) => {
+                    pattern.is_more_specific_than(best_pattern)
+                        || (!best_pattern.is_more_specific_than(pattern)
+                            && permission.is_deny()
+                            && !best_permission.is_deny())
+                }
+            };
+
+            if better {
+                best = Some((pattern, permission));
             }
         }
 
-        CachePermission::default()
+        best.map(|(_, permission)| permission.clone())
+            .unwrap_or_default()
+    }
+
+    /// Returns the name of the claim group referenced by the token.
+    pub fn claim_group(&self) -> Option<&str> {
+        self.attic_access().claim_group.as_deref()
+    }
+
+    /// Sets or clears the claim group referenced by the token.
+    pub fn set_claim_group(&mut self, group: Option<String>) {
+        self.attic_access_mut().claim_group = group;
+    }
+
+    /// Expands the claim group referenced by the token.
+    ///
+    /// The permissions in the group are added to the token. Patterns
+    /// already present in the token keep their permissions. Equally
+    /// specific patterns in the token are matched before the ones from
+    /// the group.
+    pub fn expand_claim_group(&mut self, groups: &ClaimGroups) -> Result<()> {
+        let access = self.attic_access_mut();
+        let Some(name) = &access.claim_group else {
+            return Ok(());
+        };
+
+        let group = groups
+            .get(name)
+            .ok_or_else(|| Error::UnknownClaimGroup(name.to_owned()))?;
+
+        for (pattern, permission) in group {
+            if !access.caches.contains_key(pattern) {
+                access.caches.insert(pattern.to_owned(), permission.clone());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Restricts the token to a source IP range.
+    ///
+    /// Once a range is added, the token can only be used from
+    /// within the allowed ranges.
+    pub fn add_allowed_ip_range(&mut self, range: IpNet) {
+        self.attic_access_mut()
+            .allowed_ip_ranges
+            .get_or_insert_with(Vec::new)
+            .push(range);
+    }
+
+    /// Returns whether the token is restricted to some IP ranges.
+    pub fn is_ip_restricted(&self) -> bool {
+        self.attic_access().allowed_ip_ranges.is_some()
+    }
+
+    /// Returns whether the token can be used from an IP address.
+    pub fn is_ip_allowed(&self, ip: IpAddr) -> bool {
+        let ranges = match &self.attic_access().allowed_ip_ranges {
+            Some(ranges) => ranges,
+            None => return true,
+        };
+
+        // Treat IPv4-mapped IPv6 addresses (::ffff:a.b.c.d) as IPv4
+        let ip = match ip {
+            IpAddr::V6(v6) => v6
+                .to_ipv4_mapped()
+                .map(IpAddr::V4)
+                .unwrap_or(IpAddr::V6(v6)),
+            v4 => v4,
+        };
+
+        ranges.iter().any(|range| range.contains(&ip))
     }
 
     fn attic_access(&self) -> &AtticAccess {
@@ -411,6 +592,14 @@ impl CachePermission {
             || self.configure_cache_retention
     }
 
+    /// Returns whether this is a deny entry.
+    ///
+    /// A deny entry grants no permission at all and overrides less
+    /// or equally specific wildcard grants matching the same cache.
+    pub const fn is_deny(&self) -> bool {
+        !self.can_discover()
+    }
+
     pub fn require_discover(&self) -> Result<()> {
         if !self.can_discover() {
             Err(Error::NoDiscoveryPermission)
@@ -462,3 +651,35 @@ pub fn decode_token_rs256_pubkey_base64(s: &str) -> Result<RS256PublicKey> {
 
     Ok(pubkey)
 }
+
+pub fn decode_token_es256_secret_base64(s: &str) -> Result<ES256KeyPair> {
+    let decoded = BASE64_STANDARD.decode(s).map_err(Error::Base64Error)?;
+    let secret = std::str::from_utf8(&decoded).map_err(Error::Utf8Error)?;
+    let keypair = ES256KeyPair::from_pem(secret).map_err(Error::TokenError)?;
+
+    Ok(keypair)
+}
+
+pub fn decode_token_es256_pubkey_base64(s: &str) -> Result<ES256PublicKey> {
+    let decoded = BASE64_STANDARD.decode(s).map_err(Error::Base64Error)?;
+    let pubkey = std::str::from_utf8(&decoded).map_err(Error::Utf8Error)?;
+    let pubkey = ES256PublicKey::from_pem(pubkey).map_err(Error::TokenError)?;
+
+    Ok(pubkey)
+}
+
+pub fn decode_token_eddsa_secret_base64(s: &str) -> Result<Ed25519KeyPair> {
+    let decoded = BASE64_STANDARD.decode(s).map_err(Error::Base64Error)?;
+    let secret = std::str::from_utf8(&decoded).map_err(Error::Utf8Error)?;
+    let keypair = Ed25519KeyPair::from_pem(secret).map_err(Error::TokenError)?;
+
+    Ok(keypair)
+}
+
+pub fn decode_token_eddsa_pubkey_base64(s: &str) -> Result<Ed25519PublicKey> {
+    let decoded = BASE64_STANDARD.decode(s).map_err(Error::Base64Error)?;
+    let pubkey = std::str::from_utf8(&decoded).map_err(Error::Utf8Error)?;
+    let pubkey = Ed25519PublicKey::from_pem(pubkey).map_err(Error::TokenError)?;
+
+    Ok(pubkey)
+}